@@ -0,0 +1,8 @@
+//! Generates the gRPC API (`proto/carbon.proto`) for `src/grpc.rs`.
+
+fn main() {
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this host");
+    std::env::set_var("PROTOC", protoc);
+    tonic_prost_build::compile_protos("proto/carbon.proto")
+        .expect("failed to compile carbon.proto");
+}
@@ -0,0 +1,12 @@
+//! Generates the C header (`include/carbon.h`) for `src/lib.rs`.
+
+fn main() {
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{dir}/cbindgen.toml"))
+        .expect("failed to read cbindgen.toml");
+    cbindgen::generate_with_config(&dir, config)
+        .expect("failed to generate carbon.h")
+        .write_to_file(format!("{dir}/include/carbon.h"));
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
@@ -0,0 +1,364 @@
+/* Carbon's C API. Generated from ffi/src/lib.rs by cbindgen: don't edit. */
+
+#ifndef CARBON_H
+#define CARBON_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * What a call returns.
+ */
+typedef enum CarbonStatus {
+  CARBON_STATUS_OK = 0,
+  /**
+   * A `NULL` pointer, a string that isn't UTF-8, or a spec that doesn't
+   * parse.
+   */
+  CARBON_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * Not now: configuring a VM already built, or pausing, resuming or
+   * snapshotting one not yet running.
+   */
+  CARBON_STATUS_INVALID_STATE = 2,
+  /**
+   * The VM's configuration doesn't work, e.g. a missing kernel (`carbon`
+   * exits with 3).
+   */
+  CARBON_STATUS_CONFIG = 3,
+  /**
+   * The host can't run the VM, e.g. without KVM (4).
+   */
+  CARBON_STATUS_HOST = 4,
+  /**
+   * KVM failed to run the guest (5).
+   */
+  CARBON_STATUS_GUEST = 5,
+  /**
+   * The guest kernel panicked (6).
+   */
+  CARBON_STATUS_GUEST_PANIC = 6,
+  /**
+   * Pausing, resuming, snapshotting or stopping the VM failed.
+   */
+  CARBON_STATUS_CONTROL = 7,
+  /**
+   * Carbon panicked; the handle must only be destroyed.
+   */
+  CARBON_STATUS_PANIC = 8,
+} CarbonStatus;
+
+/**
+ * Why a VM stopped.
+ */
+typedef enum CarbonStopReason {
+  /**
+   * The guest halted, shut down or hit a fatal exit.
+   */
+  CARBON_STOP_REASON_GUEST_EXIT = 0,
+  /**
+   * The guest kernel reported a panic.
+   */
+  CARBON_STOP_REASON_GUEST_PANIC = 1,
+  /**
+   * The guest asked for a reset.
+   */
+  CARBON_STOP_REASON_GUEST_REBOOT = 2,
+  /**
+   * The guest wrote `code` to the debug exit port.
+   */
+  CARBON_STOP_REASON_DEBUG_EXIT = 3,
+  /**
+   * The run timed out.
+   */
+  CARBON_STOP_REASON_TIMEOUT = 4,
+  /**
+   * Signal `code` stopped the VM.
+   */
+  CARBON_STOP_REASON_SIGNAL = 5,
+  /**
+   * [`carbon_vm_shutdown`] stopped the VM.
+   */
+  CARBON_STOP_REASON_SHUTDOWN = 6,
+  /**
+   * The guest printed exit status `code` on the console.
+   */
+  CARBON_STOP_REASON_EXIT_STATUS = 7,
+  /**
+   * The guest didn't boot within the boot timeout.
+   */
+  CARBON_STOP_REASON_BOOT_TIMEOUT = 8,
+  /**
+   * The VM sat idle and was snapshotted (see
+   * [`carbon_vm_set_hibernate_ms`]).
+   */
+  CARBON_STOP_REASON_HIBERNATED = 9,
+} CarbonStopReason;
+
+/**
+ * A VM (opaque to C).
+ */
+typedef struct CarbonVm CarbonVm;
+
+/**
+ * How a run ended.
+ */
+typedef struct CarbonStop {
+  enum CarbonStopReason reason;
+  /**
+   * The debug exit code, the exit status or the signal number; 0
+   * otherwise.
+   */
+  int code;
+} CarbonStop;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Carbon's version, e.g. `0.1.0`.
+ */
+const char *carbon_version(void);
+
+/**
+ * What went wrong in the last call on this thread that failed; empty if
+ * none has. Valid until the next failure on the thread.
+ */
+const char *carbon_last_error(void);
+
+/**
+ * Create a VM booting the kernel at `kernel` (a bzImage, or an
+ * uncompressed vmlinux), with `carbon`'s defaults, storing its handle in
+ * `*out`.
+ *
+ * # Safety
+ *
+ * `kernel` is NULL or a NUL-terminated string, and `out` NULL or writable.
+ */
+enum CarbonStatus carbon_vm_create(const char *kernel, struct CarbonVm **out);
+
+/**
+ * Create a VM resuming the snapshot with state file `state` and memory
+ * file `memory`, storing its handle in `*out`.
+ *
+ * # Safety
+ *
+ * `state` and `memory` are each NULL or a NUL-terminated string, and `out`
+ * NULL or writable.
+ */
+enum CarbonStatus carbon_vm_restore(const char *state, const char *memory, struct CarbonVm **out);
+
+/**
+ * Set the kernel command line.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `cmdline` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_set_cmdline(struct CarbonVm *vm, const char *cmdline);
+
+/**
+ * Boot with the initrd at `path`.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `path` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_set_initrd(struct CarbonVm *vm, const char *path);
+
+/**
+ * Set guest memory, in bytes.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_set_memory(struct CarbonVm *vm, uint64_t bytes);
+
+/**
+ * Set the vCPU count.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_set_cpus(struct CarbonVm *vm, uint8_t cpus);
+
+/**
+ * Attach a disk, as `--disk` takes it (`path=rootfs.img,ro`), after
+ * those attached already.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `disk` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_add_disk(struct CarbonVm *vm, const char *disk);
+
+/**
+ * Set COM1's console, as `--serial` takes it (`file=console.log`, `pty`,
+ * ...). The default is the process's stdio.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `console` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_set_serial(struct CarbonVm *vm, const char *console);
+
+/**
+ * Add a virtio-vsock device, as `--vsock` takes it (`cid=3,uds=PATH`).
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `vsock` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_set_vsock(struct CarbonVm *vm, const char *vsock);
+
+/**
+ * Serve the control socket (see `carbon run --help`) on `path`.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `path` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_set_control_socket(struct CarbonVm *vm, const char *path);
+
+/**
+ * Stop the VM if it is still running after `timeout_ms` milliseconds.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_set_timeout_ms(struct CarbonVm *vm, uint64_t timeout_ms);
+
+/**
+ * Stop the VM if the guest hasn't reached init within `timeout_ms`
+ * milliseconds.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_set_boot_timeout_ms(struct CarbonVm *vm, uint64_t timeout_ms);
+
+/**
+ * Snapshot the VM into the directory `dir` (`state.json` and
+ * `memory.bin`) and stop it once it has been idle for `idle_ms`
+ * milliseconds (at least 1), as `--hibernate-after` does.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `dir` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_set_hibernate_ms(struct CarbonVm *vm,
+                                             uint64_t idle_ms,
+                                             const char *dir);
+
+/**
+ * Start with the vCPUs paused, until [`carbon_vm_resume`].
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_set_paused(struct CarbonVm *vm);
+
+/**
+ * Write the VM's lifecycle events to `fd` as JSON lines, naming it
+ * `vm_id`, as `--event-fd` does. The descriptor is the VM's from then on.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `vm_id` is NULL or a NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_set_event_fd(struct CarbonVm *vm, int fd, const char *vm_id);
+
+/**
+ * Build the VM and run it on the calling thread until it stops, storing
+ * how in `*stop` unless `stop` is `NULL`. A VM runs once.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `stop` is NULL or writable.
+ */
+enum CarbonStatus carbon_vm_run(struct CarbonVm *vm, struct CarbonStop *stop);
+
+/**
+ * Stop the vCPUs of the running VM until [`carbon_vm_resume`].
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_pause(struct CarbonVm *vm);
+
+/**
+ * Let the vCPUs of a paused VM run on.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_resume(struct CarbonVm *vm);
+
+/**
+ * Snapshot the running VM: its state to `state`, guest RAM to `memory`,
+ * to restore with [`carbon_vm_restore`].
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call. `state` and `memory` are each NULL or a
+ * NUL-terminated string.
+ */
+enum CarbonStatus carbon_vm_snapshot(struct CarbonVm *vm, const char *state, const char *memory);
+
+/**
+ * Stop the VM: [`carbon_vm_run`] returns with
+ * `CARBON_STOP_REASON_SHUTDOWN`. Before the run starts, it stops as soon
+ * as it does.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+ * not destroyed during the call.
+ */
+enum CarbonStatus carbon_vm_shutdown(struct CarbonVm *vm);
+
+/**
+ * Free the handle. A running VM must be stopped, and its run returned,
+ * first. `NULL` is ignored.
+ *
+ * # Safety
+ *
+ * `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`]
+ * not yet destroyed, with no call on it in progress. It is dangling
+ * afterwards.
+ */
+void carbon_vm_destroy(struct CarbonVm *vm);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CARBON_H */
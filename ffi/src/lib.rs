@@ -0,0 +1,755 @@
+//! Carbon's C API: the [`carbon::VmmBuilder`]/[`carbon::Vmm`] embedding
+//! API for programs not written in Rust.
+//!
+//! The library builds as `libcarbon_ffi.so` and `libcarbon_ffi.a`, declared
+//! by `include/carbon.h` (generated from this file). A VM is an opaque
+//! `CarbonVm` handle: created from a kernel, configured, run on one thread
+//! while others pause, snapshot or stop it, then destroyed:
+//!
+//! ```c
+//! CarbonVm *vm;
+//! if (carbon_vm_create("bzImage", &vm) != CARBON_STATUS_OK) {
+//!     fprintf(stderr, "carbon: %s\n", carbon_last_error());
+//!     return 1;
+//! }
+//! carbon_vm_set_memory(vm, 1 << 30);
+//! carbon_vm_add_disk(vm, "path=rootfs.img,ro");
+//! carbon_vm_set_serial(vm, "file=console.log");
+//!
+//! CarbonStop stop;
+//! CarbonStatus status = carbon_vm_run(vm, &stop); // until the VM stops
+//! carbon_vm_destroy(vm);
+//! ```
+//!
+//! Every call but the two below returns a [`CarbonStatus`]; after a failure,
+//! [`carbon_last_error`] says what went wrong. Strings are NUL-terminated
+//! UTF-8, and specs take the form the matching `carbon` flag does
+//! (`--disk`, `--serial`, `--vsock`). Configuring stops once the VM is
+//! built, which [`carbon_vm_run`] and [`carbon_vm_shutdown`] do. Pausing,
+//! resuming and snapshotting a VM whose run hasn't started fail, and leave
+//! it configurable; a shutdown stops the run as soon as it starts.
+//!
+//! A handle may be used from any thread, but must not be destroyed while
+//! a call on it is in progress. Each call checks its pointers for `NULL`,
+//! but can't tell a valid pointer from a dangling one: to Rust callers,
+//! the calls taking pointers are `unsafe`.
+
+#![cfg(target_os = "linux")]
+
+use carbon::devices::VsockConfig;
+use carbon::error::{CarbonError, EXIT_CONFIG, EXIT_GUEST_PANIC, EXIT_HOST};
+use carbon::events::EventLog;
+use carbon::hibernate::Hibernate;
+use carbon::{ConsoleConfig, DiskConfig, StopReason, Vmm, VmmBuilder};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// What a call returns.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarbonStatus {
+    Ok = 0,
+    /// A `NULL` pointer, a string that isn't UTF-8, or a spec that doesn't
+    /// parse.
+    InvalidArgument = 1,
+    /// Not now: configuring a VM already built, or pausing, resuming or
+    /// snapshotting one not yet running.
+    InvalidState = 2,
+    /// The VM's configuration doesn't work, e.g. a missing kernel (`carbon`
+    /// exits with 3).
+    Config = 3,
+    /// The host can't run the VM, e.g. without KVM (4).
+    Host = 4,
+    /// KVM failed to run the guest (5).
+    Guest = 5,
+    /// The guest kernel panicked (6).
+    GuestPanic = 6,
+    /// Pausing, resuming, snapshotting or stopping the VM failed.
+    Control = 7,
+    /// Carbon panicked; the handle must only be destroyed.
+    Panic = 8,
+}
+
+/// Why a VM stopped.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarbonStopReason {
+    /// The guest halted, shut down or hit a fatal exit.
+    GuestExit = 0,
+    /// The guest kernel reported a panic.
+    GuestPanic = 1,
+    /// The guest asked for a reset.
+    GuestReboot = 2,
+    /// The guest wrote `code` to the debug exit port.
+    DebugExit = 3,
+    /// The run timed out.
+    Timeout = 4,
+    /// Signal `code` stopped the VM.
+    Signal = 5,
+    /// [`carbon_vm_shutdown`] stopped the VM.
+    Shutdown = 6,
+    /// The guest printed exit status `code` on the console.
+    ExitStatus = 7,
+    /// The guest didn't boot within the boot timeout.
+    BootTimeout = 8,
+    /// The VM sat idle and was snapshotted (see
+    /// [`carbon_vm_set_hibernate_ms`]).
+    Hibernated = 9,
+}
+
+/// How a run ended.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CarbonStop {
+    pub reason: CarbonStopReason,
+    /// The debug exit code, the exit status or the signal number; 0
+    /// otherwise.
+    pub code: c_int,
+}
+
+/// A VM (opaque to C).
+pub struct CarbonVm {
+    state: Mutex<State>,
+}
+
+enum State {
+    /// Taking configuration; `None` only while a setter runs.
+    Configuring(Option<Box<VmmBuilder>>),
+    Built(Arc<Vmm>),
+}
+
+/// A failed call: its status, and the message for [`carbon_last_error`].
+struct Error(CarbonStatus, String);
+
+impl Error {
+    fn invalid(message: impl Into<String>) -> Self {
+        Self(CarbonStatus::InvalidArgument, message.into())
+    }
+}
+
+impl From<CarbonError> for Error {
+    fn from(e: CarbonError) -> Self {
+        let status = match e.exit_code() {
+            EXIT_CONFIG => CarbonStatus::Config,
+            EXIT_HOST => CarbonStatus::Host,
+            EXIT_GUEST_PANIC => CarbonStatus::GuestPanic,
+            _ => CarbonStatus::Guest,
+        };
+        Self(status, carbon::error::report(&e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self(CarbonStatus::Control, e.to_string())
+    }
+}
+
+thread_local! {
+    /// The message of the last call on this thread that failed.
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Run a call, recording its error and catching its panics.
+fn call(f: impl FnOnce() -> Result<(), Error>) -> CarbonStatus {
+    let (status, message) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return CarbonStatus::Ok,
+        Ok(Err(Error(status, message))) => (status, message),
+        Err(_) => (CarbonStatus::Panic, "Carbon panicked".to_string()),
+    };
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    status
+}
+
+/// # Safety
+///
+/// `vm` is NULL or a live handle, as each call documents.
+unsafe fn vm_ref<'a>(vm: *const CarbonVm) -> Result<&'a CarbonVm, Error> {
+    // SAFETY: a non-null handle comes from carbon_vm_create or
+    // carbon_vm_restore and, as documented, isn't destroyed during a call.
+    unsafe { vm.as_ref() }.ok_or_else(|| Error::invalid("the VM handle is NULL"))
+}
+
+/// # Safety
+///
+/// `s` is NULL or a NUL-terminated string.
+unsafe fn string<'a>(s: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if s.is_null() {
+        return Err(Error::invalid(format!("{name} is NULL")));
+    }
+    // SAFETY: a non-null string is NUL-terminated, as documented.
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map_err(|_| Error::invalid(format!("{name} isn't UTF-8")))
+}
+
+/// Parse the spec `s`, as the `carbon` flag `name` takes it.
+///
+/// # Safety
+///
+/// As for [`string`].
+unsafe fn spec<T: std::str::FromStr<Err = String>>(
+    s: *const c_char,
+    name: &str,
+) -> Result<T, Error> {
+    string(s, name)?
+        .parse()
+        .map_err(|e| Error::invalid(format!("invalid {name}: {e}")))
+}
+
+fn lock(vm: &CarbonVm) -> MutexGuard<'_, State> {
+    vm.state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Apply a setting to a VM still being configured.
+///
+/// # Safety
+///
+/// As for [`vm_ref`].
+unsafe fn configure(
+    vm: *const CarbonVm,
+    f: impl FnOnce(VmmBuilder) -> VmmBuilder,
+) -> Result<(), Error> {
+    let mut state = lock(vm_ref(vm)?);
+    let State::Configuring(builder) = &mut *state else {
+        return Err(Error(
+            CarbonStatus::InvalidState,
+            "the VM is built: it can't be configured any more".into(),
+        ));
+    };
+    let configured = f(*builder.take().expect("a setter panicked"));
+    *builder = Some(Box::new(configured));
+    Ok(())
+}
+
+/// The VM, built on first use.
+///
+/// # Safety
+///
+/// As for [`vm_ref`].
+unsafe fn built(vm: *const CarbonVm) -> Result<Arc<Vmm>, Error> {
+    let mut state = lock(vm_ref(vm)?);
+    if let State::Configuring(builder) = &mut *state {
+        let vmm = builder.take().expect("a setter panicked").build()?;
+        *state = State::Built(Arc::new(vmm));
+    }
+    match &*state {
+        State::Built(vmm) => Ok(vmm.clone()),
+        State::Configuring(_) => unreachable!(),
+    }
+}
+
+/// The VM, if built: checked without building it, for controls that
+/// need it running.
+///
+/// # Safety
+///
+/// As for [`vm_ref`].
+unsafe fn started(vm: *const CarbonVm) -> Result<Arc<Vmm>, Error> {
+    match &*lock(vm_ref(vm)?) {
+        State::Built(vmm) => Ok(vmm.clone()),
+        State::Configuring(_) => Err(Error(
+            CarbonStatus::InvalidState,
+            "the VM isn't running".into(),
+        )),
+    }
+}
+
+/// # Safety
+///
+/// `out` is NULL or writable.
+unsafe fn create(builder: VmmBuilder, out: *mut *mut CarbonVm) -> Result<(), Error> {
+    if out.is_null() {
+        return Err(Error::invalid("the handle's destination is NULL"));
+    }
+    let vm = Box::new(CarbonVm {
+        state: Mutex::new(State::Configuring(Some(Box::new(builder)))),
+    });
+    // SAFETY: `out` is non-null and, as documented, writable.
+    unsafe { *out = Box::into_raw(vm) };
+    Ok(())
+}
+
+/// Carbon's version, e.g. `0.1.0`.
+#[no_mangle]
+pub extern "C" fn carbon_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
+
+/// What went wrong in the last call on this thread that failed; empty if
+/// none has. Valid until the next failure on the thread.
+#[no_mangle]
+pub extern "C" fn carbon_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Create a VM booting the kernel at `kernel` (a bzImage, or an
+/// uncompressed vmlinux), with `carbon`'s defaults, storing its handle in
+/// `*out`.
+///
+/// # Safety
+///
+/// `kernel` is NULL or a NUL-terminated string, and `out` NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_create(
+    kernel: *const c_char,
+    out: *mut *mut CarbonVm,
+) -> CarbonStatus {
+    call(|| create(VmmBuilder::new(string(kernel, "the kernel")?), out))
+}
+
+/// Create a VM resuming the snapshot with state file `state` and memory
+/// file `memory`, storing its handle in `*out`.
+///
+/// # Safety
+///
+/// `state` and `memory` are each NULL or a NUL-terminated string, and `out`
+/// NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_restore(
+    state: *const c_char,
+    memory: *const c_char,
+    out: *mut *mut CarbonVm,
+) -> CarbonStatus {
+    call(|| {
+        let state = Path::new(string(state, "the state file")?);
+        let memory = Path::new(string(memory, "the memory file")?);
+        create(VmmBuilder::restore(state, memory)?, out)
+    })
+}
+
+/// Set the kernel command line.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `cmdline` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_cmdline(
+    vm: *mut CarbonVm,
+    cmdline: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let cmdline = string(cmdline, "the command line")?;
+        configure(vm, |builder| builder.cmdline(cmdline))
+    })
+}
+
+/// Boot with the initrd at `path`.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `path` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_initrd(
+    vm: *mut CarbonVm,
+    path: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let path = string(path, "the initrd")?;
+        configure(vm, |builder| builder.initrd(path))
+    })
+}
+
+/// Set guest memory, in bytes.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_memory(vm: *mut CarbonVm, bytes: u64) -> CarbonStatus {
+    call(|| configure(vm, |builder| builder.memory(bytes)))
+}
+
+/// Set the vCPU count.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_cpus(vm: *mut CarbonVm, cpus: u8) -> CarbonStatus {
+    call(|| {
+        if cpus == 0 {
+            return Err(Error::invalid("a VM needs at least one vCPU"));
+        }
+        configure(vm, |builder| builder.cpus(cpus))
+    })
+}
+
+/// Attach a disk, as `--disk` takes it (`path=rootfs.img,ro`), after
+/// those attached already.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `disk` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_add_disk(
+    vm: *mut CarbonVm,
+    disk: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let disk: DiskConfig = spec(disk, "--disk")?;
+        configure(vm, |builder| builder.disk(disk))
+    })
+}
+
+/// Set COM1's console, as `--serial` takes it (`file=console.log`, `pty`,
+/// ...). The default is the process's stdio.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `console` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_serial(
+    vm: *mut CarbonVm,
+    console: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let console: ConsoleConfig = spec(console, "--serial")?;
+        configure(vm, |builder| builder.serial(console))
+    })
+}
+
+/// Add a virtio-vsock device, as `--vsock` takes it (`cid=3,uds=PATH`).
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `vsock` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_vsock(
+    vm: *mut CarbonVm,
+    vsock: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let vsock: VsockConfig = spec(vsock, "--vsock")?;
+        configure(vm, |builder| builder.vsock(vsock))
+    })
+}
+
+/// Serve the control socket (see `carbon run --help`) on `path`.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `path` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_control_socket(
+    vm: *mut CarbonVm,
+    path: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let path = string(path, "the control socket")?;
+        configure(vm, |builder| builder.control_socket(path))
+    })
+}
+
+/// Stop the VM if it is still running after `timeout_ms` milliseconds.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_timeout_ms(
+    vm: *mut CarbonVm,
+    timeout_ms: u64,
+) -> CarbonStatus {
+    call(|| {
+        configure(vm, |builder| {
+            builder.timeout(Duration::from_millis(timeout_ms))
+        })
+    })
+}
+
+/// Stop the VM if the guest hasn't reached init within `timeout_ms`
+/// milliseconds.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_boot_timeout_ms(
+    vm: *mut CarbonVm,
+    timeout_ms: u64,
+) -> CarbonStatus {
+    call(|| {
+        configure(vm, |builder| {
+            builder.boot_timeout(Duration::from_millis(timeout_ms))
+        })
+    })
+}
+
+/// Snapshot the VM into the directory `dir` (`state.json` and
+/// `memory.bin`) and stop it once it has been idle for `idle_ms`
+/// milliseconds (at least 1), as `--hibernate-after` does.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `dir` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_hibernate_ms(
+    vm: *mut CarbonVm,
+    idle_ms: u64,
+    dir: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        if idle_ms == 0 {
+            return Err(Error::invalid("the idle time must be at least 1ms"));
+        }
+        let dir = string(dir, "the hibernation directory")?;
+        let hibernate = Hibernate::new(Duration::from_millis(idle_ms), dir);
+        configure(vm, |builder| builder.hibernate(hibernate))
+    })
+}
+
+/// Start with the vCPUs paused, until [`carbon_vm_resume`].
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_paused(vm: *mut CarbonVm) -> CarbonStatus {
+    call(|| configure(vm, VmmBuilder::paused))
+}
+
+/// Write the VM's lifecycle events to `fd` as JSON lines, naming it
+/// `vm_id`, as `--event-fd` does. The descriptor is the VM's from then on.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `vm_id` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_set_event_fd(
+    vm: *mut CarbonVm,
+    fd: c_int,
+    vm_id: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let vm_id = string(vm_id, "the VM ID")?;
+        vm_ref(vm)?;
+        let log = EventLog::from_fd(fd, vm_id)
+            .map_err(|e| Error::invalid(format!("invalid event fd {fd}: {e}")))?;
+        configure(vm, |builder| builder.event_log(log))
+    })
+}
+
+/// Build the VM and run it on the calling thread until it stops, storing
+/// how in `*stop` unless `stop` is `NULL`. A VM runs once.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `stop` is NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_run(vm: *mut CarbonVm, stop: *mut CarbonStop) -> CarbonStatus {
+    call(|| {
+        let outcome = built(vm)?.run()?;
+        let (reason, code) = match outcome.reason {
+            StopReason::GuestExit | StopReason::InitReached => (CarbonStopReason::GuestExit, 0),
+            StopReason::GuestPanic => (CarbonStopReason::GuestPanic, 0),
+            StopReason::GuestReboot => (CarbonStopReason::GuestReboot, 0),
+            StopReason::DebugExit(code) => (CarbonStopReason::DebugExit, code.into()),
+            StopReason::ExitStatus(code) => (CarbonStopReason::ExitStatus, code.into()),
+            StopReason::Timeout => (CarbonStopReason::Timeout, 0),
+            StopReason::BootTimeout => (CarbonStopReason::BootTimeout, 0),
+            StopReason::Signal(signal) => (CarbonStopReason::Signal, signal),
+            StopReason::Shutdown => (CarbonStopReason::Shutdown, 0),
+            StopReason::Hibernated => (CarbonStopReason::Hibernated, 0),
+        };
+        // SAFETY: a non-null `stop` is writable, as documented.
+        if let Some(stop) = unsafe { stop.as_mut() } {
+            *stop = CarbonStop { reason, code };
+        }
+        Ok(())
+    })
+}
+
+/// Stop the vCPUs of the running VM until [`carbon_vm_resume`].
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_pause(vm: *mut CarbonVm) -> CarbonStatus {
+    call(|| Ok(started(vm)?.pause()?))
+}
+
+/// Let the vCPUs of a paused VM run on.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_resume(vm: *mut CarbonVm) -> CarbonStatus {
+    call(|| Ok(started(vm)?.resume()?))
+}
+
+/// Snapshot the running VM: its state to `state`, guest RAM to `memory`,
+/// to restore with [`carbon_vm_restore`].
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call. `state` and `memory` are each NULL or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_snapshot(
+    vm: *mut CarbonVm,
+    state: *const c_char,
+    memory: *const c_char,
+) -> CarbonStatus {
+    call(|| {
+        let state = Path::new(string(state, "the state file")?);
+        let memory = Path::new(string(memory, "the memory file")?);
+        Ok(started(vm)?.snapshot(state, memory)?)
+    })
+}
+
+/// Stop the VM: [`carbon_vm_run`] returns with
+/// `CARBON_STOP_REASON_SHUTDOWN`. Before the run starts, it stops as soon
+/// as it does.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`],
+/// not destroyed during the call.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_shutdown(vm: *mut CarbonVm) -> CarbonStatus {
+    call(|| Ok(built(vm)?.shutdown()?))
+}
+
+/// Free the handle. A running VM must be stopped, and its run returned,
+/// first. `NULL` is ignored.
+///
+/// # Safety
+///
+/// `vm` is NULL or a handle from [`carbon_vm_create`] or [`carbon_vm_restore`]
+/// not yet destroyed, with no call on it in progress. It is dangling
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn carbon_vm_destroy(vm: *mut CarbonVm) {
+    if !vm.is_null() {
+        // SAFETY: the handle came from Box::into_raw in `create`, and is
+        // destroyed once, as documented.
+        drop(unsafe { Box::from_raw(vm) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        // SAFETY: carbon_last_error returns a valid C string.
+        unsafe { CStr::from_ptr(carbon_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn new_vm(kernel: &CStr) -> *mut CarbonVm {
+        let mut vm = std::ptr::null_mut();
+        // SAFETY: `kernel` is a C string and `vm` is writable.
+        let status = unsafe { carbon_vm_create(kernel.as_ptr(), &mut vm) };
+        assert_eq!(status, CarbonStatus::Ok);
+        vm
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        // SAFETY: every handle comes from carbon_vm_create, every string
+        // is a C string literal, and every other pointer is writable.
+        unsafe {
+            let mut vm = std::ptr::null_mut();
+            let status = carbon_vm_create(std::ptr::null(), &mut vm);
+            assert_eq!(status, CarbonStatus::InvalidArgument);
+            assert_eq!(last_error(), "the kernel is NULL");
+            assert!(vm.is_null());
+            let status = carbon_vm_set_memory(std::ptr::null_mut(), 1 << 30);
+            assert_eq!(status, CarbonStatus::InvalidArgument);
+
+            let vm = new_vm(c"bzImage");
+            let status = carbon_vm_add_disk(vm, c"path=a.img,bogus".as_ptr());
+            assert_eq!(status, CarbonStatus::InvalidArgument);
+            assert!(
+                last_error().starts_with("invalid --disk"),
+                "{}",
+                last_error()
+            );
+            assert_eq!(carbon_vm_set_cpus(vm, 0), CarbonStatus::InvalidArgument);
+            assert_eq!(
+                carbon_vm_set_hibernate_ms(vm, 0, c"/tmp".as_ptr()),
+                CarbonStatus::InvalidArgument
+            );
+            assert_eq!(
+                carbon_vm_set_hibernate_ms(vm, 1000, std::ptr::null()),
+                CarbonStatus::InvalidArgument
+            );
+            // A failed setting leaves the VM configurable
+            assert_eq!(carbon_vm_set_cpus(vm, 2), CarbonStatus::Ok);
+            carbon_vm_destroy(vm);
+            carbon_vm_destroy(std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        // SAFETY: as in test_invalid_arguments.
+        unsafe {
+            let vm = new_vm(c"/nonexistent/kernel");
+            assert_eq!(
+                carbon_vm_set_serial(vm, c"file=/dev/null".as_ptr()),
+                CarbonStatus::Ok
+            );
+            assert_eq!(carbon_vm_set_memory(vm, 64 << 20), CarbonStatus::Ok);
+            // Controls need a running VM, and don't build it
+            assert_eq!(carbon_vm_pause(vm), CarbonStatus::InvalidState);
+            assert!(last_error().contains("isn't running"), "{}", last_error());
+            assert_eq!(carbon_vm_resume(vm), CarbonStatus::InvalidState);
+            let status = carbon_vm_snapshot(vm, c"state.json".as_ptr(), c"memory.bin".as_ptr());
+            assert_eq!(status, CarbonStatus::InvalidState);
+            assert_eq!(carbon_vm_set_cpus(vm, 2), CarbonStatus::Ok);
+
+            let mut stop = CarbonStop {
+                reason: CarbonStopReason::Shutdown,
+                code: -1,
+            };
+            assert_eq!(carbon_vm_run(vm, &mut stop), CarbonStatus::Config);
+            assert!(
+                last_error().contains("/nonexistent/kernel"),
+                "{}",
+                last_error()
+            );
+            assert_eq!(stop.code, -1);
+            assert_eq!(carbon_vm_set_cpus(vm, 1), CarbonStatus::InvalidState);
+            assert_eq!(carbon_vm_run(vm, &mut stop), CarbonStatus::Config);
+            assert!(last_error().contains("already run"), "{}", last_error());
+            // Built, but no longer running
+            assert_eq!(carbon_vm_pause(vm), CarbonStatus::Control);
+            carbon_vm_destroy(vm);
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        // SAFETY: carbon_version returns a static C string.
+        let version = unsafe { CStr::from_ptr(carbon_version()) };
+        assert_eq!(version.to_str().unwrap(), env!("CARGO_PKG_VERSION"));
+    }
+}
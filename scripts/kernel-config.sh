@@ -215,6 +215,26 @@ $CFG --disable VIRTIO_BALLOON
 $CFG --disable VIRTIO_CONSOLE
 $CFG --disable VIRTIO_INPUT
 
+# Host <-> guest sockets without networking (--vsock)
+$CFG --enable VSOCKETS
+$CFG --enable VIRTIO_VSOCKETS
+
+# Shared host directories (--shared-dir)
+$CFG --enable FUSE_FS
+$CFG --enable VIRTIO_FS
+
+# Persistent memory with DAX (--pmem)
+$CFG --enable ZONE_DEVICE
+$CFG --enable LIBNVDIMM
+$CFG --enable BLK_DEV_PMEM
+$CFG --enable VIRTIO_PMEM
+$CFG --enable FS_DAX
+
+# 9P host directory shares (--9p)
+$CFG --enable NET_9P
+$CFG --enable NET_9P_VIRTIO
+$CFG --enable 9P_FS
+
 # KVM guest optimizations - critical for fast boot
 $CFG --enable HYPERVISOR_GUEST
 $CFG --enable KVM_GUEST
@@ -222,6 +242,10 @@ $CFG --enable PARAVIRT
 $CFG --enable PARAVIRT_CLOCK
 $CFG --enable PARAVIRT_SPINLOCKS
 
+# Host clock sync over the KVM clock-pairing hypercall (/dev/ptp0)
+$CFG --enable PTP_1588_CLOCK
+$CFG --enable PTP_1588_CLOCK_KVM
+
 # Essential filesystems only
 $CFG --enable EXT4_FS
 $CFG --enable TMPFS
@@ -0,0 +1,1052 @@
+//! Firecracker-compatible HTTP API (`carbon run --api-sock PATH`).
+//!
+//! Instead of booting from flags, Carbon serves Firecracker's REST API on a
+//! Unix socket and waits for a client to describe the VM and start it, so
+//! tooling written against Firecracker (firecracker-go-sdk, orchestrators
+//! in the style of ignite) can drive Carbon unchanged:
+//!
+//! | Request                      | Effect                                     |
+//! | ---------------------------- | ------------------------------------------ |
+//! | `GET /`                      | The instance: ID and state                 |
+//! | `GET /version`               | Carbon's version                           |
+//! | `PUT /boot-source`           | Kernel, initrd and kernel command line     |
+//! | `PUT /drives/{id}`           | Add or replace a disk                      |
+//! | `GET`, `PUT /machine-config` | vCPUs and memory                           |
+//! | `PUT /vsock`                 | The vsock device                           |
+//! | `PUT /logger`                | Carbon's log level                         |
+//! | `PUT /actions`               | `InstanceStart`, or `SendCtrlAltDel`       |
+//! | `PATCH /vm`                  | `{"state": "Paused"}` or `"Resumed"`       |
+//! | `PUT /snapshot/create`       | Write a snapshot of the running VM         |
+//! | `PUT /snapshot/load`         | Start the VM from a snapshot instead       |
+//!
+//! Bodies are Firecracker's, and so are the answers: `204 No Content` once
+//! a change is made, `200 OK` with a JSON body for a `GET`, and
+//! `400 Bad Request` with `{"fault_message": "..."}` for anything refused.
+//! Fields Firecracker has but Carbon can't honour are refused rather than
+//! ignored: CPU templates, huge pages, dirty page tracking and diff
+//! snapshots, userfaultfd memory backends, and a log or metrics file.
+//! Carbon has no network devices (guests talk to the host over vsock), so
+//! `PUT /network-interfaces/{id}` is refused too.
+//!
+//! Until `InstanceStart` the VM is only a description: 1 vCPU and 128 MiB
+//! of memory, as Firecracker's, until `PUT /machine-config`. A drive marked
+//! `is_root_device` is attached first, as `/dev/vda`, and `root=` added to
+//! the command line for it unless the boot arguments name a root already.
+//! Drive rate limiters become the disk's `iops` and `bw` limits (see
+//! [`crate::devices::virtio::rate_limiter`]), `cache_type` is taken as
+//! Carbon's `writeback`, and `io_engine` is left to Carbon. A drive with a
+//! `socket` is a vhost-user-blk backend's.
+//!
+//! `InstanceStart` (or `snapshot/load`) hands the VM to the main thread,
+//! which runs it until it stops; Carbon then exits as `carbon` would, with
+//! the API served until then. The description can't change once the VM is
+//! started. `SendCtrlAltDel` stops the VM, as it stops a Firecracker guest
+//! that reboots on it. A VM that fails to boot after `InstanceStart` was
+//! answered exits Carbon with the error, as a failed `carbon` run does.
+//!
+//! Each client connection is served on its own thread, one request at a
+//! time, kept alive until the client closes it or asks to.
+
+use crate::cleanup::{self, CleanupGuard};
+use crate::devices::virtio::rate_limiter::{Bucket, RateLimit};
+use crate::devices::{DiskOptions, Transport, VsockConfig};
+use crate::error::{self, CarbonError};
+use crate::event_loop::StopSignals;
+use crate::kvm::Topology;
+use crate::logging::{self, Level};
+use crate::vmm::{DiskConfig, RunOutcome, DEFAULT_CMDLINE};
+use crate::{Vmm, VmmBuilder};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Largest request body accepted, as Firecracker's.
+const MAX_BODY: usize = 50 * 1024;
+
+/// Longest request or header line accepted.
+const MAX_LINE: u64 = 8 * 1024;
+
+/// How often the main thread checks for a stop signal before the VM starts.
+const SIGNAL_POLL: Duration = Duration::from_millis(100);
+
+/// What Firecracker answers a request to change the VM once it runs.
+const AFTER_START: &str = "The requested operation is not supported after starting the microVM.";
+
+/// What Firecracker answers a request that needs a running VM before it
+/// starts.
+const BEFORE_START: &str = "The requested operation is not supported before starting the microVM.";
+
+/// Serve the API on `path` until a client starts the VM, then run it on
+/// this thread until it stops. `id` is the instance ID `GET /` answers.
+pub fn serve(path: &Path, id: &str) -> Result<RunOutcome, CarbonError> {
+    let api_error = |source| CarbonError::Api {
+        path: path.display().to_string(),
+        source,
+    };
+    let (listener, _guard) = bind(path).map_err(api_error)?;
+    let (started, start) = mpsc::channel();
+    let api = Arc::new(Api::new(id, started));
+    // Until the VM installs its own, a stop signal ends the wait
+    let signals = StopSignals::install().map_err(api_error)?;
+    std::thread::Builder::new()
+        .name("api".into())
+        .spawn(move || accept(&listener, &api))
+        .map_err(api_error)?;
+
+    let vmm = loop {
+        match start.recv_timeout(SIGNAL_POLL) {
+            Ok(vmm) => break vmm,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(signal) = signals.take() {
+                    return Err(CarbonError::Stopped(signal));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(api_error(io::Error::other("stopped accepting clients")));
+            }
+        }
+    };
+    drop(signals);
+    vmm.run()
+}
+
+/// Listen on `path`, replacing any stale socket there. The socket is
+/// removed when the guard drops, or on exit.
+fn bind(path: &Path) -> io::Result<(UnixListener, CleanupGuard)> {
+    let (listener, guard) = cleanup::bind_socket(path, "remove API socket")?;
+    info!("[api] Serving on {}", path.display());
+    Ok((listener, guard))
+}
+
+/// Serve each client that connects on a thread of its own.
+fn accept(listener: &UnixListener, api: &Arc<Api>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("[api] Failed to accept a client: {}", e);
+                continue;
+            }
+        };
+        let api = api.clone();
+        let spawned = std::thread::Builder::new()
+            .name("api-client".into())
+            .spawn(move || {
+                if let Err(e) = serve_client(&api, stream) {
+                    debug!("[api] Client dropped: {}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("[api] Failed to serve a client: {}", e);
+        }
+    }
+}
+
+/// Answer a client's requests until it hangs up.
+fn serve_client(api: &Api, stream: UnixStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // The connection is out of step: answer, and drop it
+                return Response::fault(e.to_string()).write_to(&mut writer);
+            }
+            Err(e) => return Err(e),
+        };
+        let response = api.handle(&request.method, &request.path, &request.body);
+        debug!(
+            "[api] {} {}: {}",
+            request.method, request.path, response.status
+        );
+        response.write_to(&mut writer)?;
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+
+/// An HTTP request, as far as the API reads one.
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    /// The path, without a query or trailing `/`.
+    path: String,
+    body: Vec<u8>,
+    /// The client asked for the connection to be closed after the answer.
+    close: bool,
+}
+
+/// Read the next request on a connection: `None` once the client hangs up
+/// between requests.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        return Err(invalid(format!("invalid request line {line:?}")));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid(format!("unsupported protocol {version:?}")));
+    }
+    let path = target.split('?').next().unwrap_or_default();
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    };
+
+    let mut close = version == "HTTP/1.0";
+    let mut length = 0;
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| invalid("request cut short".into()))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("invalid header {line:?}")))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = value
+                .parse()
+                .map_err(|_| invalid(format!("invalid Content-Length {value:?}")))?;
+            if length > MAX_BODY {
+                return Err(invalid(format!(
+                    "request body of {length} bytes is over the {MAX_BODY} byte limit"
+                )));
+            }
+        } else if name.eq_ignore_ascii_case("connection") {
+            close = value.eq_ignore_ascii_case("close");
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(invalid("chunked request bodies aren't supported".into()));
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        body,
+        close,
+    }))
+}
+
+/// The next line, without its line ending: `None` at the end of input.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("request line too long, or cut short".into()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("request isn't UTF-8".into()))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// An answer to a request.
+#[derive(Debug, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    /// JSON.
+    body: Option<String>,
+}
+
+impl Response {
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            body: None,
+        }
+    }
+
+    fn json(value: &impl Serialize) -> Self {
+        Self {
+            status: 200,
+            // Plain structs of strings and numbers
+            body: Some(serde_json::to_string(value).unwrap()),
+        }
+    }
+
+    fn fault(message: String) -> Self {
+        Self {
+            status: 400,
+            body: Some(serde_json::json!({ "fault_message": message }).to_string()),
+        }
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            _ => "Bad Request",
+        };
+        write!(
+            out,
+            "HTTP/1.1 {} {}\r\nServer: Carbon API\r\n",
+            self.status, reason
+        )?;
+        if let Some(body) = &self.body {
+            write!(
+                out,
+                "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+        } else {
+            out.write_all(b"\r\n")?;
+        }
+        out.flush()
+    }
+}
+
+/// `PUT /boot-source`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BootSource {
+    kernel_image_path: String,
+    boot_args: Option<String>,
+    initrd_path: Option<String>,
+}
+
+/// `PUT /drives/{id}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Drive {
+    drive_id: String,
+    /// The image; absent for a vhost-user drive.
+    path_on_host: Option<String>,
+    is_root_device: bool,
+    is_read_only: Option<bool>,
+    /// The root partition, when the root device is partitioned.
+    partuuid: Option<String>,
+    cache_type: Option<String>,
+    rate_limiter: Option<DriveRateLimiter>,
+    io_engine: Option<String>,
+    /// A vhost-user-blk backend's socket.
+    socket: Option<String>,
+}
+
+/// A drive's `rate_limiter`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DriveRateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+/// Firecracker's token bucket: `size` tokens refilled every `refill_time`
+/// milliseconds, plus `one_time_burst` tokens to start.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenBucket {
+    size: u64,
+    one_time_burst: Option<u64>,
+    refill_time: u64,
+}
+
+impl TokenBucket {
+    /// The bucket as Carbon's: the same rate per second, holding `size`
+    /// and the one-time burst. A bucket of nothing is no limit.
+    fn bucket(&self) -> Option<Bucket> {
+        if self.size == 0 || self.refill_time == 0 {
+            return None;
+        }
+        let rate = (self.size.saturating_mul(1000) / self.refill_time).max(1);
+        Some(Bucket {
+            rate,
+            burst: Some(self.size.saturating_add(self.one_time_burst.unwrap_or(0))),
+        })
+    }
+}
+
+impl Drive {
+    fn read_only(&self) -> bool {
+        self.is_read_only.unwrap_or(false)
+    }
+
+    /// The drive as a Carbon disk.
+    fn disk(&self) -> Result<DiskConfig, String> {
+        let (path, vhost_user) = match (&self.path_on_host, &self.socket) {
+            (Some(path), None) => (path.clone(), false),
+            (None, Some(socket)) => (socket.clone(), true),
+            _ => return Err("a drive needs one of path_on_host and socket".into()),
+        };
+        if let Some(cache) = self.cache_type.as_deref() {
+            if !matches!(cache, "Unsafe" | "Writeback") {
+                return Err(format!("invalid cache_type {cache:?}"));
+            }
+        }
+        if let Some(engine) = self.io_engine.as_deref() {
+            if !matches!(engine, "Sync" | "Async") {
+                return Err(format!("invalid io_engine {engine:?}"));
+            }
+        }
+        let mut options = DiskOptions {
+            read_only: self.read_only(),
+            ..Default::default()
+        };
+        if let Some(limiter) = self.rate_limiter {
+            if vhost_user {
+                return Err("a vhost-user drive's backend sets its own rate limits".into());
+            }
+            options.rate_limit = RateLimit {
+                iops: limiter.ops.and_then(|bucket| bucket.bucket()),
+                bandwidth: limiter.bandwidth.and_then(|bucket| bucket.bucket()),
+            };
+        }
+        if vhost_user && options.read_only {
+            return Err("a vhost-user drive's backend decides if it is read-only".into());
+        }
+        Ok(DiskConfig {
+            path,
+            options,
+            fd: None,
+            vhost_user,
+            transport: Transport::default(),
+        })
+    }
+}
+
+/// `GET` and `PUT /machine-config`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MachineConfig {
+    vcpu_count: u8,
+    mem_size_mib: u64,
+    #[serde(default)]
+    smt: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu_template: Option<String>,
+    #[serde(default)]
+    track_dirty_pages: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    huge_pages: Option<String>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            vcpu_count: 1,
+            mem_size_mib: 128,
+            smt: false,
+            cpu_template: None,
+            track_dirty_pages: false,
+            huge_pages: None,
+        }
+    }
+}
+
+impl MachineConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.mem_size_mib == 0 {
+            return Err("mem_size_mib must be at least 1".into());
+        }
+        if self.cpu_template.as_deref().is_some_and(|t| t != "None") {
+            return Err("CPU templates aren't supported".into());
+        }
+        if self.huge_pages.as_deref().is_some_and(|h| h != "None") {
+            return Err("huge pages aren't supported".into());
+        }
+        if self.track_dirty_pages {
+            return Err("dirty page tracking isn't supported (snapshots are full)".into());
+        }
+        self.topology().map(|_| ())
+    }
+
+    /// With SMT, the vCPUs pair up as the threads of each core.
+    fn topology(&self) -> Result<Topology, String> {
+        if self.smt && self.vcpu_count > 1 {
+            if !self.vcpu_count.is_multiple_of(2) {
+                return Err("with smt, vcpu_count must be 1 or even".into());
+            }
+            Topology::resolve(Some(self.vcpu_count.into()), Some("threads=2"))
+        } else {
+            Topology::resolve(Some(self.vcpu_count.into()), None)
+        }
+    }
+}
+
+/// `PUT /vsock`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Vsock {
+    guest_cid: u32,
+    uds_path: String,
+    /// Names the device; a VM has one.
+    #[serde(rename = "vsock_id")]
+    _vsock_id: Option<IgnoredAny>,
+}
+
+/// `PUT /logger`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Logger {
+    level: Option<String>,
+    log_path: Option<String>,
+    /// How Firecracker formats its log; Carbon's is its own.
+    #[serde(rename = "show_level")]
+    _show_level: Option<IgnoredAny>,
+    #[serde(rename = "show_log_origin")]
+    _show_log_origin: Option<IgnoredAny>,
+    #[serde(rename = "module")]
+    _module: Option<IgnoredAny>,
+}
+
+/// `PUT /actions`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Action {
+    action_type: String,
+}
+
+/// `PATCH /vm`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VmState {
+    state: String,
+}
+
+/// `PUT /snapshot/create`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SnapshotCreate {
+    snapshot_type: Option<String>,
+    snapshot_path: String,
+    mem_file_path: String,
+    /// The format version to write; Carbon has one.
+    #[serde(rename = "version")]
+    _version: Option<IgnoredAny>,
+}
+
+/// `PUT /snapshot/load`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SnapshotLoad {
+    snapshot_path: String,
+    mem_file_path: Option<String>,
+    mem_backend: Option<MemBackend>,
+    #[serde(default)]
+    enable_diff_snapshots: bool,
+    #[serde(default)]
+    track_dirty_pages: bool,
+    #[serde(default)]
+    resume_vm: bool,
+}
+
+/// Where a snapshot's memory comes from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemBackend {
+    backend_path: String,
+    backend_type: String,
+}
+
+/// `GET /`.
+#[derive(Debug, Serialize)]
+struct InstanceInfo<'a> {
+    app_name: &'a str,
+    id: &'a str,
+    state: &'a str,
+    vmm_version: &'a str,
+}
+
+/// The VM a client is describing, and then running.
+#[derive(Default)]
+struct Setup {
+    boot: Option<BootSource>,
+    /// In the order they were first put.
+    drives: Vec<Drive>,
+    machine: MachineConfig,
+    vsock: Option<VsockConfig>,
+    /// Set once started.
+    vm: Option<Arc<Vmm>>,
+}
+
+impl Setup {
+    fn not_started(&self) -> Result<(), String> {
+        match self.vm {
+            Some(_) => Err(AFTER_START.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn running(&self) -> Result<&Vmm, String> {
+        self.vm.as_deref().ok_or_else(|| BEFORE_START.into())
+    }
+
+    /// The VM described, ready to start.
+    fn builder(&self) -> Result<VmmBuilder, String> {
+        let boot = self
+            .boot
+            .as_ref()
+            .ok_or("no boot source: PUT /boot-source first")?;
+        let mut cmdline = boot
+            .boot_args
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CMDLINE.into());
+        let root = self.drives.iter().find(|drive| drive.is_root_device);
+        if let Some(root) = root {
+            if !cmdline
+                .split_whitespace()
+                .any(|arg| arg.starts_with("root="))
+            {
+                let device = match &root.partuuid {
+                    Some(uuid) => format!("PARTUUID={uuid}"),
+                    None => "/dev/vda".into(),
+                };
+                let mode = if root.read_only() { "ro" } else { "rw" };
+                cmdline.push_str(&format!(" root={device} {mode}"));
+            }
+        }
+
+        let mut builder = VmmBuilder::new(boot.kernel_image_path.as_str())
+            .cmdline(cmdline)
+            .memory(self.machine.mem_size_mib << 20)
+            .topology(self.machine.topology()?);
+        if let Some(initrd) = &boot.initrd_path {
+            builder = builder.initrd(initrd.as_str());
+        }
+        let others = self.drives.iter().filter(|drive| !drive.is_root_device);
+        for drive in root.into_iter().chain(others) {
+            builder = builder.disk(drive.disk()?);
+        }
+        if let Some(vsock) = &self.vsock {
+            builder = builder.vsock(vsock.clone());
+        }
+        Ok(builder)
+    }
+}
+
+/// The API's state, shared by every client.
+struct Api {
+    id: String,
+    setup: Mutex<Setup>,
+    /// Hands the started VM to the thread that runs it.
+    started: Sender<Arc<Vmm>>,
+}
+
+impl Api {
+    fn new(id: &str, started: Sender<Arc<Vmm>>) -> Self {
+        Self {
+            id: id.to_string(),
+            setup: Mutex::new(Setup::default()),
+            started,
+        }
+    }
+
+    /// Answer one request.
+    fn handle(&self, method: &str, path: &str, body: &[u8]) -> Response {
+        self.route(method, path, body)
+            .unwrap_or_else(Response::fault)
+    }
+
+    fn route(&self, method: &str, path: &str, body: &[u8]) -> Result<Response, String> {
+        let mut setup = self.setup.lock().unwrap_or_else(|e| e.into_inner());
+        match (method, path) {
+            ("GET", "/") => {
+                let state = match setup.vm.as_deref() {
+                    None => "Not started",
+                    Some(vm) if vm.is_paused() => "Paused",
+                    Some(_) => "Running",
+                };
+                Ok(Response::json(&InstanceInfo {
+                    app_name: "carbon",
+                    id: &self.id,
+                    state,
+                    vmm_version: env!("CARGO_PKG_VERSION"),
+                }))
+            }
+            ("GET", "/version") => Ok(Response::json(&serde_json::json!({
+                "firecracker_version": env!("CARGO_PKG_VERSION"),
+            }))),
+            ("GET", "/machine-config") => Ok(Response::json(&setup.machine)),
+            ("PUT", "/machine-config") => {
+                setup.not_started()?;
+                let machine: MachineConfig = parse(body)?;
+                machine.validate()?;
+                setup.machine = machine;
+                Ok(Response::no_content())
+            }
+            ("PUT", "/boot-source") => {
+                setup.not_started()?;
+                let boot: BootSource = parse(body)?;
+                let paths = [Some(&boot.kernel_image_path), boot.initrd_path.as_ref()];
+                for path in paths.into_iter().flatten() {
+                    std::fs::File::open(path).map_err(|e| format!("failed to open {path}: {e}"))?;
+                }
+                setup.boot = Some(boot);
+                Ok(Response::no_content())
+            }
+            ("PUT", _) if path.starts_with("/drives/") => {
+                setup.not_started()?;
+                let drive: Drive = parse(body)?;
+                if path["/drives/".len()..] != drive.drive_id {
+                    return Err("drive_id must match the drive in the path".into());
+                }
+                drive.disk()?;
+                if drive.is_root_device
+                    && setup
+                        .drives
+                        .iter()
+                        .any(|d| d.is_root_device && d.drive_id != drive.drive_id)
+                {
+                    return Err("only one drive can be the root device".into());
+                }
+                match setup
+                    .drives
+                    .iter_mut()
+                    .find(|d| d.drive_id == drive.drive_id)
+                {
+                    Some(existing) => *existing = drive,
+                    None => setup.drives.push(drive),
+                }
+                Ok(Response::no_content())
+            }
+            ("PUT", "/vsock") => {
+                setup.not_started()?;
+                let vsock: Vsock = parse(body)?;
+                // 0-2 are reserved
+                if !(3..u32::MAX).contains(&vsock.guest_cid) {
+                    return Err(format!("guest_cid {} is reserved", vsock.guest_cid));
+                }
+                setup.vsock = Some(VsockConfig {
+                    cid: vsock.guest_cid.into(),
+                    uds: vsock.uds_path.into(),
+                    transport: Transport::default(),
+                });
+                Ok(Response::no_content())
+            }
+            ("PUT", "/logger") => {
+                let logger: Logger = parse(body)?;
+                if logger.log_path.is_some() {
+                    return Err("log_path isn't supported: Carbon logs to stderr".into());
+                }
+                if let Some(level) = &logger.level {
+                    logging::set_max_level(match level.to_ascii_lowercase().as_str() {
+                        "error" => Level::Error,
+                        "warning" | "warn" => Level::Warn,
+                        "info" => Level::Info,
+                        "debug" => Level::Debug,
+                        "trace" => Level::Trace,
+                        _ => return Err(format!("invalid log level {level:?}")),
+                    });
+                }
+                Ok(Response::no_content())
+            }
+            ("PUT", "/actions") => {
+                let action: Action = parse(body)?;
+                match action.action_type.as_str() {
+                    "InstanceStart" => {
+                        setup.not_started()?;
+                        let vmm = setup.builder()?.build().map_err(|e| error::report(&e))?;
+                        self.start(&mut setup, vmm)?;
+                    }
+                    "SendCtrlAltDel" => {
+                        setup
+                            .running()?
+                            .shutdown()
+                            .map_err(|e| format!("failed to stop the VM: {e}"))?;
+                    }
+                    "FlushMetrics" => return Err("metrics aren't supported".into()),
+                    other => return Err(format!("invalid action_type {other:?}")),
+                }
+                Ok(Response::no_content())
+            }
+            ("PATCH", "/vm") => {
+                let state: VmState = parse(body)?;
+                let vm = setup.running()?;
+                match state.state.as_str() {
+                    "Paused" => {
+                        vm.pause().map_err(|e| format!("failed to pause: {e}"))?;
+                        info!("[VMM] Paused");
+                    }
+                    "Resumed" => {
+                        vm.resume().map_err(|e| format!("failed to resume: {e}"))?;
+                        info!("[VMM] Resumed");
+                    }
+                    other => return Err(format!("invalid state {other:?}")),
+                }
+                Ok(Response::no_content())
+            }
+            ("PUT", "/snapshot/create") => {
+                let request: SnapshotCreate = parse(body)?;
+                if request
+                    .snapshot_type
+                    .as_deref()
+                    .is_some_and(|t| t != "Full")
+                {
+                    return Err("only Full snapshots are supported".into());
+                }
+                let (state, memory) = (&request.snapshot_path, &request.mem_file_path);
+                setup
+                    .running()?
+                    .snapshot(Path::new(state), Path::new(memory))
+                    .map_err(|e| format!("failed to snapshot to {state}: {e}"))?;
+                info!("[VMM] Snapshot written to {} and {}", state, memory);
+                Ok(Response::no_content())
+            }
+            ("PUT", "/snapshot/load") => {
+                setup.not_started()?;
+                let request: SnapshotLoad = parse(body)?;
+                if request.enable_diff_snapshots || request.track_dirty_pages {
+                    return Err("dirty page tracking isn't supported (snapshots are full)".into());
+                }
+                let memory = match (&request.mem_file_path, &request.mem_backend) {
+                    (Some(path), None) => path,
+                    (None, Some(backend)) if backend.backend_type == "File" => {
+                        &backend.backend_path
+                    }
+                    (None, Some(backend)) => {
+                        return Err(format!(
+                            "memory backend {:?} isn't supported",
+                            backend.backend_type
+                        ));
+                    }
+                    _ => return Err("give one of mem_file_path and mem_backend".into()),
+                };
+                let mut builder =
+                    VmmBuilder::restore(Path::new(&request.snapshot_path), Path::new(memory))
+                        .map_err(|e| error::report(&e))?;
+                if !request.resume_vm {
+                    builder = builder.paused();
+                }
+                let vmm = builder.build().map_err(|e| error::report(&e))?;
+                self.start(&mut setup, vmm)?;
+                Ok(Response::no_content())
+            }
+            ("PUT", _) if path.starts_with("/network-interfaces/") => {
+                Err("Carbon has no network devices; reach the guest over vsock instead".into())
+            }
+            _ => Err(format!(
+                "Invalid request method and/or path: {method} {path}."
+            )),
+        }
+    }
+
+    /// Hand `vmm` to the thread that runs it.
+    fn start(&self, setup: &mut Setup, vmm: Vmm) -> Result<(), String> {
+        let vmm = Arc::new(vmm);
+        self.started
+            .send(vmm.clone())
+            .map_err(|_| "Carbon is exiting".to_string())?;
+        info!("[api] Starting VM {}", self.id);
+        setup.vm = Some(vmm);
+        Ok(())
+    }
+}
+
+/// A request body as `T`.
+fn parse<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, String> {
+    serde_json::from_slice(body).map_err(|e| format!("invalid request body: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::mpsc::Receiver;
+
+    fn api() -> (Api, Receiver<Arc<Vmm>>) {
+        let (started, start) = mpsc::channel();
+        (Api::new("test-vm", started), start)
+    }
+
+    fn put(api: &Api, path: &str, body: &str) -> Response {
+        api.handle("PUT", path, body.as_bytes())
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("carbon-api-{}-{name}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_request() {
+        let input = b"PUT /drives/rootfs/?x=1 HTTP/1.1\r\nHost: localhost\r\n\
+            Content-Length: 2\r\n\r\n{}GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut reader = &input[..];
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            request,
+            Request {
+                method: "PUT".into(),
+                path: "/drives/rootfs".into(),
+                body: b"{}".to_vec(),
+                close: false,
+            }
+        );
+        let request = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!((request.path.as_str(), request.close), ("/", true));
+        assert!(read_request(&mut reader).unwrap().is_none());
+
+        for input in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / SPDY/3\r\n\r\n",
+            b"PUT / HTTP/1.1\r\nContent-Length: 999999\r\n\r\n",
+            b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost",
+        ] {
+            let err = read_request(&mut &input[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn test_response() {
+        let mut out = Vec::new();
+        Response::no_content().write_to(&mut out).unwrap();
+        assert_eq!(
+            out,
+            b"HTTP/1.1 204 No Content\r\nServer: Carbon API\r\n\r\n"
+        );
+
+        let mut out = Vec::new();
+        Response::fault("no".into()).write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{out}");
+        assert!(out.ends_with("Content-Length: 22\r\n\r\n{\"fault_message\":\"no\"}"));
+    }
+
+    #[test]
+    fn test_configure_and_start() {
+        let (api, start) = api();
+        let kernel = temp_file("kernel");
+        let info = api.handle("GET", "/", b"");
+        assert_eq!(info.status, 200);
+        assert!(info.body.unwrap().contains("\"state\":\"Not started\""));
+        let machine = api.handle("GET", "/machine-config", b"");
+        assert_eq!(
+            machine.body.as_deref(),
+            Some(r#"{"vcpu_count":1,"mem_size_mib":128,"smt":false,"track_dirty_pages":false}"#)
+        );
+
+        // Nothing to boot yet
+        let response = put(&api, "/actions", r#"{"action_type": "InstanceStart"}"#);
+        assert_eq!(response.status, 400);
+
+        let body = format!(
+            r#"{{"kernel_image_path": "{}", "boot_args": "console=ttyS0"}}"#,
+            kernel.display()
+        );
+        assert_eq!(put(&api, "/boot-source", &body).status, 204);
+        let body = r#"{"vcpu_count": 4, "mem_size_mib": 256, "smt": true}"#;
+        assert_eq!(put(&api, "/machine-config", body).status, 204);
+        let body = r#"{"drive_id": "data", "path_on_host": "/data.img", "is_root_device": false,
+            "rate_limiter": {"ops": {"size": 100, "refill_time": 1000}}}"#;
+        assert_eq!(put(&api, "/drives/data", body).status, 204);
+        let body = r#"{"drive_id": "rootfs", "path_on_host": "/root.img",
+            "is_root_device": true, "is_read_only": true}"#;
+        assert_eq!(put(&api, "/drives/rootfs", body).status, 204);
+        let body = r#"{"vsock_id": "vsock0", "guest_cid": 3, "uds_path": "/tmp/v.sock"}"#;
+        assert_eq!(put(&api, "/vsock", body).status, 204);
+
+        let response = put(&api, "/actions", r#"{"action_type": "InstanceStart"}"#);
+        assert_eq!(response, Response::no_content());
+        let vmm = start.try_recv().unwrap();
+        let config = vmm.config();
+        assert_eq!(config.cmdline, "console=ttyS0 root=/dev/vda ro");
+        assert_eq!(config.mem_size, 256 << 20);
+        assert_eq!(config.topology.cpus(), 4);
+        assert_eq!(config.topology.threads, 2);
+        let paths: Vec<&str> = config.disks.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["/root.img", "/data.img"]);
+        assert!(config.disks[0].options.read_only);
+        assert_eq!(
+            config.disks[1].options.rate_limit.to_string(),
+            "iops=100,iops-burst=100"
+        );
+        assert_eq!(config.vsock.as_ref().unwrap().cid, 3);
+
+        // The description is fixed once started
+        let info = api.handle("GET", "/", b"").body.unwrap();
+        assert!(info.contains("\"state\":\"Running\""), "{info}");
+        let response = put(
+            &api,
+            "/machine-config",
+            r#"{"vcpu_count": 1, "mem_size_mib": 64}"#,
+        );
+        assert_eq!(response, Response::fault(AFTER_START.into()));
+        let response = put(&api, "/actions", r#"{"action_type": "InstanceStart"}"#);
+        assert_eq!(response.status, 400);
+        std::fs::remove_file(&kernel).unwrap();
+    }
+
+    #[test]
+    fn test_refused() {
+        let (api, _start) = api();
+        let refused = [
+            (
+                "/boot-source",
+                r#"{"kernel_image_path": "/nonexistent/vmlinux"}"#,
+            ),
+            ("/machine-config", r#"{"vcpu_count": 2, "mem_size_mib": 0}"#),
+            (
+                "/machine-config",
+                r#"{"vcpu_count": 3, "mem_size_mib": 64, "smt": true}"#,
+            ),
+            (
+                "/machine-config",
+                r#"{"vcpu_count": 1, "mem_size_mib": 64, "cpu_template": "T2"}"#,
+            ),
+            (
+                "/machine-config",
+                r#"{"vcpu_count": 1, "mem_size_mib": 64, "mystery": 1}"#,
+            ),
+            (
+                "/drives/a",
+                r#"{"drive_id": "b", "path_on_host": "/a.img", "is_root_device": false}"#,
+            ),
+            ("/drives/a", r#"{"drive_id": "a", "is_root_device": false}"#),
+            ("/vsock", r#"{"guest_cid": 2, "uds_path": "/tmp/v.sock"}"#),
+            ("/network-interfaces/eth0", r#"{"iface_id": "eth0"}"#),
+            ("/logger", r#"{"log_path": "/tmp/fc.log"}"#),
+            ("/actions", r#"{"action_type": "FlushMetrics"}"#),
+            ("/actions", r#"{"action_type": "SendCtrlAltDel"}"#),
+            (
+                "/snapshot/create",
+                r#"{"snapshot_path": "s", "mem_file_path": "m"}"#,
+            ),
+            ("/metrics", r#"{"metrics_path": "/tmp/m"}"#),
+            ("/machine-config", "not json"),
+        ];
+        for (path, body) in refused {
+            let response = put(&api, path, body);
+            assert_eq!(response.status, 400, "{path} {body}");
+            assert!(response.body.unwrap().contains("fault_message"));
+        }
+        assert_eq!(
+            api.handle("PATCH", "/vm", br#"{"state": "Paused"}"#).status,
+            400
+        );
+        assert_eq!(api.handle("DELETE", "/", b"").status, 400);
+
+        // A second root device
+        let root = |id: &str| {
+            format!(r#"{{"drive_id": "{id}", "path_on_host": "/r.img", "is_root_device": true}}"#)
+        };
+        assert_eq!(put(&api, "/drives/a", &root("a")).status, 204);
+        assert_eq!(put(&api, "/drives/a", &root("a")).status, 204);
+        assert_eq!(put(&api, "/drives/b", &root("b")).status, 400);
+    }
+}
@@ -0,0 +1,251 @@
+//! Carbon from async code: [`AsyncVmm`].
+//!
+//! A [`Vmm`] runs on the thread that calls [`Vmm::run`], and its controls
+//! block until the vCPUs have done as asked. An orchestration service built
+//! on Tokio would rather await them, so [`VmmBuilder::build_async`] builds
+//! the VM as an [`AsyncVmm`] instead: its run is a future resolving to how
+//! the VM stopped, its controls are awaitable, and the guest's console is an
+//! [`AsyncConsole`] to read output from and write input to:
+//!
+//! ```no_run
+//! use carbon::VmmBuilder;
+//! use std::path::Path;
+//! use tokio::io::AsyncReadExt;
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let vmm = VmmBuilder::new("bzImage").memory(1 << 30).build_async()?;
+//! let mut console = vmm.console()?;
+//! let (outcome, _) = tokio::join!(vmm.run(), async {
+//!     let mut output = [0; 4096];
+//!     let read = console.read(&mut output).await?;
+//!     // ... once the guest is ready:
+//!     vmm.snapshot(Path::new("vm.json"), Path::new("vm.mem")).await?;
+//!     vmm.shutdown().await?;
+//!     Ok::<_, std::io::Error>(read)
+//! });
+//! println!("stopped: {:?}", outcome?.reason);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every async VM in the process has its devices served by one
+//! [`Reactor`], started with the first, so an idle VM costs no thread
+//! beyond its vCPUs': a run only parks a thread of Tokio's blocking pool
+//! until the reactor reports the VM stopped, and a control only borrows
+//! one while the vCPUs catch up. As on a [`Host`](crate::host::Host),
+//! signals are left to the embedding program.
+//!
+//! COM1 is the [`AsyncConsole`], whatever the builder configured. Output
+//! nobody reads is dropped once the socket's buffer fills, rather than
+//! stall the guest. Dropping the run's future doesn't stop the VM:
+//! [`AsyncVmm::shutdown`] does.
+
+use crate::builder::{Vmm, VmmBuilder};
+use crate::devices::PairConsole;
+use crate::error::CarbonError;
+use crate::reactor::Reactor;
+use crate::vmm::{RunOutcome, VmConfig};
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A VM built for async code (see the [module docs](self)).
+pub struct AsyncVmm {
+    vmm: Arc<Vmm>,
+    /// The embedding program's end of COM1, until taken.
+    console: Mutex<Option<UnixStream>>,
+}
+
+/// The guest's serial console: reads return guest output, writes are
+/// guest input.
+pub struct AsyncConsole(tokio::net::UnixStream);
+
+impl AsyncVmm {
+    pub(crate) fn new(builder: VmmBuilder) -> Result<Self, CarbonError> {
+        let (console, peer) = PairConsole::pair()
+            .map_err(|e| CarbonError::Guest(format!("failed to create the console: {e}")))?;
+        let reactor = shared_reactor()
+            .map_err(|e| CarbonError::Guest(format!("failed to start the reactor: {e}")))?;
+        let vmm = builder
+            .console(Box::new(console))
+            .reactor(reactor)
+            .build()?;
+        Ok(Self {
+            vmm: Arc::new(vmm),
+            console: Mutex::new(Some(peer)),
+        })
+    }
+
+    /// The VM's configuration.
+    pub fn config(&self) -> &VmConfig {
+        self.vmm.config()
+    }
+
+    /// The guest's console. There is one, taken by the first call, which
+    /// must be made within a Tokio runtime.
+    pub fn console(&self) -> io::Result<AsyncConsole> {
+        let stream = lock(&self.console).take().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AlreadyExists, "the console is already taken")
+        })?;
+        stream.set_nonblocking(true)?;
+        Ok(AsyncConsole(tokio::net::UnixStream::from_std(stream)?))
+    }
+
+    /// Build the VM and run it until it stops, returning why.
+    pub async fn run(&self) -> Result<RunOutcome, CarbonError> {
+        let vmm = self.vmm.clone();
+        tokio::task::spawn_blocking(move || vmm.run())
+            .await
+            .unwrap_or_else(|e| Err(CarbonError::Guest(format!("the VM's run failed: {e}"))))
+    }
+
+    /// Stop the vCPUs until [`resume`](Self::resume).
+    pub async fn pause(&self) -> io::Result<()> {
+        self.blocking(|vmm| vmm.pause()).await
+    }
+
+    /// Let the vCPUs of a paused VM run on.
+    pub async fn resume(&self) -> io::Result<()> {
+        self.blocking(|vmm| vmm.resume()).await
+    }
+
+    /// Whether the VM is running, and paused.
+    pub fn is_paused(&self) -> bool {
+        self.vmm.is_paused()
+    }
+
+    /// Snapshot the VM: its state to `state`, guest RAM to `memory`, to
+    /// restore with [`VmmBuilder::restore`].
+    pub async fn snapshot(&self, state: &Path, memory: &Path) -> io::Result<()> {
+        let (state, memory) = (state.to_path_buf(), memory.to_path_buf());
+        self.blocking(move |vmm| vmm.snapshot(&state, &memory))
+            .await
+    }
+
+    /// Dump the guest to `path` as an ELF core.
+    pub async fn dump_core(&self, path: &Path) -> io::Result<()> {
+        let path = path.to_path_buf();
+        self.blocking(move |vmm| vmm.dump_core(&path)).await
+    }
+
+    /// Stop the VM: [`run`](Self::run) resolves to
+    /// [`StopReason::Shutdown`](crate::StopReason::Shutdown). Before the
+    /// run starts, it stops as soon as it does.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        self.vmm.shutdown()
+    }
+
+    /// Run `f` on the VM where it may block.
+    async fn blocking(
+        &self,
+        f: impl FnOnce(&Vmm) -> io::Result<()> + Send + 'static,
+    ) -> io::Result<()> {
+        let vmm = self.vmm.clone();
+        tokio::task::spawn_blocking(move || f(&vmm))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+impl AsyncRead for AsyncConsole {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncConsole {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// The reactor serving every async VM's devices, started by the first.
+fn shared_reactor() -> io::Result<Arc<Reactor>> {
+    static REACTOR: Mutex<Option<Arc<Reactor>>> = Mutex::new(None);
+    let mut reactor = lock(&REACTOR);
+    if let Some(reactor) = &*reactor {
+        return Ok(reactor.clone());
+    }
+    let started = Arc::new(Reactor::new()?);
+    *reactor = Some(started.clone());
+    Ok(started)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::ConsoleBackend;
+    use std::io::{Read, Write};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_console() {
+        block_on(async {
+            let (mut backend, peer) = PairConsole::pair().unwrap();
+            let vmm = AsyncVmm {
+                vmm: Arc::new(VmmBuilder::new("bzImage").build().unwrap()),
+                console: Mutex::new(Some(peer)),
+            };
+            let mut console = vmm.console().unwrap();
+            assert!(vmm.console().is_err());
+
+            backend.write_all(b"login: ").unwrap();
+            let mut output = [0; 7];
+            console.read_exact(&mut output).await.unwrap();
+            assert_eq!(&output, b"login: ");
+            let mut input = backend.take_input().unwrap();
+            console.write_all(b"root\n").await.unwrap();
+            let mut line = [0; 5];
+            input.read_exact(&mut line).unwrap();
+            assert_eq!(&line, b"root\n");
+        });
+    }
+
+    #[test]
+    fn test_run() {
+        block_on(async {
+            let vmm = VmmBuilder::new("/nonexistent/kernel")
+                .build_async()
+                .unwrap();
+            let err = vmm.pause().await.unwrap_err();
+            assert!(err.to_string().contains("isn't running"), "{err}");
+            assert!(!vmm.is_paused());
+            let err = crate::error::report(&vmm.run().await.unwrap_err());
+            assert!(err.contains("/nonexistent/kernel"), "{err}");
+            let err = vmm.run().await.unwrap_err();
+            assert!(err.to_string().contains("already run"), "{err}");
+        });
+    }
+}
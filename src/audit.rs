@@ -0,0 +1,144 @@
+//! Host resource audit log.
+//!
+//! With `--audit-log PATH`, Carbon appends one line to `PATH` for every host
+//! resource a VM touches, so a security review of a sandbox run can list its
+//! exact host footprint:
+//!
+//! ```text
+//! ts=1760620800.123 vm=3f9c0a71d2e4b856 kind=device action=open target="/dev/kvm"
+//! ts=1760620800.125 vm=3f9c0a71d2e4b856 kind=file action=read target="bin/vmlinuz"
+//! ts=1760620800.131 vm=3f9c0a71d2e4b856 kind=disk action=open-rw target="dev.img"
+//! ts=1760620801.402 vm=3f9c0a71d2e4b856 kind=socket action=remove target="/tmp/c.sock"
+//! ```
+//!
+//! Records are `key=value` pairs with the target quoted, written with a single
+//! `O_APPEND` write each so concurrent Carbon processes can share one log.
+//! Every record carries the VM ID (`--vm-id`, random by default), and the log
+//! is bracketed by `kind=vm action=start` and `action=exit` records. The log
+//! is only ever appended to; rotating it is left to the host.
+//!
+//! Recorded kinds: `vm`, `device` (`/dev/kvm`, console PTYs), `file`
+//! (kernel, initrd, profiles, boot profiles, 9p shares, console files),
+//! `disk` (images, overlays), `socket` (console, vsock and vhost-user
+//! sockets) and `process` (device plugins).
+//! New host resources (TAP devices, forwarded ports, ...) must call
+//! [`record`] when they are acquired and released.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The open audit log, if any, and the VM ID its records carry.
+static LOG: Mutex<Option<(File, String)>> = Mutex::new(None);
+
+/// Kind of host resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Vm,
+    Device,
+    File,
+    Disk,
+    Socket,
+    Process,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Vm => "vm",
+            Kind::Device => "device",
+            Kind::File => "file",
+            Kind::Disk => "disk",
+            Kind::Socket => "socket",
+            Kind::Process => "process",
+        })
+    }
+}
+
+/// Open `path` for appending and start recording under `vm_id`.
+pub fn open(path: &Path, vm_id: &str) -> io::Result<()> {
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    *LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some((file, vm_id.to_string()));
+    record(Kind::Vm, "start", &format!("pid {}", std::process::id()));
+    Ok(())
+}
+
+/// Write the final record and stop recording.
+pub fn close(status: &str) {
+    record(Kind::Vm, "exit", status);
+    LOG.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Append a record that the VM did `action` on host resource `target`.
+///
+/// Does nothing without `--audit-log`. A failed write is logged, not fatal.
+pub fn record(kind: Kind, action: &str, target: &str) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    let Some((file, vm_id)) = log.as_mut() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let line = format_record(now.as_millis(), vm_id, kind, action, target);
+    if let Err(e) = file.write_all(line.as_bytes()) {
+        warn!("[VMM] Failed to write audit log: {}", e);
+    }
+}
+
+fn format_record(millis: u128, vm_id: &str, kind: Kind, action: &str, target: &str) -> String {
+    format!(
+        "ts={}.{:03} vm={} kind={} action={} target={:?}\n",
+        millis / 1000,
+        millis % 1000,
+        vm_id,
+        kind,
+        action,
+        target
+    )
+}
+
+/// A fresh random VM ID (16 hex digits).
+pub fn new_vm_id() -> String {
+    let mut bytes = [0u8; 8];
+    let random = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if random.is_err() {
+        // Unique enough for one host: pid and start time
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        bytes = (nanos ^ (u64::from(std::process::id()) << 32)).to_be_bytes();
+    }
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_record() {
+        assert_eq!(
+            format_record(
+                1_760_620_800_123,
+                "abc",
+                Kind::Disk,
+                "open-rw",
+                "my \"disk\".img"
+            ),
+            "ts=1760620800.123 vm=abc kind=disk action=open-rw target=\"my \\\"disk\\\".img\"\n"
+        );
+    }
+
+    #[test]
+    fn test_new_vm_id() {
+        let id = new_vm_id();
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id, new_vm_id());
+    }
+}
@@ -11,6 +11,10 @@
 //! - **FADT** (Fixed ACPI Description Table): Hardware feature description
 //! - **DSDT** (Differentiated System Description Table): AML code for devices
 //! - **MADT** (Multiple APIC Description Table): Describes APIC configuration
+//! - **MCFG** (PCI Memory Mapped Configuration): Where the PCI ECAM region
+//!   is, when devices sit on PCI
+//! - **TPM2** (Trusted Platform Module 2.0): Where the TPM's CRB control
+//!   area is, when a TPM is attached
 //!
 //! # HW_REDUCED ACPI Mode
 //!
@@ -28,7 +32,33 @@
 //! 0x000e_2000  FADT (276 bytes)
 //! 0x000e_3000  DSDT (variable, includes virtio device definitions)
 //! 0x000e_4000  MADT (variable)
+//! 0x000e_5000  MCFG (60 bytes, only with PCI devices)
+//! 0x000e_6000  TPM2 (64 bytes, only with a TPM)
 //! ```
+//!
+//! # PCI
+//!
+//! With devices on PCI, the DSDT also describes the host bridge (`PCI0`):
+//! its bus number and BAR window, and a `_PRT` routing each device's INTA
+//! to an interrupt link (`LNxx`) with the device's own GSI. A motherboard
+//! resource (`PRES`) reserves the ECAM region, which Linux checks before
+//! trusting the MCFG.
+//!
+//! # pvpanic
+//!
+//! The DSDT always describes the pvpanic port (`PEVT`, `QEMU0001`), so the
+//! guest can report kernel panics.
+//!
+//! # fw_cfg
+//!
+//! With configuration blobs to pass, the DSDT also describes the fw_cfg
+//! ports (`FWCF`, `QEMU0002`).
+//!
+//! # TPM
+//!
+//! With a TPM attached, the DSDT describes its CRB registers (`TPM0`,
+//! `MSFT0101`), and the TPM2 table tells the driver to use the CRB start
+//! method.
 
 use super::memory::GuestMemory;
 use super::BootError;
@@ -48,6 +78,15 @@ const DSDT_ADDR: u64 = 0x000e_3000;
 /// MADT location in guest memory.
 const MADT_ADDR: u64 = 0x000e_4000;
 
+/// MCFG location in guest memory.
+const MCFG_ADDR: u64 = 0x000e_5000;
+
+/// TPM2 location in guest memory.
+const TPM2_ADDR: u64 = 0x000e_6000;
+
+/// TPM2 start method: Command Response Buffer.
+const TPM2_START_METHOD_CRB: u32 = 7;
+
 /// Local APIC base address.
 const LOCAL_APIC_ADDR: u32 = 0xfee0_0000;
 
@@ -91,6 +130,32 @@ pub struct VirtioDeviceConfig {
     pub gsi: u32,
 }
 
+/// The PCI host bridge to describe in the MCFG and DSDT.
+#[derive(Clone, Debug)]
+pub struct PciHostConfig {
+    /// ECAM base address (segment 0, bus 0).
+    pub ecam_base: u64,
+    /// ECAM region size.
+    pub ecam_size: u32,
+    /// Base of the window BARs are placed in.
+    pub mmio_base: u64,
+    /// Size of the BAR window.
+    pub mmio_size: u32,
+    /// Device number and GSI of each device's INTA.
+    pub irqs: Vec<(u8, u32)>,
+}
+
+/// The TPM to describe in the TPM2 table and DSDT.
+#[derive(Clone, Debug)]
+pub struct TpmConfig {
+    /// Base address of the CRB registers.
+    pub mmio_base: u32,
+    /// Size of the CRB region.
+    pub mmio_size: u32,
+    /// Address of the CRB control area.
+    pub control_area: u64,
+}
+
 /// ACPI standard table header (used by XSDT, MADT, etc.).
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
@@ -273,8 +338,13 @@ fn compute_checksum(data: &[u8]) -> u8 {
 ///
 /// # Arguments
 /// * `memory` - Guest memory to write tables to
-/// * `num_cpus` - Number of vCPUs (currently must be 1)
+/// * `apic_ids` - APIC ID of each vCPU, the boot CPU first
 /// * `virtio_devices` - List of virtio-mmio devices to define in DSDT
+/// * `pci` - The PCI host bridge, if any devices sit on PCI
+/// * `pvpanic_port` - I/O port of the pvpanic device
+/// * `fw_cfg_ports` - First I/O port and port count of the fw_cfg device, if
+///   any
+/// * `tpm` - The TPM, if one is attached
 ///
 /// # Returns
 /// The address of the RSDP, which should be reported to the guest via
@@ -287,26 +357,43 @@ fn compute_checksum(data: &[u8]) -> u8 {
 /// command line), which works correctly with HW_REDUCED_ACPI mode.
 pub fn setup_acpi(
     memory: &GuestMemory,
-    num_cpus: u8,
+    apic_ids: &[u8],
     virtio_devices: &[VirtioDeviceConfig],
+    pci: Option<&PciHostConfig>,
+    pvpanic_port: u16,
+    fw_cfg_ports: Option<(u16, u8)>,
+    tpm: Option<&TpmConfig>,
 ) -> Result<u64, BootError> {
     // Build DSDT (must be built before FADT which references it)
-    let dsdt_size = build_dsdt(memory, virtio_devices)?;
+    let dsdt_size = build_dsdt(memory, virtio_devices, pci, pvpanic_port, fw_cfg_ports, tpm)?;
 
     // Build FADT (Fixed ACPI Description Table)
     let fadt_size = build_fadt(memory)?;
 
     // Build MADT (Multiple APIC Description Table)
-    let madt_size = build_madt(memory, num_cpus)?;
+    let madt_size = build_madt(memory, apic_ids)?;
+
+    // Build MCFG (PCI ECAM location)
+    let mut tables = vec![FADT_ADDR, MADT_ADDR];
+    if let Some(pci) = pci {
+        build_mcfg(memory, pci)?;
+        tables.push(MCFG_ADDR);
+    }
+
+    // Build TPM2 (TPM start method)
+    if let Some(tpm) = tpm {
+        build_tpm2(memory, tpm)?;
+        tables.push(TPM2_ADDR);
+    }
 
     // Build XSDT - FADT must be first per ACPI spec
-    build_xsdt(memory, &[FADT_ADDR, MADT_ADDR])?;
+    build_xsdt(memory, &tables)?;
 
     // Build RSDP (Root System Description Pointer)
     build_rsdp(memory)?;
 
-    eprintln!(
-        "[Boot] ACPI: RSDP={:#x} XSDT={:#x} FADT={:#x}({}) DSDT={:#x}({}) MADT={:#x}({}) virtio={}",
+    debug!(
+        "[Boot] ACPI: RSDP={:#x} XSDT={:#x} FADT={:#x}({}) DSDT={:#x}({}) MADT={:#x}({}) virtio={} pci={}",
         RSDP_ADDR,
         XSDT_ADDR,
         FADT_ADDR,
@@ -315,7 +402,8 @@ pub fn setup_acpi(
         dsdt_size,
         MADT_ADDR,
         madt_size,
-        virtio_devices.len()
+        virtio_devices.len(),
+        pci.map_or(0, |pci| pci.irqs.len())
     );
 
     Ok(RSDP_ADDR)
@@ -452,7 +540,7 @@ fn build_fadt(memory: &GuestMemory) -> Result<usize, BootError> {
 ///         Name(_UID, 0)
 ///         Name(_CRS, ResourceTemplate() {
 ///             Memory32Fixed(ReadWrite, base, size)
-///             Interrupt(ResourceConsumer, Level, ActiveHigh, Exclusive) { gsi }
+///             Interrupt(ResourceConsumer, Edge, ActiveHigh, Shared) { gsi }
 ///         })
 ///     }
 ///     // ... more devices
@@ -461,6 +549,10 @@ fn build_fadt(memory: &GuestMemory) -> Result<usize, BootError> {
 fn build_dsdt(
     memory: &GuestMemory,
     virtio_devices: &[VirtioDeviceConfig],
+    pci: Option<&PciHostConfig>,
+    pvpanic_port: u16,
+    fw_cfg_ports: Option<(u16, u8)>,
+    tpm: Option<&TpmConfig>,
 ) -> Result<usize, BootError> {
     let header_size = core::mem::size_of::<AcpiHeader>();
 
@@ -473,6 +565,16 @@ fn build_dsdt(
         let dev_aml = build_virtio_device_aml(dev);
         device_aml.extend_from_slice(&dev_aml);
     }
+    if let Some(pci) = pci {
+        device_aml.extend_from_slice(&build_pci_aml(pci));
+    }
+    device_aml.extend_from_slice(&build_pvpanic_aml(pvpanic_port));
+    if let Some((port, count)) = fw_cfg_ports {
+        device_aml.extend_from_slice(&build_fw_cfg_aml(port, count));
+    }
+    if let Some(tpm) = tpm {
+        device_aml.extend_from_slice(&build_tpm_aml(tpm));
+    }
 
     // Build Scope(\_SB) { devices... }
     // ScopeOp = 0x10
@@ -504,20 +606,17 @@ fn build_dsdt(
     buffer[9] = compute_checksum(&buffer);
 
     // Debug: dump AML bytes
-    eprintln!(
+    trace!(
         "[DSDT] AML bytes ({} total, {} AML):",
         dsdt_size,
         aml_code.len()
     );
-    eprint!("[DSDT] ");
-    for (i, byte) in aml_code.iter().enumerate() {
-        eprint!("{:02x} ", byte);
-        if (i + 1) % 16 == 0 {
-            eprintln!();
-            eprint!("[DSDT] ");
+    if crate::logging::enabled(crate::logging::Level::Trace) {
+        for chunk in aml_code.chunks(16) {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            trace!("[DSDT] {}", hex.join(" "));
         }
     }
-    eprintln!();
 
     // Write to guest memory
     memory.write(DSDT_ADDR, &buffer)?;
@@ -534,7 +633,7 @@ fn build_dsdt(
 ///     Name(_UID, n)
 ///     Name(_CRS, ResourceTemplate() {
 ///         Memory32Fixed(ReadWrite, base, size)
-///         Interrupt(ResourceConsumer, Level, ActiveHigh, Exclusive) { gsi }
+///         Interrupt(ResourceConsumer, Edge, ActiveHigh, Shared) { gsi }
 ///     })
 /// }
 /// ```
@@ -604,68 +703,256 @@ fn build_virtio_device_aml(dev: &VirtioDeviceConfig) -> Vec<u8> {
 /// - Extended Interrupt descriptor (GSI)
 /// - End tag
 fn build_resource_template(base: u32, size: u32, gsi: u32) -> Vec<u8> {
-    // Memory32Fixed descriptor (Small Resource, Type 0x86)
+    let mut resources = memory32_fixed_descriptor(base, size);
+    resources.extend_from_slice(&interrupt_descriptor(gsi));
+    aml_resource_template(&resources)
+}
+
+/// IO(Decode16, port, port, 1, len) resource descriptor: `len` ports at
+/// `port`.
+fn io_port_descriptor(port: u16, len: u8) -> Vec<u8> {
+    // I/O port descriptor (Small Resource, Type 0x08, length 7)
+    let mut descriptor = vec![
+        0x47, // I/O port tag
+        0x01, // Information: 16-bit decode
+    ];
+    descriptor.extend_from_slice(&port.to_le_bytes()); // Minimum base
+    descriptor.extend_from_slice(&port.to_le_bytes()); // Maximum base
+    descriptor.push(0x01); // Alignment
+    descriptor.push(len); // Range length
+    descriptor
+}
+
+/// Memory32Fixed(ReadWrite, base, size) resource descriptor.
+fn memory32_fixed_descriptor(base: u32, size: u32) -> Vec<u8> {
+    // Memory32Fixed descriptor (Large Resource, Type 0x86)
     // Tag: 0x86 (Memory32Fixed, length in next 2 bytes)
     // Length: 9 (1 + 4 + 4 for RW flag + base + length)
-    let mut resources = vec![
+    let mut descriptor = vec![
         0x86, // Memory32Fixed tag
         0x09, // Length low byte
         0x00, // Length high byte
         0x01, // Read/Write flag (1 = ReadWrite)
     ];
-    resources.extend_from_slice(&base.to_le_bytes()); // Base address
-    resources.extend_from_slice(&size.to_le_bytes()); // Range length
+    descriptor.extend_from_slice(&base.to_le_bytes()); // Base address
+    descriptor.extend_from_slice(&size.to_le_bytes()); // Range length
+    descriptor
+}
 
+/// Interrupt(ResourceConsumer, Edge, ActiveHigh, Shared) { gsi } resource
+/// descriptor.
+fn interrupt_descriptor(gsi: u32) -> Vec<u8> {
     // Extended Interrupt descriptor (Large Resource, Type 0x89)
     // Format: Tag (1) + Length (2) + Flags (1) + Count (1) + Interrupts (4*count)
-    resources.push(0x89); // Extended Interrupt tag
-    resources.push(0x06); // Length low byte (1 + 1 + 4 = 6)
-    resources.push(0x00); // Length high byte
-                          // Flags: bit 0 = consumer (1), bit 1 = edge(0)/level(1), bit 2 = active high(0)/low(1)
-                          //        bit 3 = shared(0)/exclusive(1)
-                          // We want: consumer, level-triggered, active-high, exclusive = 0b00001011 = 0x0B
-    resources.push(0x0B); // Flags: ResourceConsumer, Level, ActiveHigh, Exclusive
-    resources.push(0x01); // Interrupt count
-    resources.extend_from_slice(&gsi.to_le_bytes()); // GSI number
+    let mut descriptor = vec![
+        0x89, // Extended Interrupt tag
+        0x06, // Length low byte (1 + 1 + 4 = 6)
+        0x00, // Length high byte
+        // Flags: bit 0 = consumer (1), bit 1 = level(0)/edge(1), bit 2 = active high(0)/low(1)
+        //        bit 3 = exclusive(0)/shared(1)
+        // We want: consumer, edge-triggered, active-high, shared = 0b00001011 = 0x0B.
+        // Devices raise interrupts by pulsing an irqfd, so the line must be edge-triggered.
+        0x0B, // Flags: ResourceConsumer, Edge, ActiveHigh, Shared
+        0x01, // Interrupt count
+    ];
+    descriptor.extend_from_slice(&gsi.to_le_bytes()); // GSI number
+    descriptor
+}
+
+/// Wrap resource descriptors in a ResourceTemplate: an AML Buffer ending
+/// with an End tag.
+fn aml_resource_template(descriptors: &[u8]) -> Vec<u8> {
+    let mut resources = descriptors.to_vec();
 
     // End tag (Small Resource, Type 0x79)
     resources.push(0x79); // End tag
     resources.push(0x00); // Checksum (0 = not used)
 
     // Wrap in Buffer: BufferOp (0x11) + PkgLength + BufferSize + data
-    let mut buffer = Vec::new();
-    buffer.push(0x11); // BufferOp
-
-    // BufferSize is a TermArg (integer) - must use proper AML encoding:
-    // - 0x00 = ZeroOp (value 0)
-    // - 0x01 = OneOp (value 1)
-    // - 0x0A + byte = BytePrefix (values 2-255)
-    // - 0x0B + word = WordPrefix (larger values)
-    let buffer_size_encoding = if resources.len() <= 1 {
-        1 // ZeroOp or OneOp
-    } else if resources.len() <= 255 {
-        2 // BytePrefix + byte
-    } else {
-        3 // WordPrefix + word
+    let buffer_size = aml_integer(resources.len() as u32);
+    let mut buffer = vec![0x11]; // BufferOp
+    encode_pkg_length(&mut buffer, buffer_size.len() + resources.len());
+    buffer.extend_from_slice(&buffer_size);
+    buffer.extend_from_slice(&resources);
+    buffer
+}
+
+/// Encode an integer constant with the shortest AML encoding:
+/// ZeroOp, OneOp, or a Byte/Word/DWord prefix.
+fn aml_integer(value: u32) -> Vec<u8> {
+    match value {
+        0 => vec![0x00], // ZeroOp
+        1 => vec![0x01], // OneOp
+        2..=0xff => vec![0x0A, value as u8],
+        0x100..=0xffff => {
+            let mut bytes = vec![0x0B];
+            bytes.extend_from_slice(&(value as u16).to_le_bytes());
+            bytes
+        }
+        _ => {
+            let mut bytes = vec![0x0C];
+            bytes.extend_from_slice(&value.to_le_bytes());
+            bytes
+        }
+    }
+}
+
+/// EISAID("PNPxxxx"): a compressed EISA ID, as an AML DWord.
+fn aml_eisa_id(id: &[u8; 7]) -> Vec<u8> {
+    // Three 5-bit letters ('A' = 1), then four hex digits, big-endian
+    let letters = id[..3]
+        .iter()
+        .fold(0u16, |acc, &c| (acc << 5) | u16::from(c - 0x40));
+    let digits = u16::from_str_radix(std::str::from_utf8(&id[3..]).unwrap(), 16).unwrap();
+    let mut bytes = vec![0x0C]; // DWordPrefix
+    bytes.extend_from_slice(&letters.to_be_bytes());
+    bytes.extend_from_slice(&digits.to_be_bytes());
+    bytes
+}
+
+/// Name(name, value), where `value` is already encoded.
+fn aml_name(name: &[u8; 4], value: &[u8]) -> Vec<u8> {
+    let mut aml = vec![0x08]; // NameOp
+    aml.extend_from_slice(name);
+    aml.extend_from_slice(value);
+    aml
+}
+
+/// Device(name) { contents }.
+fn aml_device(name: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    let mut aml = vec![0x5B, 0x82]; // ExtOpPrefix, DeviceOp
+    encode_pkg_length(&mut aml, name.len() + contents.len());
+    aml.extend_from_slice(name);
+    aml.extend_from_slice(contents);
+    aml
+}
+
+/// Package() { elements }, each already encoded.
+fn aml_package(elements: &[Vec<u8>]) -> Vec<u8> {
+    let contents = elements.concat();
+    let mut aml = vec![0x12]; // PackageOp
+    encode_pkg_length(&mut aml, 1 + contents.len());
+    aml.push(elements.len() as u8); // NumElements
+    aml.extend_from_slice(&contents);
+    aml
+}
+
+/// Build AML bytecode for the PCI host bridge and its interrupt routing.
+///
+/// Generates:
+/// ```text
+/// Device(PCI0) {
+///     Name(_HID, EISAID("PNP0A08"))   // PCI Express root bridge
+///     Name(_CID, EISAID("PNP0A03"))   // PCI root bridge
+///     Name(_SEG, 0)
+///     Name(_BBN, 0)
+///     Name(_UID, 0)
+///     Name(_CRS, ResourceTemplate() {
+///         WordBusNumber(...) { 0 - 0 }
+///         DWordMemory(...) { BAR window }
+///     })
+///     Name(_PRT, Package() {
+///         Package() { 0xddddFFFF, 0, \_SB_.LNdd, 0 }  // INTA of device dd
+///         ...
+///     })
+/// }
+/// Device(PRES) {
+///     Name(_HID, EISAID("PNP0C02"))   // motherboard resources
+///     Name(_CRS, ResourceTemplate() { Memory32Fixed(ReadWrite, ECAM) })
+/// }
+/// Device(LNdd) {                      // one per device
+///     Name(_HID, EISAID("PNP0C0F"))   // PCI interrupt link
+///     Name(_UID, dd)
+///     Name(_STA, 0x0B)
+///     Name(_PRS, ResourceTemplate() { Interrupt(...) { gsi } })
+///     Name(_CRS, ResourceTemplate() { Interrupt(...) { gsi } })
+///     Method(_SRS, 1) {}
+/// }
+/// ```
+fn build_pci_aml(pci: &PciHostConfig) -> Vec<u8> {
+    let link_name = |device: u8| {
+        let hex = format!("{device:02X}");
+        [b'L', b'N', hex.as_bytes()[0], hex.as_bytes()[1]]
     };
-    encode_pkg_length(&mut buffer, buffer_size_encoding + resources.len());
-
-    // BufferSize (integer representing buffer length)
-    if resources.is_empty() {
-        buffer.push(0x00); // ZeroOp
-    } else if resources.len() == 1 {
-        buffer.push(0x01); // OneOp
-    } else if resources.len() <= 255 {
-        buffer.push(0x0A); // BytePrefix
-        buffer.push(resources.len() as u8);
-    } else {
-        buffer.push(0x0B); // WordPrefix
-        buffer.extend_from_slice(&(resources.len() as u16).to_le_bytes());
+
+    // WordBusNumber(ResourceProducer, MinFixed, MaxFixed, PosDecode,
+    //               0, 0, 0, 0, 1): bus 0 only
+    let mut resources = vec![
+        0x88, // Word Address Space tag
+        0x0D, // Length low byte
+        0x00, // Length high byte
+        0x02, // Resource type: bus number range
+        0x0C, // General flags: MinFixed, MaxFixed, PosDecode, producer
+        0x00, // Type-specific flags
+    ];
+    for value in [0u16, 0, 0, 0, 1] {
+        // Granularity, minimum, maximum, translation, length
+        resources.extend_from_slice(&value.to_le_bytes());
+    }
+    // DWordMemory(ResourceProducer, PosDecode, MinFixed, MaxFixed,
+    //             NonCacheable, ReadWrite, ...): the BAR window
+    resources.extend_from_slice(&[
+        0x87, // DWord Address Space tag
+        0x17, // Length low byte
+        0x00, // Length high byte
+        0x00, // Resource type: memory range
+        0x0C, // General flags: MinFixed, MaxFixed, PosDecode, producer
+        0x01, // Type-specific flags: ReadWrite, NonCacheable
+    ]);
+    let (base, size) = (pci.mmio_base as u32, pci.mmio_size);
+    for value in [0, base, base + (size - 1), 0, size] {
+        // Granularity, minimum, maximum, translation, length
+        resources.extend_from_slice(&value.to_le_bytes());
     }
 
-    buffer.extend_from_slice(&resources);
+    let routes: Vec<Vec<u8>> = pci
+        .irqs
+        .iter()
+        .map(|&(device, _)| {
+            let mut link = vec![0x5C]; // RootChar
+            link.push(0x2E); // DualNamePrefix
+            link.extend_from_slice(b"_SB_");
+            link.extend_from_slice(&link_name(device));
+            aml_package(&[
+                aml_integer((u32::from(device) << 16) | 0xffff), // any function
+                aml_integer(0),                                  // INTA
+                link,
+                aml_integer(0), // source index
+            ])
+        })
+        .collect();
+
+    let mut host_bridge = Vec::new();
+    host_bridge.extend(aml_name(b"_HID", &aml_eisa_id(b"PNP0A08")));
+    host_bridge.extend(aml_name(b"_CID", &aml_eisa_id(b"PNP0A03")));
+    host_bridge.extend(aml_name(b"_SEG", &aml_integer(0)));
+    host_bridge.extend(aml_name(b"_BBN", &aml_integer(0)));
+    host_bridge.extend(aml_name(b"_UID", &aml_integer(0)));
+    host_bridge.extend(aml_name(b"_CRS", &aml_resource_template(&resources)));
+    host_bridge.extend(aml_name(b"_PRT", &aml_package(&routes)));
+    let mut aml = aml_device(b"PCI0", &host_bridge);
+
+    let ecam = memory32_fixed_descriptor(pci.ecam_base as u32, pci.ecam_size);
+    let mut reserved = aml_name(b"_HID", &aml_eisa_id(b"PNP0C02"));
+    reserved.extend(aml_name(b"_CRS", &aml_resource_template(&ecam)));
+    aml.extend(aml_device(b"PRES", &reserved));
+
+    for &(device, gsi) in &pci.irqs {
+        let interrupt = aml_resource_template(&interrupt_descriptor(gsi));
+        let mut link = aml_name(b"_HID", &aml_eisa_id(b"PNP0C0F"));
+        link.extend(aml_name(b"_UID", &aml_integer(u32::from(device))));
+        // Present, enabled and functioning
+        link.extend(aml_name(b"_STA", &aml_integer(0x0B)));
+        link.extend(aml_name(b"_PRS", &interrupt));
+        link.extend(aml_name(b"_CRS", &interrupt));
+        // Method(_SRS, 1) {}: the routing is fixed, nothing to set
+        link.extend_from_slice(&[0x14, 0x06]); // MethodOp, PkgLength
+        link.extend_from_slice(b"_SRS");
+        link.push(0x01); // One argument, not serialized
+        aml.extend(aml_device(&link_name(device), &link));
+    }
 
-    buffer
+    aml
 }
 
 /// Encode a PkgLength value into the buffer.
@@ -748,7 +1035,7 @@ impl MadtInterruptOverride {
 }
 
 /// Build MADT and write to guest memory.
-fn build_madt(memory: &GuestMemory, num_cpus: u8) -> Result<usize, BootError> {
+fn build_madt(memory: &GuestMemory, apic_ids: &[u8]) -> Result<usize, BootError> {
     let header_size = core::mem::size_of::<AcpiHeader>();
 
     // MADT has a fixed part after the header: Local APIC Address (4) + Flags (4)
@@ -763,7 +1050,7 @@ fn build_madt(memory: &GuestMemory, num_cpus: u8) -> Result<usize, BootError> {
     // - One Local APIC entry per CPU
     // - One I/O APIC entry
     // - Interrupt source override for IRQ 0 (timer -> GSI 2)
-    let entries_size = (num_cpus as usize * local_apic_size) + io_apic_size + override_size;
+    let entries_size = (apic_ids.len() * local_apic_size) + io_apic_size + override_size;
 
     let table_size = header_size + fixed_size + entries_size;
     let mut buffer = vec![0u8; table_size];
@@ -788,9 +1075,9 @@ fn build_madt(memory: &GuestMemory, num_cpus: u8) -> Result<usize, BootError> {
     buffer[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes());
     offset += 4;
 
-    // Add Local APIC entries (one per CPU)
-    for i in 0..num_cpus {
-        let entry = MadtLocalApic::new(i, i);
+    // Add Local APIC entries (one per CPU), in the order Linux numbers them
+    for (i, &apic_id) in apic_ids.iter().enumerate() {
+        let entry = MadtLocalApic::new(i as u8, apic_id);
         let entry_bytes = unsafe {
             core::slice::from_raw_parts(&entry as *const _ as *const u8, local_apic_size)
         };
@@ -820,6 +1107,132 @@ fn build_madt(memory: &GuestMemory, num_cpus: u8) -> Result<usize, BootError> {
     Ok(table_size)
 }
 
+/// Build AML bytecode for the pvpanic device.
+///
+/// Generates:
+/// ```text
+/// Device(PEVT) {
+///     Name(_HID, "QEMU0001")
+///     Name(_STA, 0x0F)
+///     Name(_CRS, ResourceTemplate() { IO(Decode16, port, port, 1, 1) })
+/// }
+/// ```
+fn build_pvpanic_aml(port: u16) -> Vec<u8> {
+    let mut hid = vec![0x0D]; // StringPrefix
+    hid.extend_from_slice(b"QEMU0001");
+    hid.push(0x00); // Null terminator
+
+    let mut contents = aml_name(b"_HID", &hid);
+    contents.extend(aml_name(b"_STA", &aml_integer(0x0F)));
+    let resources = aml_resource_template(&io_port_descriptor(port, 1));
+    contents.extend(aml_name(b"_CRS", &resources));
+    aml_device(b"PEVT", &contents)
+}
+
+/// Build AML bytecode for the fw_cfg device.
+///
+/// Generates:
+/// ```text
+/// Device(FWCF) {
+///     Name(_HID, "QEMU0002")
+///     Name(_STA, 0x0B)
+///     Name(_CRS, ResourceTemplate() { IO(Decode16, port, port, 1, count) })
+/// }
+/// ```
+fn build_fw_cfg_aml(port: u16, count: u8) -> Vec<u8> {
+    let mut hid = vec![0x0D]; // StringPrefix
+    hid.extend_from_slice(b"QEMU0002");
+    hid.push(0x00); // Null terminator
+
+    let mut contents = aml_name(b"_HID", &hid);
+    // Present, enabled, functioning, but not shown in the UI (as QEMU)
+    contents.extend(aml_name(b"_STA", &aml_integer(0x0B)));
+    let resources = aml_resource_template(&io_port_descriptor(port, count));
+    contents.extend(aml_name(b"_CRS", &resources));
+    aml_device(b"FWCF", &contents)
+}
+
+/// Build AML bytecode for the TPM.
+///
+/// Generates:
+/// ```text
+/// Device(TPM0) {
+///     Name(_HID, "MSFT0101")
+///     Name(_STA, 0x0F)
+///     Name(_CRS, ResourceTemplate() { Memory32Fixed(ReadWrite, base, size) })
+/// }
+/// ```
+fn build_tpm_aml(tpm: &TpmConfig) -> Vec<u8> {
+    let mut hid = vec![0x0D]; // StringPrefix
+    hid.extend_from_slice(b"MSFT0101");
+    hid.push(0x00); // Null terminator
+
+    let mut contents = aml_name(b"_HID", &hid);
+    contents.extend(aml_name(b"_STA", &aml_integer(0x0F)));
+    let resources = aml_resource_template(&memory32_fixed_descriptor(tpm.mmio_base, tpm.mmio_size));
+    contents.extend(aml_name(b"_CRS", &resources));
+    aml_device(b"TPM0", &contents)
+}
+
+/// Build TPM2 and write to guest memory.
+///
+/// Platform class 0 (client), the control area address and the CRB start
+/// method, with no start method parameters and no event log.
+fn build_tpm2(memory: &GuestMemory, tpm: &TpmConfig) -> Result<usize, BootError> {
+    let header_size = core::mem::size_of::<AcpiHeader>();
+
+    // Platform class (2), reserved (2), control area (8), start method (4),
+    // start method parameters (12)
+    let table_size = header_size + 28;
+    let mut buffer = vec![0u8; table_size];
+
+    let header = AcpiHeader::new(b"TPM2", table_size as u32, 4);
+    let header_bytes =
+        unsafe { core::slice::from_raw_parts(&header as *const _ as *const u8, header_size) };
+    buffer[..header_size].copy_from_slice(header_bytes);
+
+    let offset = header_size + 4;
+    buffer[offset..offset + 8].copy_from_slice(&tpm.control_area.to_le_bytes());
+    buffer[offset + 8..offset + 12].copy_from_slice(&TPM2_START_METHOD_CRB.to_le_bytes());
+
+    // Compute checksum
+    buffer[9] = compute_checksum(&buffer);
+
+    // Write to guest memory
+    memory.write(TPM2_ADDR, &buffer)?;
+
+    Ok(table_size)
+}
+
+/// Build MCFG and write to guest memory.
+///
+/// One allocation: the ECAM region of segment 0, covering bus 0.
+fn build_mcfg(memory: &GuestMemory, pci: &PciHostConfig) -> Result<usize, BootError> {
+    let header_size = core::mem::size_of::<AcpiHeader>();
+
+    // Reserved (8) + one allocation: base (8), segment (2), start bus (1),
+    // end bus (1), reserved (4)
+    let table_size = header_size + 8 + 16;
+    let mut buffer = vec![0u8; table_size];
+
+    let header = AcpiHeader::new(b"MCFG", table_size as u32, 1);
+    let header_bytes =
+        unsafe { core::slice::from_raw_parts(&header as *const _ as *const u8, header_size) };
+    buffer[..header_size].copy_from_slice(header_bytes);
+
+    let offset = header_size + 8;
+    buffer[offset..offset + 8].copy_from_slice(&pci.ecam_base.to_le_bytes());
+    // Segment 0, buses 0 to 0: already zero
+
+    // Compute checksum
+    buffer[9] = compute_checksum(&buffer);
+
+    // Write to guest memory
+    memory.write(MCFG_ADDR, &buffer)?;
+
+    Ok(table_size)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -852,6 +1265,103 @@ mod tests {
         assert_eq!(core::mem::size_of::<Fadt>(), 276);
     }
 
+    #[test]
+    fn test_eisa_id() {
+        assert_eq!(aml_eisa_id(b"PNP0A08"), [0x0C, 0x41, 0xD0, 0x0A, 0x08]);
+        assert_eq!(aml_eisa_id(b"PNP0C0F"), [0x0C, 0x41, 0xD0, 0x0C, 0x0F]);
+    }
+
+    #[test]
+    fn test_pci_tables() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let pci = PciHostConfig {
+            ecam_base: 0xe000_0000,
+            ecam_size: 0x10_0000,
+            mmio_base: 0xe010_0000,
+            mmio_size: 0x10_0000,
+            irqs: vec![(1, 5), (2, 16)],
+        };
+
+        let size = build_mcfg(&memory, &pci).unwrap();
+        let mut mcfg = vec![0u8; size];
+        memory.read(MCFG_ADDR, &mut mcfg).unwrap();
+        assert_eq!(&mcfg[..4], b"MCFG");
+        assert_eq!(size, 60);
+        assert_eq!(mcfg[44..52], 0xe000_0000u64.to_le_bytes());
+        assert_eq!(mcfg.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+
+        let aml = build_pci_aml(&pci);
+        let contains = |needle: &[u8]| aml.windows(needle.len()).any(|w| w == needle);
+        for name in [b"PCI0", b"_PRT", b"PRES", b"LN01", b"LN02", b"_SRS"] {
+            assert!(contains(name), "missing {}", String::from_utf8_lossy(name));
+        }
+        // Device 2's INTA goes through \_SB_.LN02, which raises GSI 16
+        assert!(contains(&[0x0C, 0xff, 0xff, 0x02, 0x00, 0x00, 0x5C, 0x2E]));
+        assert!(contains(&[0x0B, 0x01, 16, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_pvpanic_aml() {
+        let aml = build_pvpanic_aml(0x505);
+        assert_eq!(&aml[..2], [0x5B, 0x82]);
+        assert_eq!(&aml[3..7], b"PEVT");
+        let io = [0x47, 0x01, 0x05, 0x05, 0x05, 0x05, 0x01, 0x01, 0x79, 0x00];
+        assert!(aml.windows(io.len()).any(|w| w == io));
+    }
+
+    #[test]
+    fn test_fw_cfg_aml() {
+        let aml = build_fw_cfg_aml(0x510, 0x0c);
+        assert_eq!(&aml[3..7], b"FWCF");
+        assert!(aml.windows(8).any(|w| w == b"QEMU0002"));
+        let io = [0x47, 0x01, 0x10, 0x05, 0x10, 0x05, 0x01, 0x0c, 0x79, 0x00];
+        assert!(aml.windows(io.len()).any(|w| w == io));
+    }
+
+    #[test]
+    fn test_madt_apic_ids() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let size = build_madt(&memory, &[0, 1, 4, 5]).unwrap();
+        let mut madt = vec![0u8; size];
+        memory.read(MADT_ADDR, &mut madt).unwrap();
+        assert_eq!(&madt[..4], b"APIC");
+        assert_eq!(madt.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+
+        // Local APIC entries after the 44-byte header: (processor, APIC ID)
+        let entries: Vec<_> = madt[44..]
+            .chunks(8)
+            .take(4)
+            .map(|entry| (entry[0], entry[2], entry[3]))
+            .collect();
+        assert_eq!(entries, [(0, 0, 0), (0, 1, 1), (0, 2, 4), (0, 3, 5)]);
+    }
+
+    #[test]
+    fn test_tpm_tables() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let tpm = TpmConfig {
+            mmio_base: 0xfed4_0000,
+            mmio_size: 0x1000,
+            control_area: 0xfed4_0040,
+        };
+        setup_acpi(&memory, &[0], &[], None, 0x505, None, Some(&tpm)).unwrap();
+
+        let mut tpm2 = [0u8; 64];
+        memory.read(TPM2_ADDR, &mut tpm2).unwrap();
+        assert_eq!(&tpm2[..4], b"TPM2");
+        assert_eq!(tpm2[40..48], 0xfed4_0040u64.to_le_bytes());
+        assert_eq!(tpm2[48..52], 7u32.to_le_bytes());
+        assert_eq!(tpm2.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+
+        let mut xsdt = [0u8; 60];
+        memory.read(XSDT_ADDR, &mut xsdt).unwrap();
+        assert_eq!(xsdt[52..60], TPM2_ADDR.to_le_bytes());
+
+        let aml = build_tpm_aml(&tpm);
+        assert_eq!(&aml[3..7], b"TPM0");
+        assert!(aml.windows(8).any(|w| w == b"MSFT0101"));
+    }
+
     #[test]
     fn test_pkg_length_encoding() {
         // Test 1-byte encoding (total <= 63)
@@ -45,13 +45,23 @@
 //! - Extended command line size
 //! - Relocatable kernel support
 //!
+//! # Host-side Decompression
+//!
+//! The protected-mode kernel is itself a decompressor wrapped around a
+//! compressed vmlinux. When the compression is one the VMM handles (see
+//! [`super::decompress`]), the vmlinux is decompressed on the host and
+//! loaded directly, so the guest starts in the kernel proper. The stub's
+//! address randomization is done by the VMM instead (see [`super::kaslr`]).
+//!
 //! Reference: <https://www.kernel.org/doc/html/latest/x86/boot.html>
 
+use super::decompress;
+use super::elf;
+use super::kaslr;
+use super::kernel_cache::KernelImage;
 use super::layout;
 use super::memory::GuestMemory;
 use super::BootError;
-use std::fs::File;
-use std::io::Read;
 
 /// Linux boot protocol magic number "HdrS" (ASCII: 0x48, 0x64, 0x72, 0x53).
 const BOOT_MAGIC: u32 = 0x5372_6448;
@@ -59,6 +69,9 @@ const BOOT_MAGIC: u32 = 0x5372_6448;
 /// Minimum supported boot protocol version (2.06 for 64-bit boot).
 const MIN_BOOT_VERSION: u16 = 0x0206;
 
+/// First boot protocol version locating the compressed payload (2.08).
+const PAYLOAD_VERSION: u16 = 0x0208;
+
 /// Offset of the setup header within the bzImage.
 const SETUP_HEADER_OFFSET: usize = 0x1f1;
 
@@ -66,38 +79,40 @@ const SETUP_HEADER_OFFSET: usize = 0x1f1;
 pub struct LoadedKernel {
     /// Raw setup header bytes to copy to boot_params.
     pub setup_header: Vec<u8>,
+
+    /// First guest address past the memory the kernel needs to decompress
+    /// and run in place (HIMEM_START + max(image size, init_size)).
+    pub kernel_end: u64,
+
+    /// Highest address the kernel accepts for the initrd (initrd_addr_max).
+    pub initrd_addr_max: u64,
+
+    /// Guest address of the 64-bit entry point.
+    pub entry: u64,
 }
 
-/// Load a Linux bzImage kernel into guest memory.
-///
-/// This function:
-/// 1. Reads the bzImage file from disk
-/// 2. Parses and validates the setup header
-/// 3. Loads the protected-mode kernel at the 1MB mark (0x100000)
-/// 4. Extracts the setup header for boot_params configuration
-///
-/// # Arguments
-///
-/// * `memory` - Guest memory to load the kernel into
-/// * `kernel_path` - Path to the bzImage file
-///
-/// # Returns
-///
-/// A `LoadedKernel` containing load addresses and setup header.
-///
-/// # Entry Point
-///
-/// For 64-bit boot, the entry point is `kernel_load + 0x200`. The first
-/// 512 bytes (0x000-0x1FF) contain the 16-bit entry point; the 64-bit
-/// entry point is at offset 0x200.
-pub fn load_kernel(memory: &GuestMemory, kernel_path: &str) -> Result<LoadedKernel, BootError> {
-    let mut file = File::open(kernel_path).map_err(BootError::ReadKernel)?;
-    let mut kernel_data = Vec::new();
-    file.read_to_end(&mut kernel_data)
-        .map_err(BootError::ReadKernel)?;
+/// A bzImage's setup header, as parsed from the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Boot protocol version (0x206).
+    pub version: u16,
+
+    /// Offset of the protected-mode kernel within the image.
+    pub setup_size: usize,
+
+    /// Raw setup header bytes to copy to boot_params.
+    pub setup_header: Vec<u8>,
 
-    eprintln!("[Boot] Kernel image size: {} bytes", kernel_data.len());
+    /// Highest address the kernel accepts for the initrd (initrd_addr_max).
+    pub initrd_addr_max: u64,
 
+    /// Memory the kernel needs from its load address before it has set up
+    /// its own allocator (init_size, 0 before boot protocol 2.10).
+    pub init_size: u64,
+}
+
+/// Parse and validate the setup header of the bzImage in `kernel_data`.
+pub fn parse_header(kernel_data: &[u8]) -> Result<Header, BootError> {
     // Validate minimum size for setup header
     if kernel_data.len() < 0x250 {
         return Err(BootError::InvalidKernel(
@@ -132,10 +147,10 @@ pub fn load_kernel(memory: &GuestMemory, kernel_path: &str) -> Result<LoadedKern
     let setup_sects = kernel_data[0x1f1];
     let setup_sects = if setup_sects == 0 { 4 } else { setup_sects };
 
-    eprintln!("[Boot] Setup header:");
-    eprintln!("  - Boot protocol version: {:#x}", version);
-    eprintln!("  - Setup sectors: {}", setup_sects);
-    eprintln!("  - Loadflags: {:#x}", kernel_data[0x211]);
+    debug!("[Boot] Setup header:");
+    debug!("  - Boot protocol version: {:#x}", version);
+    debug!("  - Setup sectors: {}", setup_sects);
+    debug!("  - Loadflags: {:#x}", kernel_data[0x211]);
 
     // Calculate offset to protected-mode kernel
     let setup_size = (setup_sects as usize + 1) * 512;
@@ -145,24 +160,167 @@ pub fn load_kernel(memory: &GuestMemory, kernel_path: &str) -> Result<LoadedKern
         ));
     }
 
+    // Extract setup header (0x1f1 to ~0x270) for boot_params
+    let header_end = (SETUP_HEADER_OFFSET + 0x80).min(kernel_data.len());
+    let setup_header = kernel_data[SETUP_HEADER_OFFSET..header_end].to_vec();
+
+    // initrd_addr_max at 0x22c (boot protocol 2.03+)
+    let initrd_addr_max = u32::from_le_bytes([
+        kernel_data[0x22c],
+        kernel_data[0x22d],
+        kernel_data[0x22e],
+        kernel_data[0x22f],
+    ]) as u64;
+
+    // init_size at 0x260 (boot protocol 2.10+): memory the kernel needs
+    // starting at the load address before it has set up its own allocator
+    let init_size = if version >= 0x020a && kernel_data.len() >= 0x264 {
+        u32::from_le_bytes([
+            kernel_data[0x260],
+            kernel_data[0x261],
+            kernel_data[0x262],
+            kernel_data[0x263],
+        ]) as u64
+    } else {
+        0
+    };
+
+    Ok(Header {
+        version,
+        setup_size,
+        setup_header,
+        initrd_addr_max,
+        init_size,
+    })
+}
+
+/// Load a Linux kernel into guest memory.
+///
+/// An uncompressed vmlinux (an ELF file) is handed to the ELF loader (see
+/// [`super::elf`]); anything else is a bzImage, whose header `image` has
+/// already parsed. For a bzImage, this function:
+/// 1. Loads the protected-mode kernel at the 1MB mark (0x100000), or, with
+///    a compression the VMM handles, the vmlinux inside it, at a random
+///    virtual address with `kaslr`
+/// 2. Extracts the setup header for boot_params configuration
+///
+/// # Arguments
+///
+/// * `memory` - Guest memory to load the kernel into
+/// * `image` - The bzImage or vmlinux file, mapped (see [`KernelImage`])
+/// * `kaslr` - Whether to randomize the address of a kernel decompressed
+///   on the host
+///
+/// # Returns
+///
+/// A `LoadedKernel` containing load addresses and setup header.
+///
+/// # Entry Point
+///
+/// For 64-bit boot, the entry point is `kernel_load + 0x200`. The first
+/// 512 bytes (0x000-0x1FF) contain the 16-bit entry point; the 64-bit
+/// entry point is at offset 0x200. A vmlinux decompressed on the host is
+/// entered at its ELF entry point instead.
+pub fn load_kernel(
+    memory: &GuestMemory,
+    image: &KernelImage,
+    kaslr: bool,
+) -> Result<LoadedKernel, BootError> {
+    let Some(header) = image.header() else {
+        return elf::load_vmlinux(memory, image.data());
+    };
+    let kernel_code = &image.data()[header.setup_size..];
+
+    // Load the vmlinux inside, if we can decompress it, with this header
+    if let Some(vmlinux) = image.vmlinux() {
+        let mut kernel = elf::load_vmlinux(memory, vmlinux)?;
+        kernel.setup_header = header.setup_header.clone();
+        kernel.initrd_addr_max = header.initrd_addr_max;
+        if !kaslr {
+            return Ok(kernel);
+        }
+        match kaslr::randomize(memory, vmlinux, &mut kernel) {
+            Ok(Some(offset)) => {
+                debug!("[Boot] KASLR: kernel mapped {offset:#x} above its link address");
+                return Ok(kernel);
+            }
+            Ok(None) => {
+                debug!("[Boot] Kernel can't be relocated; running it at its link address");
+                return Ok(kernel);
+            }
+            Err(e) => warn!(
+                "[Boot] Can't randomize the kernel's address ({e}); decompressing in the guest"
+            ),
+        }
+    }
+
     // Extract protected-mode kernel and load at 1MB
-    let kernel_code = &kernel_data[setup_size..];
     memory.write(layout::HIMEM_START, kernel_code)?;
 
-    eprintln!(
+    debug!(
         "[Boot] Loaded {} bytes of kernel code at {:#x}",
         kernel_code.len(),
         layout::HIMEM_START
     );
 
-    // Extract setup header (0x1f1 to ~0x270) for boot_params
-    let header_end = (SETUP_HEADER_OFFSET + 0x80).min(kernel_data.len());
-    let setup_header = kernel_data[SETUP_HEADER_OFFSET..header_end].to_vec();
+    let kernel_end = layout::HIMEM_START + header.init_size.max(kernel_code.len() as u64);
 
-    eprintln!(
-        "[Boot] Entry point at {:#x} (HIMEM_START + 0x200)",
-        layout::HIMEM_START + 0x200
-    );
+    let entry = layout::HIMEM_START + 0x200;
+    debug!("[Boot] Entry point at {:#x} (HIMEM_START + 0x200)", entry);
 
-    Ok(LoadedKernel { setup_header })
+    Ok(LoadedKernel {
+        setup_header: header.setup_header.clone(),
+        kernel_end,
+        initrd_addr_max: header.initrd_addr_max,
+        entry,
+    })
+}
+
+/// The vmlinux inside the bzImage `kernel_data` with header `header`, if
+/// the VMM can decompress it. `None` leaves it to the guest.
+pub fn vmlinux(kernel_data: &[u8], header: &Header) -> Option<Vec<u8>> {
+    if header.version < PAYLOAD_VERSION {
+        return None;
+    }
+    decompress_payload(kernel_data, &kernel_data[header.setup_size..])
+}
+
+/// Decompress the vmlinux in the protected-mode kernel `kernel_code`, as
+/// located by payload_offset (0x248) and payload_length (0x24c). `None`
+/// leaves it to the guest.
+fn decompress_payload(kernel_data: &[u8], kernel_code: &[u8]) -> Option<Vec<u8>> {
+    let field = |offset: usize| {
+        u32::from_le_bytes(kernel_data[offset..offset + 4].try_into().unwrap()) as usize
+    };
+    let (offset, length) = (field(0x248), field(0x24c));
+    let Some(payload) = kernel_code.get(offset..offset.saturating_add(length)) else {
+        warn!("[Boot] Kernel payload lies outside the image; decompressing in the guest");
+        return None;
+    };
+
+    let started = std::time::Instant::now();
+    match decompress::decompress(payload) {
+        Ok(Some(vmlinux)) if elf::is_elf(&vmlinux) => {
+            debug!(
+                "[Boot] Decompressed the kernel on the host: {} to {} bytes in {:?}",
+                payload.len(),
+                vmlinux.len(),
+                started.elapsed()
+            );
+            Some(vmlinux)
+        }
+        Ok(Some(_)) => {
+            warn!("[Boot] Kernel payload isn't a vmlinux; decompressing in the guest");
+            None
+        }
+        Ok(None) => {
+            let compression = decompress::Compression::detect(payload).unwrap();
+            debug!("[Boot] Kernel is {compression}-compressed; decompressing in the guest");
+            None
+        }
+        Err(e) => {
+            warn!("[Boot] Can't decompress the kernel ({e}); decompressing in the guest");
+            None
+        }
+    }
 }
@@ -0,0 +1,333 @@
+//! Host-side decompression of a bzImage's kernel.
+//!
+//! The protected-mode part of a bzImage is a small decompressor stub
+//! wrapped around the real kernel, a compressed vmlinux. Run in the guest,
+//! the stub decompresses it and jumps in; decompressing it in the VMM and
+//! loading the vmlinux directly (see [`super::elf`]) skips that step, which
+//! is most of the time between the first instruction and the kernel's
+//! first message.
+//!
+//! # Payload
+//!
+//! Boot protocol 2.08 added `payload_offset` (0x248) and `payload_length`
+//! (0x24c), locating the compressed kernel within the protected-mode code.
+//! The format is whatever the kernel was built with, told apart by its
+//! magic number; every format ends with the uncompressed size as a 32-bit
+//! little-endian number, which checks the result. It is only checked: the
+//! output buffer grows as output arrives rather than being sized from it,
+//! and decompression stops once the output is longer.
+//!
+//! | Format | Magic            | Decompressed here            |
+//! | ------ | ---------------- | ---------------------------- |
+//! | gzip   | `1f 8b`          | yes                          |
+//! | LZ4    | `02 21 4c 18`    | yes (legacy frame, `lz4 -l`) |
+//! | zstd   | `28 b5 2f fd`    | yes                          |
+//! | xz     | `fd 37 7a 58 5a` | yes (and its BCJ filters)    |
+//! | LZMA   | `5d 00 00`       | no                           |
+//! | bzip2  | `42 5a 68`       | no                           |
+//! | LZO    | `89 4c 5a 4f`    | no                           |
+//!
+//! A format not decompressed here is left to the guest's stub, as before.
+
+use flate2::read::GzDecoder;
+use lzma_rust2::XzReader;
+use ruzstd::decoding::StreamingDecoder;
+use std::fmt;
+use std::io::{self, Read};
+
+/// LZ4 legacy frame magic, also repeated between concatenated frames.
+const LZ4_LEGACY_MAGIC: u32 = 0x184c_2102;
+
+/// Largest block an LZ4 legacy frame holds, uncompressed.
+const LZ4_LEGACY_BLOCK: usize = 8 << 20;
+
+const LZ4_TRUNCATED: &str = "block truncated";
+
+/// Most output buffer allocated up front on the size trailer's say; past
+/// this, the buffer grows with the output. Kernels are smaller.
+const MAX_RESERVE: usize = 64 << 20;
+
+/// Compression of a bzImage payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Lz4,
+    Zstd,
+    Xz,
+    Lzma,
+    Bzip2,
+    Lzo,
+}
+
+impl Compression {
+    /// The compression `payload` starts with, if it is known.
+    pub fn detect(payload: &[u8]) -> Option<Self> {
+        const MAGIC: &[(&[u8], Compression)] = &[
+            (&[0x1f, 0x8b], Compression::Gzip),
+            (&[0x1f, 0x9e], Compression::Gzip),
+            (&[0x02, 0x21, 0x4c, 0x18], Compression::Lz4),
+            (&[0x28, 0xb5, 0x2f, 0xfd], Compression::Zstd),
+            (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Compression::Xz),
+            (&[0x5d, 0x00, 0x00], Compression::Lzma),
+            (b"BZh", Compression::Bzip2),
+            (&[0x89, b'L', b'Z', b'O'], Compression::Lzo),
+        ];
+        MAGIC
+            .iter()
+            .find(|(magic, _)| payload.starts_with(magic))
+            .map(|&(_, compression)| compression)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Gzip => "gzip",
+            Compression::Lz4 => "LZ4",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Lzma => "LZMA",
+            Compression::Bzip2 => "bzip2",
+            Compression::Lzo => "LZO",
+        })
+    }
+}
+
+/// Decompress a bzImage payload. `Ok(None)` means its format is one to
+/// leave to the guest.
+pub fn decompress(payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let compression = Compression::detect(payload).ok_or("unknown compression")?;
+    if payload.len() < 4 {
+        return Err("payload truncated".into());
+    }
+    let (stream, size) = payload.split_at(payload.len() - 4);
+    let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+
+    let data = match compression {
+        Compression::Gzip => read_output(GzDecoder::new(payload), size),
+        Compression::Lz4 => lz4_legacy(stream, size).map_err(io::Error::other),
+        Compression::Zstd => StreamingDecoder::new(stream)
+            .map_err(io::Error::other)
+            .and_then(|decoder| read_output(decoder, size)),
+        Compression::Xz => read_output(XzReader::new(stream, false), size),
+        _ => return Ok(None),
+    }
+    .map_err(|e| format!("{compression}: {e}"))?;
+    // The trailer is the size modulo 4GB
+    if data.len() as u32 as usize != size {
+        return Err(format!(
+            "{compression}: decompressed to {} bytes, expected {size}",
+            data.len()
+        ));
+    }
+    Ok(Some(data))
+}
+
+/// Read what `decoder` decompresses, expected to be `size` bytes: reading
+/// stops a byte past that.
+fn read_output(decoder: impl Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size.min(MAX_RESERVE));
+    decoder.take(size as u64 + 1).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Decompress an LZ4 legacy frame (or several back to back) of `size`
+/// bytes, ignoring anything after the last block.
+fn lz4_legacy(mut stream: &[u8], size: usize) -> Result<Vec<u8>, &'static str> {
+    let mut data = Vec::with_capacity(size.min(MAX_RESERVE));
+    while stream.len() >= 4 {
+        let word = u32::from_le_bytes(stream[..4].try_into().unwrap());
+        stream = &stream[4..];
+        if word == LZ4_LEGACY_MAGIC {
+            continue;
+        }
+        // Anything but a block that fits is past the end of the frame
+        let Some(block) = stream.get(..word as usize) else {
+            break;
+        };
+        lz4_block(block, &mut data)?;
+        if data.len() > size {
+            return Err("longer than the size trailer");
+        }
+        stream = &stream[block.len()..];
+    }
+    Ok(data)
+}
+
+/// Decompress one LZ4 block, appending to `out`. Blocks in a legacy frame
+/// don't refer back to earlier ones.
+fn lz4_block(block: &[u8], out: &mut Vec<u8>) -> Result<(), &'static str> {
+    let start = out.len();
+    let mut pos = 0;
+    while pos < block.len() {
+        let token = block[pos];
+        pos += 1;
+        let literals = lz4_length(token >> 4, block, &mut pos)?;
+        let literal = block.get(pos..pos + literals).ok_or(LZ4_TRUNCATED)?;
+        out.extend_from_slice(literal);
+        pos += literals;
+        // The last sequence is literals only
+        if pos == block.len() {
+            break;
+        }
+
+        let offset = block.get(pos..pos + 2).ok_or(LZ4_TRUNCATED)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        let matched = lz4_length(token & 0xf, block, &mut pos)? + 4;
+        if offset == 0 || offset > out.len() - start {
+            return Err("match before the start of the block");
+        }
+        if out.len() - start + matched > LZ4_LEGACY_BLOCK {
+            return Err("block too large");
+        }
+        // Copied a byte at a time: the match may overlap its own output
+        let from = out.len() - offset;
+        for i in 0..matched {
+            out.push(out[from + i]);
+        }
+    }
+    Ok(())
+}
+
+/// An LZ4 length: a nibble, continued while it is 15 by bytes until one
+/// isn't 255.
+fn lz4_length(nibble: u8, block: &[u8], pos: &mut usize) -> Result<usize, &'static str> {
+    let mut length = nibble as usize;
+    if nibble == 15 {
+        loop {
+            let byte = *block.get(*pos).ok_or(LZ4_TRUNCATED)?;
+            *pos += 1;
+            length += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn with_size(mut stream: Vec<u8>, size: usize) -> Vec<u8> {
+        stream.extend_from_slice(&(size as u32).to_le_bytes());
+        stream
+    }
+
+    #[test]
+    fn test_detect() {
+        let detect = |magic: &[u8]| Compression::detect(magic);
+        assert_eq!(detect(&[0x1f, 0x8b, 8, 0]), Some(Compression::Gzip));
+        assert_eq!(detect(&[0x02, 0x21, 0x4c, 0x18, 0]), Some(Compression::Lz4));
+        assert_eq!(detect(&[0x28, 0xb5, 0x2f, 0xfd]), Some(Compression::Zstd));
+        assert_eq!(detect(b"\xfd7zXZ\0\0"), Some(Compression::Xz));
+        assert_eq!(detect(b"BZh91AY"), Some(Compression::Bzip2));
+        assert_eq!(detect(b"\x7fELF"), None);
+
+        // Left to the guest
+        let bzip2 = with_size(b"BZh91AY&SY".to_vec(), 100);
+        assert_eq!(decompress(&bzip2), Ok(None));
+        assert!(decompress(b"\x7fELF\0\0\0\0").is_err());
+    }
+
+    fn kernel() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_zstd() {
+        let kernel = kernel();
+        let stream = ruzstd::encoding::compress_to_vec(
+            &kernel[..],
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+        let payload = with_size(stream.clone(), kernel.len());
+        assert_eq!(decompress(&payload).unwrap().unwrap(), kernel);
+        assert!(decompress(&with_size(stream.clone(), kernel.len() - 1)).is_err());
+        assert!(decompress(&with_size(
+            stream[..stream.len() / 2].to_vec(),
+            kernel.len()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_xz() {
+        // As the kernel compresses itself: x86 BCJ, then LZMA2
+        let kernel = kernel();
+        let mut options = lzma_rust2::XzOptions::with_preset(6);
+        options.set_check_sum_type(lzma_rust2::CheckType::Crc32);
+        options.prepend_pre_filter(lzma_rust2::FilterType::BcjX86, 0);
+        let mut writer = lzma_rust2::XzWriter::new(Vec::new(), options).unwrap();
+        writer.write_all(&kernel).unwrap();
+        let stream = writer.finish().unwrap();
+        let payload = with_size(stream.clone(), kernel.len());
+        assert_eq!(decompress(&payload).unwrap().unwrap(), kernel);
+        assert!(decompress(&with_size(
+            stream[..stream.len() / 2].to_vec(),
+            kernel.len()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_size_trailer_bounds_output() {
+        // A trailer claiming 4 GiB reserves no more than MAX_RESERVE, and
+        // output past a smaller claim stops the read
+        let kernel = kernel();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&kernel).unwrap();
+        let payload = encoder.finish().unwrap();
+        let data = read_output(GzDecoder::new(&payload[..]), u32::MAX as usize).unwrap();
+        assert_eq!(data, kernel);
+        let data = read_output(GzDecoder::new(&payload[..]), 1000).unwrap();
+        assert_eq!(data.len(), 1001);
+    }
+
+    #[test]
+    fn test_gzip() {
+        let kernel = kernel();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&kernel).unwrap();
+        let payload = encoder.finish().unwrap();
+        assert_eq!(decompress(&payload).unwrap().unwrap(), kernel);
+        assert!(decompress(&payload[..payload.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_lz4_legacy() {
+        // "abcabcabcabcabc!" as literals "abc", a 12-byte overlapping
+        // match at offset 3, then the literal "!"
+        let block = [0x38, b'a', b'b', b'c', 3, 0, 0x10, b'!'];
+        let mut frame = LZ4_LEGACY_MAGIC.to_le_bytes().to_vec();
+        for _ in 0..2 {
+            frame.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            frame.extend_from_slice(&block);
+        }
+        let expected = b"abcabcabcabcabc!abcabcabcabcabc!".to_vec();
+        let payload = with_size(frame.clone(), expected.len());
+        assert_eq!(decompress(&payload).unwrap().unwrap(), expected);
+
+        // The size trailer has to match
+        let payload = with_size(frame.clone(), expected.len() + 1);
+        assert!(decompress(&payload).is_err());
+
+        // A match can't reach back before the block
+        let mut bad = frame.clone();
+        bad[12] = 4;
+        assert!(decompress(&with_size(bad, expected.len())).is_err());
+
+        // Long literal run: nibble 15 plus 255 + 5
+        let literals: Vec<u8> = (0..275u32).map(|i| i as u8).collect();
+        let mut block = vec![0xf0, 255, 5];
+        block.extend_from_slice(&literals);
+        let mut out = Vec::new();
+        lz4_block(&block, &mut out).unwrap();
+        assert_eq!(out, literals);
+        assert!(lz4_block(&block[..100], &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+//! Linux vmlinux (ELF) loader.
+//!
+//! Besides a bzImage, the kernel can be booted straight from the
+//! uncompressed `vmlinux` a kernel build leaves at the top of its tree. It
+//! skips the decompressor, so boots a little faster, and needs no
+//! packaging step for a custom kernel.
+//!
+//! # Loading
+//!
+//! A vmlinux is an ELF64 executable. Each `PT_LOAD` program header gives a
+//! slice of the file, its physical load address (`p_paddr`, 16MB up for a
+//! default x86_64 build) and its size in memory; the part past the file
+//! data (`.bss`, `.brk`) is zeroed. The 64-bit entry point, `startup_64`,
+//! is the ELF entry (`e_entry`), which x86_64 kernels link as a physical
+//! address; a virtual one is translated through the segment holding it.
+//!
+//! ```text
+//! 0x0010_0000 ─► free RAM
+//! 0x0100_0000 ─► .text, .rodata      (PT_LOAD, entry at its start)
+//!                .data               (PT_LOAD)
+//!                .init, .bss, .brk   (PT_LOAD)
+//! kernel_end  ─► free RAM
+//! ```
+//!
+//! The kernel is entered the same way as a bzImage's 64-bit entry point:
+//! in long mode, with RSI pointing at boot_params. A vmlinux carries no
+//! setup header, so [`setup_header`] supplies the fields the kernel reads
+//! from one.
+//!
+//! Reference: <https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html>
+
+use super::bzimage::LoadedKernel;
+use super::layout;
+use super::memory::GuestMemory;
+use super::BootError;
+
+/// ELF identification: "\x7fELF".
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+/// `e_ident[EI_CLASS]` for 64-bit objects.
+const ELFCLASS64: u8 = 2;
+
+/// `e_ident[EI_DATA]` for little-endian objects.
+const ELFDATA2LSB: u8 = 1;
+
+/// `e_machine` for x86_64.
+const EM_X86_64: u16 = 62;
+
+/// Size of the ELF64 file header.
+const EHDR_SIZE: usize = 64;
+
+/// Size of an ELF64 program header.
+const PHDR_SIZE: usize = 56;
+
+/// Program header type of a loadable segment.
+const PT_LOAD: u32 = 1;
+
+/// Size of an ELF64 section header.
+const SHDR_SIZE: usize = 64;
+
+/// Section type taking no space in the file (`.bss`).
+const SHT_NOBITS: u32 = 8;
+
+/// Boot protocol version claimed by the synthesized setup header: 2.15,
+/// the newest, since a vmlinux is always the kernel we load.
+const BOOT_VERSION: u16 = 0x020f;
+
+/// Whether `data` starts like an ELF file.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+/// Whatever follows the ELF file at the start of `data`: past its headers,
+/// sections and segments. Empty if `data` is nothing but the file, or if
+/// its headers don't add up.
+pub fn appended(data: &[u8]) -> &[u8] {
+    if data.len() < EHDR_SIZE || !is_elf(data) {
+        return &[];
+    }
+    let table = |offset: u64, entsize: u16, num: u16| {
+        let entsize = entsize as u64;
+        (0..num as u64).map(move |i| offset.saturating_add(i.saturating_mul(entsize)))
+    };
+    let shdrs = table(u64_at(data, 40), u16_at(data, 58), u16_at(data, 60));
+    let phdrs = table(u64_at(data, 32), u16_at(data, 54), u16_at(data, 56));
+
+    let mut end = EHDR_SIZE as u64;
+    for start in shdrs {
+        let Some(shdr) = data.get(start as usize..).and_then(|s| s.get(..SHDR_SIZE)) else {
+            return &[];
+        };
+        end = end.max(start + SHDR_SIZE as u64);
+        if u32_at(shdr, 4) != SHT_NOBITS {
+            end = end.max(u64_at(shdr, 24).saturating_add(u64_at(shdr, 32)));
+        }
+    }
+    for start in phdrs {
+        let Some(phdr) = data.get(start as usize..).and_then(|p| p.get(..PHDR_SIZE)) else {
+            return &[];
+        };
+        end = end.max(start + PHDR_SIZE as u64);
+        end = end.max(u64_at(phdr, 8).saturating_add(u64_at(phdr, 32)));
+    }
+    data.get(end as usize..).unwrap_or(&[])
+}
+
+/// A loadable segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+/// A parsed vmlinux: its loadable segments and physical entry point.
+#[derive(Debug, PartialEq, Eq)]
+struct Image {
+    segments: Vec<Segment>,
+    entry: u64,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Parse the headers of the vmlinux in `data`.
+fn parse(data: &[u8]) -> Result<Image, String> {
+    if data.len() < EHDR_SIZE || !is_elf(data) {
+        return Err("not an ELF file".into());
+    }
+    if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || u16_at(data, 18) != EM_X86_64 {
+        return Err("not a 64-bit x86 ELF file".into());
+    }
+    let phoff = u64_at(data, 32);
+    let phentsize = u16_at(data, 54) as usize;
+    let phnum = u16_at(data, 56) as usize;
+    if phentsize < PHDR_SIZE {
+        return Err(format!("bad program header size {phentsize}"));
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let start = usize::try_from(phoff)
+            .ok()
+            .and_then(|phoff| phoff.checked_add(i * phentsize))
+            .filter(|start| start + PHDR_SIZE <= data.len())
+            .ok_or("program headers past the end of the file")?;
+        let phdr = &data[start..start + PHDR_SIZE];
+        if u32_at(phdr, 0) != PT_LOAD {
+            continue;
+        }
+        let segment = Segment {
+            offset: u64_at(phdr, 8),
+            vaddr: u64_at(phdr, 16),
+            paddr: u64_at(phdr, 24),
+            filesz: u64_at(phdr, 32),
+            memsz: u64_at(phdr, 40),
+        };
+        if segment.filesz > segment.memsz
+            || segment.offset.saturating_add(segment.filesz) > data.len() as u64
+        {
+            return Err(format!("segment {i} past the end of the file"));
+        }
+        segments.push(segment);
+    }
+    if segments.is_empty() {
+        return Err("no loadable segments".into());
+    }
+
+    // x86_64 kernels link e_entry as a physical address; anything else is
+    // translated through the segment it falls in
+    let e_entry = u64_at(data, 24);
+    let contains = |base: u64, s: &Segment| e_entry >= base && e_entry - base < s.memsz;
+    let entry = if segments.iter().any(|s| contains(s.paddr, s)) {
+        e_entry
+    } else if let Some(s) = segments.iter().find(|s| contains(s.vaddr, s)) {
+        s.paddr + (e_entry - s.vaddr)
+    } else {
+        return Err(format!(
+            "entry point {e_entry:#x} is outside the loadable segments"
+        ));
+    };
+
+    Ok(Image { segments, entry })
+}
+
+/// Setup header fields for a kernel that has none, laid out as in a
+/// bzImage from offset 0x1f1.
+fn setup_header(initrd_addr_max: u32) -> Vec<u8> {
+    let mut header = vec![0u8; 0x80];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        let at = offset - 0x1f1;
+        header[at..at + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0x1fe, &0xaa55u16.to_le_bytes()); // boot_flag
+    put(0x202, b"HdrS"); // header
+    put(0x206, &BOOT_VERSION.to_le_bytes()); // version
+    put(0x22c, &initrd_addr_max.to_le_bytes()); // initrd_addr_max
+    put(0x238, &(layout::CMDLINE_MAX_SIZE as u32 - 1).to_le_bytes()); // cmdline_size
+    header
+}
+
+/// Load the vmlinux in `data` into guest memory.
+///
+/// Each segment is copied to its physical address, which must lie at or
+/// above the 1MB mark, clear of the boot structures, and within RAM below
+/// the MMIO hole.
+pub fn load_vmlinux(memory: &GuestMemory, data: &[u8]) -> Result<LoadedKernel, BootError> {
+    let image = parse(data).map_err(BootError::InvalidKernel)?;
+    let mem_size = memory.size().min(layout::MMIO_HOLE_START);
+
+    let mut kernel_end = 0;
+    for segment in &image.segments {
+        let end = segment.paddr.checked_add(segment.memsz);
+        if segment.paddr < layout::HIMEM_START || end.is_none_or(|end| end > mem_size) {
+            return Err(BootError::InvalidKernel(format!(
+                "segment at {:#x} ({} bytes) doesn't fit between 1MB and the end of RAM ({} bytes)",
+                segment.paddr, segment.memsz, mem_size
+            )));
+        }
+        let file = &data[segment.offset as usize..][..segment.filesz as usize];
+        memory.write(segment.paddr, file)?;
+        let bss = vec![0u8; (segment.memsz - segment.filesz) as usize];
+        memory.write(segment.paddr + segment.filesz, &bss)?;
+        kernel_end = kernel_end.max(segment.paddr + segment.memsz);
+
+        debug!(
+            "[Boot] Loaded {} bytes of vmlinux at {:#x} ({} in memory)",
+            segment.filesz, segment.paddr, segment.memsz
+        );
+    }
+
+    debug!("[Boot] Entry point at {:#x} (ELF entry)", image.entry);
+
+    // Same limit a bzImage declares for x86_64
+    let initrd_addr_max = 0x7fff_ffff;
+    Ok(LoadedKernel {
+        setup_header: setup_header(initrd_addr_max),
+        kernel_end,
+        initrd_addr_max: initrd_addr_max as u64,
+        entry: image.entry,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// A vmlinux with one segment per `(vaddr, paddr, contents, memsz)`.
+    fn vmlinux(entry: u64, segments: &[(u64, u64, &[u8], u64)]) -> Vec<u8> {
+        let mut data = vec![0u8; EHDR_SIZE + segments.len() * PHDR_SIZE];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = ELFCLASS64;
+        data[5] = ELFDATA2LSB;
+        data[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        data[24..32].copy_from_slice(&entry.to_le_bytes());
+        data[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        data[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for (i, &(vaddr, paddr, contents, memsz)) in segments.iter().enumerate() {
+            let offset = data.len() as u64;
+            data.extend_from_slice(contents);
+            let phdr = &mut data[EHDR_SIZE + i * PHDR_SIZE..][..PHDR_SIZE];
+            phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            for (at, value) in [
+                (8, offset),
+                (16, vaddr),
+                (24, paddr),
+                (32, contents.len() as u64),
+                (40, memsz),
+            ] {
+                phdr[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    const VIRT: u64 = 0xffff_ffff_8100_0000;
+
+    #[test]
+    fn test_parse_entry() {
+        let segments: &[(u64, u64, &[u8], u64)] =
+            &[(VIRT, 16 * MB, b"text", 4), (VIRT + MB, 17 * MB, b"", 64)];
+
+        // Physical, as x86_64 kernels link it
+        let image = parse(&vmlinux(16 * MB, segments)).unwrap();
+        assert_eq!(image.entry, 16 * MB);
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[1].memsz, 64);
+
+        // Virtual, translated to physical
+        let image = parse(&vmlinux(VIRT + MB + 8, segments)).unwrap();
+        assert_eq!(image.entry, 17 * MB + 8);
+
+        assert!(parse(&vmlinux(32 * MB, segments)).is_err());
+        assert!(parse(&vmlinux(16 * MB, &[])).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects() {
+        let good = vmlinux(2 * MB, &[(2 * MB, 2 * MB, b"code", 4)]);
+        assert!(parse(&good).is_ok());
+        assert!(parse(&good[..EHDR_SIZE]).is_err());
+        assert!(parse(&good[..good.len() - 1]).is_err());
+
+        let mut class32 = good.clone();
+        class32[4] = 1;
+        assert!(parse(&class32).is_err());
+        let mut arm64 = good.clone();
+        arm64[18] = 183;
+        assert!(parse(&arm64).is_err());
+        assert!(parse(b"MZ not an ELF file at all, only some other executable").is_err());
+    }
+
+    #[test]
+    fn test_appended() {
+        let mut data = vmlinux(2 * MB, &[(2 * MB, 2 * MB, b"text", 4)]);
+        assert!(appended(&data).is_empty());
+        data.extend_from_slice(b"relocs");
+        assert_eq!(appended(&data), b"relocs");
+
+        // Past the section headers, which end the file
+        data.truncate(data.len() - 6);
+        let shoff = data.len() as u64;
+        data.extend_from_slice(&[0u8; 2 * SHDR_SIZE]);
+        data[40..48].copy_from_slice(&shoff.to_le_bytes());
+        data[58..60].copy_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+        data[60..62].copy_from_slice(&2u16.to_le_bytes());
+        assert!(appended(&data).is_empty());
+        data.extend_from_slice(b"relocs");
+        assert_eq!(appended(&data), b"relocs");
+        assert!(appended(&data[..data.len() - 7]).is_empty());
+    }
+
+    #[test]
+    fn test_load_vmlinux() {
+        let memory = GuestMemory::new(8 * MB).unwrap();
+        // Stale data where .bss goes
+        memory.write(4 * MB + 4, &[0xff; 8]).unwrap();
+        let data = vmlinux(
+            2 * MB,
+            &[(2 * MB, 2 * MB, b"text", 4), (4 * MB, 4 * MB, b"data", 12)],
+        );
+        let kernel = load_vmlinux(&memory, &data).unwrap();
+        assert_eq!(kernel.entry, 2 * MB);
+        assert_eq!(kernel.kernel_end, 4 * MB + 12);
+
+        let mut buf = [0u8; 12];
+        memory.read(4 * MB, &mut buf).unwrap();
+        assert_eq!(&buf, b"data\0\0\0\0\0\0\0\0");
+        memory.read(2 * MB, &mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], b"text");
+
+        // The synthesized header looks like a bzImage's
+        let header = &kernel.setup_header;
+        assert_eq!(&header[0x202 - 0x1f1..][..4], b"HdrS");
+        assert_eq!(u16_at(header, 0x206 - 0x1f1), BOOT_VERSION);
+
+        // Segments must sit above 1MB and inside RAM
+        let low = vmlinux(0x8000, &[(0x8000, 0x8000, b"text", 4)]);
+        assert!(load_vmlinux(&memory, &low).is_err());
+        let high = vmlinux(7 * MB, &[(7 * MB, 7 * MB, b"text", 2 * MB)]);
+        assert!(load_vmlinux(&memory, &high).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Initial ramdisk (initrd/initramfs) loader.
+//!
+//! An initrd is an archive (usually a cpio "initramfs") that the kernel
+//! unpacks into its root filesystem before running `/init`. The boot loader
+//! only has to copy it somewhere in guest RAM and tell the kernel where:
+//!
+//! - **ramdisk_image** (0x218): Guest physical address of the initrd
+//! - **ramdisk_size** (0x21c): Size of the initrd in bytes
+//!
+//! # Placement
+//!
+//! Like most boot loaders, we place the initrd as high as possible: at the
+//! top of guest RAM, page-aligned, and no higher than the kernel's
+//! `initrd_addr_max`. This keeps it clear of the region the kernel
+//! decompresses itself into at the 1MB mark.
+//!
+//! ```text
+//! 0x0010_0000 ─► kernel (+ init_size for in-place decompression)
+//!      ...        free RAM
+//! initrd_addr ─► initrd (page aligned)
+//! mem_size    ─► end of RAM
+//! ```
+//!
+//! Reference: <https://www.kernel.org/doc/html/latest/x86/boot.html>
+
+use super::bzimage::LoadedKernel;
+use super::layout;
+use super::memory::GuestMemory;
+use super::BootError;
+use crate::audit;
+
+/// Initrd load addresses are aligned to a page boundary.
+const INITRD_ALIGN: u64 = 0x1000;
+
+/// Location of an initrd loaded into guest memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedInitrd {
+    /// Guest physical address of the first byte.
+    pub addr: u64,
+    /// Size in bytes.
+    pub size: u32,
+}
+
+/// Load an initrd image into the top of guest memory.
+///
+/// # Arguments
+///
+/// * `memory` - Guest memory to load the initrd into
+/// * `initrd_path` - Path to the initrd image (typically a cpio archive)
+/// * `mem_size` - Total guest memory size in bytes
+/// * `kernel` - The loaded kernel, whose header constrains placement
+pub fn load_initrd(
+    memory: &GuestMemory,
+    initrd_path: &str,
+    mem_size: u64,
+    kernel: &LoadedKernel,
+) -> Result<LoadedInitrd, BootError> {
+    let data = std::fs::read(initrd_path).map_err(|source| BootError::ReadInitrd {
+        path: initrd_path.to_string(),
+        source,
+    })?;
+    audit::record(audit::Kind::File, "read", initrd_path);
+    let size = data.len() as u64;
+
+    let addr = initrd_load_addr(size, mem_size, kernel)
+        .ok_or(BootError::InitrdTooLarge { size, mem_size })?;
+    memory.write(addr, &data)?;
+
+    debug!("[Boot] Loaded {} bytes of initrd at {:#x}", size, addr);
+
+    Ok(LoadedInitrd {
+        addr,
+        size: size as u32,
+    })
+}
+
+/// Pick the highest page-aligned address that fits an initrd of `size` bytes.
+///
+/// Returns `None` if the initrd would overlap the kernel or not fit below
+/// the MMIO hole (the ramdisk fields in boot_params are 32 bits wide, and
+/// RAM above the hole starts at 4GB).
+fn initrd_load_addr(size: u64, mem_size: u64, kernel: &LoadedKernel) -> Option<u64> {
+    // initrd_addr_max is the address of the last usable byte
+    let limit = mem_size
+        .min(kernel.initrd_addr_max.saturating_add(1))
+        .min(layout::MMIO_HOLE_START);
+    let addr = limit.checked_sub(size)? & !(INITRD_ALIGN - 1);
+
+    if addr < kernel.kernel_end {
+        return None;
+    }
+
+    Some(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn kernel(kernel_end: u64, initrd_addr_max: u64) -> LoadedKernel {
+        LoadedKernel {
+            setup_header: Vec::new(),
+            kernel_end,
+            initrd_addr_max,
+            entry: 0,
+        }
+    }
+
+    #[test]
+    fn test_initrd_placed_at_top_of_memory() {
+        let k = kernel(16 * MB, 0x7fff_ffff);
+        let addr = initrd_load_addr(9000, 128 * MB, &k).unwrap();
+        assert_eq!(addr % INITRD_ALIGN, 0);
+        assert!(addr + 9000 <= 128 * MB);
+        assert!(128 * MB - addr < 9000 + INITRD_ALIGN);
+    }
+
+    #[test]
+    fn test_initrd_respects_addr_max() {
+        let k = kernel(16 * MB, 64 * MB - 1);
+        let addr = initrd_load_addr(4096, 512 * MB, &k).unwrap();
+        assert_eq!(addr, 64 * MB - 4096);
+    }
+
+    #[test]
+    fn test_initrd_must_not_overlap_kernel() {
+        let k = kernel(60 * MB, 0x7fff_ffff);
+        assert!(initrd_load_addr(8 * MB, 64 * MB, &k).is_none());
+        assert!(initrd_load_addr(128 * MB, 64 * MB, &k).is_none());
+    }
+}
@@ -0,0 +1,284 @@
+//! Kernel address randomization (KASLR) for kernels the VMM decompresses.
+//!
+//! A bzImage's decompressor stub picks a random address for the kernel
+//! before jumping in. Decompressed on the host (see [`super::decompress`]),
+//! the kernel never runs the stub, so the VMM picks the offset instead,
+//! from the host's `/dev/urandom`. x86 has no setup_data entry for a KASLR
+//! seed (the stub hashes boot_params with RDRAND and the TSC), so this is
+//! how host entropy reaches the kernel's address, and the guest spends no
+//! RDRAND reads on it.
+//!
+//! # Relocations
+//!
+//! A kernel built with `CONFIG_RANDOMIZE_BASE` has its relocation table
+//! appended to the vmlinux inside the bzImage: 32-bit words, each the
+//! sign-extended virtual address of a field to adjust. Read from the end
+//! back, it is three zero-terminated lists:
+//!
+//! ```text
+//! vmlinux (ELF) | 0 | 64-bit ... | 0 | inverse 32-bit ... | 0 | 32-bit ...
+//! ```
+//!
+//! Moving the kernel up by `delta` adds `delta` to each 32- and 64-bit
+//! field and subtracts it from each inverse one (a per-CPU offset, counted
+//! back from the kernel).
+//!
+//! # Offset
+//!
+//! Only the virtual address moves. The kernel stays at its physical link
+//! address and maps itself `delta` higher, as the stub does when it keeps
+//! the kernel in place. `delta` is a multiple of the kernel's alignment
+//! (`kernel_alignment`, 0x230) that keeps it inside the 1GB kernel image
+//! area, the same choice the stub has. `KASLR_FLAG` in loadflags tells the
+//! kernel, which then randomizes its memory regions too.
+//!
+//! A kernel that isn't relocatable, or has no relocation table, runs at
+//! its link address, as does any kernel with `nokaslr` on its command line.
+
+use super::bzimage::LoadedKernel;
+use super::elf;
+use super::memory::GuestMemory;
+use super::params::host_random;
+use std::ops::Range;
+
+/// Virtual address the kernel maps physical address 0 at
+/// (`__START_KERNEL_map`).
+const START_KERNEL_MAP: u64 = 0xffff_ffff_8000_0000;
+
+/// Size of the kernel image area with `CONFIG_RANDOMIZE_BASE`
+/// (`KERNEL_IMAGE_SIZE`).
+const KERNEL_IMAGE_SIZE: u64 = 1 << 30;
+
+/// Smallest offset step: the kernel maps itself with 2MB pages.
+const MIN_ALIGN: u64 = 2 << 20;
+
+/// loadflags bit telling the kernel it was randomized.
+const KASLR_FLAG: u8 = 1 << 1;
+
+/// First boot protocol version with `pref_address` (2.10).
+const PREF_ADDRESS_VERSION: u16 = 0x020a;
+
+/// A kernel's relocation table: the virtual addresses of the fields to
+/// adjust, as their low 32 bits.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Relocs {
+    rel32: Vec<u32>,
+    inv32: Vec<u32>,
+    rel64: Vec<u32>,
+}
+
+impl Relocs {
+    /// Parse a relocation table that is exactly `table`.
+    fn parse(table: &[u8]) -> Result<Self, String> {
+        if !table.len().is_multiple_of(4) {
+            return Err(format!("{} bytes isn't a whole table", table.len()));
+        }
+        let mut words = table
+            .chunks_exact(4)
+            .rev()
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+        let mut list = || {
+            let mut list = Vec::new();
+            loop {
+                match words.next() {
+                    Some(0) => return Ok(list),
+                    Some(word) => list.push(word),
+                    None => return Err("table truncated".to_string()),
+                }
+            }
+        };
+        let relocs = Relocs {
+            rel32: list()?,
+            inv32: list()?,
+            rel64: list()?,
+        };
+        if words.next().is_some() {
+            return Err("data before the table".into());
+        }
+        Ok(relocs)
+    }
+
+    /// Apply the table to a kernel loaded at its link address, occupying
+    /// guest addresses `image`, and moved up by `delta`. Nothing is
+    /// written unless every field lies inside `image`.
+    fn apply(&self, memory: &GuestMemory, image: Range<u64>, delta: u64) -> Result<(), String> {
+        let addr = |reloc: u32| (reloc as i32 as u64).wrapping_sub(START_KERNEL_MAP);
+        let lists = [(&self.rel32, 4), (&self.inv32, 4), (&self.rel64, 8)];
+        for (list, width) in lists {
+            if let Some(&bad) = list.iter().find(|&&reloc| {
+                let addr = addr(reloc);
+                addr < image.start || addr.saturating_add(width) > image.end
+            }) {
+                return Err(format!(
+                    "relocation at {:#x} lies outside the kernel",
+                    bad as i32 as u64
+                ));
+            }
+        }
+
+        let error = |e: super::BootError| e.to_string();
+        for &reloc in &self.rel32 {
+            let mut field = [0u8; 4];
+            memory.read(addr(reloc), &mut field).map_err(error)?;
+            let value = u32::from_le_bytes(field).wrapping_add(delta as u32);
+            memory.write_u32(addr(reloc), value).map_err(error)?;
+        }
+        for &reloc in &self.inv32 {
+            let mut field = [0u8; 4];
+            memory.read(addr(reloc), &mut field).map_err(error)?;
+            let value = u32::from_le_bytes(field).wrapping_sub(delta as u32);
+            memory.write_u32(addr(reloc), value).map_err(error)?;
+        }
+        for &reloc in &self.rel64 {
+            let mut field = [0u8; 8];
+            memory.read(addr(reloc), &mut field).map_err(error)?;
+            let value = u64::from_le_bytes(field).wrapping_add(delta);
+            memory.write_u64(addr(reloc), value).map_err(error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The offset for a kernel linked at `load_addr` and taking `image_size`
+/// bytes, in steps of `align`: slot `random` of those that keep it inside
+/// the kernel image area.
+fn choose_offset(load_addr: u64, image_size: u64, align: u64, random: u64) -> u64 {
+    let align = align.max(MIN_ALIGN);
+    let room = KERNEL_IMAGE_SIZE.saturating_sub(load_addr.saturating_add(image_size));
+    (random % (room / align + 1)) * align
+}
+
+/// Move the vmlinux in `vmlinux`, just loaded as `kernel` at its link
+/// address, to a random virtual address, and mark its setup header
+/// randomized.
+///
+/// Returns the offset from the link address, or `None` for a kernel that
+/// can't be moved. After an error, guest memory is as it was.
+pub fn randomize(
+    memory: &GuestMemory,
+    vmlinux: &[u8],
+    kernel: &mut LoadedKernel,
+) -> Result<Option<u64>, String> {
+    let header = &kernel.setup_header;
+    let field = |offset: usize, len: usize| {
+        let at = offset - 0x1f1;
+        header.get(at..at + len).map(|bytes| {
+            let mut value = [0u8; 8];
+            value[..len].copy_from_slice(bytes);
+            u64::from_le_bytes(value)
+        })
+    };
+    let version = field(0x206, 2).unwrap_or(0) as u16;
+    let relocatable = field(0x234, 1).unwrap_or(0) != 0;
+    let table = elf::appended(vmlinux);
+    if version < PREF_ADDRESS_VERSION || !relocatable || table.is_empty() {
+        return Ok(None);
+    }
+    let load_addr = field(0x258, 8).unwrap_or(0);
+    let align = field(0x230, 4).unwrap_or(0);
+    if load_addr >= kernel.kernel_end {
+        return Err(format!(
+            "kernel ends at {:#x}, below its load address {load_addr:#x}",
+            kernel.kernel_end
+        ));
+    }
+
+    let relocs = Relocs::parse(table).map_err(|e| format!("bad relocation table: {e}"))?;
+    let random = host_random(8).map_err(|e| format!("no host randomness: {e}"))?;
+    let image = load_addr..kernel.kernel_end;
+    let delta = choose_offset(
+        load_addr,
+        image.end - image.start,
+        align,
+        u64::from_le_bytes(random.try_into().unwrap()),
+    );
+    if delta != 0 {
+        relocs.apply(memory, image, delta)?;
+    }
+    kernel.setup_header[0x211 - 0x1f1] |= KASLR_FLAG;
+    Ok(Some(delta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// A relocation table holding the given lists, laid out back to front.
+    fn table(rel32: &[u32], inv32: &[u32], rel64: &[u32]) -> Vec<u8> {
+        let mut words = vec![0];
+        for list in [rel64, inv32, rel32] {
+            words.extend(list.iter().rev());
+            words.push(0);
+        }
+        words.pop();
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_parse_relocs() {
+        let relocs = Relocs::parse(&table(&[1, 2], &[3], &[4, 5, 6])).unwrap();
+        assert_eq!(relocs.rel32, [1, 2]);
+        assert_eq!(relocs.inv32, [3]);
+        assert_eq!(relocs.rel64, [4, 5, 6]);
+
+        let empty = Relocs::parse(&[0; 12]).unwrap();
+        assert_eq!(empty, Relocs::default());
+
+        // Two terminators missing, a ragged end, and something before it
+        assert!(Relocs::parse(&[0; 4]).is_err());
+        assert!(Relocs::parse(&[0; 13]).is_err());
+        assert!(Relocs::parse(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_choose_offset() {
+        // A 30MB kernel at 16MB has (1GB - 46MB) / 2MB + 1 = 490 slots
+        assert_eq!(choose_offset(16 * MB, 30 * MB, 2 * MB, 0), 0);
+        assert_eq!(choose_offset(16 * MB, 30 * MB, 2 * MB, 489), 489 * 2 * MB);
+        assert_eq!(choose_offset(16 * MB, 30 * MB, 2 * MB, 490), 0);
+        // Never less than 2MB steps, and never past the image area
+        assert_eq!(choose_offset(16 * MB, 30 * MB, 4096, 1), 2 * MB);
+        assert_eq!(choose_offset(16 * MB, 2048 * MB, 2 * MB, 7), 0);
+    }
+
+    #[test]
+    fn test_apply() {
+        let memory = GuestMemory::new(8 * MB).unwrap();
+        let virt = |phys: u64| (START_KERNEL_MAP + phys) as u32;
+        let kernel = 2 * MB;
+        memory
+            .write_u32(kernel + 0x10, virt(kernel + 0x100))
+            .unwrap();
+        memory.write_u32(kernel + 0x20, -0x1000i32 as u32).unwrap();
+        memory
+            .write_u64(kernel + 0x30, START_KERNEL_MAP + kernel + 0x200)
+            .unwrap();
+        let relocs = Relocs {
+            rel32: vec![virt(kernel + 0x10)],
+            inv32: vec![virt(kernel + 0x20)],
+            rel64: vec![virt(kernel + 0x30)],
+        };
+        relocs.apply(&memory, kernel..kernel + MB, 4 * MB).unwrap();
+
+        let mut field = [0u8; 8];
+        memory.read(kernel + 0x10, &mut field[..4]).unwrap();
+        assert_eq!(&field[..4], &virt(6 * MB + 0x100).to_le_bytes());
+        memory.read(kernel + 0x20, &mut field[..4]).unwrap();
+        let inverse = -(0x1000 + 4 * MB as i32);
+        assert_eq!(&field[..4], &inverse.to_le_bytes());
+        memory.read(kernel + 0x30, &mut field).unwrap();
+        assert_eq!(field, (START_KERNEL_MAP + 6 * MB + 0x200).to_le_bytes());
+
+        // A field outside the kernel fails before anything is written
+        let outside = Relocs {
+            rel32: vec![virt(kernel + 0x10)],
+            rel64: vec![virt(kernel + MB - 4)],
+            ..Default::default()
+        };
+        assert!(outside.apply(&memory, kernel..kernel + MB, 2 * MB).is_err());
+        memory.read(kernel + 0x10, &mut field[..4]).unwrap();
+        assert_eq!(&field[..4], &virt(6 * MB + 0x100).to_le_bytes());
+    }
+}
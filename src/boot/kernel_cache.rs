@@ -0,0 +1,254 @@
+//! Kernel images shared by the VMs in one process.
+//!
+//! Booting a kernel reads the image, parses its setup header and, for most
+//! bzImages, decompresses the vmlinux inside (see [`super::decompress`]).
+//! When one process boots many VMs from the same kernel (`carbon bench`, or
+//! an embedder launching VMs back to back), [`KernelImage::shared`] hands
+//! each the same [`KernelImage`]: the file is mapped once, its header parsed
+//! once, and the payload decompressed by the first VM that loads it.
+//!
+//! Images are identified by device, inode, size and modification time, so
+//! a kernel rebuilt between VMs is read afresh. An image lives as long as
+//! anything holds it; to keep one between VMs that run one after another,
+//! hold the `Arc` across them. As with any mapped file, replace a kernel
+//! by renaming a new file over it: one truncated in place while a VM loads
+//! from it faults the process.
+
+use super::bzimage::{self, Header};
+use super::elf;
+use super::BootError;
+use crate::audit;
+use crate::digest::Sha256Digest;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+/// Identity of a kernel file on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ImageKey {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+}
+
+impl ImageKey {
+    fn of(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.len(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        })
+    }
+}
+
+/// Images some VM holds, so later VMs can find them.
+static IMAGES: Mutex<Vec<(ImageKey, Weak<KernelImage>)>> = Mutex::new(Vec::new());
+
+/// A read-only private mapping of a whole file.
+struct Mapping {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+// Safety: the mapping is read-only and only unmapped on drop, so any thread
+// may read it.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> io::Result<Self> {
+        if len == 0 {
+            return Ok(Self {
+                addr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // SAFETY: mapping a regular file we hold open; the result is checked
+        // and unmapped on drop.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { addr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: `addr` maps `len` readable bytes until drop.
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            // SAFETY: unmapping exactly what `new` mapped.
+            unsafe { libc::munmap(self.addr, self.len) };
+        }
+    }
+}
+
+/// A kernel file, mapped, with its header parsed and its decompressed
+/// vmlinux kept once it has been needed.
+pub struct KernelImage {
+    map: Mapping,
+    /// The bzImage's setup header, `None` for a vmlinux.
+    header: Option<Header>,
+    vmlinux: OnceLock<Option<Vec<u8>>>,
+    sha256: OnceLock<Sha256Digest>,
+}
+
+impl KernelImage {
+    /// The image of the kernel at `path`, mapped and parsed unless some VM
+    /// in this process already holds it.
+    pub fn shared(path: &str) -> Result<Arc<Self>, BootError> {
+        let read_error = |source| BootError::ReadKernel {
+            path: path.to_string(),
+            source,
+        };
+        let file = File::open(path).map_err(read_error)?;
+        audit::record(audit::Kind::File, "read", path);
+        let key = ImageKey::of(&file).map_err(read_error)?;
+
+        let mut images = IMAGES.lock().unwrap_or_else(|e| e.into_inner());
+        images.retain(|(_, image)| image.strong_count() > 0);
+        if let Some(image) = images
+            .iter()
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, image)| image.upgrade())
+        {
+            debug!("[Boot] Kernel image already mapped by this process");
+            return Ok(image);
+        }
+        let map = Mapping::new(&file, key.size as usize).map_err(read_error)?;
+        let image = Arc::new(Self::new(map)?);
+        images.push((key, Arc::downgrade(&image)));
+        Ok(image)
+    }
+
+    fn new(map: Mapping) -> Result<Self, BootError> {
+        let data = map.bytes();
+        debug!("[Boot] Kernel image size: {} bytes", data.len());
+        let header = if elf::is_elf(data) {
+            None
+        } else {
+            Some(bzimage::parse_header(data)?)
+        };
+        Ok(Self {
+            map,
+            header,
+            vmlinux: OnceLock::new(),
+            sha256: OnceLock::new(),
+        })
+    }
+
+    /// The whole file.
+    pub fn data(&self) -> &[u8] {
+        self.map.bytes()
+    }
+
+    /// The setup header of a bzImage; `None` for a vmlinux.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// The SHA-256 digest of the file, computed on the first call.
+    pub fn sha256(&self) -> Sha256Digest {
+        *self.sha256.get_or_init(|| Sha256Digest::of(self.data()))
+    }
+
+    /// The vmlinux inside a bzImage, decompressed on the first call. `None`
+    /// for a vmlinux, or a bzImage whose payload is left to the guest.
+    pub fn vmlinux(&self) -> Option<&[u8]> {
+        self.vmlinux
+            .get_or_init(|| {
+                let header = self.header.as_ref()?;
+                bzimage::vmlinux(self.data(), header)
+            })
+            .as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn kernel(name: &str, data: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("carbon-kernel-cache-{}-{name}", std::process::id()));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    /// A bzImage with one setup sector whose payload is `vmlinux`, gzipped.
+    fn bzimage(vmlinux: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(vmlinux).unwrap();
+        let payload = encoder.finish().unwrap();
+
+        let mut data = vec![0u8; 1024];
+        data[0x1f1] = 1;
+        data[0x202..0x206].copy_from_slice(b"HdrS");
+        data[0x206..0x208].copy_from_slice(&0x020fu16.to_le_bytes());
+        data[0x24c..0x250].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&payload);
+        data
+    }
+
+    #[test]
+    fn test_shared() {
+        let path = kernel("shared", b"\x7fELF, as far as the cache can tell");
+        let path = path.to_str().unwrap();
+        let first = KernelImage::shared(path).unwrap();
+        let second = KernelImage::shared(path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(first.header().is_none());
+        assert!(first.vmlinux().is_none());
+        assert_eq!(
+            first.sha256(),
+            Sha256Digest::of(b"\x7fELF, as far as the cache can tell")
+        );
+
+        // Replaced, it is a different kernel
+        let rebuilt = kernel("rebuilt", b"\x7fELF, rebuilt since");
+        std::fs::rename(rebuilt, path).unwrap();
+        let rebuilt = KernelImage::shared(path).unwrap();
+        assert!(!Arc::ptr_eq(&first, &rebuilt));
+        assert_eq!(rebuilt.data(), b"\x7fELF, rebuilt since");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_vmlinux_decompressed_once() {
+        let path = kernel("bzimage", &bzimage(b"\x7fELF vmlinux"));
+        let image = KernelImage::shared(path.to_str().unwrap()).unwrap();
+        assert_eq!(image.header().unwrap().setup_size, 1024);
+        let vmlinux = image.vmlinux().unwrap();
+        assert_eq!(vmlinux, b"\x7fELF vmlinux");
+        assert!(std::ptr::eq(vmlinux, image.vmlinux().unwrap()));
+        std::fs::remove_file(path).ok();
+
+        let path = kernel("not-a-kernel", b"MZ");
+        assert!(KernelImage::shared(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).ok();
+    }
+}
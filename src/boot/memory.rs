@@ -31,18 +31,33 @@
 //! 0x00100000 ├─────────────────┤
 //!            │ Kernel Code     │ ← bzImage loaded here
 //!            │                 │
-//!            │                 │
 //!            │ High Memory     │ ← Available RAM
 //!            │                 │
-//! mem_size   └─────────────────┘
+//! 0xc0000000 ├─────────────────┤ ← or mem_size, if smaller
+//!            │ MMIO hole       │ ← virtio-mmio, PCI, IOAPIC, LAPIC
+//! 4GB        ├─────────────────┤
+//!            │ RAM above 4GB   │ ← whatever didn't fit below the hole
+//!            └─────────────────┘
 //! ```
 //!
+//! Each RAM region is its own KVM memory slot (see
+//! [`layout::ram_regions`](super::layout::ram_regions)).
+//!
 //! # Memory Limits
 //!
 //! - **Minimum**: Must be > 1MB to load kernel at the 1MB mark
-//! - **Maximum for identity mapping**: 1GB (512 × 2MB pages)
-//!   - Larger VMs work fine; the kernel sets up its own page tables during boot
-//!   - Only the first 1GB is identity-mapped for early boot
+//! - **Identity mapping**: RAM below the MMIO hole, in 1GB steps (see
+//!   `paging::setup_page_tables`)
+//!   - Larger VMs work fine; the kernel sets up its own page tables during boot,
+//!     and maps RAM above 4GB itself
+//!
+//! # Backing
+//!
+//! RAM is private anonymous memory unless a [`MemoryBacking`] asks for a
+//! memfd or a named file (`--memory-backend`), or vhost-user devices need
+//! one to map. All regions are then consecutive in the one file, which
+//! other processes can map through its descriptor or path; a sealed memfd's
+//! size can't change under them.
 //!
 //! # Usage
 //!
@@ -58,34 +73,180 @@
 //! // Write typed values (little-endian)
 //! memory.write_obj(0xDEADBEEF_u32, GuestAddress(0x100000))?;
 //!
-//! // Get host pointer for KVM registration
-//! let (host_addr, size) = memory.as_raw_parts();
+//! // Get host pointers for KVM registration
+//! for region in memory.regions() {
+//!     vm.set_user_memory_region(slot, region.guest_addr, region.size, region.host_addr)?;
+//! }
 //! ```
 
-use super::BootError;
-use vm_memory::{Bytes, GuestAddress, GuestMemory as GuestMemoryTrait, GuestMemoryMmap};
+use super::{layout, BootError};
+use crate::kvm::GuestRam;
+use crate::size::ByteSize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use vm_memory::mmap::MmapRegionBuilder;
+use vm_memory::{
+    Bytes, FileOffset, GuestAddress, GuestMemory as GuestMemoryTrait, GuestMemoryMmap,
+    GuestMemoryRegion, GuestRegionMmap,
+};
+
+/// What backs guest RAM (`--memory-backend`).
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MemoryBacking {
+    /// Private anonymous memory (`anonymous`). Where vhost-user devices
+    /// need to map guest memory, a memfd is used instead.
+    #[default]
+    Anonymous,
+    /// A memfd (`memfd`), shared with whoever holds its descriptor:
+    /// vhost-user backends map it, and it can be read for a snapshot or
+    /// handed to a cloned VM without copying.
+    Memfd,
+    /// A memfd sealed at its size (`sealed`), so a process it is shared
+    /// with can neither truncate it under the guest nor grow it, and can
+    /// trust its size without checking.
+    Sealed,
+    /// A named file (`file=PATH[,shared=on|off]`), e.g. under /dev/shm,
+    /// created if missing and grown to the size of RAM. Shared, the
+    /// guest's writes land in the file, where other processes (a memory
+    /// inspector, a vhost-user backend) see them. Private, the default as
+    /// in QEMU, the file only provides the initial contents, copied on
+    /// write.
+    File { path: PathBuf, shared: bool },
+}
+
+impl MemoryBacking {
+    /// Whether other processes can map the memory and see the guest's
+    /// writes, as vhost-user backends need.
+    pub fn is_shared(&self) -> bool {
+        !matches!(
+            self,
+            MemoryBacking::Anonymous | MemoryBacking::File { shared: false, .. }
+        )
+    }
+}
+
+impl FromStr for MemoryBacking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "anonymous" => return Ok(MemoryBacking::Anonymous),
+            "memfd" => return Ok(MemoryBacking::Memfd),
+            "sealed" => return Ok(MemoryBacking::Sealed),
+            _ => {}
+        }
+        let Some(spec) = s.strip_prefix("file=") else {
+            return Err(format!(
+                "invalid memory backend {s:?} (expected anonymous, memfd, sealed or file=PATH)"
+            ));
+        };
+        let mut options = spec.split(',');
+        let path = options.next().unwrap_or_default();
+        if path.is_empty() {
+            return Err("file memory backend needs file=PATH".into());
+        }
+        let mut shared = false;
+        for option in options {
+            shared = match option.split_once('=') {
+                Some(("shared", "on")) => true,
+                Some(("shared", "off")) => false,
+                _ => return Err(format!("invalid memory backend option {option:?}")),
+            };
+        }
+        Ok(MemoryBacking::File {
+            path: path.into(),
+            shared,
+        })
+    }
+}
+
+impl fmt::Display for MemoryBacking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryBacking::Anonymous => f.write_str("anonymous"),
+            MemoryBacking::Memfd => f.write_str("memfd"),
+            MemoryBacking::Sealed => f.write_str("sealed"),
+            MemoryBacking::File { path, shared } => {
+                let shared = if *shared { "on" } else { "off" };
+                write!(f, "file={},shared={shared}", path.display())
+            }
+        }
+    }
+}
+
+/// Whether the host may swap guest RAM out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SwapPolicy {
+    /// Left to the host: pages are swapped out under memory pressure, as
+    /// for any process.
+    #[default]
+    Default,
+    /// Locked into host memory (`--mlock`), so a page fault never waits on
+    /// swap. Needs an RLIMIT_MEMLOCK of at least the RAM size, or
+    /// CAP_IPC_LOCK.
+    Locked,
+    /// Offered to swap (`--swappable`): the control socket's `page-out`
+    /// pushes a cold VM's memory out ahead of any memory pressure.
+    Swappable,
+}
+
+impl fmt::Display for SwapPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SwapPolicy::Default => "default",
+            SwapPolicy::Locked => "locked",
+            SwapPolicy::Swappable => "swappable",
+        })
+    }
+}
+
+/// Seals a [`MemoryBacking::Sealed`] memfd gets: its size is fixed, and
+/// the seals themselves are. Its contents stay writable.
+const MEMFD_SEALS: libc::c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
 
 /// Guest physical memory region backed by vm-memory.
 ///
 /// This is a thin wrapper around `GuestMemoryMmap` that provides a simpler
-/// API for our use case (single contiguous region starting at address 0).
+/// API for our use case (RAM from address 0, split around the MMIO hole).
 ///
-/// The underlying memory is allocated using mmap with:
+/// By default the underlying memory is allocated using mmap with:
 /// - `MAP_PRIVATE`: Changes are not written to any file
 /// - `MAP_ANONYMOUS`: Not backed by a file
 /// - `MAP_NORESERVE`: Don't reserve swap space (allows overcommit)
+///
+/// or, for another [`MemoryBacking`], as a mapping of a memfd or file.
 pub struct GuestMemory {
     /// The underlying vm-memory guest memory.
     inner: GuestMemoryMmap,
-    /// Size of the memory region in bytes.
+    /// Total RAM in bytes, across regions.
     size: u64,
+    /// Whether the mapping is of a file other processes can share.
+    shared: bool,
+}
+
+/// One contiguous range of guest RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamRegion {
+    /// Guest physical address of the first byte.
+    pub guest_addr: u64,
+    /// Size in bytes.
+    pub size: u64,
+    /// Host virtual address the region is mapped at.
+    pub host_addr: u64,
+    /// Offset of the region in [`GuestMemory::shared_file`], if shared.
+    pub file_offset: u64,
 }
 
 impl GuestMemory {
     /// Allocate a new guest memory region.
     ///
-    /// Creates a contiguous memory region of the specified size starting at
-    /// guest physical address 0. The memory is:
+    /// Creates `size` bytes of RAM starting at guest physical address 0,
+    /// split around the MMIO hole if it reaches it. The memory is:
     /// - Readable and writable
     /// - Private (changes aren't visible to other processes)
     /// - Anonymous (not backed by a file)
@@ -98,8 +259,10 @@ impl GuestMemory {
     ///
     /// Returns an error if memory allocation fails.
     pub fn new(size: u64) -> Result<Self, BootError> {
-        // Create a single memory region starting at guest address 0
-        let regions = vec![(GuestAddress(0), size as usize)];
+        let regions: Vec<_> = layout::ram_regions(size)
+            .into_iter()
+            .map(|(start, len)| (GuestAddress(start), len as usize))
+            .collect();
 
         let inner = GuestMemoryMmap::from_ranges(&regions).map_err(|e| {
             BootError::MemoryAllocation(std::io::Error::other(format!(
@@ -108,22 +271,279 @@ impl GuestMemory {
             )))
         })?;
 
-        Ok(Self { inner, size })
+        Ok(Self {
+            inner,
+            size,
+            shared: false,
+        })
+    }
+
+    /// Allocate guest memory backed as `backing` says.
+    pub fn with_backing(size: u64, backing: &MemoryBacking) -> Result<Self, BootError> {
+        match backing {
+            MemoryBacking::Anonymous => Self::new(size),
+            MemoryBacking::Memfd => Self::new_shared(size, false),
+            MemoryBacking::Sealed => Self::new_shared(size, true),
+            MemoryBacking::File { path, shared } => Self::new_file(size, path, *shared),
+        }
     }
 
-    /// Get raw parts for KVM memory region registration.
+    /// Allocate guest memory that other processes can map.
     ///
-    /// Returns (host_virtual_address, size) for use with `set_user_memory_region`.
+    /// Like [`GuestMemory::new`], but the memory is a shared mapping of a
+    /// memfd, which [`GuestMemory::shared_file`] returns. vhost-user
+    /// backends (e.g. virtiofsd) map it to access the guest's virtqueues
+    /// and buffers directly. With `seal`, the memfd's size is sealed once
+    /// set (see [`MemoryBacking::Sealed`]).
+    pub fn new_shared(size: u64, seal: bool) -> Result<Self, BootError> {
+        let flags = if seal {
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING
+        } else {
+            libc::MFD_CLOEXEC
+        };
+        // SAFETY: the name is a valid C string; the returned fd is checked.
+        let fd = unsafe { libc::memfd_create(c"carbon-guest-memory".as_ptr(), flags) };
+        if fd < 0 {
+            return Err(BootError::MemoryAllocation(std::io::Error::last_os_error()));
+        }
+        // SAFETY: fd is a fresh descriptor that nothing else owns.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size).map_err(BootError::MemoryAllocation)?;
+        // SAFETY: F_ADD_SEALS takes an int argument, on a descriptor we own.
+        if seal && unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, MEMFD_SEALS) } < 0 {
+            return Err(BootError::MemoryAllocation(std::io::Error::last_os_error()));
+        }
+        Self::map_file(file, size, true)
+    }
+
+    /// Allocate guest memory in the file at `path`, created if missing and
+    /// grown to `size` if smaller (see [`MemoryBacking::File`]).
+    pub fn new_file(size: u64, path: &Path, shared: bool) -> Result<Self, BootError> {
+        let with_path = |e: std::io::Error| {
+            BootError::MemoryAllocation(std::io::Error::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            ))
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(with_path)?;
+        if file.metadata().map_err(with_path)?.len() < size {
+            file.set_len(size).map_err(with_path)?;
+        }
+        Self::map_file(file, size, shared)
+    }
+
+    /// Map `file` as `size` bytes of RAM, its regions consecutive in the
+    /// file. `shared` maps it `MAP_SHARED`, so writes reach the file.
+    fn map_file(file: File, size: u64, shared: bool) -> Result<Self, BootError> {
+        let flags = if shared {
+            libc::MAP_NORESERVE | libc::MAP_SHARED
+        } else {
+            libc::MAP_NORESERVE | libc::MAP_PRIVATE
+        };
+        let file = Arc::new(file);
+        let mut offset = 0;
+        let mut regions = Vec::new();
+        for (start, len) in layout::ram_regions(size) {
+            let mapping = MmapRegionBuilder::new(len as usize)
+                .with_mmap_prot(libc::PROT_READ | libc::PROT_WRITE)
+                .with_mmap_flags(flags)
+                .with_file_offset(FileOffset::from_arc(file.clone(), offset))
+                .build()
+                .map_err(|e| {
+                    BootError::MemoryAllocation(std::io::Error::other(format!(
+                        "Failed to map guest memory: {}",
+                        e
+                    )))
+                })?;
+            let region = GuestRegionMmap::new(mapping, GuestAddress(start)).map_err(|e| {
+                BootError::MemoryAllocation(std::io::Error::other(format!(
+                    "Failed to create guest memory: {}",
+                    e
+                )))
+            })?;
+            regions.push(region);
+            offset += len;
+        }
+        let inner = GuestMemoryMmap::from_regions(regions).map_err(|e| {
+            BootError::MemoryAllocation(std::io::Error::other(format!(
+                "Failed to create guest memory: {}",
+                e
+            )))
+        })?;
+
+        Ok(Self {
+            inner,
+            size,
+            shared,
+        })
+    }
+
+    /// The file backing shared guest memory, if it was allocated with
+    /// [`GuestMemory::new_shared`] or is a shared file mapping.
+    pub fn shared_file(&self) -> Option<&File> {
+        if !self.shared {
+            return None;
+        }
+        let region = self.inner.iter().next()?;
+        region.file_offset().map(FileOffset::file)
+    }
+
+    /// The RAM regions, in address order, for KVM and vhost-user memory
+    /// tables.
     ///
     /// # Safety
     ///
-    /// The returned pointer is valid only while this GuestMemory exists.
+    /// The host addresses are valid only while this GuestMemory exists.
     /// Do not free or reallocate the memory.
-    pub fn as_raw_parts(&self) -> (u64, u64) {
-        // Get the first (and only) region
-        let region = self.inner.iter().next().expect("memory has no regions");
-        let host_addr = region.as_ptr() as u64;
-        (host_addr, self.size)
+    pub fn regions(&self) -> Vec<RamRegion> {
+        self.inner
+            .iter()
+            .map(|region| RamRegion {
+                guest_addr: region.start_addr().0,
+                size: region.len(),
+                host_addr: region.as_ptr() as u64,
+                file_offset: region.file_offset().map_or(0, FileOffset::start),
+            })
+            .collect()
+    }
+
+    /// Fault in every page of RAM now, trading startup time for a guest
+    /// that never waits on the host to allocate one. Uses
+    /// `MADV_POPULATE_WRITE` (Linux 5.14), or writes to each page where the
+    /// host kernel lacks it.
+    pub fn prefault(&self) -> Result<(), BootError> {
+        for region in self.regions() {
+            // SAFETY: the range is a mapping this GuestMemory owns.
+            let ret = unsafe {
+                libc::madvise(
+                    region.host_addr as *mut libc::c_void,
+                    region.size as usize,
+                    libc::MADV_POPULATE_WRITE,
+                )
+            };
+            if ret == 0 {
+                continue;
+            }
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINVAL) {
+                return Err(BootError::MemoryAllocation(std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to prefault guest memory: {}", e),
+                )));
+            }
+            // SAFETY: sysconf has no preconditions.
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            for offset in (0..region.size as usize).step_by(page_size) {
+                let byte = (region.host_addr as usize + offset) as *mut u8;
+                // SAFETY: the byte is inside the region's mapping, and is
+                // written back as it was.
+                unsafe { byte.write_volatile(byte.read_volatile()) };
+            }
+        }
+        Ok(())
+    }
+
+    /// Lock every page of RAM into host memory, faulting it in, so host
+    /// swap never stalls the guest. Fails with the RLIMIT_MEMLOCK that
+    /// stood in the way, if one did.
+    pub fn lock(&self) -> Result<(), BootError> {
+        for region in self.regions() {
+            // SAFETY: the range is a mapping this GuestMemory owns.
+            let ret = unsafe {
+                libc::mlock(
+                    region.host_addr as *const libc::c_void,
+                    region.size as usize,
+                )
+            };
+            if ret == 0 {
+                continue;
+            }
+            let e = std::io::Error::last_os_error();
+            let message = match e.raw_os_error() {
+                Some(libc::ENOMEM | libc::EPERM) => format!(
+                    "Failed to lock {} of guest memory: {} (RLIMIT_MEMLOCK is {}; raise it \
+                     with `ulimit -l` or grant CAP_IPC_LOCK)",
+                    ByteSize(self.size),
+                    e,
+                    memlock_limit()
+                ),
+                _ => format!("Failed to lock guest memory: {}", e),
+            };
+            return Err(BootError::MemoryAllocation(std::io::Error::new(
+                e.kind(),
+                message,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Ask the host to reclaim RAM now (`MADV_PAGEOUT`, Linux 5.4),
+    /// writing it to swap or its backing file. The guest faults pages back
+    /// in as it touches them.
+    pub fn page_out(&self) -> std::io::Result<()> {
+        for region in self.regions() {
+            // SAFETY: the range is a mapping this GuestMemory owns; paging
+            // it out leaves its contents as they were.
+            let ret = unsafe {
+                libc::madvise(
+                    region.host_addr as *mut libc::c_void,
+                    region.size as usize,
+                    libc::MADV_PAGEOUT,
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of RAM resident in host memory (`mincore`).
+    pub fn resident(&self) -> std::io::Result<u64> {
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let mut resident = 0;
+        for region in self.regions() {
+            let mut pages = vec![0u8; region.size.div_ceil(page_size) as usize];
+            // SAFETY: the range is a mapping this GuestMemory owns, and the
+            // vector has a byte per page.
+            let ret = unsafe {
+                libc::mincore(
+                    region.host_addr as *mut libc::c_void,
+                    region.size as usize,
+                    pages.as_mut_ptr(),
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            resident += pages.iter().filter(|&&page| page & 1 != 0).count() as u64 * page_size;
+        }
+        Ok(resident)
+    }
+
+    /// Total RAM in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Guest physical address just past the last byte of RAM.
+    pub fn end(&self) -> u64 {
+        self.inner.last_addr().0 + 1
+    }
+
+    /// Host virtual address of guest physical `addr`, if it is RAM.
+    pub fn host_address(&self, addr: u64) -> Option<u64> {
+        self.inner
+            .get_host_address(GuestAddress(addr))
+            .ok()
+            .map(|ptr| ptr as u64)
     }
 
     /// Write bytes at a guest physical address.
@@ -184,6 +604,33 @@ impl GuestMemory {
     }
 }
 
+/// The soft RLIMIT_MEMLOCK, for error messages.
+fn memlock_limit() -> String {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit writes into the struct it is given.
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return "unknown".into();
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        "unlimited".into()
+    } else {
+        ByteSize(limit.rlim_cur).to_string()
+    }
+}
+
+impl GuestRam for GuestMemory {
+    fn read_ram(&self, gpa: u64, data: &mut [u8]) -> std::io::Result<()> {
+        self.read(gpa, data).map_err(std::io::Error::other)
+    }
+
+    fn write_ram(&self, gpa: u64, data: &[u8]) -> std::io::Result<()> {
+        self.write(gpa, data).map_err(std::io::Error::other)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,8 +645,38 @@ mod tests {
     #[test]
     fn test_allocate() {
         let mem = GuestMemory::new(4096).unwrap();
-        let (_, size) = mem.as_raw_parts();
-        assert_eq!(size, 4096);
+        let regions = mem.regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!((regions[0].guest_addr, regions[0].size), (0, 4096));
+        assert_eq!((mem.size(), mem.end()), (4096, 4096));
+    }
+
+    #[test]
+    fn test_mmio_hole() {
+        let size = layout::MMIO_HOLE_START + 8192;
+        let mem = GuestMemory::new_shared(size, false).unwrap();
+        let regions = mem.regions();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].size, layout::MMIO_HOLE_START);
+        assert_eq!(regions[1].guest_addr, layout::HIGH_RAM_START);
+        assert_eq!(regions[1].file_offset, layout::MMIO_HOLE_START);
+        assert_eq!(mem.size(), size);
+        assert_eq!(mem.end(), layout::HIGH_RAM_START + 8192);
+
+        // RAM above 4GB is the file past the low region; the hole isn't RAM
+        use std::os::unix::fs::FileExt;
+        mem.write_u32(layout::HIGH_RAM_START + 4, 0xfeedface)
+            .unwrap();
+        let mut buf = [0u8; 4];
+        let file = mem.shared_file().unwrap();
+        file.read_at(&mut buf, layout::MMIO_HOLE_START + 4).unwrap();
+        assert_eq!(u32::from_le_bytes(buf), 0xfeedface);
+        assert!(mem.write_u8(layout::MMIO_HOLE_START, 0).is_err());
+        assert_eq!(mem.host_address(layout::MMIO_HOLE_START), None);
+        assert_eq!(
+            mem.host_address(layout::HIGH_RAM_START + 4),
+            Some(regions[1].host_addr + 4)
+        );
     }
 
     #[test]
@@ -233,6 +710,115 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_shared_memory_is_visible_through_file() {
+        use std::os::unix::fs::FileExt;
+
+        let mem = GuestMemory::new_shared(8192, false).unwrap();
+        mem.write_u32(4096, 0xfeedface).unwrap();
+        let mut buf = [0u8; 4];
+        mem.shared_file().unwrap().read_at(&mut buf, 4096).unwrap();
+        assert_eq!(u32::from_le_bytes(buf), 0xfeedface);
+        assert!(GuestMemory::new(4096).unwrap().shared_file().is_none());
+    }
+
+    #[test]
+    fn test_sealed_memory() {
+        use std::os::unix::fs::FileExt;
+
+        let mem = GuestMemory::with_backing(8192, &MemoryBacking::Sealed).unwrap();
+        let file = mem.shared_file().unwrap();
+        // SAFETY: F_GET_SEALS takes no argument.
+        let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+        assert_eq!(seals, MEMFD_SEALS);
+
+        // The size is fixed, the contents aren't
+        assert!(file.set_len(4096).is_err());
+        assert!(file.set_len(16384).is_err());
+        file.write_at(&[0x42], 100).unwrap();
+        assert_eq!(read_vec(&mem, 100, 1), vec![0x42]);
+
+        let mem = GuestMemory::with_backing(8192, &MemoryBacking::Memfd).unwrap();
+        let file = mem.shared_file().unwrap();
+        // SAFETY: F_GET_SEALS takes no argument.
+        let seals = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GET_SEALS) };
+        assert_eq!(seals, libc::F_SEAL_SEAL);
+    }
+
+    #[test]
+    fn test_parse_memory_backing() {
+        assert_eq!("memfd".parse(), Ok(MemoryBacking::Memfd));
+        assert_eq!(
+            "file=/dev/shm/vm0,shared=on".parse(),
+            Ok(MemoryBacking::File {
+                path: "/dev/shm/vm0".into(),
+                shared: true,
+            })
+        );
+        let private: MemoryBacking = "file=/dev/shm/vm0".parse().unwrap();
+        assert!(!private.is_shared());
+        assert_eq!(private.to_string(), "file=/dev/shm/vm0,shared=off");
+        assert!(MemoryBacking::Sealed.is_shared());
+        assert!("file=".parse::<MemoryBacking>().is_err());
+        assert!("file=vm0,shared=yes".parse::<MemoryBacking>().is_err());
+        assert!("hugepages".parse::<MemoryBacking>().is_err());
+    }
+
+    #[test]
+    fn test_file_memory() {
+        use std::os::unix::fs::FileExt;
+
+        let path = std::env::temp_dir().join(format!("carbon-memory-{}", std::process::id()));
+        std::fs::write(&path, [0x17]).unwrap();
+        let mem = GuestMemory::new_file(8192, &path, true).unwrap();
+        assert_eq!(read_vec(&mem, 0, 2), vec![0x17, 0]);
+        mem.write_u32(4096, 0xfeedface).unwrap();
+        let mut buf = [0u8; 4];
+        let file = File::open(&path).unwrap();
+        file.read_at(&mut buf, 4096).unwrap();
+        assert_eq!(u32::from_le_bytes(buf), 0xfeedface);
+        assert!(mem.shared_file().is_some());
+        drop(mem);
+
+        // Private, the file provides the contents, but the guest's writes
+        // stay out of it; a larger file isn't truncated
+        let mem = GuestMemory::new_file(4096, &path, false).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 8192);
+        mem.write_u8(0, 0x42).unwrap();
+        assert_eq!(read_vec(&mem, 0, 1), vec![0x42]);
+        file.read_at(&mut buf[..1], 0).unwrap();
+        assert_eq!(buf[0], 0x17);
+        assert!(mem.shared_file().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_prefault() {
+        let mem = GuestMemory::new_shared(1 << 20, false).unwrap();
+        mem.write_u8(0x1234, 0x42).unwrap();
+        assert!(mem.resident().unwrap() < 1 << 20);
+        mem.prefault().unwrap();
+        assert_eq!(mem.resident().unwrap(), 1 << 20);
+        assert_eq!(read_vec(&mem, 0x1234, 1), vec![0x42]);
+    }
+
+    #[test]
+    fn test_lock_and_page_out() {
+        // Within the smallest default RLIMIT_MEMLOCK (64K)
+        let mem = GuestMemory::new(32 << 10).unwrap();
+        mem.write_u8(0x1234, 0x42).unwrap();
+        mem.lock().unwrap();
+        assert_eq!(mem.resident().unwrap(), 32 << 10);
+
+        let mem = GuestMemory::new_shared(1 << 20, false).unwrap();
+        mem.prefault().unwrap();
+        mem.write_u8(0x1234, 0x42).unwrap();
+        // Hosts without swap keep the memfd's pages, but it mustn't fail
+        // or lose data
+        mem.page_out().unwrap();
+        assert_eq!(read_vec(&mem, 0x1234, 1), vec![0x42]);
+    }
+
     #[test]
     fn test_write_out_of_bounds() {
         let mem = GuestMemory::new(100).unwrap();
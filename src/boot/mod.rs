@@ -13,7 +13,10 @@
 //! The boot process requires:
 //!
 //! 1. **Kernel Loading**: The bzImage must be parsed to extract the protected-mode
-//!    kernel code, which is loaded at the 1MB mark (0x100000).
+//!    kernel code, which is loaded at the 1MB mark (0x100000). An uncompressed
+//!    vmlinux (ELF) is loaded segment by segment at its physical addresses
+//!    instead (16MB for a default build), as is the vmlinux inside a bzImage
+//!    when the VMM can decompress it.
 //!
 //! 2. **Boot Parameters**: A `boot_params` structure (also called the "zero page")
 //!    must be populated with system information including:
@@ -28,7 +31,7 @@
 //!    - EFER MSR set for long mode
 //!
 //! 4. **Entry Point**: For 64-bit boot, execution begins at kernel_load_address + 0x200
-//!    with RSI pointing to the boot_params structure.
+//!    (a vmlinux's ELF entry) with RSI pointing to the boot_params structure.
 //!
 //! # Supported Kernel Versions
 //!
@@ -48,19 +51,24 @@
 //! 0x0000_8000 - 0x0000_9000  Stack space (grows downward from 0x8ff0)
 //! 0x0000_9000 - 0x0000_a000  PML4 (Page Map Level 4)
 //! 0x0000_a000 - 0x0000_b000  PDPTE (Page Directory Pointer Table Entry)
-//! 0x0000_b000 - 0x0000_c000  PDE (Page Directory Entries for 2MB pages)
+//! 0x0000_b000 - 0x0000_e000  PDE (Page Directory Entries for 2MB pages), one
+//!                            table per identity-mapped GB, up to three
 //! 0x0002_0000 - 0x0002_0800  Kernel command line
 //! 0x0009_fc00 - 0x000a_0000  MP Table (EBDA region)
-//! 0x0010_0000 - kernel_end   Kernel code (loaded from bzImage)
-//! kernel_end  - mem_size     Available RAM for kernel use
+//! 0x0010_0000 - kernel_end   Kernel code (loaded from bzImage; a vmlinux
+//!                            starts at its physical address, usually 16MB)
+//! kernel_end  - 0xc000_0000  Available RAM for kernel use, up to mem_size
+//!                            (an initrd, if given, sits page-aligned at the top)
+//! 0xc000_0000 - 0x1_0000_0000  MMIO hole (devices, IOAPIC, LAPIC)
+//! 0x1_0000_0000 -            RAM beyond 3GB, if mem_size is larger
 //! ```
 //!
 //! # Memory Limits
 //!
 //! - **Minimum**: Guest memory must be > 1MB to load the kernel
-//! - **Maximum for identity mapping**: 1GB (512 × 2MB pages)
-//!   - The initial page tables identity-map only the first 1GB
-//!   - The kernel sets up its own page tables during boot, so larger VMs work fine
+//! - **Identity mapping**: the RAM below the MMIO hole, in 1GB steps (up to 3GB)
+//!   - The initial page tables map at least the first 1GB
+//!   - The kernel sets up its own page tables during boot, reaching RAM above 4GB
 //!
 //! # Default Command Line Flags
 //!
@@ -73,30 +81,39 @@
 //! # Example Usage
 //!
 //! ```ignore
-//! let vm = kvm::create_vm()?;
+//! let vm = kvm::create_vm(CpuMode::Host, &CpuFeatures::default(), Topology::default(), false, false)?;
 //! let memory = GuestMemory::new(512 * 1024 * 1024)?;
 //! let config = BootConfig {
 //!     kernel_path: "vmlinuz".to_string(),
+//!     kernel_sha256: None,
 //!     cmdline: "console=ttyS0".to_string(),
 //!     mem_size: 512 * 1024 * 1024,
+//!     initrd_path: None,
 //! };
-//! setup_boot(&vm, &memory, &config)?;
+//! let entry = setup_boot(&vm, &memory, &config)?;
 //! let vcpu = vm.create_vcpu(0)?;
 //! vcpu.set_boot_msrs()?;
-//! setup_vcpu_regs(&vcpu, &memory)?;
+//! setup_vcpu_regs(&vcpu, &memory, entry)?;
 //! ```
 
 mod acpi;
 mod bzimage;
+mod decompress;
+mod elf;
+mod initrd;
+mod kaslr;
+mod kernel_cache;
 mod memory;
 mod mptable;
 mod paging;
 mod params;
 
-pub use acpi::{setup_acpi, VirtioDeviceConfig};
-pub use memory::GuestMemory;
+pub use acpi::{setup_acpi, PciHostConfig, TpmConfig, VirtioDeviceConfig};
+pub use kernel_cache::KernelImage;
+pub use memory::{GuestMemory, MemoryBacking, RamRegion, SwapPolicy};
 pub use mptable::setup_mptable;
 
+use crate::digest::Sha256Digest;
 use crate::kvm::{KvmError, VmFd};
 use thiserror::Error;
 
@@ -153,6 +170,12 @@ pub mod layout {
     /// Modern kernels support up to 2KB. Older boot protocols had smaller limits.
     pub const CMDLINE_MAX_SIZE: usize = 2048;
 
+    /// setup_data list location.
+    ///
+    /// Extra boot data (currently just the RNG seed) chained from
+    /// boot_params, just past the command line's maximum extent.
+    pub const SETUP_DATA_START: u64 = 0x2_1000;
+
     /// High memory start address (1MB mark).
     ///
     /// The protected-mode kernel code is loaded here. The 1MB address is
@@ -164,6 +187,27 @@ pub mod layout {
 
     /// Default guest memory size (512MB).
     pub const DEFAULT_MEM_SIZE: u64 = 512 * 1024 * 1024;
+
+    /// Start of the 32-bit MMIO hole (3GB).
+    ///
+    /// Device registers (virtio-mmio at 0xd000_0000, the PCI host bridge,
+    /// the IOAPIC and LAPIC) live between here and 4GB, so RAM stops here
+    /// and whatever doesn't fit below continues at [`HIGH_RAM_START`].
+    pub const MMIO_HOLE_START: u64 = 0xc000_0000;
+
+    /// Where RAM that doesn't fit below the MMIO hole continues (4GB).
+    pub const HIGH_RAM_START: u64 = 1 << 32;
+
+    /// Guest physical RAM regions `(start, size)` for `mem_size` bytes of
+    /// RAM: up to the MMIO hole, then the remainder above 4GB.
+    pub fn ram_regions(mem_size: u64) -> Vec<(u64, u64)> {
+        let low = mem_size.min(MMIO_HOLE_START);
+        let mut regions = vec![(0, low)];
+        if mem_size > low {
+            regions.push((HIGH_RAM_START, mem_size - low));
+        }
+        regions
+    }
 }
 
 /// Errors that can occur during boot setup.
@@ -175,24 +219,51 @@ pub enum BootError {
     #[error("KVM error: {0}")]
     Kvm(#[from] KvmError),
 
-    #[error("Failed to read kernel: {0}")]
-    ReadKernel(#[source] std::io::Error),
+    #[error("Failed to read kernel {path}: {source}")]
+    ReadKernel {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
 
     #[error("Invalid kernel image: {0}")]
     InvalidKernel(String),
 
+    #[error("Kernel {path} has digest {actual}, not the pinned {expected}")]
+    KernelDigest {
+        path: String,
+        expected: Sha256Digest,
+        actual: Sha256Digest,
+    },
+
     #[error("Command line too long: {len} bytes (max {max})")]
     CmdlineTooLong { len: usize, max: usize },
+
+    #[error("Failed to read initrd {path}: {source}")]
+    ReadInitrd {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error(
+        "Initrd too large: {size} bytes does not fit above the kernel in {mem_size} bytes of RAM"
+    )]
+    InitrdTooLarge { size: u64, mem_size: u64 },
 }
 
 /// Configuration for booting a Linux kernel.
 pub struct BootConfig {
-    /// Path to the kernel bzImage file.
+    /// Path to the kernel bzImage or vmlinux file.
     ///
     /// The bzImage is the standard format for bootable Linux kernels on x86.
     /// It contains a setup header, real-mode code, and compressed protected-mode code.
+    /// A vmlinux is the uncompressed ELF kernel a build produces.
     pub kernel_path: String,
 
+    /// SHA-256 digest the kernel file must have, if pinned.
+    pub kernel_sha256: Option<Sha256Digest>,
+
     /// Kernel command line arguments.
     ///
     /// Common options include:
@@ -209,14 +280,22 @@ pub struct BootConfig {
     /// The kernel uses this to know how much RAM is available.
     /// Must be > 1MB for kernel loading.
     pub mem_size: u64,
+
+    /// Optional path to an initrd/initramfs image.
+    ///
+    /// The image is loaded at the top of guest RAM and its location passed
+    /// to the kernel via boot_params.
+    pub initrd_path: Option<String>,
 }
 
 impl Default for BootConfig {
     fn default() -> Self {
         Self {
             kernel_path: String::new(),
+            kernel_sha256: None,
             cmdline: "console=ttyS0".to_string(),
             mem_size: layout::DEFAULT_MEM_SIZE,
+            initrd_path: None,
         }
     }
 }
@@ -226,30 +305,67 @@ impl Default for BootConfig {
 /// This function performs all the setup required before the vCPU can begin
 /// executing the kernel:
 ///
-/// 1. Loads the kernel from the bzImage file into guest memory at 1MB
+/// 1. Loads the kernel from the bzImage file into guest memory at 1MB, or
+///    a vmlinux at its physical addresses (and the initrd, if any, at the
+///    top of RAM)
 /// 2. Sets up the boot_params structure with memory map and configuration
-/// 3. Creates identity-mapped page tables for the first 1GB of memory
+/// 3. Creates identity-mapped page tables for the RAM below the MMIO hole
 /// 4. Registers the guest memory region with KVM
 ///
-/// After this function returns, call `setup_vcpu_regs` to configure the
-/// vCPU's registers, then the vCPU is ready to run.
-pub fn setup_boot(vm: &VmFd, memory: &GuestMemory, config: &BootConfig) -> Result<(), BootError> {
-    // Load the kernel from bzImage into guest memory
-    let loaded_kernel = bzimage::load_kernel(memory, &config.kernel_path)?;
+/// Returns the kernel's 64-bit entry point. After this function returns,
+/// call `setup_vcpu_regs` with it to configure the vCPU's registers, then
+/// the vCPU is ready to run.
+pub fn setup_boot(vm: &VmFd, memory: &GuestMemory, config: &BootConfig) -> Result<u64, BootError> {
+    // Load the kernel from bzImage (or vmlinux) into guest memory, at a
+    // random address unless the command line turns KASLR off
+    let kaslr = !config
+        .cmdline
+        .split_whitespace()
+        .any(|arg| arg == "nokaslr");
+    let image = KernelImage::shared(&config.kernel_path)?;
+    if let Some(expected) = config.kernel_sha256 {
+        let actual = image.sha256();
+        if actual != expected {
+            return Err(BootError::KernelDigest {
+                path: config.kernel_path.clone(),
+                expected,
+                actual,
+            });
+        }
+        debug!("[Boot] Kernel digest matches {}", expected);
+    }
+    let loaded_kernel = bzimage::load_kernel(memory, &image, kaslr)?;
+
+    // Load the initrd above the kernel, if one was given
+    let loaded_initrd = match config.initrd_path {
+        Some(ref path) => Some(initrd::load_initrd(
+            memory,
+            path,
+            config.mem_size,
+            &loaded_kernel,
+        )?),
+        None => None,
+    };
 
     // Populate the boot_params structure with memory map, cmdline, etc.
-    params::setup_boot_params(memory, config, &loaded_kernel)?;
+    params::setup_boot_params(memory, config, &loaded_kernel, loaded_initrd.as_ref())?;
 
-    // Create page tables for 64-bit mode (identity mapping first 1GB)
+    // Create page tables for 64-bit mode (identity mapping RAM below the hole)
     paging::setup_page_tables(memory)?;
 
-    // Register the guest memory region with KVM so the CPU can access it
-    let (host_addr, size) = memory.as_raw_parts();
-    unsafe {
-        vm.set_user_memory_region(0, 0, size, host_addr)?;
+    // Register the guest memory regions with KVM so the CPU can access them
+    for (slot, region) in memory.regions().into_iter().enumerate() {
+        unsafe {
+            vm.set_user_memory_region(
+                slot as u32,
+                region.guest_addr,
+                region.size,
+                region.host_addr,
+            )?;
+        }
     }
 
-    Ok(())
+    Ok(loaded_kernel.entry)
 }
 
 /// Configure vCPU registers for 64-bit Linux boot.
@@ -263,10 +379,15 @@ pub fn setup_boot(vm: &VmFd, memory: &GuestMemory, config: &BootConfig) -> Resul
 /// - **General registers**: RIP (entry point), RSP/RBP (stack), RSI (boot_params)
 /// - **FPU state**: x87 control word and MXCSR for SSE
 ///
-/// The kernel entry point for 64-bit boot is at kernel_load_address + 0x200.
-/// This offset accounts for the real-mode entry point at +0x000 (unused for
-/// direct 64-bit boot) and the 64-bit entry at +0x200.
-pub fn setup_vcpu_regs(vcpu: &crate::kvm::VcpuFd, memory: &GuestMemory) -> Result<(), BootError> {
-    paging::setup_cpu_regs(vcpu, memory)?;
+/// `entry` is the address `setup_boot` returned. For a bzImage it is
+/// kernel_load_address + 0x200: the real-mode entry point at +0x000 is
+/// unused for direct 64-bit boot, and the 64-bit entry is at +0x200. For a
+/// vmlinux it is the ELF entry point.
+pub fn setup_vcpu_regs(
+    vcpu: &crate::kvm::VcpuFd,
+    memory: &GuestMemory,
+    entry: u64,
+) -> Result<(), BootError> {
+    paging::setup_cpu_regs(vcpu, memory, entry)?;
     Ok(())
 }
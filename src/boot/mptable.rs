@@ -171,8 +171,13 @@ fn compute_checksum(data: &[u8]) -> u8 {
 ///
 /// Creates the MP Floating Pointer and MP Configuration Table
 /// that describe the system's processor and interrupt routing configuration.
-pub fn setup_mptable(memory: &GuestMemory, num_cpus: u8) -> Result<u64, BootError> {
-    let ioapic_id = num_cpus; // I/O APIC ID comes after CPU APIC IDs
+/// `apic_ids` holds each vCPU's APIC ID, the boot CPU first.
+pub fn setup_mptable(memory: &GuestMemory, apic_ids: &[u8]) -> Result<u64, BootError> {
+    // I/O APIC ID comes after CPU APIC IDs
+    let ioapic_id = apic_ids
+        .iter()
+        .max()
+        .map_or(0, |&max| max.saturating_add(1));
 
     // Calculate sizes
     let fp_size = core::mem::size_of::<MpFloatingPointer>();
@@ -185,13 +190,13 @@ pub fn setup_mptable(memory: &GuestMemory, num_cpus: u8) -> Result<u64, BootErro
 
     // Calculate total table size:
     // - 1 header
-    // - num_cpus processor entries
+    // - one processor entry per CPU
     // - 1 bus entry (ISA)
     // - 1 I/O APIC entry
     // - NUM_LEGACY_IRQS interrupt source entries
     // - 2 local interrupt source entries (ExtINT, NMI)
     let table_size = header_size
-        + (num_cpus as usize * proc_size)
+        + (apic_ids.len() * proc_size)
         + bus_size
         + ioapic_size
         + (NUM_LEGACY_IRQS as usize * intsrc_size)
@@ -202,12 +207,12 @@ pub fn setup_mptable(memory: &GuestMemory, num_cpus: u8) -> Result<u64, BootErro
     let mut entry_count: u16 = 0;
 
     // Add processor entries
-    for cpu_id in 0..num_cpus {
+    for (i, &apic_id) in apic_ids.iter().enumerate() {
         let entry = MpProcessorEntry {
             entry_type: MP_PROCESSOR,
-            apic_id: cpu_id,
+            apic_id,
             apic_version: APIC_VERSION,
-            cpu_flags: CPU_ENABLED | if cpu_id == 0 { CPU_BOOT } else { 0 },
+            cpu_flags: CPU_ENABLED | if i == 0 { CPU_BOOT } else { 0 },
             cpu_signature: CPU_STEPPING,
             feature_flags: CPU_FEATURE_APIC | CPU_FEATURE_FPU,
             reserved: [0; 2],
@@ -346,9 +351,12 @@ pub fn setup_mptable(memory: &GuestMemory, num_cpus: u8) -> Result<u64, BootErro
     let fp_bytes = unsafe { core::slice::from_raw_parts(&fp as *const _ as *const u8, fp_size) };
     memory.write(MPTABLE_START, fp_bytes)?;
 
-    eprintln!(
+    debug!(
         "[Boot] MPTable: addr={:#x} entries={} ({}CPUs, {}IRQs)",
-        MPTABLE_START, entry_count, num_cpus, NUM_LEGACY_IRQS
+        MPTABLE_START,
+        entry_count,
+        apic_ids.len(),
+        NUM_LEGACY_IRQS
     );
 
     Ok(MPTABLE_START)
@@ -30,9 +30,11 @@
 //! CR3 → PML4 → PDPTE → PDE (with PS bit) → 2MB Physical Page
 //! ```
 //!
-//! This gives us identity-mapped (virtual = physical) access to the first 1GB
-//! of memory, which is sufficient for early kernel boot. The kernel sets up
-//! its own page tables during initialization and can map all available memory.
+//! This gives us identity-mapped (virtual = physical) access to the RAM below
+//! the MMIO hole (at least the first 1GB), which covers everything the boot
+//! loader places: the kernel, boot_params, the command line and the initrd.
+//! The kernel sets up its own page tables during initialization and can map
+//! all available memory, including RAM above 4GB.
 //!
 //! # Global Descriptor Table (GDT)
 //!
@@ -58,7 +60,7 @@
 //!
 //! The Linux 64-bit boot protocol expects:
 //!
-//! - **RIP**: Kernel entry point (load_address + 0x200)
+//! - **RIP**: Kernel entry point (load_address + 0x200, or a vmlinux's ELF entry)
 //! - **RSI**: Pointer to boot_params structure
 //! - **RSP/RBP**: Valid stack pointer
 //! - **RFLAGS**: Interrupts disabled, reserved bit 1 set
@@ -91,10 +93,14 @@ const PDPTE_START: u64 = 0xa000;
 
 /// PDE (Page Directory Entry) table address.
 ///
-/// Third level of the page table hierarchy.
+/// Third level of the page table hierarchy, one 4KB table per identity-mapped
+/// 1GB, back to back from here.
 /// With 2MB pages (PS bit set), each entry maps directly to a 2MB physical page.
 const PDE_START: u64 = 0xb000;
 
+/// Size of guest memory one PDE table maps (512 × 2MB).
+const PDE_TABLE_SPAN: u64 = 1 << 30;
+
 // ============================================================================
 // Control Register Flags
 // ============================================================================
@@ -164,48 +170,54 @@ const GDT_TABLE: [u64; 5] = [
     gdt_entry(0x808b, 0, 0xfffff), // 0x20: TSS - Task State Segment
 ];
 
-/// Pre-computed PDE entries for identity mapping first 1GB.
+/// PDE table identity-mapping the `gb`th 1GB of memory.
 ///
 /// Each entry maps a 2MB page with flags: Present + Read/Write + Page Size (2MB).
-/// Entry i maps virtual [i*2MB, (i+1)*2MB) to physical [i*2MB, (i+1)*2MB).
-const fn compute_pde_entries() -> [u64; 512] {
-    let mut entries = [0u64; 512];
-    let mut i = 0;
-    while i < 512 {
-        // Physical address = i * 2MB, flags = 0x83 (Present + R/W + PS)
-        entries[i] = ((i as u64) << 21) | 0x83;
-        i += 1;
-    }
-    entries
+/// Entry i maps virtual [base + i*2MB, base + (i+1)*2MB) to the same physical
+/// addresses.
+fn pde_table(gb: u64) -> Vec<u8> {
+    (0..512u64)
+        .flat_map(|i| {
+            // Flags = 0x83 (Present + R/W + PS)
+            let entry = (gb * PDE_TABLE_SPAN + (i << 21)) | 0x83;
+            entry.to_le_bytes()
+        })
+        .collect()
 }
 
-/// Pre-computed PDE table for identity mapping.
-const PDE_ENTRIES: [u64; 512] = compute_pde_entries();
-
-/// Set up identity-mapped page tables for the first 1GB of memory.
+/// Set up identity-mapped page tables for the RAM below the MMIO hole.
 ///
-/// Creates a simple page table hierarchy using 2MB pages:
+/// Creates a simple page table hierarchy using 2MB pages, one PDE table per
+/// 1GB (at least one, at most three):
 ///
 /// ```text
-/// PML4[0] → PDPTE[0] → PDE[0..511] → 2MB pages at 0MB, 2MB, 4MB, ... 1022MB
+/// PML4[0] → PDPTE[0] → PDE table 0 → 2MB pages at 0MB, 2MB, ... 1022MB
+///           PDPTE[1] → PDE table 1 → 2MB pages at 1GB, ... 2GB - 2MB
+///           PDPTE[2] → PDE table 2 → 2MB pages at 2GB, ... 3GB - 2MB
 /// ```
 ///
-/// This maps virtual addresses 0x0 - 0x3FFFFFFF to the same physical addresses
-/// (identity mapping), which is what the kernel expects during early boot.
+/// This maps virtual addresses to the same physical addresses (identity
+/// mapping), which is what the kernel expects during early boot.
 pub fn setup_page_tables(memory: &GuestMemory) -> Result<(), BootError> {
+    let tables = memory
+        .size()
+        .min(layout::MMIO_HOLE_START)
+        .div_ceil(PDE_TABLE_SPAN)
+        .max(1);
+
     // PML4 entry 0: Points to PDPTE table
     // Flags 0x03 = Present + Read/Write
     memory.write_u64(PML4_START, PDPTE_START | 0x03)?;
 
-    // PDPTE entry 0: Points to PDE table
-    // Flags 0x03 = Present + Read/Write
-    memory.write_u64(PDPTE_START, PDE_START | 0x03)?;
+    for gb in 0..tables {
+        // PDPTE entry: Points to this 1GB's PDE table
+        // Flags 0x03 = Present + Read/Write
+        let pde = PDE_START + gb * 0x1000;
+        memory.write_u64(PDPTE_START + gb * 8, pde | 0x03)?;
 
-    // Write all 512 PDE entries at once
-    // Each entry is 8 bytes, so we write 4096 bytes total
-    let pde_bytes: &[u8] =
-        unsafe { std::slice::from_raw_parts(PDE_ENTRIES.as_ptr() as *const u8, 512 * 8) };
-    memory.write(PDE_START, pde_bytes)?;
+        // Write all 512 PDE entries at once
+        memory.write(pde, &pde_table(gb))?;
+    }
 
     Ok(())
 }
@@ -274,7 +286,7 @@ fn setup_fpu(vcpu: &VcpuFd) -> Result<(), BootError> {
 /// 4. **Control registers**: Enable protected mode and paging
 /// 5. **EFER MSR**: Enable long mode
 /// 6. **General registers**: Set entry point, stack, boot_params pointer
-pub fn setup_cpu_regs(vcpu: &VcpuFd, memory: &GuestMemory) -> Result<(), BootError> {
+pub fn setup_cpu_regs(vcpu: &VcpuFd, memory: &GuestMemory, entry: u64) -> Result<(), BootError> {
     // Set up GDT and IDT in guest memory
     setup_gdt_idt(memory)?;
 
@@ -320,16 +332,16 @@ pub fn setup_cpu_regs(vcpu: &VcpuFd, memory: &GuestMemory) -> Result<(), BootErr
 
     vcpu.set_sregs(&sregs)?;
 
-    eprintln!("[Boot] CPU special registers:");
-    eprintln!("  - CR0: {:#x}", sregs.cr0);
-    eprintln!("  - CR3: {:#x}", sregs.cr3);
-    eprintln!("  - CR4: {:#x}", sregs.cr4);
-    eprintln!("  - EFER: {:#x}", sregs.efer);
+    debug!("[Boot] CPU special registers:");
+    debug!("  - CR0: {:#x}", sregs.cr0);
+    debug!("  - CR3: {:#x}", sregs.cr3);
+    debug!("  - CR4: {:#x}", sregs.cr4);
+    debug!("  - EFER: {:#x}", sregs.efer);
 
     // Set up general-purpose registers for Linux 64-bit boot
     let regs = kvm_regs {
-        rflags: 0x2,                      // Only reserved bit 1 set, interrupts disabled
-        rip: layout::HIMEM_START + 0x200, // 64-bit entry point
+        rflags: 0x2, // Only reserved bit 1 set, interrupts disabled
+        rip: entry,  // 64-bit entry point
         rsp: layout::BOOT_STACK_POINTER,
         rbp: layout::BOOT_STACK_POINTER,
         rsi: layout::BOOT_PARAMS_START, // boot_params pointer
@@ -338,10 +350,43 @@ pub fn setup_cpu_regs(vcpu: &VcpuFd, memory: &GuestMemory) -> Result<(), BootErr
 
     vcpu.set_regs(&regs)?;
 
-    eprintln!("[Boot] CPU general registers:");
-    eprintln!("  - RIP: {:#x}", regs.rip);
-    eprintln!("  - RSP: {:#x}", regs.rsp);
-    eprintln!("  - RSI: {:#x} (boot_params)", regs.rsi);
+    debug!("[Boot] CPU general registers:");
+    debug!("  - RIP: {:#x}", regs.rip);
+    debug!("  - RSP: {:#x}", regs.rsp);
+    debug!("  - RSI: {:#x} (boot_params)", regs.rsi);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u64(memory: &GuestMemory, addr: u64) -> u64 {
+        let mut bytes = [0u8; 8];
+        memory.read(addr, &mut bytes).unwrap();
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn test_identity_map_below_hole() {
+        // 2.5GB: three PDE tables, the last mapping 2GB onwards
+        let memory = GuestMemory::new(5 << 29).unwrap();
+        setup_page_tables(&memory).unwrap();
+        assert_eq!(
+            read_u64(&memory, PDPTE_START + 16),
+            (PDE_START + 0x2000) | 0x03
+        );
+        assert_eq!(read_u64(&memory, PDPTE_START + 24), 0);
+        assert_eq!(
+            read_u64(&memory, PDE_START + 0x2000 + 8),
+            ((2 << 30) + (2 << 20)) | 0x83
+        );
+
+        // Less than 1GB still maps the first 1GB
+        let memory = GuestMemory::new(16 << 20).unwrap();
+        setup_page_tables(&memory).unwrap();
+        assert_eq!(read_u64(&memory, PDPTE_START + 8), 0);
+        assert_eq!(read_u64(&memory, PDE_START + 511 * 8), (511 << 21) | 0x83);
+    }
+}
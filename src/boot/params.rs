@@ -43,18 +43,39 @@
 //! boot_params at offset 0x1f1. We then override specific fields to configure
 //! the boot environment correctly.
 //!
+//! # setup_data
+//!
+//! Boot protocol 2.09 added a linked list of extra data, `setup_data`. We
+//! pass one entry, `SETUP_RNG_SEED`: 32 bytes from the host's
+//! `/dev/urandom`, which the kernel mixes into its RNG (and then wipes)
+//! before anything else runs. Guests get good entropy from the first
+//! instruction, without waiting for virtio-rng or jitter entropy. If the
+//! host can't provide randomness, the guest boots without a seed.
+//!
 //! Reference: <https://www.kernel.org/doc/html/latest/x86/boot.html>
 //! Reference: <https://www.kernel.org/doc/html/latest/x86/zero-page.html>
 
 use super::acpi::RSDP_ADDR;
 use super::bzimage::LoadedKernel;
+use super::initrd::LoadedInitrd;
 use super::layout;
 use super::memory::GuestMemory;
 use super::{BootConfig, BootError};
+use std::fs::File;
+use std::io::{self, Read};
 
 /// Size of the boot_params structure (one 4KB page).
 const BOOT_PARAMS_SIZE: usize = 4096;
 
+/// First boot protocol version with the setup_data list.
+const SETUP_DATA_MIN_VERSION: u16 = 0x0209;
+
+/// setup_data type: a seed for the kernel's RNG.
+const SETUP_RNG_SEED: u32 = 9;
+
+/// Size of the RNG seed, as QEMU passes.
+const RNG_SEED_SIZE: usize = 32;
+
 /// E820 memory region types.
 ///
 /// These values are defined by the BIOS E820 specification and tell the
@@ -84,6 +105,9 @@ mod offsets {
     /// Start of setup header within boot_params.
     pub const SETUP_HEADER: usize = 0x1f1;
 
+    /// version field (2 bytes) - boot protocol version.
+    pub const VERSION: usize = 0x206;
+
     /// type_of_loader field (1 byte) - offset 0x210 in bzImage, 0x210 in boot_params.
     /// But relative to setup_header start (0x1f1), it's at 0x210 - 0x1f1 = 0x1f.
     pub const TYPE_OF_LOADER: usize = 0x210;
@@ -91,9 +115,18 @@ mod offsets {
     /// loadflags field (1 byte) - offset 0x211 in bzImage/boot_params.
     pub const LOADFLAGS: usize = 0x211;
 
+    /// ramdisk_image field (4 bytes) - guest address of the initrd.
+    pub const RAMDISK_IMAGE: usize = 0x218;
+
+    /// ramdisk_size field (4 bytes) - size of the initrd in bytes.
+    pub const RAMDISK_SIZE: usize = 0x21c;
+
     /// cmd_line_ptr field (4 bytes) - offset 0x228 in boot_params.
     pub const CMD_LINE_PTR: usize = 0x228;
 
+    /// setup_data field (8 bytes) - guest address of the setup_data list.
+    pub const SETUP_DATA: usize = 0x250;
+
     /// Start of E820 memory map array (128 entries × 20 bytes each).
     pub const E820_MAP: usize = 0x2d0;
 }
@@ -106,16 +139,20 @@ mod offsets {
 /// 1. **Setup header**: Copied from the bzImage
 /// 2. **Command line**: Pointer to our command line string
 /// 3. **Memory map**: E820 entries describing available RAM
+/// 4. **Initrd**: Location and size of the initrd, if one was loaded
+/// 5. **setup_data**: A seed for the kernel's RNG
 ///
 /// # Arguments
 ///
 /// * `memory` - Guest memory where boot_params will be written
 /// * `config` - Boot configuration (cmdline, memory size)
 /// * `loaded_kernel` - Result from bzimage loading with setup_header
+/// * `initrd` - Location of the loaded initrd, if any
 pub fn setup_boot_params(
     memory: &GuestMemory,
     config: &BootConfig,
     loaded_kernel: &LoadedKernel,
+    initrd: Option<&LoadedInitrd>,
 ) -> Result<(), BootError> {
     // Start with a zeroed boot_params buffer
     let mut params = [0u8; BOOT_PARAMS_SIZE];
@@ -148,6 +185,27 @@ pub fn setup_boot_params(
     let cmd_line_ptr = (layout::CMDLINE_START as u32).to_le_bytes();
     params[offsets::CMD_LINE_PTR..offsets::CMD_LINE_PTR + 4].copy_from_slice(&cmd_line_ptr);
 
+    // Initrd location - both fields are 32 bits, the loader keeps it below 4GB
+    if let Some(initrd) = initrd {
+        let image = (initrd.addr as u32).to_le_bytes();
+        params[offsets::RAMDISK_IMAGE..offsets::RAMDISK_IMAGE + 4].copy_from_slice(&image);
+        let size = initrd.size.to_le_bytes();
+        params[offsets::RAMDISK_SIZE..offsets::RAMDISK_SIZE + 4].copy_from_slice(&size);
+    }
+
+    // RNG seed, for kernels that read setup_data
+    let version = u16::from_le_bytes([params[offsets::VERSION], params[offsets::VERSION + 1]]);
+    if version >= SETUP_DATA_MIN_VERSION {
+        match host_random(RNG_SEED_SIZE) {
+            Ok(seed) => {
+                write_setup_data(memory, layout::SETUP_DATA_START, SETUP_RNG_SEED, &seed)?;
+                params[offsets::SETUP_DATA..offsets::SETUP_DATA + 8]
+                    .copy_from_slice(&layout::SETUP_DATA_START.to_le_bytes());
+            }
+            Err(e) => warn!("[Boot] No RNG seed for the guest: {}", e),
+        }
+    }
+
     // Write the boot_params structure to guest memory
     memory.write(layout::BOOT_PARAMS_START, &params)?;
 
@@ -161,7 +219,7 @@ pub fn setup_boot_params(
         e820_entries,
     )?;
 
-    eprintln!(
+    debug!(
         "[Boot] boot_params at {:#x}, cmdline at {:#x}",
         layout::BOOT_PARAMS_START,
         layout::CMDLINE_START
@@ -186,14 +244,39 @@ fn setup_cmdline(memory: &GuestMemory, cmdline: &str) -> Result<(), BootError> {
     memory.write(layout::CMDLINE_START, cmdline.as_bytes())?;
     memory.write_u8(layout::CMDLINE_START + cmdline.len() as u64, 0)?;
 
-    eprintln!("[Boot] Command line: {}", cmdline);
+    debug!("[Boot] Command line: {}", cmdline);
     Ok(())
 }
 
+/// Read `len` random bytes from the host.
+pub(super) fn host_random(len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Write a single setup_data entry, ending the list, at `addr`.
+///
+/// Each entry is a 16-byte header followed by the data:
+/// - Bytes 0-7: Address of the next entry (u64, 0 for none)
+/// - Bytes 8-11: Type (u32)
+/// - Bytes 12-15: Data length (u32)
+fn write_setup_data(
+    memory: &GuestMemory,
+    addr: u64,
+    type_: u32,
+    data: &[u8],
+) -> Result<(), BootError> {
+    memory.write_u64(addr, 0)?;
+    memory.write_u32(addr + 8, type_)?;
+    memory.write_u32(addr + 12, data.len() as u32)?;
+    memory.write(addr + 16, data)
+}
+
 /// Set up the E820 memory map in boot_params.
 ///
 /// The E820 map tells the kernel what physical memory regions exist
-/// and what they can be used for. For a simple VM, we create three entries:
+/// and what they can be used for. We create three or four entries:
 ///
 /// 1. **Low memory** (0x0 - 0x9FC00): ~640KB of usable RAM
 ///    This is the traditional "conventional memory" area.
@@ -202,8 +285,15 @@ fn setup_cmdline(memory: &GuestMemory, cmdline: &str) -> Result<(), BootError> {
 ///    This covers the EBDA (Extended BIOS Data Area), video memory,
 ///    ROM area, and other legacy PC reserved regions.
 ///
-/// 3. **High memory** (0x100000 - mem_size): Main RAM
-///    All memory from 1MB to the end of guest RAM is usable.
+/// 3. **High memory** (0x100000 - 3GB or mem_size): Main RAM
+///    All memory from 1MB to the end of guest RAM or the start of the MMIO
+///    hole, whichever comes first, is usable.
+///
+/// 4. **Memory above 4GB**: the RAM that didn't fit below the MMIO hole,
+///    when `mem_size` is larger than 3GB.
+///
+/// The MMIO hole itself is left out: it is not RAM, and the kernel finds
+/// the devices in it from ACPI.
 fn setup_e820_map(memory: &GuestMemory, mem_size: u64) -> Result<u8, BootError> {
     let e820_addr = layout::BOOT_PARAMS_START + offsets::E820_MAP as u64;
     let entry_size = 20u64; // Each E820 entry is 20 bytes (8 + 8 + 4)
@@ -229,17 +319,22 @@ fn setup_e820_map(memory: &GuestMemory, mem_size: u64) -> Result<u8, BootError>
     )?;
     entry_idx += 1;
 
-    // Entry 2: High memory (extended memory)
-    write_e820_entry(
-        memory,
-        e820_addr + entry_idx * entry_size,
-        0x10_0000,            // Start at 1MB
-        mem_size - 0x10_0000, // Rest of memory
-        E820Type::Ram,
-    )?;
-    entry_idx += 1;
+    // Entry 2: High memory (extended memory), then RAM above 4GB
+    for (start, size) in layout::ram_regions(mem_size) {
+        let end = start + size;
+        // The first region's first 1MB is described above
+        let start = start.max(layout::HIMEM_START);
+        write_e820_entry(
+            memory,
+            e820_addr + entry_idx * entry_size,
+            start,
+            end - start,
+            E820Type::Ram,
+        )?;
+        entry_idx += 1;
+    }
 
-    eprintln!(
+    debug!(
         "[Boot] E820 map: {} entries, {} MB total",
         entry_idx,
         mem_size / (1024 * 1024)
@@ -266,3 +361,57 @@ fn write_e820_entry(
     memory.write_u32(addr + 16, type_ as u32)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_e820_mmio_hole() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let entries = |mem_size| {
+            let count = setup_e820_map(&memory, mem_size).unwrap();
+            let mut map = vec![0u8; count as usize * 20];
+            memory
+                .read(
+                    layout::BOOT_PARAMS_START + offsets::E820_MAP as u64,
+                    &mut map,
+                )
+                .unwrap();
+            map.chunks(20)
+                .map(|entry| {
+                    let field = |range: std::ops::Range<usize>| {
+                        let mut bytes = [0u8; 8];
+                        bytes[..range.len()].copy_from_slice(&entry[range]);
+                        u64::from_le_bytes(bytes)
+                    };
+                    (field(0..8), field(8..16), field(16..20))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let ram = E820Type::Ram as u64;
+        assert_eq!(entries(512 << 20)[2], (0x10_0000, (511 << 20), ram));
+
+        // 4GB: RAM up to 3GB, then the last 1GB above 4GB
+        let map = entries(4 << 30);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[2], (0x10_0000, 0xc000_0000 - 0x10_0000, ram));
+        assert_eq!(map[3], (1 << 32, 1 << 30, ram));
+    }
+
+    #[test]
+    fn test_rng_seed_setup_data() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let seed = host_random(RNG_SEED_SIZE).unwrap();
+        write_setup_data(&memory, layout::SETUP_DATA_START, SETUP_RNG_SEED, &seed).unwrap();
+
+        let mut entry = [0u8; 16 + RNG_SEED_SIZE];
+        memory.read(layout::SETUP_DATA_START, &mut entry).unwrap();
+        assert_eq!(entry[..8], [0; 8]);
+        assert_eq!(entry[8..12], 9u32.to_le_bytes());
+        assert_eq!(entry[12..16], 32u32.to_le_bytes());
+        assert_eq!(entry[16..], seed[..]);
+        assert_ne!(seed, [0; RNG_SEED_SIZE]);
+    }
+}
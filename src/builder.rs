@@ -0,0 +1,478 @@
+//! Embedding Carbon: [`VmmBuilder`] and [`Vmm`].
+//!
+//! The builder describes a VM the way the command line does, from a kernel
+//! and the built-in defaults (512M of memory, one vCPU, COM1 on stdio); the
+//! [`Vmm`] it builds runs it on the calling thread, while other threads
+//! pause, snapshot or stop it:
+//!
+//! ```no_run
+//! use carbon::{ConsoleConfig, VmmBuilder};
+//! use std::path::Path;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let vmm = VmmBuilder::new("bzImage")
+//!     .cmdline("console=ttyS0 quiet")
+//!     .memory(1 << 30)
+//!     .disk("path=rootfs.img,ro".parse()?)
+//!     .serial(ConsoleConfig::File("console.log".into()))
+//!     .build()?;
+//! std::thread::scope(|scope| {
+//!     let running = scope.spawn(|| vmm.run());
+//!     // ... once the guest is ready:
+//!     vmm.pause()?;
+//!     vmm.snapshot(Path::new("vm.json"), Path::new("vm.mem"))?;
+//!     vmm.shutdown()?;
+//!     running.join().unwrap()?;
+//!     Ok(())
+//! })
+//! # }
+//! ```
+//!
+//! Whatever the builder has no method for is set on the [`VmConfig`] it
+//! holds, through [`VmmBuilder::config_mut`]. To await the run and the
+//! controls rather than block on them, build an
+//! [`AsyncVmm`](crate::async_vmm::AsyncVmm) with
+//! [`VmmBuilder::build_async`].
+//!
+//! A VM installs the process's SIGINT, SIGTERM and SIGHUP handlers while it
+//! runs, and stops on any of them, as `carbon` does; one served on a shared
+//! [`Reactor`] leaves signals to its host (see [`crate::host`]).
+
+use crate::async_vmm::AsyncVmm;
+use crate::boot::{layout, MemoryBacking};
+use crate::devices::{
+    ConsoleBackend, ConsoleConfig, P9Share, PmemConfig, SharedDirConfig, VsockConfig,
+};
+use crate::error::CarbonError;
+use crate::events::EventLog;
+use crate::hibernate::Hibernate;
+use crate::kvm::{Topology, VcpuStats};
+use crate::reactor::Reactor;
+use crate::snapshot::Snapshot;
+use crate::vmm::{
+    self, BootProfile, DiskConfig, RunOptions, RunOutcome, VcpuDebug, VmConfig, VmHandle,
+    DEFAULT_CMDLINE,
+};
+use std::io::{self, Write};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Describes a VM to build (see the [module docs](self)).
+pub struct VmmBuilder {
+    config: VmConfig,
+    options: RunOptions,
+}
+
+impl VmmBuilder {
+    /// A VM booting the kernel at `kernel` (a bzImage, or an uncompressed
+    /// vmlinux), with the defaults `carbon` has without flags.
+    pub fn new(kernel: impl Into<String>) -> Self {
+        Self::with_config(VmConfig {
+            kernel_path: kernel.into(),
+            kernel_sha256: None,
+            cmdline: DEFAULT_CMDLINE.to_string(),
+            boot_profile: BootProfile::default(),
+            mem_size: layout::DEFAULT_MEM_SIZE,
+            memory_backing: MemoryBacking::default(),
+            prefault: false,
+            swap_policy: Default::default(),
+            initrd: None,
+            kaslr: true,
+            disks: Vec::new(),
+            rootfs: None,
+            scratch_disk: None,
+            cpu_mode: Default::default(),
+            cpu_features: Default::default(),
+            topology: Topology::flat(1),
+            cpu_affinity: Default::default(),
+            hyperv: false,
+            nested: false,
+            exit_on_halt: false,
+            rtc: Default::default(),
+            device_plugins: Vec::new(),
+            vsock: None,
+            shared_dirs: Vec::new(),
+            p9_shares: Vec::new(),
+            pmem: None,
+            debug_exit: None,
+            serial: ConsoleConfig::default(),
+            serial2: None,
+            fw_cfg: Vec::new(),
+            tpm: None,
+            control_socket: None,
+            dump_core_on_fault: None,
+            hotplug_disks: 0,
+        })
+    }
+
+    /// The VM `config` describes in full.
+    pub fn with_config(config: VmConfig) -> Self {
+        Self {
+            config,
+            options: RunOptions::default(),
+        }
+    }
+
+    /// The VM a snapshot was taken of, resuming where it stopped rather
+    /// than booting (see [`crate::snapshot`]): its state file `state`, and
+    /// `memory` mapped private as guest RAM.
+    pub fn restore(state: &Path, memory: &Path) -> Result<Self, CarbonError> {
+        let snapshot_error = |path: &Path, source| CarbonError::Snapshot {
+            path: path.display().to_string(),
+            source,
+        };
+        let snapshot = Snapshot::read(state).map_err(|source| snapshot_error(state, source))?;
+        let mut config = snapshot.config.clone();
+        let size = std::fs::metadata(memory)
+            .map_err(|source| snapshot_error(memory, source))?
+            .len();
+        if size != config.mem_size {
+            let source = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the memory file holds {} bytes, but the VM has {}",
+                    size, config.mem_size
+                ),
+            );
+            return Err(snapshot_error(memory, source));
+        }
+        config.memory_backing = MemoryBacking::File {
+            path: memory.to_path_buf(),
+            shared: false,
+        };
+        // The snapshotted VM's socket isn't reused
+        config.control_socket = None;
+        let mut builder = Self::with_config(config);
+        builder.options.restore = Some(snapshot);
+        Ok(builder)
+    }
+
+    /// The VM as described so far, for settings without a method here.
+    pub fn config_mut(&mut self) -> &mut VmConfig {
+        &mut self.config
+    }
+
+    /// Kernel command line, before the boot profile's parameters.
+    pub fn cmdline(mut self, cmdline: impl Into<String>) -> Self {
+        self.config.cmdline = cmdline.into();
+        self
+    }
+
+    /// Which parameters are appended to the command line.
+    pub fn boot_profile(mut self, profile: BootProfile) -> Self {
+        self.config.boot_profile = profile;
+        self
+    }
+
+    /// Initrd/initramfs image.
+    pub fn initrd(mut self, path: impl Into<String>) -> Self {
+        self.config.initrd = Some(path.into());
+        self
+    }
+
+    /// Guest memory size in bytes.
+    pub fn memory(mut self, bytes: u64) -> Self {
+        self.config.mem_size = bytes;
+        self
+    }
+
+    /// What backs guest memory.
+    pub fn memory_backing(mut self, backing: MemoryBacking) -> Self {
+        self.config.memory_backing = backing;
+        self
+    }
+
+    /// vCPU count, as cores of one socket.
+    pub fn cpus(mut self, count: u8) -> Self {
+        self.config.topology = Topology::flat(count);
+        self
+    }
+
+    /// vCPUs arranged into sockets, cores and threads.
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.config.topology = topology;
+        self
+    }
+
+    /// Attach a disk, after those attached already (`/dev/vda`, then
+    /// `/dev/vdb`, ...).
+    pub fn disk(mut self, disk: DiskConfig) -> Self {
+        self.config.disks.push(disk);
+        self
+    }
+
+    /// Add a virtio-vsock device.
+    pub fn vsock(mut self, vsock: VsockConfig) -> Self {
+        self.config.vsock = Some(vsock);
+        self
+    }
+
+    /// Share a host directory served by a vhost-user-fs backend.
+    pub fn shared_dir(mut self, dir: SharedDirConfig) -> Self {
+        self.config.shared_dirs.push(dir);
+        self
+    }
+
+    /// Share a host directory over virtio-9p.
+    pub fn p9_share(mut self, share: P9Share) -> Self {
+        self.config.p9_shares.push(share);
+        self
+    }
+
+    /// Map an image into guest memory over virtio-pmem.
+    pub fn pmem(mut self, pmem: PmemConfig) -> Self {
+        self.config.pmem = Some(pmem);
+        self
+    }
+
+    /// Run a device plugin executable (see [`crate::devices::plugin`]).
+    pub fn device_plugin(mut self, path: impl Into<String>) -> Self {
+        self.config.device_plugins.push(path.into());
+        self
+    }
+
+    /// Console backend of COM1.
+    pub fn serial(mut self, serial: ConsoleConfig) -> Self {
+        self.config.serial = serial;
+        self
+    }
+
+    /// Attach COM2, with this console backend.
+    pub fn serial2(mut self, serial: ConsoleConfig) -> Self {
+        self.config.serial2 = Some(serial);
+        self
+    }
+
+    /// Serve COM1 with `console` rather than its configured backend.
+    pub fn console(mut self, console: Box<dyn ConsoleBackend>) -> Self {
+        self.options.console = Some(console);
+        self
+    }
+
+    /// Write VMM lifecycle events (`vcpu-started`, `init-reached`,
+    /// `stopped reason=...`) to `events`, one per write.
+    pub fn events(mut self, events: Box<dyn Write + Send>) -> Self {
+        self.options.events.set_text(events);
+        self
+    }
+
+    /// Append VMM lifecycle events to `log` as JSON lines (see
+    /// [`crate::events`]).
+    pub fn event_log(mut self, log: EventLog) -> Self {
+        self.options.events.set_log(log);
+        self
+    }
+
+    /// Serve runtime control commands on a Unix socket at `path` (see
+    /// [`crate::control`]).
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.control_socket = Some(path.into());
+        self
+    }
+
+    /// Serve the control socket on `listener`, already bound to the
+    /// configured path, rather than binding it (see
+    /// [`hibernate::run`](crate::hibernate::run)).
+    pub(crate) fn control_listener(mut self, listener: UnixListener) -> Self {
+        self.options.control_listener = Some(listener);
+        self
+    }
+
+    /// Stop the VM if it is still running after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop the VM if the guest hasn't reached init (printed the init
+    /// marker) within `timeout`. A restored VM has no boot to time.
+    pub fn boot_timeout(mut self, timeout: Duration) -> Self {
+        self.options.boot_timeout = Some(timeout);
+        self
+    }
+
+    /// Snapshot the VM into `hibernate.dir` and stop it, with
+    /// [`StopReason::Hibernated`](vmm::StopReason::Hibernated), once it has
+    /// been idle for `hibernate.idle_timeout` (see [`crate::hibernate`]).
+    /// The run fails to start if the VM can't be snapshotted.
+    pub fn hibernate(mut self, hibernate: Hibernate) -> Self {
+        self.options.hibernate = Some(hibernate);
+        self
+    }
+
+    /// Stop the VM once the guest prints `marker` on the console, which
+    /// the kernel prints as it starts init (see
+    /// [`vmm::DEFAULT_INIT_MARKER`]).
+    pub fn stop_at_init(mut self, marker: impl Into<String>) -> Self {
+        self.options.init_marker = marker.into();
+        self.options.stop_at_init = true;
+        self
+    }
+
+    /// Serve the VM's devices from `reactor`'s thread, shared with other
+    /// VMs, rather than the thread running it.
+    pub fn reactor(mut self, reactor: Arc<Reactor>) -> Self {
+        self.options.reactor = Some(reactor);
+        self
+    }
+
+    /// Whether the VM reads stdin, which only one VM in a process can.
+    pub(crate) fn uses_stdin(&self) -> bool {
+        self.options.console.is_none() && self.config.serial == ConsoleConfig::Stdio
+            || self.config.serial2 == Some(ConsoleConfig::Stdio)
+    }
+
+    /// Keep the vCPUs paused until [`Vmm::resume`].
+    pub fn paused(mut self) -> Self {
+        self.options.start_paused = true;
+        self
+    }
+
+    /// The VM, to run and control from async code (see
+    /// [`crate::async_vmm`]).
+    pub fn build_async(self) -> Result<AsyncVmm, CarbonError> {
+        AsyncVmm::new(self)
+    }
+
+    /// The VM, ready to run.
+    pub fn build(self) -> Result<Vmm, CarbonError> {
+        let mut options = self.options;
+        let handle = VmHandle::new()
+            .map_err(|e| CarbonError::Guest(format!("failed to create VM handle: {e}")))?;
+        let handle = Arc::new(handle);
+        options.handle = Some(handle.clone());
+        Ok(Vmm {
+            config: self.config,
+            options: Mutex::new(Some(options)),
+            handle,
+        })
+    }
+}
+
+/// A VM built by [`VmmBuilder`]: runs once, on the thread that calls
+/// [`run`](Self::run), and takes the other calls from any thread while it
+/// does.
+pub struct Vmm {
+    config: VmConfig,
+    /// Taken by the run.
+    options: Mutex<Option<RunOptions>>,
+    handle: Arc<VmHandle>,
+}
+
+impl Vmm {
+    /// The VM's configuration.
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// Build the VM and run it until it stops, returning why.
+    pub fn run(&self) -> Result<RunOutcome, CarbonError> {
+        let options = self
+            .options
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .ok_or_else(|| CarbonError::Config("the VM has already run".into()))?;
+        vmm::run(&self.config, options)
+    }
+
+    /// Stop the vCPUs until [`resume`](Self::resume).
+    pub fn pause(&self) -> io::Result<()> {
+        self.handle.pause()
+    }
+
+    /// Let the vCPUs of a paused VM run on.
+    pub fn resume(&self) -> io::Result<()> {
+        self.handle.resume()
+    }
+
+    /// Whether the VM is running, and paused.
+    pub fn is_paused(&self) -> bool {
+        self.handle.is_paused()
+    }
+
+    /// Snapshot the VM: its state to `state`, guest RAM to `memory`, to
+    /// restore with [`VmmBuilder::restore`].
+    pub fn snapshot(&self, state: &Path, memory: &Path) -> io::Result<()> {
+        self.handle.snapshot(state, memory)
+    }
+
+    /// Dump the guest to `path` as an ELF core.
+    pub fn dump_core(&self, path: &Path) -> io::Result<()> {
+        self.handle.dump_core(path)
+    }
+
+    /// Each vCPU's statistics so far, by index: exits by reason, time in
+    /// and out of the guest, and KVM's own counters. Fails until the VM's
+    /// vCPUs are created, and once it has stopped.
+    pub fn stats(&self) -> io::Result<Vec<VcpuStats>> {
+        self.handle.stats()
+    }
+
+    /// Breakpoints and single-stepping on vCPU `index`, while the VM is
+    /// paused. A vCPU reaching a breakpoint pauses the VM, for
+    /// [`is_paused`](Self::is_paused) to tell. Fails while the VM isn't
+    /// running.
+    pub fn debug(&self, index: usize) -> io::Result<VcpuDebug> {
+        self.handle.debug(index)
+    }
+
+    /// Stop the VM: [`run`](Self::run) returns
+    /// [`StopReason::Shutdown`](vmm::StopReason::Shutdown). Before the run
+    /// starts, it stops as soon as it does.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.handle.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let vmm = VmmBuilder::new("bzImage")
+            .cmdline("console=ttyS0 quiet")
+            .memory(1 << 30)
+            .cpus(2)
+            .disk("path=root.img,ro".parse().unwrap())
+            .disk("scratch.img".parse().unwrap())
+            .serial2(ConsoleConfig::Pty)
+            .build()
+            .unwrap();
+        let config = vmm.config();
+        assert_eq!(config.kernel_path, "bzImage");
+        assert_eq!(config.cmdline, "console=ttyS0 quiet");
+        assert_eq!(config.mem_size, 1 << 30);
+        assert_eq!(config.topology.cpus(), 2);
+        let disks: Vec<_> = config.disks.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(disks, ["root.img", "scratch.img"]);
+        assert!(config.disks[0].options.read_only);
+        assert_eq!(config.serial, ConsoleConfig::Stdio);
+        assert_eq!(config.serial2, Some(ConsoleConfig::Pty));
+    }
+
+    #[test]
+    fn test_controls_need_a_running_vm() {
+        let vmm = VmmBuilder::new("bzImage").build().unwrap();
+        let err = vmm.pause().unwrap_err();
+        assert!(err.to_string().contains("isn't running"), "{err}");
+        assert!(vmm.resume().is_err());
+        assert!(!vmm.is_paused());
+        let path = Path::new("unused");
+        assert!(vmm.snapshot(path, path).is_err());
+        assert!(vmm.stats().is_err());
+        assert!(vmm.debug(0).is_err());
+        vmm.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_runs_once() {
+        let vmm = VmmBuilder::new("missing-kernel").build().unwrap();
+        *vmm.options.lock().unwrap() = None;
+        let err = vmm.run().unwrap_err();
+        assert!(err.to_string().contains("already run"), "{err}");
+    }
+}
@@ -0,0 +1,265 @@
+//! Guaranteed cleanup on exit and on panic.
+//!
+//! Some host state must be put back no matter how Carbon stops: terminal
+//! modes, locks and unsynced writes on disk images, and the sockets Carbon
+//! listens on (see [`bind_socket`]). Each of these registers a cleanup action and
+//! keeps the returned [`CleanupGuard`]:
+//!
+//! - On a normal exit the guard is dropped and runs its action.
+//! - On a panic, [`install_panic_hook`]'s hook runs every outstanding action,
+//!   logs a fatal event and aborts. Aborting means no destructors run and no
+//!   half-alive VMM keeps a vCPU spinning, which is why the hook, not `Drop`,
+//!   is responsible on this path.
+//!
+//! Actions run at most once, in reverse registration order.
+
+use crate::audit;
+use std::io::{self, IsTerminal};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, PanicHookInfo};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+type Action = Box<dyn FnOnce() + Send>;
+
+/// Outstanding cleanup actions, by guard id.
+static ACTIONS: Mutex<Vec<(u64, &'static str, Action)>> = Mutex::new(Vec::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Runs a cleanup action when dropped, unless a panic ran it first.
+#[must_use = "the cleanup runs as soon as the guard is dropped"]
+pub struct CleanupGuard {
+    id: u64,
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        let action = {
+            let mut actions = ACTIONS.lock().unwrap_or_else(|e| e.into_inner());
+            actions
+                .iter()
+                .position(|(id, _, _)| *id == self.id)
+                .map(|i| actions.remove(i).2)
+        };
+        if let Some(action) = action {
+            action();
+        }
+    }
+}
+
+/// Register `action` to run when the guard drops or Carbon panics.
+///
+/// `what` names the action in the fatal log (e.g. `"sync disk image"`).
+pub fn register(what: &'static str, action: impl FnOnce() + Send + 'static) -> CleanupGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    ACTIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((id, what, Box::new(action)));
+    CleanupGuard { id }
+}
+
+/// Listen on a Unix socket at `path`, removing the socket file when the
+/// guard drops or Carbon panics. `what` names the cleanup (e.g. `"remove
+/// control socket"`).
+///
+/// A socket file left at `path` by a process that is gone (connecting to it
+/// is refused) is replaced. Anything else there, a live socket included,
+/// fails with [`io::ErrorKind::AddrInUse`] and is left alone.
+pub fn bind_socket(path: &Path, what: &'static str) -> io::Result<(UnixListener, CleanupGuard)> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    audit::record(audit::Kind::Socket, "bind", &path.display().to_string());
+    let socket_path = path.to_path_buf();
+    let guard = register(what, move || {
+        let _ = std::fs::remove_file(&socket_path);
+        audit::record(
+            audit::Kind::Socket,
+            "remove",
+            &socket_path.display().to_string(),
+        );
+    });
+    Ok((listener, guard))
+}
+
+/// Remove the socket file at `path` if nothing listens on it any more.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let in_use = || {
+        io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("address in use: {}", path.display()),
+        )
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(in_use());
+    }
+    match UnixStream::connect(path) {
+        Err(e) if e.raw_os_error() == Some(libc::ECONNREFUSED) => std::fs::remove_file(path),
+        _ => Err(in_use()),
+    }
+}
+
+/// Save the terminal mode of stdin and restore it on exit or panic.
+///
+/// Returns `None` if stdin isn't a terminal.
+pub fn guard_terminal() -> Option<CleanupGuard> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return None;
+    }
+    let fd = stdin.as_raw_fd();
+    // SAFETY: termios is plain old data, filled in by tcgetattr.
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: fd is a valid terminal and `saved` is a valid termios.
+    if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+        return None;
+    }
+    Some(register("restore terminal mode", move || {
+        // SAFETY: restoring attributes previously read from the same fd.
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    }))
+}
+
+/// Put the terminal on stdin into raw mode, so keystrokes reach the guest
+/// console as typed, until the guard drops or Carbon panics.
+///
+/// Signal keys (Ctrl-C still stops Carbon) and output processing are kept.
+/// Returns `None` if stdin isn't a terminal.
+pub fn raw_terminal() -> Option<CleanupGuard> {
+    let guard = guard_terminal()?;
+    let fd = io::stdin().as_raw_fd();
+    // SAFETY: termios is plain old data, filled in by tcgetattr.
+    let mut raw: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: fd is a valid terminal and `raw` is a valid termios.
+    unsafe {
+        if libc::tcgetattr(fd, &mut raw) == 0 {
+            libc::cfmakeraw(&mut raw);
+            raw.c_lflag |= libc::ISIG;
+            raw.c_oflag |= libc::OPOST;
+            libc::tcsetattr(fd, libc::TCSANOW, &raw);
+        }
+    }
+    Some(guard)
+}
+
+/// Run every outstanding action. Returns the names of the actions run.
+fn run_pending() -> Vec<&'static str> {
+    // try_lock: the panic may have happened while the registry was locked
+    let pending = match ACTIONS.try_lock() {
+        Ok(mut actions) => std::mem::take(&mut *actions),
+        Err(std::sync::TryLockError::Poisoned(e)) => std::mem::take(&mut *e.into_inner()),
+        Err(std::sync::TryLockError::WouldBlock) => return Vec::new(),
+    };
+    pending
+        .into_iter()
+        .rev()
+        .map(|(_, what, action)| {
+            // A failing cleanup must not stop the others
+            let _ = panic::catch_unwind(panic::AssertUnwindSafe(action));
+            what
+        })
+        .collect()
+}
+
+/// Install the panic hook: clean up, log a fatal event, then abort.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let cleaned = run_pending();
+        error!("{}", fatal_event(info, &cleaned));
+        default_hook(info);
+        std::process::abort();
+    }));
+}
+
+/// One-line, key=value description of a panic for log scrapers.
+fn fatal_event(info: &PanicHookInfo, cleaned: &[&str]) -> String {
+    let message = info
+        .payload()
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<non-string panic payload>");
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}", l.file(), l.line()))
+        .unwrap_or_else(|| "unknown".into());
+    let thread = std::thread::current();
+    format!(
+        "[VMM] fatal event=panic thread={} location={} cleanup={:?} message={:?}",
+        thread.name().unwrap_or("unnamed"),
+        location,
+        cleaned.join(","),
+        message
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_guard_runs_action_once() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let guard = register("count", move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        drop(guard);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_panic_path_runs_outstanding_actions() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let guard = register("count on panic", move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(run_pending().contains(&"count on panic"));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Already run: dropping the guard must not run it again
+        drop(guard);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_bind_socket() {
+        let path = std::env::temp_dir().join(format!("carbon-cleanup-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // Live: refused, and still served
+        let (listener, guard) = bind_socket(&path, "remove test socket").unwrap();
+        let err = bind_socket(&path, "remove test socket").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(UnixStream::connect(&path).is_ok());
+
+        // Stale: the listener closed without removing its file
+        std::mem::forget(guard);
+        drop(listener);
+        assert!(path.exists());
+        let (_listener, guard) = bind_socket(&path, "remove test socket").unwrap();
+        drop(guard);
+        assert!(!path.exists());
+
+        // Not a socket: left alone
+        std::fs::write(&path, b"data").unwrap();
+        let err = bind_socket(&path, "remove test socket").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Clones of a snapshot (`carbon restore --clone`).
+//!
+//! A snapshot (see [`crate::snapshot`]) of a warmed-up template VM can be
+//! restored any number of times at once, each restore a clone carrying on
+//! from the point the template was paused at:
+//!
+//! - Guest memory is shared copy-on-write. Every clone maps the snapshot's
+//!   memory file private, so a page is read into the page cache once for
+//!   all of them, and copied for a clone only when it writes to it. With the
+//!   file on tmpfs (`/dev/shm`) no clone waits on a disk.
+//! - Each disk the guest can write gets a copy of its own per clone,
+//!   removed when the clone exits, so clones see neither each other's
+//!   writes nor change the template's images. The copy is a reflink where
+//!   the filesystem supports one (btrfs, XFS), instant and taking no space
+//!   until written, and a sparse copy elsewhere. Read-only disks are shared.
+//! - Each clone has a VM ID of its own in the audit log (`--vm-id`, random
+//!   by default), and its own console and control socket.
+//!
+//! What the guest holds stays as the template left it: its random number
+//! generator, machine ID, host name and any keys it made. A guest meant to
+//! be cloned should refresh them once resumed.
+
+use crate::audit;
+use crate::cleanup::{self, CleanupGuard};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+static NEXT_COPY: AtomicU32 = AtomicU32::new(0);
+
+/// A clone's private copy of a disk image, deleted when dropped (or on
+/// panic).
+pub struct DiskCopy {
+    path: PathBuf,
+    _cleanup: CleanupGuard,
+}
+
+impl DiskCopy {
+    /// Copy `image` next to it, where a reflink is possible.
+    pub fn create(image: &Path) -> io::Result<Self> {
+        let source = File::open(image)?;
+        let name = image
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+        let path = image.with_file_name(format!(
+            ".{}.clone-{}-{}",
+            name.to_string_lossy(),
+            std::process::id(),
+            NEXT_COPY.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        audit::record(audit::Kind::Disk, "create", &path.display().to_string());
+        let remove_path = path.clone();
+        let cleanup = cleanup::register("remove disk clone", move || {
+            let _ = std::fs::remove_file(&remove_path);
+            audit::record(
+                audit::Kind::Disk,
+                "remove",
+                &remove_path.display().to_string(),
+            );
+        });
+        let copy = Self {
+            path,
+            _cleanup: cleanup,
+        };
+        if !reflink(&source, &file)? {
+            sparse_copy(&source, &file)?;
+        }
+        file.sync_all()?;
+        Ok(copy)
+    }
+
+    /// Path of the copy.
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+}
+
+/// Make `to` share `from`'s blocks, if the filesystem can. Returns whether
+/// it did.
+fn reflink(from: &File, to: &File) -> io::Result<bool> {
+    // SAFETY: FICLONE takes the source descriptor as its argument.
+    if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // Not supported here, or across filesystems
+        Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+/// Copy the data of `from` to `to`, leaving its holes as holes.
+fn sparse_copy(from: &File, to: &File) -> io::Result<()> {
+    let len = from.metadata()?.len();
+    to.set_len(len)?;
+    let mut offset = 0;
+    while offset < len {
+        // SAFETY: lseek on an open descriptor has no memory effects.
+        let data = unsafe { libc::lseek(from.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = io::Error::last_os_error();
+            // No data past offset
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(e);
+        }
+        // SAFETY: as above.
+        let hole = unsafe { libc::lseek(from.as_raw_fd(), data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        copy_range(from, to, data as u64, hole as u64 - data as u64)?;
+        offset = hole as u64;
+    }
+    Ok(())
+}
+
+/// Copy `len` bytes at `offset` of `from` to the same offset of `to`.
+fn copy_range(from: &File, to: &File, offset: u64, mut len: u64) -> io::Result<()> {
+    let mut off_in = offset as libc::loff_t;
+    let mut off_out = offset as libc::loff_t;
+    while len > 0 {
+        // SAFETY: both descriptors are open and the offsets are ours.
+        let copied = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                &mut off_in,
+                to.as_raw_fd(),
+                &mut off_out,
+                len as usize,
+                0,
+            )
+        };
+        match copied {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            n => len -= n as u64,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    #[test]
+    fn test_disk_copy() {
+        let image = std::env::temp_dir().join(format!("carbon-clone-{}.img", std::process::id()));
+        let file = File::create(&image).unwrap();
+        file.set_len(4 << 20).unwrap();
+        file.write_all_at(b"template", 1 << 20).unwrap();
+        drop(file);
+
+        let copy = DiskCopy::create(&image).unwrap();
+        let path = PathBuf::from(copy.path());
+        assert_eq!(path.parent(), image.parent());
+        let cloned = std::fs::read(&path).unwrap();
+        assert_eq!(cloned, std::fs::read(&image).unwrap());
+        // Holes stay holes, however the copy was made
+        assert!(std::fs::metadata(&path).unwrap().blocks() < 4 << 20 >> 9);
+
+        // Writes to the copy don't reach the image
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"clone", 1 << 20).unwrap();
+        let mut data = [0u8; 8];
+        File::open(&image)
+            .unwrap()
+            .read_exact_at(&mut data, 1 << 20)
+            .unwrap();
+        assert_eq!(&data, b"template");
+
+        drop(copy);
+        assert!(!path.exists());
+        std::fs::remove_file(&image).unwrap();
+    }
+}
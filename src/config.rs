@@ -0,0 +1,409 @@
+//! Named configuration profiles.
+//!
+//! A profile is a TOML file bundling the options you would otherwise repeat on
+//! every invocation:
+//!
+//! ```toml
+//! # ~/.config/carbon/profiles/dev.toml
+//! kernel = "/srv/carbon/vmlinuz"
+//! cmdline = "console=ttyS0 root=/dev/vda"
+//! memory = "2G"
+//! disk = "/srv/carbon/dev.img"
+//! cpu = "baseline"
+//! ```
+//!
+//! so that `carbon run --profile dev --disk work.img` is a complete command line.
+//!
+//! # Lookup
+//!
+//! `--profile NAME` reads `NAME.toml` from the profile directory:
+//! `$XDG_CONFIG_HOME/carbon/profiles`, falling back to
+//! `~/.config/carbon/profiles`. A value containing `/` or ending in `.toml` is
+//! used as a path instead. Without `--profile`, the `default` profile is
+//! loaded if it exists.
+//!
+//! # Precedence
+//!
+//! Each option is resolved from the first source that sets it:
+//!
+//! 1. Command-line flag (`--memory 2G`)
+//! 2. Environment variable (`CARBON_MEMORY=2G`)
+//! 3. Profile (`memory = "2G"`), or the `--config` file in its place (see
+//!    [`crate::config_file`])
+//! 4. Built-in default
+//!
+//! The variables are `CARBON_PROFILE`, `CARBON_CONFIG`, `CARBON_KERNEL`,
+//! `CARBON_KERNEL_SHA256`, `CARBON_CMDLINE`,
+//! `CARBON_MEMORY`, `CARBON_MEMORY_BACKEND`, `CARBON_PREFAULT`, `CARBON_MLOCK`,
+//! `CARBON_SWAPPABLE`, `CARBON_INITRD`, `CARBON_NO_KASLR`, `CARBON_BOOT_PROFILE`,
+//! `CARBON_NO_AUTO_CMDLINE`,
+//! `CARBON_DISK_PREFETCH`, `CARBON_DISK_READ_ONLY`, `CARBON_PMEM`,
+//! `CARBON_ROOTFS`, `CARBON_ROOTFS_SHA256`, `CARBON_ROOTFS_OVERLAY_SIZE`, `CARBON_SCRATCH_DISK`,
+//! `CARBON_CPU`, `CARBON_CPU_FEATURES`, `CARBON_CPUS`, `CARBON_TOPOLOGY`,
+//! `CARBON_CPU_AFFINITY`, `CARBON_HYPERV`, `CARBON_ENABLE_NESTED`,
+//! `CARBON_RTC_OFFSET`, `CARBON_RTC_START`, `CARBON_VSOCK`, `CARBON_DEBUG_EXIT`,
+//! `CARBON_EXIT_ON_HALT`, `CARBON_SERIAL`, `CARBON_SERIAL2`, `CARBON_TPM`,
+//! `CARBON_DEVICE_PLUGINS` (comma-separated), `CARBON_DISK`,
+//! `CARBON_SHARED_DIRS`, `CARBON_9P` and `CARBON_FW_CFG`
+//! (semicolon-separated), plus `CARBON_LOG` for `--log-level`. An empty
+//! variable counts as set. Paths are used exactly as written.
+
+use crate::audit;
+use crate::size::{self, ByteSize};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Profile loaded when `--profile` isn't given (if it exists).
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Errors from locating or parsing a profile.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("profile {name:?} not found (looked for {})", path.display())]
+    ProfileNotFound { name: String, path: PathBuf },
+
+    #[error("cannot locate profile directory: neither XDG_CONFIG_HOME nor HOME is set")]
+    NoConfigDir,
+
+    #[error("failed to read profile {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("invalid profile {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+}
+
+/// Options a profile may set. Every field is optional.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Path to the kernel bzImage or vmlinux.
+    pub kernel: Option<String>,
+    /// SHA-256 digest the kernel must have (`--kernel-sha256`).
+    pub kernel_sha256: Option<String>,
+    /// Kernel command line.
+    pub cmdline: Option<String>,
+    /// Guest memory size (`"2G"`, or a bare number of MiB).
+    #[serde(default, deserialize_with = "deserialize_memory")]
+    pub memory: Option<ByteSize>,
+    /// What backs guest memory (`--memory-backend`, e.g. `"sealed"` or
+    /// `"file=/dev/shm/vm0,shared=on"`).
+    pub memory_backend: Option<String>,
+    /// Fault in guest memory at startup (`--prefault`).
+    pub prefault: Option<bool>,
+    /// Lock guest memory into host RAM (`--mlock`).
+    pub mlock: Option<bool>,
+    /// Allow paging guest memory out on request (`--swappable`).
+    pub swappable: Option<bool>,
+    /// Path to an initrd/initramfs image.
+    pub initrd: Option<String>,
+    /// Run the kernel at its link address (`--no-kaslr`).
+    pub no_kaslr: Option<bool>,
+    /// Parameters appended to the command line (`fast`, `compat` or
+    /// `custom`).
+    pub boot_profile: Option<String>,
+    /// Append no boot profile parameters (`--no-auto-cmdline`).
+    pub no_auto_cmdline: Option<bool>,
+    /// Disks (`--disk`, options included): one (`"dev.img"`) or a list
+    /// (`["dev.img", "path=data.img,ro,logical-block-size=4K"]`).
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    pub disk: Option<Vec<String>>,
+    /// Image mapped as virtio-pmem (`--pmem`, options included).
+    pub pmem: Option<String>,
+    /// Read-only base image with a scratch overlay (`--rootfs`).
+    pub rootfs: Option<String>,
+    /// SHA-256 digest the `rootfs` base image must have (`--rootfs-sha256`).
+    pub rootfs_sha256: Option<String>,
+    /// Size of the `--rootfs` scratch overlay (`"1G"`, or a bare number of MiB).
+    #[serde(default, deserialize_with = "deserialize_disk_size")]
+    pub rootfs_overlay_size: Option<ByteSize>,
+    /// In-memory scratch disk (`--scratch-disk`, e.g. `"size=2G"`).
+    pub scratch_disk: Option<String>,
+    /// Prefetch the disk's learned boot profile (`--disk-prefetch`).
+    pub disk_prefetch: Option<bool>,
+    /// Expose every disk read-only (`--disk-read-only`).
+    pub disk_read_only: Option<bool>,
+    /// CPU model (`host`, `portable`, `baseline` or `x86-64-v3`).
+    pub cpu: Option<String>,
+    /// Features on top of `cpu` (`--cpu-features`, e.g. `"+avx2,-rdtscp"`).
+    pub cpu_features: Option<String>,
+    /// Number of vCPUs (`--cpus`).
+    pub cpus: Option<u32>,
+    /// vCPU arrangement (`--topology`, e.g. `"sockets=1,cores=2,threads=2"`).
+    pub topology: Option<String>,
+    /// Host cores for the vCPU threads: as `--cpu-affinity` (`"2,3"`), or
+    /// each vCPU's own (`["2", "3-4"]`).
+    pub cpu_affinity: Option<CpuAffinity>,
+    /// Expose Hyper-V enlightenments (`--hyperv`).
+    pub hyperv: Option<bool>,
+    /// Expose VMX/SVM to the guest (`--enable-nested`).
+    pub enable_nested: Option<bool>,
+    /// Guest RTC offset from host UTC in seconds (`--rtc-offset`).
+    pub rtc_offset: Option<i64>,
+    /// Fixed guest RTC start as Unix time (`--rtc-start`).
+    pub rtc_start: Option<i64>,
+    /// Device plugin executables (`--device-plugin`).
+    pub device_plugins: Option<Vec<String>>,
+    /// virtio-vsock device (`--vsock`, e.g. `"cid=3,uds=/tmp/v.sock"`).
+    pub vsock: Option<String>,
+    /// Shared directories (`--shared-dir`, e.g. `"socket=/run/vfsd.sock,tag=workspace"`).
+    pub shared_dirs: Option<Vec<String>>,
+    /// 9P shares (`--9p`, e.g. `"path=/srv/workspace,tag=host"`).
+    #[serde(rename = "9p")]
+    pub p9_shares: Option<Vec<String>>,
+    /// I/O port of the debug exit device (`--debug-exit`, e.g. `0xf4`).
+    pub debug_exit: Option<u16>,
+    /// Stop once the guest halts for good (`--exit-on-halt`).
+    pub exit_on_halt: Option<bool>,
+    /// Console backend of the first serial port (`--serial`, e.g. `"pty"`).
+    pub serial: Option<String>,
+    /// Console backend of the second serial port (`--serial2`).
+    pub serial2: Option<String>,
+    /// fw_cfg blobs (`--fw-cfg`, e.g. `"name=opt/agent,file=agent.toml"`).
+    pub fw_cfg: Option<Vec<String>>,
+    /// swtpm data socket (`--tpm`).
+    pub tpm: Option<PathBuf>,
+}
+
+/// A profile's `cpu_affinity`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum CpuAffinity {
+    /// Cores, as `--cpu-affinity` takes them.
+    Cores(String),
+    /// The cores of each vCPU in turn.
+    PerVcpu(Vec<String>),
+}
+
+impl Profile {
+    /// Parse a profile from TOML text.
+    pub fn from_toml(text: &str, path: &Path) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Load the profile selected on the command line.
+    ///
+    /// With `Some(name)` the profile must exist. With `None` the `default`
+    /// profile is used if present, and an empty profile otherwise.
+    pub fn load(name: Option<&str>) -> Result<Self, ConfigError> {
+        let path = match name {
+            Some(name) => profile_path(name)?,
+            None => match profile_path(DEFAULT_PROFILE) {
+                Ok(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let text = std::fs::read_to_string(&path).map_err(|source| {
+            if source.kind() == std::io::ErrorKind::NotFound {
+                ConfigError::ProfileNotFound {
+                    name: name.unwrap_or(DEFAULT_PROFILE).to_string(),
+                    path: path.clone(),
+                }
+            } else {
+                ConfigError::Read {
+                    path: path.clone(),
+                    source,
+                }
+            }
+        })?;
+        audit::record(audit::Kind::File, "read", &path.display().to_string());
+        Self::from_toml(&text, &path)
+    }
+}
+
+/// Directory holding named profiles.
+pub fn profile_dir() -> Result<PathBuf, ConfigError> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .ok_or(ConfigError::NoConfigDir)?;
+    Ok(config_home.join("carbon").join("profiles"))
+}
+
+/// Directory for caches Carbon can rebuild at will (e.g. disk boot profiles):
+/// `$XDG_CACHE_HOME/carbon`, falling back to `~/.cache/carbon`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("carbon"))
+}
+
+/// Resolve a `--profile` value to a file path.
+fn profile_path(name: &str) -> Result<PathBuf, ConfigError> {
+    if name.contains('/') || name.ends_with(".toml") {
+        return Ok(PathBuf::from(name));
+    }
+    Ok(profile_dir()?.join(format!("{name}.toml")))
+}
+
+/// Accept `memory = "2G"` as well as `memory = 512` (MiB).
+pub(crate) fn deserialize_memory<'de, D>(deserializer: D) -> Result<Option<ByteSize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_size(deserializer, size::parse_memory)
+}
+
+/// Accept `rootfs_overlay_size = "1G"` as well as `rootfs_overlay_size = 512` (MiB).
+fn deserialize_disk_size<'de, D>(deserializer: D) -> Result<Option<ByteSize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_size(deserializer, size::parse_disk)
+}
+
+/// Accept `disk = "dev.img"` as well as `disk = ["a.img", "b.img"]`.
+fn deserialize_one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(Some(match Raw::deserialize(deserializer)? {
+        Raw::One(s) => vec![s],
+        Raw::Many(v) => v,
+    }))
+}
+
+fn deserialize_size<'de, D>(
+    deserializer: D,
+    parse: fn(&str) -> Result<ByteSize, size::SizeError>,
+) -> Result<Option<ByteSize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Number(u64),
+        Text(String),
+    }
+
+    let text = match Raw::deserialize(deserializer)? {
+        Raw::Number(n) => n.to_string(),
+        Raw::Text(s) => s,
+    };
+    parse(&text).map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Profile, ConfigError> {
+        Profile::from_toml(text, Path::new("test.toml"))
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        let profile = parse(
+            r#"
+            kernel = "bin/vmlinuz"
+            cmdline = "console=ttyS0 quiet"
+            memory = "2G"
+            disk = "dev.img"
+            cpu = "baseline"
+            cpus = 4
+            topology = "threads=2"
+            no_kaslr = true
+            boot_profile = "compat"
+            memory_backend = "file=/dev/shm/vm0,shared=on"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(profile.kernel.as_deref(), Some("bin/vmlinuz"));
+        assert_eq!(profile.memory, Some(ByteSize(2 * size::GIB)));
+        assert_eq!(profile.disk, Some(vec!["dev.img".to_string()]));
+        assert_eq!(profile.initrd, None);
+        assert_eq!(profile.cpu.as_deref(), Some("baseline"));
+        assert_eq!(profile.cpus, Some(4));
+        assert_eq!(profile.topology.as_deref(), Some("threads=2"));
+        assert_eq!(profile.no_kaslr, Some(true));
+        assert_eq!(
+            profile.memory_backend.as_deref(),
+            Some("file=/dev/shm/vm0,shared=on")
+        );
+        assert_eq!(profile.boot_profile.as_deref(), Some("compat"));
+        assert_eq!(profile.no_auto_cmdline, None);
+    }
+
+    #[test]
+    fn test_disk_list() {
+        let profile = parse(r#"disk = ["a.img", "path=b.img,ro"]"#).unwrap();
+        assert_eq!(
+            profile.disk,
+            Some(vec!["a.img".to_string(), "path=b.img,ro".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_cpu_affinity() {
+        let profile = parse(r#"cpu_affinity = "2,3""#).unwrap();
+        assert_eq!(profile.cpu_affinity, Some(CpuAffinity::Cores("2,3".into())));
+        let profile = parse(r#"cpu_affinity = ["2", "3-4"]"#).unwrap();
+        assert_eq!(
+            profile.cpu_affinity,
+            Some(CpuAffinity::PerVcpu(vec!["2".into(), "3-4".into()]))
+        );
+    }
+
+    #[test]
+    fn test_memory_number_is_mib() {
+        let profile = parse("memory = 256").unwrap();
+        assert_eq!(profile.memory, Some(ByteSize(256 * size::MIB)));
+    }
+
+    #[test]
+    fn test_debug_exit_port() {
+        let profile = parse("debug_exit = 0xf4").unwrap();
+        assert_eq!(profile.debug_exit, Some(0xf4));
+        assert!(parse("debug_exit = 0x10000").is_err());
+    }
+
+    #[test]
+    fn test_exit_on_halt() {
+        assert_eq!(
+            parse("exit_on_halt = true").unwrap().exit_on_halt,
+            Some(true)
+        );
+        assert!(parse("exit_on_halt = \"yes\"").is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_profiles() {
+        assert!(parse("memory = \"lots\"").is_err());
+        assert!(parse("kernal = \"typo\"").is_err());
+    }
+
+    #[test]
+    fn test_profile_path() {
+        assert_eq!(
+            profile_path("./dev.toml").unwrap(),
+            PathBuf::from("./dev.toml")
+        );
+        assert!(profile_path("dev")
+            .unwrap()
+            .ends_with("carbon/profiles/dev.toml"));
+    }
+}
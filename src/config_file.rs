@@ -0,0 +1,613 @@
+//! Declarative VM configuration files (`--config vm.json`).
+//!
+//! A config file describes a whole VM in one place, in JSON or TOML (by its
+//! extension; anything else is JSON if it starts with `{`):
+//!
+//! ```json
+//! {
+//!   "machine": { "cpus": 2, "memory": "1G", "cpu": "portable" },
+//!   "boot": {
+//!     "kernel": "/srv/carbon/vmlinuz",
+//!     "cmdline": "console=ttyS0 quiet",
+//!     "initrd": "/srv/carbon/initrd.img"
+//!   },
+//!   "drives": [
+//!     { "path": "/srv/carbon/root.img", "read_only": true },
+//!     { "path": "work.img", "cache": "none", "iops": 2000 },
+//!     "path=data.img,logical-block-size=4K"
+//!   ],
+//!   "vsock": { "cid": 3, "uds": "/run/carbon/vm0.sock" },
+//!   "console": { "serial": "file=console.log" },
+//!   "logging": { "level": "debug", "audit_log": "/var/log/carbon.audit" }
+//! }
+//! ```
+//!
+//! | Section    | Keys                                                       |
+//! | ---------- | ---------------------------------------------------------- |
+//! | `machine`  | `cpus`, `topology`, `memory`, `memory_backend`, `cpu`,     |
+//! |            | `cpu_features`, `cpu_affinity`, `hyperv`, `nested`,        |
+//! |            | `prefault`, `mlock`, `swappable`                           |
+//! | `boot`     | `kernel`, `kernel_sha256`, `cmdline`, `initrd`, `profile`, |
+//! |            | `kaslr`                                                    |
+//! | `drives`   | a list of `--disk` specs, or tables of `path` (or          |
+//! |            | `vhost_user`), `read_only`, `direct`, `cache`,             |
+//! |            | `exclusive`, `logical_block_size`, `physical_block_size`,  |
+//! |            | `sha256`, `key_file`, `key_command`, `iops`, `bw`,         |
+//! |            | `iops_burst`, `bw_burst`, `transport`                      |
+//! | `vsock`    | `cid`, `uds`, `transport`                                  |
+//! | `console`  | `serial`, `serial2` (as `--serial`)                        |
+//! | `logging`  | `level` (as `--log-level`), `audit_log`                    |
+//!
+//! Values take the forms of the flags of the same names. Every section and
+//! key is optional, and anything else is an error, as is a value the flag
+//! would refuse: the whole file is checked before the VM is built, and
+//! errors name the key at fault (`machine.cpu: ...`, `drives[1]: ...`).
+//!
+//! The file takes the place of a profile (see [`crate::config`]), so
+//! `--config` and `--profile` don't mix, and flags and `CARBON_*` variables
+//! override what the file sets. Carbon has no network devices: a `network`
+//! section is refused, and vsock is the way to reach the guest.
+
+use crate::audit;
+use crate::boot::MemoryBacking;
+use crate::config::{self, CpuAffinity, Profile};
+use crate::devices::{ConsoleConfig, VsockConfig};
+use crate::kvm::{self, CpuFeatures, CpuMode, Topology};
+use crate::logging::Level;
+use crate::size::ByteSize;
+use crate::vmm::{BootProfile, DiskConfig};
+use clap::ValueEnum;
+use serde::de::{self, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Why a config file couldn't be loaded.
+#[derive(Error, Debug)]
+pub enum ConfigFileError {
+    #[error(transparent)]
+    Read(#[from] io::Error),
+
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("{0}")]
+    Toml(Box<toml::de::Error>),
+
+    /// A value the corresponding flag would refuse, or keys that conflict.
+    #[error("{key}: {message}")]
+    Invalid { key: String, message: String },
+}
+
+fn invalid(key: impl Into<String>, message: impl fmt::Display) -> ConfigFileError {
+    ConfigFileError::Invalid {
+        key: key.into(),
+        message: message.to_string(),
+    }
+}
+
+/// A VM config file (see the module docs).
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub machine: Machine,
+    #[serde(default)]
+    pub boot: Boot,
+    #[serde(default)]
+    pub drives: Vec<Drive>,
+    /// Refused: Carbon has no network devices.
+    #[serde(default)]
+    network: Option<IgnoredAny>,
+    pub vsock: Option<Vsock>,
+    #[serde(default)]
+    pub console: Console,
+    #[serde(default)]
+    pub logging: Logging,
+}
+
+/// The `machine` section: vCPUs and memory.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Machine {
+    pub cpus: Option<u32>,
+    pub topology: Option<String>,
+    #[serde(default, deserialize_with = "config::deserialize_memory")]
+    pub memory: Option<ByteSize>,
+    pub memory_backend: Option<String>,
+    pub cpu: Option<String>,
+    pub cpu_features: Option<String>,
+    pub cpu_affinity: Option<CpuAffinity>,
+    pub hyperv: Option<bool>,
+    pub nested: Option<bool>,
+    pub prefault: Option<bool>,
+    pub mlock: Option<bool>,
+    pub swappable: Option<bool>,
+}
+
+/// The `boot` section: what the VM boots.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Boot {
+    pub kernel: Option<String>,
+    pub kernel_sha256: Option<String>,
+    pub cmdline: Option<String>,
+    pub initrd: Option<String>,
+    /// `--boot-profile`.
+    pub profile: Option<String>,
+    /// False for `--no-kaslr`.
+    pub kaslr: Option<bool>,
+}
+
+/// One of `drives`: a `--disk` spec, or a table of its options.
+#[derive(Debug)]
+pub enum Drive {
+    Spec(String),
+    Table(Box<DriveTable>),
+}
+
+/// A drive as a table; the keys are the `--disk` options, with `_` for
+/// `-`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriveTable {
+    pub path: Option<String>,
+    /// Socket of a vhost-user-blk backend, instead of `path`.
+    pub vhost_user: Option<String>,
+    #[serde(default)]
+    pub read_only: bool,
+    pub direct: Option<bool>,
+    pub cache: Option<String>,
+    pub exclusive: Option<bool>,
+    pub logical_block_size: Option<Size>,
+    pub physical_block_size: Option<Size>,
+    pub sha256: Option<String>,
+    pub key_file: Option<String>,
+    pub key_command: Option<String>,
+    pub iops: Option<u64>,
+    pub bw: Option<Size>,
+    pub iops_burst: Option<u64>,
+    pub bw_burst: Option<Size>,
+    pub transport: Option<String>,
+}
+
+/// A size as a number of bytes, or a string with a unit (`"4K"`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Size::Bytes(bytes) => write!(f, "{bytes}"),
+            Size::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+/// The `vsock` section.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vsock {
+    pub cid: u64,
+    pub uds: PathBuf,
+    pub transport: Option<String>,
+}
+
+/// The `console` section: the serial ports' backends.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Console {
+    pub serial: Option<String>,
+    pub serial2: Option<String>,
+}
+
+/// The `logging` section.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Logging {
+    pub level: Option<String>,
+    pub audit_log: Option<PathBuf>,
+}
+
+impl<'de> Deserialize<'de> for Drive {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DriveVisitor;
+
+        impl<'de> Visitor<'de> for DriveVisitor {
+            type Value = Drive;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a --disk spec or a table of drive options")
+            }
+
+            fn visit_str<E: de::Error>(self, spec: &str) -> Result<Drive, E> {
+                Ok(Drive::Spec(spec.to_string()))
+            }
+
+            // Through the table's own deserializer, for its errors
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Drive, A::Error> {
+                DriveTable::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(|table| Drive::Table(Box::new(table)))
+            }
+        }
+
+        deserializer.deserialize_any(DriveVisitor)
+    }
+}
+
+impl ConfigFile {
+    /// Read, parse and check the file at `path`.
+    pub fn load(path: &Path) -> Result<Self, ConfigFileError> {
+        let text = std::fs::read_to_string(path)?;
+        audit::record(audit::Kind::File, "read", &path.display().to_string());
+        let json = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => true,
+            Some("toml") => false,
+            _ => text.trim_start().starts_with('{'),
+        };
+        let file = if json {
+            Self::from_json(&text)?
+        } else {
+            Self::from_toml(&text)?
+        };
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn from_json(text: &str) -> Result<Self, ConfigFileError> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    fn from_toml(text: &str) -> Result<Self, ConfigFileError> {
+        toml::from_str(text).map_err(|e| ConfigFileError::Toml(Box::new(e)))
+    }
+
+    /// Check every value as its flag would.
+    fn validate(&self) -> Result<(), ConfigFileError> {
+        if self.network.is_some() {
+            return Err(invalid(
+                "network",
+                "Carbon has no network devices; reach the guest over vsock",
+            ));
+        }
+
+        let machine = &self.machine;
+        let topology = Topology::resolve(machine.cpus, machine.topology.as_deref())
+            .map_err(|e| invalid("machine", e))?;
+        if let Some(backend) = &machine.memory_backend {
+            backend
+                .parse::<MemoryBacking>()
+                .map_err(|e| invalid("machine.memory_backend", e))?;
+        }
+        if let Some(cpu) = &machine.cpu {
+            CpuMode::from_str(cpu, true).map_err(|_| {
+                invalid(
+                    "machine.cpu",
+                    format!("invalid {cpu:?} (host, portable, baseline or x86-64-v3)"),
+                )
+            })?;
+        }
+        if let Some(features) = &machine.cpu_features {
+            features
+                .parse::<CpuFeatures>()
+                .map_err(|e| invalid("machine.cpu_features", e))?;
+        }
+        match &machine.cpu_affinity {
+            Some(CpuAffinity::Cores(cores)) => {
+                kvm::CpuAffinity::resolve(topology.cpus(), cores).map(drop)
+            }
+            Some(CpuAffinity::PerVcpu(cores)) => {
+                kvm::CpuAffinity::per_vcpu(topology.cpus(), cores).map(drop)
+            }
+            None => Ok(()),
+        }
+        .map_err(|e| invalid("machine.cpu_affinity", e))?;
+        if machine.mlock == Some(true) && machine.swappable == Some(true) {
+            return Err(invalid("machine", "mlock and swappable don't mix"));
+        }
+
+        if let Some(digest) = &self.boot.kernel_sha256 {
+            digest
+                .parse::<crate::digest::Sha256Digest>()
+                .map_err(|e| invalid("boot.kernel_sha256", e))?;
+        }
+        if let Some(profile) = &self.boot.profile {
+            BootProfile::from_str(profile, true).map_err(|_| {
+                invalid(
+                    "boot.profile",
+                    format!("invalid {profile:?} (fast, compat or custom)"),
+                )
+            })?;
+        }
+
+        for (index, spec) in self.disk_specs()?.iter().enumerate() {
+            spec.parse::<DiskConfig>()
+                .map_err(|e| invalid(format!("drives[{index}]"), e))?;
+        }
+        if let Some(spec) = self.vsock_spec() {
+            spec.parse::<VsockConfig>()
+                .map_err(|e| invalid("vsock", e))?;
+        }
+        for (key, spec) in [
+            ("console.serial", &self.console.serial),
+            ("console.serial2", &self.console.serial2),
+        ] {
+            if let Some(spec) = spec {
+                spec.parse::<ConsoleConfig>().map_err(|e| invalid(key, e))?;
+            }
+        }
+
+        if let Some(level) = &self.logging.level {
+            Level::from_str(level, true).map_err(|_| {
+                invalid(
+                    "logging.level",
+                    format!("invalid {level:?} (error, warn, info, debug or trace)"),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The log level the file asks for, if any.
+    pub fn log_level(&self) -> Option<Level> {
+        // Checked by `validate`
+        let level = self.logging.level.as_deref()?;
+        Level::from_str(level, true).ok()
+    }
+
+    /// `--disk` specs for the drives, in order.
+    fn disk_specs(&self) -> Result<Vec<String>, ConfigFileError> {
+        self.drives
+            .iter()
+            .enumerate()
+            .map(|(index, drive)| match drive {
+                Drive::Spec(spec) => Ok(spec.clone()),
+                Drive::Table(table) => table
+                    .spec()
+                    .map_err(|e| invalid(format!("drives[{index}]"), e)),
+            })
+            .collect()
+    }
+
+    /// The `--vsock` spec for the vsock section.
+    fn vsock_spec(&self) -> Option<String> {
+        let vsock = self.vsock.as_ref()?;
+        let mut spec = format!("cid={},uds={}", vsock.cid, vsock.uds.display());
+        if let Some(transport) = &vsock.transport {
+            spec += &format!(",transport={transport}");
+        }
+        Some(spec)
+    }
+
+    /// The file as a profile, which the command line overrides.
+    pub fn to_profile(&self) -> Result<Profile, ConfigFileError> {
+        let machine = &self.machine;
+        let boot = &self.boot;
+        let disk = self.disk_specs()?;
+        Ok(Profile {
+            kernel: boot.kernel.clone(),
+            kernel_sha256: boot.kernel_sha256.clone(),
+            cmdline: boot.cmdline.clone(),
+            initrd: boot.initrd.clone(),
+            boot_profile: boot.profile.clone(),
+            no_kaslr: boot.kaslr.map(|kaslr| !kaslr),
+            memory: machine.memory,
+            memory_backend: machine.memory_backend.clone(),
+            prefault: machine.prefault,
+            mlock: machine.mlock,
+            swappable: machine.swappable,
+            cpu: machine.cpu.clone(),
+            cpu_features: machine.cpu_features.clone(),
+            cpus: machine.cpus,
+            topology: machine.topology.clone(),
+            cpu_affinity: machine.cpu_affinity.clone(),
+            hyperv: machine.hyperv,
+            enable_nested: machine.nested,
+            disk: (!disk.is_empty()).then_some(disk),
+            vsock: self.vsock_spec(),
+            serial: self.console.serial.clone(),
+            serial2: self.console.serial2.clone(),
+            ..Profile::default()
+        })
+    }
+}
+
+impl DriveTable {
+    /// The `--disk` spec with these options.
+    fn spec(&self) -> Result<String, String> {
+        let mut spec = match (&self.path, &self.vhost_user) {
+            (Some(path), None) => format!("path={path}"),
+            (None, Some(socket)) => format!("vhost-user={socket}"),
+            (Some(_), Some(_)) => return Err("path and vhost_user don't mix".into()),
+            (None, None) => return Err("needs a path (or vhost_user)".into()),
+        };
+        if spec.contains(',') {
+            return Err("the path can't contain `,`".into());
+        }
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let mut options = Vec::new();
+        if self.read_only {
+            options.push("ro".to_string());
+        }
+        let flags = [("direct", self.direct), ("exclusive", self.exclusive)];
+        for (key, value) in flags {
+            if let Some(value) = value {
+                options.push(format!("{key}={}", on_off(value)));
+            }
+        }
+        let counts = [("iops", self.iops), ("iops-burst", self.iops_burst)];
+        for (key, value) in counts {
+            if let Some(value) = value {
+                options.push(format!("{key}={value}"));
+            }
+        }
+        let sizes = [
+            ("logical-block-size", &self.logical_block_size),
+            ("physical-block-size", &self.physical_block_size),
+            ("bw", &self.bw),
+            ("bw-burst", &self.bw_burst),
+        ];
+        for (key, value) in sizes {
+            if let Some(value) = value {
+                options.push(format!("{key}={value}"));
+            }
+        }
+        let text = [
+            ("cache", &self.cache),
+            ("sha256", &self.sha256),
+            ("key-file", &self.key_file),
+            ("key-command", &self.key_command),
+            ("transport", &self.transport),
+        ];
+        for (key, value) in text {
+            if let Some(value) = value {
+                options.push(format!("{key}={value}"));
+            }
+        }
+        for option in options {
+            spec += ",";
+            spec += &option;
+        }
+        Ok(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(text: &str) -> Result<ConfigFile, ConfigFileError> {
+        let file = ConfigFile::from_json(text)?;
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn toml(text: &str) -> Result<ConfigFile, ConfigFileError> {
+        let file = ConfigFile::from_toml(text)?;
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn error(result: Result<ConfigFile, ConfigFileError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_json() {
+        let file = json(
+            r#"{
+                "machine": { "cpus": 2, "memory": "1G", "cpu": "portable" },
+                "boot": { "kernel": "vmlinuz", "cmdline": "quiet", "kaslr": false },
+                "drives": [
+                    { "path": "root.img", "read_only": true },
+                    { "path": "work.img", "cache": "none", "iops": 2000, "bw": "10M",
+                      "logical_block_size": 4096 },
+                    "path=data.img,physical-block-size=4K"
+                ],
+                "vsock": { "cid": 3, "uds": "/tmp/v.sock" },
+                "console": { "serial": "pty" },
+                "logging": { "level": "debug", "audit_log": "audit.log" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(file.log_level(), Some(Level::Debug));
+        assert_eq!(file.logging.audit_log, Some(PathBuf::from("audit.log")));
+
+        let profile = file.to_profile().unwrap();
+        assert_eq!(profile.kernel.as_deref(), Some("vmlinuz"));
+        assert_eq!(profile.cmdline.as_deref(), Some("quiet"));
+        assert_eq!(profile.no_kaslr, Some(true));
+        assert_eq!(profile.cpus, Some(2));
+        assert_eq!(profile.memory, Some(ByteSize(1 << 30)));
+        assert_eq!(profile.cpu.as_deref(), Some("portable"));
+        assert_eq!(
+            profile.disk.unwrap(),
+            [
+                "path=root.img,ro",
+                "path=work.img,iops=2000,logical-block-size=4096,bw=10M,cache=none",
+                "path=data.img,physical-block-size=4K",
+            ]
+        );
+        assert_eq!(profile.vsock.as_deref(), Some("cid=3,uds=/tmp/v.sock"));
+        assert_eq!(profile.serial.as_deref(), Some("pty"));
+    }
+
+    #[test]
+    fn test_toml() {
+        let file = toml(
+            r#"
+            [machine]
+            cpus = 4
+            memory = 512
+
+            [boot]
+            kernel = "vmlinuz"
+
+            [[drives]]
+            vhost_user = "/run/spdk.sock"
+            "#,
+        )
+        .unwrap();
+        let profile = file.to_profile().unwrap();
+        assert_eq!(profile.memory, Some(ByteSize(512 << 20)));
+        assert_eq!(profile.disk.unwrap(), ["vhost-user=/run/spdk.sock"]);
+        assert_eq!(profile.vsock, None);
+        assert_eq!(file.log_level(), None);
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let err = error(json(r#"{ "machine": { "memroy": "1G" } }"#));
+        assert!(err.contains("unknown field `memroy`"), "{err}");
+        assert!(err.contains("line 1"), "{err}");
+        let err = error(toml("[machine]\ncpus = \"two\""));
+        assert!(err.contains("line 2"), "{err}");
+
+        let err = error(json(r#"{ "machine": { "cpu": "fastest" } }"#));
+        assert!(err.starts_with("machine.cpu: "), "{err}");
+        let err = error(json(
+            r#"{ "drives": [ "root.img", { "path": "a.img", "cache": "lots" } ] }"#,
+        ));
+        assert!(err.starts_with("drives[1]: "), "{err}");
+        let err = error(json(r#"{ "drives": [ { "read_only": true } ] }"#));
+        assert!(err.contains("needs a path"), "{err}");
+        let err = error(json(r#"{ "drives": [ { "path": "a.img", "ro": true } ] }"#));
+        assert!(err.contains("unknown field `ro`"), "{err}");
+        let err = error(json(r#"{ "vsock": { "cid": 2, "uds": "v.sock" } }"#));
+        assert!(err.starts_with("vsock: "), "{err}");
+        let err = error(json(r#"{ "logging": { "level": "loud" } }"#));
+        assert!(err.starts_with("logging.level: "), "{err}");
+        let err = error(json(r#"{ "machine": { "cpus": 0 } }"#));
+        assert!(err.starts_with("machine: "), "{err}");
+        let err = error(json(r#"{ "network": [ { "tap": "tap0" } ] }"#));
+        assert!(err.contains("vsock"), "{err}");
+    }
+
+    #[test]
+    fn test_load_by_extension() {
+        let dir = std::env::temp_dir().join(format!("carbon-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (json_path, toml_path) = (dir.join("vm.json"), dir.join("vm.conf"));
+        std::fs::write(&json_path, r#"{ "boot": { "kernel": "a" } }"#).unwrap();
+        std::fs::write(&toml_path, "[boot]\nkernel = \"b\"\n").unwrap();
+        assert_eq!(
+            ConfigFile::load(&json_path).unwrap().boot.kernel.as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            ConfigFile::load(&toml_path).unwrap().boot.kernel.as_deref(),
+            Some("b")
+        );
+        assert!(matches!(
+            ConfigFile::load(&dir.join("missing.json")),
+            Err(ConfigFileError::Read(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,505 @@
+//! Runtime control socket (`--control-socket PATH`).
+//!
+//! Carbon listens on a Unix socket for commands that change the running VM.
+//! Unlike `--console-socket` it doesn't wait for a client before booting,
+//! and clients may come and go, several at once; the VMM's event loop (see
+//! [`crate::event_loop`]) answers each command as it arrives. The protocol
+//! is plain text: each command is one line, answered by one line, `ok`
+//! (followed by the result, for commands that have one) or
+//! `error: MESSAGE`.
+//!
+//! | Command                         | Effect                                  |
+//! | ------------------------------- | --------------------------------------- |
+//! | `disk-rate-limit N [OPTION...]` | Replace the rate limits of disk N       |
+//! | `disk-plug DISK`                | Attach a disk; answers `ok N MMIO_BASE` |
+//! | `disk-unplug N`                 | Detach hot-plugged disk N               |
+//! | `vcpu-stats N`                  | Answer vCPU N's statistics              |
+//! | `dump-core PATH`                | Write the guest's memory to an ELF core |
+//! | `snapshot STATE MEMORY`         | Write a snapshot of the VM              |
+//! | `pause`                         | Stop the vCPUs until `resume`           |
+//! | `resume`                        | Let a paused VM run on                  |
+//! | `status`                        | Answer `ok running` or `ok paused`      |
+//! | `memory-stats`                  | Answer guest memory accounting          |
+//! | `page-out`                      | Push guest memory out to swap           |
+//!
+//! Disks are numbered in attach order from 0 (`/dev/vda`), and hot-plug
+//! slots (`--hotplug-disks`) after the boot disks. The `disk-rate-limit`
+//! options are those of `--disk`: `iops=N`, `iops-burst=N`, `bw=SIZE` and
+//! `bw-burst=SIZE`; with none, the disk is no longer limited. The buckets
+//! start full under the new limits.
+//!
+//! `disk-plug` takes an image as `--disk` does (`path=IMAGE,ro`, ...), but
+//! not `fd=`, `key-fd=`, `vhost-user=` or `transport=pci`. See
+//! [`crate::devices::virtio::hotplug`] for how the guest finds the disk and
+//! what unplugging a disk in use does.
+//!
+//! `vcpu-stats` answers with the vCPU's exits and time so far, as
+//! `ok exits=N io=N ... guest_ms=N host_ms=N kvm.NAME=N ...` (see
+//! [`crate::kvm::StatsReader`]).
+//!
+//! `dump-core` stops every vCPU for as long as the dump takes, then lets
+//! the guest run on; the answer comes once the file is written. See
+//! [`crate::coredump`] for what the file holds.
+//!
+//! `snapshot` stops the guest the same way, writing its state to STATE and
+//! its RAM to MEMORY, for `carbon restore` to carry on from later
+//! (`carbon snapshot` sends it from the command line); see
+//! [`crate::snapshot`] for which VMs can be snapshotted.
+//!
+//! `pause` answers once every vCPU has left the guest. A paused VM's
+//! devices still serve what the guest queued before, and its clock runs
+//! on. `dump-core` and `snapshot` work while paused, capturing the VM as
+//! it stopped.
+//!
+//! `memory-stats` answers `ok size=N resident=N locked=N`, in bytes:
+//! guest RAM, how much of it the host holds in memory now, and how much is
+//! locked there (all of it with `--mlock`). `page-out` needs `--swappable`;
+//! it asks the host to reclaim all guest RAM, for a VM about to sit idle,
+//! and answers with `memory-stats` after.
+
+use crate::boot::{GuestMemory, SwapPolicy};
+use crate::cleanup::{self, CleanupGuard};
+use crate::devices::virtio::crypt::KeySource;
+use crate::devices::virtio::hotplug::DiskHotplug;
+use crate::devices::virtio::rate_limiter::{RateLimit, RateLimiter};
+use crate::devices::Transport;
+use crate::kvm::StatsReader;
+use crate::vmm::{Capture, DiskConfig};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What the control socket can reach in the running VM.
+#[derive(Default)]
+pub struct Controls {
+    /// Each disk's rate limiter, in attach order; `None` for disks served
+    /// by a vhost-user backend.
+    pub disks: Vec<Option<Arc<RateLimiter>>>,
+    /// The hot-plug slots, if any were reserved.
+    pub hotplug: Option<DiskHotplug>,
+    /// Each vCPU's statistics, by index.
+    pub vcpus: Vec<StatsReader>,
+    /// Writes core dumps and snapshots of the guest.
+    pub capture: Option<Arc<Capture>>,
+    /// Guest RAM, for accounting and paging out.
+    pub memory: Option<Arc<GuestMemory>>,
+    /// Whether guest RAM is locked or may be paged out.
+    pub swap_policy: SwapPolicy,
+}
+
+/// What a control socket answers commands against.
+pub trait Commands {
+    /// Run one command line, returning its result if it has one.
+    fn execute(&mut self, line: &str) -> Result<Option<String>, String>;
+}
+
+impl Commands for Controls {
+    fn execute(&mut self, line: &str) -> Result<Option<String>, String> {
+        Controls::execute(self, line)
+    }
+}
+
+impl Controls {
+    /// Run one command line, returning its result if it has one.
+    fn execute(&self, line: &str) -> Result<Option<String>, String> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("disk-rate-limit") => {
+                let index = words.next().ok_or("disk-rate-limit needs a disk number")?;
+                let limiter = self.rate_limiter(index)?;
+                let mut limit = RateLimit::default();
+                for option in words {
+                    let (key, value) = option
+                        .split_once('=')
+                        .ok_or_else(|| format!("invalid option {option:?}"))?;
+                    limit.set(key, value)?;
+                }
+                limit.validate()?;
+                limiter.set(limit);
+                info!("[VMM] Disk {} rate limit: {}", index, limit);
+                Ok(None)
+            }
+            Some("disk-plug") => {
+                let hotplug = self.hotplug()?;
+                let disk: DiskConfig = words.next().ok_or("disk-plug needs a disk")?.parse()?;
+                if disk.vhost_user || disk.fd.is_some() || disk.transport != Transport::Mmio {
+                    return Err("only images opened by path can be plugged, over MMIO".into());
+                }
+                if matches!(disk.options.key, Some(KeySource::Fd(_))) {
+                    return Err("a plugged disk's key can't come from a descriptor".into());
+                }
+                let (number, mmio_base) = hotplug.plug(&disk.path, disk.options)?;
+                Ok(Some(format!("{number} {mmio_base:#x}")))
+            }
+            Some("disk-unplug") => {
+                let index = words.next().ok_or("disk-unplug needs a disk number")?;
+                let number = index
+                    .parse::<usize>()
+                    .map_err(|_| format!("no disk {index}"))?;
+                if number < self.disks.len() {
+                    return Err(format!("disk {number} was attached at boot"));
+                }
+                self.hotplug()?.unplug(number)?;
+                Ok(None)
+            }
+            Some("vcpu-stats") => {
+                let index = words.next().ok_or("vcpu-stats needs a vCPU number")?;
+                let reader = index
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|number| self.vcpus.get(number))
+                    .ok_or_else(|| format!("no vCPU {index}"))?;
+                Ok(Some(reader.stats().to_string()))
+            }
+            Some("dump-core") => {
+                let path = words.next().ok_or("dump-core needs a path")?;
+                let capture = self.capture.as_ref().ok_or("no VM to dump")?;
+                capture
+                    .dump(Path::new(path))
+                    .map_err(|e| format!("failed to dump core to {path}: {e}"))?;
+                info!("[VMM] Guest core dumped to {}", path);
+                Ok(None)
+            }
+            Some("snapshot") => {
+                let (Some(state), Some(memory)) = (words.next(), words.next()) else {
+                    return Err("snapshot needs a state file and a memory file".into());
+                };
+                let capture = self.capture.as_ref().ok_or("no VM to snapshot")?;
+                capture
+                    .snapshot(Path::new(state), Path::new(memory))
+                    .map_err(|e| format!("failed to snapshot to {state}: {e}"))?;
+                info!("[VMM] Snapshot written to {} and {}", state, memory);
+                Ok(None)
+            }
+            Some("pause") => {
+                let capture = self.capture.as_ref().ok_or("no VM to pause")?;
+                capture
+                    .pause()
+                    .map_err(|e| format!("failed to pause: {e}"))?;
+                info!("[VMM] Paused");
+                Ok(None)
+            }
+            Some("resume") => {
+                let capture = self.capture.as_ref().ok_or("no VM to resume")?;
+                capture
+                    .resume()
+                    .map_err(|e| format!("failed to resume: {e}"))?;
+                info!("[VMM] Resumed");
+                Ok(None)
+            }
+            Some("status") => {
+                let capture = self.capture.as_ref().ok_or("no VM")?;
+                let status = if capture.is_paused() {
+                    "paused"
+                } else {
+                    "running"
+                };
+                Ok(Some(status.into()))
+            }
+            Some("memory-stats") => self.memory_stats().map(Some),
+            Some("page-out") => {
+                if self.swap_policy != SwapPolicy::Swappable {
+                    return Err("guest memory isn't swappable (see --swappable)".into());
+                }
+                let memory = self.memory()?;
+                memory
+                    .page_out()
+                    .map_err(|e| format!("failed to page out guest memory: {e}"))?;
+                let stats = self.memory_stats()?;
+                info!("[VMM] Paged out guest memory: {}", stats);
+                Ok(Some(stats))
+            }
+            Some(command) => Err(format!("unknown command {command:?}")),
+            None => Err("empty command".into()),
+        }
+    }
+
+    /// The rate limiter of disk `index`, boot or hot-plugged.
+    fn rate_limiter(&self, index: &str) -> Result<Arc<RateLimiter>, String> {
+        let no_disk = || format!("no disk {index}");
+        let number = index.parse::<usize>().map_err(|_| no_disk())?;
+        match self.disks.get(number) {
+            Some(Some(limiter)) => Ok(limiter.clone()),
+            Some(None) => Err(format!("disk {index} is served by a vhost-user backend")),
+            None => self
+                .hotplug
+                .as_ref()
+                .and_then(|hotplug| hotplug.rate_limiter(number))
+                .ok_or_else(no_disk),
+        }
+    }
+
+    /// `size=N resident=N locked=N`, in bytes.
+    fn memory_stats(&self) -> Result<String, String> {
+        let memory = self.memory()?;
+        let resident = memory
+            .resident()
+            .map_err(|e| format!("failed to read guest memory residency: {e}"))?;
+        let locked = match self.swap_policy {
+            SwapPolicy::Locked => memory.size(),
+            _ => 0,
+        };
+        Ok(format!(
+            "size={} resident={} locked={}",
+            memory.size(),
+            resident,
+            locked
+        ))
+    }
+
+    fn memory(&self) -> Result<&GuestMemory, String> {
+        self.memory
+            .as_deref()
+            .ok_or_else(|| "no guest memory".into())
+    }
+
+    fn hotplug(&self) -> Result<&DiskHotplug, String> {
+        self.hotplug
+            .as_ref()
+            .ok_or_else(|| "no hot-plug slots (see --hotplug-disks)".into())
+    }
+}
+
+/// Longest command line accepted; a client sending a longer one is
+/// disconnected.
+const MAX_COMMAND: usize = 4096;
+
+/// The control socket and its connected clients.
+pub struct ControlSocket<C = Controls> {
+    listener: UnixListener,
+    commands: C,
+    clients: Vec<Client>,
+}
+
+/// A connected client and what it has sent of its next command.
+struct Client {
+    stream: UnixStream,
+    pending: Vec<u8>,
+}
+
+impl<C: Commands> ControlSocket<C> {
+    /// Listen on `path` for commands against `commands`.
+    ///
+    /// A stale socket file at `path` is replaced (see
+    /// [`cleanup::bind_socket`]). The returned guard removes the socket file
+    /// on exit, including after a panic.
+    pub fn bind(path: &Path, commands: C) -> io::Result<(Self, CleanupGuard)> {
+        let (listener, guard) = cleanup::bind_socket(path, "remove control socket")?;
+        info!("[VMM] Control socket on {}", path.display());
+        Ok((Self::from_listener(listener, commands), guard))
+    }
+
+    /// Serve commands against `commands` on a socket the caller bound, and
+    /// removes.
+    pub fn from_listener(listener: UnixListener, commands: C) -> Self {
+        Self {
+            listener,
+            commands,
+            clients: Vec::new(),
+        }
+    }
+
+    /// Accept a client waiting to connect. Returns its descriptor, to wait
+    /// on for commands and pass to [`ControlSocket::serve`].
+    pub fn accept(&mut self) -> io::Result<RawFd> {
+        let (stream, _) = self.listener.accept()?;
+        let fd = stream.as_raw_fd();
+        self.clients.push(Client {
+            stream,
+            pending: Vec::new(),
+        });
+        Ok(fd)
+    }
+
+    /// Read what client `fd` has sent, without blocking once it has been
+    /// reported readable, and answer each complete command. Returns `false`
+    /// once the client is gone; its descriptor is then closed.
+    pub fn serve(&mut self, fd: RawFd) -> bool {
+        let Some(index) = self
+            .clients
+            .iter()
+            .position(|client| client.stream.as_raw_fd() == fd)
+        else {
+            return false;
+        };
+        match self.clients[index].receive(&mut self.commands) {
+            Ok(true) => true,
+            Ok(false) => {
+                self.clients.remove(index);
+                false
+            }
+            Err(e) => {
+                debug!("[VMM] Control client error: {}", e);
+                self.clients.remove(index);
+                false
+            }
+        }
+    }
+
+    /// What the socket answers commands against.
+    pub fn commands(&mut self) -> &mut C {
+        &mut self.commands
+    }
+}
+
+impl<C> AsRawFd for ControlSocket<C> {
+    /// The listening socket, readable when a client is waiting to connect.
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Client {
+    /// Take one read's worth of input and answer the commands it completes.
+    /// Returns `false` at end of input.
+    fn receive(&mut self, commands: &mut impl Commands) -> io::Result<bool> {
+        let mut buf = [0u8; 1024];
+        let len = match self.stream.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        self.pending.extend_from_slice(&buf[..len]);
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            match commands.execute(line.trim()) {
+                Ok(None) => writeln!(self.stream, "ok")?,
+                Ok(Some(result)) => writeln!(self.stream, "ok {result}")?,
+                Err(e) => writeln!(self.stream, "error: {e}")?,
+            }
+        }
+        if self.pending.len() > MAX_COMMAND {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "command too long",
+            ));
+        }
+        Ok(true)
+    }
+}
+
+/// Send `command` to the control socket at `path`, waiting up to `timeout`
+/// for the answer. Returns the command's result, empty if it has none; an
+/// `error:` answer is an error.
+pub fn send(path: &Path, command: &str, timeout: Duration) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    writeln!(stream, "{command}")?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    let reply = reply.trim_end();
+    match reply.strip_prefix("ok") {
+        Some(result) => Ok(result.trim_start().to_string()),
+        None => Err(io::Error::other(
+            reply.strip_prefix("error: ").unwrap_or(reply).to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_rate_limit() {
+        let limiter = Arc::new(RateLimiter::default());
+        let controls = Controls {
+            disks: vec![Some(limiter.clone()), None],
+            hotplug: None,
+            vcpus: Vec::new(),
+            capture: None,
+            memory: None,
+            swap_policy: SwapPolicy::Default,
+        };
+        controls
+            .execute("disk-rate-limit 0 iops=100 bw=10M")
+            .unwrap();
+        let limit = limiter.limit();
+        assert_eq!(limit.iops.map(|b| b.rate), Some(100));
+        assert_eq!(limit.bandwidth.map(|b| b.rate), Some(10 << 20));
+        controls.execute("disk-rate-limit 0").unwrap();
+        assert!(!limiter.limit().is_limited());
+
+        assert!(controls.execute("disk-rate-limit 1 iops=5").is_err());
+        assert!(controls.execute("disk-rate-limit 2 iops=5").is_err());
+        assert!(controls.execute("disk-rate-limit 0 iops").is_err());
+        assert!(controls.execute("disk-rate-limit 0 bw-burst=1M").is_err());
+        assert!(controls.execute("disk-plug path=base.img").is_err());
+        assert!(controls
+            .execute("disk-plug path=base.img,key-fd=5")
+            .is_err());
+        assert!(controls.execute("disk-unplug 0").is_err());
+        assert!(controls.execute("vcpu-stats 0").is_err());
+        assert!(controls.execute("dump-core").is_err());
+        assert!(controls.execute("dump-core vm.core").is_err());
+        assert!(controls.execute("snapshot vm.json").is_err());
+        assert!(controls.execute("snapshot vm.json vm.mem").is_err());
+        assert!(controls.execute("pause").is_err());
+        assert!(controls.execute("resume").is_err());
+        assert!(controls.execute("status").is_err());
+        assert!(controls.execute("memory-stats").is_err());
+        assert!(controls.execute("page-out").is_err());
+        assert!(controls.execute("reboot").is_err());
+        assert!(controls.execute("").is_err());
+    }
+
+    #[test]
+    fn test_memory_controls() {
+        let memory = Arc::new(GuestMemory::new(1 << 20).unwrap());
+        memory.write_u8(0, 1).unwrap();
+        let mut controls = Controls {
+            memory: Some(memory),
+            ..Controls::default()
+        };
+        let stats = controls.execute("memory-stats").unwrap().unwrap();
+        assert!(stats.starts_with("size=1048576 resident="));
+        assert!(stats.ends_with(" locked=0"));
+        assert!(controls.execute("page-out").is_err());
+
+        controls.swap_policy = SwapPolicy::Swappable;
+        let stats = controls.execute("page-out").unwrap().unwrap();
+        assert!(stats.starts_with("size=1048576 "));
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let path = std::env::temp_dir().join(format!("carbon-control-{}", std::process::id()));
+        let limiter = Arc::new(RateLimiter::default());
+        let controls = Controls {
+            disks: vec![Some(limiter.clone())],
+            hotplug: None,
+            vcpus: Vec::new(),
+            capture: None,
+            memory: None,
+            swap_policy: SwapPolicy::Default,
+        };
+        let (mut socket, _guard) = ControlSocket::bind(&path, controls).unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let client = socket.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut reply = String::new();
+        writeln!(&stream, "disk-rate-limit 0 iops=50").unwrap();
+        assert!(socket.serve(client));
+        reader.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "ok\n");
+        assert_eq!(limiter.limit().iops.map(|b| b.rate), Some(50));
+
+        // A command split across reads is answered once it's whole
+        reply.clear();
+        write!(&stream, "disk-rate-").unwrap();
+        assert!(socket.serve(client));
+        writeln!(&stream, "limit 3").unwrap();
+        assert!(socket.serve(client));
+        reader.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "error: no disk 3\n");
+
+        drop((stream, reader));
+        assert!(!socket.serve(client));
+    }
+}
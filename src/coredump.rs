@@ -0,0 +1,432 @@
+//! Guest memory dumps as ELF core files (`dump-core`, `--dump-core-on-fault`).
+//!
+//! A dump holds guest RAM plus each vCPU's registers, in the layout QEMU's
+//! `dump-guest-memory` writes, so the usual post-mortem tools read it
+//! against the guest's vmlinux: `crash vmlinux carbon.core`, or gdb with
+//! `target core`.
+//!
+//! ```text
+//! ELF header (ET_CORE, EM_X86_64)
+//! PT_NOTE ──► per vCPU: NT_PRSTATUS "CORE" (general registers)
+//!                       "QEMU" QEMUCPUState (segments, descriptor tables, CRs)
+//! PT_LOAD ──► one per RAM region, p_paddr = guest physical address
+//! RAM, page aligned
+//! ```
+//!
+//! `crash` finds the kernel's page tables through CR3 in the QEMU notes;
+//! gdb takes the general registers from NT_PRSTATUS. Pages of RAM that are
+//! all zero are left as holes, so the file is only as large on disk as the
+//! memory the guest has touched.
+
+use crate::audit;
+use crate::boot::{GuestMemory, RamRegion};
+use crate::kvm::VcpuState;
+use kvm_bindings::{kvm_dtable, kvm_segment};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// `KERNEL_GS_BASE`, the GS base `swapgs` switches to.
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 7;
+const NT_PRSTATUS: u32 = 1;
+
+/// Size of x86-64 `struct elf_prstatus`.
+const PRSTATUS_SIZE: usize = 336;
+/// Offset of `pr_pid` in it.
+const PRSTATUS_PID: usize = 32;
+/// Offset of `pr_reg` (`struct user_regs_struct`) in it.
+const PRSTATUS_REGS: usize = 112;
+
+/// `QEMUCPUState` version carrying `kernel_gs_base`.
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+
+/// Where RAM starts in the file.
+const PAGE_SIZE: u64 = 4096;
+
+/// Unit RAM is copied and checked for zeroes in.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Write `memory` and the registers of `vcpus` (by vCPU index) to `path`
+/// as an ELF core. The vCPUs must be stopped, or their registers won't
+/// match the memory.
+pub fn write(path: &Path, memory: &GuestMemory, vcpus: &[(usize, VcpuState)]) -> io::Result<()> {
+    let regions = memory.regions();
+    let mut file = File::create(path)?;
+    audit::record(audit::Kind::File, "create", &path.display().to_string());
+    file.write_all(&headers(&regions, vcpus))?;
+
+    let end = write_ram(&mut file, memory, data_offset(&regions, vcpus))?;
+    // Trailing holes still count towards the size
+    file.set_len(end)?;
+    file.sync_all()
+}
+
+/// Copy guest RAM to `file` from `offset` on, one region after another,
+/// leaving chunks that are all zero as holes. Returns where RAM ends.
+pub fn write_ram(file: &mut File, memory: &GuestMemory, mut offset: u64) -> io::Result<u64> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    for region in &memory.regions() {
+        let mut done = 0;
+        while done < region.size {
+            let len = CHUNK_SIZE.min((region.size - done) as usize);
+            let chunk = &mut chunk[..len];
+            memory
+                .read(region.guest_addr + done, chunk)
+                .map_err(io::Error::other)?;
+            if chunk.iter().any(|&b| b != 0) {
+                file.seek(SeekFrom::Start(offset + done))?;
+                file.write_all(chunk)?;
+            }
+            done += len as u64;
+        }
+        offset += region.size;
+    }
+    Ok(offset)
+}
+
+/// Offset of the first byte of RAM in the file: just past the headers and
+/// notes, page aligned.
+fn data_offset(regions: &[RamRegion], vcpus: &[(usize, VcpuState)]) -> u64 {
+    let headers = ELF_HEADER_SIZE + (1 + regions.len()) * PROGRAM_HEADER_SIZE;
+    let notes = vcpus.len() * (prstatus_note_size() + qemu_note_size());
+    ((headers + notes) as u64).next_multiple_of(PAGE_SIZE)
+}
+
+/// The ELF header, program headers and notes: everything before RAM.
+fn headers(regions: &[RamRegion], vcpus: &[(usize, VcpuState)]) -> Vec<u8> {
+    let mut notes = Vec::new();
+    for (index, state) in vcpus {
+        note(&mut notes, "CORE", NT_PRSTATUS, &prstatus(*index, state));
+        note(&mut notes, "QEMU", 0, &qemu_cpu_state(state));
+    }
+
+    let phnum = 1 + regions.len();
+    let notes_offset = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+    let mut out = Vec::with_capacity(notes_offset + notes.len());
+    // e_ident: ELFCLASS64, ELFDATA2LSB, EV_CURRENT, System V ABI
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&ET_CORE.to_le_bytes());
+    out.extend_from_slice(&EM_X86_64.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    out.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    out.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // e_phoff
+    out.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    out.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    out.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(phnum as u16).to_le_bytes());
+    out.extend_from_slice(&[0; 6]); // No section headers
+
+    let notes_size = notes.len() as u64;
+    program_header(&mut out, PT_NOTE, 0, notes_offset as u64, 0, notes_size);
+    let mut offset = data_offset(regions, vcpus);
+    for region in regions {
+        program_header(
+            &mut out,
+            PT_LOAD,
+            PF_RWX,
+            offset,
+            region.guest_addr,
+            region.size,
+        );
+        offset += region.size;
+    }
+    out.extend_from_slice(&notes);
+    out
+}
+
+/// Append an `Elf64_Phdr`. RAM has no virtual address (`p_vaddr` 0), as
+/// in QEMU's dumps without paging information.
+fn program_header(out: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, paddr: u64, size: u64) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes()); // p_vaddr
+    out.extend_from_slice(&paddr.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes()); // p_filesz
+    out.extend_from_slice(&size.to_le_bytes()); // p_memsz
+    out.extend_from_slice(&0u64.to_le_bytes()); // p_align
+}
+
+/// Append a note: `Elf64_Nhdr`, then name and descriptor, each padded to
+/// four bytes.
+fn note(out: &mut Vec<u8>, name: &str, kind: u32, desc: &[u8]) {
+    let name_size = name.len() + 1;
+    out.extend_from_slice(&(name_size as u32).to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+    out.resize(out.len() + name_size.next_multiple_of(4) - name.len(), 0);
+    out.extend_from_slice(desc);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+fn note_size(name: &str, desc_size: usize) -> usize {
+    12 + (name.len() + 1).next_multiple_of(4) + desc_size.next_multiple_of(4)
+}
+
+fn prstatus_note_size() -> usize {
+    note_size("CORE", PRSTATUS_SIZE)
+}
+
+fn qemu_note_size() -> usize {
+    note_size("QEMU", qemu_cpu_state_size())
+}
+
+/// `struct elf_prstatus` for vCPU `index`, which gdb shows as thread
+/// `index + 1`.
+fn prstatus(index: usize, state: &VcpuState) -> Vec<u8> {
+    let (regs, sregs) = (&state.regs, &state.sregs);
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+    desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&(index as u32 + 1).to_le_bytes());
+    // struct user_regs_struct, in its order
+    let user_regs = [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rax, // orig_rax
+        regs.rip,
+        sregs.cs.selector.into(),
+        regs.rflags,
+        regs.rsp,
+        sregs.ss.selector.into(),
+        sregs.fs.base,
+        sregs.gs.base,
+        sregs.ds.selector.into(),
+        sregs.es.selector.into(),
+        sregs.fs.selector.into(),
+        sregs.gs.selector.into(),
+    ];
+    for (i, value) in user_regs.iter().enumerate() {
+        let at = PRSTATUS_REGS + i * 8;
+        desc[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    desc
+}
+
+/// Size of QEMU's `QEMUCPUState`: version and size, 18 registers, 10
+/// segments of 24 bytes, 5 control registers and `kernel_gs_base`.
+fn qemu_cpu_state_size() -> usize {
+    8 + 18 * 8 + 10 * 24 + 5 * 8 + 8
+}
+
+/// QEMU's `QEMUCPUState`, which `crash` reads CR3 and the segments from.
+fn qemu_cpu_state(state: &VcpuState) -> Vec<u8> {
+    let (regs, sregs) = (&state.regs, &state.sregs);
+    let mut desc = Vec::with_capacity(qemu_cpu_state_size());
+    desc.extend_from_slice(&QEMU_CPU_STATE_VERSION.to_le_bytes());
+    desc.extend_from_slice(&(qemu_cpu_state_size() as u32).to_le_bytes());
+    for value in [
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rsp,
+        regs.rbp,
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15,
+        regs.rip,
+        regs.rflags,
+    ] {
+        desc.extend_from_slice(&value.to_le_bytes());
+    }
+    for segment in [
+        &sregs.cs, &sregs.ds, &sregs.es, &sregs.fs, &sregs.gs, &sregs.ss, &sregs.ldt, &sregs.tr,
+    ] {
+        qemu_segment(&mut desc, segment);
+    }
+    for table in [&sregs.gdt, &sregs.idt] {
+        qemu_table(&mut desc, table);
+    }
+    for value in [sregs.cr0, 0, sregs.cr2, sregs.cr3, sregs.cr4] {
+        desc.extend_from_slice(&value.to_le_bytes());
+    }
+    let kernel_gs_base = state
+        .msrs
+        .iter()
+        .find(|entry| entry.index == MSR_KERNEL_GS_BASE)
+        .map_or(0, |entry| entry.data);
+    desc.extend_from_slice(&kernel_gs_base.to_le_bytes());
+    desc
+}
+
+/// A `QEMUCPUSegment`: selector, limit, the descriptor's attribute bits
+/// (access byte, then the flags nibble at bit 12) and base.
+fn qemu_segment(out: &mut Vec<u8>, segment: &kvm_segment) {
+    let flags = u32::from(segment.type_)
+        | u32::from(segment.s) << 4
+        | u32::from(segment.dpl) << 5
+        | u32::from(segment.present) << 7
+        | u32::from(segment.avl) << 12
+        | u32::from(segment.l) << 13
+        | u32::from(segment.db) << 14
+        | u32::from(segment.g) << 15;
+    out.extend_from_slice(&u32::from(segment.selector).to_le_bytes());
+    out.extend_from_slice(&segment.limit.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&segment.base.to_le_bytes());
+}
+
+/// A descriptor table as a `QEMUCPUSegment` holding only limit and base.
+fn qemu_table(out: &mut Vec<u8>, table: &kvm_dtable) {
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&u32::from(table.limit).to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&table.base.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::kvm_msr_entry;
+    use std::io::Read;
+
+    fn vcpu_state() -> VcpuState {
+        let mut state = VcpuState {
+            regs: Default::default(),
+            sregs: Default::default(),
+            xsave: Default::default(),
+            xcrs: Default::default(),
+            lapic: Default::default(),
+            events: Default::default(),
+            mp_state: Default::default(),
+            msrs: vec![kvm_msr_entry {
+                index: MSR_KERNEL_GS_BASE,
+                data: 0xffff_8880_0000_0000,
+                ..Default::default()
+            }],
+            nested: None,
+        };
+        state.regs.rip = 0xffff_ffff_8100_0000;
+        state.regs.rax = 0x1234;
+        state.sregs.cr3 = 0x1000;
+        state.sregs.cs.selector = 0x10;
+        state
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_headers() {
+        let regions = [
+            RamRegion {
+                guest_addr: 0,
+                size: 0x1000_0000,
+                host_addr: 0,
+                file_offset: 0,
+            },
+            RamRegion {
+                guest_addr: 0x1_0000_0000,
+                size: 0x2000_0000,
+                host_addr: 0,
+                file_offset: 0,
+            },
+        ];
+        let vcpus = [(0, vcpu_state()), (1, vcpu_state())];
+        let data = headers(&regions, &vcpus);
+        assert_eq!(&data[..4], b"\x7fELF");
+        assert_eq!(u16_at(&data, 16), ET_CORE);
+        assert_eq!(u16_at(&data, 18), EM_X86_64);
+        assert_eq!(u16_at(&data, 56), 3);
+
+        // PT_NOTE, then a PT_LOAD per region, back to back after the notes
+        let note = ELF_HEADER_SIZE;
+        assert_eq!(u32_at(&data, note), PT_NOTE);
+        let notes_offset = u64_at(&data, note + 8) as usize;
+        let notes_size = u64_at(&data, note + 32) as usize;
+        assert_eq!(notes_offset + notes_size, data.len());
+        let data_start = data_offset(&regions, &vcpus);
+        assert_eq!(data_start % PAGE_SIZE, 0);
+        assert!(data_start >= data.len() as u64);
+        let load = ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE;
+        assert_eq!(u32_at(&data, load), PT_LOAD);
+        assert_eq!(u64_at(&data, load + 8), data_start + 0x1000_0000);
+        assert_eq!(u64_at(&data, load + 24), 0x1_0000_0000);
+        assert_eq!(u64_at(&data, load + 32), 0x2000_0000);
+
+        // Each vCPU's NT_PRSTATUS, then its QEMU note
+        let mut at = notes_offset;
+        for pid in 1..=2 {
+            assert_eq!(u32_at(&data, at + 4) as usize, PRSTATUS_SIZE);
+            assert_eq!(u32_at(&data, at + 8), NT_PRSTATUS);
+            assert_eq!(&data[at + 12..at + 17], b"CORE\0");
+            let desc = at + 20;
+            assert_eq!(u32_at(&data, desc + PRSTATUS_PID), pid);
+            assert_eq!(u64_at(&data, desc + PRSTATUS_REGS + 10 * 8), 0x1234);
+            assert_eq!(
+                u64_at(&data, desc + PRSTATUS_REGS + 16 * 8),
+                0xffff_ffff_8100_0000
+            );
+            assert_eq!(u64_at(&data, desc + PRSTATUS_REGS + 17 * 8), 0x10);
+            at += prstatus_note_size();
+
+            assert_eq!(&data[at + 12..at + 17], b"QEMU\0");
+            let desc = at + 20;
+            assert_eq!(u32_at(&data, desc), QEMU_CPU_STATE_VERSION);
+            let size = u32_at(&data, desc + 4) as usize;
+            assert_eq!(size, qemu_cpu_state_size());
+            assert_eq!(u64_at(&data, desc + size - 24), 0x1000);
+            assert_eq!(u64_at(&data, desc + size - 8), 0xffff_8880_0000_0000);
+            at += qemu_note_size();
+        }
+        assert_eq!(at, data.len());
+    }
+
+    #[test]
+    fn test_write() {
+        let memory = GuestMemory::new(4 << 20).unwrap();
+        memory.write(0x1000, b"carbon").unwrap();
+        memory.write((4 << 20) - 1, &[0xaa]).unwrap();
+        let vcpus = [(0, vcpu_state())];
+        let path = std::env::temp_dir().join(format!("carbon-core-{}", std::process::id()));
+        write(&path, &memory, &vcpus).unwrap();
+
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let start = data_offset(&memory.regions(), &vcpus) as usize;
+        assert_eq!(data.len(), start + (4 << 20));
+        assert_eq!(&data[start + 0x1000..start + 0x1006], b"carbon");
+        assert_eq!(data[data.len() - 1], 0xaa);
+    }
+}
@@ -0,0 +1,212 @@
+//! Running `carbon` in the background (`--detach`).
+//!
+//! `carbon --detach` turns itself into a daemon the classic way, so an init
+//! system can start it without a wrapper script:
+//!
+//! ```text
+//! carbon ──fork──► child ──setsid, fork──► daemon
+//!   │                │                       │ stdin  ◄── /dev/null
+//!   │                └─► exits               │ stdout ──► --log-file
+//!   │                                        │ stderr ──► --log-file
+//!   │                                        │ pid    ──► --pid-file
+//!   ◄────────────── "ready" or the error ────┘
+//!   exits 0, or with the error
+//! ```
+//!
+//! The child leaves the terminal's session with `setsid`; its own child,
+//! the daemon, isn't a session leader, so it can never acquire a controlling
+//! terminal again. The command started waits on a pipe until the daemon has
+//! its log and pid file, so a missing log directory or a pid file another
+//! Carbon holds is an error from the command itself, not a silent exit in
+//! the background.
+//!
+//! Without `--log-file`, the console and diagnostics are discarded. The
+//! working directory is kept, so relative paths given on the command line
+//! still name the same files.
+//!
+//! The pid file holds the daemon's pid and a lock on it for as long as the
+//! daemon runs, so a second Carbon given the same pid file refuses to
+//! start; it is removed on exit.
+//!
+//! `fork` copies only the calling thread, so [`detach`] must be called
+//! before Carbon starts any other.
+
+use crate::cleanup::{self, CleanupGuard};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// Where a detached Carbon puts its pid and output.
+#[derive(Debug, Clone, Default)]
+pub struct DaemonConfig {
+    /// Write the daemon's pid here.
+    pub pid_file: Option<PathBuf>,
+    /// Append stdout and stderr here instead of discarding them.
+    pub log_file: Option<PathBuf>,
+}
+
+/// Carry on as a daemon (see the [module docs](self)). Returns in the
+/// daemon only, with the guard that removes its pid file; the command
+/// started exits once the daemon is ready, or returns the error if it
+/// failed to set up.
+pub fn detach(config: &DaemonConfig) -> io::Result<Option<CleanupGuard>> {
+    let (mut ready_read, ready_write) = pipe()?;
+    // SAFETY: no other thread is running (see the module docs), so the
+    // child is a full copy of this process.
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => {
+            drop(ready_write);
+            let mut answer = Vec::new();
+            ready_read.read_to_end(&mut answer)?;
+            return match answer.split_first() {
+                Some((0, _)) => std::process::exit(0),
+                Some((_, message)) => Err(io::Error::other(
+                    String::from_utf8_lossy(message).into_owned(),
+                )),
+                None => Err(io::Error::other("the daemon exited while starting")),
+            };
+        }
+    }
+    drop(ready_read);
+
+    // The child: a session of its own, and a daemon that can't lead it
+    // SAFETY: setsid and fork have no memory-safety preconditions; the
+    // child exits without unwinding or running destructors.
+    unsafe {
+        if libc::setsid() == -1 {
+            report(ready_write, Some(&io::Error::last_os_error()));
+            libc::_exit(1);
+        }
+        match libc::fork() {
+            -1 => {
+                report(ready_write, Some(&io::Error::last_os_error()));
+                libc::_exit(1);
+            }
+            0 => {}
+            _ => libc::_exit(0),
+        }
+    }
+
+    let daemon = set_up(config);
+    report(ready_write, daemon.as_ref().err());
+    if daemon.is_err() {
+        // SAFETY: as above; nothing of the daemon's needs cleaning up.
+        unsafe { libc::_exit(1) };
+    }
+    daemon
+}
+
+/// Redirect the daemon's standard streams and write its pid file.
+fn set_up(config: &DaemonConfig) -> io::Result<Option<CleanupGuard>> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let log = match &config.log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| annotate(e, "log file", path))?,
+        None => null.try_clone()?,
+    };
+    let guard = match &config.pid_file {
+        Some(path) => {
+            let pid = std::process::id();
+            let file = write_pid_file(path, pid).map_err(|e| annotate(e, "pid file", path))?;
+            let path = path.clone();
+            Some(cleanup::register("remove pid file", move || {
+                let _ = std::fs::remove_file(&path);
+                drop(file);
+            }))
+        }
+        None => None,
+    };
+    for (file, fd) in [
+        (&null, libc::STDIN_FILENO),
+        (&log, libc::STDOUT_FILENO),
+        (&log, libc::STDERR_FILENO),
+    ] {
+        // SAFETY: both descriptors are open; dup2 replaces `fd` atomically.
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(guard)
+}
+
+/// Lock the pid file at `path` and write `pid` to it. The lock lasts as
+/// long as the file returned is open.
+fn write_pid_file(path: &Path, pid: u32) -> io::Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    // SAFETY: flock on an open descriptor has no other preconditions.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+        let e = io::Error::last_os_error();
+        return Err(if e.kind() == io::ErrorKind::WouldBlock {
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "another process holds it locked",
+            )
+        } else {
+            e
+        });
+    }
+    file.set_len(0)?;
+    writeln!(file, "{pid}")?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+/// Tell the command waiting on `ready` how setting up went: a zero byte,
+/// or the error after a byte that isn't.
+fn report(mut ready: File, error: Option<&io::Error>) {
+    let answer = match error {
+        None => vec![0],
+        Some(e) => format!("!{e}").into_bytes(),
+    };
+    let _ = ready.write_all(&answer);
+}
+
+fn annotate(e: io::Error, what: &str, path: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{what} {}: {e}", path.display()))
+}
+
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds: [RawFd; 2] = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe2 returns.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just opened both descriptors, owned by nothing else.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("carbon-pid-{}", std::process::id()));
+        std::fs::write(&path, "a stale pid that is much longer\n").unwrap();
+
+        let held = write_pid_file(&path, 1234).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1234\n");
+        // A second Carbon can't take it while the first runs
+        let err = write_pid_file(&path, 5678).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1234\n");
+
+        drop(held);
+        write_pid_file(&path, 5678).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "5678\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -4,19 +4,53 @@
 //! The guest writes a register index to port 0x70, then reads/writes
 //! the register value from/to port 0x71.
 //!
-//! We implement minimal emulation to avoid the kernel's RTC timeout.
-//! When the kernel reads Status Register A (0x0A), bit 7 indicates
-//! "update in progress". Returning 0x00 tells the kernel the RTC is
-//! ready, avoiding a 1+ second timeout.
+//! # Time Source
+//!
+//! The time registers follow an [`RtcClock`]: the host's UTC clock by
+//! default (optionally shifted by a fixed offset), or a fixed start time for
+//! reproducible runs. Either way the clock advances in real time while the VM
+//! runs, so a guest without network time still boots with the right date.
+//! Values are reported in BCD, 24-hour mode, as most guests expect, or in
+//! whatever format the guest selects in Status Register B.
+//!
+//! # Update Cycle
+//!
+//! A real RTC updates its time registers once a second, and Status Register
+//! A's UIP bit (update in progress) rises 244 µs before each update so
+//! readers can avoid a torn read. We raise UIP in that same window, aligned
+//! to the clock's second boundaries: Linux waits for UIP to drop and then
+//! checks it again after reading, so the time it reads is exact, and the
+//! wait is never longer than 244 µs.
+//!
+//! # Writes
+//!
+//! The guest can set the clock (`hwclock --systohc`): setting Status
+//! Register B's SET bit stops the clock, time register writes then set it,
+//! and clearing SET starts it again from the written time. Registers with no
+//! special meaning (including the alarm) are plain battery-backed RAM.
 //!
 //! Reference: <https://wiki.osdev.org/CMOS>
 
+use super::PioDevice;
+use serde::{Deserialize, Serialize};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
 /// CMOS I/O port for the index register.
 pub const CMOS_PORT_INDEX: u16 = 0x70;
 
 /// CMOS I/O port for the data register.
 pub const CMOS_PORT_DATA: u16 = 0x71;
 
+/// Time registers.
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY_OF_WEEK: u8 = 0x06;
+const REG_DAY_OF_MONTH: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_CENTURY: u8 = 0x32;
+
 /// Status Register A - bit 7 is UIP (Update In Progress).
 const REG_STATUS_A: u8 = 0x0A;
 
@@ -29,26 +63,188 @@ const REG_STATUS_C: u8 = 0x0C;
 /// Status Register D - bit 7 indicates valid RAM/time.
 const REG_STATUS_D: u8 = 0x0D;
 
+/// Status Register A: update in progress (read-only).
+const STATUS_A_UIP: u8 = 0x80;
+
+/// Status Register B: halt the clock so it can be set.
+const STATUS_B_SET: u8 = 0x80;
+
+/// Status Register B: binary rather than BCD values.
+const STATUS_B_BINARY: u8 = 0x04;
+
+/// Status Register B: 24-hour rather than 12-hour mode.
+const STATUS_B_24H: u8 = 0x02;
+
+/// Hours register: PM flag in 12-hour mode.
+const HOURS_PM: u8 = 0x80;
+
+/// How long before each update UIP is raised.
+const UIP_WINDOW_NANOS: i128 = 244_000;
+
+/// Nanoseconds per second.
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// Where the RTC gets its time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RtcClock {
+    /// Host UTC time plus `offset` seconds.
+    Host { offset: i64 },
+    /// Start at this Unix time when the VM is created, then advance.
+    Fixed { start: i64 },
+}
+
+impl Default for RtcClock {
+    fn default() -> Self {
+        RtcClock::Host { offset: 0 }
+    }
+}
+
+/// Broken-down UTC time, as the RTC registers report it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DateTime {
+    year: i64,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    /// 1 = Sunday, as in the RTC day-of-week register.
+    weekday: u8,
+}
+
+impl DateTime {
+    /// Convert Unix time to UTC calendar time.
+    fn from_unix(secs: i64) -> Self {
+        let days = secs.div_euclid(86_400);
+        let rem = secs.rem_euclid(86_400);
+
+        // Civil-from-days (Howard Hinnant), with eras of 400 years
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4).rem_euclid(7) + 1) as u8,
+        }
+    }
+
+    /// Convert UTC calendar time to Unix time (`weekday` is ignored).
+    fn to_unix(self) -> i64 {
+        // Days-from-civil, the inverse of `from_unix`
+        let month = i64::from(self.month.clamp(1, 12));
+        let year = self.year - i64::from(month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let doy = (153 * ((month + 9) % 12) + 2) / 5 + i64::from(self.day.max(1)) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        days * 86_400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)
+    }
+}
+
+/// Encode 0-99 as packed BCD.
+fn bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Decode packed BCD.
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
 /// CMOS RTC device.
 ///
-/// Provides minimal RTC emulation to satisfy kernel boot requirements.
-/// Returns static time values and status registers that indicate
-/// the RTC is ready (not updating).
+/// Time registers follow the configured [`RtcClock`] until the guest sets
+/// the clock; everything else is 128 bytes of CMOS RAM.
 pub struct Cmos {
     /// Currently selected register index.
     index: u8,
+    /// Clock time at `started`, in nanoseconds since the Unix epoch.
+    base: i128,
+    /// When `base` was taken.
+    started: Instant,
+    /// Time registers as the guest sets them, while Status Register B's SET
+    /// bit is held. Kept field by field so that an intermediate date (e.g.
+    /// February 31st while the month is written before the day) survives.
+    halted: Option<DateTime>,
+    /// Status Register A, without UIP.
+    status_a: u8,
+    /// Status Register B.
+    status_b: u8,
+    /// Battery-backed RAM, for the registers without special meaning.
+    ram: [u8; 128],
 }
 
 impl Cmos {
-    /// Create a new CMOS device.
-    pub fn new() -> Self {
-        Self { index: 0 }
+    /// Create a new CMOS device reporting time from `clock`.
+    pub fn new(clock: RtcClock) -> Self {
+        let base = match clock {
+            RtcClock::Host { offset } => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as i128);
+                now.saturating_add(i128::from(offset) * NANOS_PER_SEC)
+            }
+            RtcClock::Fixed { start } => i128::from(start) * NANOS_PER_SEC,
+        };
+        Self {
+            index: 0,
+            base,
+            started: Instant::now(),
+            halted: None,
+            // Standard 32.768 kHz divider, 1024 Hz rate
+            status_a: 0x26,
+            // 24-hour mode, BCD, no interrupts
+            status_b: STATUS_B_24H,
+            ram: [0; 128],
+        }
+    }
+
+    /// Current clock time in nanoseconds since the Unix epoch.
+    fn now_nanos(&self) -> i128 {
+        self.base
+            .saturating_add(self.started.elapsed().as_nanos() as i128)
+    }
+
+    /// Current RTC time.
+    fn now(&self) -> DateTime {
+        self.halted.unwrap_or_else(|| {
+            DateTime::from_unix(self.now_nanos().div_euclid(NANOS_PER_SEC) as i64)
+        })
+    }
+
+    /// Restart the clock at `time`.
+    fn set_time(&mut self, time: DateTime) {
+        self.base = i128::from(time.to_unix()) * NANOS_PER_SEC;
+        self.started = Instant::now();
+    }
+
+    /// Whether an update is about to happen (the UIP bit).
+    fn update_in_progress(&self) -> bool {
+        self.halted.is_none()
+            && self.now_nanos().rem_euclid(NANOS_PER_SEC) >= NANOS_PER_SEC - UIP_WINDOW_NANOS
     }
 
     /// Write to CMOS (port 0x70 or 0x71).
     ///
     /// Port 0x70: Sets the register index (lower 7 bits, bit 7 is NMI mask).
-    /// Port 0x71: Writes to the selected register (mostly ignored).
+    /// Port 0x71: Writes to the selected register.
     pub fn write(&mut self, port: u16, value: u8) {
         match port {
             CMOS_PORT_INDEX => {
@@ -56,10 +252,7 @@ impl Cmos {
                 // Bit 7 is NMI disable (we ignore it)
                 self.index = value & 0x7F;
             }
-            CMOS_PORT_DATA => {
-                // We ignore writes to CMOS registers
-                // (time setting, alarm, etc. not needed for boot)
-            }
+            CMOS_PORT_DATA => self.write_register(self.index, value),
             _ => {}
         }
     }
@@ -71,23 +264,85 @@ impl Cmos {
         if port != CMOS_PORT_DATA {
             return 0xFF;
         }
+        let value = self.register(self.index, self.now());
+        if self.index == REG_STATUS_A && self.update_in_progress() {
+            value | STATUS_A_UIP
+        } else {
+            value
+        }
+    }
 
-        match self.index {
-            // Time registers - return zeros (midnight Jan 1)
-            0x00 => 0x00, // Seconds
-            0x02 => 0x00, // Minutes
-            0x04 => 0x00, // Hours
-            0x06 => 0x01, // Day of week (1 = Sunday)
-            0x07 => 0x01, // Day of month
-            0x08 => 0x01, // Month
-            0x09 => 0x00, // Year (2000)
-            0x32 => 0x20, // Century (20xx)
+    /// Encode a time field in the format selected in Status Register B.
+    fn encode(&self, value: u8) -> u8 {
+        if self.status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            bcd(value)
+        }
+    }
 
-            // Status Register A: UIP=0 (not updating), divider and rate bits
-            REG_STATUS_A => 0x26, // Standard divider settings, UIP=0
+    /// Decode a time field written in the format selected in Status Register B.
+    fn decode(&self, value: u8) -> u8 {
+        if self.status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    }
+
+    /// Encode the hours register, honoring 12-hour mode.
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.status_b & STATUS_B_24H != 0 {
+            return self.encode(hour);
+        }
+        let value = self.encode(match hour % 12 {
+            0 => 12,
+            h => h,
+        });
+        if hour >= 12 {
+            value | HOURS_PM
+        } else {
+            value
+        }
+    }
+
+    /// Decode the hours register, honoring 12-hour mode.
+    fn decode_hour(&self, value: u8) -> u8 {
+        if self.status_b & STATUS_B_24H != 0 {
+            return self.decode(value);
+        }
+        let hour = self.decode(value & !HOURS_PM) % 12;
+        if value & HOURS_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    /// Value of register `index` at Unix time `now`.
+    #[cfg(test)]
+    fn read_register(&self, index: u8, now: i64) -> u8 {
+        self.register(index, DateTime::from_unix(now))
+    }
+
+    /// Value of register `index` at `time`.
+    fn register(&self, index: u8, time: DateTime) -> u8 {
+        match index {
+            // Time registers, in the format selected in Status Register B
+            REG_SECONDS => self.encode(time.second),
+            REG_MINUTES => self.encode(time.minute),
+            REG_HOURS => self.encode_hour(time.hour),
+            REG_DAY_OF_WEEK => self.encode(time.weekday),
+            REG_DAY_OF_MONTH => self.encode(time.day),
+            REG_MONTH => self.encode(time.month),
+            REG_YEAR => self.encode(time.year.rem_euclid(100) as u8),
+            REG_CENTURY => self.encode(time.year.div_euclid(100).clamp(0, 99) as u8),
+
+            // Status Register A: divider and rate bits (UIP is added by `read`)
+            REG_STATUS_A => self.status_a,
 
-            // Status Register B: 24h mode, BCD format, no interrupts
-            REG_STATUS_B => 0x02, // 24-hour mode
+            // Status Register B: format and interrupt enables
+            REG_STATUS_B => self.status_b,
 
             // Status Register C: No interrupts pending
             REG_STATUS_C => 0x00,
@@ -95,14 +350,188 @@ impl Cmos {
             // Status Register D: Valid RAM and time (bit 7 set)
             REG_STATUS_D => 0x80,
 
-            // All other registers return 0
-            _ => 0x00,
+            _ => self.ram[usize::from(index & 0x7F)],
+        }
+    }
+
+    /// Write `value` to register `index`.
+    fn write_register(&mut self, index: u8, value: u8) {
+        let mut time = self.now();
+        match index {
+            REG_SECONDS => time.second = self.decode(value).min(59),
+            REG_MINUTES => time.minute = self.decode(value).min(59),
+            REG_HOURS => time.hour = self.decode_hour(value).min(23),
+            REG_DAY_OF_MONTH => time.day = self.decode(value),
+            REG_MONTH => time.month = self.decode(value),
+            REG_YEAR => {
+                time.year = time.year.div_euclid(100) * 100 + i64::from(self.decode(value) % 100)
+            }
+            REG_CENTURY => {
+                time.year = i64::from(self.decode(value)) * 100 + time.year.rem_euclid(100)
+            }
+            // UIP is read-only
+            REG_STATUS_A => {
+                self.status_a = value & !STATUS_A_UIP;
+                return;
+            }
+            REG_STATUS_B => {
+                if value & STATUS_B_SET != 0 {
+                    self.halted = Some(self.now());
+                } else if let Some(time) = self.halted.take() {
+                    self.set_time(time);
+                }
+                self.status_b = value;
+                return;
+            }
+            // The day of the week follows from the date; C and D are read-only
+            REG_DAY_OF_WEEK | REG_STATUS_C | REG_STATUS_D => return,
+            _ => {
+                self.ram[usize::from(index & 0x7F)] = value;
+                return;
+            }
+        }
+        match &mut self.halted {
+            Some(halted) => *halted = time,
+            None => self.set_time(time),
+        }
+    }
+}
+
+/// Registered at [`CMOS_PORT_INDEX`], with the data port after it.
+impl PioDevice for Cmos {
+    fn read(&mut self, offset: u16, data: &mut [u8]) {
+        data.fill(Cmos::read(self, CMOS_PORT_INDEX + offset));
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) {
+        for &byte in data {
+            Cmos::write(self, CMOS_PORT_INDEX + offset, byte);
         }
     }
 }
 
 impl Default for Cmos {
     fn default() -> Self {
-        Self::new()
+        Self::new(RtcClock::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_unix() {
+        assert_eq!(
+            DateTime::from_unix(1_709_210_096),
+            DateTime {
+                year: 2024,
+                month: 2,
+                day: 29,
+                hour: 12,
+                minute: 34,
+                second: 56,
+                weekday: 5, // Thursday
+            }
+        );
+        let before_2000 = DateTime::from_unix(946_684_799);
+        assert_eq!(
+            (before_2000.year, before_2000.month, before_2000.day),
+            (1999, 12, 31)
+        );
+        assert_eq!(DateTime::from_unix(0).weekday, 5);
+    }
+
+    #[test]
+    fn test_registers_are_bcd() {
+        let cmos = Cmos::new(RtcClock::Fixed { start: 0 });
+        let now = 1_709_210_096;
+        assert_eq!(cmos.read_register(REG_SECONDS, now), 0x56);
+        assert_eq!(cmos.read_register(REG_HOURS, now), 0x12);
+        assert_eq!(cmos.read_register(REG_MONTH, now), 0x02);
+        assert_eq!(cmos.read_register(REG_YEAR, now), 0x24);
+        assert_eq!(cmos.read_register(REG_CENTURY, now), 0x20);
+    }
+
+    #[test]
+    fn test_to_unix_roundtrip() {
+        for secs in [0, 946_684_799, 951_782_400, 1_709_210_096, 4_102_444_800] {
+            assert_eq!(DateTime::from_unix(secs).to_unix(), secs);
+        }
+    }
+
+    #[test]
+    fn test_set_clock() {
+        let mut cmos = Cmos::new(RtcClock::Fixed { start: 946_684_000 });
+        let mut write = |index, value| {
+            cmos.write(CMOS_PORT_INDEX, index);
+            cmos.write(CMOS_PORT_DATA, value);
+        };
+        // hwclock: halt, set 2024-02-29 12:34:56, restart
+        write(REG_STATUS_B, STATUS_B_SET | STATUS_B_24H);
+        write(REG_CENTURY, 0x20);
+        write(REG_YEAR, 0x24);
+        write(REG_MONTH, 0x02);
+        write(REG_DAY_OF_MONTH, 0x29);
+        write(REG_HOURS, 0x12);
+        write(REG_MINUTES, 0x34);
+        write(REG_SECONDS, 0x56);
+        assert_eq!(cmos.now().to_unix(), 1_709_210_096);
+        assert!(!cmos.update_in_progress());
+        cmos.write(CMOS_PORT_INDEX, REG_STATUS_B);
+        cmos.write(CMOS_PORT_DATA, STATUS_B_24H);
+        assert!(cmos.halted.is_none());
+        assert!((1_709_210_096..1_709_210_098).contains(&cmos.now().to_unix()));
+        cmos.write(CMOS_PORT_INDEX, REG_DAY_OF_WEEK);
+        assert_eq!(cmos.read(CMOS_PORT_DATA), 0x05);
+    }
+
+    #[test]
+    fn test_binary_12_hour_mode() {
+        let mut cmos = Cmos::new(RtcClock::Fixed { start: 0 });
+        cmos.write(CMOS_PORT_INDEX, REG_STATUS_B);
+        cmos.write(CMOS_PORT_DATA, STATUS_B_BINARY);
+        // 2024-02-29 18:04:05 UTC
+        let now = 1_709_229_845;
+        assert_eq!(cmos.read_register(REG_HOURS, now), HOURS_PM | 6);
+        assert_eq!(cmos.read_register(REG_SECONDS, now), 5);
+        assert_eq!(cmos.read_register(REG_HOURS, 1_709_164_800), 12);
+        assert_eq!(cmos.decode_hour(HOURS_PM | 12), 12);
+        assert_eq!(cmos.decode_hour(12), 0);
+    }
+
+    #[test]
+    fn test_uip_window() {
+        let mut cmos = Cmos::new(RtcClock::Fixed { start: 0 });
+        // Rewind the clock so it sits just before a second boundary
+        cmos.base = -(UIP_WINDOW_NANOS / 2);
+        cmos.started = Instant::now();
+        cmos.write(CMOS_PORT_INDEX, REG_STATUS_A);
+        let status = cmos.read(CMOS_PORT_DATA);
+        // The read may race past the boundary, but never out of the window early
+        if cmos.now_nanos() < 0 {
+            assert_eq!(status & STATUS_A_UIP, STATUS_A_UIP);
+        }
+        cmos.base = 0;
+        cmos.started = Instant::now();
+        assert_eq!(cmos.read(CMOS_PORT_DATA), 0x26);
+    }
+
+    #[test]
+    fn test_cmos_ram() {
+        let mut cmos = Cmos::default();
+        cmos.write(CMOS_PORT_INDEX, 0x40);
+        cmos.write(CMOS_PORT_DATA, 0xa5);
+        cmos.write(CMOS_PORT_INDEX, 0x0f);
+        assert_eq!(cmos.read(CMOS_PORT_DATA), 0x00);
+        cmos.write(CMOS_PORT_INDEX, 0x40);
+        assert_eq!(cmos.read(CMOS_PORT_DATA), 0xa5);
+    }
+
+    #[test]
+    fn test_fixed_clock() {
+        let mut cmos = Cmos::new(RtcClock::Fixed { start: 946_684_000 });
+        cmos.write(CMOS_PORT_INDEX, REG_YEAR);
+        assert_eq!(cmos.read(CMOS_PORT_DATA), 0x99);
     }
 }
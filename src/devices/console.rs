@@ -0,0 +1,391 @@
+//! Serial console backends.
+//!
+//! Each serial port sends guest output to, and takes guest input from, a
+//! [`ConsoleBackend`], chosen per port with `--serial` (COM1) and
+//! `--serial2` (COM2):
+//!
+//! | Spec          | Output                   | Input                          |
+//! | ------------- | ------------------------ | ------------------------------ |
+//! | `stdio`       | stdout                   | stdin (raw mode on a terminal) |
+//! | `file=PATH`   | appended to PATH         | none                           |
+//! | `socket=PATH` | one Unix socket client   | the same client                |
+//! | `pty`         | a new pseudo-terminal    | the same pseudo-terminal       |
+//!
+//! A bare `PATH` is short for `file=PATH`. The socket backend listens on PATH
+//! and waits for a client before the VM boots. The PTY's path is logged;
+//! attach to it with e.g. `screen /dev/pts/N`. Output is dropped while the
+//! PTY's buffer is full, so a console nobody reads never stalls the guest.
+
+use crate::audit;
+use crate::cleanup::{self, CleanupGuard};
+use crate::logging::ConsoleOutput;
+use crate::mux;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where a serial port's output goes and its input comes from.
+pub trait ConsoleBackend: Write + Send {
+    /// Take the source of input to the guest, if the backend has one.
+    fn take_input(&mut self) -> Option<Box<dyn ConsoleInput>> {
+        None
+    }
+}
+
+/// A source of console input: read once its descriptor is readable.
+pub trait ConsoleInput: Read + AsRawFd + Send {}
+
+impl<T: Read + AsRawFd + Send> ConsoleInput for T {}
+
+/// A console backend spec: `stdio`, `file=PATH` (or a bare `PATH`),
+/// `socket=PATH` or `pty`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsoleConfig {
+    /// Output to stdout, input from stdin.
+    #[default]
+    Stdio,
+    /// Output appended to a file.
+    File(PathBuf),
+    /// A Unix socket client, waited for before boot.
+    Socket(PathBuf),
+    /// A newly allocated pseudo-terminal.
+    Pty,
+}
+
+impl FromStr for ConsoleConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config = match s {
+            "" => return Err("empty console spec".into()),
+            "stdio" => Self::Stdio,
+            "pty" => Self::Pty,
+            _ => match s.split_once('=') {
+                Some(("file", path)) => Self::File(path.into()),
+                Some(("socket", path)) => Self::Socket(path.into()),
+                // A bare path keeps working, even one containing `=`
+                _ => Self::File(s.into()),
+            },
+        };
+        match &config {
+            Self::File(path) | Self::Socket(path) if path.as_os_str().is_empty() => {
+                Err(format!("missing path in console spec {s:?}"))
+            }
+            _ => Ok(config),
+        }
+    }
+}
+
+impl fmt::Display for ConsoleConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stdio => f.write_str("stdio"),
+            Self::File(path) => write!(f, "file={}", path.display()),
+            Self::Socket(path) => write!(f, "socket={}", path.display()),
+            Self::Pty => f.write_str("pty"),
+        }
+    }
+}
+
+impl ConsoleConfig {
+    /// Open the backend for serial port `port` (e.g. `"COM1"`, for logs).
+    ///
+    /// Blocks until a client connects for `socket=PATH`.
+    pub fn open(&self, port: &str) -> io::Result<Box<dyn ConsoleBackend>> {
+        Ok(match self {
+            Self::Stdio => Box::new(StdioConsole {
+                _terminal: cleanup::raw_terminal(),
+            }),
+            Self::File(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                audit::record(audit::Kind::File, "append", &path.display().to_string());
+                Box::new(FileConsole(file))
+            }
+            Self::Socket(path) => {
+                let (stream, guard) = mux::accept_client(path)?;
+                Box::new(SocketConsole {
+                    stream,
+                    _cleanup: guard,
+                })
+            }
+            Self::Pty => {
+                let pty = PtyConsole::open()?;
+                info!("[VMM] {} console on {}", port, pty.path);
+                Box::new(pty)
+            }
+        })
+    }
+}
+
+/// Output discarded, no input (benchmark runs).
+impl ConsoleBackend for io::Sink {}
+
+/// Stdout and stdin, with the terminal in raw mode while the VM runs.
+struct StdioConsole {
+    _terminal: Option<CleanupGuard>,
+}
+
+impl Write for StdioConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        ConsoleOutput.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        ConsoleOutput.flush()
+    }
+}
+
+impl ConsoleBackend for StdioConsole {
+    fn take_input(&mut self) -> Option<Box<dyn ConsoleInput>> {
+        // Unbuffered, so nothing read waits in a buffer once the
+        // descriptor reads as empty
+        let stdin = io::stdin().as_fd().try_clone_to_owned().ok()?;
+        Some(Box::new(File::from(stdin)))
+    }
+}
+
+/// A file guest output is appended to.
+struct FileConsole(File);
+
+impl Write for FileConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ConsoleBackend for FileConsole {}
+
+/// A connected Unix socket client.
+struct SocketConsole {
+    stream: UnixStream,
+    /// Removes the socket file on exit.
+    _cleanup: CleanupGuard,
+}
+
+impl Write for SocketConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl ConsoleBackend for SocketConsole {
+    fn take_input(&mut self) -> Option<Box<dyn ConsoleInput>> {
+        let stream = self.stream.try_clone().ok()?;
+        Some(Box::new(stream))
+    }
+}
+
+/// A pseudo-terminal: Carbon holds the leader, a terminal program opens the
+/// follower at `path`.
+struct PtyConsole {
+    /// Leader side, non-blocking.
+    leader: File,
+    /// Follower side, held open so the leader never sees a hangup while no
+    /// terminal program is attached.
+    _follower: File,
+    /// Path of the follower, e.g. `/dev/pts/3`.
+    path: String,
+}
+
+impl PtyConsole {
+    fn open() -> io::Result<Self> {
+        let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK | libc::O_CLOEXEC;
+        // SAFETY: posix_openpt has no memory-safety preconditions.
+        let fd = unsafe { libc::posix_openpt(flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just opened and is owned by nothing else.
+        let leader = unsafe { File::from_raw_fd(fd) };
+        // SAFETY: fd is a valid pseudo-terminal leader.
+        if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0 as libc::c_char; 64];
+        // SAFETY: `name` is writable for its full length.
+        let err = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        // SAFETY: ptsname_r NUL-terminated the name on success.
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let follower = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        // Raw mode: bytes pass through untouched in both directions
+        // SAFETY: termios is plain old data, filled in by tcgetattr.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: the follower is a valid terminal and `termios` is valid.
+        unsafe {
+            if libc::tcgetattr(follower.as_raw_fd(), &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(follower.as_raw_fd(), libc::TCSANOW, &termios);
+            }
+        }
+        audit::record(audit::Kind::Device, "open", &path);
+
+        Ok(Self {
+            leader,
+            _follower: follower,
+            path,
+        })
+    }
+}
+
+impl Write for PtyConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.leader.write(buf) {
+            // Nobody is reading: drop the output rather than stall the guest
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ConsoleBackend for PtyConsole {
+    fn take_input(&mut self) -> Option<Box<dyn ConsoleInput>> {
+        let leader = self.leader.try_clone().ok()?;
+        Some(Box::new(BlockingInput(leader)))
+    }
+}
+
+/// One end of a Unix socket pair, the other end held by the program
+/// embedding Carbon: guest output is sent to it, and it sends the guest its
+/// input (see [`crate::async_vmm`]). Like the PTY's, output is dropped while
+/// the socket's buffer is full.
+pub(crate) struct PairConsole(UnixStream);
+
+impl PairConsole {
+    /// The console, and the end of the pair the embedding program keeps.
+    pub(crate) fn pair() -> io::Result<(Self, UnixStream)> {
+        let (console, peer) = UnixStream::pair()?;
+        console.set_nonblocking(true)?;
+        Ok((Self(console), peer))
+    }
+}
+
+impl Write for PairConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.write(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ConsoleBackend for PairConsole {
+    fn take_input(&mut self) -> Option<Box<dyn ConsoleInput>> {
+        let stream = self.0.try_clone().ok()?;
+        Some(Box::new(BlockingInput(File::from(OwnedFd::from(stream)))))
+    }
+}
+
+/// Blocking reads from a non-blocking descriptor (the PTY leader, or a
+/// [`PairConsole`]).
+struct BlockingInput(File);
+
+impl AsRawFd for BlockingInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Read for BlockingInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let mut pollfd = libc::pollfd {
+                        fd: self.0.as_raw_fd(),
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    // SAFETY: `pollfd` is a valid array of one entry.
+                    if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                        let e = io::Error::last_os_error();
+                        if e.kind() != io::ErrorKind::Interrupted {
+                            return Err(e);
+                        }
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_console_config() {
+        assert_eq!("stdio".parse(), Ok(ConsoleConfig::Stdio));
+        assert_eq!("pty".parse(), Ok(ConsoleConfig::Pty));
+        assert_eq!(
+            "file=/tmp/com2.log".parse(),
+            Ok(ConsoleConfig::File("/tmp/com2.log".into()))
+        );
+        assert_eq!(
+            "socket=/run/com1.sock".parse(),
+            Ok(ConsoleConfig::Socket("/run/com1.sock".into()))
+        );
+        assert_eq!("app.log".parse(), Ok(ConsoleConfig::File("app.log".into())));
+        assert!("socket=".parse::<ConsoleConfig>().is_err());
+        assert!("".parse::<ConsoleConfig>().is_err());
+        assert_eq!(
+            ConsoleConfig::Socket("/run/com1.sock".into()).to_string(),
+            "socket=/run/com1.sock"
+        );
+    }
+
+    #[test]
+    fn test_pty_roundtrip() {
+        let mut pty = PtyConsole::open().unwrap();
+        let mut input = pty.take_input().unwrap();
+        let mut follower = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&pty.path)
+            .unwrap();
+
+        pty.write_all(b"out").unwrap();
+        let mut buf = [0u8; 3];
+        follower.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"out");
+
+        follower.write_all(b"in").unwrap();
+        let mut buf = [0u8; 2];
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"in");
+    }
+}
@@ -0,0 +1,95 @@
+//! isa-debug-exit: an I/O port the guest writes to terminate the VMM.
+//!
+//! For test runs: writing value N to the port stops the VM, and Carbon exits
+//! with code `(N << 1) | 1` (truncated to 8 bits), like QEMU's
+//! `isa-debug-exit`. The odd codes keep a guest-chosen status apart from a
+//! normal stop (0). Writes may be 1, 2 or 4 bytes wide; reads return 0.
+//!
+//! Test harnesses written for QEMU's default port 0xf4 work unchanged with
+//! `--debug-exit 0xf4`.
+
+use super::PioDevice;
+
+/// Debug exit port state.
+#[derive(Debug, Default)]
+pub struct DebugExit {
+    /// Exit code requested by the guest, until taken.
+    code: Option<u8>,
+}
+
+impl DebugExit {
+    /// Create the device, registered at the port chosen with
+    /// `--debug-exit`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle a read: nothing to report.
+    pub fn read(&self) -> u8 {
+        0
+    }
+
+    /// Handle a write of `data` (little-endian).
+    pub fn write(&mut self, data: &[u8]) {
+        let mut value = [0u8; 4];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+        self.code = Some(((value << 1) | 1) as u8);
+    }
+
+    /// The exit code the guest asked for since the last call, if any.
+    pub fn take_exit_code(&mut self) -> Option<u8> {
+        self.code.take()
+    }
+}
+
+impl PioDevice for DebugExit {
+    fn read(&mut self, _offset: u16, data: &mut [u8]) {
+        data.fill(DebugExit::read(self));
+    }
+
+    fn write(&mut self, _offset: u16, data: &[u8]) {
+        DebugExit::write(self, data);
+    }
+}
+
+/// Parse an I/O port number, decimal or `0x`-prefixed hex.
+pub fn parse_port(s: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("invalid I/O port {s:?} (expected 0-0xffff)"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let mut device = DebugExit::new();
+        assert_eq!(device.take_exit_code(), None);
+
+        device.write(&[0]);
+        assert_eq!(device.take_exit_code(), Some(1));
+        assert_eq!(device.take_exit_code(), None);
+
+        device.write(&[0x10, 0, 0, 0]);
+        assert_eq!(device.take_exit_code(), Some(0x21));
+
+        // Only the low bits survive, as with exit(2)
+        device.write(&0x80u16.to_le_bytes());
+        assert_eq!(device.take_exit_code(), Some(1));
+    }
+
+    #[test]
+    fn test_parse_port() {
+        assert_eq!(parse_port("0xf4"), Ok(0xf4));
+        assert_eq!(parse_port("244"), Ok(0xf4));
+        assert_eq!(parse_port("0XF4"), Ok(0xf4));
+        assert!(parse_port("0x10000").is_err());
+        assert!(parse_port("port").is_err());
+    }
+}
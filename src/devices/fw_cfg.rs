@@ -0,0 +1,469 @@
+//! fw_cfg: named configuration blobs for the guest, QEMU-compatible.
+//!
+//! Passes files into the guest without building them into a disk image:
+//! agent configuration, credentials, extra ACPI tables. Each blob is added
+//! with `--fw-cfg name=NAME,file=PATH` (read at startup) or
+//! `--fw-cfg name=NAME,string=TEXT`. Use names under `opt/`, e.g.
+//! `opt/com.example/agent.toml`; QEMU reserves the others for firmware.
+//!
+//! The device is described in the DSDT as ACPI device `QEMU0002`. Linux
+//! binds it with `CONFIG_FW_CFG_SYSFS` and shows each blob at
+//! `/sys/firmware/qemu_fw_cfg/by_name/NAME/raw`.
+//!
+//! # Interface
+//!
+//! The ports follow QEMU's x86 layout:
+//!
+//! | Port            | Access | Use                                      |
+//! | --------------- | ------ | ---------------------------------------- |
+//! | `0x510`         | write  | Select an item (16-bit key), rewind to 0 |
+//! | `0x511`         | read   | Next byte of the selected item           |
+//! | `0x514`-`0x51b` | write  | DMA descriptor address, big-endian       |
+//!
+//! Writing the low half of the DMA address (at `0x518`) starts the transfer.
+//! Items are the signature (`"QEMU"`, key 0), the feature bits (key 1:
+//! traditional interface and DMA), the file directory (key 0x19) listing
+//! each blob's size, key and name, and the blobs themselves from key 0x20.
+//! A DMA descriptor can select an item, then read or skip bytes of it;
+//! blobs are read-only, so DMA writes fail with the error bit.
+//!
+//! Reference: QEMU `docs/specs/fw_cfg.rst`
+
+use super::PioDevice;
+use crate::audit;
+use crate::boot::GuestMemory;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// First I/O port of the device (selector), as in QEMU.
+pub const FW_CFG_PORT_BASE: u16 = 0x510;
+
+/// Number of I/O ports the device decodes.
+pub const FW_CFG_PORT_COUNT: u8 = 0x0c;
+
+/// Port offsets from [`FW_CFG_PORT_BASE`].
+const SELECTOR_OFFSET: u16 = 0;
+const DATA_OFFSET: u16 = 1;
+const DMA_HIGH_OFFSET: u16 = 4;
+const DMA_LOW_OFFSET: u16 = 8;
+
+/// Item keys.
+const KEY_SIGNATURE: u16 = 0x00;
+const KEY_ID: u16 = 0x01;
+const KEY_FILE_DIR: u16 = 0x19;
+const KEY_FILE_FIRST: u16 = 0x20;
+
+/// Feature bits (item [`KEY_ID`], little-endian): traditional interface, DMA.
+const FEATURES: [u8; 4] = 0b11u32.to_le_bytes();
+
+/// Read from the DMA address ports: the DMA interface is present.
+const DMA_SIGNATURE: &[u8; 8] = b"QEMU CFG";
+
+/// DMA control bits.
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SKIP: u32 = 1 << 2;
+const DMA_SELECT: u32 = 1 << 3;
+const DMA_WRITE: u32 = 1 << 4;
+
+/// Size of a DMA descriptor: control, length, address.
+const DMA_ACCESS_SIZE: usize = 16;
+
+/// Most bytes a DMA read copies to guest memory at once. The length is the
+/// guest's, up to 4 GiB, so nothing the size of a transfer is allocated.
+const DMA_CHUNK: usize = 4096;
+
+/// Room for a name in a directory entry, including the NUL terminator.
+const MAX_NAME_LEN: usize = 56;
+
+/// A blob for the guest: `name=NAME,file=PATH` or `name=NAME,string=TEXT`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FwCfgItem {
+    /// Name the guest looks the blob up by.
+    pub name: String,
+    /// Where the contents come from.
+    pub source: FwCfgSource,
+}
+
+/// Contents of a [`FwCfgItem`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FwCfgSource {
+    /// A host file, read at startup.
+    File(PathBuf),
+    /// Literal text.
+    String(String),
+}
+
+impl FromStr for FwCfgItem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = s
+            .strip_prefix("name=")
+            .ok_or("fw_cfg item needs name=NAME")?;
+        let (name, contents) = spec
+            .split_once(',')
+            .ok_or("fw_cfg item needs file=PATH or string=TEXT")?;
+        if name.is_empty() || name.len() >= MAX_NAME_LEN {
+            return Err(format!(
+                "fw_cfg name {name:?} must be 1 to {} bytes",
+                MAX_NAME_LEN - 1
+            ));
+        }
+        // The contents come last, so a path or string may contain `,`
+        let source = if let Some(path) = contents.strip_prefix("file=") {
+            if path.is_empty() {
+                return Err("fw_cfg item needs file=PATH".into());
+            }
+            FwCfgSource::File(path.into())
+        } else if let Some(text) = contents.strip_prefix("string=") {
+            FwCfgSource::String(text.into())
+        } else {
+            return Err(format!(
+                "unknown fw_cfg option {contents:?} (expected file=PATH or string=TEXT)"
+            ));
+        };
+        Ok(Self {
+            name: name.into(),
+            source,
+        })
+    }
+}
+
+impl FwCfgItem {
+    /// Read the blob's contents.
+    pub fn load(&self) -> io::Result<Vec<u8>> {
+        match &self.source {
+            FwCfgSource::File(path) => {
+                let data = fs::read(path)?;
+                audit::record(audit::Kind::File, "read", &path.display().to_string());
+                Ok(data)
+            }
+            FwCfgSource::String(text) => Ok(text.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// fw_cfg device state.
+pub struct FwCfg {
+    /// Blobs by name, in key order from [`KEY_FILE_FIRST`].
+    files: Vec<(String, Vec<u8>)>,
+    /// The file directory item, rebuilt as files are added.
+    dir: Vec<u8>,
+    /// Selected item.
+    key: u16,
+    /// Read position in the selected item.
+    offset: usize,
+    /// High half of the DMA descriptor address, until the low half arrives.
+    dma_high: u32,
+    /// Reference to guest memory for DMA, set via set_memory().
+    memory: Option<*const GuestMemory>,
+}
+
+// Safety: FwCfg can be sent between threads. Guest memory outlives the
+// device, and DMA happens under the lock the vCPU threads share it through.
+unsafe impl Send for FwCfg {}
+
+impl FwCfg {
+    /// Create the device with no blobs.
+    pub fn new() -> Self {
+        let mut fw_cfg = Self {
+            files: Vec::new(),
+            dir: Vec::new(),
+            key: KEY_SIGNATURE,
+            offset: 0,
+            dma_high: 0,
+            memory: None,
+        };
+        fw_cfg.build_dir();
+        fw_cfg
+    }
+
+    /// Add a blob named `name`.
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) -> Result<(), String> {
+        if self.files.iter().any(|(n, _)| n == name) {
+            return Err(format!("duplicate fw_cfg name {name:?}"));
+        }
+        if name.is_empty() || name.len() >= MAX_NAME_LEN {
+            return Err(format!("invalid fw_cfg name {name:?}"));
+        }
+        if u32::try_from(data.len()).is_err() {
+            return Err(format!("fw_cfg blob {name:?} is larger than 4 GiB"));
+        }
+        self.files.push((name.into(), data));
+        self.build_dir();
+        Ok(())
+    }
+
+    /// Set guest memory reference for DMA.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the GuestMemory outlives this device.
+    pub fn set_memory(&mut self, memory: &GuestMemory) {
+        self.memory = Some(memory as *const GuestMemory);
+    }
+
+    /// Select item `key` and rewind to its start.
+    fn select(&mut self, key: u16) {
+        self.key = key;
+        self.offset = 0;
+    }
+
+    /// Contents of the selected item (empty if there is no such item).
+    fn item(&self) -> &[u8] {
+        match self.key {
+            KEY_SIGNATURE => b"QEMU",
+            KEY_ID => &FEATURES,
+            KEY_FILE_DIR => &self.dir,
+            key => key
+                .checked_sub(KEY_FILE_FIRST)
+                .and_then(|index| self.files.get(usize::from(index)))
+                .map_or(&[], |(_, data)| data),
+        }
+    }
+
+    /// Rebuild the file directory: a count, then size, key and name of each
+    /// blob, all big-endian.
+    fn build_dir(&mut self) {
+        let mut dir = (self.files.len() as u32).to_be_bytes().to_vec();
+        for (key, (name, data)) in (KEY_FILE_FIRST..).zip(&self.files) {
+            dir.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            dir.extend_from_slice(&key.to_be_bytes());
+            dir.extend_from_slice(&[0; 2]);
+            let mut entry_name = [0u8; MAX_NAME_LEN];
+            entry_name[..name.len()].copy_from_slice(name.as_bytes());
+            dir.extend_from_slice(&entry_name);
+        }
+        self.dir = dir;
+    }
+
+    /// Run the DMA descriptor at guest address `addr`, then clear its
+    /// control word (or leave just the error bit) to report completion.
+    fn dma(&mut self, addr: u64) {
+        let memory = match self.memory {
+            Some(ptr) => unsafe { &*ptr },
+            None => return,
+        };
+        let mut access = [0u8; DMA_ACCESS_SIZE];
+        if let Err(e) = memory.read(addr, &mut access) {
+            warn!("[fw_cfg] Bad DMA descriptor at {:#x}: {}", addr, e);
+            return;
+        }
+        let control = u32::from_be_bytes(access[0..4].try_into().unwrap());
+        let length = u32::from_be_bytes(access[4..8].try_into().unwrap()) as usize;
+        let address = u64::from_be_bytes(access[8..16].try_into().unwrap());
+
+        if control & DMA_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+        let ok = if control & DMA_READ != 0 {
+            let item = self.item();
+            let start = self.offset.min(item.len());
+            let end = self.offset.saturating_add(length).min(item.len());
+            let ok = dma_write(memory, address, &item[start..end], length);
+            self.offset = end;
+            ok
+        } else if control & DMA_WRITE != 0 {
+            // Blobs are read-only
+            false
+        } else if control & DMA_SKIP != 0 {
+            self.offset = self.offset.saturating_add(length);
+            true
+        } else {
+            true
+        };
+        let status = if ok { 0 } else { DMA_ERROR };
+        if let Err(e) = memory.write(addr, &status.to_be_bytes()) {
+            warn!("[fw_cfg] Failed to complete DMA at {:#x}: {}", addr, e);
+        }
+    }
+}
+
+/// Copy `data` to guest memory at `address`, then zeros for the rest of
+/// `length` bytes (the guest reads zeros past the end of an item), a chunk
+/// at a time. Returns whether all of it was written.
+fn dma_write(memory: &GuestMemory, address: u64, data: &[u8], length: usize) -> bool {
+    const ZEROS: [u8; DMA_CHUNK] = [0; DMA_CHUNK];
+    let mut done = 0;
+    while done < length {
+        let len = (length - done).min(DMA_CHUNK);
+        let chunk = match data.get(done..) {
+            Some(rest) if !rest.is_empty() => &rest[..len.min(rest.len())],
+            _ => &ZEROS[..len],
+        };
+        let Some(addr) = address.checked_add(done as u64) else {
+            return false;
+        };
+        if memory.write(addr, chunk).is_err() {
+            return false;
+        }
+        done += chunk.len();
+    }
+    true
+}
+
+impl PioDevice for FwCfg {
+    fn read(&mut self, offset: u16, data: &mut [u8]) {
+        match offset {
+            DATA_OFFSET => {
+                for byte in data {
+                    *byte = self.item().get(self.offset).copied().unwrap_or(0);
+                    self.offset = self.offset.saturating_add(1);
+                }
+            }
+            DMA_HIGH_OFFSET.. => {
+                for (i, byte) in data.iter_mut().enumerate() {
+                    let index = usize::from(offset - DMA_HIGH_OFFSET) + i;
+                    *byte = DMA_SIGNATURE.get(index).copied().unwrap_or(0);
+                }
+            }
+            // The selector is write-only
+            _ => data.fill(0),
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) {
+        match (offset, data.len()) {
+            (SELECTOR_OFFSET, 1) => self.select(u16::from(data[0])),
+            (SELECTOR_OFFSET, _) => self.select(u16::from_le_bytes([data[0], data[1]])),
+            (DMA_HIGH_OFFSET, 4) => {
+                self.dma_high = u32::from_be_bytes(data.try_into().unwrap());
+            }
+            (DMA_LOW_OFFSET, 4) => {
+                let low = u32::from_be_bytes(data.try_into().unwrap());
+                let addr = (u64::from(self.dma_high) << 32) | u64::from(low);
+                self.dma_high = 0;
+                self.dma(addr);
+            }
+            // Writes through the data port are obsolete and ignored
+            _ => {}
+        }
+    }
+}
+
+impl Default for FwCfg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Select `key` and read `len` bytes through the data port.
+    fn read_item(fw_cfg: &mut FwCfg, key: u16, len: usize) -> Vec<u8> {
+        fw_cfg.write(SELECTOR_OFFSET, &key.to_le_bytes());
+        let mut data = vec![0u8; len];
+        for byte in &mut data {
+            fw_cfg.read(DATA_OFFSET, std::slice::from_mut(byte));
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_item() {
+        assert_eq!(
+            "name=opt/agent,file=/etc/agent.toml".parse(),
+            Ok(FwCfgItem {
+                name: "opt/agent".into(),
+                source: FwCfgSource::File("/etc/agent.toml".into()),
+            })
+        );
+        assert_eq!(
+            "name=opt/tags,string=a,b".parse(),
+            Ok(FwCfgItem {
+                name: "opt/tags".into(),
+                source: FwCfgSource::String("a,b".into()),
+            })
+        );
+        assert!("opt/agent,file=x".parse::<FwCfgItem>().is_err());
+        assert!("name=opt/agent".parse::<FwCfgItem>().is_err());
+        assert!("name=opt/agent,file=".parse::<FwCfgItem>().is_err());
+        assert!("name=opt/agent,data=x".parse::<FwCfgItem>().is_err());
+        let long = format!("name=opt/{},string=x", "n".repeat(60));
+        assert!(long.parse::<FwCfgItem>().is_err());
+    }
+
+    #[test]
+    fn test_port_interface() {
+        let mut fw_cfg = FwCfg::new();
+        fw_cfg.add_file("opt/hello", b"hi".to_vec()).unwrap();
+        assert!(fw_cfg.add_file("opt/hello", Vec::new()).is_err());
+
+        assert_eq!(read_item(&mut fw_cfg, KEY_SIGNATURE, 4), b"QEMU");
+        assert_eq!(read_item(&mut fw_cfg, KEY_ID, 4), [3, 0, 0, 0]);
+
+        let dir = read_item(&mut fw_cfg, KEY_FILE_DIR, 4 + 64);
+        assert_eq!(dir[..4], 1u32.to_be_bytes());
+        assert_eq!(dir[4..8], 2u32.to_be_bytes());
+        assert_eq!(dir[8..10], KEY_FILE_FIRST.to_be_bytes());
+        assert_eq!(&dir[12..22], b"opt/hello\0");
+
+        // Reads past the end return zeros
+        assert_eq!(read_item(&mut fw_cfg, KEY_FILE_FIRST, 3), b"hi\0");
+        assert_eq!(read_item(&mut fw_cfg, 0x7f, 1), [0]);
+
+        let mut signature = [0u8; 4];
+        fw_cfg.read(DMA_HIGH_OFFSET, &mut signature);
+        assert_eq!(&signature, b"QEMU");
+    }
+
+    #[test]
+    fn test_dma_read() {
+        let memory = GuestMemory::new(1 << 16).unwrap();
+        let mut fw_cfg = FwCfg::new();
+        fw_cfg
+            .add_file("opt/config", b"key=value".to_vec())
+            .unwrap();
+        fw_cfg.set_memory(&memory);
+
+        // Select the blob, skip 4 bytes, then read 8 (past its end)
+        let access = |control: u32, length: u32| {
+            let mut access = control.to_be_bytes().to_vec();
+            access.extend_from_slice(&length.to_be_bytes());
+            access.extend_from_slice(&0x2000u64.to_be_bytes());
+            memory.write(0x1000, &access).unwrap();
+        };
+        let run = |fw_cfg: &mut FwCfg| {
+            fw_cfg.write(DMA_HIGH_OFFSET, &0u32.to_be_bytes());
+            fw_cfg.write(DMA_LOW_OFFSET, &0x1000u32.to_be_bytes());
+            let mut control = [0u8; 4];
+            memory.read(0x1000, &mut control).unwrap();
+            u32::from_be_bytes(control)
+        };
+        access(u32::from(KEY_FILE_FIRST) << 16 | DMA_SELECT | DMA_SKIP, 4);
+        assert_eq!(run(&mut fw_cfg), 0);
+        access(DMA_READ, 8);
+        assert_eq!(run(&mut fw_cfg), 0);
+        let mut data = [0u8; 8];
+        memory.read(0x2000, &mut data).unwrap();
+        assert_eq!(&data, b"value\0\0\0");
+
+        access(DMA_WRITE, 1);
+        assert_eq!(run(&mut fw_cfg), DMA_ERROR);
+
+        // A read longer than the item spans chunks of data, then zeros
+        let blob: Vec<u8> = (0..DMA_CHUNK + 100).map(|i| i as u8).collect();
+        fw_cfg.add_file("opt/blob", blob.clone()).unwrap();
+        memory.write(0x2000, &[0xff; 3 * DMA_CHUNK]).unwrap();
+        access(
+            (u32::from(KEY_FILE_FIRST) + 1) << 16 | DMA_SELECT | DMA_READ,
+            3 * DMA_CHUNK as u32,
+        );
+        assert_eq!(run(&mut fw_cfg), 0);
+        let mut data = vec![0u8; 3 * DMA_CHUNK];
+        memory.read(0x2000, &mut data).unwrap();
+        assert_eq!(data[..blob.len()], blob);
+        assert!(data[blob.len()..].iter().all(|&b| b == 0));
+
+        // A length past the end of guest memory fails, without allocating
+        // anything its size
+        access(DMA_SELECT | DMA_READ, u32::MAX);
+        assert_eq!(run(&mut fw_cfg), DMA_ERROR);
+    }
+}
@@ -6,13 +6,28 @@
 //! # Memory Layout
 //!
 //! ```text
-//! 0xd000_0000 - 0xd000_0FFF  virtio-blk MMIO (4KB)
-//! 0xd000_1000 - 0xd000_1FFF  virtio-vsock MMIO (reserved)
+//! 0xd000_0000 - 0xd000_0FFF  virtio-blk MMIO (4KB), first disk
+//! 0xd000_1000 - 0xd000_1FFF  virtio-vsock MMIO (4KB)
 //! 0xd000_2000 - 0xd000_2FFF  virtio-net MMIO (reserved)
+//! 0xd000_3000 - 0xd000_3FFF  virtio-blk MMIO (4KB), second disk
+//! 0xd000_4000 - 0xd000_5FFF  virtio-fs MMIO (4KB each), shared directories
+//! 0xd000_6000 - 0xd000_7FFF  virtio-9p MMIO (4KB each), 9P shares
+//! 0xd000_8000 - 0xd000_8FFF  virtio-pmem MMIO (4KB); the memory itself
+//!                            sits above RAM, at 4GB or higher
+//! 0xd000_9000 - 0xd000_EFFF  virtio-blk MMIO (4KB each), third to eighth disks
+//! 0xd100_0000 - 0xd1FF_FFFF  device plugin regions (see `plugin`)
+//! 0xe000_0000 - 0xe01F_FFFF  PCI ECAM and BAR window (see `pci`)
+//! 0xfed4_0000 - 0xfed4_0FFF  TPM CRB registers and buffer (see `tpm`)
 //! ```
 //!
 //! Each virtio device gets a 4KB MMIO region for its configuration registers
-//! and virtqueue notification.
+//! and virtqueue notification. A device attached over PCI leaves its region
+//! unused, but keeps its IRQ. The VMM has KVM signal QUEUE_NOTIFY writes to
+//! the devices on this bus on ioeventfds (see [`MmioBus::queue_notifiers`]),
+//! so notifying a queue doesn't stop the vCPU.
+
+use super::virtio::MMIO_QUEUE_NOTIFY;
+use crate::snapshot::DeviceState;
 
 /// Base address for virtio MMIO devices.
 pub const VIRTIO_MMIO_BASE: u64 = 0xd000_0000;
@@ -26,11 +41,42 @@ pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
 /// This works with standard ACPI mode (not HW_REDUCED).
 pub const VIRTIO_BLK_IRQ: u32 = 5;
 
+/// MMIO base and IRQ of each virtio-blk device, in attach order. Disks past
+/// the second use GSIs above the legacy ISA range (the IOAPIC has 24 pins).
+pub const VIRTIO_BLK_SLOTS: [(u64, u32); 8] = [
+    (VIRTIO_MMIO_BASE, VIRTIO_BLK_IRQ),
+    (VIRTIO_MMIO_BASE + 3 * VIRTIO_MMIO_SIZE, 6),
+    (VIRTIO_MMIO_BASE + 9 * VIRTIO_MMIO_SIZE, 16),
+    (VIRTIO_MMIO_BASE + 10 * VIRTIO_MMIO_SIZE, 17),
+    (VIRTIO_MMIO_BASE + 11 * VIRTIO_MMIO_SIZE, 18),
+    (VIRTIO_MMIO_BASE + 12 * VIRTIO_MMIO_SIZE, 19),
+    (VIRTIO_MMIO_BASE + 13 * VIRTIO_MMIO_SIZE, 20),
+    (VIRTIO_MMIO_BASE + 14 * VIRTIO_MMIO_SIZE, 21),
+];
+
+/// MMIO base and IRQ of the virtio-vsock device.
+pub const VIRTIO_VSOCK_SLOT: (u64, u32) = (VIRTIO_MMIO_BASE + VIRTIO_MMIO_SIZE, 7);
+
+/// MMIO base and IRQ of each virtio-fs device, in attach order.
+pub const VIRTIO_FS_SLOTS: [(u64, u32); 2] = [
+    (VIRTIO_MMIO_BASE + 4 * VIRTIO_MMIO_SIZE, 9),
+    (VIRTIO_MMIO_BASE + 5 * VIRTIO_MMIO_SIZE, 10),
+];
+
+/// MMIO base and IRQ of each virtio-9p device, in attach order.
+pub const VIRTIO_9P_SLOTS: [(u64, u32); 2] = [
+    (VIRTIO_MMIO_BASE + 6 * VIRTIO_MMIO_SIZE, 11),
+    (VIRTIO_MMIO_BASE + 7 * VIRTIO_MMIO_SIZE, 12),
+];
+
+/// MMIO base and IRQ of the virtio-pmem device.
+pub const VIRTIO_PMEM_SLOT: (u64, u32) = (VIRTIO_MMIO_BASE + 8 * VIRTIO_MMIO_SIZE, 13);
+
 /// Trait for devices that respond to MMIO access.
 ///
 /// Implementors handle reads and writes to their MMIO register space.
 /// The offset is relative to the device's base address.
-pub trait MmioDevice {
+pub trait MmioDevice: Send {
     /// Handle an MMIO read at the given offset.
     ///
     /// # Arguments
@@ -46,6 +92,29 @@ pub trait MmioDevice {
     /// * `offset` - Offset within the device's MMIO region (0 to size-1)
     /// * `data` - Data being written
     fn write(&mut self, offset: u64, data: &[u8]);
+
+    /// How many virtqueues the device has, notified by writing the queue's
+    /// index to QUEUE_NOTIFY. Devices without any keep the default of none.
+    fn notify_queues(&self) -> u32 {
+        0
+    }
+
+    /// Serve requests held back by a rate limit, once the VM's
+    /// [`RetryTimer`](crate::devices::virtio::rate_limiter::RetryTimer)
+    /// fires. Devices without limits keep the default of nothing to do.
+    fn retry(&mut self) {}
+
+    /// The device's state, for a snapshot (see [`crate::snapshot`]).
+    /// Devices with none the guest would miss keep the default of `None`.
+    fn save_state(&self) -> Option<DeviceState> {
+        None
+    }
+
+    /// Take back a state [`save_state`](Self::save_state) returned, in a
+    /// device built from the same configuration.
+    fn restore_state(&mut self, _state: &DeviceState) -> Result<(), String> {
+        Err("the device has no state to restore".into())
+    }
 }
 
 /// A registered device on the MMIO bus.
@@ -88,6 +157,47 @@ impl MmioBus {
         self.devices.sort_by_key(|e| e.base);
     }
 
+    /// The QUEUE_NOTIFY writes the registered devices take, as the
+    /// register's address and the value written, one per virtqueue.
+    pub fn queue_notifiers(&self) -> Vec<(u64, u32)> {
+        self.devices
+            .iter()
+            .flat_map(|entry| {
+                let addr = entry.base + MMIO_QUEUE_NOTIFY;
+                (0..entry.device.notify_queues()).map(move |queue| (addr, queue))
+            })
+            .collect()
+    }
+
+    /// Have every registered device serve what it held back (see
+    /// [`MmioDevice::retry`]).
+    pub fn retry(&mut self) {
+        for entry in &mut self.devices {
+            entry.device.retry();
+        }
+    }
+
+    /// The state of each registered device that has any, by base address.
+    pub fn save_state(&self) -> Vec<(u64, DeviceState)> {
+        self.devices
+            .iter()
+            .filter_map(|entry| Some((entry.base, entry.device.save_state()?)))
+            .collect()
+    }
+
+    /// Restore the device registered at `base` to `state`.
+    pub fn restore_state(&mut self, base: u64, state: &DeviceState) -> Result<(), String> {
+        let entry = self
+            .devices
+            .iter_mut()
+            .find(|entry| entry.base == base)
+            .ok_or_else(|| format!("no MMIO device at {base:#x}"))?;
+        entry
+            .device
+            .restore_state(state)
+            .map_err(|e| format!("MMIO device at {base:#x}: {e}"))
+    }
+
     /// Find the device that handles the given address.
     fn find_device(&mut self, addr: u64) -> Option<(&mut dyn MmioDevice, u64)> {
         for entry in &mut self.devices {
@@ -166,5 +276,8 @@ mod tests {
         // Read from unmapped region returns 0xff
         bus.read(0x2000, &mut data);
         assert_eq!(data, [0xff; 4]);
+
+        // It has no virtqueues to notify
+        assert!(bus.queue_notifiers().is_empty());
     }
 }
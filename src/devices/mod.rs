@@ -1,15 +1,54 @@
 //! Device emulation for the VMM.
 
 mod cmos;
+mod console;
+pub mod debug_exit;
+mod fw_cfg;
 mod mmio;
+pub mod pci;
+mod pio;
+pub mod plugin;
+mod pvpanic;
+mod reset;
 mod serial;
+mod tpm;
 pub mod virtio;
 
-pub use cmos::{Cmos, CMOS_PORT_DATA, CMOS_PORT_INDEX};
-pub use mmio::{MmioBus, VIRTIO_BLK_IRQ, VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE};
-pub use serial::Serial;
-pub use virtio::blk::VirtioBlk;
+pub use cmos::{Cmos, RtcClock, CMOS_PORT_INDEX};
+pub(crate) use console::PairConsole;
+pub use console::{ConsoleBackend, ConsoleConfig, ConsoleInput};
+pub use debug_exit::DebugExit;
+pub use fw_cfg::{FwCfg, FwCfgItem, FW_CFG_PORT_BASE, FW_CFG_PORT_COUNT};
+pub use mmio::{
+    MmioBus, MmioDevice, VIRTIO_9P_SLOTS, VIRTIO_BLK_SLOTS, VIRTIO_FS_SLOTS, VIRTIO_MMIO_SIZE,
+    VIRTIO_PMEM_SLOT, VIRTIO_VSOCK_SLOT,
+};
+pub use pci::{PciBus, PCI_ECAM_BASE, PCI_ECAM_SIZE, PCI_MMIO_BASE, PCI_MMIO_SIZE};
+pub use pio::{PioBus, PioDevice};
+pub use plugin::Plugin;
+pub use pvpanic::{PanicEvent, Pvpanic, PVPANIC_PORT};
+pub use reset::{ResetControl, I8042, I8042_DATA_PORT, I8042_PORT_COUNT, RESET_CONTROL_PORT};
+pub use serial::{Serial, SerialInput, RX_RETRY_INTERVAL};
+pub use tpm::{TpmCrb, TPM_CRB_BASE, TPM_CRB_CONTROL_AREA, TPM_CRB_SIZE};
+pub use virtio::blk::{DiskOptions, VirtioBlk};
+pub use virtio::fs::SharedDirConfig;
+pub use virtio::p9::{P9Share, Virtio9p};
+pub use virtio::pci::VirtioPci;
+pub use virtio::pmem::{pmem_guest_addr, PmemConfig, VirtioPmem, PMEM_MEMORY_SLOT};
+pub use virtio::vhost_user_device::VhostUserDevice;
+pub use virtio::vsock::{VirtioVsock, VsockConfig};
+pub use virtio::Transport;
 
 /// I/O port range for COM1 serial port.
 pub const SERIAL_COM1_BASE: u16 = 0x3f8;
 pub const SERIAL_COM1_END: u16 = 0x3ff;
+
+/// GSI of the COM1 serial port's interrupt (ISA IRQ 4).
+pub const SERIAL_COM1_IRQ: u32 = 4;
+
+/// I/O port range for COM2 serial port.
+pub const SERIAL_COM2_BASE: u16 = 0x2f8;
+pub const SERIAL_COM2_END: u16 = 0x2ff;
+
+/// GSI of the COM2 serial port's interrupt (ISA IRQ 3).
+pub const SERIAL_COM2_IRQ: u32 = 3;
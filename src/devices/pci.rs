@@ -0,0 +1,416 @@
+//! PCI host bridge and configuration space.
+//!
+//! Devices can be attached over PCI instead of virtio-mmio (see
+//! [`super::virtio::pci`]), for guest kernels built without virtio-mmio.
+//! The bus is as small as that needs: segment 0 with bus 0 only, its
+//! configuration space memory-mapped (ECAM, described to the guest by the
+//! ACPI MCFG table), a host bridge at 00.0, and single-function devices
+//! with one 32-bit memory BAR each.
+//!
+//! # Memory Layout
+//!
+//! ```text
+//! 0xe000_0000 - 0xe00F_FFFF  ECAM: 4KB of config space per function, bus 0
+//! 0xe010_0000 - 0xe01F_FFFF  BAR window: each device's BAR0, in slot order
+//! ```
+//!
+//! Both sit on the MMIO bus as a single [`PciBus`]. BARs are assigned up
+//! front, like firmware would, and accesses are routed by their current
+//! values, so the guest may move them within the window.
+//!
+//! # Interrupts
+//!
+//! There is no MSI-X: each device raises INTA, which the DSDT routes to its
+//! own GSI through a PCI interrupt link.
+
+use super::mmio::MmioDevice;
+
+/// Base of the ECAM region (bus 0).
+pub const PCI_ECAM_BASE: u64 = 0xe000_0000;
+
+/// Size of the ECAM region: 32 devices × 8 functions × 4KB, for bus 0.
+pub const PCI_ECAM_SIZE: u64 = 0x10_0000;
+
+/// Base of the window BARs are placed in.
+pub const PCI_MMIO_BASE: u64 = PCI_ECAM_BASE + PCI_ECAM_SIZE;
+
+/// Size of the BAR window.
+pub const PCI_MMIO_SIZE: u64 = 0x10_0000;
+
+/// Devices per bus.
+const PCI_MAX_DEVICES: usize = 32;
+
+// Config space registers
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_CLASS_REVISION: usize = 0x08; // revision, then class (3 bytes)
+const PCI_BAR0: usize = 0x10;
+const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+const PCI_SUBSYSTEM_ID: usize = 0x2e;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_INTERRUPT_LINE: usize = 0x3c;
+const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+/// Size of the config space we implement; extended config space reads as 0.
+const PCI_CONFIG_SIZE: usize = 256;
+
+/// First capability (the standard header ends here).
+const PCI_CAPABILITY_START: usize = 0x40;
+
+/// Command register: memory space decoding enabled.
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+
+/// Command bits the guest may change: memory space, bus master, INTx
+/// disable.
+const PCI_COMMAND_WRITABLE: u16 = PCI_COMMAND_MEMORY | (1 << 2) | (1 << 10);
+
+/// Status register: the function has a capability list.
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+/// Interrupt pin: INTA.
+const PCI_INTERRUPT_PIN_INTA: u8 = 1;
+
+/// Host bridge identity: Red Hat's generic PCIe host bridge.
+const HOST_BRIDGE_VENDOR_ID: u16 = 0x1b36;
+const HOST_BRIDGE_DEVICE_ID: u16 = 0x0008;
+
+/// Class code of a host bridge (class, subclass, programming interface).
+const PCI_CLASS_BRIDGE_HOST: u32 = 0x06_00_00;
+
+/// Configuration space of one function: the type 0 header and capabilities.
+pub struct PciConfig {
+    bytes: [u8; PCI_CONFIG_SIZE],
+    /// Bits of each byte the guest may change.
+    writable: [u8; PCI_CONFIG_SIZE],
+    /// Size of BAR0, or 0 without one.
+    bar_size: u32,
+    /// Offset of the last capability added, to chain the next one.
+    last_capability: Option<usize>,
+    /// Where the next capability goes.
+    next_capability: usize,
+}
+
+impl PciConfig {
+    /// A header for `vendor_id:device_id` with `class` (class, subclass and
+    /// programming interface, e.g. `0x06_00_00`) and `revision`.
+    pub fn new(vendor_id: u16, device_id: u16, class: u32, revision: u8) -> Self {
+        let mut config = Self {
+            bytes: [0; PCI_CONFIG_SIZE],
+            writable: [0; PCI_CONFIG_SIZE],
+            bar_size: 0,
+            last_capability: None,
+            next_capability: PCI_CAPABILITY_START,
+        };
+        config.set_u16(PCI_VENDOR_ID, vendor_id);
+        config.set_u16(PCI_DEVICE_ID, device_id);
+        config.set_u32(PCI_CLASS_REVISION, (class << 8) | u32::from(revision));
+        config.writable[PCI_COMMAND..PCI_COMMAND + 2]
+            .copy_from_slice(&PCI_COMMAND_WRITABLE.to_le_bytes());
+        config
+    }
+
+    /// Set the subsystem vendor and ID.
+    pub fn set_subsystem(&mut self, vendor_id: u16, id: u16) {
+        self.set_u16(PCI_SUBSYSTEM_VENDOR_ID, vendor_id);
+        self.set_u16(PCI_SUBSYSTEM_ID, id);
+    }
+
+    /// Give the function a 32-bit, non-prefetchable memory BAR0 of `size`
+    /// bytes (a power of two of at least 16). [`PciBus::add`] places it.
+    pub fn add_bar(&mut self, size: u32) {
+        assert!(size.is_power_of_two() && size >= 16, "invalid BAR size");
+        self.bar_size = size;
+        // Only the address bits above the size stick, which is how the
+        // guest sizes the BAR
+        self.writable[PCI_BAR0..PCI_BAR0 + 4].copy_from_slice(&(!(size - 1)).to_le_bytes());
+    }
+
+    /// Raise INTA; `line` is what the guest reads as the interrupt line.
+    pub fn set_interrupt(&mut self, line: u8) {
+        self.bytes[PCI_INTERRUPT_LINE] = line;
+        self.bytes[PCI_INTERRUPT_PIN] = PCI_INTERRUPT_PIN_INTA;
+        self.writable[PCI_INTERRUPT_LINE] = 0xff;
+    }
+
+    /// Append a capability with ID `id`; `data` follows the ID and next
+    /// pointer.
+    pub fn add_capability(&mut self, id: u8, data: &[u8]) {
+        let offset = self.next_capability;
+        assert!(
+            offset + 2 + data.len() <= PCI_CONFIG_SIZE,
+            "capabilities overflow config space"
+        );
+        self.bytes[offset] = id;
+        self.bytes[offset + 2..offset + 2 + data.len()].copy_from_slice(data);
+        match self.last_capability {
+            Some(last) => self.bytes[last + 1] = offset as u8,
+            None => {
+                self.bytes[PCI_CAPABILITY_LIST] = offset as u8;
+                self.set_u16(PCI_STATUS, PCI_STATUS_CAP_LIST);
+            }
+        }
+        self.last_capability = Some(offset);
+        self.next_capability = (offset + 2 + data.len()).next_multiple_of(4);
+    }
+
+    /// Where BAR0 is and how big it is, if it decodes.
+    fn memory_bar(&self) -> Option<(u64, u64)> {
+        let command = u16::from_le_bytes([self.bytes[PCI_COMMAND], self.bytes[PCI_COMMAND + 1]]);
+        if self.bar_size == 0 || command & PCI_COMMAND_MEMORY == 0 {
+            return None;
+        }
+        let bar = u32::from_le_bytes(self.bytes[PCI_BAR0..PCI_BAR0 + 4].try_into().unwrap());
+        Some((u64::from(bar & !0xf), u64::from(self.bar_size)))
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.bytes.get(offset + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        for (i, &value) in data.iter().enumerate() {
+            let Some(mask) = self.writable.get(offset + i) else {
+                return;
+            };
+            let byte = &mut self.bytes[offset + i];
+            *byte = (*byte & !mask) | (value & mask);
+        }
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Function 0 of a device on the bus.
+struct PciFunction {
+    config: PciConfig,
+    /// Handles BAR0 accesses, at offsets within the BAR.
+    bar: Option<Box<dyn MmioDevice>>,
+}
+
+/// Bus 0 behind the host bridge, serving ECAM and the BAR window.
+///
+/// Registered on the MMIO bus at [`PCI_ECAM_BASE`], covering
+/// [`PCI_ECAM_SIZE`] + [`PCI_MMIO_SIZE`] bytes.
+pub struct PciBus {
+    /// Indexed by device number; 0 is the host bridge.
+    devices: Vec<PciFunction>,
+    /// Where the next BAR goes.
+    next_bar: u64,
+}
+
+impl PciBus {
+    /// A bus with just the host bridge.
+    pub fn new() -> Self {
+        let host_bridge = PciConfig::new(
+            HOST_BRIDGE_VENDOR_ID,
+            HOST_BRIDGE_DEVICE_ID,
+            PCI_CLASS_BRIDGE_HOST,
+            0,
+        );
+        Self {
+            devices: vec![PciFunction {
+                config: host_bridge,
+                bar: None,
+            }],
+            next_bar: PCI_MMIO_BASE,
+        }
+    }
+
+    /// Attach a device with `config`, whose BAR0 accesses go to `bar`, and
+    /// return its device number. Devices are numbered in the order they are
+    /// added, from 1.
+    ///
+    /// # Panics
+    ///
+    /// If the bus or the BAR window is full.
+    pub fn add(&mut self, mut config: PciConfig, bar: Box<dyn MmioDevice>) -> u8 {
+        assert!(self.devices.len() < PCI_MAX_DEVICES, "PCI bus is full");
+        let size = u64::from(config.bar_size);
+        if size != 0 {
+            let addr = self.next_bar.next_multiple_of(size);
+            assert!(
+                addr + size <= PCI_MMIO_BASE + PCI_MMIO_SIZE,
+                "PCI BAR window is full"
+            );
+            config.set_u32(PCI_BAR0, addr as u32);
+            self.next_bar = addr + size;
+        }
+        // Decode memory from the start, as firmware would leave it
+        config.set_u16(PCI_COMMAND, PCI_COMMAND_MEMORY);
+        self.devices.push(PciFunction {
+            config,
+            bar: Some(bar),
+        });
+        (self.devices.len() - 1) as u8
+    }
+
+    /// Whether any device besides the host bridge is attached.
+    pub fn is_empty(&self) -> bool {
+        self.devices.len() == 1
+    }
+
+    /// The function an ECAM offset addresses, and the register within it.
+    fn config_function(&mut self, offset: u64) -> Option<(&mut PciConfig, usize)> {
+        let device = (offset >> 15) as usize & 0x1f;
+        let function = (offset >> 12) & 0x7;
+        if function != 0 {
+            return None;
+        }
+        let register = (offset & 0xfff) as usize;
+        self.devices
+            .get_mut(device)
+            .map(|f| (&mut f.config, register))
+    }
+
+    /// The device whose BAR0 decodes `addr`, and the offset within it.
+    fn bar_device(&mut self, addr: u64) -> Option<(&mut dyn MmioDevice, u64)> {
+        let function = self.devices.iter_mut().find(|f| {
+            f.config
+                .memory_bar()
+                .is_some_and(|(base, size)| (base..base + size).contains(&addr))
+        })?;
+        let (base, _) = function.config.memory_bar()?;
+        Some((function.bar.as_deref_mut()?, addr - base))
+    }
+}
+
+impl Default for PciBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MmioDevice for PciBus {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let found = if offset < PCI_ECAM_SIZE {
+            self.config_function(offset)
+                .map(|(config, register)| config.read(register, data))
+        } else {
+            self.bar_device(PCI_ECAM_BASE + offset)
+                .map(|(device, offset)| device.read(offset, data))
+        };
+        // Absent functions and unclaimed addresses read as all-ones
+        if found.is_none() {
+            data.fill(0xff);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset < PCI_ECAM_SIZE {
+            if let Some((config, register)) = self.config_function(offset) {
+                config.write(register, data);
+            }
+        } else if let Some((device, offset)) = self.bar_device(PCI_ECAM_BASE + offset) {
+            device.write(offset, data);
+        }
+    }
+
+    fn retry(&mut self) {
+        for function in &mut self.devices {
+            if let Some(bar) = &mut function.bar {
+                bar.retry();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// BAR0 that remembers the last write.
+    struct Register(u32);
+
+    impl MmioDevice for Register {
+        fn read(&mut self, _offset: u64, data: &mut [u8]) {
+            data.copy_from_slice(&self.0.to_le_bytes()[..data.len()]);
+        }
+
+        fn write(&mut self, _offset: u64, data: &[u8]) {
+            self.0 = u32::from_le_bytes(data.try_into().unwrap());
+        }
+    }
+
+    fn read_u32(bus: &mut PciBus, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        bus.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    /// ECAM offset of a register of device `device`, function 0.
+    fn ecam(device: u64, register: u64) -> u64 {
+        (device << 15) | register
+    }
+
+    #[test]
+    fn test_host_bridge_and_absent_devices() {
+        let mut bus = PciBus::new();
+        assert!(bus.is_empty());
+        assert_eq!(read_u32(&mut bus, ecam(0, 0)), 0x0008_1b36);
+        assert_eq!(read_u32(&mut bus, ecam(0, 8)) >> 8, PCI_CLASS_BRIDGE_HOST);
+        assert_eq!(read_u32(&mut bus, ecam(1, 0)), 0xffff_ffff);
+        // Function 1 of the host bridge doesn't exist either
+        assert_eq!(read_u32(&mut bus, 1 << 12), 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_bar_sizing_and_routing() {
+        let mut bus = PciBus::new();
+        let mut config = PciConfig::new(0x1af4, 0x1042, 0, 1);
+        config.add_bar(0x4000);
+        config.set_interrupt(5);
+        assert_eq!(bus.add(config, Box::new(Register(7))), 1);
+
+        let bar = PCI_MMIO_BASE - PCI_ECAM_BASE;
+        assert_eq!(read_u32(&mut bus, ecam(1, 0x10)), PCI_MMIO_BASE as u32);
+        assert_eq!(read_u32(&mut bus, bar), 7);
+        bus.write(bar, &9u32.to_le_bytes());
+        assert_eq!(read_u32(&mut bus, bar), 9);
+
+        // Sizing: all-ones reads back the size mask
+        bus.write(ecam(1, 0x10), &u32::MAX.to_le_bytes());
+        assert_eq!(read_u32(&mut bus, ecam(1, 0x10)), !(0x4000 - 1));
+
+        // Moved, and with memory decoding off, it no longer answers
+        let moved = PCI_MMIO_BASE as u32 + 0x8000;
+        bus.write(ecam(1, 0x10), &moved.to_le_bytes());
+        assert_eq!(read_u32(&mut bus, bar + 0x8000), 9);
+        bus.write(ecam(1, 0x04), &0u16.to_le_bytes());
+        assert_eq!(read_u32(&mut bus, bar + 0x8000), 0xffff_ffff);
+
+        // Read-only registers stay put
+        bus.write(ecam(1, 0), &0u32.to_le_bytes());
+        assert_eq!(read_u32(&mut bus, ecam(1, 0)), 0x1042_1af4);
+        assert_eq!(read_u32(&mut bus, ecam(1, 0x3c)) & 0xffff, 0x0105);
+    }
+
+    #[test]
+    fn test_capability_chain() {
+        let mut config = PciConfig::new(0x1af4, 0x1042, 0, 1);
+        // 16 bytes, then a second one at the next dword
+        config.add_capability(0x09, &[0xaa; 14]);
+        config.add_capability(0x09, &[0xbb; 3]);
+
+        let mut data = [0u8; 2];
+        config.read(PCI_STATUS, &mut data);
+        assert_ne!(u16::from_le_bytes(data) & PCI_STATUS_CAP_LIST, 0);
+        config.read(PCI_CAPABILITY_LIST, &mut data[..1]);
+        assert_eq!(data[0], 0x40);
+        config.read(0x40, &mut data);
+        assert_eq!(data, [0x09, 0x50]);
+        config.read(0x50, &mut data);
+        assert_eq!(data, [0x09, 0]);
+        config.read(0x4f, &mut data);
+        assert_eq!(data, [0xaa, 0x09]);
+    }
+}
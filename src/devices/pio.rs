@@ -0,0 +1,219 @@
+//! Port I/O bus for the legacy PC devices.
+//!
+//! This module routes IN and OUT instructions to the device that owns the
+//! port, the way [`MmioBus`](super::MmioBus) routes MMIO accesses.
+//!
+//! # Port Layout
+//!
+//! ```text
+//! 0x0060, 0x0064   i8042 keyboard controller, for reset only (see `reset`)
+//! 0x0070 - 0x0071  CMOS RTC index and data (see `cmos`)
+//! 0x02f8 - 0x02ff  COM2 serial port, when attached
+//! 0x03f8 - 0x03ff  COM1 serial port
+//! 0x0505           pvpanic (see `pvpanic`)
+//! 0x0510 - 0x051b  fw_cfg selector, data and DMA, when blobs are passed
+//! 0x0cf9           reset control register (see `reset`)
+//! ```
+//!
+//! Device plugins claim ranges of their own, and `--debug-exit` a port of
+//! its choosing. Where ranges overlap, the device registered first takes
+//! the access: the VMM registers the debug exit port first, so it wins
+//! over built-in devices.
+
+use crate::snapshot::DeviceState;
+use std::sync::{Arc, Mutex};
+
+/// Trait for devices that respond to port I/O.
+///
+/// Implementors handle INs and OUTs to their ports. The offset is relative
+/// to the first port of the range the device was registered at.
+pub trait PioDevice: Send {
+    /// Handle an IN of `data.len()` bytes (1, 2 or 4) at the given offset.
+    fn read(&mut self, offset: u16, data: &mut [u8]);
+
+    /// Handle an OUT of `data` at the given offset.
+    fn write(&mut self, offset: u16, data: &[u8]);
+
+    /// The device's state, for a snapshot (see [`crate::snapshot`]).
+    /// Devices with none the guest would miss keep the default of `None`.
+    fn save_state(&self) -> Option<DeviceState> {
+        None
+    }
+
+    /// Take back a state [`save_state`](Self::save_state) returned, in a
+    /// device built from the same configuration.
+    fn restore_state(&mut self, _state: &DeviceState) -> Result<(), String> {
+        Err("the device has no state to restore".into())
+    }
+}
+
+/// A device the VMM also keeps a handle to, to see what the guest asked of
+/// it (e.g. a reset).
+impl<T: PioDevice> PioDevice for Arc<Mutex<T>> {
+    fn read(&mut self, offset: u16, data: &mut [u8]) {
+        lock(self).read(offset, data);
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) {
+        lock(self).write(offset, data);
+    }
+
+    fn save_state(&self) -> Option<DeviceState> {
+        lock(self).save_state()
+    }
+
+    fn restore_state(&mut self, state: &DeviceState) -> Result<(), String> {
+        lock(self).restore_state(state)
+    }
+}
+
+/// A registered device on the port I/O bus.
+struct PioDeviceEntry {
+    /// First port of the device's range.
+    base: u16,
+    /// Number of ports in the range.
+    count: u16,
+    /// The device implementation.
+    device: Box<dyn PioDevice>,
+}
+
+/// Port I/O bus that routes INs and OUTs to registered devices.
+pub struct PioBus {
+    /// Registered devices, in registration order.
+    devices: Vec<PioDeviceEntry>,
+}
+
+impl PioBus {
+    /// Create a new empty port I/O bus.
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Register a device on the bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - First port of the device's range
+    /// * `count` - Number of ports in the range
+    /// * `device` - The device implementation
+    pub fn register(&mut self, base: u16, count: u16, device: Box<dyn PioDevice>) {
+        self.devices.push(PioDeviceEntry {
+            base,
+            count,
+            device,
+        });
+    }
+
+    /// The state of each registered device that has any, by first port.
+    pub fn save_state(&self) -> Vec<(u16, DeviceState)> {
+        self.devices
+            .iter()
+            .filter_map(|entry| Some((entry.base, entry.device.save_state()?)))
+            .collect()
+    }
+
+    /// Restore the device registered at `base` to `state`.
+    pub fn restore_state(&mut self, base: u16, state: &DeviceState) -> Result<(), String> {
+        let entry = self
+            .devices
+            .iter_mut()
+            .find(|entry| entry.base == base)
+            .ok_or_else(|| format!("no port I/O device at {base:#x}"))?;
+        entry
+            .device
+            .restore_state(state)
+            .map_err(|e| format!("port I/O device at {base:#x}: {e}"))
+    }
+
+    /// Find the device that handles the given port.
+    fn find_device(&mut self, port: u16) -> Option<(&mut dyn PioDevice, u16)> {
+        for entry in &mut self.devices {
+            let offset = port.wrapping_sub(entry.base);
+            if offset < entry.count {
+                return Some((entry.device.as_mut(), offset));
+            }
+        }
+        None
+    }
+
+    /// Handle an IN from the guest. Returns false if no device has the
+    /// port.
+    pub fn read(&mut self, port: u16, data: &mut [u8]) -> bool {
+        if let Some((device, offset)) = self.find_device(port) {
+            device.read(offset, data);
+            true
+        } else {
+            // Return 0xff for unhandled ports, as a floating ISA bus does
+            data.fill(0xff);
+            false
+        }
+    }
+
+    /// Handle an OUT from the guest. Returns false if no device has the
+    /// port.
+    pub fn write(&mut self, port: u16, data: &[u8]) -> bool {
+        if let Some((device, offset)) = self.find_device(port) {
+            device.write(offset, data);
+            true
+        } else {
+            // Writes to unhandled ports are silently ignored
+            false
+        }
+    }
+}
+
+impl Default for PioBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockDevice {
+        registers: [u8; 4],
+    }
+
+    impl PioDevice for MockDevice {
+        fn read(&mut self, offset: u16, data: &mut [u8]) {
+            data.fill(self.registers[usize::from(offset)]);
+        }
+
+        fn write(&mut self, offset: u16, data: &[u8]) {
+            self.registers[usize::from(offset)] = data[0];
+        }
+    }
+
+    #[test]
+    fn test_pio_bus() {
+        let mut bus = PioBus::new();
+        let shared = Arc::new(Mutex::new(MockDevice::default()));
+        // Registered first, so it takes 0x3f8 from the device after it
+        bus.register(0x3f8, 1, Box::new(shared.clone()));
+        bus.register(0x3f8, 4, Box::<MockDevice>::default());
+
+        // Offsets are relative to the device's first port
+        assert!(bus.write(0x3fa, &[0x42]));
+        let mut data = [0u8; 2];
+        assert!(bus.read(0x3fa, &mut data));
+        assert_eq!(data, [0x42; 2]);
+
+        assert!(bus.write(0x3f8, &[0x17]));
+        assert_eq!(lock(&shared).registers[0], 0x17);
+
+        // Unhandled ports read as 0xff, including just past a range
+        let mut data = [0u8; 1];
+        assert!(!bus.read(0x3fc, &mut data));
+        assert_eq!(data, [0xff]);
+        assert!(!bus.write(0x3f7, &[0]));
+    }
+}
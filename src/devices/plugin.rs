@@ -0,0 +1,428 @@
+//! Out-of-process device plugins.
+//!
+//! A device plugin is an executable that emulates MMIO and/or port I/O
+//! devices for the guest, so bespoke devices (a policy switch, a telemetry
+//! counter, ...) don't need changes to Carbon itself. `--device-plugin PATH`
+//! starts the program and talks to it over its stdin and stdout; its stderr
+//! is passed through. Running devices out of process keeps a buggy plugin
+//! from corrupting the VMM, and lets plugins be written in any language.
+//!
+//! # Protocol (version 1)
+//!
+//! Line-based ASCII. Numbers are decimal or `0x`-prefixed hex; values are the
+//! access's bytes as a little-endian integer.
+//!
+//! Handshake: Carbon sends `carbon-device-plugin 1`. The plugin answers with
+//! the regions it wants, one per line, then `ready`:
+//!
+//! ```text
+//! mmio 0xd1000000 0x1000      MMIO region: base, size
+//! pio 0x510 2                 I/O ports: first port, count
+//! ready
+//! ```
+//!
+//! Then, for every guest access to one of those regions:
+//!
+//! ```text
+//! read mmio 0xd1000004 4      → plugin replies with the value, e.g. 0x2a
+//! write pio 0x510 1 0x7       → no reply
+//! ```
+//!
+//! Addresses are absolute. The vCPU waits for each read's reply, so plugins
+//! should answer promptly. When the VM stops, Carbon closes the plugin's
+//! stdin and then kills it.
+//!
+//! MMIO regions must lie in [`PLUGIN_MMIO_BASE`]..[`PLUGIN_MMIO_END`], and
+//! port ranges must not overlap Carbon's own devices. Ports KVM emulates in
+//! the kernel (PIC, PIT) never reach plugins. If a plugin dies or replies
+//! with garbage, the failure is logged and its reads return all-ones, like
+//! unmapped I/O.
+
+use super::mmio::MmioDevice;
+use super::PioDevice;
+use crate::audit;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// Start of the guest-physical window plugin MMIO regions must lie in.
+pub const PLUGIN_MMIO_BASE: u64 = 0xd100_0000;
+
+/// End (exclusive) of the plugin MMIO window.
+pub const PLUGIN_MMIO_END: u64 = 0xd200_0000;
+
+/// First line Carbon sends to a plugin.
+const HANDSHAKE: &str = "carbon-device-plugin 1";
+
+/// Largest number of regions one plugin may claim.
+const MAX_REGIONS: usize = 64;
+
+/// Address space a region lives in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    Mmio,
+    Pio,
+}
+
+impl Space {
+    fn name(self) -> &'static str {
+        match self {
+            Space::Mmio => "mmio",
+            Space::Pio => "pio",
+        }
+    }
+}
+
+/// A region claimed by a plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub space: Space,
+    pub range: Range<u64>,
+}
+
+impl Region {
+    /// Whether the two regions share an address.
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.space == other.space
+            && self.range.start < other.range.end
+            && other.range.start < self.range.end
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:#x}..{:#x}",
+            self.space.name(),
+            self.range.start,
+            self.range.end
+        )
+    }
+}
+
+/// The running plugin process.
+struct Process {
+    path: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    /// Set after the first failure, so a dead plugin is reported once.
+    failed: bool,
+}
+
+impl Process {
+    fn read(&mut self, space: Space, addr: u64, data: &mut [u8]) {
+        let value = self.exchange(space, addr, data.len()).unwrap_or(u64::MAX);
+        data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+    }
+
+    fn exchange(&mut self, space: Space, addr: u64, len: usize) -> Option<u64> {
+        let request = format!("read {} {:#x} {}", space.name(), addr, len);
+        let result = self.send(&request).and_then(|()| {
+            let line = read_line(&mut self.stdout)?;
+            parse_number(&line)
+                .ok_or_else(|| io::Error::other(format!("bad reply to {request:?}: {line:?}")))
+        });
+        self.check(result)
+    }
+
+    fn write(&mut self, space: Space, addr: u64, data: &[u8]) {
+        let mut bytes = [0u8; 8];
+        bytes[..data.len()].copy_from_slice(data);
+        let request = format!(
+            "write {} {:#x} {} {:#x}",
+            space.name(),
+            addr,
+            data.len(),
+            u64::from_le_bytes(bytes)
+        );
+        let result = self.send(&request);
+        self.check(result);
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        writeln!(stdin, "{line}")?;
+        stdin.flush()
+    }
+
+    fn check<T>(&mut self, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                if !self.failed {
+                    warn!("[plugin] {} failed: {}", self.path, e);
+                    self.failed = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // Closing stdin asks the plugin to exit; don't wait on a stuck one
+        self.stdin.take();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        audit::record(audit::Kind::Process, "exit", &self.path);
+    }
+}
+
+/// A started device plugin.
+pub struct Plugin {
+    process: Arc<Mutex<Process>>,
+    regions: Vec<Region>,
+}
+
+impl Plugin {
+    /// Start the plugin at `path` and read the regions it claims.
+    pub fn spawn(path: &str) -> io::Result<Self> {
+        let mut child = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        audit::record(audit::Kind::Process, "spawn", path);
+        let mut process = Process {
+            path: path.to_string(),
+            stdin: child.stdin.take(),
+            stdout: BufReader::new(child.stdout.take().expect("stdout is piped")),
+            child,
+            failed: false,
+        };
+
+        process.send(HANDSHAKE)?;
+        let mut regions = Vec::new();
+        loop {
+            let line = read_line(&mut process.stdout)?;
+            if line == "ready" {
+                break;
+            }
+            if regions.len() == MAX_REGIONS {
+                return Err(invalid(format!("more than {MAX_REGIONS} regions")));
+            }
+            let region =
+                parse_region(&line).ok_or_else(|| invalid(format!("bad region line {line:?}")))?;
+            validate(&region)?;
+            regions.push(region);
+        }
+
+        Ok(Self {
+            process: Arc::new(Mutex::new(process)),
+            regions,
+        })
+    }
+
+    /// Regions the plugin claimed.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// MMIO devices for the plugin's MMIO regions, with their base and size.
+    pub fn mmio_devices(&self) -> Vec<(u64, u64, PluginMmio)> {
+        self.regions
+            .iter()
+            .filter(|r| r.space == Space::Mmio)
+            .map(|r| {
+                let device = PluginMmio {
+                    process: self.process.clone(),
+                    base: r.range.start,
+                };
+                (r.range.start, r.range.end - r.range.start, device)
+            })
+            .collect()
+    }
+
+    /// Port I/O devices for the plugin's port ranges, with their first port
+    /// and count.
+    pub fn port_devices(&self) -> Vec<(u16, u16, PluginPorts)> {
+        self.regions
+            .iter()
+            .filter(|r| r.space == Space::Pio)
+            .map(|r| {
+                let device = PluginPorts {
+                    process: self.process.clone(),
+                    base: r.range.start as u16,
+                };
+                let count = (r.range.end - r.range.start) as u16;
+                (r.range.start as u16, count, device)
+            })
+            .collect()
+    }
+}
+
+/// One plugin MMIO region on the [`super::MmioBus`].
+pub struct PluginMmio {
+    process: Arc<Mutex<Process>>,
+    base: u64,
+}
+
+impl MmioDevice for PluginMmio {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        lock(&self.process).read(Space::Mmio, self.base + offset, data);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        lock(&self.process).write(Space::Mmio, self.base + offset, data);
+    }
+}
+
+/// One plugin port range on the [`super::PioBus`].
+pub struct PluginPorts {
+    process: Arc<Mutex<Process>>,
+    base: u16,
+}
+
+impl PioDevice for PluginPorts {
+    fn read(&mut self, offset: u16, data: &mut [u8]) {
+        let port = self.base + offset;
+        lock(&self.process).read(Space::Pio, port.into(), data);
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) {
+        let port = self.base + offset;
+        lock(&self.process).write(Space::Pio, port.into(), data);
+    }
+}
+
+fn lock(process: &Mutex<Process>) -> std::sync::MutexGuard<'_, Process> {
+    process.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Ports Carbon emulates itself (serial COM1, CMOS).
+const RESERVED_PORTS: &[Range<u64>] = &[0x3f8..0x400, 0x70..0x72];
+
+fn validate(region: &Region) -> io::Result<()> {
+    let Range { start, end } = region.range;
+    if start >= end {
+        return Err(invalid(format!("empty region at {start:#x}")));
+    }
+    let outside = match region.space {
+        Space::Mmio => start < PLUGIN_MMIO_BASE || end > PLUGIN_MMIO_END,
+        Space::Pio => end > 0x1_0000,
+    };
+    if outside {
+        return Err(invalid(format!(
+            "{region} is outside the range plugins may use"
+        )));
+    }
+    if region.space == Space::Pio
+        && RESERVED_PORTS
+            .iter()
+            .any(|r| r.start < end && start < r.end)
+    {
+        return Err(invalid(format!("{region} overlaps a built-in device")));
+    }
+    Ok(())
+}
+
+fn parse_region(line: &str) -> Option<Region> {
+    let mut words = line.split_whitespace();
+    let space = match words.next()? {
+        "mmio" => Space::Mmio,
+        "pio" => Space::Pio,
+        _ => return None,
+    };
+    let start = parse_number(words.next()?)?;
+    let len = parse_number(words.next()?)?;
+    if words.next().is_some() {
+        return None;
+    }
+    Some(Region {
+        space,
+        range: start..start.checked_add(len)?,
+    })
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "plugin closed its stdout",
+        ));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_region() {
+        assert_eq!(
+            parse_region("mmio 0xd1000000 0x1000"),
+            Some(Region {
+                space: Space::Mmio,
+                range: 0xd100_0000..0xd100_1000
+            })
+        );
+        assert_eq!(parse_region("pio 1296 2").unwrap().range, 0x510..0x512);
+        assert_eq!(parse_region("dma 0 1"), None);
+        assert_eq!(parse_region("pio 0x510"), None);
+    }
+
+    #[test]
+    fn test_validate_rejects_conflicts() {
+        let region = |line| validate(&parse_region(line).unwrap());
+        assert!(region("mmio 0xd1000000 0x1000").is_ok());
+        assert!(region("mmio 0xd0000000 0x1000").is_err());
+        assert!(region("pio 0x3f0 0x10").is_err());
+        assert!(region("pio 0x510 0").is_err());
+    }
+
+    #[test]
+    fn test_plugin_process_roundtrip() {
+        // A register at 0x510 that reads back what was last written
+        let script = std::env::temp_dir().join(format!("carbon-plugin-{}", std::process::id()));
+        std::fs::write(
+            &script,
+            "#!/bin/sh\n\
+             read hello\n\
+             echo 'pio 0x510 1'\n\
+             echo ready\n\
+             value=0\n\
+             while read op space addr len arg; do\n\
+               if [ \"$op\" = write ]; then value=$arg; else echo $value; fi\n\
+             done\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+
+        let plugin = Plugin::spawn(script.to_str().unwrap()).unwrap();
+        assert_eq!(plugin.regions().len(), 1);
+        let mut ports = plugin.port_devices();
+        let (base, count, device) = &mut ports[0];
+        assert_eq!((*base, *count), (0x510, 1));
+
+        device.write(0, &[0x2a]);
+        let mut data = [0u8; 1];
+        device.read(0, &mut data);
+        assert_eq!(data, [0x2a]);
+        drop(ports);
+        drop(plugin);
+        std::fs::remove_file(script).ok();
+    }
+}
@@ -0,0 +1,102 @@
+//! pvpanic device for guest panic notification.
+//!
+//! A single I/O port the guest kernel writes to from its panic notifier, so
+//! the VMM can tell "the guest panicked" apart from a normal shutdown
+//! (`panic=-1` reboots either way). It is described in the DSDT as ACPI
+//! device `QEMU0001`, which Linux binds with `CONFIG_PVPANIC_MMIO`.
+//!
+//! Reading the port returns the events the device supports; the guest
+//! writes the ones that happen:
+//!
+//! - `PANICKED` (bit 0): the kernel panicked.
+//! - `CRASH_LOADED` (bit 1): the kernel panicked, and a kdump kernel is
+//!   taking over to save a crash dump. The VM keeps running.
+//!
+//! Reference: QEMU `docs/specs/pvpanic.rst`
+
+use super::PioDevice;
+
+/// I/O port of the device (QEMU's default).
+pub const PVPANIC_PORT: u16 = 0x505;
+
+/// Event: the guest panicked.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+
+/// Event: the guest panicked and is loading a crash kernel.
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// A panic reported by the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicEvent {
+    /// The guest kernel panicked.
+    Panicked,
+    /// The guest kernel panicked and is booting a crash kernel.
+    CrashLoaded,
+}
+
+/// pvpanic device state.
+#[derive(Debug, Default)]
+pub struct Pvpanic {
+    /// The last event written, until taken.
+    event: Option<PanicEvent>,
+}
+
+impl Pvpanic {
+    /// Create a pvpanic device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle a read: the supported events.
+    pub fn read(&self) -> u8 {
+        PVPANIC_PANICKED | PVPANIC_CRASH_LOADED
+    }
+
+    /// Handle a write of `events`.
+    pub fn write(&mut self, events: u8) {
+        // A crash kernel taking over is the more specific report
+        if events & PVPANIC_CRASH_LOADED != 0 {
+            self.event = Some(PanicEvent::CrashLoaded);
+        } else if events & PVPANIC_PANICKED != 0 {
+            self.event = Some(PanicEvent::Panicked);
+        }
+    }
+
+    /// The event the guest reported since the last call, if any.
+    pub fn take_event(&mut self) -> Option<PanicEvent> {
+        self.event.take()
+    }
+}
+
+impl PioDevice for Pvpanic {
+    fn read(&mut self, _offset: u16, data: &mut [u8]) {
+        data.fill(Pvpanic::read(self));
+    }
+
+    fn write(&mut self, _offset: u16, data: &[u8]) {
+        Pvpanic::write(self, data[0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let mut pvpanic = Pvpanic::new();
+        assert_eq!(pvpanic.read(), 0b11);
+        assert_eq!(pvpanic.take_event(), None);
+
+        // Unknown events are ignored
+        pvpanic.write(1 << 2);
+        assert_eq!(pvpanic.take_event(), None);
+
+        pvpanic.write(PVPANIC_PANICKED);
+        assert_eq!(pvpanic.take_event(), Some(PanicEvent::Panicked));
+        assert_eq!(pvpanic.take_event(), None);
+
+        pvpanic.write(PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+        assert_eq!(pvpanic.take_event(), Some(PanicEvent::CrashLoaded));
+    }
+}
@@ -0,0 +1,155 @@
+//! Reset ports: the i8042 keyboard controller and the reset control
+//! register.
+//!
+//! A guest reboots by asking the platform to reset the CPU. We emulate just
+//! enough of the two classic ways to ask that the request is seen as such,
+//! instead of as the triple fault a guest falls back to:
+//!
+//! - **i8042** (ports 0x60/0x64): writing command 0xFE pulses the reset
+//!   line. Linux uses it with `reboot=k`, which the VMM passes. The status
+//!   register always reads as idle, so the guest never waits for it.
+//! - **Reset control register** (port 0xCF9): writing a value with the
+//!   RST_CPU bit set resets the system (`reboot=pci`).
+//!
+//! There is no keyboard: the guest finds no PS/2 controller in ACPI and
+//! doesn't probe one.
+
+use super::PioDevice;
+
+/// i8042 data port.
+pub const I8042_DATA_PORT: u16 = 0x60;
+
+/// i8042 command (write) and status (read) port.
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+
+/// Ports the i8042 is registered at, from [`I8042_DATA_PORT`].
+pub const I8042_PORT_COUNT: u16 = I8042_COMMAND_PORT - I8042_DATA_PORT + 1;
+
+/// Reset control register port.
+pub const RESET_CONTROL_PORT: u16 = 0xcf9;
+
+/// i8042 command: pulse the CPU reset line.
+const I8042_CMD_RESET: u8 = 0xfe;
+
+/// Reset control register: reset the CPU.
+const RESET_CONTROL_RST_CPU: u8 = 1 << 2;
+
+/// Reset control register bits the guest may set: SYS_RST, RST_CPU and
+/// FULL_RST.
+const RESET_CONTROL_MASK: u8 = 0x0e;
+
+/// The i8042 keyboard controller, registered at [`I8042_DATA_PORT`] for
+/// [`I8042_PORT_COUNT`] ports. The ports between data and command aren't
+/// its own (KVM's PIT handles 0x61), and read as unhandled.
+#[derive(Debug, Default)]
+pub struct I8042 {
+    /// The guest asked for a reset, until taken.
+    reset_requested: bool,
+}
+
+impl I8042 {
+    /// Create the keyboard controller.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the guest asked for a reset since the last call.
+    pub fn take_reset(&mut self) -> bool {
+        std::mem::take(&mut self.reset_requested)
+    }
+}
+
+impl PioDevice for I8042 {
+    fn read(&mut self, offset: u16, data: &mut [u8]) {
+        match offset + I8042_DATA_PORT {
+            // No data, and ready for commands
+            I8042_DATA_PORT | I8042_COMMAND_PORT => data.fill(0),
+            _ => data.fill(0xff),
+        }
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) {
+        // Other keyboard controller commands and data are ignored
+        if offset + I8042_DATA_PORT == I8042_COMMAND_PORT && data[0] == I8042_CMD_RESET {
+            self.reset_requested = true;
+        }
+    }
+}
+
+/// The reset control register.
+#[derive(Debug, Default)]
+pub struct ResetControl {
+    /// Last value written.
+    value: u8,
+    /// The guest asked for a reset, until taken.
+    reset_requested: bool,
+}
+
+impl ResetControl {
+    /// Create the reset control register.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the guest asked for a reset since the last call.
+    pub fn take_reset(&mut self) -> bool {
+        std::mem::take(&mut self.reset_requested)
+    }
+}
+
+impl PioDevice for ResetControl {
+    fn read(&mut self, _offset: u16, data: &mut [u8]) {
+        data.fill(self.value);
+    }
+
+    fn write(&mut self, _offset: u16, data: &[u8]) {
+        self.value = data[0] & RESET_CONTROL_MASK;
+        if data[0] & RESET_CONTROL_RST_CPU != 0 {
+            self.reset_requested = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The offset of `port` in the i8042's range.
+    const fn offset(port: u16) -> u16 {
+        port - I8042_DATA_PORT
+    }
+
+    #[test]
+    fn test_i8042_reset() {
+        let mut i8042 = I8042::new();
+        let mut status = [0xffu8];
+        i8042.read(offset(I8042_COMMAND_PORT), &mut status);
+        assert_eq!(status, [0]);
+        // Other commands (e.g. read the command byte) don't reset
+        i8042.write(offset(I8042_COMMAND_PORT), &[0x20]);
+        i8042.write(offset(I8042_DATA_PORT), &[I8042_CMD_RESET]);
+        assert!(!i8042.take_reset());
+
+        i8042.write(offset(I8042_COMMAND_PORT), &[I8042_CMD_RESET]);
+        assert!(i8042.take_reset());
+        assert!(!i8042.take_reset());
+
+        // The ports between aren't the controller's
+        i8042.read(offset(0x61), &mut status);
+        assert_eq!(status, [0xff]);
+    }
+
+    #[test]
+    fn test_reset_control_register() {
+        let mut register = ResetControl::new();
+        // Selecting a hard reset doesn't trigger it yet
+        register.write(0, &[0x02]);
+        let mut value = [0u8];
+        register.read(0, &mut value);
+        assert_eq!(value, [0x02]);
+        assert!(!register.take_reset());
+
+        register.write(0, &[0x06]);
+        assert!(register.take_reset());
+    }
+}
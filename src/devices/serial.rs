@@ -1,9 +1,30 @@
 //! 8250 UART serial port emulation.
 //!
-//! Implements a minimal 8250 UART for console output.
-//! Only supports output (TX) - input is not implemented for milestone 1.
+//! Implements enough of an 8250/16550A UART for an interactive console:
+//!
+//! - **TX**: bytes written to THR go to a caller-supplied writer (stdout by
+//!   default) at once, so the transmitter is always empty.
+//! - **RX**: bytes from the host (see [`Serial::input`]) land in the receive
+//!   FIFO: 16 bytes deep with the FIFO enabled (FCR bit 0), a single holding
+//!   register without. While it is full the host side waits rather than
+//!   overrunning it, so pasted text isn't lost.
+//! - **Interrupts**: received data and THR empty, each enabled in IER, are
+//!   raised on the COM1 line (IRQ 4). The line is edge-triggered, so it is
+//!   pulsed whenever IIR goes from "no interrupt" to pending. With the FIFO
+//!   enabled, data below the trigger level reports a character timeout right
+//!   away instead of after four character times.
+//!
+//! The receive side is shared with the VMM's event loop (or, for input epoll
+//! can't watch, an input thread), so the UART state lives behind a mutex.
 
-use std::io::{self, Write};
+use super::PioDevice;
+use crate::kvm::IrqLine;
+use crate::snapshot::DeviceState;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 /// 8250 UART register offsets
 mod regs {
@@ -25,10 +46,19 @@ mod regs {
     pub const SCR: u16 = 7;
 }
 
+/// Interrupt Enable Register bits
+mod ier {
+    /// Received data available
+    pub const RX_DATA: u8 = 0x01;
+    /// Transmitter Holding Register empty
+    pub const THR_EMPTY: u8 = 0x02;
+    /// Bits that exist on an 8250
+    pub const MASK: u8 = 0x0f;
+}
+
 /// Line Status Register bits
 mod lsr {
     /// Data Ready
-    #[allow(dead_code)]
     pub const DR: u8 = 0x01;
     /// Transmitter Holding Register Empty
     pub const THRE: u8 = 0x20;
@@ -40,10 +70,39 @@ mod lsr {
 mod iir {
     /// No interrupt pending
     pub const NO_INT: u8 = 0x01;
+    /// Transmitter Holding Register empty
+    pub const THR_EMPTY: u8 = 0x02;
+    /// Received data available (at the FIFO trigger level)
+    pub const RX_DATA: u8 = 0x04;
+    /// Character timeout: data below the FIFO trigger level
+    pub const RX_TIMEOUT: u8 = 0x0c;
+    /// FIFOs enabled
+    pub const FIFO_ENABLED: u8 = 0xc0;
 }
 
+/// FIFO Control Register bits
+mod fcr {
+    /// Enable the FIFOs
+    pub const ENABLE: u8 = 0x01;
+    /// Clear the receive FIFO
+    pub const CLEAR_RX: u8 = 0x02;
+    /// Receive FIFO trigger level
+    pub const TRIGGER: u8 = 0xc0;
+}
+
+/// Depth of the receive FIFO (16550A).
+const FIFO_SIZE: usize = 16;
+
+/// How long the host side waits for the guest to drain a full FIFO.
+pub const RX_RETRY_INTERVAL: Duration = Duration::from_millis(2);
+
 /// 8250 UART serial port.
 pub struct Serial {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// UART state, shared with the host side of the receiver.
+struct Inner {
     /// Interrupt Enable Register
     ier: u8,
     /// Line Control Register
@@ -52,55 +111,130 @@ pub struct Serial {
     mcr: u8,
     /// Scratch Register
     scr: u8,
-    /// FIFO Control Register
+    /// FIFO Control Register (enable and trigger level bits)
     fcr: u8,
     /// Divisor Latch (low byte)
     dll: u8,
     /// Divisor Latch (high byte)
     dlh: u8,
+    /// Receive FIFO, oldest byte first
+    rx: VecDeque<u8>,
+    /// THR emptied since the guest last saw the interrupt for it
+    thr_empty_pending: bool,
+    /// Whether the interrupt line is currently asserted
+    irq_asserted: bool,
+    /// Line the interrupt is raised on
+    irq: Option<Arc<dyn IrqLine>>,
+    /// Destination for transmitted bytes
+    output: Box<dyn Write + Send>,
+}
+
+/// A UART's registers and unread input, as a snapshot records them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SerialState {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fcr: u8,
+    dll: u8,
+    dlh: u8,
+    rx: Vec<u8>,
+    thr_empty_pending: bool,
+    irq_asserted: bool,
 }
 
 impl Serial {
+    /// Create a serial port that writes guest output to stdout.
     pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    /// Create a serial port that writes guest output to `output`.
+    pub fn with_output(output: Box<dyn Write + Send>) -> Self {
         Self {
-            ier: 0,
-            lcr: 0,
-            mcr: 0,
-            scr: 0,
-            fcr: 0,
-            dll: 0,
-            dlh: 0,
+            inner: Arc::new(Mutex::new(Inner {
+                ier: 0,
+                lcr: 0,
+                mcr: 0,
+                scr: 0,
+                fcr: 0,
+                dll: 0,
+                dlh: 0,
+                rx: VecDeque::with_capacity(FIFO_SIZE),
+                thr_empty_pending: false,
+                irq_asserted: false,
+                irq: None,
+                output,
+            })),
         }
     }
 
+    /// Set the line the UART's interrupt is raised on.
+    pub fn set_interrupt(&mut self, irq: Arc<dyn IrqLine>) {
+        lock(&self.inner).irq = Some(irq);
+    }
+
+    /// The receiver's host side, for feeding the guest input as it arrives.
+    pub fn input(&self) -> SerialInput {
+        SerialInput(self.inner.clone())
+    }
+
+    /// Feed bytes read from `input` to the guest, from a thread of its own,
+    /// for input that can't be waited on with the rest.
+    ///
+    /// The thread ends at end of input; the guest keeps running without it.
+    pub fn set_input(&mut self, input: Box<dyn Read + Send>) -> io::Result<()> {
+        let inner = self.inner.clone();
+        std::thread::Builder::new()
+            .name("serial-input".into())
+            .spawn(move || forward_input(&inner, input))?;
+        Ok(())
+    }
+
     /// Handle a read from the serial port.
     /// `offset` is the register offset from the base port (0-7).
     pub fn read(&self, offset: u16) -> u8 {
-        let dlab = self.lcr & 0x80 != 0;
+        let mut uart = lock(&self.inner);
+        let dlab = uart.lcr & 0x80 != 0;
 
         match offset {
-            regs::THR_RBR if dlab => self.dll,
+            regs::THR_RBR if dlab => uart.dll,
             regs::THR_RBR => {
-                // No data available (we don't support input)
-                0
+                let byte = uart.rx.pop_front().unwrap_or(0);
+                uart.update_irq();
+                byte
             }
-            regs::IER if dlab => self.dlh,
-            regs::IER => self.ier,
+            regs::IER if dlab => uart.dlh,
+            regs::IER => uart.ier,
             regs::IIR_FCR => {
-                // No interrupt pending
-                iir::NO_INT
+                let id = uart.interrupt_id();
+                // Reading IIR acknowledges a THR empty interrupt
+                if id == iir::THR_EMPTY {
+                    uart.thr_empty_pending = false;
+                    uart.update_irq();
+                }
+                if uart.fifo_enabled() {
+                    id | iir::FIFO_ENABLED
+                } else {
+                    id
+                }
             }
-            regs::LCR => self.lcr,
-            regs::MCR => self.mcr,
+            regs::LCR => uart.lcr,
+            regs::MCR => uart.mcr,
             regs::LSR => {
-                // Always ready to transmit, no data to receive
-                lsr::THRE | lsr::TEMT
+                // Always ready to transmit
+                let mut value = lsr::THRE | lsr::TEMT;
+                if !uart.rx.is_empty() {
+                    value |= lsr::DR;
+                }
+                value
             }
             regs::MSR => {
                 // Carrier Detect, Clear To Send, Data Set Ready
                 0xb0
             }
-            regs::SCR => self.scr,
+            regs::SCR => uart.scr,
             _ => 0,
         }
     }
@@ -108,32 +242,203 @@ impl Serial {
     /// Handle a write to the serial port.
     /// `offset` is the register offset from the base port (0-7).
     pub fn write(&mut self, offset: u16, value: u8) {
-        let dlab = self.lcr & 0x80 != 0;
+        let mut uart = lock(&self.inner);
+        let dlab = uart.lcr & 0x80 != 0;
 
         match offset {
-            regs::THR_RBR if dlab => self.dll = value,
+            regs::THR_RBR if dlab => uart.dll = value,
             regs::THR_RBR => {
-                // Write character to stdout
-                let _ = io::stdout().write_all(&[value]);
-                let _ = io::stdout().flush();
+                // Write character to the console output
+                let _ = uart.output.write_all(&[value]);
+                let _ = uart.output.flush();
+                // Sent at once, so THR is empty again
+                uart.thr_empty_pending = true;
+                uart.update_irq();
             }
-            regs::IER if dlab => self.dlh = value,
-            regs::IER => self.ier = value,
-            regs::IIR_FCR => self.fcr = value,
-            regs::LCR => self.lcr = value,
-            regs::MCR => self.mcr = value,
-            regs::SCR => self.scr = value,
+            regs::IER if dlab => uart.dlh = value,
+            regs::IER => {
+                // Enabling the THR empty interrupt raises it, THR being empty
+                if value & ier::THR_EMPTY != 0 && uart.ier & ier::THR_EMPTY == 0 {
+                    uart.thr_empty_pending = true;
+                }
+                uart.ier = value & ier::MASK;
+                uart.update_irq();
+            }
+            regs::IIR_FCR => {
+                // Turning the FIFOs on or off clears them
+                if (value ^ uart.fcr) & fcr::ENABLE != 0 || value & fcr::CLEAR_RX != 0 {
+                    uart.rx.clear();
+                }
+                uart.fcr = value & (fcr::ENABLE | fcr::TRIGGER);
+                uart.update_irq();
+            }
+            regs::LCR => uart.lcr = value,
+            regs::MCR => uart.mcr = value,
+            regs::SCR => uart.scr = value,
             _ => {}
         }
     }
 }
 
+impl PioDevice for Serial {
+    fn read(&mut self, offset: u16, data: &mut [u8]) {
+        data.fill(Serial::read(self, offset));
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) {
+        for &byte in data {
+            Serial::write(self, offset, byte);
+        }
+    }
+
+    fn save_state(&self) -> Option<DeviceState> {
+        let uart = lock(&self.inner);
+        let state = SerialState {
+            ier: uart.ier,
+            lcr: uart.lcr,
+            mcr: uart.mcr,
+            scr: uart.scr,
+            fcr: uart.fcr,
+            dll: uart.dll,
+            dlh: uart.dlh,
+            rx: uart.rx.iter().copied().collect(),
+            thr_empty_pending: uart.thr_empty_pending,
+            irq_asserted: uart.irq_asserted,
+        };
+        serde_json::to_value(state).ok()
+    }
+
+    fn restore_state(&mut self, state: &DeviceState) -> Result<(), String> {
+        let state = SerialState::deserialize(state).map_err(|e| e.to_string())?;
+        let mut uart = lock(&self.inner);
+        uart.ier = state.ier;
+        uart.lcr = state.lcr;
+        uart.mcr = state.mcr;
+        uart.scr = state.scr;
+        uart.fcr = state.fcr;
+        uart.dll = state.dll;
+        uart.dlh = state.dlh;
+        uart.rx = state.rx.into();
+        uart.thr_empty_pending = state.thr_empty_pending;
+        // The interrupt controller's state came back with the VM's
+        uart.irq_asserted = state.irq_asserted;
+        Ok(())
+    }
+}
+
 impl Default for Serial {
     fn default() -> Self {
         Self::new()
     }
 }
 
+impl Inner {
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & fcr::ENABLE != 0
+    }
+
+    /// How many received bytes the UART holds.
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// Bytes in the FIFO at which received data is reported as available.
+    fn rx_trigger_level(&self) -> usize {
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    /// The highest-priority pending interrupt, as reported in IIR.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & ier::RX_DATA != 0 && !self.rx.is_empty() {
+            if self.fifo_enabled() && self.rx.len() < self.rx_trigger_level() {
+                iir::RX_TIMEOUT
+            } else {
+                iir::RX_DATA
+            }
+        } else if self.ier & ier::THR_EMPTY != 0 && self.thr_empty_pending {
+            iir::THR_EMPTY
+        } else {
+            iir::NO_INT
+        }
+    }
+
+    /// Pulse the interrupt line if an interrupt just became pending.
+    fn update_irq(&mut self) {
+        let pending = self.interrupt_id() != iir::NO_INT;
+        if pending && !self.irq_asserted {
+            if let Some(irq) = &self.irq {
+                irq.trigger();
+            }
+        }
+        self.irq_asserted = pending;
+    }
+
+    /// Queue bytes received from the host, as many as fit in the FIFO.
+    /// Returns how many were taken.
+    fn receive(&mut self, data: &[u8]) -> usize {
+        let count = self
+            .rx_capacity()
+            .saturating_sub(self.rx.len())
+            .min(data.len());
+        if count > 0 {
+            self.rx.extend(&data[..count]);
+            self.update_irq();
+        }
+        count
+    }
+}
+
+/// The host side of a serial port's receiver.
+#[derive(Clone)]
+pub struct SerialInput(Arc<Mutex<Inner>>);
+
+impl SerialInput {
+    /// Queue bytes for the guest, as many as fit in the receive FIFO.
+    /// Returns how many were taken; try the rest again once the guest has
+    /// had [`RX_RETRY_INTERVAL`] to read some.
+    pub fn receive(&self, data: &[u8]) -> usize {
+        lock(&self.0).receive(data)
+    }
+}
+
+fn lock(inner: &Mutex<Inner>) -> MutexGuard<'_, Inner> {
+    inner.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Copy host input into the receive FIFO until end of input.
+fn forward_input(inner: &Mutex<Inner>, mut input: Box<dyn Read + Send>) {
+    let mut buf = [0u8; 64];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("[serial] Failed to read console input: {}", e);
+                return;
+            }
+        };
+        let mut pending = &buf[..len];
+        while !pending.is_empty() {
+            let taken = lock(inner).receive(pending);
+            pending = &pending[taken..];
+            if !pending.is_empty() {
+                // Wait for the guest to drain the FIFO
+                std::thread::sleep(RX_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -146,6 +451,21 @@ mod tests {
         assert_eq!(lsr & lsr::TEMT, lsr::TEMT, "TEMT should be set");
     }
 
+    #[test]
+    fn test_state_round_trip() {
+        let mut serial = Serial::new();
+        serial.write(regs::IIR_FCR, fcr::ENABLE);
+        serial.write(regs::SCR, 0x42);
+        serial.input().receive(b"hi");
+        let state = PioDevice::save_state(&serial).unwrap();
+
+        let mut restored = Serial::new();
+        PioDevice::restore_state(&mut restored, &state).unwrap();
+        assert_eq!(restored.read(regs::SCR), 0x42);
+        assert_eq!(restored.read(regs::THR_RBR), b'h');
+        assert_eq!(restored.read(regs::THR_RBR), b'i');
+    }
+
     #[test]
     fn test_scratch_register() {
         let mut serial = Serial::new();
@@ -194,4 +514,83 @@ mod tests {
         let serial = Serial::new();
         assert_eq!(serial.read(regs::IIR_FCR), iir::NO_INT);
     }
+
+    #[test]
+    fn test_receive_without_fifo() {
+        let mut serial = Serial::new();
+        // A single holding register
+        assert_eq!(lock(&serial.inner).receive(b"ab"), 1);
+        assert_eq!(serial.read(regs::LSR) & lsr::DR, lsr::DR);
+        // Masked until enabled in IER
+        assert_eq!(serial.read(regs::IIR_FCR), iir::NO_INT);
+        serial.write(regs::IER, ier::RX_DATA);
+        assert_eq!(serial.read(regs::IIR_FCR), iir::RX_DATA);
+
+        assert_eq!(serial.read(regs::THR_RBR), b'a');
+        assert_eq!(serial.read(regs::LSR) & lsr::DR, 0);
+        assert_eq!(serial.read(regs::IIR_FCR), iir::NO_INT);
+        assert_eq!(lock(&serial.inner).receive(b"b"), 1);
+        assert_eq!(serial.read(regs::THR_RBR), b'b');
+    }
+
+    #[test]
+    fn test_receive_fifo() {
+        let mut serial = Serial::new();
+        // FIFO on, trigger level 4
+        serial.write(regs::IIR_FCR, fcr::ENABLE | 0x40);
+        serial.write(regs::IER, ier::RX_DATA);
+
+        assert_eq!(lock(&serial.inner).receive(&[0; 20]), FIFO_SIZE);
+        assert_eq!(serial.read(regs::IIR_FCR), iir::RX_DATA | iir::FIFO_ENABLED);
+        for _ in 0..FIFO_SIZE - 3 {
+            serial.read(regs::THR_RBR);
+        }
+        // Below the trigger level: character timeout
+        assert_eq!(
+            serial.read(regs::IIR_FCR),
+            iir::RX_TIMEOUT | iir::FIFO_ENABLED
+        );
+
+        // Clearing the FIFO drops what is left
+        serial.write(regs::IIR_FCR, fcr::ENABLE | fcr::CLEAR_RX);
+        assert_eq!(serial.read(regs::LSR) & lsr::DR, 0);
+        assert_eq!(serial.read(regs::IIR_FCR), iir::NO_INT | iir::FIFO_ENABLED);
+    }
+
+    #[test]
+    fn test_thr_empty_interrupt() {
+        let mut serial = Serial::with_output(Box::new(io::sink()));
+        // Raised when enabled, and acknowledged by reading IIR
+        serial.write(regs::IER, ier::THR_EMPTY);
+        assert_eq!(serial.read(regs::IIR_FCR), iir::THR_EMPTY);
+        assert_eq!(serial.read(regs::IIR_FCR), iir::NO_INT);
+
+        // Raised again once a byte is sent
+        serial.write(regs::THR_RBR, b'x');
+        lock(&serial.inner).receive(b"y");
+        serial.write(regs::IER, ier::THR_EMPTY | ier::RX_DATA);
+        // Received data comes first
+        assert_eq!(serial.read(regs::IIR_FCR), iir::RX_DATA);
+        serial.read(regs::THR_RBR);
+        assert_eq!(serial.read(regs::IIR_FCR), iir::THR_EMPTY);
+    }
+
+    #[test]
+    fn test_input_thread() {
+        let mut serial = Serial::new();
+        serial.write(regs::IIR_FCR, fcr::ENABLE);
+        serial.set_input(Box::new(&b"hello"[..])).unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..1000 {
+            while serial.read(regs::LSR) & lsr::DR != 0 {
+                received.push(serial.read(regs::THR_RBR));
+            }
+            if received.len() == 5 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(received, b"hello");
+    }
 }
@@ -0,0 +1,376 @@
+//! TPM 2.0 over the Command Response Buffer (CRB) interface, backed by
+//! swtpm.
+//!
+//! The guest gets a TPM for measured boot and attestation, while the TPM
+//! itself (its keys, PCRs and NV storage) lives in an external `swtpm`
+//! process. Carbon forwards each command over swtpm's data socket and
+//! hands the response back to the guest:
+//!
+//! ```text
+//! swtpm socket --tpm2 --tpmstate dir=/var/lib/vtpm \
+//!     --server type=unixio,path=/run/vtpm.sock \
+//!     --flags not-need-init,startup-clear
+//! carbon run --tpm /run/vtpm.sock ...
+//! ```
+//!
+//! `not-need-init` and `startup-clear` let swtpm take commands straight away,
+//! so Carbon needs no control channel.
+//!
+//! # Discovery
+//!
+//! The registers sit at [`TPM_CRB_BASE`], described by a TPM2 ACPI table
+//! (start method 7, "Command Response Buffer") and a `MSFT0101` device in
+//! the DSDT. Linux binds it with `CONFIG_TCG_CRB` and exposes `/dev/tpm0`
+//! and `/dev/tpmrm0`.
+//!
+//! # Interface
+//!
+//! Only locality 0 is implemented, without interrupts: the driver polls.
+//! Commands run synchronously when the guest writes `CTRL_START`, so the
+//! start bit is already clear when the driver first checks it. The command
+//! and response share one buffer after the registers, as in QEMU.
+//!
+//! A command blocks whatever serves the device, so swtpm gets
+//! [`SWTPM_TIMEOUT`] to take it and answer. Past that, or if swtpm fails,
+//! the TPM goes into its fatal error state (`CTRL_STS.tpmSts`) for good: a
+//! late response would otherwise be taken for the next command's.
+//!
+//! Reference: TCG PC Client Platform TPM Profile (PTP) Specification,
+//! section 6.5 (CRB interface)
+
+use super::mmio::MmioDevice;
+use crate::audit;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Guest physical address of the CRB registers (the standard TPM address).
+pub const TPM_CRB_BASE: u64 = 0xfed4_0000;
+
+/// Size of the CRB region: registers, then the command/response buffer.
+pub const TPM_CRB_SIZE: u64 = 0x1000;
+
+/// Register offsets (locality 0).
+const REG_LOC_STATE: u64 = 0x00;
+const REG_LOC_CTRL: u64 = 0x08;
+const REG_LOC_STS: u64 = 0x0c;
+const REG_INTF_ID: u64 = 0x30;
+const REG_CTRL_REQ: u64 = 0x40;
+const REG_CTRL_STS: u64 = 0x44;
+const REG_CTRL_START: u64 = 0x4c;
+const REG_CTRL_CMD_SIZE: u64 = 0x58;
+const REG_CTRL_CMD_LADDR: u64 = 0x5c;
+const REG_CTRL_RSP_SIZE: u64 = 0x64;
+const REG_CTRL_RSP_ADDR: u64 = 0x68;
+
+/// Offset of the control area (`CTRL_REQ`), which the TPM2 table points at.
+pub const TPM_CRB_CONTROL_AREA: u64 = REG_CTRL_REQ;
+
+/// Offset and size of the command/response buffer.
+const DATA_BUFFER: u64 = 0x80;
+const DATA_BUFFER_SIZE: usize = (TPM_CRB_SIZE - DATA_BUFFER) as usize;
+
+/// LOC_STATE bits.
+const LOC_STATE_ESTABLISHED: u32 = 1 << 0;
+const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID: u32 = 1 << 7;
+
+/// LOC_CTRL bits.
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+
+/// LOC_STS bits.
+const LOC_STS_GRANTED: u32 = 1 << 0;
+
+/// CTRL_REQ bits.
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+
+/// CTRL_STS bits.
+const CTRL_STS_FATAL: u32 = 1 << 0;
+const CTRL_STS_IDLE: u32 = 1 << 1;
+
+/// CTRL_START bits.
+const CTRL_START_INVOKE: u32 = 1 << 0;
+
+/// INTF_ID: a CRB interface (type and version 1), 64-byte transfers, CRB
+/// the only interface supported and selected; vendor and device as QEMU.
+const INTF_ID: u64 = 1 | (1 << 4) | (3 << 11) | (1 << 14) | (1 << 17) | (0x1014 << 32) | (1 << 48);
+
+/// Size of a TPM command or response header: tag, size, code.
+const TPM_HEADER_SIZE: usize = 10;
+
+/// Longest swtpm may take to take a command, or to answer it: well past
+/// its slowest commands, such as generating an RSA key.
+pub const SWTPM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TPM CRB device state.
+pub struct TpmCrb {
+    /// swtpm's data channel.
+    stream: UnixStream,
+    /// Locality 0 is assigned to the guest.
+    assigned: bool,
+    /// The TPM is idle (after goIdle, before cmdReady).
+    idle: bool,
+    /// Talking to swtpm failed; the TPM reports a fatal error from then on.
+    fatal: bool,
+    /// How long swtpm gets to take a command, or to answer it.
+    timeout: Duration,
+    /// Command/response buffer.
+    buffer: Box<[u8; DATA_BUFFER_SIZE]>,
+}
+
+impl TpmCrb {
+    /// Connect to swtpm's data socket at `socket`.
+    pub fn connect(socket: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(socket)?;
+        audit::record(
+            audit::Kind::Socket,
+            "connect",
+            &socket.display().to_string(),
+        );
+        Self::new(stream, SWTPM_TIMEOUT)
+    }
+
+    /// A TPM forwarding commands on `stream`, failing any swtpm takes
+    /// longer than `timeout` to take or answer.
+    fn new(stream: UnixStream, timeout: Duration) -> io::Result<Self> {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Self {
+            stream,
+            assigned: false,
+            idle: true,
+            fatal: false,
+            timeout,
+            buffer: Box::new([0; DATA_BUFFER_SIZE]),
+        })
+    }
+
+    /// Value of the 32-bit register at `offset`.
+    fn register(&self, offset: u64) -> u32 {
+        let buffer = (TPM_CRB_BASE + DATA_BUFFER) as u32;
+        match offset {
+            REG_LOC_STATE => {
+                let mut state = LOC_STATE_ESTABLISHED | LOC_STATE_REG_VALID;
+                if self.assigned {
+                    state |= LOC_STATE_ASSIGNED;
+                }
+                state
+            }
+            REG_LOC_STS if self.assigned => LOC_STS_GRANTED,
+            REG_INTF_ID => INTF_ID as u32,
+            r if r == REG_INTF_ID + 4 => (INTF_ID >> 32) as u32,
+            REG_CTRL_STS => {
+                let mut status = 0;
+                if self.fatal {
+                    status |= CTRL_STS_FATAL;
+                }
+                if self.idle {
+                    status |= CTRL_STS_IDLE;
+                }
+                status
+            }
+            REG_CTRL_CMD_SIZE | REG_CTRL_RSP_SIZE => DATA_BUFFER_SIZE as u32,
+            REG_CTRL_CMD_LADDR | REG_CTRL_RSP_ADDR => buffer,
+            // CTRL_REQ and CTRL_START read as 0: requests and commands
+            // complete at once. The high address halves are 0 too.
+            _ => 0,
+        }
+    }
+
+    /// Send the command in the buffer to swtpm and put its response there.
+    fn execute(&mut self) {
+        if self.fatal {
+            return;
+        }
+        match self.transfer() {
+            Ok(()) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                warn!(
+                    "[TPM] swtpm didn't respond within {:?}; the TPM has failed",
+                    self.timeout
+                );
+                self.fatal = true;
+            }
+            Err(e) => {
+                warn!("[TPM] swtpm failed: {}", e);
+                self.fatal = true;
+            }
+        }
+    }
+
+    fn transfer(&mut self) -> io::Result<()> {
+        let size = u32::from_be_bytes(self.buffer[2..6].try_into().unwrap()) as usize;
+        let size = size.clamp(TPM_HEADER_SIZE, DATA_BUFFER_SIZE);
+        self.stream.write_all(&self.buffer[..size])?;
+
+        self.stream
+            .read_exact(&mut self.buffer[..TPM_HEADER_SIZE])?;
+        let size = u32::from_be_bytes(self.buffer[2..6].try_into().unwrap()) as usize;
+        if !(TPM_HEADER_SIZE..=DATA_BUFFER_SIZE).contains(&size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("response of {size} bytes"),
+            ));
+        }
+        self.stream
+            .read_exact(&mut self.buffer[TPM_HEADER_SIZE..size])
+    }
+}
+
+impl MmioDevice for TpmCrb {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= DATA_BUFFER {
+            let start = (offset - DATA_BUFFER) as usize;
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = self.buffer.get(start + i).copied().unwrap_or(0);
+            }
+            return;
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u64;
+            *byte = self.register(offset & !3).to_le_bytes()[(offset & 3) as usize];
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset >= DATA_BUFFER {
+            let start = (offset - DATA_BUFFER) as usize;
+            if let Some(dest) = self.buffer.get_mut(start..start + data.len()) {
+                dest.copy_from_slice(data);
+            }
+            return;
+        }
+        let mut value = [0u8; 4];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+        match offset {
+            REG_LOC_CTRL => {
+                if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+                    self.assigned = true;
+                } else if value & LOC_CTRL_RELINQUISH != 0 {
+                    self.assigned = false;
+                }
+            }
+            REG_CTRL_REQ => {
+                // cmdReady (bit 0) leaves idle, goIdle enters it
+                self.idle = value & CTRL_REQ_GO_IDLE != 0;
+            }
+            REG_CTRL_START if value & CTRL_START_INVOKE != 0 => self.execute(),
+            // The rest is read-only, or unused without interrupts
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn read_u32(tpm: &mut TpmCrb, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        tpm.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_registers() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let mut tpm = TpmCrb::new(stream, SWTPM_TIMEOUT).unwrap();
+
+        assert_eq!(read_u32(&mut tpm, REG_LOC_STATE) & LOC_STATE_ASSIGNED, 0);
+        tpm.write(REG_LOC_CTRL, &LOC_CTRL_REQUEST_ACCESS.to_le_bytes());
+        let state = read_u32(&mut tpm, REG_LOC_STATE);
+        assert_eq!(state & LOC_STATE_ASSIGNED, LOC_STATE_ASSIGNED);
+        assert_eq!(state & LOC_STATE_REG_VALID, LOC_STATE_REG_VALID);
+        assert_eq!(read_u32(&mut tpm, REG_LOC_STS), LOC_STS_GRANTED);
+
+        assert_eq!(read_u32(&mut tpm, REG_CTRL_STS), CTRL_STS_IDLE);
+        tpm.write(REG_CTRL_REQ, &1u32.to_le_bytes());
+        assert_eq!(read_u32(&mut tpm, REG_CTRL_STS), 0);
+
+        let buffer = (TPM_CRB_BASE + DATA_BUFFER) as u32;
+        assert_eq!(read_u32(&mut tpm, REG_CTRL_CMD_SIZE), 0xf80);
+        assert_eq!(read_u32(&mut tpm, REG_CTRL_CMD_LADDR), buffer);
+        assert_eq!(read_u32(&mut tpm, REG_CTRL_RSP_SIZE), 0xf80);
+        let mut rsp_addr = [0u8; 8];
+        tpm.read(REG_CTRL_RSP_ADDR, &mut rsp_addr);
+        assert_eq!(u64::from_le_bytes(rsp_addr), u64::from(buffer));
+        assert_eq!(read_u32(&mut tpm, REG_INTF_ID) & 0xf, 1);
+    }
+
+    #[test]
+    fn test_command_roundtrip() {
+        let (stream, mut swtpm) = UnixStream::pair().unwrap();
+        let mut tpm = TpmCrb::new(stream, SWTPM_TIMEOUT).unwrap();
+
+        // TPM2_Startup(SU_CLEAR), answered with TPM_RC_SUCCESS
+        let command = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x44, 0, 0];
+        let response = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0];
+        let server = thread::spawn(move || {
+            let mut received = [0u8; 12];
+            swtpm.read_exact(&mut received).unwrap();
+            swtpm.write_all(&response).unwrap();
+            received
+        });
+
+        tpm.write(DATA_BUFFER, &command);
+        tpm.write(REG_CTRL_START, &CTRL_START_INVOKE.to_le_bytes());
+        assert_eq!(server.join().unwrap(), command);
+        assert_eq!(read_u32(&mut tpm, REG_CTRL_START), 0);
+        let mut data = [0u8; 10];
+        tpm.read(DATA_BUFFER, &mut data);
+        assert_eq!(data, response);
+        assert_eq!(read_u32(&mut tpm, REG_CTRL_STS) & CTRL_STS_FATAL, 0);
+    }
+
+    #[test]
+    fn test_swtpm_gone_is_fatal() {
+        let (stream, swtpm) = UnixStream::pair().unwrap();
+        drop(swtpm);
+        let mut tpm = TpmCrb::new(stream, SWTPM_TIMEOUT).unwrap();
+        tpm.write(DATA_BUFFER, &[0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0]);
+        tpm.write(REG_CTRL_START, &CTRL_START_INVOKE.to_le_bytes());
+        assert_eq!(
+            read_u32(&mut tpm, REG_CTRL_STS) & CTRL_STS_FATAL,
+            CTRL_STS_FATAL
+        );
+    }
+
+    #[test]
+    fn test_swtpm_timeout_is_fatal() {
+        let (stream, mut swtpm) = UnixStream::pair().unwrap();
+        let mut tpm = TpmCrb::new(stream, Duration::from_millis(50)).unwrap();
+        let command = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0];
+        tpm.write(DATA_BUFFER, &command);
+        tpm.write(REG_CTRL_START, &CTRL_START_INVOKE.to_le_bytes());
+        assert_eq!(
+            read_u32(&mut tpm, REG_CTRL_STS) & CTRL_STS_FATAL,
+            CTRL_STS_FATAL
+        );
+
+        // A late answer is never taken for a later command's: that isn't
+        // sent at all
+        let mut received = [0u8; 10];
+        swtpm.read_exact(&mut received).unwrap();
+        swtpm.write_all(&command).unwrap();
+        tpm.write(REG_CTRL_START, &CTRL_START_INVOKE.to_le_bytes());
+        swtpm.set_nonblocking(true).unwrap();
+        assert_eq!(
+            swtpm.read(&mut received).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            read_u32(&mut tpm, REG_CTRL_STS) & CTRL_STS_FATAL,
+            CTRL_STS_FATAL
+        );
+    }
+}
@@ -19,6 +19,101 @@
 //!    - 1 = IOERR
 //!    - 2 = UNSUPP
 //!
+//! # Block sizes
+//!
+//! Requests always address 512-byte sectors, but the device reports a
+//! logical and physical block size (`VIRTIO_BLK_F_BLK_SIZE`,
+//! `VIRTIO_BLK_F_TOPOLOGY`) so the guest sizes and aligns its I/O for the
+//! backing storage, e.g. 4096/4096 for an image on a 4K-native drive. Both
+//! default to 512.
+//!
+//! # Sparse images
+//!
+//! Holes in the image stay holes, so per-VM overlay images only take up
+//! the space the guest actually wrote:
+//!
+//! - reads of a range that is entirely a hole (`SEEK_DATA`) return zeroes
+//!   without touching the storage,
+//! - writes of all-zero data punch a hole instead of allocating zeroes,
+//! - discard (`VIRTIO_BLK_F_DISCARD`) and write-zeroes with the unmap flag
+//!   (`VIRTIO_BLK_F_WRITE_ZEROES`) punch holes; write-zeroes without it
+//!   zeroes the range in place (`FALLOC_FL_ZERO_RANGE`).
+//!
+//! Where the host filesystem can't punch holes, discards are ignored and
+//! zeroes are written out. Read-only disks offer neither command.
+//!
+//! # Cache policy
+//!
+//! `cache=` chooses how guest writes reach the image, like QEMU's option of
+//! the same name:
+//!
+//! | Policy                | Host page cache | Guest sees    | Writes are durable    |
+//! | --------------------- | --------------- | ------------- | --------------------- |
+//! | `writeback` (default) | used            | a write cache | after a guest flush   |
+//! | `writethrough`        | used            | no cache      | when they complete    |
+//! | `none`                | bypassed        | a write cache | after a guest flush   |
+//!
+//! With `writeback` and `none` the device offers `VIRTIO_BLK_F_FLUSH` and
+//! syncs the image when the guest flushes. With `writethrough` it doesn't:
+//! every write, discard and write-zeroes request is synced before it
+//! completes, so there is nothing left to flush. `none` opens the image with
+//! `O_DIRECT`, as `direct=on` does; `direct=on` with `writethrough` syncs
+//! each write as well.
+//!
+//! # Direct I/O
+//!
+//! With `direct=on` (or `cache=none`) the image is opened with `O_DIRECT`,
+//! so guest I/O skips the host page cache instead of caching every block
+//! twice (once in the guest, once in the host). `O_DIRECT` needs offsets, lengths and memory
+//! aligned to the host's direct I/O alignment (from `statx`, else 4096), but
+//! guest segments need only be sector-aligned: every request goes through an
+//! aligned bounce buffer covering the enclosing aligned range, and unaligned
+//! writes read that range first and write it back whole. The image size must
+//! be a multiple of the alignment. Direct disks bypass the shared
+//! [`BlockCache`] and never prefetch, both of which rely on buffered reads.
+//!
+//! # Encryption
+//!
+//! With a key (`key-file=`, `key-fd=` or `key-command=`), sectors are
+//! encrypted with AES-256-XTS between the guest and the image (see
+//! [`super::crypt`]). Encrypted disks offer neither discard nor
+//! write-zeroes, and skip the sparse-image shortcuts above: a hole and an
+//! all-zero sector mean nothing once the image holds ciphertext.
+//!
+//! # Queues
+//!
+//! The device offers a request queue per vCPU (`VIRTIO_BLK_F_MQ`), up to
+//! [`MAX_QUEUES`], as [`VirtioBlk::set_vcpus`] says; one until then. The
+//! guest driver maps each vCPU to a queue and names it in the QUEUE_NOTIFY
+//! write; queues share the disk but nothing else.
+//!
+//! # Host block devices
+//!
+//! The image can also be a host block device, e.g. an LVM volume under
+//! `/dev/mapper` or a raw partition. Its capacity comes from
+//! `BLKGETSIZE64` (a device node's file size is zero), and flushes still
+//! `fsync` it, which makes the kernel flush the device's volatile write
+//! cache. With `exclusive=on` the device is opened with `O_EXCL`, which
+//! fails while it is mounted or held exclusively by another VM, so two VMs
+//! can't share a volume by accident.
+//!
+//! # Removal
+//!
+//! A hot-plugged disk (see [`super::hotplug`]) can be removed while the
+//! guest still uses it. [`VirtioBlk::remove`] syncs the image, fails every
+//! request the guest has queued or queues later with `IOERR`, and reports a
+//! capacity of zero with a configuration change interrupt, which Linux logs
+//! as a capacity change and passes on to whatever has the disk mounted. The
+//! device keeps answering until the driver lets go of it.
+//!
+//! # vhost-user backends
+//!
+//! A disk can instead be served by an external vhost-user-blk backend (e.g.
+//! SPDK), which then owns the data path: the device is a
+//! [`VhostUserDevice`](super::vhost_user_device::VhostUserDevice) described
+//! by [`vhost_user_spec`], and the backend reports the capacity, block sizes
+//! and queue count itself.
+//!
 //! # Example Request Flow (Read)
 //!
 //! ```text
@@ -32,15 +127,33 @@
 //!   │                                     │ Write data to guest buffer
 //!   │                                     │ Write status byte
 //!   │                                     │ Update used->idx
-//!   │◄──────────────────────────── (poll) │
+//!   │◄─────────────────────── IRQ (irqfd) │
 //!   │                                     │
 //! ```
 
+use crate::audit;
 use crate::boot::GuestMemory;
+use crate::cleanup::{self, CleanupGuard};
 use crate::devices::mmio::MmioDevice;
+use crate::digest::{Hasher, Sha256Digest};
+use crate::events::{Event, Events};
+use crate::kvm::IrqLine;
+use crate::snapshot::DeviceState;
+use serde::{Deserialize, Serialize};
+use std::alloc::{self, Layout};
 use std::fs::{File, OpenOptions};
-use std::os::unix::fs::FileExt;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str::FromStr;
+use std::sync::Arc;
 
+use super::cache::BlockCache;
+use super::crypt::{self, KeySource, XtsCipher};
+use super::prefetch::Prefetcher;
+use super::probe::{self, ImageFormat};
+use super::rate_limiter::{RateLimit, RateLimiter, RetryTimer};
+use super::vhost_user_device::{ConfigSpace, VhostUserSpec};
 use super::{
     VirtqDesc, Virtqueue, MAX_QUEUE_SIZE, MMIO_DEVICE_FEATURES, MMIO_DEVICE_FEATURES_SEL,
     MMIO_DEVICE_ID, MMIO_DRIVER_FEATURES, MMIO_DRIVER_FEATURES_SEL, MMIO_INTERRUPT_ACK,
@@ -48,8 +161,8 @@ use super::{
     MMIO_QUEUE_DEVICE_HIGH, MMIO_QUEUE_DEVICE_LOW, MMIO_QUEUE_DRIVER_HIGH, MMIO_QUEUE_DRIVER_LOW,
     MMIO_QUEUE_NOTIFY, MMIO_QUEUE_NUM, MMIO_QUEUE_NUM_MAX, MMIO_QUEUE_READY, MMIO_QUEUE_SEL,
     MMIO_STATUS, MMIO_VENDOR_ID, MMIO_VERSION, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK,
-    STATUS_FEATURES_OK, VIRTIO_MMIO_MAGIC, VIRTIO_MMIO_VERSION, VIRTIO_VENDOR_ID,
-    VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+    STATUS_FEATURES_OK, VIRTIO_F_EVENT_IDX, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_MAGIC,
+    VIRTIO_MMIO_VERSION, VIRTIO_VENDOR_ID, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
 };
 
 /// Virtio device ID for block devices.
@@ -58,18 +171,38 @@ const VIRTIO_BLK_DEVICE_ID: u32 = 2;
 /// Sector size in bytes.
 const SECTOR_SIZE: u64 = 512;
 
-/// Block size (logical block size reported to guest).
-const BLK_SIZE: u32 = 512;
+/// Default logical and physical block size reported to the guest.
+pub const DEFAULT_BLOCK_SIZE: u32 = 512;
+/// Largest logical block size: Linux refuses blocks bigger than a page.
+pub const MAX_LOGICAL_BLOCK_SIZE: u32 = 4096;
+
+/// Legacy CHS geometry reported to the guest: heads and sectors per track.
+/// Only tools that still ask for a geometry (e.g. fdisk) see it.
+const GEOMETRY_HEADS: u64 = 16;
+const GEOMETRY_SECTORS: u64 = 63;
 
 // Feature bits (from virtio spec)
 /// Maximum size of any single segment is in `size_max`.
 const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
 /// Maximum number of segments in a request is in `seg_max`.
 const VIRTIO_BLK_F_SEG_MAX: u32 = 1 << 2;
+/// Legacy disk geometry is in `geometry`.
+const VIRTIO_BLK_F_GEOMETRY: u32 = 1 << 4;
+/// Device is read-only.
+const VIRTIO_BLK_F_RO: u32 = 1 << 5;
 /// Block size of disk is in `blk_size`.
 const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
 /// Cache flush command support.
 const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
+/// Physical block size and I/O alignment are in `topology`.
+const VIRTIO_BLK_F_TOPOLOGY: u32 = 1 << 10;
+/// Device supports multiple request queues, counted in `num_queues`.
+const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
+/// Discard command support; limits are in the discard config fields.
+const VIRTIO_BLK_F_DISCARD: u32 = 1 << 13;
+/// Write-zeroes command support; limits are in the write-zeroes config
+/// fields.
+const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 1 << 14;
 
 /// VIRTIO_F_VERSION_1 - Required for virtio-mmio v2 devices.
 /// This is bit 32, so it goes in the high features word.
@@ -80,10 +213,28 @@ const SIZE_MAX: u32 = 1024 * 1024;
 /// Maximum segments per request.
 const SEG_MAX: u32 = 128;
 
+/// Most sectors one discard or write-zeroes segment may cover.
+const MAX_DISCARD_SECTORS: u32 = u32::MAX;
+/// Most segments in one discard or write-zeroes request.
+const MAX_DISCARD_SEG: u32 = SEG_MAX;
+/// Size of a discard or write-zeroes segment: sector (8 bytes),
+/// num_sectors (4 bytes), flags (4 bytes).
+const DISCARD_SEGMENT_SIZE: usize = 16;
+/// Segment flag: the write-zeroes range may be deallocated.
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1 << 0;
+
+/// Most request queues offered to the guest. The guest uses at most one
+/// per vCPU, so each vCPU submits I/O without contending for a shared
+/// ring; past this, vCPUs share queues rather than each costing the host
+/// an ioeventfd per disk.
+pub const MAX_QUEUES: u16 = 16;
+
 // Block request types
 const VIRTIO_BLK_T_IN: u32 = 0; // Read
 const VIRTIO_BLK_T_OUT: u32 = 1; // Write
 const VIRTIO_BLK_T_FLUSH: u32 = 4; // Flush
+const VIRTIO_BLK_T_DISCARD: u32 = 11; // Discard
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13; // Write zeroes
 
 // Block status codes
 const VIRTIO_BLK_S_OK: u8 = 0;
@@ -94,14 +245,161 @@ const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 const CONFIG_CAPACITY: u64 = 0x100; // 8 bytes
 const CONFIG_SIZE_MAX: u64 = 0x108; // 4 bytes
 const CONFIG_SEG_MAX: u64 = 0x10c; // 4 bytes
-const CONFIG_BLK_SIZE: u64 = 0x114; // 4 bytes (after geometry)
+const CONFIG_GEOMETRY: u64 = 0x110; // cylinders (2 bytes), heads, sectors
+const CONFIG_BLK_SIZE: u64 = 0x114; // 4 bytes
+const CONFIG_TOPOLOGY: u64 = 0x118; // physical_block_exp, alignment_offset, min_io_size (2 bytes)
+const CONFIG_OPT_IO_SIZE: u64 = 0x11c; // 4 bytes
+const CONFIG_NUM_QUEUES: u64 = 0x120; // writeback, unused, num_queues (2 bytes)
+const CONFIG_MAX_DISCARD_SECTORS: u64 = 0x124; // 4 bytes
+const CONFIG_MAX_DISCARD_SEG: u64 = 0x128; // 4 bytes
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: u64 = 0x12c; // 4 bytes
+const CONFIG_MAX_WRITE_ZEROES_SECTORS: u64 = 0x130; // 4 bytes
+const CONFIG_MAX_WRITE_ZEROES_SEG: u64 = 0x134; // 4 bytes
+const CONFIG_WRITE_ZEROES_MAY_UNMAP: u64 = 0x138; // may_unmap, unused (3 bytes)
+
+/// `_IOR(0x12, 114, size_t)`: size of a block device in bytes.
+const BLKGETSIZE64: u64 = (2 << 30) | (8 << 16) | (0x12 << 8) | 114;
+
+/// Direct I/O alignment assumed when the kernel doesn't report one.
+const DIRECT_IO_DEFAULT_ALIGN: u64 = 4096;
+
+/// Config space read from vhost-user-blk backends: up to the end of the
+/// discard and write-zeroes limits.
+const VHOST_USER_CONFIG_SIZE: u32 = 0x3c;
+
+/// How guest writes reach the image: `cache=writeback|writethrough|none`
+/// (see [Cache policy](self#cache-policy)).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CachePolicy {
+    /// Writes land in the host page cache; guest flushes sync the image.
+    #[default]
+    Writeback,
+    /// Every write is synced before it completes.
+    Writethrough,
+    /// Writes bypass the host page cache (`O_DIRECT`); guest flushes sync
+    /// the image.
+    None,
+}
+
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "writeback" => Ok(Self::Writeback),
+            "writethrough" => Ok(Self::Writethrough),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "invalid cache policy {s:?} (expected writeback, writethrough or none)"
+            )),
+        }
+    }
+}
+
+/// How a disk image is exposed to the guest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskOptions {
+    /// Refuse guest writes and flushes. Read-only images are opened
+    /// read-only and locked shared, so several VMs can use one.
+    pub read_only: bool,
+    /// Read a read-only image through the [`BlockCache`] shared by the VMs
+    /// in this process. Set for every VM on a [`crate::host::Host`];
+    /// alone in its process, a VM has no one to share with and reads
+    /// through the host page cache only.
+    #[serde(default)]
+    pub shared_cache: bool,
+    /// Learn the image's boot profile and prefetch it on later starts (see
+    /// [`super::prefetch`]).
+    pub prefetch: bool,
+    /// Logical block size in bytes: the smallest unit the guest addresses
+    /// ([`DEFAULT_BLOCK_SIZE`] if unset).
+    pub logical_block_size: Option<u32>,
+    /// Physical block size in bytes: the unit the backing storage writes
+    /// atomically (the logical block size if unset).
+    pub physical_block_size: Option<u32>,
+    /// Open the image with `O_DIRECT`, bypassing the host page cache (see
+    /// [Direct I/O](self#direct-io)).
+    pub direct: bool,
+    /// How guest writes reach the image.
+    pub cache: CachePolicy,
+    /// Open a host block device with `O_EXCL`, failing if it is mounted or
+    /// already held exclusively (see [Host block devices](self#host-block-devices)).
+    pub exclusive: bool,
+    /// IOPS and bandwidth limits (see [`super::rate_limiter`]).
+    pub rate_limit: RateLimit,
+    /// Encrypt the image under this key (see [Encryption](self#encryption)).
+    pub key: Option<KeySource>,
+    /// SHA-256 digest the image must have when opened (see
+    /// [`crate::digest`]).
+    pub sha256: Option<Sha256Digest>,
+}
+
+impl DiskOptions {
+    /// Whether the image is opened with `O_DIRECT`: with `direct` or
+    /// `cache=none`.
+    pub fn direct_io(&self) -> bool {
+        self.direct || self.cache == CachePolicy::None
+    }
+
+    /// Logical and physical block size, checked to be powers of two with
+    /// the logical size in 512..=[`MAX_LOGICAL_BLOCK_SIZE`] and the physical
+    /// size no smaller.
+    pub fn block_sizes(&self) -> Result<(u32, u32), String> {
+        let logical = self.logical_block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+        let physical = self.physical_block_size.unwrap_or(logical);
+        if !logical.is_power_of_two()
+            || !(DEFAULT_BLOCK_SIZE..=MAX_LOGICAL_BLOCK_SIZE).contains(&logical)
+        {
+            return Err(format!(
+                "logical block size must be a power of two from {DEFAULT_BLOCK_SIZE} to \
+                 {MAX_LOGICAL_BLOCK_SIZE}, not {logical}"
+            ));
+        }
+        // min_io_size counts logical blocks in 16 bits
+        if !physical.is_power_of_two() || physical < logical || physical / logical > 1 << 15 {
+            return Err(format!(
+                "physical block size must be a power of two no smaller than the logical \
+                 block size ({logical}), not {physical}"
+            ));
+        }
+        Ok((logical, physical))
+    }
+}
+
+/// The virtio-blk device a vhost-user-blk backend implements. It starts
+/// with one queue; backends with more report how many.
+pub fn vhost_user_spec() -> VhostUserSpec {
+    VhostUserSpec {
+        name: "vhost-user-blk",
+        device_id: VIRTIO_BLK_DEVICE_ID,
+        num_queues: 1,
+        config: ConfigSpace::Backend(VHOST_USER_CONFIG_SIZE),
+    }
+}
 
 /// Virtio block device.
 pub struct VirtioBlk {
     /// The disk image file.
     disk: File,
+    /// Shared read cache, for buffered read-only disks.
+    cache: Option<Arc<BlockCache>>,
+    /// Refuse guest writes and flushes.
+    read_only: bool,
+    /// Direct I/O alignment in bytes, if the image was opened with
+    /// `O_DIRECT`.
+    direct_align: Option<u64>,
+    /// Sync every write before completing it (`cache=writethrough`).
+    writethrough: bool,
+    /// Removed from the VM: every request fails (see [Removal](self#removal)).
+    removed: bool,
+    /// Encrypts sectors on their way to the image, if keyed.
+    crypt: Option<XtsCipher>,
     /// Disk capacity in sectors.
     capacity: u64,
+    /// Logical block size in bytes.
+    blk_size: u32,
+    /// Physical block size, as a power of two of the logical block size.
+    physical_block_exp: u8,
 
     /// Device features (low 32 bits).
     device_features_lo: u32,
@@ -121,15 +419,49 @@ pub struct VirtioBlk {
 
     /// Queue selection register.
     queue_sel: u32,
-    /// The virtqueue.
-    queue: Virtqueue,
+    /// The request queues, as many as `num_queues`.
+    queues: Vec<Virtqueue>,
+    num_queues: u16,
 
     /// Reference to guest memory for virtqueue processing.
     /// This is set after device creation via set_memory().
     memory: Option<*const GuestMemory>,
+    /// Line to interrupt the guest on, set via set_interrupt().
+    irq: Option<Arc<dyn IrqLine>>,
 
     /// Count of processed requests (for debugging).
     request_count: u64,
+
+    /// Boot-profile recorder and prefetcher, if enabled.
+    prefetch: Option<Prefetcher>,
+    /// IOPS and bandwidth limits, shared with the control socket.
+    rate_limiter: Arc<RateLimiter>,
+    /// Armed for requests held back by the limits, set via
+    /// set_retry_timer(). Without one, they wait for the next notify.
+    retry_timer: Option<Arc<RetryTimer>>,
+    /// Where host I/O errors are reported, and the disk's name there.
+    events: Events,
+    name: String,
+
+    /// Syncs and unlocks the image on drop or panic.
+    _cleanup: CleanupGuard,
+}
+
+/// The transport state of a [`VirtioBlk`], as a snapshot records it.
+///
+/// Requests complete as they are served, so there are none in flight to
+/// record; those held back by the rate limits are still on their queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlkState {
+    /// Disk capacity in sectors, checked against the image on restore.
+    capacity: u64,
+    device_features: u64,
+    driver_features: u64,
+    features_sel: u32,
+    status: u32,
+    interrupt_status: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
 }
 
 // Safety: VirtioBlk can be sent between threads. The raw pointer to GuestMemory
@@ -141,36 +473,257 @@ impl VirtioBlk {
     ///
     /// # Arguments
     ///
-    /// * `disk_path` - Path to the raw disk image file
+    /// * `disk_path` - Path to the raw disk image file or host block device
+    /// * `options` - Read-only, prefetch, block size, direct I/O and
+    ///   exclusive open settings
     ///
     /// # Errors
     ///
-    /// Returns an error if the file cannot be opened.
-    pub fn new(disk_path: &str) -> std::io::Result<Self> {
-        let disk = OpenOptions::new().read(true).write(true).open(disk_path)?;
+    /// Returns an error if the file cannot be opened (or, with `exclusive`,
+    /// is not a block device or is in use), or for any of the reasons
+    /// [`VirtioBlk::from_file`] does.
+    pub fn new(disk_path: &str, options: DiskOptions) -> std::io::Result<Self> {
+        let mut flags = 0;
+        if options.direct_io() {
+            flags |= libc::O_DIRECT;
+        }
+        if options.exclusive {
+            flags |= libc::O_EXCL;
+        }
+        let disk = OpenOptions::new()
+            .read(true)
+            .write(!options.read_only)
+            .custom_flags(flags)
+            .open(disk_path)
+            .map_err(|e| {
+                if e.raw_os_error() == Some(libc::EBUSY) {
+                    std::io::Error::new(
+                        e.kind(),
+                        "block device is in use (mounted, or opened exclusively)",
+                    )
+                } else {
+                    e
+                }
+            })?;
+        let block_device = disk.metadata()?.file_type().is_block_device();
+        if options.exclusive && !block_device {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "exclusive=on needs a host block device",
+            ));
+        }
+        Self::from_file(disk, disk_path, options)
+    }
 
-        let metadata = disk.metadata()?;
-        let capacity = metadata.len() / SECTOR_SIZE;
+    /// Create a virtio block device backed by the inherited, already open
+    /// descriptor `fd`, taking ownership of it. Nothing is opened by path,
+    /// so the image needs no filesystem access from this process.
+    ///
+    /// The descriptor must be open for writing unless `options.read_only`
+    /// is set. Direct I/O is applied with `fcntl`; `exclusive` can't be, as
+    /// `O_EXCL` only means anything at open time. There's no path to key a
+    /// boot profile on, so `prefetch` is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `fd` is not open, is not writable for a writable
+    /// disk, or asks for `exclusive`, or for any of the reasons
+    /// [`VirtioBlk::from_file`] does.
+    pub fn from_fd(fd: RawFd, options: DiskOptions) -> std::io::Result<Self> {
+        // SAFETY: fcntl on any fd number has no memory-safety requirements.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if options.exclusive {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "exclusive=on needs the image opened by path",
+            ));
+        }
+        if !options.read_only && flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "descriptor is open read-only (add ro to use it read-only)",
+            ));
+        }
+        // SAFETY: the descriptor is open and handed over to this device.
+        let disk = unsafe { File::from_raw_fd(fd) };
+        // SAFETY: as above.
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            if options.direct_io() && libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        let options = DiskOptions {
+            prefetch: false,
+            ..options
+        };
+        Self::from_file(disk, &format!("fd={fd}"), options)
+    }
 
-        eprintln!(
-            "[virtio-blk] Opened disk: {} ({} sectors, {} bytes)",
-            disk_path,
+    /// Create a virtio block device backed by the open image `disk`, named
+    /// `disk_name` in logs and the audit trail.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the block sizes are invalid or the image is not
+    /// a whole number of logical blocks (or, with direct I/O, of the direct
+    /// I/O alignment), or if another process holds it open for writing
+    /// (images are locked with `flock` while in use: exclusively when
+    /// writable, shared when read-only).
+    fn from_file(disk: File, disk_name: &str, options: DiskOptions) -> std::io::Result<Self> {
+        let (blk_size, physical_block_size) = options
+            .block_sizes()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let block_device = disk.metadata()?.file_type().is_block_device();
+
+        let lock = if options.read_only {
+            libc::LOCK_SH
+        } else {
+            libc::LOCK_EX
+        };
+        // SAFETY: flock on a valid fd has no memory-safety requirements.
+        if unsafe { libc::flock(disk.as_raw_fd(), lock | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Err(std::io::Error::new(
+                    err.kind(),
+                    "image is locked by another process",
+                ));
+            }
+            return Err(err);
+        }
+        audit::record(
+            audit::Kind::Disk,
+            if options.read_only {
+                "open-ro"
+            } else {
+                "open-rw"
+            },
+            disk_name,
+        );
+        let sync_handle = disk.try_clone()?;
+        let audit_path = disk_name.to_string();
+        let cleanup = cleanup::register("sync disk image", move || {
+            let _ = sync_handle.sync_all();
+            // SAFETY: as above.
+            unsafe { libc::flock(sync_handle.as_raw_fd(), libc::LOCK_UN) };
+            audit::record(audit::Kind::Disk, "close", &audit_path);
+        });
+
+        let size = image_size(&disk)?;
+        if size % blk_size as u64 != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "image size {} is not a multiple of the {}-byte logical block size",
+                    size, blk_size
+                ),
+            ));
+        }
+        let direct_align = options.direct_io().then(|| direct_io_alignment(&disk));
+        if let Some(align) = direct_align {
+            if size % align != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "image size {} is not a multiple of the {}-byte direct I/O alignment",
+                        size, align
+                    ),
+                ));
+            }
+        }
+        let capacity = size / SECTOR_SIZE;
+
+        info!(
+            "[virtio-blk] Opened disk: {} ({} sectors, {} bytes{}{})",
+            disk_name,
             capacity,
-            metadata.len()
+            size,
+            if block_device { ", block device" } else { "" },
+            if options.read_only { ", read-only" } else { "" }
         );
+        if blk_size != DEFAULT_BLOCK_SIZE || physical_block_size != blk_size {
+            info!(
+                "[virtio-blk] Block size: {} logical, {} physical",
+                blk_size, physical_block_size
+            );
+        }
+
+        if let Some(align) = direct_align {
+            info!("[virtio-blk] Direct I/O, {}-byte alignment", align);
+        }
+        if options.cache == CachePolicy::Writethrough && !options.read_only {
+            info!("[virtio-blk] Write-through: every write is synced");
+        }
+        if options.rate_limit.is_limited() {
+            info!("[virtio-blk] Rate limit: {}", options.rate_limit);
+        }
+        if let Some(expected) = options.sha256 {
+            let actual = image_sha256(&disk, size, direct_align)?;
+            if actual != expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("digest mismatch: expected {expected}, got {actual}"),
+                ));
+            }
+            info!("[virtio-blk] Digest matches {}", expected);
+        }
+        let crypt = match &options.key {
+            Some(source) => Some(XtsCipher::new(&source.load(disk_name)?)?),
+            None => None,
+        };
+        if crypt.is_some() {
+            info!("[virtio-blk] Encrypted with AES-256-XTS");
+        }
+
+        let cache = if options.read_only && options.shared_cache && !options.direct_io() {
+            Some(BlockCache::shared(&disk)?)
+        } else {
+            None
+        };
+        let prefetch = if options.prefetch && !options.direct_io() {
+            Prefetcher::start(&disk, disk_name)
+        } else {
+            None
+        };
 
         // Advertise our supported features
-        let device_features_lo = VIRTIO_BLK_F_SIZE_MAX
+        let mut device_features_lo = VIRTIO_BLK_F_SIZE_MAX
             | VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_GEOMETRY
             | VIRTIO_BLK_F_BLK_SIZE
-            | VIRTIO_BLK_F_FLUSH;
+            | VIRTIO_BLK_F_TOPOLOGY
+            | VIRTIO_BLK_F_MQ
+            | VIRTIO_F_EVENT_IDX;
+        if options.read_only {
+            device_features_lo |= VIRTIO_BLK_F_RO;
+        } else {
+            if crypt.is_none() {
+                device_features_lo |= VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES;
+            }
+            // Without FLUSH the guest treats the disk as write-through
+            if options.cache != CachePolicy::Writethrough {
+                device_features_lo |= VIRTIO_BLK_F_FLUSH;
+            }
+        }
 
         // High features word includes VIRTIO_F_VERSION_1 (required for mmio v2)
         let device_features_hi = VIRTIO_F_VERSION_1;
 
         Ok(Self {
             disk,
+            cache,
+            read_only: options.read_only,
+            direct_align,
+            writethrough: options.cache == CachePolicy::Writethrough,
+            removed: false,
+            crypt,
             capacity,
+            blk_size,
+            physical_block_exp: (physical_block_size / blk_size).trailing_zeros() as u8,
             device_features_lo,
             device_features_hi,
             driver_features_lo: 0,
@@ -179,9 +732,17 @@ impl VirtioBlk {
             status: 0,
             interrupt_status: 0,
             queue_sel: 0,
-            queue: Virtqueue::new(),
+            queues: new_queues(1),
+            num_queues: 1,
             memory: None,
+            irq: None,
             request_count: 0,
+            prefetch,
+            rate_limiter: Arc::new(RateLimiter::new(options.rate_limit)),
+            retry_timer: None,
+            events: Events::default(),
+            name: String::new(),
+            _cleanup: cleanup,
         })
     }
 
@@ -195,39 +756,133 @@ impl VirtioBlk {
         self.memory = Some(memory as *const GuestMemory);
     }
 
-    /// Process all pending requests in the virtqueue.
-    fn process_queue(&mut self) {
+    /// Offer a request queue for each of the VM's `vcpus`, up to
+    /// [`MAX_QUEUES`]. Set before the device is on a bus, which asks it how
+    /// many queues to notify.
+    pub fn set_vcpus(&mut self, vcpus: u32) {
+        self.num_queues = vcpus.clamp(1, u32::from(MAX_QUEUES)) as u16;
+        self.queues = new_queues(self.num_queues);
+    }
+
+    /// Set the line used-buffer notifications are raised on.
+    pub fn set_interrupt(&mut self, irq: Arc<dyn IrqLine>) {
+        self.irq = Some(irq);
+    }
+
+    /// Arm `timer` for requests held back by the rate limits, to be served
+    /// on [`MmioDevice::retry`].
+    pub fn set_retry_timer(&mut self, timer: Arc<RetryTimer>) {
+        self.rate_limiter.set_timer(timer.clone());
+        self.retry_timer = Some(timer);
+    }
+
+    /// Report reads, writes and syncs of the image that fail as
+    /// `disk_error` events naming the disk `name`.
+    pub fn report_errors(&mut self, events: Events, name: &str) {
+        self.events = events;
+        self.name = name.to_string();
+    }
+
+    /// Report a failed read, write or sync of the image.
+    fn io_error(&self, error: String) {
+        warn!("[virtio-blk] {}", error);
+        self.events.emit(&Event::DiskError {
+            disk: self.name.clone(),
+            error,
+        });
+    }
+
+    /// The device's rate limiter, for changing its limits at runtime.
+    pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    /// Remove the disk from under the guest: sync the image, fail pending
+    /// and future requests, and report a capacity of zero.
+    pub fn remove(&mut self) {
+        if !self.read_only {
+            let _ = self.handle_flush();
+        }
+        self.removed = true;
+        self.capacity = 0;
+        for index in 0..self.queues.len() {
+            if self.queues[index].ready {
+                self.process_queue(index);
+            }
+        }
+        self.interrupt_status |= VIRTIO_MMIO_INT_CONFIG;
+        if let Some(irq) = &self.irq {
+            irq.trigger();
+        }
+    }
+
+    /// Whether a guest driver has the device (it hasn't been reset since
+    /// the driver found it).
+    pub fn in_use(&self) -> bool {
+        self.status != 0
+    }
+
+    /// Whether [`VirtioBlk::remove`] was called.
+    pub fn is_removed(&self) -> bool {
+        self.removed
+    }
+
+    /// Process all pending requests in queue `index`.
+    fn process_queue(&mut self, index: usize) {
         let memory = match self.memory {
             Some(ptr) => unsafe { &*ptr },
             None => return,
         };
+        if index >= self.queues.len() {
+            warn!("[virtio-blk] Notify for unknown queue {}", index);
+            return;
+        }
 
-        while self.queue.has_pending(memory) {
-            if let Some(desc_idx) = self.queue.pop_avail(memory) {
-                let len = self.process_request(memory, desc_idx);
-                if self.queue.push_used(memory, desc_idx, len).is_err() {
-                    eprintln!("[virtio-blk] Failed to push to used ring");
+        while self.queues[index].has_pending(memory) {
+            if let Some(desc_idx) = self.queues[index].pop_avail(memory) {
+                let Some(len) = self.process_request(memory, index, desc_idx) else {
+                    // Over the rate limit: left for the retry timer
+                    self.queues[index].undo_pop(memory);
+                    break;
+                };
+                if self.queues[index]
+                    .push_used(memory, desc_idx, len)
+                    .is_none()
+                {
+                    warn!("[virtio-blk] Failed to push to used ring");
                 }
                 self.request_count += 1;
-                self.interrupt_status |= 1; // Set USED_BUFFER interrupt
+            }
+        }
+        if self.queues[index].needs_interrupt(memory) {
+            self.interrupt_status |= 1; // Set USED_BUFFER interrupt
+            if let Some(irq) = &self.irq {
+                irq.trigger();
             }
         }
     }
 
     /// Process a single block request.
     ///
-    /// Returns the number of bytes written to guest-writable buffers.
-    fn process_request(&mut self, memory: &GuestMemory, head_idx: u16) -> u32 {
+    /// Returns the number of bytes written to guest-writable buffers, or
+    /// `None` if the rate limits hold the request back, with the retry
+    /// timer armed for when it may run.
+    fn process_request(
+        &mut self,
+        memory: &GuestMemory,
+        queue: usize,
+        head_idx: u16,
+    ) -> Option<u32> {
         // Read the descriptor chain
         let mut desc_idx = head_idx;
         let mut descs = Vec::new();
 
         loop {
-            let desc = match self.queue.read_desc(memory, desc_idx) {
+            let desc = match self.queues[queue].read_desc(memory, desc_idx) {
                 Some(d) => d,
                 None => {
-                    eprintln!("[virtio-blk] Failed to read descriptor {}", desc_idx);
-                    return 0;
+                    warn!("[virtio-blk] Failed to read descriptor {}", desc_idx);
+                    return Some(0);
                 }
             };
             descs.push(desc);
@@ -239,19 +894,19 @@ impl VirtioBlk {
         }
 
         if descs.len() < 2 {
-            eprintln!(
+            warn!(
                 "[virtio-blk] Request too short: {} descriptors",
                 descs.len()
             );
-            return 0;
+            return Some(0);
         }
 
         // First descriptor: request header (16 bytes)
         let header_desc = &descs[0];
         let mut header_buf = [0u8; 16];
         if memory.read(header_desc.addr, &mut header_buf).is_err() {
-            eprintln!("[virtio-blk] Failed to read request header");
-            return 0;
+            warn!("[virtio-blk] Failed to read request header");
+            return Some(0);
         }
 
         let req_type =
@@ -270,17 +925,49 @@ impl VirtioBlk {
         // Last descriptor: status byte (1 byte, device-writable)
         let status_desc = &descs[descs.len() - 1];
         if status_desc.flags & VIRTQ_DESC_F_WRITE == 0 {
-            eprintln!("[virtio-blk] Status descriptor not writable");
-            return 0;
+            warn!("[virtio-blk] Status descriptor not writable");
+            return Some(0);
         }
 
         // Middle descriptors: data buffers
         let data_descs = &descs[1..descs.len() - 1];
         let mut total_written = 0u32;
 
-        let status = match req_type {
+        if self.removed {
+            if memory
+                .write(status_desc.addr, &[VIRTIO_BLK_S_IOERR])
+                .is_err()
+            {
+                warn!("[virtio-blk] Failed to write status");
+            }
+            return Some(1);
+        }
+
+        // Hold the request back while the device is over its rate limit
+        let bytes = match req_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => data_descs.iter().map(|d| d.len as u64).sum(),
+            _ => 0,
+        };
+        let wait = self.rate_limiter.throttle(bytes);
+        if !wait.is_zero() {
+            trace!("[virtio-blk] Rate limited for {:?}", wait);
+            if let Some(timer) = &self.retry_timer {
+                timer.schedule(wait);
+            }
+            return None;
+        }
+
+        let mut status = match req_type {
             VIRTIO_BLK_T_IN => {
                 // Read from disk to guest
+                if let Some(prefetch) = &mut self.prefetch {
+                    let len = data_descs
+                        .iter()
+                        .filter(|d| d.flags & VIRTQ_DESC_F_WRITE != 0)
+                        .map(|d| d.len as u64)
+                        .sum();
+                    prefetch.record(sector * SECTOR_SIZE, len);
+                }
                 self.handle_read(memory, sector, data_descs, &mut total_written)
             }
             VIRTIO_BLK_T_OUT => {
@@ -291,26 +978,69 @@ impl VirtioBlk {
                 // Sync disk
                 self.handle_flush()
             }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                self.handle_discard(memory, req_type, data_descs)
+            }
             _ => {
-                eprintln!("[virtio-blk] Unsupported request type: {}", req_type);
+                warn!("[virtio-blk] Unsupported request type: {}", req_type);
                 VIRTIO_BLK_S_UNSUPP
             }
         };
+        if self.writethrough
+            && status == VIRTIO_BLK_S_OK
+            && matches!(
+                req_type,
+                VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+            )
+        {
+            status = self.handle_flush();
+        }
 
         // Write status byte
         if memory.write(status_desc.addr, &[status]).is_err() {
-            eprintln!("[virtio-blk] Failed to write status");
+            warn!("[virtio-blk] Failed to write status");
         }
         total_written += 1; // Status byte
 
         if self.request_count < 10 {
-            eprintln!(
+            trace!(
                 "[virtio-blk] Request #{}: type={} sector={} status={} written={}",
-                self.request_count, req_type, sector, status, total_written
+                self.request_count,
+                req_type,
+                sector,
+                status,
+                total_written
             );
         }
 
-        total_written
+        Some(total_written)
+    }
+
+    /// Read what the guest sees at `sector` into `buf` (a whole number of
+    /// sectors if the disk is encrypted).
+    fn read_sectors(&self, buf: &mut [u8], sector: u64) -> std::io::Result<()> {
+        let offset = sector * SECTOR_SIZE;
+        match (&self.cache, self.direct_align) {
+            (Some(cache), _) => cache.read_exact_at(buf, offset)?,
+            // Nothing to read: the buffer is already zeroes
+            (None, _) if self.crypt.is_none() && is_hole(&self.disk, offset, buf.len() as u64) => {}
+            (None, Some(align)) => read_aligned(&self.disk, buf, offset, align)?,
+            (None, None) => {
+                self.disk.read_at(buf, offset)?;
+            }
+        }
+        if let Some(crypt) = &self.crypt {
+            crypt.decrypt(buf, sector);
+        }
+        Ok(())
+    }
+
+    /// What the image holds, from its first [`probe::PROBE_SIZE`] bytes.
+    pub fn probe(&self) -> std::io::Result<ImageFormat> {
+        let len = (probe::PROBE_SIZE as u64).min(self.capacity * SECTOR_SIZE);
+        let mut head = vec![0u8; len as usize];
+        self.read_sectors(&mut head, 0)?;
+        Ok(probe::probe(&head))
     }
 
     /// Handle a read request.
@@ -328,17 +1058,21 @@ impl VirtioBlk {
 
             let offset = sector * SECTOR_SIZE;
             let len = desc.len as usize;
+            if self.crypt.is_some() && !len.is_multiple_of(crypt::SECTOR_SIZE) {
+                warn!("[virtio-blk] Partial-sector read of encrypted disk");
+                return VIRTIO_BLK_S_IOERR;
+            }
 
             // Read from disk
             let mut buf = vec![0u8; len];
-            if let Err(e) = self.disk.read_at(&mut buf, offset) {
-                eprintln!("[virtio-blk] Read error at offset {}: {}", offset, e);
+            if let Err(e) = self.read_sectors(&mut buf, sector) {
+                self.io_error(format!("Read error at offset {offset}: {e}"));
                 return VIRTIO_BLK_S_IOERR;
             }
 
             // Write to guest memory
             if memory.write(desc.addr, &buf).is_err() {
-                eprintln!("[virtio-blk] Failed to write to guest memory");
+                warn!("[virtio-blk] Failed to write to guest memory");
                 return VIRTIO_BLK_S_IOERR;
             }
 
@@ -351,6 +1085,12 @@ impl VirtioBlk {
 
     /// Handle a write request.
     fn handle_write(&self, memory: &GuestMemory, mut sector: u64, data_descs: &[VirtqDesc]) -> u8 {
+        if self.read_only {
+            // The guest was told the disk is read-only
+            warn!("[virtio-blk] Write to read-only disk at sector {}", sector);
+            return VIRTIO_BLK_S_IOERR;
+        }
+
         for desc in data_descs {
             if desc.flags & VIRTQ_DESC_F_WRITE != 0 {
                 continue; // Skip writable descriptors (we read from non-writable ones)
@@ -362,13 +1102,27 @@ impl VirtioBlk {
             // Read from guest memory
             let mut buf = vec![0u8; len];
             if memory.read(desc.addr, &mut buf).is_err() {
-                eprintln!("[virtio-blk] Failed to read from guest memory");
+                warn!("[virtio-blk] Failed to read from guest memory");
                 return VIRTIO_BLK_S_IOERR;
             }
 
-            // Write to disk
-            if let Err(e) = self.disk.write_at(&buf, offset) {
-                eprintln!("[virtio-blk] Write error at offset {}: {}", offset, e);
+            if let Some(crypt) = &self.crypt {
+                if !len.is_multiple_of(crypt::SECTOR_SIZE) {
+                    warn!("[virtio-blk] Partial-sector write to encrypted disk");
+                    return VIRTIO_BLK_S_IOERR;
+                }
+                crypt.encrypt(&mut buf, sector);
+            }
+
+            // Write to disk, punching a hole rather than allocating zeroes
+            let zeroes = self.crypt.is_none() && buf.iter().all(|&b| b == 0);
+            let result = if zeroes && punch_hole(&self.disk, offset, len as u64).is_ok() {
+                Ok(())
+            } else {
+                self.write_disk(&buf, offset)
+            };
+            if let Err(e) = result {
+                self.io_error(format!("Write error at offset {offset}: {e}"));
                 return VIRTIO_BLK_S_IOERR;
             }
 
@@ -378,17 +1132,119 @@ impl VirtioBlk {
         VIRTIO_BLK_S_OK
     }
 
+    /// Write `data` at `offset`, through a bounce buffer for direct I/O.
+    fn write_disk(&self, data: &[u8], offset: u64) -> std::io::Result<()> {
+        match self.direct_align {
+            Some(align) => write_aligned(&self.disk, data, offset, align),
+            None => self.disk.write_all_at(data, offset),
+        }
+    }
+
+    /// Handle a discard or write-zeroes request: punch holes in (or zero)
+    /// each segment's range.
+    fn handle_discard(&self, memory: &GuestMemory, req_type: u32, data_descs: &[VirtqDesc]) -> u8 {
+        if self.read_only {
+            // The guest wasn't offered either command
+            warn!("[virtio-blk] Discard on read-only disk");
+            return VIRTIO_BLK_S_IOERR;
+        }
+        if self.crypt.is_some() {
+            return VIRTIO_BLK_S_UNSUPP;
+        }
+
+        for desc in data_descs {
+            if desc.flags & VIRTQ_DESC_F_WRITE != 0 {
+                continue;
+            }
+            let mut buf = vec![0u8; desc.len as usize];
+            if memory.read(desc.addr, &mut buf).is_err() {
+                warn!("[virtio-blk] Failed to read from guest memory");
+                return VIRTIO_BLK_S_IOERR;
+            }
+
+            for segment in buf.chunks_exact(DISCARD_SEGMENT_SIZE) {
+                let sector = u64::from_le_bytes(segment[0..8].try_into().unwrap());
+                let num_sectors = u32::from_le_bytes(segment[8..12].try_into().unwrap());
+                let flags = u32::from_le_bytes(segment[12..16].try_into().unwrap());
+                let unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                // Discards take no flags, write-zeroes only the unmap flag
+                if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0
+                    || (unmap && req_type == VIRTIO_BLK_T_DISCARD)
+                {
+                    return VIRTIO_BLK_S_UNSUPP;
+                }
+                if sector
+                    .checked_add(num_sectors as u64)
+                    .is_none_or(|end| end > self.capacity)
+                {
+                    warn!(
+                        "[virtio-blk] Discard past end of disk: sector {} + {}",
+                        sector, num_sectors
+                    );
+                    return VIRTIO_BLK_S_IOERR;
+                }
+
+                let offset = sector * SECTOR_SIZE;
+                let len = num_sectors as u64 * SECTOR_SIZE;
+                let result = match req_type {
+                    // A discard is only a hint: skip it if holes aren't supported
+                    VIRTIO_BLK_T_DISCARD => match punch_hole(&self.disk, offset, len) {
+                        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+                        result => result,
+                    },
+                    _ if unmap => punch_hole(&self.disk, offset, len),
+                    _ => zero_range(&self.disk, offset, len),
+                };
+                let result = match result {
+                    Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                        self.write_zeroes(offset, len)
+                    }
+                    result => result,
+                };
+                if let Err(e) = result {
+                    warn!("[virtio-blk] Discard error at offset {}: {}", offset, e);
+                    return VIRTIO_BLK_S_IOERR;
+                }
+            }
+        }
+
+        VIRTIO_BLK_S_OK
+    }
+
+    /// Write `len` bytes of zeroes at `offset`, for filesystems that can't
+    /// zero a range themselves.
+    fn write_zeroes(&self, mut offset: u64, len: u64) -> std::io::Result<()> {
+        let end = offset + len;
+        let zeroes = vec![0u8; (len.min(SIZE_MAX as u64)) as usize];
+        while offset < end {
+            let chunk = (end - offset).min(zeroes.len() as u64) as usize;
+            self.write_disk(&zeroes[..chunk], offset)?;
+            offset += chunk as u64;
+        }
+        Ok(())
+    }
+
     /// Handle a flush request.
     fn handle_flush(&self) -> u8 {
+        if self.read_only {
+            // Nothing to flush, and the guest wasn't offered VIRTIO_BLK_F_FLUSH
+            warn!("[virtio-blk] Flush of read-only disk");
+            return VIRTIO_BLK_S_IOERR;
+        }
         match self.disk.sync_all() {
             Ok(()) => VIRTIO_BLK_S_OK,
             Err(e) => {
-                eprintln!("[virtio-blk] Flush error: {}", e);
+                self.io_error(format!("Flush error: {e}"));
                 VIRTIO_BLK_S_IOERR
             }
         }
     }
 
+    /// The queue chosen by QUEUE_SEL, if it exists.
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
     /// Read a 32-bit register value.
     fn read_register(&mut self, offset: u64) -> u32 {
         match offset {
@@ -403,14 +1259,14 @@ impl VirtioBlk {
                     self.device_features_hi
                 }
             }
-            MMIO_QUEUE_NUM_MAX => MAX_QUEUE_SIZE as u32,
-            MMIO_QUEUE_READY => {
-                if self.queue.ready {
-                    1
-                } else {
-                    0
-                }
-            }
+            MMIO_QUEUE_NUM_MAX => self
+                .queues
+                .get(self.queue_sel as usize)
+                .map_or(0, |_| MAX_QUEUE_SIZE as u32),
+            MMIO_QUEUE_READY => match self.queues.get(self.queue_sel as usize) {
+                Some(queue) if queue.ready => 1,
+                _ => 0,
+            },
             MMIO_INTERRUPT_STATUS => self.interrupt_status,
             MMIO_STATUS => self.status,
 
@@ -419,11 +1275,23 @@ impl VirtioBlk {
             0x104 => (self.capacity >> 32) as u32,
             CONFIG_SIZE_MAX => SIZE_MAX,
             CONFIG_SEG_MAX => SEG_MAX,
-            CONFIG_BLK_SIZE => BLK_SIZE,
+            CONFIG_GEOMETRY => {
+                let cylinders = (self.capacity / (GEOMETRY_HEADS * GEOMETRY_SECTORS)).min(0xFFFF);
+                cylinders as u32 | (GEOMETRY_HEADS as u32) << 16 | (GEOMETRY_SECTORS as u32) << 24
+            }
+            CONFIG_BLK_SIZE => self.blk_size,
+            // Blocks start at offset 0; the minimum I/O is one physical block
+            CONFIG_TOPOLOGY => self.physical_block_exp as u32 | 1 << (16 + self.physical_block_exp),
+            CONFIG_OPT_IO_SIZE => 0,
+            CONFIG_NUM_QUEUES => u32::from(self.num_queues) << 16,
+            CONFIG_MAX_DISCARD_SECTORS | CONFIG_MAX_WRITE_ZEROES_SECTORS => MAX_DISCARD_SECTORS,
+            CONFIG_MAX_DISCARD_SEG | CONFIG_MAX_WRITE_ZEROES_SEG => MAX_DISCARD_SEG,
+            CONFIG_DISCARD_SECTOR_ALIGNMENT => self.blk_size / SECTOR_SIZE as u32,
+            CONFIG_WRITE_ZEROES_MAY_UNMAP => 1,
 
             _ => {
                 if self.request_count < 100 {
-                    eprintln!("[virtio-blk] Unknown register read: {:#x}", offset);
+                    warn!("[virtio-blk] Unknown register read: {:#x}", offset);
                 }
                 0
             }
@@ -450,25 +1318,29 @@ impl VirtioBlk {
                 self.queue_sel = value;
             }
             MMIO_QUEUE_NUM => {
-                if value <= MAX_QUEUE_SIZE as u32 {
-                    self.queue.size = value as u16;
+                if let Some(queue) = self.selected_queue() {
+                    if value <= MAX_QUEUE_SIZE as u32 {
+                        queue.size = value as u16;
+                    }
                 }
             }
             MMIO_QUEUE_READY => {
-                self.queue.ready = value != 0;
-                if self.queue.ready {
-                    eprintln!(
-                        "[virtio-blk] Queue {} ready: desc={:#x} avail={:#x} used={:#x}",
-                        self.queue_sel,
-                        self.queue.desc_table,
-                        self.queue.avail_ring,
-                        self.queue.used_ring
-                    );
+                let queue_sel = self.queue_sel;
+                let event_idx = self.driver_features_lo & VIRTIO_F_EVENT_IDX != 0;
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value != 0;
+                    queue.event_idx = event_idx;
+                    if queue.ready {
+                        debug!(
+                            "[virtio-blk] Queue {} ready: desc={:#x} avail={:#x} used={:#x}",
+                            queue_sel, queue.desc_table, queue.avail_ring, queue.used_ring
+                        );
+                    }
                 }
             }
             MMIO_QUEUE_NOTIFY => {
-                // Guest is notifying us that there are descriptors to process
-                self.process_queue();
+                // Guest is notifying us that queue `value` has descriptors to process
+                self.process_queue(value as usize);
             }
             MMIO_INTERRUPT_ACK => {
                 self.interrupt_status &= !value;
@@ -477,9 +1349,9 @@ impl VirtioBlk {
                 self.status = value;
                 if value == 0 {
                     // Reset
-                    self.queue = Virtqueue::new();
+                    self.queues = new_queues(self.num_queues);
                     self.interrupt_status = 0;
-                    eprintln!("[virtio-blk] Device reset");
+                    debug!("[virtio-blk] Device reset");
                 } else {
                     // Log status transitions
                     let mut flags = Vec::new();
@@ -495,36 +1367,45 @@ impl VirtioBlk {
                     if value & STATUS_DRIVER_OK != 0 {
                         flags.push("DRIVER_OK");
                     }
-                    eprintln!("[virtio-blk] Status: {} ({:#x})", flags.join("|"), value);
+                    debug!("[virtio-blk] Status: {} ({:#x})", flags.join("|"), value);
                 }
             }
             MMIO_QUEUE_DESC_LOW => {
-                self.queue.desc_table =
-                    (self.queue.desc_table & 0xFFFF_FFFF_0000_0000) | value as u64;
+                if let Some(queue) = self.selected_queue() {
+                    queue.desc_table = (queue.desc_table & 0xFFFF_FFFF_0000_0000) | value as u64;
+                }
             }
             MMIO_QUEUE_DESC_HIGH => {
-                self.queue.desc_table =
-                    (self.queue.desc_table & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+                if let Some(queue) = self.selected_queue() {
+                    queue.desc_table =
+                        (queue.desc_table & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+                }
             }
             MMIO_QUEUE_DRIVER_LOW => {
-                self.queue.avail_ring =
-                    (self.queue.avail_ring & 0xFFFF_FFFF_0000_0000) | value as u64;
+                if let Some(queue) = self.selected_queue() {
+                    queue.avail_ring = (queue.avail_ring & 0xFFFF_FFFF_0000_0000) | value as u64;
+                }
             }
             MMIO_QUEUE_DRIVER_HIGH => {
-                self.queue.avail_ring =
-                    (self.queue.avail_ring & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+                if let Some(queue) = self.selected_queue() {
+                    queue.avail_ring =
+                        (queue.avail_ring & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+                }
             }
             MMIO_QUEUE_DEVICE_LOW => {
-                self.queue.used_ring =
-                    (self.queue.used_ring & 0xFFFF_FFFF_0000_0000) | value as u64;
+                if let Some(queue) = self.selected_queue() {
+                    queue.used_ring = (queue.used_ring & 0xFFFF_FFFF_0000_0000) | value as u64;
+                }
             }
             MMIO_QUEUE_DEVICE_HIGH => {
-                self.queue.used_ring =
-                    (self.queue.used_ring & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+                if let Some(queue) = self.selected_queue() {
+                    queue.used_ring =
+                        (queue.used_ring & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+                }
             }
             _ => {
                 if self.request_count < 100 {
-                    eprintln!(
+                    warn!(
                         "[virtio-blk] Unknown register write: {:#x} = {:#x}",
                         offset, value
                     );
@@ -534,6 +1415,223 @@ impl VirtioBlk {
     }
 }
 
+/// Size in bytes of the image open as `file`: its length, or for a block
+/// device, the device's size.
+pub(super) fn image_size(file: &File) -> std::io::Result<u64> {
+    let metadata = file.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0u64;
+    // SAFETY: BLKGETSIZE64 writes one u64 to the valid pointer.
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+/// Whether `len` bytes at `offset` in `file` are entirely a hole, so read
+/// back as zeroes. False if the filesystem can't tell.
+fn is_hole(file: &File, offset: u64, len: u64) -> bool {
+    // SAFETY: lseek on a valid fd has no memory-safety requirements.
+    let data = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+    if data < 0 {
+        // ENXIO: no data at or after `offset`
+        return std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO);
+    }
+    data as u64 >= offset + len
+}
+
+/// Deallocate `len` bytes at `offset` in `file`, leaving a hole that reads
+/// back as zeroes. The file size is unchanged.
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    fallocate(
+        file,
+        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+        offset,
+        len,
+    )
+}
+
+/// Zero `len` bytes at `offset` in `file` without deallocating them.
+fn zero_range(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    fallocate(
+        file,
+        libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+        offset,
+        len,
+    )
+}
+
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> std::io::Result<()> {
+    // SAFETY: fallocate on a valid fd has no memory-safety requirements.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Alignment `O_DIRECT` I/O on `file` needs for offsets, lengths and
+/// memory, as reported by `statx` (`STATX_DIOALIGN`), else 4096.
+fn direct_io_alignment(file: &File) -> u64 {
+    // SAFETY: statx is plain old data, filled in by statx(2).
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    // SAFETY: the path is a valid empty C string and `stx` is writable.
+    let ret = unsafe {
+        libc::statx(
+            file.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            libc::STATX_DIOALIGN,
+            &mut stx,
+        )
+    };
+    if ret == 0 && stx.stx_mask & libc::STATX_DIOALIGN != 0 && stx.stx_dio_offset_align != 0 {
+        u64::from(stx.stx_dio_offset_align.max(stx.stx_dio_mem_align))
+    } else {
+        DIRECT_IO_DEFAULT_ALIGN
+    }
+}
+
+/// A zeroed heap buffer whose address is a multiple of its alignment, for
+/// `O_DIRECT` I/O.
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    /// Allocate `len` bytes (non-zero) aligned to `align` (a power of two).
+    fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("invalid bounce buffer layout");
+        // SAFETY: `layout` has a non-zero size: callers skip empty I/O.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` is a live, initialised allocation of `layout.size()`.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and `&mut self` makes the access unique.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` was allocated with `layout` and not yet freed.
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// The `align`-aligned range enclosing `len` bytes at `offset`, as a start
+/// offset and a zeroed bounce buffer covering it.
+fn bounce_range(offset: u64, len: usize, align: u64) -> (u64, AlignedBuffer) {
+    let start = offset & !(align - 1);
+    let end = (offset + len as u64).next_multiple_of(align);
+    (
+        start,
+        AlignedBuffer::new((end - start) as usize, align as usize),
+    )
+}
+
+/// The SHA-256 digest of the `size`-byte image `disk`, as stored, read
+/// with `direct_align` alignment if opened with `O_DIRECT`.
+fn image_sha256(
+    disk: &File,
+    size: u64,
+    direct_align: Option<u64>,
+) -> std::io::Result<Sha256Digest> {
+    const CHUNK: u64 = 1 << 20;
+    let mut hasher = Hasher::default();
+    let mut buf = vec![0u8; CHUNK as usize];
+    let mut offset = 0;
+    while offset < size {
+        let len = CHUNK.min(size - offset);
+        let chunk = &mut buf[..len as usize];
+        if is_hole(disk, offset, len) {
+            chunk.fill(0);
+        } else if let Some(align) = direct_align {
+            read_aligned(disk, chunk, offset, align)?;
+        } else {
+            read_full_at(disk, chunk, offset)?;
+        }
+        hasher.update(chunk);
+        offset += len;
+    }
+    Ok(hasher.finish())
+}
+
+/// Fill `buf` from `file` at `offset`, stopping early only at end of file.
+fn read_full_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match file.read_at(buf, offset) {
+            Ok(0) => break,
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Read `buf.len()` bytes at `offset` from a file opened with `O_DIRECT`,
+/// through a bounce buffer aligned to `align`.
+fn read_aligned(file: &File, buf: &mut [u8], offset: u64, align: u64) -> std::io::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let (start, mut bounce) = bounce_range(offset, buf.len(), align);
+    read_full_at(file, &mut bounce, start)?;
+    let skip = (offset - start) as usize;
+    buf.copy_from_slice(&bounce[skip..skip + buf.len()]);
+    Ok(())
+}
+
+/// Write `data` at `offset` to a file opened with `O_DIRECT`, through a
+/// bounce buffer aligned to `align`. The partial blocks at either end are
+/// read first so the bytes around `data` are written back unchanged.
+fn write_aligned(file: &File, data: &[u8], offset: u64, align: u64) -> std::io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let (start, mut bounce) = bounce_range(offset, data.len(), align);
+    if start != offset || bounce.len() != data.len() {
+        read_full_at(file, &mut bounce, start)?;
+    }
+    let skip = (offset - start) as usize;
+    bounce[skip..skip + data.len()].copy_from_slice(data);
+    file.write_all_at(&bounce, start)
+}
+
+/// `count` fresh request queues.
+fn new_queues(count: u16) -> Vec<Virtqueue> {
+    (0..count).map(|_| Virtqueue::new()).collect()
+}
+
 impl MmioDevice for VirtioBlk {
     fn read(&mut self, offset: u64, data: &mut [u8]) {
         let value = self.read_register(offset & !0x3); // Align to 4 bytes
@@ -548,7 +1646,7 @@ impl MmioDevice for VirtioBlk {
     fn write(&mut self, offset: u64, data: &[u8]) {
         // Only handle 4-byte aligned writes
         if data.len() != 4 || offset & 0x3 != 0 {
-            eprintln!(
+            warn!(
                 "[virtio-blk] Non-aligned write: offset={:#x} len={}",
                 offset,
                 data.len()
@@ -559,4 +1657,388 @@ impl MmioDevice for VirtioBlk {
         let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
         self.write_register(offset, value);
     }
+
+    fn notify_queues(&self) -> u32 {
+        self.num_queues.into()
+    }
+
+    fn retry(&mut self) {
+        for index in 0..self.queues.len() {
+            if self.queues[index].ready {
+                self.process_queue(index);
+            }
+        }
+    }
+
+    fn save_state(&self) -> Option<DeviceState> {
+        let state = BlkState {
+            capacity: self.capacity,
+            device_features: (self.device_features_hi as u64) << 32
+                | self.device_features_lo as u64,
+            driver_features: (self.driver_features_hi as u64) << 32
+                | self.driver_features_lo as u64,
+            features_sel: self.features_sel,
+            status: self.status,
+            interrupt_status: self.interrupt_status,
+            queue_sel: self.queue_sel,
+            queues: self.queues.clone(),
+        };
+        serde_json::to_value(state).ok()
+    }
+
+    fn restore_state(&mut self, state: &DeviceState) -> Result<(), String> {
+        let state = BlkState::deserialize(state).map_err(|e| e.to_string())?;
+        if state.capacity != self.capacity {
+            return Err(format!(
+                "the disk holds {} sectors, the snapshot {}",
+                self.capacity, state.capacity
+            ));
+        }
+        let device_features =
+            (self.device_features_hi as u64) << 32 | self.device_features_lo as u64;
+        if state.device_features != device_features {
+            return Err(format!(
+                "the disk offers features {device_features:#x}, the snapshot {:#x}",
+                state.device_features
+            ));
+        }
+        if state.queues.len() != self.queues.len() {
+            return Err(format!(
+                "the disk has {} queues, the snapshot {}",
+                self.queues.len(),
+                state.queues.len()
+            ));
+        }
+        self.driver_features_lo = state.driver_features as u32;
+        self.driver_features_hi = (state.driver_features >> 32) as u32;
+        self.features_sel = state.features_sel;
+        self.status = state.status;
+        self.interrupt_status = state.interrupt_status;
+        self.queue_sel = state.queue_sel;
+        self.queues = state.queues;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Where [`Ring`] puts the queue in guest memory.
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const QUEUE_SIZE: u16 = 8;
+
+    /// A virtqueue the test drives as the guest driver would.
+    struct Ring {
+        /// Boxed, as the device keeps a pointer to it.
+        memory: Box<GuestMemory>,
+        /// The guest's avail->idx.
+        avail: u16,
+    }
+
+    impl Ring {
+        /// Guest memory with the queue laid out, and `blk` set up to use it
+        /// as queue `index`.
+        fn new(blk: &mut VirtioBlk, index: usize) -> Self {
+            let memory = Box::new(GuestMemory::new(1 << 20).unwrap());
+            blk.set_memory(&memory);
+            let queue = &mut blk.queues[index];
+            queue.size = QUEUE_SIZE;
+            queue.desc_table = DESC_TABLE;
+            queue.avail_ring = AVAIL_RING;
+            queue.used_ring = USED_RING;
+            queue.ready = true;
+            Self { memory, avail: 0 }
+        }
+
+        fn desc(&self, index: u16, addr: u64, len: u32, flags: u16) {
+            let desc = DESC_TABLE + 16 * u64::from(index);
+            self.memory.write(desc, &addr.to_le_bytes()).unwrap();
+            self.memory.write(desc + 8, &len.to_le_bytes()).unwrap();
+            self.memory.write(desc + 12, &flags.to_le_bytes()).unwrap();
+            self.memory
+                .write(desc + 14, &(index + 1).to_le_bytes())
+                .unwrap();
+        }
+
+        /// Make a one-sector read of `sector` available, returning where
+        /// its status byte goes.
+        fn read(&mut self, sector: u64) -> u64 {
+            let head = (self.avail % (QUEUE_SIZE / 3)) * 3;
+            let buffers = 0x10000 + u64::from(head) * 0x1000;
+            let mut header = [0u8; 16];
+            header[..4].copy_from_slice(&VIRTIO_BLK_T_IN.to_le_bytes());
+            header[8..].copy_from_slice(&sector.to_le_bytes());
+            self.memory.write(buffers, &header).unwrap();
+            self.memory.write(buffers + 0x400, &[0xff]).unwrap();
+            self.desc(head, buffers, 16, VIRTQ_DESC_F_NEXT);
+            self.desc(
+                head + 1,
+                buffers + 0x200,
+                SECTOR_SIZE as u32,
+                VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            );
+            self.desc(head + 2, buffers + 0x400, 1, VIRTQ_DESC_F_WRITE);
+            let slot = AVAIL_RING + 4 + 2 * u64::from(self.avail % QUEUE_SIZE);
+            self.memory.write(slot, &head.to_le_bytes()).unwrap();
+            self.avail = self.avail.wrapping_add(1);
+            self.memory
+                .write(AVAIL_RING + 2, &self.avail.to_le_bytes())
+                .unwrap();
+            buffers + 0x400
+        }
+
+        /// The device's used->idx.
+        fn used(&self) -> u16 {
+            let mut idx = [0u8; 2];
+            self.memory.read(USED_RING + 2, &mut idx).unwrap();
+            u16::from_le_bytes(idx)
+        }
+
+        fn status(&self, addr: u64) -> u8 {
+            let mut status = [0u8];
+            self.memory.read(addr, &mut status).unwrap();
+            status[0]
+        }
+    }
+
+    /// A 1 MiB image named after `name`, removed on drop.
+    struct Image(std::path::PathBuf);
+
+    impl Image {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("carbon-blk-{name}-{}.img", std::process::id()));
+            File::create(&path).unwrap().set_len(1 << 20).unwrap();
+            Self(path)
+        }
+
+        fn open(&self, options: DiskOptions) -> VirtioBlk {
+            VirtioBlk::new(self.0.to_str().unwrap(), options).unwrap()
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_holds_requests() {
+        let image = Image::new("throttle");
+        let mut limit = RateLimit::default();
+        limit.set("iops", "1").unwrap();
+        let mut blk = image.open(DiskOptions {
+            rate_limit: limit,
+            ..DiskOptions::default()
+        });
+        let timer = Arc::new(RetryTimer::new().unwrap());
+        blk.set_retry_timer(timer.clone());
+        let mut ring = Ring::new(&mut blk, 0);
+
+        // The first read takes the bucket's one token; the second stays on
+        // the ring, without waiting, and the timer is armed for it
+        let first = ring.read(0);
+        let second = ring.read(1);
+        let started = std::time::Instant::now();
+        blk.process_queue(0);
+        assert!(started.elapsed() < std::time::Duration::from_millis(500));
+        assert_eq!(ring.used(), 1);
+        assert_eq!(ring.status(first), VIRTIO_BLK_S_OK);
+        assert_eq!(ring.status(second), 0xff);
+        assert_eq!(blk.queues[0].last_avail_idx, 1);
+
+        // Lifting the limit fires the timer, and the retry serves it
+        blk.rate_limiter().set(RateLimit::default());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(timer.take());
+        blk.retry();
+        assert_eq!(ring.used(), 2);
+        assert_eq!(ring.status(second), VIRTIO_BLK_S_OK);
+    }
+
+    /// The `num_queues` config field.
+    fn config_num_queues(blk: &mut VirtioBlk) -> u16 {
+        let mut data = [0u8; 2];
+        blk.read(CONFIG_NUM_QUEUES + 2, &mut data);
+        u16::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_num_queues() {
+        let image = Image::new("num-queues");
+        let mut blk = image.open(DiskOptions::default());
+        assert_eq!(config_num_queues(&mut blk), 1);
+        assert_eq!(blk.notify_queues(), 1);
+
+        // A queue per vCPU, up to the cap
+        for (vcpus, queues) in [(3, 3), (MAX_QUEUES.into(), MAX_QUEUES), (200, MAX_QUEUES)] {
+            blk.set_vcpus(vcpus);
+            assert_eq!(config_num_queues(&mut blk), queues);
+            assert_eq!(blk.notify_queues(), u32::from(queues));
+            assert_eq!(blk.queues.len(), usize::from(queues));
+        }
+
+        // A reset keeps the count
+        blk.set_vcpus(2);
+        blk.write(MMIO_STATUS, &0u32.to_le_bytes());
+        assert_eq!(blk.queues.len(), 2);
+    }
+
+    #[test]
+    fn test_notify_other_queue() {
+        let image = Image::new("queue-notify");
+        let mut blk = image.open(DiskOptions::default());
+        blk.set_vcpus(4);
+        let mut ring = Ring::new(&mut blk, 2);
+        let status = ring.read(0);
+
+        // Notifying another queue leaves queue 2's request alone
+        blk.write(MMIO_QUEUE_NOTIFY, &1u32.to_le_bytes());
+        assert_eq!(ring.used(), 0);
+        blk.write(MMIO_QUEUE_NOTIFY, &2u32.to_le_bytes());
+        assert_eq!(ring.used(), 1);
+        assert_eq!(ring.status(status), VIRTIO_BLK_S_OK);
+        assert_eq!(blk.queues[2].last_avail_idx, 1);
+        assert_eq!(blk.queues[0].last_avail_idx, 0);
+    }
+
+    #[test]
+    fn test_notify_out_of_range() {
+        let image = Image::new("notify-range");
+        let mut blk = image.open(DiskOptions::default());
+        blk.set_vcpus(2);
+        let mut ring = Ring::new(&mut blk, 1);
+        ring.read(0);
+        for queue in [2, u32::from(MAX_QUEUES), u32::MAX] {
+            blk.write(MMIO_QUEUE_NOTIFY, &queue.to_le_bytes());
+        }
+        assert_eq!(ring.used(), 0);
+        assert!(blk.queues.iter().all(|queue| queue.last_avail_idx == 0));
+    }
+
+    #[test]
+    fn test_shared_cache_opt_in() {
+        let image = Image::new("shared-cache");
+        let read_only = DiskOptions {
+            read_only: true,
+            ..DiskOptions::default()
+        };
+        assert!(image.open(read_only.clone()).cache.is_none());
+        let shared = image.open(DiskOptions {
+            shared_cache: true,
+            ..read_only
+        });
+        assert!(shared.cache.is_some());
+    }
+
+    #[test]
+    fn test_aligned_bounce_io() {
+        // The bounce logic is the same with or without O_DIRECT
+        let path = std::env::temp_dir().join(format!("carbon-blk-{}.img", std::process::id()));
+        let image: Vec<u8> = (0..3 * 4096).map(|i| (i / 512) as u8).collect();
+        File::create(&path).unwrap().write_all(&image).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        // A sector-aligned read straddling two 4K blocks
+        let mut buf = vec![0u8; 1024];
+        read_aligned(&file, &mut buf, 3584, 4096).unwrap();
+        assert_eq!(&buf[..512], &[7; 512]);
+        assert_eq!(&buf[512..], &[8; 512]);
+
+        // An unaligned write leaves the rest of both blocks untouched
+        write_aligned(&file, &[0xaa; 1024], 3584, 4096).unwrap();
+        let mut expected = image.clone();
+        expected[3584..4608].fill(0xaa);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        // An aligned write at the end doesn't grow the image
+        write_aligned(&file, &[0x55; 4096], 8192, 4096).unwrap();
+        expected[8192..].fill(0x55);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        let (start, bounce) = bounce_range(4608, 512, 4096);
+        assert_eq!((start, bounce.len()), (4096, 4096));
+        assert_eq!(bounce.as_ptr() as usize % 4096, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let path = std::env::temp_dir().join(format!("carbon-state-{}.img", std::process::id()));
+        File::create(&path).unwrap().set_len(1 << 20).unwrap();
+        let path_str = path.to_str().unwrap();
+        let mut blk = VirtioBlk::new(path_str, DiskOptions::default()).unwrap();
+        blk.write_register(MMIO_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        blk.queues[0].desc_table = 0x1000;
+        blk.queues[0].last_avail_idx = 7;
+        let state = blk.save_state().unwrap();
+        drop(blk);
+
+        let mut restored = VirtioBlk::new(path_str, DiskOptions::default()).unwrap();
+        restored.restore_state(&state).unwrap();
+        assert_eq!(restored.status, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        assert_eq!(restored.queues[0].desc_table, 0x1000);
+        assert_eq!(restored.queues[0].last_avail_idx, 7);
+        drop(restored);
+
+        // A different disk behind the same device is refused
+        File::create(&path).unwrap().set_len(2 << 20).unwrap();
+        let mut other = VirtioBlk::new(path_str, DiskOptions::default()).unwrap();
+        assert!(other.restore_state(&state).is_err());
+        drop(other);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_holes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = std::env::temp_dir().join(format!("carbon-sparse-{}.img", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(1 << 20).unwrap();
+        assert!(is_hole(&file, 0, 1 << 20));
+
+        file.write_all_at(&[0xff; 65536], 65536).unwrap();
+        file.sync_all().unwrap();
+        assert!(!is_hole(&file, 65536, 4096));
+        assert!(is_hole(&file, 0, 65536));
+        let allocated = file.metadata().unwrap().blocks();
+        assert!(allocated > 0);
+
+        let mut image = vec![0u8; 1 << 20];
+        image[65536..131072].fill(0xff);
+        assert_eq!(
+            image_sha256(&file, 1 << 20, None).unwrap(),
+            Sha256Digest::of(&image)
+        );
+
+        punch_hole(&file, 65536, 65536).unwrap();
+        assert!(file.metadata().unwrap().blocks() < allocated);
+        assert!(is_hole(&file, 65536, 4096));
+        let mut buf = [0xffu8; 4096];
+        file.read_exact_at(&mut buf, 65536).unwrap();
+        assert_eq!(buf, [0; 4096]);
+        assert_eq!(file.metadata().unwrap().len(), 1 << 20);
+
+        file.write_all_at(&[0xff; 4096], 0).unwrap();
+        zero_range(&file, 0, 4096).unwrap();
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0; 4096]);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,310 @@
+//! Block cache shared by read-only disks in one process.
+//!
+//! When several VMs in the same Carbon process boot from one base image, each
+//! would otherwise read the same hot boot blocks from the host on its own.
+//! Read-only virtio-blk devices with `shared_cache` set (as every VM on a
+//! [`crate::host::Host`] has) instead read through a [`BlockCache`], and
+//! [`BlockCache::shared`] hands every device opened on the same image the
+//! same cache, so each block is read from the host once. A VM alone in its
+//! process reads through the host page cache only, rather than keep a
+//! second copy of the blocks no one else will read.
+//!
+//! Images are identified by device, inode, size and modification time, so an
+//! image rewritten between VMs gets a fresh cache rather than stale blocks.
+//! A cache lives as long as any device uses it. Only read-only devices use
+//! the cache: a writable disk's blocks belong to that VM alone.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use super::blk::image_size;
+
+/// Granularity of caching.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Default upper bound on cached data per image.
+pub const DEFAULT_CAPACITY: u64 = 256 * 1024 * 1024;
+
+/// Identity of an image file on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ImageKey {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+}
+
+impl ImageKey {
+    fn of(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: image_size(file)?,
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        })
+    }
+}
+
+/// Caches open on some image, so later devices can find them.
+static CACHES: Mutex<Vec<(ImageKey, Weak<BlockCache>)>> = Mutex::new(Vec::new());
+
+/// Cached blocks, on a list from most to least recently used so the least
+/// recently used is evicted without a search.
+#[derive(Default)]
+struct Blocks {
+    /// Each cached block's slot, by block index.
+    map: HashMap<u64, usize>,
+    slots: Vec<Slot>,
+    /// The most and least recently used slots.
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+/// A cached block and its neighbours on the list.
+struct Slot {
+    index: u64,
+    data: Arc<[u8]>,
+    /// More recently used.
+    prev: Option<usize>,
+    /// Less recently used.
+    next: Option<usize>,
+}
+
+impl Blocks {
+    /// Block `index`, if cached, which becomes the most recently used.
+    fn get(&mut self, index: u64) -> Option<Arc<[u8]>> {
+        let slot = *self.map.get(&index)?;
+        self.unlink(slot);
+        self.push_front(slot);
+        Some(self.slots[slot].data.clone())
+    }
+
+    /// Cache block `index` as the most recently used, evicting the least
+    /// recently used if `capacity` blocks are cached.
+    fn insert(&mut self, index: u64, data: Arc<[u8]>, capacity: usize) {
+        let slot = if let Some(&slot) = self.map.get(&index) {
+            // Another device read it meanwhile
+            self.unlink(slot);
+            slot
+        } else if self.slots.len() < capacity {
+            self.slots.push(Slot {
+                index,
+                data: data.clone(),
+                prev: None,
+                next: None,
+            });
+            self.slots.len() - 1
+        } else {
+            let slot = self
+                .tail
+                .expect("a full cache has a least recently used block");
+            self.unlink(slot);
+            self.map.remove(&self.slots[slot].index);
+            slot
+        };
+        self.slots[slot].index = index;
+        self.slots[slot].data = data;
+        self.map.insert(index, slot);
+        self.push_front(slot);
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = (self.slots[slot].prev, self.slots[slot].next);
+        match prev {
+            Some(prev) => self.slots[prev].next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.slots[next].prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    fn push_front(&mut self, slot: usize) {
+        self.slots[slot].prev = None;
+        self.slots[slot].next = self.head;
+        match self.head {
+            Some(head) => self.slots[head].prev = Some(slot),
+            None => self.tail = Some(slot),
+        }
+        self.head = Some(slot);
+    }
+}
+
+/// Read cache over one read-only image.
+pub struct BlockCache {
+    file: File,
+    size: u64,
+    capacity_blocks: usize,
+    blocks: Mutex<Blocks>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// The cache for the image open as `file`, created if no device has it.
+    pub fn shared(file: &File) -> io::Result<Arc<Self>> {
+        let key = ImageKey::of(file)?;
+        let mut caches = CACHES.lock().unwrap_or_else(|e| e.into_inner());
+        caches.retain(|(_, cache)| cache.strong_count() > 0);
+        if let Some(cache) = caches
+            .iter()
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, cache)| cache.upgrade())
+        {
+            return Ok(cache);
+        }
+        let cache = Arc::new(Self::new(file.try_clone()?, key.size, DEFAULT_CAPACITY));
+        caches.push((key, Arc::downgrade(&cache)));
+        Ok(cache)
+    }
+
+    fn new(file: File, size: u64, capacity: u64) -> Self {
+        Self {
+            file,
+            size,
+            capacity_blocks: (capacity / BLOCK_SIZE).max(1) as usize,
+            blocks: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Fill `buf` from the image at byte `offset`, like [`FileExt::read_exact_at`].
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= self.size)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / BLOCK_SIZE;
+            let block = self.block(index)?;
+            let start = (pos - index * BLOCK_SIZE) as usize;
+            let len = (block.len() - start).min((end - pos) as usize);
+            let at = (pos - offset) as usize;
+            buf[at..at + len].copy_from_slice(&block[start..start + len]);
+            pos += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Block `index`, read from the image on a miss.
+    fn block(&self, index: u64) -> io::Result<Arc<[u8]>> {
+        let cached = self
+            .blocks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(index);
+        if let Some(data) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+
+        // Read without holding the lock so other devices aren't stalled
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let start = index * BLOCK_SIZE;
+        let mut data = vec![0u8; BLOCK_SIZE.min(self.size - start) as usize];
+        self.file.read_exact_at(&mut data, start)?;
+        let data: Arc<[u8]> = data.into();
+
+        self.blocks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(index, data.clone(), self.capacity_blocks);
+        Ok(data)
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        debug!(
+            "[virtio-blk] Shared cache: {} hits, {} misses",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn image(name: &str, len: usize) -> (std::path::PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("carbon-cache-{}-{name}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        file.write_all(&data).unwrap();
+        (path.clone(), File::open(&path).unwrap())
+    }
+
+    #[test]
+    fn test_reads_across_blocks() {
+        let (path, file) = image("span", BLOCK_SIZE as usize * 2 + 100);
+        let cache = BlockCache::new(file, BLOCK_SIZE * 2 + 100, DEFAULT_CAPACITY);
+
+        let mut buf = vec![0u8; 300];
+        let offset = BLOCK_SIZE * 2 - 200;
+        cache.read_exact_at(&mut buf, offset).unwrap();
+        let expected: Vec<u8> = (offset..offset + 300).map(|i| (i % 251) as u8).collect();
+        assert_eq!(buf, expected);
+        assert!(cache.read_exact_at(&mut buf, BLOCK_SIZE * 2).is_err());
+
+        cache.read_exact_at(&mut buf[..10], offset).unwrap();
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 1);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let (path, file) = image("evict", BLOCK_SIZE as usize * 3);
+        let cache = BlockCache::new(file, BLOCK_SIZE * 3, BLOCK_SIZE * 2);
+        let mut buf = [0u8; 1];
+        for block in [0, 1, 0, 2] {
+            cache.read_exact_at(&mut buf, block * BLOCK_SIZE).unwrap();
+        }
+        let blocks = cache.blocks.lock().unwrap();
+        let mut cached: Vec<_> = blocks.map.keys().copied().collect();
+        cached.sort();
+        assert_eq!(cached, vec![0, 2]);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_lru_order() {
+        let block = |n: u8| -> Arc<[u8]> { vec![n].into() };
+        let mut blocks = Blocks::default();
+        for index in 0..3 {
+            blocks.insert(index, block(index as u8), 3);
+        }
+        // 0 is now the most recently used, then 2, then 1
+        assert_eq!(blocks.get(0).as_deref(), Some(&[0][..]));
+        blocks.insert(3, block(3), 3);
+        assert!(blocks.get(1).is_none());
+        // Re-inserting a cached block replaces it without evicting any
+        blocks.insert(2, block(20), 3);
+        assert_eq!(blocks.map.len(), 3);
+        blocks.insert(4, block(4), 3);
+        assert!(blocks.get(0).is_none());
+        assert_eq!(blocks.get(2).as_deref(), Some(&[20][..]));
+        assert_eq!(blocks.get(3).as_deref(), Some(&[3][..]));
+        assert_eq!(blocks.get(4).as_deref(), Some(&[4][..]));
+        assert_eq!(blocks.slots.len(), 3);
+    }
+
+    #[test]
+    fn test_same_image_shares_cache() {
+        let (path, file) = image("shared", 4096);
+        let a = BlockCache::shared(&file).unwrap();
+        let b = BlockCache::shared(&File::open(&path).unwrap()).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        std::fs::remove_file(path).ok();
+    }
+}
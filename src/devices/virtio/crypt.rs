@@ -0,0 +1,257 @@
+//! Transparent AES-XTS disk encryption.
+//!
+//! With a key (`--disk path=IMAGE,key-file=KEY`), virtio-blk encrypts every
+//! sector on its way to the image and decrypts it on the way back, so the
+//! image on the host only ever holds ciphertext and the guest needs no
+//! encryption setup of its own, nor has to be trusted with it.
+//!
+//! # Format
+//!
+//! AES-256 in XTS mode (IEEE 1619) over 512-byte sectors, each sector's
+//! tweak being its number as a little-endian 128-bit integer. That is the
+//! layout of dm-crypt's `aes-xts-plain64` with a 512-bit key and no offset,
+//! so an image can be opened on the host for backup or inspection with
+//! `cryptsetup open --type plain --cipher aes-xts-plain64 --key-size 512
+//! --key-file KEY`. There is no header: a wrong key reads as noise rather
+//! than failing to open.
+//!
+//! # Keys
+//!
+//! A key is 64 bytes, the two AES-256 keys, given raw or as 128 hex digits
+//! (a trailing newline is fine). It comes from one of:
+//!
+//! - `key-file=PATH`: a file.
+//! - `key-fd=N`: descriptor N, inherited already open (e.g. a pipe from the
+//!   orchestrator, so the key never touches the host filesystem), read to
+//!   the end and closed.
+//! - `key-command=PROGRAM`: the standard output of PROGRAM, run with the
+//!   image path as its only argument; the hook for fetching keys from a
+//!   KMS. A non-zero exit fails the open.
+//!
+//! The key and AES round keys are wiped from memory when the disk closes.
+//!
+//! # Limits
+//!
+//! XTS hides the contents of sectors, not which sectors were written, and
+//! doesn't detect tampering. Encrypted disks offer neither discard nor
+//! write-zeroes and never punch holes (zeroes are stored as ciphertext like
+//! anything else), and a blank image reads as noise until the guest writes
+//! it, as with any dm-crypt volume.
+
+use crate::audit;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use zeroize::Zeroizing;
+
+/// Bytes encrypted as one unit, with one tweak.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Key size: two AES-256 keys.
+const KEY_SIZE: usize = 64;
+
+/// AES block size.
+const BLOCK_SIZE: usize = 16;
+
+/// Where a disk's key comes from (see [Keys](self#keys)).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeySource {
+    /// `key-file=PATH`.
+    File(PathBuf),
+    /// `key-fd=N`.
+    Fd(RawFd),
+    /// `key-command=PROGRAM`.
+    Command(PathBuf),
+}
+
+impl KeySource {
+    /// Fetch the key for the image `disk_name`.
+    pub fn load(&self, disk_name: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+        let mut key = Zeroizing::new(Vec::new());
+        match self {
+            KeySource::File(path) => {
+                File::open(path)?.read_to_end(&mut key)?;
+                audit::record(audit::Kind::File, "read", &path.display().to_string());
+            }
+            KeySource::Fd(fd) => {
+                // SAFETY: F_GETFD on any integer is harmless; it checks the
+                // descriptor is open before we take ownership of it.
+                if unsafe { libc::fcntl(*fd, libc::F_GETFD) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // SAFETY: the descriptor is open and inherited for us alone.
+                unsafe { File::from_raw_fd(*fd) }.read_to_end(&mut key)?;
+            }
+            KeySource::Command(program) => {
+                let output = Command::new(program)
+                    .arg(disk_name)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output()?;
+                audit::record(audit::Kind::Process, "run", &program.display().to_string());
+                let stdout = Zeroizing::new(output.stdout);
+                if !output.status.success() {
+                    return Err(io::Error::other(format!(
+                        "key command {} failed ({})",
+                        program.display(),
+                        output.status
+                    )));
+                }
+                key.extend_from_slice(&stdout);
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// An AES-256-XTS key schedule.
+pub struct XtsCipher {
+    /// Encrypts the data.
+    data: Aes256,
+    /// Encrypts the tweaks.
+    tweak: Aes256,
+}
+
+impl XtsCipher {
+    /// A cipher for `key`: 64 bytes, raw or as hex digits.
+    pub fn new(key: &[u8]) -> io::Result<Self> {
+        let key = decode_key(key).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "key must be 64 bytes, or 128 hex digits (AES-256-XTS)",
+            )
+        })?;
+        let (data, tweak) = key.split_at(KEY_SIZE / 2);
+        // IEEE 1619 requires the two halves to differ
+        if data == tweak {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "key halves must differ",
+            ));
+        }
+        Ok(Self {
+            data: Aes256::new(GenericArray::from_slice(data)),
+            tweak: Aes256::new(GenericArray::from_slice(tweak)),
+        })
+    }
+
+    /// Encrypt `buf`, a whole number of sectors starting at `sector`.
+    pub fn encrypt(&self, buf: &mut [u8], sector: u64) {
+        self.crypt(buf, sector, true);
+    }
+
+    /// Decrypt `buf`, a whole number of sectors starting at `sector`.
+    pub fn decrypt(&self, buf: &mut [u8], sector: u64) {
+        self.crypt(buf, sector, false);
+    }
+
+    fn crypt(&self, buf: &mut [u8], first_sector: u64, encrypt: bool) {
+        debug_assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        for (sector, data) in (first_sector..).zip(buf.chunks_exact_mut(SECTOR_SIZE)) {
+            let mut tweak = GenericArray::from((sector as u128).to_le_bytes());
+            self.tweak.encrypt_block(&mut tweak);
+            for block in data.chunks_exact_mut(BLOCK_SIZE) {
+                let block = GenericArray::from_mut_slice(block);
+                xor(block, &tweak);
+                if encrypt {
+                    self.data.encrypt_block(block);
+                } else {
+                    self.data.decrypt_block(block);
+                }
+                xor(block, &tweak);
+                // Multiply the tweak by x in GF(2^128)
+                let value = u128::from_le_bytes(tweak.into());
+                let carry = if value >> 127 != 0 { 0x87 } else { 0 };
+                tweak = GenericArray::from(((value << 1) ^ carry).to_le_bytes());
+            }
+        }
+    }
+}
+
+fn xor(block: &mut [u8], tweak: &[u8]) {
+    for (byte, t) in block.iter_mut().zip(tweak) {
+        *byte ^= t;
+    }
+}
+
+/// The 64-byte key in `key`, raw or as hex digits with optional trailing
+/// whitespace.
+fn decode_key(key: &[u8]) -> Option<Zeroizing<[u8; KEY_SIZE]>> {
+    let mut decoded = Zeroizing::new([0u8; KEY_SIZE]);
+    if key.len() == KEY_SIZE {
+        decoded.copy_from_slice(key);
+        return Some(decoded);
+    }
+    let hex = key.trim_ascii_end();
+    if hex.len() != 2 * KEY_SIZE {
+        return None;
+    }
+    for (byte, pair) in decoded.iter_mut().zip(hex.chunks_exact(2)) {
+        let digit = |c: u8| (c as char).to_digit(16);
+        *byte = (digit(pair[0])? << 4 | digit(pair[1])?) as u8;
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> Vec<u8> {
+        (0..KEY_SIZE as u8).collect()
+    }
+
+    #[test]
+    fn test_xts_roundtrip() {
+        let cipher = XtsCipher::new(&test_key()).unwrap();
+        let plain: Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| (i * 7) as u8).collect();
+        let mut buf = plain.clone();
+        cipher.encrypt(&mut buf, 5);
+        // Matches aes-xts-plain64 (from an independent implementation)
+        assert_eq!(buf[..4], [0x35, 0xaf, 0x68, 0x69]);
+        assert_eq!(buf[SECTOR_SIZE..SECTOR_SIZE + 4], [0x37, 0x37, 0x3b, 0x13]);
+        assert_eq!(buf[buf.len() - 4..], [0xbf, 0x76, 0xe8, 0x17]);
+
+        // Each sector is encrypted on its own
+        let mut second = plain[SECTOR_SIZE..].to_vec();
+        cipher.encrypt(&mut second, 6);
+        assert_eq!(second, buf[SECTOR_SIZE..]);
+
+        cipher.decrypt(&mut buf, 5);
+        assert_eq!(buf, plain);
+    }
+
+    #[test]
+    fn test_decode_key() {
+        let key = test_key();
+        let hex: String = key.iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(*decode_key(&key).unwrap(), key[..]);
+        assert_eq!(*decode_key(format!("{hex}\n").as_bytes()).unwrap(), key[..]);
+        assert!(decode_key(&key[..32]).is_none());
+        assert!(decode_key(hex.replace('0', "g").as_bytes()).is_none());
+        assert!(XtsCipher::new(&[7; KEY_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_key_sources() {
+        let path = std::env::temp_dir().join(format!("carbon-key-{}", std::process::id()));
+        std::fs::write(&path, test_key()).unwrap();
+        let key = KeySource::File(path.clone()).load("disk.img").unwrap();
+        assert_eq!(*key, test_key());
+        std::fs::remove_file(&path).unwrap();
+
+        let key = KeySource::Command("/bin/echo".into())
+            .load("disk.img")
+            .unwrap();
+        assert_eq!(*key, b"disk.img\n"[..]);
+        assert!(KeySource::Command("/bin/false".into())
+            .load("disk.img")
+            .is_err());
+    }
+}
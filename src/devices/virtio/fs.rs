@@ -0,0 +1,122 @@
+//! Virtio filesystem device (virtio-fs), backed by vhost-user.
+//!
+//! Shares a host directory with the guest without building a disk image.
+//! The filesystem itself is served by an external vhost-user backend,
+//! usually virtiofsd:
+//!
+//! ```text
+//! virtiofsd --socket-path=/run/vfsd.sock --shared-dir=/srv/workspace &
+//! carbon ... --shared-dir socket=/run/vfsd.sock,tag=workspace
+//! # in the guest:
+//! mount -t virtiofs workspace /mnt
+//! ```
+//!
+//! The device itself is a
+//! [`VhostUserDevice`](super::vhost_user_device::VhostUserDevice); this
+//! module only describes it: the tag goes in config space, alongside the
+//! number of request queues.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::vhost_user_device::{ConfigSpace, VhostUserSpec};
+use super::Transport;
+
+/// Virtio device ID for filesystem devices.
+const VIRTIO_FS_DEVICE_ID: u32 = 26;
+
+/// Config space: the tag the guest mounts by (36 bytes, NUL-padded),
+/// followed by the number of request queues (4 bytes).
+const TAG_LEN: usize = 36;
+
+/// One high-priority queue plus one request queue.
+const NUM_QUEUES: usize = 2;
+
+/// `--shared-dir socket=PATH,tag=TAG[,transport=pci]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedDirConfig {
+    /// The vhost-user backend's socket.
+    pub socket: PathBuf,
+    /// Name the guest mounts the filesystem by.
+    pub tag: String,
+    /// How the device is attached.
+    pub transport: Transport,
+}
+
+impl FromStr for SharedDirConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut socket, mut tag, mut transport) = (None, None, Transport::default());
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("socket", value)) if !value.is_empty() => {
+                    socket = Some(PathBuf::from(value));
+                }
+                Some(("tag", value)) => {
+                    if value.is_empty() || value.len() > TAG_LEN {
+                        return Err(format!("tag must be 1 to {TAG_LEN} bytes"));
+                    }
+                    tag = Some(value.to_string());
+                }
+                Some(("transport", value)) => transport = value.parse()?,
+                _ => return Err(format!("invalid shared-dir option {option:?}")),
+            }
+        }
+        Ok(Self {
+            socket: socket.ok_or("shared-dir needs socket=PATH")?,
+            tag: tag.ok_or("shared-dir needs tag=TAG")?,
+            transport,
+        })
+    }
+}
+
+impl SharedDirConfig {
+    /// The virtio-fs device the backend implements.
+    pub fn spec(&self) -> VhostUserSpec {
+        let mut config = vec![0u8; TAG_LEN];
+        config[..self.tag.len()].copy_from_slice(self.tag.as_bytes());
+        config.extend_from_slice(&(NUM_QUEUES as u32 - 1).to_le_bytes());
+        VhostUserSpec {
+            name: "virtio-fs",
+            device_id: VIRTIO_FS_DEVICE_ID,
+            num_queues: NUM_QUEUES,
+            config: ConfigSpace::Fixed(config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            "socket=/run/vfsd.sock,tag=workspace".parse(),
+            Ok(SharedDirConfig {
+                socket: PathBuf::from("/run/vfsd.sock"),
+                tag: "workspace".into(),
+                transport: Transport::Mmio,
+            })
+        );
+        assert!("socket=/run/vfsd.sock".parse::<SharedDirConfig>().is_err());
+        assert!(format!("socket=/s,tag={}", "x".repeat(37))
+            .parse::<SharedDirConfig>()
+            .is_err());
+        assert!("socket=/s,tag=t,cache=always"
+            .parse::<SharedDirConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_spec() {
+        let config: SharedDirConfig = "socket=/s,tag=work".parse().unwrap();
+        let ConfigSpace::Fixed(bytes) = config.spec().config else {
+            panic!("virtio-fs config is built by Carbon");
+        };
+        assert_eq!(bytes.len(), TAG_LEN + 4);
+        assert_eq!(&bytes[..5], b"work\0");
+        assert_eq!(bytes[TAG_LEN..], [1, 0, 0, 0]);
+    }
+}
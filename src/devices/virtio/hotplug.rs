@@ -0,0 +1,327 @@
+//! Virtio-blk hot-plug (`--hotplug-disks N`).
+//!
+//! Disks can be attached and removed while the guest runs, over the control
+//! socket (see [`crate::control`]). The guest only learns about virtio-mmio
+//! devices from the DSDT, written once at boot, so `--hotplug-disks N`
+//! reserves N free virtio-blk slots up front: each is described like any
+//! other virtio-mmio device, but reads as device ID 0 while it is empty,
+//! which Linux's virtio-mmio driver skips as a placeholder.
+//!
+//! # Plugging
+//!
+//! `disk-plug` opens an image into the first free slot. The guest then has
+//! to probe the slot again; probing a bound device does nothing, so a
+//! rescan of every virtio-mmio device picks up all new disks:
+//!
+//! ```text
+//! for dev in /sys/bus/platform/devices/LNRO0005:*; do
+//!     echo "${dev##*/}" > /sys/bus/platform/drivers_probe
+//! done
+//! ```
+//!
+//! # Unplugging
+//!
+//! `disk-unplug` syncs and closes a disk the guest isn't using (never
+//! probed, or unbound through `/sys/bus/platform/drivers/virtio-mmio/unbind`)
+//! and frees its slot at once. A disk still in use is removed from under the
+//! guest instead (see [Removal](super::blk#removal)): requests run to
+//! completion under the slot's lock, so none is in flight, and the rest
+//! fail. Its slot is freed, and the image closed, when the guest driver
+//! resets the device.
+//!
+//! Only hot-plugged disks can be unplugged, and slots use the MMIO
+//! transport.
+
+use super::blk::{DiskOptions, VirtioBlk};
+use super::rate_limiter::{RateLimiter, RetryTimer};
+use super::{MMIO_DEVICE_ID, MMIO_MAGIC_VALUE, MMIO_VENDOR_ID, MMIO_VERSION};
+use super::{VIRTIO_MMIO_MAGIC, VIRTIO_MMIO_VERSION, VIRTIO_VENDOR_ID};
+use crate::boot::GuestMemory;
+use crate::devices::mmio::MmioDevice;
+use crate::kvm::IrqLine;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// One reserved virtio-blk slot.
+struct Slot {
+    /// The disk number the control socket knows it by.
+    number: usize,
+    mmio_base: u64,
+    /// The slot's GSI; each plugged disk gets a clone.
+    irq: Arc<dyn IrqLine>,
+    disk: Option<VirtioBlk>,
+}
+
+/// State shared by the slots' MMIO devices and the control socket.
+struct Slots {
+    /// Guest memory, until the VM stops.
+    memory: Option<*const GuestMemory>,
+    /// Armed by plugged disks holding back requests over their limits.
+    retry_timer: Arc<RetryTimer>,
+    /// The VM's vCPUs, for plugged disks' queues.
+    vcpus: u32,
+    slots: Vec<Slot>,
+}
+
+// Safety: the GuestMemory pointer is only dereferenced under the lock while
+// it is set, and DiskSlots clears it before the memory is dropped.
+unsafe impl Send for Slots {}
+
+/// The reserved slots, owned by the VMM. Dropping them closes every
+/// hot-plugged disk, and later commands fail.
+pub struct DiskSlots {
+    shared: Arc<Mutex<Slots>>,
+}
+
+/// A handle for plugging and unplugging disks in [`DiskSlots`].
+#[derive(Clone)]
+pub struct DiskHotplug {
+    shared: Arc<Mutex<Slots>>,
+}
+
+fn lock(shared: &Mutex<Slots>) -> MutexGuard<'_, Slots> {
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl DiskSlots {
+    /// Empty slots for disks `first`, `first + 1`, ..., one per
+    /// `(mmio_base, irq)`, whose disks have queues for `vcpus` and arm
+    /// `retry_timer`.
+    ///
+    /// `memory` must outlive the slots.
+    pub fn new(
+        memory: &GuestMemory,
+        first: usize,
+        slots: Vec<(u64, Arc<dyn IrqLine>)>,
+        vcpus: u32,
+        retry_timer: Arc<RetryTimer>,
+    ) -> Self {
+        let slots = slots
+            .into_iter()
+            .enumerate()
+            .map(|(i, (mmio_base, irq))| Slot {
+                number: first + i,
+                mmio_base,
+                irq,
+                disk: None,
+            })
+            .collect();
+        Self {
+            shared: Arc::new(Mutex::new(Slots {
+                memory: Some(memory as *const GuestMemory),
+                retry_timer,
+                vcpus,
+                slots,
+            })),
+        }
+    }
+
+    /// Each slot's MMIO base and device, for the MMIO bus.
+    pub fn devices(&self) -> Vec<(u64, Box<dyn MmioDevice>)> {
+        lock(&self.shared)
+            .slots
+            .iter()
+            .enumerate()
+            .map(|(index, slot)| {
+                let device = SlotDevice {
+                    shared: self.shared.clone(),
+                    index,
+                };
+                (slot.mmio_base, Box::new(device) as Box<dyn MmioDevice>)
+            })
+            .collect()
+    }
+
+    /// A handle for the control socket.
+    pub fn handle(&self) -> DiskHotplug {
+        DiskHotplug {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for DiskSlots {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.memory = None;
+        for slot in &mut shared.slots {
+            slot.disk = None;
+        }
+    }
+}
+
+impl DiskHotplug {
+    /// Open the image at `path` into the first free slot. Returns the disk
+    /// number and the slot's MMIO base.
+    pub fn plug(&self, path: &str, options: DiskOptions) -> Result<(usize, u64), String> {
+        let mut disk = VirtioBlk::new(path, options).map_err(|e| format!("{path}: {e}"))?;
+        let mut shared = lock(&self.shared);
+        let memory = shared.memory.ok_or("the VM has stopped")?;
+        disk.set_vcpus(shared.vcpus);
+        disk.set_retry_timer(shared.retry_timer.clone());
+        let slot = shared
+            .slots
+            .iter_mut()
+            .find(|slot| slot.disk.is_none())
+            .ok_or("no free hot-plug slot")?;
+        // SAFETY: the memory is alive while it is set (see Slots).
+        disk.set_memory(unsafe { &*memory });
+        disk.set_interrupt(slot.irq.clone());
+        slot.disk = Some(disk);
+        info!(
+            "[VMM] Disk {} plugged at {:#x}: {}",
+            slot.number, slot.mmio_base, path
+        );
+        Ok((slot.number, slot.mmio_base))
+    }
+
+    /// Unplug disk `number`: close it if the guest isn't using it, else
+    /// remove it from under the guest.
+    pub fn unplug(&self, number: usize) -> Result<(), String> {
+        let mut shared = lock(&self.shared);
+        let slot = shared
+            .slots
+            .iter_mut()
+            .find(|slot| slot.number == number)
+            .filter(|slot| slot.disk.is_some())
+            .ok_or_else(|| format!("no disk {number}"))?;
+        let disk = slot.disk.as_mut().unwrap();
+        if disk.is_removed() {
+            return Err(format!(
+                "disk {number} is already unplugged, waiting for the guest to release it"
+            ));
+        }
+        if disk.in_use() {
+            disk.remove();
+            info!(
+                "[VMM] Disk {} removed; its slot frees up when the guest releases it",
+                number
+            );
+        } else {
+            slot.disk = None;
+            info!("[VMM] Disk {} unplugged", number);
+        }
+        Ok(())
+    }
+
+    /// The rate limiter of disk `number`, if it is plugged in.
+    pub fn rate_limiter(&self, number: usize) -> Option<Arc<RateLimiter>> {
+        lock(&self.shared)
+            .slots
+            .iter()
+            .find(|slot| slot.number == number)?
+            .disk
+            .as_ref()
+            .map(VirtioBlk::rate_limiter)
+    }
+}
+
+/// A slot on the MMIO bus: its disk, or an empty virtio-mmio device.
+struct SlotDevice {
+    shared: Arc<Mutex<Slots>>,
+    index: usize,
+}
+
+impl MmioDevice for SlotDevice {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let mut shared = lock(&self.shared);
+        if let Some(disk) = &mut shared.slots[self.index].disk {
+            return disk.read(offset, data);
+        }
+        // Device ID 0: a placeholder the driver skips
+        let value = match offset & !0x3 {
+            MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            MMIO_VERSION => VIRTIO_MMIO_VERSION,
+            MMIO_DEVICE_ID => 0,
+            MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let start = (offset & 0x3) as usize;
+        let len = data.len().min(4 - start);
+        data[..len].copy_from_slice(&bytes[start..start + len]);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut shared = lock(&self.shared);
+        let slot = &mut shared.slots[self.index];
+        if let Some(disk) = &mut slot.disk {
+            disk.write(offset, data);
+            if disk.is_removed() && !disk.in_use() {
+                slot.disk = None;
+                info!("[VMM] Disk {} released by the guest", slot.number);
+            }
+        }
+    }
+
+    fn retry(&mut self) {
+        if let Some(disk) = &mut lock(&self.shared).slots[self.index].disk {
+            disk.retry();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::MMIO_STATUS;
+    use crate::devices::virtio::{MMIO_CONFIG, MMIO_INTERRUPT_STATUS};
+    use crate::kvm::Irqfd;
+
+    fn read_u32(device: &mut dyn MmioDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_plug_unplug() {
+        let path = std::env::temp_dir().join(format!("carbon-hotplug-{}.img", std::process::id()));
+        std::fs::File::create(&path)
+            .unwrap()
+            .set_len(1 << 20)
+            .unwrap();
+        let path = path.to_str().unwrap();
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let timer = Arc::new(RetryTimer::new().unwrap());
+        let slots = DiskSlots::new(
+            &memory,
+            2,
+            vec![(0x1000, Arc::new(Irqfd::unconnected(16)))],
+            1,
+            timer,
+        );
+        let (_, mut device) = slots.devices().pop().unwrap();
+        let hotplug = slots.handle();
+
+        // Empty, then a disk, then empty again
+        assert_eq!(
+            read_u32(device.as_mut(), MMIO_MAGIC_VALUE),
+            VIRTIO_MMIO_MAGIC
+        );
+        assert_eq!(read_u32(device.as_mut(), MMIO_DEVICE_ID), 0);
+        assert!(hotplug.unplug(2).is_err());
+        assert_eq!(hotplug.plug(path, DiskOptions::default()), Ok((2, 0x1000)));
+        assert_eq!(read_u32(device.as_mut(), MMIO_DEVICE_ID), 2);
+        assert!(hotplug.rate_limiter(2).is_some());
+        assert!(hotplug.plug(path, DiskOptions::default()).is_err());
+        hotplug.unplug(2).unwrap();
+        assert_eq!(read_u32(device.as_mut(), MMIO_DEVICE_ID), 0);
+        assert!(hotplug.rate_limiter(2).is_none());
+
+        // In use: removed, then freed once the guest resets it
+        hotplug.plug(path, DiskOptions::default()).unwrap();
+        device.write(MMIO_STATUS, &1u32.to_le_bytes());
+        hotplug.unplug(2).unwrap();
+        assert_eq!(read_u32(device.as_mut(), MMIO_DEVICE_ID), 2);
+        assert_eq!(read_u32(device.as_mut(), MMIO_CONFIG), 0);
+        assert_eq!(read_u32(device.as_mut(), MMIO_INTERRUPT_STATUS) & 2, 2);
+        assert!(hotplug.unplug(2).is_err());
+        device.write(MMIO_STATUS, &0u32.to_le_bytes());
+        assert_eq!(read_u32(device.as_mut(), MMIO_DEVICE_ID), 0);
+
+        // Nothing can be plugged once the VM has stopped
+        drop(slots);
+        assert!(hotplug.plug(path, DiskOptions::default()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -30,7 +30,7 @@
 //!
 //! # MMIO Transport
 //!
-//! We use the virtio-mmio transport (as opposed to PCI). The device appears
+//! By default devices use the virtio-mmio transport. The device appears
 //! at a fixed memory address and is discovered via kernel command line:
 //!
 //! ```text
@@ -39,11 +39,34 @@
 //!
 //! This tells Linux: "There's a 4KB virtio device at address 0xd0000000, IRQ 5"
 //!
+//! # PCI Transport
+//!
+//! For guest kernels built without virtio-mmio, a device can sit on the PCI
+//! bus instead ([`Transport::Pci`]). [`pci::VirtioPci`] wraps the same
+//! device model and translates virtio-pci register accesses into virtio-mmio
+//! ones, so devices only implement the MMIO register interface.
+//!
 //! Reference: <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>
 
 pub mod blk;
+mod cache;
+pub mod crypt;
+pub mod fs;
+pub mod hotplug;
+pub mod p9;
+pub mod pci;
+pub mod pmem;
+mod prefetch;
+pub mod probe;
+pub mod rate_limiter;
+mod vhost_user;
+pub mod vhost_user_device;
+pub mod vsock;
 
 use crate::boot::GuestMemory;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{fence, Ordering};
 
 // ============================================================================
 // MMIO Register Offsets (virtio-mmio v2)
@@ -115,6 +138,9 @@ pub const MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
 /// Queue device (used) high address register (write).
 pub const MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
 
+/// Start of the device-specific config space.
+pub const MMIO_CONFIG: u64 = 0x100;
+
 // ============================================================================
 // Magic and Version
 // ============================================================================
@@ -144,6 +170,45 @@ pub const STATUS_DRIVER_OK: u32 = 4;
 /// Feature negotiation complete.
 pub const STATUS_FEATURES_OK: u32 = 8;
 
+/// INTERRUPT_STATUS bit: the device configuration changed.
+pub const VIRTIO_MMIO_INT_CONFIG: u32 = 1 << 1;
+
+// ============================================================================
+// Transport Feature Bits
+// ============================================================================
+
+/// VIRTIO_F_EVENT_IDX (bit 29, low features word): the driver publishes
+/// `used_event` and the device `avail_event`, so each side only notifies the
+/// other once it passes that index (see [`Virtqueue::event_idx`]).
+pub const VIRTIO_F_EVENT_IDX: u32 = 1 << 29;
+
+// ============================================================================
+// Transports
+// ============================================================================
+
+/// How a device is attached to the guest: `transport=mmio|pci` in its
+/// command-line options.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transport {
+    /// A virtio-mmio region described in the DSDT.
+    #[default]
+    Mmio,
+    /// A virtio-pci function on the PCI host bridge.
+    Pci,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mmio" => Ok(Self::Mmio),
+            "pci" => Ok(Self::Pci),
+            _ => Err(format!("invalid transport {s:?} (expected mmio or pci)")),
+        }
+    }
+}
+
 // ============================================================================
 // Virtqueue Structures
 // ============================================================================
@@ -200,7 +265,14 @@ impl VirtqDesc {
 /// - Descriptor table: array of buffer descriptors
 /// - Available ring: guest tells device which descriptors are ready
 /// - Used ring: device tells guest which descriptors are complete
-#[derive(Debug, Default)]
+///
+/// With [`VIRTIO_F_EVENT_IDX`] negotiated, each ring also carries an event
+/// index after its entries: `used_event` at the end of the available ring
+/// (the guest wants an interrupt once the used index passes it) and
+/// `avail_event` at the end of the used ring (we want a QUEUE_NOTIFY once
+/// the available index passes it). Devices set [`Self::event_idx`] and ask
+/// [`Self::needs_interrupt`] before raising an interrupt.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Virtqueue {
     /// Queue size (number of descriptors).
     pub size: u16,
@@ -214,6 +286,10 @@ pub struct Virtqueue {
     pub used_ring: u64,
     /// Last available index we processed.
     pub last_avail_idx: u16,
+    /// VIRTIO_F_EVENT_IDX was negotiated.
+    pub event_idx: bool,
+    /// Used entries added since the last [`Self::needs_interrupt`].
+    pub num_added: u16,
 }
 
 impl Virtqueue {
@@ -266,9 +342,32 @@ impl Virtqueue {
         let desc_idx = u16::from_le_bytes(desc_idx_buf);
 
         self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        self.set_avail_event(memory)?;
         Some(desc_idx)
     }
 
+    /// Put back the chain [`Self::pop_avail`] just returned, to be popped
+    /// again later.
+    pub fn undo_pop(&mut self, memory: &GuestMemory) {
+        self.last_avail_idx = self.last_avail_idx.wrapping_sub(1);
+        let _ = self.set_avail_event(memory);
+    }
+
+    /// With EVENT_IDX, ask to be notified only for entries past the ones
+    /// we've taken: while the guest sees us behind, it queues without
+    /// notifying. The fence orders this store before the caller re-reads
+    /// avail->idx, so an entry added meanwhile is never missed.
+    fn set_avail_event(&self, memory: &GuestMemory) -> Option<()> {
+        if self.event_idx {
+            let avail_event_addr = self.used_ring + 4 + self.size as u64 * 8;
+            memory
+                .write(avail_event_addr, &self.last_avail_idx.to_le_bytes())
+                .ok()?;
+            fence(Ordering::SeqCst);
+        }
+        Some(())
+    }
+
     /// Add a descriptor chain to the used ring.
     ///
     /// # Arguments
@@ -276,11 +375,13 @@ impl Virtqueue {
     /// * `memory` - Guest memory
     /// * `desc_idx` - Head descriptor index of the completed chain
     /// * `len` - Total bytes written to the guest buffers
-    pub fn push_used(&self, memory: &GuestMemory, desc_idx: u16, len: u32) -> Result<(), ()> {
+    ///
+    /// Returns `None` if the used ring isn't in guest memory.
+    pub fn push_used(&mut self, memory: &GuestMemory, desc_idx: u16, len: u32) -> Option<()> {
         // Read used->idx
         let used_idx_addr = self.used_ring + 2;
         let mut idx_buf = [0u8; 2];
-        memory.read(used_idx_addr, &mut idx_buf).map_err(|_| ())?;
+        memory.read(used_idx_addr, &mut idx_buf).ok()?;
         let used_idx = u16::from_le_bytes(idx_buf);
 
         // Write used->ring[used_idx % size]
@@ -291,19 +392,47 @@ impl Virtqueue {
         // Write id (descriptor index as u32)
         memory
             .write(elem_addr, &(desc_idx as u32).to_le_bytes())
-            .map_err(|_| ())?;
+            .ok()?;
         // Write len
-        memory
-            .write(elem_addr + 4, &len.to_le_bytes())
-            .map_err(|_| ())?;
+        memory.write(elem_addr + 4, &len.to_le_bytes()).ok()?;
 
         // Increment used->idx
         let new_idx = used_idx.wrapping_add(1);
-        memory
-            .write(used_idx_addr, &new_idx.to_le_bytes())
-            .map_err(|_| ())?;
+        memory.write(used_idx_addr, &new_idx.to_le_bytes()).ok()?;
+        self.num_added = self.num_added.wrapping_add(1);
 
-        Ok(())
+        Some(())
+    }
+
+    /// Whether the guest wants an interrupt for the used entries added
+    /// since the last call.
+    ///
+    /// Without EVENT_IDX that is any new entry. With it, only when the used
+    /// index moved past the guest's `used_event`; otherwise the guest is
+    /// still working through the used ring and will see the new entries.
+    pub fn needs_interrupt(&mut self, memory: &GuestMemory) -> bool {
+        let added = std::mem::take(&mut self.num_added);
+        if added == 0 {
+            return false;
+        }
+        if !self.event_idx {
+            return true;
+        }
+        // Order our used->idx store before reading the guest's used_event
+        fence(Ordering::SeqCst);
+        let mut buf = [0u8; 2];
+        let used_event_addr = self.avail_ring + 4 + self.size as u64 * 2;
+        if memory.read(used_event_addr, &mut buf).is_err() {
+            return true;
+        }
+        let used_event = u16::from_le_bytes(buf);
+        if memory.read(self.used_ring + 2, &mut buf).is_err() {
+            return true;
+        }
+        let used_idx = u16::from_le_bytes(buf);
+        // vring_need_event(): did we step over used_event since the last
+        // interrupt, i.e. is it in (used_idx - added, used_idx]?
+        used_idx.wrapping_sub(used_event).wrapping_sub(1) < added
     }
 
     /// Read a descriptor from the descriptor table.
@@ -315,3 +444,74 @@ impl Virtqueue {
         VirtqDesc::read_from(memory, desc_addr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A queue of 8 descriptors at 0x1000, with `count` entries available.
+    fn queue(memory: &GuestMemory, count: u16, event_idx: bool) -> Virtqueue {
+        for i in 0..count {
+            memory
+                .write(0x1100 + 4 + i as u64 * 2, &i.to_le_bytes())
+                .unwrap();
+        }
+        memory.write(0x1100 + 2, &count.to_le_bytes()).unwrap();
+        Virtqueue {
+            size: 8,
+            ready: true,
+            desc_table: 0x1000,
+            avail_ring: 0x1100,
+            used_ring: 0x1200,
+            event_idx,
+            ..Virtqueue::default()
+        }
+    }
+
+    fn read_u16(memory: &GuestMemory, addr: u64) -> u16 {
+        let mut buf = [0u8; 2];
+        memory.read(addr, &mut buf).unwrap();
+        u16::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn test_event_idx() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let mut queue = queue(&memory, 4, true);
+        let used_event = 0x1100 + 4 + 8 * 2;
+        let avail_event = 0x1200 + 4 + 8 * 8;
+
+        // Each pop moves avail_event along with us
+        let head = queue.pop_avail(&memory).unwrap();
+        assert_eq!(read_u16(&memory, avail_event), 1);
+        queue.push_used(&memory, head, 0).unwrap();
+
+        // The guest wants to hear about the second completion, not the first
+        memory.write(used_event, &1u16.to_le_bytes()).unwrap();
+        assert!(!queue.needs_interrupt(&memory));
+        let head = queue.pop_avail(&memory).unwrap();
+        queue.push_used(&memory, head, 0).unwrap();
+        assert!(queue.needs_interrupt(&memory));
+        assert!(!queue.needs_interrupt(&memory));
+
+        // Stepping over used_event in a batch still interrupts
+        memory.write(used_event, &2u16.to_le_bytes()).unwrap();
+        for _ in 0..2 {
+            let head = queue.pop_avail(&memory).unwrap();
+            queue.push_used(&memory, head, 0).unwrap();
+        }
+        assert!(queue.needs_interrupt(&memory));
+        assert_eq!(read_u16(&memory, avail_event), 4);
+    }
+
+    #[test]
+    fn test_no_event_idx() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let mut queue = queue(&memory, 1, false);
+        let head = queue.pop_avail(&memory).unwrap();
+        assert_eq!(read_u16(&memory, 0x1200 + 4 + 8 * 8), 0);
+        assert!(!queue.needs_interrupt(&memory));
+        queue.push_used(&memory, head, 0).unwrap();
+        assert!(queue.needs_interrupt(&memory));
+    }
+}
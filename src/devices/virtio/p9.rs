@@ -0,0 +1,1673 @@
+//! Virtio 9P device with an in-process 9P2000.L server.
+//!
+//! A lighter alternative to virtio-fs: Carbon serves the host directory
+//! itself, so no daemon has to be started first:
+//!
+//! ```text
+//! carbon ... --9p path=/srv/workspace,tag=host
+//! # in the guest:
+//! mount -t 9p -o trans=virtio,version=9p2000.L host /mnt
+//! ```
+//!
+//! The guest sends each request (a T-message) in the device-readable part
+//! of a descriptor chain and gets the reply (R-message) in the writable
+//! part. Every message starts with `size[4] type[1] tag[2]`; integers are
+//! little-endian and strings are `len[2]` followed by the bytes.
+//!
+//! The guest names files by *fid*, a handle it picks: `Tattach` binds one
+//! to the shared root, `Twalk` derives new ones a path component at a time,
+//! and the remaining requests act on them.
+//!
+//! # Confinement
+//!
+//! Each fid holds an `O_PATH` handle on its file, and requests act on that
+//! handle or on a single name in it with the `*at` syscalls, never on a
+//! path joined onto the root. The guest resolves symlinks itself (it reads
+//! them with `Treadlink` and walks the target), so nothing here follows
+//! one: walks stop at them, and paths are opened with `openat2` and
+//! `RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS`, so a symlink anywhere along
+//! the way, made by the guest or already on the host, is an error rather
+//! than a way out. `..` is resolved lexically, stopping at the root.
+//! `Tlopen` reopens the fid's handle itself, so a fid keeps naming the file
+//! it was walked to even if the host moves things around underneath it.
+//! Requests longer than the negotiated msize are dropped unread.
+//!
+//! Files are created as Carbon's user and stay its: guest-requested groups
+//! are ignored and changing an owner fails with `EPERM`. `Tmknod` makes
+//! FIFOs and sockets only, never device nodes.
+//!
+//! Reference: <https://github.com/chaos/diod/blob/master/protocol.md>
+
+use crate::audit;
+use crate::devices::mmio::MmioDevice;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::{
+    Transport, VirtqDesc, Virtqueue, MAX_QUEUE_SIZE, MMIO_DEVICE_FEATURES,
+    MMIO_DEVICE_FEATURES_SEL, MMIO_DEVICE_ID, MMIO_DRIVER_FEATURES, MMIO_DRIVER_FEATURES_SEL,
+    MMIO_INTERRUPT_ACK, MMIO_INTERRUPT_STATUS, MMIO_MAGIC_VALUE, MMIO_QUEUE_DESC_HIGH,
+    MMIO_QUEUE_DESC_LOW, MMIO_QUEUE_DEVICE_HIGH, MMIO_QUEUE_DEVICE_LOW, MMIO_QUEUE_DRIVER_HIGH,
+    MMIO_QUEUE_DRIVER_LOW, MMIO_QUEUE_NOTIFY, MMIO_QUEUE_NUM, MMIO_QUEUE_NUM_MAX, MMIO_QUEUE_READY,
+    MMIO_QUEUE_SEL, MMIO_STATUS, MMIO_VENDOR_ID, MMIO_VERSION, VIRTIO_F_EVENT_IDX,
+    VIRTIO_MMIO_MAGIC, VIRTIO_MMIO_VERSION, VIRTIO_VENDOR_ID, VIRTQ_DESC_F_NEXT,
+    VIRTQ_DESC_F_WRITE,
+};
+use crate::boot::GuestMemory;
+use crate::kvm::IrqLine;
+
+/// Virtio device ID for 9P transports.
+const VIRTIO_9P_DEVICE_ID: u32 = 9;
+
+/// Feature bit: the mount tag is in config space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+/// VIRTIO_F_VERSION_1 - required for virtio-mmio v2 devices.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Config space: `tag_len[2]` followed by the tag.
+const CONFIG_TAG_LEN: u64 = 0x100;
+
+/// Longest mount tag we accept.
+const MAX_TAG_LEN: usize = 64;
+
+/// Largest message size we negotiate.
+const MAX_MSIZE: u32 = 512 * 1024;
+
+/// Protocol version we speak.
+const VERSION: &[u8] = b"9P2000.L";
+
+// Message types (each reply is its request's type + 1)
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+/// Size of `size[4] type[1] tag[2]`.
+const HEADER_SIZE: usize = 7;
+/// Size of an `Rread` before the data.
+const RREAD_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+/// Most path components in one `Twalk`.
+const MAX_WALK_ELEMENTS: usize = 16;
+
+// Qid types
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+// Tsetattr valid bits
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Rgetattr: every field of the basic set is valid.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+/// Tunlinkat flag: remove a directory.
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// Rstatfs filesystem type.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// Tlock/Tgetlock values.
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+/// `--9p path=DIR,tag=TAG[,transport=pci]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct P9Share {
+    /// Host directory to share.
+    pub path: PathBuf,
+    /// Name the guest mounts the share by.
+    pub tag: String,
+    /// How the device is attached.
+    pub transport: Transport,
+}
+
+impl FromStr for P9Share {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut path, mut tag, mut transport) = (None, None, Transport::default());
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("path", value)) if !value.is_empty() => path = Some(PathBuf::from(value)),
+                Some(("tag", value)) => {
+                    if value.is_empty() || value.len() > MAX_TAG_LEN {
+                        return Err(format!("tag must be 1 to {MAX_TAG_LEN} bytes"));
+                    }
+                    tag = Some(value.to_string());
+                }
+                Some(("transport", value)) => transport = value.parse()?,
+                _ => return Err(format!("invalid 9p option {option:?}")),
+            }
+        }
+        Ok(Self {
+            path: path.ok_or("9p needs path=DIR")?,
+            tag: tag.ok_or("9p needs tag=TAG")?,
+            transport,
+        })
+    }
+}
+
+/// Virtio 9P device.
+pub struct Virtio9p {
+    server: Server,
+    /// `tag_len[2]` followed by the tag.
+    config: Vec<u8>,
+
+    driver_features: u64,
+    features_sel: u32,
+
+    status: u32,
+    interrupt_status: u32,
+
+    queue_sel: u32,
+    queue: Virtqueue,
+
+    /// Reference to guest memory, set via set_memory().
+    memory: Option<*const GuestMemory>,
+    /// Line to interrupt the guest on, set via set_interrupt().
+    irq: Option<Arc<dyn IrqLine>>,
+}
+
+// Safety: as for VirtioBlk, the GuestMemory pointer is only used during MMIO
+// operations on the vCPU thread.
+unsafe impl Send for Virtio9p {}
+
+impl Virtio9p {
+    /// Share the directory in `share`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the path doesn't name a directory.
+    pub fn new(share: &P9Share) -> io::Result<Self> {
+        let root = fs::canonicalize(&share.path)?;
+        if !root.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        let mut config = (share.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(share.tag.as_bytes());
+
+        audit::record(audit::Kind::File, "share", &root.display().to_string());
+        info!("[virtio-9p] Sharing {} as {:?}", root.display(), share.tag);
+        Ok(Self {
+            server: Server::new(&root)?,
+            config,
+            driver_features: 0,
+            features_sel: 0,
+            status: 0,
+            interrupt_status: 0,
+            queue_sel: 0,
+            queue: Virtqueue::new(),
+            memory: None,
+            irq: None,
+        })
+    }
+
+    /// Set the guest memory reference for virtqueue processing.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the GuestMemory reference remains valid
+    /// for the lifetime of this device.
+    pub fn set_memory(&mut self, memory: &GuestMemory) {
+        self.memory = Some(memory as *const GuestMemory);
+    }
+
+    /// Set the line used-buffer notifications are raised on.
+    pub fn set_interrupt(&mut self, irq: Arc<dyn IrqLine>) {
+        self.irq = Some(irq);
+    }
+
+    /// Serve all pending requests.
+    fn process_queue(&mut self) {
+        let memory = match self.memory {
+            Some(ptr) => unsafe { &*ptr },
+            None => return,
+        };
+
+        while let Some(head) = self.queue.pop_avail(memory) {
+            let len = self.process_request(memory, head);
+            if self.queue.push_used(memory, head, len).is_none() {
+                warn!("[virtio-9p] Failed to push to used ring");
+            }
+        }
+        if self.queue.needs_interrupt(memory) {
+            self.interrupt_status |= 1;
+            if let Some(irq) = &self.irq {
+                irq.trigger();
+            }
+        }
+    }
+
+    /// Serve one request, returning the number of bytes written back.
+    fn process_request(&mut self, memory: &GuestMemory, head: u16) -> u32 {
+        let mut descs: Vec<VirtqDesc> = Vec::new();
+        let mut idx = head;
+        loop {
+            let Some(desc) = self.queue.read_desc(memory, idx) else {
+                warn!("[virtio-9p] Failed to read descriptor {}", idx);
+                return 0;
+            };
+            descs.push(desc);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 || descs.len() > MAX_QUEUE_SIZE as usize {
+                break;
+            }
+            idx = desc.next;
+        }
+
+        // No message is larger than msize: don't allocate what the guest
+        // claims before checking
+        let readable: u64 = descs
+            .iter()
+            .filter(|d| d.flags & VIRTQ_DESC_F_WRITE == 0)
+            .map(|d| u64::from(d.len))
+            .sum();
+        if readable > u64::from(self.server.msize) {
+            warn!(
+                "[virtio-9p] Request of {} bytes exceeds msize {}",
+                readable, self.server.msize
+            );
+            return 0;
+        }
+
+        let mut request = Vec::with_capacity(readable as usize);
+        for desc in descs.iter().filter(|d| d.flags & VIRTQ_DESC_F_WRITE == 0) {
+            let start = request.len();
+            request.resize(start + desc.len as usize, 0);
+            if memory.read(desc.addr, &mut request[start..]).is_err() {
+                warn!("[virtio-9p] Failed to read request");
+                return 0;
+            }
+        }
+
+        let reply = self.server.handle(&request);
+        let mut written = 0;
+        for desc in descs.iter().filter(|d| d.flags & VIRTQ_DESC_F_WRITE != 0) {
+            let chunk = &reply[written.min(reply.len())..];
+            let len = chunk.len().min(desc.len as usize);
+            if len == 0 {
+                break;
+            }
+            if memory.write(desc.addr, &chunk[..len]).is_err() {
+                warn!("[virtio-9p] Failed to write reply");
+                return 0;
+            }
+            written += len;
+        }
+        if written < reply.len() {
+            warn!(
+                "[virtio-9p] Reply truncated: {} of {} bytes",
+                written,
+                reply.len()
+            );
+        }
+        written as u32
+    }
+
+    /// Read a 32-bit register value.
+    fn read_register(&mut self, offset: u64) -> u32 {
+        match offset {
+            MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            MMIO_VERSION => VIRTIO_MMIO_VERSION,
+            MMIO_DEVICE_ID => VIRTIO_9P_DEVICE_ID,
+            MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            MMIO_DEVICE_FEATURES => {
+                let features =
+                    VIRTIO_9P_MOUNT_TAG | VIRTIO_F_VERSION_1 | u64::from(VIRTIO_F_EVENT_IDX);
+                match self.features_sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            MMIO_QUEUE_NUM_MAX if self.queue_sel == 0 => MAX_QUEUE_SIZE as u32,
+            MMIO_QUEUE_NUM_MAX => 0,
+            MMIO_QUEUE_READY => (self.queue_sel == 0 && self.queue.ready) as u32,
+            MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            MMIO_STATUS => self.status,
+            _ if offset >= CONFIG_TAG_LEN => {
+                let start = (offset - CONFIG_TAG_LEN) as usize;
+                let mut bytes = [0u8; 4];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.config.get(start + i).copied().unwrap_or(0);
+                }
+                u32::from_le_bytes(bytes)
+            }
+            _ => {
+                warn!("[virtio-9p] Unknown register read: {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    /// Write a 32-bit register value.
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let set_low = |addr: &mut u64| *addr = (*addr & 0xFFFF_FFFF_0000_0000) | value as u64;
+        let set_high =
+            |addr: &mut u64| *addr = (*addr & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+        match offset {
+            MMIO_DEVICE_FEATURES_SEL | MMIO_DRIVER_FEATURES_SEL => self.features_sel = value,
+            MMIO_DRIVER_FEATURES => match self.features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | value as u64,
+                1 => {
+                    self.driver_features =
+                        (self.driver_features & 0xFFFF_FFFF) | ((value as u64) << 32)
+                }
+                _ => {}
+            },
+            MMIO_QUEUE_SEL => self.queue_sel = value,
+            MMIO_QUEUE_NOTIFY => self.process_queue(),
+            MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            MMIO_STATUS => {
+                self.status = value;
+                if value == 0 {
+                    self.queue = Virtqueue::new();
+                    self.interrupt_status = 0;
+                    self.server.reset();
+                    debug!("[virtio-9p] Device reset");
+                } else {
+                    debug!("[virtio-9p] Status: {:#x}", value);
+                }
+            }
+            _ if self.queue_sel != 0 => {}
+            MMIO_QUEUE_NUM if value <= MAX_QUEUE_SIZE as u32 => self.queue.size = value as u16,
+            MMIO_QUEUE_READY => {
+                self.queue.ready = value != 0;
+                self.queue.event_idx = self.driver_features & u64::from(VIRTIO_F_EVENT_IDX) != 0;
+            }
+            MMIO_QUEUE_DESC_LOW => set_low(&mut self.queue.desc_table),
+            MMIO_QUEUE_DESC_HIGH => set_high(&mut self.queue.desc_table),
+            MMIO_QUEUE_DRIVER_LOW => set_low(&mut self.queue.avail_ring),
+            MMIO_QUEUE_DRIVER_HIGH => set_high(&mut self.queue.avail_ring),
+            MMIO_QUEUE_DEVICE_LOW => set_low(&mut self.queue.used_ring),
+            MMIO_QUEUE_DEVICE_HIGH => set_high(&mut self.queue.used_ring),
+            _ => warn!(
+                "[virtio-9p] Unknown register write: {:#x} = {:#x}",
+                offset, value
+            ),
+        }
+    }
+}
+
+impl MmioDevice for Virtio9p {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = self.read_register(offset & !0x3); // Align to 4 bytes
+        let bytes = value.to_le_bytes();
+
+        // Handle sub-word reads
+        let start = (offset & 0x3) as usize;
+        let len = data.len().min(4 - start);
+        data[..len].copy_from_slice(&bytes[start..start + len]);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        // Only handle 4-byte aligned writes
+        if data.len() != 4 || offset & 0x3 != 0 {
+            warn!(
+                "[virtio-9p] Non-aligned write: offset={:#x} len={}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        self.write_register(offset, value);
+    }
+
+    fn notify_queues(&self) -> u32 {
+        1
+    }
+}
+
+// ============================================================================
+// 9P2000.L server
+// ============================================================================
+
+fn errno(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+/// Cursor over a T-message body.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(errno(libc::EPROTO));
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<OsString> {
+        let len = self.u16()? as usize;
+        Ok(OsString::from_vec(self.bytes(len)?.to_vec()))
+    }
+}
+
+/// An R-message under construction.
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn qid(&mut self, qid: Qid) -> &mut Self {
+        self.u8(qid.kind).u32(0).u64(qid.path)
+    }
+}
+
+/// A file's identity on the wire: its type and inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Qid {
+    kind: u8,
+    path: u64,
+}
+
+impl Qid {
+    fn new(file_type: fs::FileType, ino: u64) -> Self {
+        let kind = if file_type.is_dir() {
+            QTDIR
+        } else if file_type.is_symlink() {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        Self { kind, path: ino }
+    }
+
+    fn of(metadata: &Metadata) -> Self {
+        Self::new(metadata.file_type(), metadata.ino())
+    }
+}
+
+/// A directory entry as `Treaddir` returns it.
+struct DirEntry {
+    qid: Qid,
+    /// `DT_*` type.
+    kind: u8,
+    name: OsString,
+}
+
+/// What a fid refers to.
+struct Fid {
+    /// Path relative to the shared root, to resolve `..` lexically.
+    path: PathBuf,
+    /// `O_PATH` handle on the file, opened when the fid was made. Requests
+    /// act on it, or on a name in it, so a symlink swapped in anywhere along
+    /// `path` later can't redirect them.
+    handle: File,
+    /// The file, once opened.
+    file: Option<File>,
+    /// Directory listing, taken when reading starts at offset 0, so later
+    /// offsets index a stable list.
+    entries: Vec<DirEntry>,
+}
+
+impl Fid {
+    fn new(path: PathBuf, handle: File) -> Self {
+        Self {
+            path,
+            handle,
+            file: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// Serves 9P2000.L requests from one host directory.
+struct Server {
+    /// `O_PATH` handle on the shared root.
+    root: File,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    fn new(root: &Path) -> io::Result<Self> {
+        let root = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(root)?;
+        Ok(Self {
+            root,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Drop all fids (on device reset or `Tversion`).
+    fn reset(&mut self) {
+        self.fids.clear();
+    }
+
+    /// Handle one T-message, returning the R-message.
+    fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader { buf: request };
+        let (Ok(size), Ok(kind), Ok(tag)) = (reader.u32(), reader.u8(), reader.u16()) else {
+            return Vec::new();
+        };
+        if (size as usize) < HEADER_SIZE || size as usize > request.len() {
+            warn!("[virtio-9p] Bad request size {}", size);
+            return Vec::new();
+        }
+        reader.buf = &request[HEADER_SIZE..size as usize];
+
+        let mut reply = Writer(vec![0; HEADER_SIZE]);
+        let kind = match self.dispatch(kind, &mut reader, &mut reply) {
+            Ok(()) => kind + 1,
+            Err(e) => {
+                trace!("[virtio-9p] Request type {} failed: {}", kind, e);
+                reply.0.truncate(HEADER_SIZE);
+                reply.u32(e.raw_os_error().unwrap_or(libc::EIO) as u32);
+                RLERROR
+            }
+        };
+        let mut reply = reply.0;
+        let len = reply.len() as u32;
+        reply[..4].copy_from_slice(&len.to_le_bytes());
+        reply[4] = kind;
+        reply[5..7].copy_from_slice(&tag.to_le_bytes());
+        reply
+    }
+
+    fn dispatch(&mut self, kind: u8, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        match kind {
+            TVERSION => self.version(r, w),
+            TATTACH => self.attach(r, w),
+            TWALK => self.walk(r, w),
+            TCLUNK => {
+                self.fids.remove(&r.u32()?);
+                Ok(())
+            }
+            TREMOVE => self.remove(r),
+            TFLUSH => Ok(()), // Requests complete synchronously
+            TGETATTR => self.getattr(r, w),
+            TSETATTR => self.setattr(r),
+            TSTATFS => self.statfs(r, w),
+            TLOPEN => self.lopen(r, w),
+            TLCREATE => self.lcreate(r, w),
+            TREAD => self.read(r, w),
+            TWRITE => self.write(r, w),
+            TFSYNC => self.fsync(r),
+            TREADDIR => self.readdir(r, w),
+            TMKDIR => self.mkdir(r, w),
+            TSYMLINK => self.symlink(r, w),
+            TMKNOD => self.mknod(r, w),
+            TREADLINK => self.readlink(r, w),
+            TLINK => self.link(r),
+            TRENAME => self.rename(r),
+            TRENAMEAT => self.renameat(r),
+            TUNLINKAT => self.unlinkat(r),
+            TLOCK => {
+                // Locks are advisory and the guest is the only client
+                w.u8(LOCK_SUCCESS);
+                Ok(())
+            }
+            TGETLOCK => {
+                let (fid, _kind) = (r.u32()?, r.u8()?);
+                self.fid(fid)?;
+                let (start, length, proc_id) = (r.u64()?, r.u64()?, r.u32()?);
+                let client_id = r.string()?;
+                w.u8(LOCK_TYPE_UNLCK)
+                    .u64(start)
+                    .u64(length)
+                    .u32(proc_id)
+                    .bytes(client_id.as_bytes());
+                Ok(())
+            }
+            TXATTRWALK | TXATTRCREATE => Err(errno(libc::EOPNOTSUPP)),
+            _ => {
+                debug!("[virtio-9p] Unsupported request type {}", kind);
+                Err(errno(libc::EOPNOTSUPP))
+            }
+        }
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    /// Open `path`, relative to the shared root, without leaving it or
+    /// following a symlink anywhere along the way.
+    fn open(&self, path: &Path, flags: i32) -> io::Result<File> {
+        open_beneath(&self.root, path, flags, 0)
+    }
+
+    /// The directory holding the fid's file, and the file's name in it (the
+    /// root is `.` in itself).
+    fn parent(&self, fid: &Fid) -> io::Result<(File, CString)> {
+        match (fid.path.parent(), fid.path.file_name()) {
+            (Some(parent), Some(name)) => Ok((self.open(parent, libc::O_PATH)?, cname(name)?)),
+            _ => Ok((self.root.try_clone()?, c".".into())),
+        }
+    }
+
+    /// The directory fid `dfid`, and `name` checked to be a single path
+    /// component in it, with the path they make.
+    fn child(&self, dfid: u32, name: &OsStr) -> io::Result<(&File, CString, PathBuf)> {
+        check_name(name)?;
+        let dir = self.fid(dfid)?;
+        Ok((&dir.handle, cname(name)?, dir.path.join(name)))
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+        self.reset();
+        self.msize = msize.clamp(4096, MAX_MSIZE);
+        let version = if version.as_bytes() == VERSION {
+            VERSION
+        } else {
+            b"unknown"
+        };
+        debug!(
+            "[virtio-9p] Version {} with msize {}",
+            String::from_utf8_lossy(version),
+            self.msize
+        );
+        w.u32(self.msize).bytes(version);
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let qid = Qid::of(&self.root.metadata()?);
+        self.fids
+            .insert(fid, Fid::new(PathBuf::new(), self.root.try_clone()?));
+        w.qid(qid);
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let (fid, newfid) = (r.u32()?, r.u32()?);
+        let count = r.u16()? as usize;
+        if count > MAX_WALK_ELEMENTS {
+            return Err(errno(libc::E2BIG));
+        }
+        let names = (0..count)
+            .map(|_| r.string())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let start = self.fid(fid)?;
+        let mut path = start.path.clone();
+        let mut handle = start.handle.try_clone()?;
+        let mut qids = Vec::with_capacity(count);
+        for (i, name) in names.iter().enumerate() {
+            // `..` is resolved lexically, from the root; names one at a time
+            let next = match name.as_bytes() {
+                b".." => {
+                    path.pop();
+                    self.open(&path, libc::O_PATH)
+                }
+                b"." => handle.try_clone(),
+                _ => {
+                    check_name(name)?;
+                    path.push(name);
+                    open_beneath(&handle, Path::new(name), libc::O_PATH, 0)
+                }
+            };
+            let metadata = match next {
+                Ok(next) => {
+                    handle = next;
+                    handle.metadata()?
+                }
+                // A partial walk reports how far it got
+                Err(_) if i > 0 => break,
+                Err(e) => return Err(e),
+            };
+            qids.push(Qid::of(&metadata));
+            // The guest resolves symlinks itself, never walk through one
+            if metadata.is_symlink() {
+                break;
+            }
+        }
+
+        if qids.len() == count {
+            self.fids.insert(newfid, Fid::new(path, handle));
+        }
+        w.u16(qids.len() as u16);
+        for qid in qids {
+            w.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, r: &mut Reader) -> io::Result<()> {
+        // The fid is clunked even if removal fails
+        let fid = self
+            .fids
+            .remove(&r.u32()?)
+            .ok_or_else(|| errno(libc::EBADF))?;
+        if fid.path.as_os_str().is_empty() {
+            return Err(errno(libc::EBUSY));
+        }
+        let (dir, name) = self.parent(&fid)?;
+        let flags = if fid.handle.metadata()?.is_dir() {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        // SAFETY: dir is an open directory and name is NUL-terminated.
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let m = self.fid(fid)?.handle.metadata()?;
+        w.u64(GETATTR_BASIC)
+            .qid(Qid::of(&m))
+            .u32(m.mode())
+            .u32(m.uid())
+            .u32(m.gid())
+            .u64(m.nlink())
+            .u64(m.rdev())
+            .u64(m.size())
+            .u64(m.blksize())
+            .u64(m.blocks())
+            .u64(m.atime() as u64)
+            .u64(m.atime_nsec() as u64)
+            .u64(m.mtime() as u64)
+            .u64(m.mtime_nsec() as u64)
+            .u64(m.ctime() as u64)
+            .u64(m.ctime_nsec() as u64);
+        // btime, gen and data_version aren't in the basic set
+        w.u64(0).u64(0).u64(0).u64(0);
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> io::Result<()> {
+        let (fid, valid, mode, uid, gid) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+        let size = r.u64()?;
+        let (atime_sec, atime_nsec) = (r.u64()?, r.u64()?);
+        let (mtime_sec, mtime_nsec) = (r.u64()?, r.u64()?);
+
+        let fid = self.fid(fid)?;
+        let metadata = fid.handle.metadata()?;
+        if valid & (SETATTR_MODE | SETATTR_SIZE) != 0 && metadata.is_symlink() {
+            return Err(errno(libc::ELOOP));
+        }
+        // Files belong to Carbon's user: the guest can't give them away
+        if (valid & SETATTR_UID != 0 && uid != metadata.uid())
+            || (valid & SETATTR_GID != 0 && gid != metadata.gid())
+        {
+            return Err(errno(libc::EPERM));
+        }
+        let (dir, name) = self.parent(fid)?;
+        if valid & SETATTR_MODE != 0 {
+            // SAFETY: dir is an open directory and name is NUL-terminated;
+            // name isn't a symlink, checked above.
+            check(unsafe { libc::fchmodat(dir.as_raw_fd(), name.as_ptr(), mode & 0o7777, 0) })?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            reopen(&fid.handle, libc::O_WRONLY)?.set_len(size)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let time = |set: u32, explicit: u32, sec: u64, nsec: u64| libc::timespec {
+                tv_sec: if valid & explicit != 0 { sec as i64 } else { 0 },
+                tv_nsec: if valid & set == 0 {
+                    libc::UTIME_OMIT
+                } else if valid & explicit != 0 {
+                    nsec as i64
+                } else {
+                    libc::UTIME_NOW
+                },
+            };
+            let times = [
+                time(SETATTR_ATIME, SETATTR_ATIME_SET, atime_sec, atime_nsec),
+                time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime_sec, mtime_nsec),
+            ];
+            // SAFETY: name is NUL-terminated and times holds two timespecs.
+            check(unsafe {
+                libc::utimensat(
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let handle = &self.fid(fid)?.handle;
+        // SAFETY: fstatvfs only writes to st, which is a valid out-pointer.
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        check(unsafe { libc::fstatvfs(handle.as_raw_fd(), &mut st) })?;
+        w.u32(V9FS_MAGIC)
+            .u32(st.f_bsize as u32)
+            .u64(st.f_blocks)
+            .u64(st.f_bfree)
+            .u64(st.f_bavail)
+            .u64(st.f_files)
+            .u64(st.f_ffree)
+            .u64(st.f_fsid)
+            .u32(st.f_namemax as u32);
+        Ok(())
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let (fid, flags) = (r.u32()?, r.u32()?);
+        let file = reopen(&self.fid(fid)?.handle, open_flags(flags))?;
+        let qid = Qid::of(&file.metadata()?);
+        let fid = self.fid_mut(fid)?;
+        fid.file = Some(file);
+        fid.entries.clear();
+        w.qid(qid).u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let (flags, mode, _gid) = (r.u32()?, r.u32()?, r.u32()?);
+        let (dir, _, path) = self.child(fid, &name)?;
+        let file = open_beneath(
+            dir,
+            Path::new(&name),
+            open_flags(flags) | libc::O_CREAT,
+            mode & 0o7777,
+        )?;
+        let handle = open_beneath(dir, Path::new(&name), libc::O_PATH, 0)?;
+        let qid = Qid::of(&file.metadata()?);
+        // The fid now refers to the new file
+        *self.fid_mut(fid)? = Fid {
+            file: Some(file),
+            ..Fid::new(path, handle)
+        };
+        w.qid(qid).u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let mut buf = vec![0; count.min(self.msize - RREAD_HEADER_SIZE) as usize];
+        let len = file.read_at(&mut buf, offset)?;
+        w.u32(len as u32);
+        w.0.extend_from_slice(&buf[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+        let data = r.bytes(count as usize)?;
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let len = file.write_at(data, offset)?;
+        w.u32(len as u32);
+        Ok(())
+    }
+
+    fn fsync(&mut self, r: &mut Reader) -> io::Result<()> {
+        let (fid, datasync) = (r.u32()?, r.u32()?);
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        if datasync != 0 {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+    }
+
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let (fid, offset, count) = (r.u32()?, r.u64()?, r.u32()?);
+        let count = count.min(self.msize - RREAD_HEADER_SIZE) as usize;
+        if offset == 0 {
+            let entries = self.list(self.fid(fid)?)?;
+            self.fid_mut(fid)?.entries = entries;
+        }
+
+        let mut data = Writer(Vec::new());
+        let entries = &self.fid(fid)?.entries;
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            let len = 13 + 8 + 1 + 2 + entry.name.len();
+            if data.0.len() + len > count {
+                break;
+            }
+            data.qid(entry.qid)
+                .u64(index as u64 + 1)
+                .u8(entry.kind)
+                .bytes(entry.name.as_bytes());
+        }
+        w.u32(data.0.len() as u32);
+        w.0.extend_from_slice(&data.0);
+        Ok(())
+    }
+
+    /// List a directory, including `.` and `..`.
+    fn list(&self, fid: &Fid) -> io::Result<Vec<DirEntry>> {
+        let dir = reopen(&fid.handle, libc::O_RDONLY | libc::O_DIRECTORY)?;
+        let parent = match fid.path.parent() {
+            Some(parent) => self.open(parent, libc::O_PATH)?.metadata()?,
+            None => self.root.metadata()?,
+        };
+        let mut entries = vec![
+            DirEntry {
+                qid: Qid::of(&dir.metadata()?),
+                kind: libc::DT_DIR,
+                name: ".".into(),
+            },
+            DirEntry {
+                qid: Qid::of(&parent),
+                kind: libc::DT_DIR,
+                name: "..".into(),
+            },
+        ];
+        entries.extend(read_dir(dir)?);
+        Ok(entries)
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let (mode, _gid) = (r.u32()?, r.u32()?);
+        let (dir, cname, _) = self.child(dfid, &name)?;
+        // SAFETY: dir is an open file and cname is NUL-terminated.
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), cname.as_ptr(), mode & 0o7777) })?;
+        w.qid(child_qid(dir, &name)?);
+        Ok(())
+    }
+
+    fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let (name, target) = (r.string()?, r.string()?);
+        let (dir, cname, _) = self.child(dfid, &name)?;
+        let target = CString::new(target.into_vec()).map_err(|_| errno(libc::EINVAL))?;
+        // SAFETY: dir is an open file and both strings are NUL-terminated.
+        check(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), cname.as_ptr()) })?;
+        w.qid(child_qid(dir, &name)?);
+        Ok(())
+    }
+
+    fn mknod(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let (mode, _major, _minor, _gid) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+        // Never device nodes: they'd open the host's devices
+        if !matches!(mode & libc::S_IFMT, libc::S_IFIFO | libc::S_IFSOCK) {
+            return Err(errno(libc::EPERM));
+        }
+        let (dir, cname, _) = self.child(dfid, &name)?;
+        let mode = mode & (libc::S_IFMT | 0o7777);
+        // SAFETY: dir is an open file and cname is NUL-terminated.
+        check(unsafe { libc::mknodat(dir.as_raw_fd(), cname.as_ptr(), mode, 0) })?;
+        w.qid(child_qid(dir, &name)?);
+        Ok(())
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let handle = &self.fid(fid)?.handle;
+        let mut target = vec![0u8; libc::PATH_MAX as usize];
+        // SAFETY: the empty path names the symlink handle itself; target has
+        // room for the length given.
+        let len = unsafe {
+            libc::readlinkat(
+                handle.as_raw_fd(),
+                c"".as_ptr(),
+                target.as_mut_ptr().cast(),
+                target.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        w.bytes(&target[..len as usize]);
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut Reader) -> io::Result<()> {
+        let (dfid, fid) = (r.u32()?, r.u32()?);
+        let name = r.string()?;
+        let (from_dir, from_name) = self.parent(self.fid(fid)?)?;
+        let (dir, cname, _) = self.child(dfid, &name)?;
+        // SAFETY: both directories are open and both names NUL-terminated.
+        check(unsafe {
+            libc::linkat(
+                from_dir.as_raw_fd(),
+                from_name.as_ptr(),
+                dir.as_raw_fd(),
+                cname.as_ptr(),
+                0,
+            )
+        })
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> io::Result<()> {
+        let (fid, dfid) = (r.u32()?, r.u32()?);
+        let name = r.string()?;
+        let from = self.fid(fid)?;
+        if from.path.as_os_str().is_empty() {
+            return Err(errno(libc::EBUSY));
+        }
+        let (from_dir, from_name) = self.parent(from)?;
+        let from = from.path.clone();
+        let (to_dir, to_name, to) = self.child(dfid, &name)?;
+        move_at(&from_dir, &from_name, to_dir, &to_name)?;
+        self.moved(&from, &to);
+        Ok(())
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let olddirfid = r.u32()?;
+        let oldname = r.string()?;
+        let newdirfid = r.u32()?;
+        let newname = r.string()?;
+        let (from_dir, from_name, from) = self.child(olddirfid, &oldname)?;
+        let (to_dir, to_name, to) = self.child(newdirfid, &newname)?;
+        move_at(from_dir, &from_name, to_dir, &to_name)?;
+        self.moved(&from, &to);
+        Ok(())
+    }
+
+    /// Keep the paths of fids under `from` naming the same files after a
+    /// rename to `to`.
+    fn moved(&mut self, from: &Path, to: &Path) {
+        for fid in self.fids.values_mut() {
+            if let Ok(rest) = fid.path.strip_prefix(from) {
+                fid.path = to.join(rest);
+            }
+        }
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()?;
+        let (dir, cname, _) = self.child(dfid, &name)?;
+        let flags = if flags & AT_REMOVEDIR != 0 {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        // SAFETY: dir is an open file and cname is NUL-terminated.
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), cname.as_ptr(), flags) })
+    }
+}
+
+/// `openat2` `path` (relative) under `dir` with `RESOLVE_BENEATH |
+/// RESOLVE_NO_SYMLINKS`: the kernel refuses to leave `dir` or follow a
+/// symlink anywhere on the way. A final symlink opened `O_PATH` is the
+/// symlink itself.
+fn open_beneath(dir: &File, path: &Path, flags: i32, mode: u32) -> io::Result<File> {
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    let cpath = cstring(path)?;
+    // SAFETY: open_how is plain data; all-zero is a valid value.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_NOFOLLOW | libc::O_CLOEXEC) as u64;
+    how.mode = u64::from(mode);
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS;
+    // SAFETY: dir is open, cpath is NUL-terminated and how is an open_how
+    // of the size given.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            cpath.as_ptr(),
+            &how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: openat2 just returned this descriptor, owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Open the file an `O_PATH` handle refers to for I/O. Directories are
+/// opened as `.` beneath themselves, anything else through its
+/// `/proc/self/fd` entry, which names the file the handle was opened on
+/// rather than whatever its path leads to now.
+fn reopen(handle: &File, flags: i32) -> io::Result<File> {
+    let file_type = handle.metadata()?.file_type();
+    if file_type.is_symlink() {
+        return Err(errno(libc::ELOOP));
+    }
+    if file_type.is_dir() {
+        return open_beneath(handle, Path::new("."), flags, 0);
+    }
+    OpenOptions::new()
+        .read(matches!(
+            flags & libc::O_ACCMODE,
+            libc::O_RDONLY | libc::O_RDWR
+        ))
+        .write(matches!(
+            flags & libc::O_ACCMODE,
+            libc::O_WRONLY | libc::O_RDWR
+        ))
+        .custom_flags(flags & !libc::O_ACCMODE)
+        .open(format!("/proc/self/fd/{}", handle.as_raw_fd()))
+}
+
+/// Qid of the file just made as `name` in `dir`.
+fn child_qid(dir: &File, name: &OsStr) -> io::Result<Qid> {
+    Ok(Qid::of(
+        &open_beneath(dir, Path::new(name), libc::O_PATH, 0)?.metadata()?,
+    ))
+}
+
+fn move_at(from_dir: &File, from: &CStr, to_dir: &File, to: &CStr) -> io::Result<()> {
+    // SAFETY: both directories are open and both names NUL-terminated.
+    check(unsafe {
+        libc::renameat(
+            from_dir.as_raw_fd(),
+            from.as_ptr(),
+            to_dir.as_raw_fd(),
+            to.as_ptr(),
+        )
+    })
+}
+
+/// The entries of the open directory `dir`, without `.` and `..`.
+fn read_dir(dir: File) -> io::Result<Vec<DirEntry>> {
+    // SAFETY: fdopendir takes ownership of the descriptor; closedir below
+    // closes it.
+    let stream = unsafe { libc::fdopendir(dir.into_raw_fd()) };
+    if stream.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let mut entries = Vec::new();
+    loop {
+        // SAFETY: stream is an open directory stream; the entry is valid
+        // until the next readdir64 call, and is copied out before it.
+        let entry = unsafe { libc::readdir64(stream) };
+        if entry.is_null() {
+            break;
+        }
+        // SAFETY: as above; d_name is NUL-terminated.
+        let (ino, d_type, name) = unsafe {
+            let entry = &*entry;
+            let name = CStr::from_ptr(entry.d_name.as_ptr());
+            (entry.d_ino, entry.d_type, name.to_bytes().to_vec())
+        };
+        if name == b"." || name == b".." {
+            continue;
+        }
+        let kind = if d_type == libc::DT_DIR {
+            QTDIR
+        } else if d_type == libc::DT_LNK {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        entries.push(DirEntry {
+            qid: Qid { kind, path: ino },
+            kind: d_type,
+            name: OsString::from_vec(name),
+        });
+    }
+    // SAFETY: stream is open and not used again.
+    unsafe { libc::closedir(stream) };
+    Ok(entries)
+}
+
+/// Reject names that aren't a single path component.
+fn check_name(name: &OsStr) -> io::Result<()> {
+    match name.as_bytes() {
+        b"" | b"." | b".." => Err(errno(libc::EINVAL)),
+        bytes if bytes.contains(&b'/') || bytes.contains(&0) => Err(errno(libc::EINVAL)),
+        _ => Ok(()),
+    }
+}
+
+/// Flags of a `Tlopen`/`Tlcreate` we pass on. 9P2000.L uses the Linux
+/// values.
+fn open_flags(flags: u32) -> i32 {
+    let access = match flags as i32 & libc::O_ACCMODE {
+        libc::O_WRONLY => libc::O_WRONLY,
+        libc::O_RDWR => libc::O_RDWR,
+        _ => libc::O_RDONLY,
+    };
+    let passed = libc::O_TRUNC | libc::O_APPEND | libc::O_EXCL | libc::O_DIRECTORY;
+    access | (flags as i32 & passed)
+}
+
+/// Turn a libc -1 into the error.
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    cname(path.as_os_str())
+}
+
+fn cname(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| errno(libc::EINVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds T-messages.
+    fn message(kind: u8, body: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut w = Writer(vec![0; HEADER_SIZE]);
+        body(&mut w);
+        let len = w.0.len() as u32;
+        w.0[..4].copy_from_slice(&len.to_le_bytes());
+        w.0[4] = kind;
+        w.0[5..7].copy_from_slice(&1u16.to_le_bytes());
+        w.0
+    }
+
+    /// Sends a request, returning the reply type and body.
+    fn call(server: &mut Server, kind: u8, body: impl FnOnce(&mut Writer)) -> (u8, Vec<u8>) {
+        let reply = server.handle(&message(kind, body));
+        let len = u32::from_le_bytes(reply[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, reply.len());
+        (reply[4], reply[HEADER_SIZE..].to_vec())
+    }
+
+    fn walk(server: &mut Server, fid: u32, newfid: u32, names: &[&str]) -> (u8, Vec<u8>) {
+        call(server, TWALK, |w| {
+            w.u32(fid).u32(newfid).u16(names.len() as u16);
+            for name in names {
+                w.bytes(name.as_bytes());
+            }
+        })
+    }
+
+    fn temp_share(name: &str) -> (PathBuf, Server) {
+        let dir = std::env::temp_dir().join(format!("carbon-9p-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/hello.txt"), b"hello, guest").unwrap();
+        let mut server = Server::new(&dir).unwrap();
+        let (kind, body) = call(&mut server, TVERSION, |w| {
+            w.u32(8192).bytes(b"9P2000.L");
+        });
+        assert_eq!(kind, TVERSION + 1);
+        assert_eq!(&body[4..], b"\x08\x009P2000.L");
+        let (kind, _) = call(&mut server, TATTACH, |w| {
+            w.u32(0).u32(u32::MAX).bytes(b"root").bytes(b"").u32(0);
+        });
+        assert_eq!(kind, TATTACH + 1);
+        (dir, server)
+    }
+
+    #[test]
+    fn test_parse_share() {
+        assert_eq!(
+            "path=/srv/workspace,tag=host".parse(),
+            Ok(P9Share {
+                path: PathBuf::from("/srv/workspace"),
+                tag: "host".into(),
+                transport: Transport::Mmio,
+            })
+        );
+        assert_eq!(
+            "path=/srv,tag=host,transport=pci"
+                .parse::<P9Share>()
+                .map(|share| share.transport),
+            Ok(Transport::Pci)
+        );
+        assert!("path=/srv,tag=host,transport=isa"
+            .parse::<P9Share>()
+            .is_err());
+        assert!("path=/srv".parse::<P9Share>().is_err());
+        assert!("tag=host".parse::<P9Share>().is_err());
+        assert!("path=/srv,tag=host,ro".parse::<P9Share>().is_err());
+    }
+
+    #[test]
+    fn test_walk_open_read() {
+        let (dir, mut server) = temp_share("read");
+
+        let (kind, body) = walk(&mut server, 0, 1, &["sub", "hello.txt"]);
+        assert_eq!(kind, TWALK + 1);
+        assert_eq!(body[..2], 2u16.to_le_bytes());
+        assert_eq!(body[2], QTDIR);
+
+        let (kind, _) = call(&mut server, TLOPEN, |w| {
+            w.u32(1).u32(libc::O_RDONLY as u32);
+        });
+        assert_eq!(kind, TLOPEN + 1);
+        let (kind, body) = call(&mut server, TREAD, |w| {
+            w.u32(1).u64(7).u32(100);
+        });
+        assert_eq!(kind, TREAD + 1);
+        assert_eq!(&body[4..], b"guest");
+
+        // A missing first component is an error, a later one a partial walk
+        let (kind, body) = walk(&mut server, 0, 2, &["missing"]);
+        assert_eq!(
+            (kind, body),
+            (RLERROR, (libc::ENOENT as u32).to_le_bytes().to_vec())
+        );
+        let (kind, body) = walk(&mut server, 0, 2, &["sub", "missing"]);
+        assert_eq!(kind, TWALK + 1);
+        assert_eq!(body[..2], 1u16.to_le_bytes());
+        assert!(server.fid(2).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_walk_stays_inside_share() {
+        let (dir, mut server) = temp_share("confine");
+        std::os::unix::fs::symlink("/", dir.join("escape")).unwrap();
+
+        // `..` stops at the root
+        let (kind, _) = walk(&mut server, 0, 1, &["..", "..", "sub"]);
+        assert_eq!(kind, TWALK + 1);
+        assert_eq!(server.fid(1).unwrap().path, PathBuf::from("sub"));
+
+        // Walks stop at symlinks instead of following them
+        let (kind, body) = walk(&mut server, 0, 2, &["escape", "etc"]);
+        assert_eq!(kind, TWALK + 1);
+        assert_eq!(body[..2], 1u16.to_le_bytes());
+        assert_eq!(body[2], QTSYMLINK);
+        assert!(server.fid(2).is_err());
+
+        // And opening one fails rather than following it
+        walk(&mut server, 0, 3, &["escape"]);
+        let (kind, _) = call(&mut server, TLOPEN, |w| {
+            w.u32(3).u32(libc::O_RDONLY as u32);
+        });
+        assert_eq!(kind, RLERROR);
+
+        let (kind, _) = walk(&mut server, 0, 4, &["sub/hello.txt"]);
+        assert_eq!(kind, RLERROR);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_write_readdir() {
+        let (dir, mut server) = temp_share("create");
+
+        walk(&mut server, 0, 1, &[]);
+        let (kind, _) = call(&mut server, TLCREATE, |w| {
+            w.u32(1)
+                .bytes(b"new.txt")
+                .u32(libc::O_RDWR as u32)
+                .u32(0o644)
+                .u32(0);
+        });
+        assert_eq!(kind, TLCREATE + 1);
+        let (kind, body) = call(&mut server, TWRITE, |w| {
+            w.u32(1).u64(0).u32(4);
+            w.0.extend_from_slice(b"data");
+        });
+        assert_eq!((kind, body), (TWRITE + 1, 4u32.to_le_bytes().to_vec()));
+        assert_eq!(fs::read(dir.join("new.txt")).unwrap(), b"data");
+
+        walk(&mut server, 0, 2, &[]);
+        call(&mut server, TLOPEN, |w| {
+            w.u32(2).u32(libc::O_RDONLY as u32);
+        });
+        let (kind, body) = call(&mut server, TREADDIR, |w| {
+            w.u32(2).u64(0).u32(4096);
+        });
+        assert_eq!(kind, TREADDIR + 1);
+        let mut names = Vec::new();
+        let mut r = Reader { buf: &body[4..] };
+        while !r.buf.is_empty() {
+            r.bytes(13 + 8 + 1).unwrap();
+            names.push(r.string().unwrap());
+        }
+        names.sort();
+        assert_eq!(names, [".", "..", "new.txt", "sub"]);
+
+        // Renames keep fids pointing at the file
+        let (kind, _) = call(&mut server, TRENAMEAT, |w| {
+            w.u32(0).bytes(b"new.txt").u32(0).bytes(b"renamed.txt");
+        });
+        assert_eq!(kind, TRENAMEAT + 1);
+        assert_eq!(server.fid(1).unwrap().path, PathBuf::from("renamed.txt"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fids_keep_their_files() {
+        let (dir, mut server) = temp_share("handles");
+        let outside = dir.with_extension("outside");
+        let _ = fs::remove_dir_all(&outside);
+        fs::create_dir(&outside).unwrap();
+        walk(&mut server, 0, 1, &["sub"]);
+
+        // Swap a symlink out of the share in where the fid's directory was
+        fs::rename(dir.join("sub"), dir.join("moved")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("sub")).unwrap();
+
+        // The fid still names the directory it was walked to
+        let (kind, _) = call(&mut server, TMKDIR, |w| {
+            w.u32(1).bytes(b"made").u32(0o755).u32(0);
+        });
+        assert_eq!(kind, TMKDIR + 1);
+        assert!(dir.join("moved/made").is_dir());
+        let (kind, _) = walk(&mut server, 1, 2, &["hello.txt"]);
+        assert_eq!(kind, TWALK + 1);
+        call(&mut server, TLOPEN, |w| {
+            w.u32(2).u32(libc::O_RDONLY as u32);
+        });
+        let (_, body) = call(&mut server, TREAD, |w| {
+            w.u32(2).u64(0).u32(100);
+        });
+        assert_eq!(&body[4..], b"hello, guest");
+
+        // Paths through the symlink don't resolve
+        let (kind, _) = walk(&mut server, 1, 3, &["..", "sub", "made"]);
+        assert_eq!(kind, TWALK + 1);
+        assert!(server.fid(3).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn test_setattr_keeps_owner() {
+        let (dir, mut server) = temp_share("chown");
+        let owner = fs::metadata(dir.join("sub/hello.txt")).unwrap();
+        walk(&mut server, 0, 1, &["sub", "hello.txt"]);
+        let setattr = |server: &mut Server, valid: u32, uid: u32, gid: u32| {
+            call(server, TSETATTR, |w| {
+                w.u32(1).u32(valid).u32(0).u32(uid).u32(gid).u64(0);
+                w.u64(0).u64(0).u64(0).u64(0);
+            })
+        };
+
+        let (kind, body) = setattr(&mut server, SETATTR_UID, owner.uid() + 1, 0);
+        assert_eq!(
+            (kind, body),
+            (RLERROR, (libc::EPERM as u32).to_le_bytes().to_vec())
+        );
+        let (kind, _) = setattr(&mut server, SETATTR_GID, 0, owner.gid() + 1);
+        assert_eq!(kind, RLERROR);
+        // Setting the owner it already has is fine
+        let (kind, _) = setattr(
+            &mut server,
+            SETATTR_UID | SETATTR_GID,
+            owner.uid(),
+            owner.gid(),
+        );
+        assert_eq!(kind, TSETATTR + 1);
+        let after = fs::metadata(dir.join("sub/hello.txt")).unwrap();
+        assert_eq!((after.uid(), after.gid()), (owner.uid(), owner.gid()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_mknod_refuses_devices() {
+        let (dir, mut server) = temp_share("mknod");
+        let mknod = |server: &mut Server, name: &[u8], mode: u32| {
+            call(server, TMKNOD, |w| {
+                w.u32(0).bytes(name).u32(mode).u32(8).u32(0).u32(0);
+            })
+        };
+
+        for mode in [libc::S_IFBLK, libc::S_IFCHR, libc::S_IFREG, 0] {
+            let (kind, body) = mknod(&mut server, b"dev", mode | 0o600);
+            assert_eq!(
+                (kind, body),
+                (RLERROR, (libc::EPERM as u32).to_le_bytes().to_vec())
+            );
+        }
+        assert!(!dir.join("dev").exists());
+
+        let (kind, _) = mknod(&mut server, b"fifo", libc::S_IFIFO | 0o600);
+        assert_eq!(kind, TMKNOD + 1);
+        let file_type = fs::symlink_metadata(dir.join("fifo")).unwrap().file_type();
+        assert!(std::os::unix::fs::FileTypeExt::is_fifo(&file_type));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_requests_over_msize() {
+        let dir = std::env::temp_dir().join(format!("carbon-9p-msize-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut device = Virtio9p::new(&P9Share {
+            path: dir.clone(),
+            tag: "host".into(),
+            transport: Transport::Mmio,
+        })
+        .unwrap();
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        device.queue.size = 4;
+        device.queue.desc_table = 0x1000;
+        device.server.msize = 4096;
+        let version = message(TVERSION, |w| {
+            w.u32(4096).bytes(b"9P2000.L");
+        });
+        memory.write(0x10000, &version).unwrap();
+        let chain = |readable: &[u32]| {
+            for (i, &len) in readable.iter().enumerate() {
+                let desc = 0x1000 + 16 * i as u64;
+                memory.write(desc, &0x10000u64.to_le_bytes()).unwrap();
+                memory.write(desc + 8, &len.to_le_bytes()).unwrap();
+                memory
+                    .write(desc + 12, &VIRTQ_DESC_F_NEXT.to_le_bytes())
+                    .unwrap();
+                memory
+                    .write(desc + 14, &(i as u16 + 1).to_le_bytes())
+                    .unwrap();
+            }
+            let reply = 0x1000 + 16 * readable.len() as u64;
+            memory.write(reply, &0x20000u64.to_le_bytes()).unwrap();
+            memory.write(reply + 8, &256u32.to_le_bytes()).unwrap();
+            memory
+                .write(reply + 12, &VIRTQ_DESC_F_WRITE.to_le_bytes())
+                .unwrap();
+        };
+
+        chain(&[version.len() as u32]);
+        assert!(device.process_request(&memory, 0) > 0);
+        // Readable buffers adding up to more than msize aren't read at all
+        chain(&[2048, 2048, 1]);
+        assert_eq!(device.process_request(&memory, 0), 0);
+        chain(&[u32::MAX]);
+        assert_eq!(device.process_request(&memory, 0), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
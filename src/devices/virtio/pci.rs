@@ -0,0 +1,538 @@
+//! Virtio over PCI (virtio-pci, modern interface).
+//!
+//! [`VirtioPci`] puts a virtio device on the PCI bus ([`crate::devices::pci`])
+//! instead of a virtio-mmio region. The device model is unchanged: each
+//! virtio-pci access becomes the virtio-mmio register access with the same
+//! meaning, and the transport keeps what PCI exposes but MMIO doesn't, such
+//! as queue sizes and addresses reading back.
+//!
+//! # BAR Layout
+//!
+//! BAR0 holds the four regions the vendor capabilities point the driver at:
+//!
+//! ```text
+//! 0x0000 - 0x0037  common config: features, status, queue setup
+//! 0x1000           ISR status: reading returns and clears it
+//! 0x2000 - 0x2EFF  device config (MMIO offset 0x100 onward)
+//! 0x3000 -         queue notify: 4 bytes per queue
+//! ```
+//!
+//! There is no MSI-X, so the driver falls back to INTx and reads the ISR to
+//! learn why the device interrupted.
+
+use crate::devices::mmio::MmioDevice;
+use crate::devices::pci::PciConfig;
+
+use super::{
+    MMIO_CONFIG, MMIO_DEVICE_FEATURES, MMIO_DEVICE_FEATURES_SEL, MMIO_DEVICE_ID,
+    MMIO_DRIVER_FEATURES, MMIO_DRIVER_FEATURES_SEL, MMIO_INTERRUPT_ACK, MMIO_INTERRUPT_STATUS,
+    MMIO_QUEUE_DESC_HIGH, MMIO_QUEUE_DESC_LOW, MMIO_QUEUE_DEVICE_HIGH, MMIO_QUEUE_DEVICE_LOW,
+    MMIO_QUEUE_DRIVER_HIGH, MMIO_QUEUE_DRIVER_LOW, MMIO_QUEUE_NOTIFY, MMIO_QUEUE_NUM,
+    MMIO_QUEUE_NUM_MAX, MMIO_QUEUE_READY, MMIO_QUEUE_SEL, MMIO_STATUS,
+};
+
+/// PCI vendor ID of virtio devices.
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+
+/// Modern virtio-pci device IDs are this plus the virtio device ID.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+
+/// Revision 1 marks a modern-only (non-transitional) device.
+const VIRTIO_PCI_REVISION: u8 = 1;
+
+/// Class code: "other" device; drivers bind by vendor and device ID.
+const PCI_CLASS_OTHER: u32 = 0xff_00_00;
+
+/// Size of BAR0.
+pub const VIRTIO_PCI_BAR_SIZE: u32 = 0x4000;
+
+/// Most queues probed from the device.
+const MAX_QUEUES: u32 = 64;
+
+// BAR0 regions
+const COMMON_CFG: u64 = 0x0000;
+const COMMON_CFG_SIZE: u64 = 0x38;
+const ISR_CFG: u64 = 0x1000;
+const DEVICE_CFG: u64 = 0x2000;
+const DEVICE_CFG_SIZE: u64 = 0xf00;
+const NOTIFY_CFG: u64 = 0x3000;
+
+/// Bytes between consecutive queues' notify addresses.
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+// Capabilities
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Common config fields
+const COMMON_DFSELECT: u64 = 0x00;
+const COMMON_DF: u64 = 0x04;
+const COMMON_GFSELECT: u64 = 0x08;
+const COMMON_GF: u64 = 0x0c;
+const COMMON_MSIX: u64 = 0x10;
+const COMMON_NUMQ: u64 = 0x12;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_CFGGENERATION: u64 = 0x15;
+const COMMON_Q_SELECT: u64 = 0x16;
+const COMMON_Q_SIZE: u64 = 0x18;
+const COMMON_Q_MSIX: u64 = 0x1a;
+const COMMON_Q_ENABLE: u64 = 0x1c;
+const COMMON_Q_NOFF: u64 = 0x1e;
+const COMMON_Q_DESCLO: u64 = 0x20;
+const COMMON_Q_AVAILLO: u64 = 0x28;
+const COMMON_Q_USEDLO: u64 = 0x30;
+
+/// MSI-X vector meaning "none": there is no MSI-X to configure.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// What the transport remembers about a queue for the driver to read back.
+#[derive(Debug, Clone, Copy)]
+struct PciQueue {
+    /// Largest size the device accepts.
+    max_size: u16,
+    size: u16,
+    desc: u64,
+    driver: u64,
+    device: u64,
+}
+
+impl PciQueue {
+    fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            desc: 0,
+            driver: 0,
+            device: 0,
+        }
+    }
+}
+
+/// A virtio device behind the virtio-pci transport, handling BAR0.
+pub struct VirtioPci {
+    device: Box<dyn MmioDevice>,
+    /// Virtio device ID.
+    device_id: u32,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    /// Driver features, per select (the MMIO registers are write-only).
+    driver_features: [u32; 2],
+    queue_select: u16,
+    queues: Vec<PciQueue>,
+}
+
+impl VirtioPci {
+    /// Wrap `device`, an MMIO device with its memory and interrupt already
+    /// set up. Its queues are probed from QUEUE_NUM_MAX.
+    pub fn new(device: Box<dyn MmioDevice>) -> Self {
+        let mut pci = Self {
+            device,
+            device_id: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: [0; 2],
+            queue_select: 0,
+            queues: Vec::new(),
+        };
+        pci.device_id = pci.mmio_read(MMIO_DEVICE_ID);
+        for index in 0..MAX_QUEUES {
+            pci.mmio_write(MMIO_QUEUE_SEL, index);
+            match pci.mmio_read(MMIO_QUEUE_NUM_MAX) {
+                0 => break,
+                max_size => pci.queues.push(PciQueue::new(max_size as u16)),
+            }
+        }
+        pci.mmio_write(MMIO_QUEUE_SEL, 0);
+        pci
+    }
+
+    /// Config space for the device, raising INTA on `line`.
+    pub fn config(&self, line: u8) -> PciConfig {
+        let mut config = PciConfig::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + self.device_id as u16,
+            PCI_CLASS_OTHER,
+            VIRTIO_PCI_REVISION,
+        );
+        config.set_subsystem(VIRTIO_PCI_VENDOR_ID, self.device_id as u16);
+        config.add_bar(VIRTIO_PCI_BAR_SIZE);
+        config.set_interrupt(line);
+        let notify_len = self.queues.len() as u32 * NOTIFY_OFF_MULTIPLIER;
+        let caps = [
+            (
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                COMMON_CFG,
+                COMMON_CFG_SIZE as u32,
+            ),
+            (VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG, 1),
+            (
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                DEVICE_CFG,
+                DEVICE_CFG_SIZE as u32,
+            ),
+            (VIRTIO_PCI_CAP_NOTIFY_CFG, NOTIFY_CFG, notify_len.max(1)),
+        ];
+        for (cfg_type, offset, length) in caps {
+            // struct virtio_pci_cap after the ID and next pointer: length,
+            // type, BAR, ID, padding, offset and length in the BAR; the
+            // notify capability adds the multiplier
+            let mut cap = vec![16, cfg_type, 0, 0, 0, 0];
+            cap.extend_from_slice(&(offset as u32).to_le_bytes());
+            cap.extend_from_slice(&length.to_le_bytes());
+            if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                cap[0] = 20;
+                cap.extend_from_slice(&NOTIFY_OFF_MULTIPLIER.to_le_bytes());
+            }
+            config.add_capability(PCI_CAP_ID_VNDR, &cap);
+        }
+        config
+    }
+
+    fn mmio_read(&mut self, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        self.device.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn mmio_write(&mut self, offset: u64, value: u32) {
+        self.device.write(offset, &value.to_le_bytes());
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut PciQueue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    /// The common config structure as the driver would read it now.
+    fn common_config(&mut self) -> [u8; COMMON_CFG_SIZE as usize] {
+        let mut common = [0u8; COMMON_CFG_SIZE as usize];
+        let mut put = |offset: u64, bytes: &[u8]| {
+            common[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes);
+        };
+
+        // The device has one features select for both directions
+        self.mmio_write(MMIO_DEVICE_FEATURES_SEL, self.device_feature_select);
+        let device_features = self.mmio_read(MMIO_DEVICE_FEATURES);
+        let driver_features = self
+            .driver_features
+            .get(self.driver_feature_select as usize)
+            .copied()
+            .unwrap_or(0);
+        put(COMMON_DFSELECT, &self.device_feature_select.to_le_bytes());
+        put(COMMON_DF, &device_features.to_le_bytes());
+        put(COMMON_GFSELECT, &self.driver_feature_select.to_le_bytes());
+        put(COMMON_GF, &driver_features.to_le_bytes());
+        put(COMMON_MSIX, &VIRTIO_MSI_NO_VECTOR.to_le_bytes());
+        put(COMMON_NUMQ, &(self.queues.len() as u16).to_le_bytes());
+        put(COMMON_STATUS, &[self.mmio_read(MMIO_STATUS) as u8]);
+        put(COMMON_CFGGENERATION, &[0]);
+        put(COMMON_Q_SELECT, &self.queue_select.to_le_bytes());
+
+        let ready = self.mmio_read(MMIO_QUEUE_READY) as u16;
+        let queue_select = self.queue_select;
+        if let Some(queue) = self.queues.get(queue_select as usize) {
+            put(COMMON_Q_SIZE, &queue.size.to_le_bytes());
+            put(COMMON_Q_MSIX, &VIRTIO_MSI_NO_VECTOR.to_le_bytes());
+            put(COMMON_Q_ENABLE, &ready.to_le_bytes());
+            put(COMMON_Q_NOFF, &queue_select.to_le_bytes());
+            put(COMMON_Q_DESCLO, &queue.desc.to_le_bytes());
+            put(COMMON_Q_AVAILLO, &queue.driver.to_le_bytes());
+            put(COMMON_Q_USEDLO, &queue.device.to_le_bytes());
+        }
+        common
+    }
+
+    fn read_common(&mut self, offset: u64, data: &mut [u8]) {
+        let common = self.common_config();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = common.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn write_common(&mut self, offset: u64, data: &[u8]) {
+        let mut bytes = [0u8; 8];
+        bytes[..data.len().min(8)].copy_from_slice(&data[..data.len().min(8)]);
+        let value = u64::from_le_bytes(bytes);
+
+        match offset {
+            COMMON_DFSELECT => self.device_feature_select = value as u32,
+            COMMON_GFSELECT => self.driver_feature_select = value as u32,
+            COMMON_GF => {
+                if let Some(features) = self
+                    .driver_features
+                    .get_mut(self.driver_feature_select as usize)
+                {
+                    *features = value as u32;
+                }
+                self.mmio_write(MMIO_DRIVER_FEATURES_SEL, self.driver_feature_select);
+                self.mmio_write(MMIO_DRIVER_FEATURES, value as u32);
+            }
+            // No MSI-X: the driver reads back NO_VECTOR and uses INTx
+            COMMON_MSIX | COMMON_Q_MSIX => {}
+            COMMON_STATUS => {
+                self.mmio_write(MMIO_STATUS, value as u32);
+                if value == 0 {
+                    self.reset();
+                }
+            }
+            COMMON_Q_SELECT => {
+                self.queue_select = value as u16;
+                self.mmio_write(MMIO_QUEUE_SEL, value as u32);
+            }
+            COMMON_Q_SIZE => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.size = value as u16;
+                }
+            }
+            COMMON_Q_ENABLE if value != 0 => self.enable_queue(),
+            COMMON_Q_DESCLO..COMMON_CFG_SIZE => {
+                // Addresses may be written as halves or whole
+                let start = (offset - COMMON_Q_DESCLO) as usize;
+                if let Some(queue) = self.selected_queue() {
+                    for (i, &byte) in data.iter().enumerate() {
+                        let (field, shift) = ((start + i) / 8, (start + i) % 8 * 8);
+                        let address = match field {
+                            0 => &mut queue.desc,
+                            1 => &mut queue.driver,
+                            2 => &mut queue.device,
+                            _ => break,
+                        };
+                        *address = (*address & !(0xff << shift)) | (u64::from(byte) << shift);
+                    }
+                }
+            }
+            _ => warn!("[virtio-pci] Ignoring write to common config offset {offset:#x}"),
+        }
+    }
+
+    /// Hand the selected queue's setup to the device and enable it.
+    fn enable_queue(&mut self) {
+        let Some(&queue) = self.queues.get(self.queue_select as usize) else {
+            return;
+        };
+        self.mmio_write(MMIO_QUEUE_NUM, u32::from(queue.size));
+        for (low, high, address) in [
+            (MMIO_QUEUE_DESC_LOW, MMIO_QUEUE_DESC_HIGH, queue.desc),
+            (MMIO_QUEUE_DRIVER_LOW, MMIO_QUEUE_DRIVER_HIGH, queue.driver),
+            (MMIO_QUEUE_DEVICE_LOW, MMIO_QUEUE_DEVICE_HIGH, queue.device),
+        ] {
+            self.mmio_write(low, address as u32);
+            self.mmio_write(high, (address >> 32) as u32);
+        }
+        self.mmio_write(MMIO_QUEUE_READY, 1);
+    }
+
+    /// Forget the driver's setup after the device was reset.
+    fn reset(&mut self) {
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = [0; 2];
+        self.queue_select = 0;
+        self.mmio_write(MMIO_QUEUE_SEL, 0);
+        for queue in &mut self.queues {
+            *queue = PciQueue::new(queue.max_size);
+        }
+    }
+}
+
+impl MmioDevice for VirtioPci {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        match offset {
+            COMMON_CFG..COMMON_CFG_SIZE => self.read_common(offset, data),
+            ISR_CFG => {
+                let status = self.mmio_read(MMIO_INTERRUPT_STATUS);
+                if status != 0 {
+                    self.mmio_write(MMIO_INTERRUPT_ACK, status);
+                }
+                data.fill(0);
+                data[0] = status as u8;
+            }
+            _ if (DEVICE_CFG..DEVICE_CFG + DEVICE_CFG_SIZE).contains(&offset) => {
+                self.device.read(MMIO_CONFIG + offset - DEVICE_CFG, data);
+            }
+            _ => data.fill(0),
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        match offset {
+            COMMON_CFG..COMMON_CFG_SIZE => self.write_common(offset, data),
+            _ if (DEVICE_CFG..DEVICE_CFG + DEVICE_CFG_SIZE).contains(&offset) => {
+                self.device.write(MMIO_CONFIG + offset - DEVICE_CFG, data);
+            }
+            _ if offset >= NOTIFY_CFG => {
+                let index = (offset - NOTIFY_CFG) / u64::from(NOTIFY_OFF_MULTIPLIER);
+                if index < self.queues.len() as u64 {
+                    self.mmio_write(MMIO_QUEUE_NOTIFY, index as u32);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn retry(&mut self) {
+        self.device.retry();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::pci::PciBus;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Register writes the mock device has seen.
+    type WriteLog = Arc<Mutex<Vec<(u64, u32)>>>;
+
+    /// A two-queue MMIO device that records register writes.
+    #[derive(Default)]
+    struct MockDevice {
+        registers: HashMap<u64, u32>,
+        writes: WriteLog,
+    }
+
+    impl MmioDevice for MockDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            let queue_sel = self.registers.get(&MMIO_QUEUE_SEL).copied().unwrap_or(0);
+            let value = match offset {
+                MMIO_DEVICE_ID => 2,
+                MMIO_QUEUE_NUM_MAX if queue_sel < 2 => 128,
+                MMIO_QUEUE_NUM_MAX => 0,
+                MMIO_DEVICE_FEATURES => {
+                    match self.registers.get(&MMIO_DEVICE_FEATURES_SEL) {
+                        Some(1) => 1, // VIRTIO_F_VERSION_1
+                        _ => 0x2000_0000,
+                    }
+                }
+                MMIO_INTERRUPT_STATUS => self.registers.get(&offset).copied().unwrap_or(0),
+                MMIO_CONFIG => 0x1234,
+                _ => 0,
+            };
+            data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            let value = u32::from_le_bytes(data.try_into().unwrap());
+            if offset == MMIO_INTERRUPT_ACK {
+                self.registers.remove(&MMIO_INTERRUPT_STATUS);
+            }
+            self.registers.insert(offset, value);
+            self.writes.lock().unwrap().push((offset, value));
+        }
+    }
+
+    fn read_u32(pci: &mut VirtioPci, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        pci.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn read_u16(pci: &mut VirtioPci, offset: u64) -> u16 {
+        let mut data = [0u8; 2];
+        pci.read(offset, &mut data);
+        u16::from_le_bytes(data)
+    }
+
+    fn pci_with_log() -> (VirtioPci, WriteLog) {
+        let device = MockDevice::default();
+        let writes = device.writes.clone();
+        let pci = VirtioPci::new(Box::new(device));
+        writes.lock().unwrap().clear();
+        (pci, writes)
+    }
+
+    #[test]
+    fn test_probe_and_config_space() {
+        let (mut pci, _) = pci_with_log();
+        assert_eq!(pci.queues.len(), 2);
+        assert_eq!(read_u16(&mut pci, COMMON_NUMQ), 2);
+        assert_eq!(read_u16(&mut pci, COMMON_Q_SIZE), 128);
+        assert_eq!(read_u16(&mut pci, COMMON_MSIX), VIRTIO_MSI_NO_VECTOR);
+        assert_eq!(read_u32(&mut pci, DEVICE_CFG), 0x1234);
+
+        // Features follow the select
+        assert_eq!(read_u32(&mut pci, COMMON_DF), 0x2000_0000);
+        pci.write(COMMON_DFSELECT, &1u32.to_le_bytes());
+        assert_eq!(read_u32(&mut pci, COMMON_DF), 1);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let (pci, _) = pci_with_log();
+        let config = pci.config(5);
+        let mut bus = PciBus::new();
+        assert_eq!(bus.add(config, Box::new(pci)), 1);
+
+        let mut read = |register: u64| {
+            let mut data = [0u8; 4];
+            bus.read((1 << 15) | register, &mut data);
+            data
+        };
+        assert_eq!(u32::from_le_bytes(read(0)), 0x1042_1af4);
+        assert_eq!(read(0x3c)[..2], [5, 1]);
+
+        // Vendor capabilities, by type, chained from the header
+        let mut cap_types = Vec::new();
+        let mut cap = read(0x34)[0];
+        while cap != 0 {
+            let [id, next, _, cfg_type] = read(u64::from(cap));
+            assert_eq!(id, PCI_CAP_ID_VNDR);
+            cap_types.push(cfg_type);
+            cap = next;
+        }
+        assert_eq!(cap_types, [1, 3, 4, 2]);
+    }
+
+    #[test]
+    fn test_queue_setup_and_notify() {
+        let (mut pci, writes) = pci_with_log();
+        pci.write(COMMON_Q_SELECT, &1u16.to_le_bytes());
+        pci.write(COMMON_Q_SIZE, &64u16.to_le_bytes());
+        pci.write(COMMON_Q_DESCLO, &0x1000u32.to_le_bytes());
+        pci.write(COMMON_Q_DESCLO + 4, &1u32.to_le_bytes());
+        pci.write(COMMON_Q_AVAILLO, &0x2000u64.to_le_bytes());
+        pci.write(COMMON_Q_USEDLO, &0x3000u32.to_le_bytes());
+        assert_eq!(read_u32(&mut pci, COMMON_Q_DESCLO + 4), 1);
+        assert!(!writes.lock().unwrap().contains(&(MMIO_QUEUE_NUM, 64)));
+
+        pci.write(COMMON_Q_ENABLE, &1u16.to_le_bytes());
+        let log = writes.lock().unwrap().clone();
+        for expected in [
+            (MMIO_QUEUE_SEL, 1),
+            (MMIO_QUEUE_NUM, 64),
+            (MMIO_QUEUE_DESC_LOW, 0x1000),
+            (MMIO_QUEUE_DESC_HIGH, 1),
+            (MMIO_QUEUE_DRIVER_LOW, 0x2000),
+            (MMIO_QUEUE_DEVICE_LOW, 0x3000),
+            (MMIO_QUEUE_READY, 1),
+        ] {
+            assert!(log.contains(&expected), "missing {expected:?}");
+        }
+
+        writes.lock().unwrap().clear();
+        pci.write(NOTIFY_CFG + 4, &1u16.to_le_bytes());
+        pci.write(NOTIFY_CFG + 8, &2u16.to_le_bytes());
+        assert_eq!(*writes.lock().unwrap(), [(MMIO_QUEUE_NOTIFY, 1)]);
+
+        // Reset puts the shadows back
+        pci.write(COMMON_STATUS, &[0]);
+        assert_eq!(read_u16(&mut pci, COMMON_Q_SELECT), 0);
+        pci.write(COMMON_Q_SELECT, &1u16.to_le_bytes());
+        assert_eq!(read_u16(&mut pci, COMMON_Q_SIZE), 128);
+        assert_eq!(read_u32(&mut pci, COMMON_Q_DESCLO), 0);
+    }
+
+    #[test]
+    fn test_isr_read_clears() {
+        let (mut pci, _) = pci_with_log();
+        pci.device.write(MMIO_INTERRUPT_STATUS, &1u32.to_le_bytes());
+        let mut isr = [0u8; 1];
+        pci.read(ISR_CFG, &mut isr);
+        assert_eq!(isr, [1]);
+        pci.read(ISR_CFG, &mut isr);
+        assert_eq!(isr, [0]);
+    }
+}
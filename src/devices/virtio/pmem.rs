@@ -0,0 +1,482 @@
+//! Virtio persistent memory device (virtio-pmem).
+//!
+//! Maps a host file straight into guest physical memory, above RAM. The
+//! guest sees it as `/dev/pmem0` and can mount a filesystem on it with DAX,
+//! so file reads and writes become plain memory accesses, with no block
+//! layer and no page cache copy in the guest:
+//!
+//! ```text
+//! carbon ... --pmem rootfs.ext4 --cmdline "... root=/dev/pmem0 rootflags=dax"
+//! ```
+//!
+//! The mapping is `MAP_SHARED`, so guest writes land in the host page cache
+//! of the file. The device's single virtqueue carries flush requests: the
+//! guest sends one when it needs its writes to be durable (fsync, sync
+//! mounts) and we `fsync` the file.
+//!
+//! The region is registered with KVM as its own memory slot (see
+//! [`PMEM_MEMORY_SLOT`]) and is not in the E820 map; the guest learns its
+//! address and size from the device's config space.
+
+use crate::audit;
+use crate::boot::GuestMemory;
+use crate::devices::mmio::MmioDevice;
+use crate::kvm::IrqLine;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::{
+    Transport, VirtqDesc, Virtqueue, MAX_QUEUE_SIZE, MMIO_DEVICE_FEATURES,
+    MMIO_DEVICE_FEATURES_SEL, MMIO_DEVICE_ID, MMIO_DRIVER_FEATURES, MMIO_DRIVER_FEATURES_SEL,
+    MMIO_INTERRUPT_ACK, MMIO_INTERRUPT_STATUS, MMIO_MAGIC_VALUE, MMIO_QUEUE_DESC_HIGH,
+    MMIO_QUEUE_DESC_LOW, MMIO_QUEUE_DEVICE_HIGH, MMIO_QUEUE_DEVICE_LOW, MMIO_QUEUE_DRIVER_HIGH,
+    MMIO_QUEUE_DRIVER_LOW, MMIO_QUEUE_NOTIFY, MMIO_QUEUE_NUM, MMIO_QUEUE_NUM_MAX, MMIO_QUEUE_READY,
+    MMIO_QUEUE_SEL, MMIO_STATUS, MMIO_VENDOR_ID, MMIO_VERSION, VIRTIO_F_EVENT_IDX,
+    VIRTIO_MMIO_MAGIC, VIRTIO_MMIO_VERSION, VIRTIO_VENDOR_ID, VIRTQ_DESC_F_NEXT,
+    VIRTQ_DESC_F_WRITE,
+};
+
+/// Virtio device ID for persistent memory devices.
+const VIRTIO_PMEM_DEVICE_ID: u32 = 27;
+
+/// VIRTIO_F_VERSION_1 - required for virtio-mmio v2 devices.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Config space offsets
+const CONFIG_START: u64 = 0x100; // 8 bytes
+const CONFIG_SIZE: u64 = 0x108; // 8 bytes
+
+/// KVM memory slot of the pmem region (slots 0 and 1 are guest RAM, below
+/// and above the MMIO hole).
+pub const PMEM_MEMORY_SLOT: u32 = 2;
+
+/// The region starts at or above 4 GiB, clear of RAM and the 32-bit MMIO
+/// hole, on a 1 GiB boundary.
+const PMEM_MIN_ADDR: u64 = 1 << 32;
+const PMEM_ALIGN: u64 = 1 << 30;
+
+/// Image sizes must be a multiple of this (the guest hotplugs the region
+/// in 2 MiB subsections).
+pub const PMEM_SIZE_ALIGN: u64 = 2 << 20;
+
+/// Flush request type.
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+
+// Flush response codes
+const VIRTIO_PMEM_RESP_OK: u32 = 0;
+const VIRTIO_PMEM_RESP_EIO: u32 = 1;
+
+/// Guest physical address of the pmem region for a VM whose RAM ends at
+/// `ram_end`.
+pub fn pmem_guest_addr(ram_end: u64) -> u64 {
+    ram_end.next_multiple_of(PMEM_ALIGN).max(PMEM_MIN_ADDR)
+}
+
+/// `--pmem IMAGE`, or `--pmem path=IMAGE[,transport=pci]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PmemConfig {
+    /// The image to map.
+    pub path: String,
+    /// How the device is attached.
+    pub transport: Transport,
+}
+
+impl FromStr for PmemConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self {
+            path: s.to_string(),
+            transport: Transport::default(),
+        };
+        // A bare path keeps working, even one containing `,` or `=`
+        let Some(spec) = s.strip_prefix("path=") else {
+            return Ok(config);
+        };
+        let mut options = spec.split(',');
+        config.path = options.next().unwrap_or_default().to_string();
+        if config.path.is_empty() {
+            return Err("pmem needs path=IMAGE".into());
+        }
+        for option in options {
+            match option.split_once('=') {
+                Some(("transport", value)) => config.transport = value.parse()?,
+                _ => return Err(format!("invalid pmem option {option:?}")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Virtio persistent memory device.
+pub struct VirtioPmem {
+    /// The backing image (kept for flushes).
+    file: File,
+    /// Host address of the mapping.
+    host_addr: *mut libc::c_void,
+    /// Size of the mapping (the image size).
+    size: u64,
+    /// Guest physical address of the region.
+    guest_addr: u64,
+
+    driver_features: u64,
+    features_sel: u32,
+
+    status: u32,
+    interrupt_status: u32,
+
+    queue_sel: u32,
+    queue: Virtqueue,
+
+    /// Reference to guest memory, set via set_memory().
+    memory: Option<*const GuestMemory>,
+    /// Line to interrupt the guest on, set via set_interrupt().
+    irq: Option<Arc<dyn IrqLine>>,
+
+    /// For the audit log.
+    path: String,
+}
+
+// Safety: as for VirtioBlk, the GuestMemory pointer is only used during MMIO
+// operations on the vCPU thread. The mapping is owned by the device.
+unsafe impl Send for VirtioPmem {}
+
+impl VirtioPmem {
+    /// Map the image at `path` for placement at `guest_addr`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the image can't be opened and mapped, is locked
+    /// by another process, or its size isn't a non-zero multiple of
+    /// [`PMEM_SIZE_ALIGN`].
+    pub fn new(path: &str, guest_addr: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        // SAFETY: flock on a valid fd has no memory-safety requirements.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(io::Error::new(
+                    err.kind(),
+                    "image is locked by another process",
+                ));
+            }
+            return Err(err);
+        }
+
+        let size = file.metadata()?.len();
+        if size == 0 || size % PMEM_SIZE_ALIGN != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "image size {size} is not a non-zero multiple of {} MiB",
+                    PMEM_SIZE_ALIGN >> 20
+                ),
+            ));
+        }
+
+        // SAFETY: mapping a regular file we hold open; the result is checked
+        // and unmapped on drop.
+        let host_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_NORESERVE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if host_addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        audit::record(audit::Kind::Disk, "open-pmem", path);
+
+        info!(
+            "[virtio-pmem] Mapped {} ({} bytes) at guest {:#x}",
+            path, size, guest_addr
+        );
+        Ok(Self {
+            file,
+            host_addr,
+            size,
+            guest_addr,
+            driver_features: 0,
+            features_sel: 0,
+            status: 0,
+            interrupt_status: 0,
+            queue_sel: 0,
+            queue: Virtqueue::new(),
+            memory: None,
+            irq: None,
+            path: path.to_string(),
+        })
+    }
+
+    /// The region, as `(guest_addr, size, host_addr)` for
+    /// `set_user_memory_region`.
+    pub fn region(&self) -> (u64, u64, u64) {
+        (self.guest_addr, self.size, self.host_addr as u64)
+    }
+
+    /// Set the guest memory reference for virtqueue processing.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the GuestMemory reference remains valid
+    /// for the lifetime of this device.
+    pub fn set_memory(&mut self, memory: &GuestMemory) {
+        self.memory = Some(memory as *const GuestMemory);
+    }
+
+    /// Set the line used-buffer notifications are raised on.
+    pub fn set_interrupt(&mut self, irq: Arc<dyn IrqLine>) {
+        self.irq = Some(irq);
+    }
+
+    /// Process all pending flush requests.
+    fn process_queue(&mut self) {
+        let memory = match self.memory {
+            Some(ptr) => unsafe { &*ptr },
+            None => return,
+        };
+
+        while let Some(head) = self.queue.pop_avail(memory) {
+            let len = self.process_request(memory, head);
+            if self.queue.push_used(memory, head, len).is_none() {
+                warn!("[virtio-pmem] Failed to push to used ring");
+            }
+        }
+        if self.queue.needs_interrupt(memory) {
+            self.interrupt_status |= 1;
+            if let Some(irq) = &self.irq {
+                irq.trigger();
+            }
+        }
+    }
+
+    /// Handle one request: `type[4]` in, `ret[4]` out.
+    fn process_request(&mut self, memory: &GuestMemory, head: u16) -> u32 {
+        let mut descs: Vec<VirtqDesc> = Vec::new();
+        let mut idx = head;
+        loop {
+            let Some(desc) = self.queue.read_desc(memory, idx) else {
+                warn!("[virtio-pmem] Failed to read descriptor {}", idx);
+                return 0;
+            };
+            descs.push(desc);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 || descs.len() > MAX_QUEUE_SIZE as usize {
+                break;
+            }
+            idx = desc.next;
+        }
+
+        let (Some(request), Some(response)) = (
+            descs.iter().find(|d| d.flags & VIRTQ_DESC_F_WRITE == 0),
+            descs.iter().find(|d| d.flags & VIRTQ_DESC_F_WRITE != 0),
+        ) else {
+            warn!("[virtio-pmem] Malformed request");
+            return 0;
+        };
+        let mut req_type = [0u8; 4];
+        if memory.read(request.addr, &mut req_type).is_err() {
+            warn!("[virtio-pmem] Failed to read request");
+            return 0;
+        }
+
+        let ret = match u32::from_le_bytes(req_type) {
+            VIRTIO_PMEM_REQ_TYPE_FLUSH => match self.file.sync_all() {
+                Ok(()) => VIRTIO_PMEM_RESP_OK,
+                Err(e) => {
+                    warn!("[virtio-pmem] Flush error: {}", e);
+                    VIRTIO_PMEM_RESP_EIO
+                }
+            },
+            other => {
+                warn!("[virtio-pmem] Unsupported request type: {}", other);
+                VIRTIO_PMEM_RESP_EIO
+            }
+        };
+        if memory.write(response.addr, &ret.to_le_bytes()).is_err() {
+            warn!("[virtio-pmem] Failed to write response");
+            return 0;
+        }
+        4
+    }
+
+    /// Read a 32-bit register value.
+    fn read_register(&mut self, offset: u64) -> u32 {
+        match offset {
+            MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            MMIO_VERSION => VIRTIO_MMIO_VERSION,
+            MMIO_DEVICE_ID => VIRTIO_PMEM_DEVICE_ID,
+            MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            MMIO_DEVICE_FEATURES => {
+                let features = VIRTIO_F_VERSION_1 | u64::from(VIRTIO_F_EVENT_IDX);
+                match self.features_sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            MMIO_QUEUE_NUM_MAX if self.queue_sel == 0 => MAX_QUEUE_SIZE as u32,
+            MMIO_QUEUE_NUM_MAX => 0,
+            MMIO_QUEUE_READY => (self.queue_sel == 0 && self.queue.ready) as u32,
+            MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            MMIO_STATUS => self.status,
+
+            // Config space
+            CONFIG_START => self.guest_addr as u32,
+            0x104 => (self.guest_addr >> 32) as u32,
+            CONFIG_SIZE => self.size as u32,
+            0x10c => (self.size >> 32) as u32,
+
+            _ => {
+                warn!("[virtio-pmem] Unknown register read: {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    /// Write a 32-bit register value.
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let set_low = |addr: &mut u64| *addr = (*addr & 0xFFFF_FFFF_0000_0000) | value as u64;
+        let set_high =
+            |addr: &mut u64| *addr = (*addr & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+        match offset {
+            MMIO_DEVICE_FEATURES_SEL | MMIO_DRIVER_FEATURES_SEL => self.features_sel = value,
+            MMIO_DRIVER_FEATURES => match self.features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | value as u64,
+                1 => {
+                    self.driver_features =
+                        (self.driver_features & 0xFFFF_FFFF) | ((value as u64) << 32)
+                }
+                _ => {}
+            },
+            MMIO_QUEUE_SEL => self.queue_sel = value,
+            MMIO_QUEUE_NOTIFY => self.process_queue(),
+            MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            MMIO_STATUS => {
+                self.status = value;
+                if value == 0 {
+                    self.queue = Virtqueue::new();
+                    self.interrupt_status = 0;
+                    debug!("[virtio-pmem] Device reset");
+                } else {
+                    debug!("[virtio-pmem] Status: {:#x}", value);
+                }
+            }
+            _ if self.queue_sel != 0 => {}
+            MMIO_QUEUE_NUM if value <= MAX_QUEUE_SIZE as u32 => self.queue.size = value as u16,
+            MMIO_QUEUE_READY => {
+                self.queue.ready = value != 0;
+                self.queue.event_idx = self.driver_features & u64::from(VIRTIO_F_EVENT_IDX) != 0;
+            }
+            MMIO_QUEUE_DESC_LOW => set_low(&mut self.queue.desc_table),
+            MMIO_QUEUE_DESC_HIGH => set_high(&mut self.queue.desc_table),
+            MMIO_QUEUE_DRIVER_LOW => set_low(&mut self.queue.avail_ring),
+            MMIO_QUEUE_DRIVER_HIGH => set_high(&mut self.queue.avail_ring),
+            MMIO_QUEUE_DEVICE_LOW => set_low(&mut self.queue.used_ring),
+            MMIO_QUEUE_DEVICE_HIGH => set_high(&mut self.queue.used_ring),
+            _ => warn!(
+                "[virtio-pmem] Unknown register write: {:#x} = {:#x}",
+                offset, value
+            ),
+        }
+    }
+}
+
+impl Drop for VirtioPmem {
+    fn drop(&mut self) {
+        let _ = self.file.sync_all();
+        // SAFETY: host_addr/size are the mapping created in new(), and the VM
+        // no longer runs once its devices are dropped.
+        unsafe { libc::munmap(self.host_addr, self.size as usize) };
+        audit::record(audit::Kind::Disk, "close", &self.path);
+    }
+}
+
+impl MmioDevice for VirtioPmem {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = self.read_register(offset & !0x3); // Align to 4 bytes
+        let bytes = value.to_le_bytes();
+
+        // Handle sub-word reads
+        let start = (offset & 0x3) as usize;
+        let len = data.len().min(4 - start);
+        data[..len].copy_from_slice(&bytes[start..start + len]);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        // Only handle 4-byte aligned writes
+        if data.len() != 4 || offset & 0x3 != 0 {
+            warn!(
+                "[virtio-pmem] Non-aligned write: offset={:#x} len={}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        self.write_register(offset, value);
+    }
+
+    fn notify_queues(&self) -> u32 {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_addr_is_above_ram() {
+        assert_eq!(pmem_guest_addr(512 << 20), 1 << 32);
+        assert_eq!(pmem_guest_addr(4 << 30), 4 << 30);
+        assert_eq!(pmem_guest_addr((6 << 30) + 1), 7 << 30);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: PmemConfig = "images/a,b.ext4".parse().unwrap();
+        assert_eq!(config.path, "images/a,b.ext4");
+        assert_eq!(config.transport, Transport::Mmio);
+        assert_eq!(
+            "path=rootfs.ext4,transport=pci".parse(),
+            Ok(PmemConfig {
+                path: "rootfs.ext4".into(),
+                transport: Transport::Pci,
+            })
+        );
+        assert!("path=".parse::<PmemConfig>().is_err());
+        assert!("path=rootfs.ext4,ro".parse::<PmemConfig>().is_err());
+    }
+
+    #[test]
+    fn test_mapping_and_config() {
+        let path = std::env::temp_dir().join(format!("carbon-pmem-{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        std::fs::write(&path, b"not aligned").unwrap();
+        assert!(VirtioPmem::new(path_str, 1 << 32).is_err());
+
+        let file = File::create(&path).unwrap();
+        file.set_len(PMEM_SIZE_ALIGN).unwrap();
+        let mut pmem = VirtioPmem::new(path_str, 1 << 32).unwrap();
+        let (guest_addr, size, host_addr) = pmem.region();
+        assert_eq!((guest_addr, size), (1 << 32, PMEM_SIZE_ALIGN));
+        assert_eq!(pmem.read_register(CONFIG_START + 4), 1);
+        assert_eq!(pmem.read_register(CONFIG_SIZE), PMEM_SIZE_ALIGN as u32);
+
+        // Guest writes go straight to the file
+        unsafe { *(host_addr as *mut u8).add(4096) = 0xab };
+        drop(pmem);
+        assert_eq!(std::fs::read(&path).unwrap()[4096], 0xab);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
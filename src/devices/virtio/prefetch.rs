@@ -0,0 +1,256 @@
+//! Boot-profile guided disk prefetch.
+//!
+//! The first boot against a cold image (on a network filesystem, or simply
+//! not in the page cache) stalls on every block the guest touches. Those
+//! blocks are almost the same on every boot, so we learn them once and pull
+//! them in ahead of the guest next time:
+//!
+//! 1. **Record**: for the first [`RECORD_WINDOW`] of the device's life, note
+//!    which [`CHUNK_SIZE`] chunks the guest reads, in first-access order.
+//! 2. **Save**: write them out as the image's *boot profile* in the cache
+//!    directory (`$XDG_CACHE_HOME/carbon/prefetch`).
+//! 3. **Prefetch**: on the next start, a background thread reads the
+//!    profiled chunks in the same order, so by the time the guest asks for
+//!    them they are already in the host page cache.
+//!
+//! Each boot replaces the profile, so it follows the image as it changes.
+//! Prefetch is best effort: a missing or unreadable profile just means no
+//! prefetch, and I/O errors on the prefetch thread are ignored.
+
+use crate::audit;
+use crate::config;
+use std::collections::HashSet;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Granularity of recording and prefetching.
+pub const CHUNK_SIZE: u64 = 128 * 1024;
+
+/// How long after device creation reads count as part of the boot.
+pub const RECORD_WINDOW: Duration = Duration::from_secs(30);
+
+/// Upper bound on profiled chunks (2 GiB of data).
+const MAX_PROFILE_CHUNKS: usize = 16 * 1024;
+
+/// First line of a profile file.
+const PROFILE_HEADER: &str = "carbon-boot-profile 1";
+
+/// Chunks read during a boot, in first-access order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootProfile {
+    chunks: Vec<u64>,
+}
+
+impl BootProfile {
+    /// Serialize as text: a header, then one `start count` run per line.
+    fn to_text(&self) -> String {
+        let mut out = format!("{PROFILE_HEADER}\n");
+        let mut runs = self.chunks.iter().peekable();
+        while let Some(&start) = runs.next() {
+            let mut count = 1;
+            while runs.peek() == Some(&&(start + count)) {
+                runs.next();
+                count += 1;
+            }
+            out.push_str(&format!("{start} {count}\n"));
+        }
+        out
+    }
+
+    /// Parse the text format. Returns `None` for anything malformed.
+    fn from_text(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        if lines.next()? != PROFILE_HEADER {
+            return None;
+        }
+        let mut chunks = Vec::new();
+        for line in lines {
+            let (start, count) = line.split_once(' ')?;
+            let start: u64 = start.parse().ok()?;
+            let count: u64 = count.parse().ok()?;
+            if chunks.len() as u64 + count > MAX_PROFILE_CHUNKS as u64 {
+                return None;
+            }
+            chunks.extend(start..start.checked_add(count)?);
+        }
+        Some(Self { chunks })
+    }
+}
+
+/// Learns the boot profile of one image and prefetches the previous one.
+pub struct Prefetcher {
+    profile_path: PathBuf,
+    started: Instant,
+    /// `None` once the record window has closed and the profile was saved.
+    recording: Option<(BootProfile, HashSet<u64>)>,
+    /// Tells the background reader to stop early.
+    stop: Arc<AtomicBool>,
+}
+
+impl Prefetcher {
+    /// Start prefetching `disk` from its saved profile and begin recording.
+    ///
+    /// Returns `None` if there's nowhere to keep profiles.
+    pub fn start(disk: &File, image_path: &str) -> Option<Self> {
+        let profile_path = profile_path(Path::new(image_path))?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let saved = std::fs::read_to_string(&profile_path)
+            .ok()
+            .and_then(|text| {
+                audit::record(
+                    audit::Kind::File,
+                    "read",
+                    &profile_path.display().to_string(),
+                );
+                BootProfile::from_text(&text)
+            });
+        if let (Some(profile), Ok(file)) = (saved, disk.try_clone()) {
+            let stop = stop.clone();
+            let _ = std::thread::Builder::new()
+                .name("blk-prefetch".into())
+                .spawn(move || prefetch(file, profile, stop));
+        }
+
+        Some(Self {
+            profile_path,
+            started: Instant::now(),
+            recording: Some(Default::default()),
+            stop,
+        })
+    }
+
+    /// Note a guest read of `len` bytes at byte `offset`.
+    pub fn record(&mut self, offset: u64, len: u64) {
+        let Some((profile, seen)) = &mut self.recording else {
+            return;
+        };
+        if self.started.elapsed() > RECORD_WINDOW || profile.chunks.len() >= MAX_PROFILE_CHUNKS {
+            self.save();
+            return;
+        }
+        let first = offset / CHUNK_SIZE;
+        let last = (offset + len.max(1) - 1) / CHUNK_SIZE;
+        for chunk in first..=last {
+            if seen.insert(chunk) {
+                profile.chunks.push(chunk);
+            }
+        }
+    }
+
+    /// Write the recorded profile and stop recording.
+    fn save(&mut self) {
+        let Some((profile, _)) = self.recording.take() else {
+            return;
+        };
+        if profile.chunks.is_empty() {
+            return;
+        }
+        let result = self
+            .profile_path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(&self.profile_path, profile.to_text()));
+        let path = self.profile_path.display();
+        match result {
+            Ok(()) => {
+                audit::record(audit::Kind::File, "write", &path.to_string());
+                debug!(
+                    "[virtio-blk] Saved boot profile: {} chunks to {}",
+                    profile.chunks.len(),
+                    path
+                );
+            }
+            Err(e) => warn!("[virtio-blk] Failed to save boot profile {}: {}", path, e),
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.save();
+    }
+}
+
+/// Background reader: pull every profiled chunk into the page cache.
+fn prefetch(file: File, profile: BootProfile, stop: Arc<AtomicBool>) {
+    let start = Instant::now();
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut fetched = 0;
+    for chunk in &profile.chunks {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        if file.read_at(&mut buf, chunk * CHUNK_SIZE).is_ok() {
+            fetched += 1;
+        }
+    }
+    debug!(
+        "[virtio-blk] Prefetched {} of {} boot chunks in {:?}",
+        fetched,
+        profile.chunks.len(),
+        start.elapsed()
+    );
+}
+
+/// Cache file for an image's profile, keyed by its canonical path.
+fn profile_path(image: &Path) -> Option<PathBuf> {
+    let canonical = image.canonicalize().ok()?;
+    let key = fnv1a(canonical.as_os_str().as_encoded_bytes());
+    Some(
+        config::cache_dir()?
+            .join("prefetch")
+            .join(format!("{key:016x}.profile")),
+    )
+}
+
+/// 64-bit FNV-1a, a stable hash for cache file names.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_text_roundtrip() {
+        let profile = BootProfile {
+            chunks: vec![0, 1, 2, 40, 7, 8],
+        };
+        let text = profile.to_text();
+        assert_eq!(text, "carbon-boot-profile 1\n0 3\n40 1\n7 2\n");
+        assert_eq!(BootProfile::from_text(&text), Some(profile));
+    }
+
+    #[test]
+    fn test_profile_rejects_garbage() {
+        assert_eq!(BootProfile::from_text(""), None);
+        assert_eq!(BootProfile::from_text("carbon-boot-profile 1\nx y\n"), None);
+        assert_eq!(
+            BootProfile::from_text("carbon-boot-profile 1\n0 99999999\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_record_spans_chunks_once() {
+        let mut prefetcher = Prefetcher {
+            profile_path: PathBuf::new(),
+            started: Instant::now(),
+            recording: Some(Default::default()),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        prefetcher.record(CHUNK_SIZE - 512, 1024);
+        prefetcher.record(0, 512);
+        let (profile, _) = prefetcher.recording.take().unwrap();
+        assert_eq!(profile.chunks, vec![0, 1]);
+    }
+}
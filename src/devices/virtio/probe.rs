@@ -0,0 +1,319 @@
+//! Disk image format detection.
+//!
+//! Before booting, Carbon reads the start of every disk image (through the
+//! device, so an encrypted disk is probed decrypted) and looks for the
+//! signatures of the formats it might hold:
+//!
+//! | Found                                           | Outcome                     |
+//! | ----------------------------------------------- | --------------------------- |
+//! | qcow2, VMDK, VDI, VHDX or dynamic VHD           | Error: not a raw image      |
+//! | gzip, xz, zstd or bzip2 data                    | Error: compressed           |
+//! | GPT or MBR partition table                      | Linux partition looked up   |
+//! | ext2/3/4, XFS, btrfs, squashfs, EROFS, ISO 9660 | Attached as is              |
+//! | Swap                                            | Attached as is              |
+//! | Only zeroes                                     | Blank: nothing to boot from |
+//!
+//! Anything else is attached without comment. The `root=` generated for
+//! `--rootfs`, or for a command line without one, follows the image's
+//! partition table, and a `root=/dev/vdX` given on the command line that
+//! doesn't fit the disk it names is warned about, as the guest would
+//! otherwise fail to mount it, or wait for it forever.
+
+use std::fmt;
+
+/// How much of the image [`probe`] looks at: enough to reach the btrfs
+/// superblock at 64 KiB.
+pub const PROBE_SIZE: usize = 68 * 1024;
+
+/// What a disk image holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A partition table, and the number of the partition to boot from, if
+    /// one holds a Linux filesystem.
+    Partitioned {
+        table: &'static str,
+        root: Option<u32>,
+    },
+    /// A filesystem (or swap) on the whole disk.
+    Filesystem(&'static str),
+    /// An image format or compression Carbon can't attach.
+    Unsupported(&'static str),
+    /// Nothing but zeroes.
+    Blank,
+    /// Nothing recognised.
+    Unknown,
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Partitioned {
+                table,
+                root: Some(n),
+            } => write!(f, "{table} partition table, Linux on partition {n}"),
+            Self::Partitioned { table, root: None } => {
+                write!(f, "{table} partition table, no Linux partition")
+            }
+            Self::Filesystem(name) => write!(f, "{name}"),
+            Self::Unsupported(name) => write!(f, "{name}"),
+            Self::Blank => f.write_str("blank"),
+            Self::Unknown => f.write_str("unknown contents"),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// Why an image of this format can't be attached, if it can't.
+    pub fn error(&self) -> Option<String> {
+        let Self::Unsupported(name) = self else {
+            return None;
+        };
+        Some(match *name {
+            "gzip" | "xz" | "zstd" | "bzip2" => {
+                format!("image is {name}-compressed; decompress it first")
+            }
+            _ => format!(
+                "image is in {name} format, not raw; convert it with `qemu-img convert -O raw`"
+            ),
+        })
+    }
+}
+
+/// Image formats and compression recognised by their leading bytes.
+const UNSUPPORTED: [(&[u8], &str); 9] = [
+    (b"QFI\xfb", "qcow2"),
+    (b"KDMV", "VMDK"),
+    (b"# Disk DescriptorFile", "VMDK"),
+    (b"vhdxfile", "VHDX"),
+    (b"conectix", "VHD"),
+    (b"\x1f\x8b", "gzip"),
+    (b"\xfd7zXZ\x00", "xz"),
+    (b"\x28\xb5\x2f\xfd", "zstd"),
+    (b"BZh", "bzip2"),
+];
+
+/// Filesystem signatures: offset, magic, name.
+const FILESYSTEMS: [(usize, &[u8], &str); 5] = [
+    (0, b"XFSB", "XFS"),
+    (0x10040, b"_BHRfS_M", "btrfs"),
+    (0, b"hsqs", "squashfs"),
+    (1024, b"\xe2\xe1\xf5\xe0", "EROFS"),
+    (0x8001, b"CD001", "ISO 9660"),
+];
+
+/// GPT partition type GUIDs (as stored) of Linux root and data partitions,
+/// in order of preference.
+const LINUX_PARTITION_TYPES: [[u8; 16]; 2] = [
+    // 4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709: root (x86-64)
+    [
+        0xe3, 0xbc, 0x68, 0x4f, 0xcd, 0xe8, 0xb1, 0x4d, 0x96, 0xe7, 0xfb, 0xca, 0xf9, 0x84, 0xb7,
+        0x09,
+    ],
+    // 0FC63DAF-8483-4772-8E79-3D69D8477DE4: Linux filesystem data
+    [
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ],
+];
+
+/// MBR partition type of Linux filesystems.
+const MBR_LINUX: u8 = 0x83;
+
+/// What the image starting with `head` (up to [`PROBE_SIZE`] bytes) holds.
+pub fn probe(head: &[u8]) -> ImageFormat {
+    let at = |offset: usize, len: usize| head.get(offset..offset + len);
+    if let Some((_, name)) = UNSUPPORTED
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+    {
+        return ImageFormat::Unsupported(name);
+    }
+    if at(0x40, 4) == Some(&0xbeda_107f_u32.to_le_bytes()) {
+        return ImageFormat::Unsupported("VDI");
+    }
+    for block_size in [512, 4096] {
+        if at(block_size, 8) == Some(b"EFI PART") {
+            return ImageFormat::Partitioned {
+                table: "GPT",
+                root: gpt_root(head, block_size),
+            };
+        }
+    }
+    if at(1080, 2) == Some(&0xef53_u16.to_le_bytes()) {
+        return ImageFormat::Filesystem(ext_version(head));
+    }
+    if let Some((_, _, name)) = FILESYSTEMS
+        .iter()
+        .find(|(offset, magic, _)| at(*offset, magic.len()) == Some(magic))
+    {
+        return ImageFormat::Filesystem(name);
+    }
+    if at(4086, 10) == Some(b"SWAPSPACE2") {
+        return ImageFormat::Filesystem("swap");
+    }
+    if let Some(root) = mbr_root(head) {
+        return ImageFormat::Partitioned { table: "MBR", root };
+    }
+    if head.iter().all(|&b| b == 0) {
+        return ImageFormat::Blank;
+    }
+    ImageFormat::Unknown
+}
+
+/// ext2, ext3 or ext4, from the superblock's feature flags.
+fn ext_version(head: &[u8]) -> &'static str {
+    let flags = |offset: usize| u32::from_le_bytes(head[1024 + offset..][..4].try_into().unwrap());
+    const COMPAT_HAS_JOURNAL: u32 = 0x4;
+    const INCOMPAT_EXTENTS: u32 = 0x40;
+    const INCOMPAT_64BIT: u32 = 0x80;
+    if head.len() < 1024 + 0x68 {
+        "ext2/3/4"
+    } else if flags(0x60) & (INCOMPAT_EXTENTS | INCOMPAT_64BIT) != 0 {
+        "ext4"
+    } else if flags(0x5c) & COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    }
+}
+
+/// The number of the GPT partition to boot from: the first x86-64 root
+/// partition, else the first Linux data partition.
+fn gpt_root(head: &[u8], block_size: usize) -> Option<u32> {
+    let header = head.get(block_size..block_size + 92)?;
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let count = u32::from_le_bytes(header[80..84].try_into().unwrap());
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if entry_size < 128 {
+        return None;
+    }
+    let start = usize::try_from(entries_lba).ok()?.checked_mul(block_size)?;
+    let types: Vec<(u32, &[u8])> = (0..count)
+        .map_while(|i| {
+            let offset = start + i as usize * entry_size;
+            Some((i + 1, head.get(offset..offset + 16)?))
+        })
+        .collect();
+    LINUX_PARTITION_TYPES.iter().find_map(|linux| {
+        types
+            .iter()
+            .find(|(_, guid)| guid == linux)
+            .map(|(number, _)| *number)
+    })
+}
+
+/// If `head` starts with an MBR partition table, the number of its first
+/// Linux partition (if any).
+fn mbr_root(head: &[u8]) -> Option<Option<u32>> {
+    if head.get(510..512) != Some(&[0x55, 0xaa]) {
+        return None;
+    }
+    // FAT and NTFS boot sectors end the same way
+    if head.get(3..7) == Some(b"NTFS")
+        || head.get(0x36..0x39) == Some(b"FAT")
+        || head.get(0x52..0x55) == Some(b"FAT")
+    {
+        return None;
+    }
+    let entries: Vec<&[u8]> = head[446..510].chunks_exact(16).collect();
+    if entries.iter().any(|entry| entry[0] & 0x7f != 0) || entries.iter().all(|e| e[4] == 0) {
+        return None;
+    }
+    Some(
+        entries
+            .iter()
+            .position(|entry| entry[4] == MBR_LINUX)
+            .map(|i| i as u32 + 1),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(writes: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut head = vec![0u8; PROBE_SIZE];
+        for (offset, bytes) in writes {
+            head[*offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        head
+    }
+
+    #[test]
+    fn test_probe_formats() {
+        assert_eq!(probe(&image(&[])), ImageFormat::Blank);
+        assert_eq!(probe(&image(&[(100, b"x")])), ImageFormat::Unknown);
+        assert_eq!(
+            probe(&image(&[(0, b"QFI\xfb\x00\x00\x00\x03")])),
+            ImageFormat::Unsupported("qcow2")
+        );
+        assert!(probe(b"\x1f\x8b\x08").error().unwrap().contains("gzip"));
+        assert!(probe(b"QFI\xfb").error().unwrap().contains("qemu-img"));
+        assert_eq!(
+            probe(&image(&[(0x40, &0xbeda_107f_u32.to_le_bytes())])),
+            ImageFormat::Unsupported("VDI")
+        );
+
+        // ext4: magic plus the extents feature
+        let ext4 = image(&[(1080, &[0x53, 0xef]), (1024 + 0x60, &[0x40])]);
+        assert_eq!(probe(&ext4), ImageFormat::Filesystem("ext4"));
+        assert_eq!(ImageFormat::Filesystem("ext4").error(), None);
+        let ext2 = image(&[(1080, &[0x53, 0xef])]);
+        assert_eq!(probe(&ext2), ImageFormat::Filesystem("ext2"));
+        assert_eq!(
+            probe(&image(&[(0x10040, b"_BHRfS_M")])),
+            ImageFormat::Filesystem("btrfs")
+        );
+        assert_eq!(
+            probe(&image(&[(4086, b"SWAPSPACE2")])),
+            ImageFormat::Filesystem("swap")
+        );
+    }
+
+    #[test]
+    fn test_probe_partitions() {
+        // MBR: an EFI system partition, then Linux
+        let mut mbr = image(&[(510, &[0x55, 0xaa])]);
+        mbr[446 + 4] = 0xef;
+        mbr[446 + 16 + 4] = MBR_LINUX;
+        assert_eq!(
+            probe(&mbr),
+            ImageFormat::Partitioned {
+                table: "MBR",
+                root: Some(2)
+            }
+        );
+        // A FAT boot sector isn't a partition table
+        let fat = image(&[(510, &[0x55, 0xaa]), (0x52, b"FAT32"), (446 + 4, &[1])]);
+        assert_eq!(probe(&fat), ImageFormat::Unknown);
+
+        // GPT: entries at LBA 2, a Linux data partition, then a root one
+        let mut header = [0u8; 92];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let gpt = image(&[
+            (510, &[0x55, 0xaa]),
+            (446 + 4, &[0xee]),
+            (512, &header),
+            (1024, &LINUX_PARTITION_TYPES[1]),
+            (1024 + 2 * 128, &LINUX_PARTITION_TYPES[0]),
+        ]);
+        assert_eq!(
+            probe(&gpt),
+            ImageFormat::Partitioned {
+                table: "GPT",
+                root: Some(3)
+            }
+        );
+        let empty_gpt = image(&[(512, &header)]);
+        assert_eq!(
+            probe(&empty_gpt),
+            ImageFormat::Partitioned {
+                table: "GPT",
+                root: None
+            }
+        );
+    }
+}
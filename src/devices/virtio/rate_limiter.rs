@@ -0,0 +1,411 @@
+//! Token-bucket rate limiting for device I/O.
+//!
+//! A [`RateLimiter`] has up to two token buckets: one counting requests
+//! (IOPS) and one counting bytes (bandwidth). A bucket holds at most its
+//! burst size in tokens and refills at its rate, in tokens per second; every
+//! request takes one request token and one byte token per byte it moves.
+//!
+//! A request runs once every bucket holds its tokens, or is full for a
+//! request larger than the bucket, which then goes into debt: a burst up to
+//! the bucket size runs at full speed, sustained I/O settles at the refill
+//! rate, and requests larger than the bucket still make progress.
+//!
+//! Devices serve requests on the event loop's thread (or a shared reactor's),
+//! under the lock every vCPU exit takes, so a request over the limit must
+//! not wait there. The device leaves it on its virtqueue and arms the VM's
+//! [`RetryTimer`], which the event loop watches; when it fires, devices serve
+//! what they held back (see [`MmioDevice::retry`](crate::devices::mmio::MmioDevice::retry)).
+//!
+//! Limits are set per disk (`--disk path=IMAGE,iops=N,bw=SIZE`) and can be
+//! changed while the VM runs through the control socket (see
+//! [`crate::control`]), which shares the device's limiter.
+
+use crate::size::ByteSize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// A token bucket's limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bucket {
+    /// Tokens added per second.
+    pub rate: u64,
+    /// Most tokens the bucket holds (the rate if unset: one second's worth).
+    pub burst: Option<u64>,
+}
+
+impl Bucket {
+    fn size(&self) -> u64 {
+        self.burst.unwrap_or(self.rate)
+    }
+}
+
+/// Rate limits for one device: `iops=N[,iops-burst=N][,bw=SIZE][,bw-burst=SIZE]`,
+/// where `bw` is bytes per second (`50M`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Requests per second.
+    pub iops: Option<Bucket>,
+    /// Bytes per second.
+    pub bandwidth: Option<Bucket>,
+}
+
+impl RateLimit {
+    /// Option names [`RateLimit::set`] accepts.
+    pub const KEYS: [&str; 4] = ["iops", "iops-burst", "bw", "bw-burst"];
+
+    /// Apply one `key=value` option (one of [`RateLimit::KEYS`]).
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let count = |value: &str| match value.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!(
+                "invalid {key}={value:?} (expected a positive number)"
+            )),
+        };
+        let bytes = |value: &str| match value.parse::<ByteSize>() {
+            Ok(size) if size.bytes() > 0 => Ok(size.bytes()),
+            _ => Err(format!(
+                "invalid {key}={value:?} (expected a size, e.g. 50M)"
+            )),
+        };
+        fn bucket(bucket: &mut Option<Bucket>) -> &mut Bucket {
+            bucket.get_or_insert(Bucket {
+                rate: 0,
+                burst: None,
+            })
+        }
+        match key {
+            "iops" => bucket(&mut self.iops).rate = count(value)?,
+            "iops-burst" => bucket(&mut self.iops).burst = Some(count(value)?),
+            "bw" => bucket(&mut self.bandwidth).rate = bytes(value)?,
+            "bw-burst" => bucket(&mut self.bandwidth).burst = Some(bytes(value)?),
+            _ => return Err(format!("invalid rate limit option {key:?}")),
+        }
+        Ok(())
+    }
+
+    /// Check every burst comes with a rate.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.iops.is_some_and(|b| b.rate == 0) {
+            return Err("iops-burst needs iops".into());
+        }
+        if self.bandwidth.is_some_and(|b| b.rate == 0) {
+            return Err("bw-burst needs bw".into());
+        }
+        Ok(())
+    }
+
+    /// Whether any limit is set.
+    pub fn is_limited(&self) -> bool {
+        self.iops.is_some() || self.bandwidth.is_some()
+    }
+}
+
+impl fmt::Display for RateLimit {
+    /// The limits as options, e.g. `iops=100,bw=1048576`, or `unlimited`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut options = Vec::new();
+        for (name, bucket) in [("iops", self.iops), ("bw", self.bandwidth)] {
+            if let Some(bucket) = bucket {
+                options.push(format!("{name}={}", bucket.rate));
+                if let Some(burst) = bucket.burst {
+                    options.push(format!("{name}-burst={burst}"));
+                }
+            }
+        }
+        if options.is_empty() {
+            f.write_str("unlimited")
+        } else {
+            f.write_str(&options.join(","))
+        }
+    }
+}
+
+/// A bucket's limits and current fill.
+#[derive(Debug)]
+struct TokenBucket {
+    bucket: Bucket,
+    /// Tokens available; negative while in debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    fn new(bucket: Bucket, now: Instant) -> Self {
+        Self {
+            bucket,
+            tokens: bucket.size() as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Refill the bucket for the time since the last refill, and return
+    /// how long until it holds enough for `count` tokens (zero if it does).
+    fn refill(&mut self, count: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        let rate = self.bucket.rate as f64;
+        let size = self.bucket.size() as f64;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(size);
+        // A request larger than the bucket needs a full one
+        let needed = (count as f64).min(size);
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / rate)
+        }
+    }
+}
+
+/// A device's IOPS and bandwidth buckets, shared with the control socket so
+/// the limits can change at runtime.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<[Option<TokenBucket>; 2]>,
+    /// Fired when the limits change, so held requests are looked at again.
+    timer: OnceLock<Arc<RetryTimer>>,
+}
+
+impl RateLimiter {
+    /// A limiter enforcing `limit`, with full buckets.
+    pub fn new(limit: RateLimit) -> Self {
+        let limiter = Self::default();
+        limiter.set(limit);
+        limiter
+    }
+
+    /// Replace the limits. The buckets start full again, and requests held
+    /// back under the old limits are retried.
+    pub fn set(&self, limit: RateLimit) {
+        let now = Instant::now();
+        *self.buckets.lock().unwrap_or_else(|e| e.into_inner()) = [
+            limit.iops.map(|b| TokenBucket::new(b, now)),
+            limit.bandwidth.map(|b| TokenBucket::new(b, now)),
+        ];
+        if let Some(timer) = self.timer.get() {
+            timer.schedule(Duration::ZERO);
+        }
+    }
+
+    /// Fire `timer` whenever the limits change. The first timer set stays.
+    pub fn set_timer(&self, timer: Arc<RetryTimer>) {
+        let _ = self.timer.set(timer);
+    }
+
+    /// The limits currently enforced.
+    #[cfg(test)]
+    pub fn limit(&self) -> RateLimit {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        RateLimit {
+            iops: buckets[0].as_ref().map(|b| b.bucket),
+            bandwidth: buckets[1].as_ref().map(|b| b.bucket),
+        }
+    }
+
+    /// Charge a request moving `bytes` and return zero if it may run now;
+    /// otherwise charge nothing and return how long until it may.
+    pub fn throttle(&self, bytes: u64) -> Duration {
+        self.throttle_at(bytes, Instant::now())
+    }
+
+    fn throttle_at(&self, bytes: u64, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let [iops, bandwidth] = &mut *buckets;
+        let ops_wait = iops.as_mut().map_or(Duration::ZERO, |b| b.refill(1, now));
+        let bytes_wait = bandwidth
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.refill(bytes, now));
+        let wait = ops_wait.max(bytes_wait);
+        if wait.is_zero() {
+            if let Some(iops) = iops {
+                iops.tokens -= 1.0;
+            }
+            if let Some(bandwidth) = bandwidth {
+                bandwidth.tokens -= bytes as f64;
+            }
+        }
+        wait
+    }
+}
+
+/// A timerfd the event loop watches, armed by devices for the earliest
+/// time a request they held back may run. One serves every device of a VM:
+/// when it fires, the event loop has each [`retry`](crate::devices::mmio::MmioDevice::retry).
+#[derive(Debug)]
+pub struct RetryTimer {
+    fd: OwnedFd,
+    /// When the timer fires, while armed.
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl RetryTimer {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: timerfd_create has no memory-safety preconditions.
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            // SAFETY: a new descriptor, owned by nothing else.
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            deadline: Mutex::new(None),
+        })
+    }
+
+    /// Fire in `after`, unless the timer fires sooner already.
+    pub fn schedule(&self, after: Duration) {
+        let at = Instant::now() + after;
+        let mut deadline = self.deadline.lock().unwrap_or_else(|e| e.into_inner());
+        if deadline.is_some_and(|deadline| deadline <= at) {
+            return;
+        }
+        // A zero it_value disarms the timer
+        let after = after.max(Duration::from_nanos(1));
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: after.as_secs() as libc::time_t,
+                tv_nsec: after.subsec_nanos() as libc::c_long,
+            },
+        };
+        // SAFETY: `spec` is a valid itimerspec, and the old value isn't
+        // asked for.
+        let armed =
+            unsafe { libc::timerfd_settime(self.fd.as_raw_fd(), 0, &spec, std::ptr::null_mut()) };
+        if armed == 0 {
+            *deadline = Some(at);
+        } else {
+            warn!(
+                "[virtio] Failed to arm the retry timer: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Take the timer's expiry, once the event loop finds it readable.
+    /// Returns whether it had fired; the devices it was armed for arm it
+    /// again as they retry.
+    pub fn take(&self) -> bool {
+        let mut deadline = self.deadline.lock().unwrap_or_else(|e| e.into_inner());
+        let mut expirations = [0u8; 8];
+        // SAFETY: reads at most 8 bytes into `expirations`.
+        let read = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                expirations.as_mut_ptr().cast(),
+                expirations.len(),
+            )
+        };
+        if read != expirations.len() as isize {
+            return false;
+        }
+        *deadline = None;
+        true
+    }
+}
+
+impl AsRawFd for RetryTimer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(options: &[(&str, &str)]) -> Result<RateLimit, String> {
+        let mut limit = RateLimit::default();
+        for (key, value) in options {
+            limit.set(key, value)?;
+        }
+        limit.validate().map(|()| limit)
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let parsed = limit(&[("iops", "100"), ("bw", "1M"), ("bw-burst", "4M")]).unwrap();
+        assert_eq!(
+            parsed,
+            RateLimit {
+                iops: Some(Bucket {
+                    rate: 100,
+                    burst: None
+                }),
+                bandwidth: Some(Bucket {
+                    rate: 1 << 20,
+                    burst: Some(4 << 20)
+                }),
+            }
+        );
+        assert!(limit(&[("iops", "0")]).is_err());
+        assert!(limit(&[("bw", "fast")]).is_err());
+        assert!(limit(&[("iops-burst", "10")]).is_err());
+        assert!(!limit(&[]).unwrap().is_limited());
+        assert_eq!(parsed.to_string(), "iops=100,bw=1048576,bw-burst=4194304");
+        assert_eq!(RateLimit::default().to_string(), "unlimited");
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(limit(&[("iops", "10"), ("iops-burst", "2")]).unwrap());
+        // The burst is free, then each request waits for a refill
+        assert_eq!(limiter.throttle_at(0, start), Duration::ZERO);
+        assert_eq!(limiter.throttle_at(0, start), Duration::ZERO);
+        assert_eq!(limiter.throttle_at(0, start), Duration::from_millis(100));
+        // Refilled after the wait, and never beyond the burst size
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.throttle_at(0, later), Duration::ZERO);
+        assert_eq!(limiter.throttle_at(0, later), Duration::ZERO);
+        assert!(limiter.throttle_at(0, later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_bandwidth_debt() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(limit(&[("bw", "1M")]).unwrap());
+        // A request bigger than the bucket goes through, and the next waits
+        // for its debt to be repaid and the bucket to refill
+        assert_eq!(limiter.throttle_at(3 << 20, start), Duration::ZERO);
+        assert_eq!(limiter.throttle_at(1 << 20, start), Duration::from_secs(3));
+        // Waiting charges nothing
+        let later = start + Duration::from_secs(3);
+        assert_eq!(limiter.throttle_at(3 << 20, later), Duration::ZERO);
+        limiter.set(RateLimit::default());
+        assert_eq!(limiter.throttle_at(3 << 20, start), Duration::ZERO);
+        assert!(!limiter.limit().is_limited());
+    }
+
+    #[test]
+    fn test_retry_timer() {
+        let timer = Arc::new(RetryTimer::new().unwrap());
+        assert!(!timer.take());
+        timer.schedule(Duration::from_secs(60));
+        // A sooner deadline wins, a later one doesn't
+        timer.schedule(Duration::from_millis(1));
+        timer.schedule(Duration::from_secs(60));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(timer.take());
+        assert!(!timer.take());
+
+        // Changing the limits fires it at once
+        let limiter = RateLimiter::new(limit(&[("iops", "1")]).unwrap());
+        limiter.set_timer(timer.clone());
+        limiter.set(RateLimit::default());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(timer.take());
+    }
+}
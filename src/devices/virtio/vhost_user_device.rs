@@ -0,0 +1,410 @@
+//! Virtio devices whose data path lives in a vhost-user backend.
+//!
+//! [`VhostUserDevice`] is the guest-facing half of any such device. It
+//! emulates the virtio-mmio transport, forwards feature negotiation to the
+//! backend, and when the driver is ready hands the backend guest memory and
+//! the virtqueues (see [`super::vhost_user`]). Requests then flow between
+//! the guest and the backend without passing through Carbon, and the
+//! backend's used-buffer notifications go straight to the device's irqfd.
+//!
+//! Device types only differ in what the guest sees, which a
+//! [`VhostUserSpec`] describes: the device ID, the number of queues, and the
+//! config space. Config space is either built by Carbon (virtio-fs's mount
+//! tag) or read from the backend with `GET_CONFIG` (e.g. the capacity a
+//! vhost-user-blk backend reports). Backends that report their queue count
+//! (`GET_QUEUE_NUM`) get that many queues, so it matches what their config
+//! space tells the driver.
+//!
+//! The backend maps guest memory itself, so it must be shareable: VMs with
+//! vhost-user devices allocate it from a memfd.
+
+use crate::audit;
+use crate::boot::GuestMemory;
+use crate::devices::mmio::MmioDevice;
+use crate::kvm::IrqLine;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::vhost_user::{self, Frontend, MemoryRegion, VringAddrs};
+use super::{
+    Virtqueue, MAX_QUEUE_SIZE, MMIO_CONFIG, MMIO_DEVICE_FEATURES, MMIO_DEVICE_FEATURES_SEL,
+    MMIO_DEVICE_ID, MMIO_DRIVER_FEATURES, MMIO_DRIVER_FEATURES_SEL, MMIO_INTERRUPT_ACK,
+    MMIO_INTERRUPT_STATUS, MMIO_MAGIC_VALUE, MMIO_QUEUE_DESC_HIGH, MMIO_QUEUE_DESC_LOW,
+    MMIO_QUEUE_DEVICE_HIGH, MMIO_QUEUE_DEVICE_LOW, MMIO_QUEUE_DRIVER_HIGH, MMIO_QUEUE_DRIVER_LOW,
+    MMIO_QUEUE_NOTIFY, MMIO_QUEUE_NUM, MMIO_QUEUE_NUM_MAX, MMIO_QUEUE_READY, MMIO_QUEUE_SEL,
+    MMIO_STATUS, MMIO_VENDOR_ID, MMIO_VERSION, STATUS_DRIVER_OK, VIRTIO_MMIO_MAGIC,
+    VIRTIO_MMIO_VERSION, VIRTIO_VENDOR_ID,
+};
+
+/// Device status: something went wrong, the driver must reset the device.
+const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+
+/// Most queues a backend may ask for.
+const MAX_QUEUES: usize = 64;
+
+/// Where a device's config space comes from.
+#[derive(Debug, Clone)]
+pub enum ConfigSpace {
+    /// Built by Carbon.
+    Fixed(Vec<u8>),
+    /// Read from the backend (`GET_CONFIG`): this many bytes.
+    Backend(u32),
+}
+
+/// What a vhost-user device looks like to the guest.
+#[derive(Debug, Clone)]
+pub struct VhostUserSpec {
+    /// Name for log messages, e.g. `virtio-fs`.
+    pub name: &'static str,
+    /// Virtio device ID.
+    pub device_id: u32,
+    /// Number of virtqueues, or the minimum if the backend reports its own
+    /// count.
+    pub num_queues: usize,
+    /// Device config space.
+    pub config: ConfigSpace,
+}
+
+/// A virtio-mmio device backed by a vhost-user backend.
+pub struct VhostUserDevice {
+    name: &'static str,
+    device_id: u32,
+    config: Vec<u8>,
+    backend: Frontend,
+    /// Features offered to the driver.
+    device_features: u64,
+    /// The backend speaks vhost-user protocol features.
+    protocol_features: bool,
+    driver_features: u64,
+    features_sel: u32,
+
+    status: u32,
+
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    /// Per queue: eventfd to kick the backend.
+    kicks: Vec<File>,
+    /// The backend owns the queues.
+    started: bool,
+
+    /// Reference to guest memory, set via set_memory().
+    memory: Option<*const GuestMemory>,
+    /// Line to interrupt the guest on, set via set_interrupt(). The backend
+    /// signals it itself, as every queue's call eventfd.
+    irq: Option<Arc<dyn IrqLine>>,
+}
+
+// Safety: as for VirtioBlk, the GuestMemory pointer is only used during MMIO
+// operations on the vCPU thread.
+unsafe impl Send for VhostUserDevice {}
+
+impl VhostUserDevice {
+    /// Connect to the backend listening on `socket`, negotiate what `spec`
+    /// needs, and read the backend's features.
+    pub fn connect(socket: &Path, spec: VhostUserSpec) -> io::Result<Self> {
+        let mut backend = Frontend::connect(socket)?;
+        audit::record(
+            audit::Kind::Socket,
+            "connect",
+            &socket.display().to_string(),
+        );
+        let features = backend.get_features()?;
+        let protocol_features = features & vhost_user::VHOST_USER_F_PROTOCOL_FEATURES != 0;
+        let wanted = match spec.config {
+            ConfigSpace::Fixed(_) => 0,
+            ConfigSpace::Backend(_) => vhost_user::VHOST_USER_PROTOCOL_F_CONFIG,
+        };
+        let mut num_queues = spec.num_queues;
+        if protocol_features {
+            let offered = backend.get_protocol_features()?;
+            let used = offered & (wanted | vhost_user::VHOST_USER_PROTOCOL_F_MQ);
+            backend.set_protocol_features(used)?;
+            if offered & wanted != wanted {
+                return Err(io::Error::other(
+                    "backend can't report its config space (no CONFIG protocol feature)",
+                ));
+            }
+            if used & vhost_user::VHOST_USER_PROTOCOL_F_MQ != 0 {
+                let count = backend.get_queue_num()?;
+                num_queues = num_queues.max(count.min(MAX_QUEUES as u64) as usize);
+            }
+        } else if wanted != 0 {
+            return Err(io::Error::other(
+                "backend can't report its config space (no protocol features)",
+            ));
+        }
+        let config = match spec.config {
+            ConfigSpace::Fixed(config) => config,
+            ConfigSpace::Backend(size) => backend.get_config(size)?,
+        };
+
+        let kicks = (0..num_queues)
+            .map(|_| vhost_user::eventfd())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        info!(
+            "[{}] Backend at {} ({} queues)",
+            spec.name,
+            socket.display(),
+            num_queues
+        );
+        Ok(Self {
+            name: spec.name,
+            device_id: spec.device_id,
+            config,
+            backend,
+            device_features: features & !vhost_user::VHOST_USER_F_PROTOCOL_FEATURES,
+            protocol_features,
+            driver_features: 0,
+            features_sel: 0,
+            status: 0,
+            queue_sel: 0,
+            queues: new_queues(num_queues),
+            kicks,
+            started: false,
+            memory: None,
+            irq: None,
+        })
+    }
+
+    /// Set the guest memory reference for virtqueue processing.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the GuestMemory reference remains valid
+    /// for the lifetime of this device.
+    pub fn set_memory(&mut self, memory: &GuestMemory) {
+        self.memory = Some(memory as *const GuestMemory);
+    }
+
+    /// Set the line used-buffer notifications are raised on.
+    pub fn set_interrupt(&mut self, irq: Arc<dyn IrqLine>) {
+        self.irq = Some(irq);
+    }
+
+    /// Hand guest memory and the ready queues to the backend.
+    fn start(&mut self) -> io::Result<()> {
+        // SAFETY: see set_memory.
+        let memory = self
+            .memory
+            .map(|ptr| unsafe { &*ptr })
+            .ok_or_else(|| io::Error::other("guest memory not set"))?;
+        let file = memory
+            .shared_file()
+            .ok_or_else(|| io::Error::other("guest memory is not shareable"))?;
+        let irq = self
+            .irq
+            .as_ref()
+            .ok_or_else(|| io::Error::other("interrupt not set"))?;
+        // The backend interrupts the guest itself, through the irqfd
+        let irqfd = irq
+            .irqfd()
+            .ok_or_else(|| io::Error::other("vhost-user needs KVM irqfd support"))?;
+
+        let mut features = self.driver_features;
+        if self.protocol_features {
+            features |= vhost_user::VHOST_USER_F_PROTOCOL_FEATURES;
+        }
+        self.backend.set_features(features)?;
+        let regions: Vec<_> = memory
+            .regions()
+            .into_iter()
+            .map(|region| {
+                let region = MemoryRegion {
+                    guest_addr: region.guest_addr,
+                    size: region.size,
+                    user_addr: region.host_addr,
+                    mmap_offset: region.file_offset,
+                };
+                (region, file)
+            })
+            .collect();
+        self.backend.set_mem_table(&regions)?;
+
+        for (index, queue) in self.queues.iter().enumerate() {
+            if !queue.ready {
+                continue;
+            }
+            let index = index as u32;
+            self.backend.set_vring_num(index, queue.size.into())?;
+            self.backend.set_vring_base(index, 0)?;
+            let host_addr = |addr: u64| {
+                memory.host_address(addr).ok_or_else(|| {
+                    io::Error::other(format!("queue {index} at {addr:#x} isn't in RAM"))
+                })
+            };
+            self.backend.set_vring_addr(
+                index,
+                VringAddrs {
+                    desc: host_addr(queue.desc_table)?,
+                    used: host_addr(queue.used_ring)?,
+                    avail: host_addr(queue.avail_ring)?,
+                },
+            )?;
+            self.backend
+                .set_vring_kick(index, &self.kicks[index as usize])?;
+            self.backend.set_vring_call(index, &irqfd)?;
+            if self.protocol_features {
+                self.backend.set_vring_enable(index, true)?;
+            }
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    /// Stop the backend's queues on reset.
+    fn stop(&mut self) {
+        for (index, queue) in self.queues.iter().enumerate() {
+            if queue.ready {
+                if let Err(e) = self.backend.get_vring_base(index as u32) {
+                    warn!("[{}] Failed to stop queue {}: {}", self.name, index, e);
+                }
+            }
+        }
+        self.started = false;
+    }
+
+    /// Read a 32-bit register value.
+    fn read_register(&mut self, offset: u64) -> u32 {
+        match offset {
+            MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            MMIO_VERSION => VIRTIO_MMIO_VERSION,
+            MMIO_DEVICE_ID => self.device_id,
+            MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            MMIO_DEVICE_FEATURES => match self.features_sel {
+                0 => self.device_features as u32,
+                1 => (self.device_features >> 32) as u32,
+                _ => 0,
+            },
+            MMIO_QUEUE_NUM_MAX => self
+                .queues
+                .get(self.queue_sel as usize)
+                .map_or(0, |_| MAX_QUEUE_SIZE as u32),
+            MMIO_QUEUE_READY => self
+                .queues
+                .get(self.queue_sel as usize)
+                .map_or(0, |q| q.ready as u32),
+            // The backend raises the interrupt without telling us, so while
+            // it owns the queues any interrupt may be a used buffer
+            MMIO_INTERRUPT_STATUS => self.started as u32,
+            MMIO_STATUS => self.status,
+            _ if offset >= MMIO_CONFIG => {
+                let start = (offset - MMIO_CONFIG) as usize;
+                let mut bytes = [0u8; 4];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.config.get(start + i).copied().unwrap_or(0);
+                }
+                u32::from_le_bytes(bytes)
+            }
+            _ => {
+                warn!("[{}] Unknown register read: {:#x}", self.name, offset);
+                0
+            }
+        }
+    }
+
+    /// Write a 32-bit register value.
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let set_low = |addr: &mut u64| *addr = (*addr & 0xFFFF_FFFF_0000_0000) | value as u64;
+        let set_high =
+            |addr: &mut u64| *addr = (*addr & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+        match offset {
+            MMIO_DEVICE_FEATURES_SEL | MMIO_DRIVER_FEATURES_SEL => self.features_sel = value,
+            MMIO_DRIVER_FEATURES => match self.features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | value as u64,
+                1 => {
+                    self.driver_features =
+                        (self.driver_features & 0xFFFF_FFFF) | ((value as u64) << 32)
+                }
+                _ => {}
+            },
+            MMIO_QUEUE_SEL => self.queue_sel = value,
+            MMIO_QUEUE_NOTIFY => {
+                if let Some(mut kick) = self.kicks.get(value as usize) {
+                    let _ = kick.write(&1u64.to_ne_bytes());
+                }
+            }
+            MMIO_INTERRUPT_ACK => {}
+            MMIO_STATUS => {
+                if value == 0 {
+                    if self.started {
+                        self.stop();
+                    }
+                    self.queues = new_queues(self.queues.len());
+                    self.status = 0;
+                    debug!("[{}] Device reset", self.name);
+                    return;
+                }
+                let driver_ok =
+                    value & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0;
+                self.status = value;
+                debug!("[{}] Status: {:#x}", self.name, value);
+                if driver_ok {
+                    if let Err(e) = self.start() {
+                        warn!("[{}] Failed to start backend: {}", self.name, e);
+                        self.status |= STATUS_DEVICE_NEEDS_RESET;
+                    }
+                }
+            }
+            _ if offset >= MMIO_CONFIG => {
+                warn!(
+                    "[{}] Ignoring config write: {:#x} = {:#x}",
+                    self.name, offset, value
+                );
+            }
+            _ => {
+                let Some(queue) = self.queues.get_mut(self.queue_sel as usize) else {
+                    return;
+                };
+                match offset {
+                    MMIO_QUEUE_NUM if value <= MAX_QUEUE_SIZE as u32 => queue.size = value as u16,
+                    MMIO_QUEUE_READY => queue.ready = value != 0,
+                    MMIO_QUEUE_DESC_LOW => set_low(&mut queue.desc_table),
+                    MMIO_QUEUE_DESC_HIGH => set_high(&mut queue.desc_table),
+                    MMIO_QUEUE_DRIVER_LOW => set_low(&mut queue.avail_ring),
+                    MMIO_QUEUE_DRIVER_HIGH => set_high(&mut queue.avail_ring),
+                    MMIO_QUEUE_DEVICE_LOW => set_low(&mut queue.used_ring),
+                    MMIO_QUEUE_DEVICE_HIGH => set_high(&mut queue.used_ring),
+                    _ => warn!(
+                        "[{}] Unknown register write: {:#x} = {:#x}",
+                        self.name, offset, value
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl MmioDevice for VhostUserDevice {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = self.read_register(offset & !0x3); // Align to 4 bytes
+        let bytes = value.to_le_bytes();
+
+        // Handle sub-word reads
+        let start = (offset & 0x3) as usize;
+        let len = data.len().min(4 - start);
+        data[..len].copy_from_slice(&bytes[start..start + len]);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        // Only handle 4-byte aligned writes
+        if data.len() != 4 || offset & 0x3 != 0 {
+            warn!(
+                "[{}] Non-aligned write: offset={:#x} len={}",
+                self.name,
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        self.write_register(offset, value);
+    }
+}
+
+fn new_queues(count: usize) -> Vec<Virtqueue> {
+    (0..count).map(|_| Virtqueue::new()).collect()
+}
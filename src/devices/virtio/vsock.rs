@@ -0,0 +1,1076 @@
+//! Virtio socket device (virtio-vsock).
+//!
+//! Lets host processes talk to the guest over stream sockets without any
+//! guest networking. Host-side connections go through a Unix domain socket,
+//! following the Firecracker model:
+//!
+//! - **Host to guest**: connect to the `uds` socket and send
+//!   `CONNECT <port>\n`. Once the guest accepts on vsock port `<port>`,
+//!   Carbon answers `OK <host port>\n` and the stream carries the connection.
+//!   If the guest refuses, the stream is closed.
+//! - **Guest to host**: a guest connecting to CID 2 (the host), port `<port>`,
+//!   is connected to the Unix socket `<uds>_<port>`, which a host process
+//!   must be listening on.
+//!
+//! ```text
+//! host app ──► /tmp/v.sock ──► "CONNECT 52\n" ──► guest listener on port 52
+//! guest app ──► vsock CID 2, port 1234 ──► host listener on /tmp/v.sock_1234
+//! ```
+//!
+//! # Protocol
+//!
+//! Every packet starts with a 44-byte header (addresses, operation, and the
+//! sender's credit). Data flows in `RW` packets and is flow-controlled by
+//! credit: a sender may have at most `buf_alloc` bytes in flight that the
+//! receiver hasn't forwarded (`fwd_cnt`). Only stream sockets are supported.
+//!
+//! The device has three virtqueues: RX (device to guest), TX (guest to
+//! device) and events (unused). Guest packets are handled when the guest
+//! notifies the TX queue; host sockets are watched by a backend thread that
+//! fills RX buffers as data arrives.
+//!
+//! Reference: virtio spec 1.2, section 5.10
+
+use crate::audit;
+use crate::boot::GuestMemory;
+use crate::cleanup::{self, CleanupGuard};
+use crate::devices::mmio::MmioDevice;
+use crate::kvm::IrqLine;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+use super::{
+    Transport, VirtqDesc, Virtqueue, MAX_QUEUE_SIZE, MMIO_DEVICE_FEATURES,
+    MMIO_DEVICE_FEATURES_SEL, MMIO_DEVICE_ID, MMIO_DRIVER_FEATURES, MMIO_DRIVER_FEATURES_SEL,
+    MMIO_INTERRUPT_ACK, MMIO_INTERRUPT_STATUS, MMIO_MAGIC_VALUE, MMIO_QUEUE_DESC_HIGH,
+    MMIO_QUEUE_DESC_LOW, MMIO_QUEUE_DEVICE_HIGH, MMIO_QUEUE_DEVICE_LOW, MMIO_QUEUE_DRIVER_HIGH,
+    MMIO_QUEUE_DRIVER_LOW, MMIO_QUEUE_NOTIFY, MMIO_QUEUE_NUM, MMIO_QUEUE_NUM_MAX, MMIO_QUEUE_READY,
+    MMIO_QUEUE_SEL, MMIO_STATUS, MMIO_VENDOR_ID, MMIO_VERSION, VIRTIO_F_EVENT_IDX,
+    VIRTIO_MMIO_MAGIC, VIRTIO_MMIO_VERSION, VIRTIO_VENDOR_ID, VIRTQ_DESC_F_NEXT,
+    VIRTQ_DESC_F_WRITE,
+};
+
+/// Virtio device ID for socket devices.
+const VIRTIO_VSOCK_DEVICE_ID: u32 = 19;
+
+/// VIRTIO_F_VERSION_1 (bit 32, bit 0 of the high features word).
+const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+
+/// Well-known CID of the host.
+const HOST_CID: u64 = 2;
+
+/// Config space: the guest's CID (8 bytes).
+const CONFIG_GUEST_CID: u64 = 0x100;
+
+// Virtqueues
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const NUM_QUEUES: usize = 3;
+
+/// Size of the packet header.
+const HEADER_SIZE: usize = 44;
+
+/// Socket type: stream (the only one supported).
+const TYPE_STREAM: u16 = 1;
+
+// Operations
+const OP_REQUEST: u16 = 1;
+const OP_RESPONSE: u16 = 2;
+const OP_RST: u16 = 3;
+const OP_SHUTDOWN: u16 = 4;
+const OP_RW: u16 = 5;
+const OP_CREDIT_UPDATE: u16 = 6;
+const OP_CREDIT_REQUEST: u16 = 7;
+
+// SHUTDOWN flags
+const SHUTDOWN_RECV: u32 = 1;
+const SHUTDOWN_SEND: u32 = 2;
+
+/// Receive buffer we advertise per connection.
+const BUF_ALLOC: u32 = 256 * 1024;
+
+/// Largest payload in one RX packet.
+const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// First host-side port for host-initiated connections.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+/// Longest the backend thread sleeps before rechecking its sockets.
+const POLL_TIMEOUT_MS: i32 = 50;
+
+/// Longest `CONNECT` line a host client may send.
+const MAX_CONNECT_LINE: usize = 64;
+
+/// `--vsock cid=N,uds=PATH[,transport=pci]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VsockConfig {
+    /// The guest's context ID.
+    pub cid: u64,
+    /// Unix socket host processes connect through.
+    pub uds: PathBuf,
+    /// How the device is attached.
+    pub transport: Transport,
+}
+
+impl FromStr for VsockConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut cid, mut uds, mut transport) = (None, None, Transport::default());
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("cid", value)) => {
+                    let value: u64 = value
+                        .parse()
+                        .map_err(|_| format!("invalid vsock cid {value:?}"))?;
+                    // 0-2 are reserved, and the CID is 32 bits on the wire
+                    if !(3..u64::from(u32::MAX)).contains(&value) {
+                        return Err(format!("vsock cid {value} is reserved or too large"));
+                    }
+                    cid = Some(value);
+                }
+                Some(("uds", value)) if !value.is_empty() => uds = Some(PathBuf::from(value)),
+                Some(("transport", value)) => transport = value.parse()?,
+                _ => return Err(format!("invalid vsock option {option:?}")),
+            }
+        }
+        Ok(Self {
+            cid: cid.ok_or("vsock needs cid=N")?,
+            uds: uds.ok_or("vsock needs uds=PATH")?,
+            transport,
+        })
+    }
+}
+
+/// A packet header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(buf: &[u8; HEADER_SIZE]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.len.to_le_bytes());
+        buf[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        buf[30..32].copy_from_slice(&self.op.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        buf
+    }
+}
+
+/// Connection key: (host port, guest port).
+type Key = (u32, u32);
+
+/// A stream between a host Unix socket and a guest vsock port.
+struct Connection {
+    stream: UnixStream,
+    /// Host-initiated and waiting for the guest's RESPONSE.
+    connecting: bool,
+    /// The guest's receive buffer size, from its last packet.
+    peer_buf_alloc: u32,
+    /// Bytes the guest has consumed, from its last packet.
+    peer_fwd_cnt: u32,
+    /// Bytes sent to the guest.
+    tx_cnt: u32,
+    /// Bytes received from the guest and written to the host stream.
+    fwd_cnt: u32,
+    /// `fwd_cnt` last reported to the guest.
+    fwd_cnt_sent: u32,
+    /// Guest data not yet written to the host stream.
+    to_host: Vec<u8>,
+    /// The host stream reached EOF.
+    host_eof: bool,
+    /// The guest won't send more data.
+    guest_done: bool,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, connecting: bool) -> Self {
+        Self {
+            stream,
+            connecting,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+            to_host: Vec::new(),
+            host_eof: false,
+            guest_done: false,
+        }
+    }
+
+    /// Bytes the guest can still accept.
+    fn credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    /// Write buffered guest data to the host stream.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.to_host.is_empty() {
+            match self.stream.write(&self.to_host) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.to_host.drain(..n);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(n as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.guest_done && self.to_host.is_empty() {
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+}
+
+/// A host client that hasn't sent its `CONNECT` line yet.
+struct PendingClient {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+/// Guest memory, shared with the backend thread.
+#[derive(Clone, Copy)]
+struct MemoryRef(*const GuestMemory);
+
+// Safety: GuestMemory is only accessed through shared references, and the
+// device joins its backend thread before the memory can be dropped.
+unsafe impl Send for MemoryRef {}
+
+/// Device state, shared by the vCPU (MMIO) and the backend thread.
+struct Inner {
+    cid: u64,
+    uds: PathBuf,
+
+    /// Driver-selected features (low, high).
+    driver_features: [u32; 2],
+    features_sel: u32,
+    status: u32,
+    interrupt_status: u32,
+    queue_sel: u32,
+    queues: [Virtqueue; NUM_QUEUES],
+    memory: Option<MemoryRef>,
+    irq: Option<Arc<dyn IrqLine>>,
+
+    listener: UnixListener,
+    pending: Vec<PendingClient>,
+    connections: HashMap<Key, Connection>,
+    /// Control packets waiting for an RX buffer.
+    control: VecDeque<Header>,
+    next_host_port: u32,
+}
+
+impl Inner {
+    fn memory(&self) -> Option<&'static GuestMemory> {
+        // SAFETY: see MemoryRef.
+        self.memory.map(|m| unsafe { &*m.0 })
+    }
+
+    fn reset(&mut self) {
+        self.queues = Default::default();
+        self.interrupt_status = 0;
+        self.connections.clear();
+        self.control.clear();
+    }
+
+    /// A header from the host side of `key` to the guest.
+    fn header(&self, key: Key, op: u16) -> Header {
+        Header {
+            src_cid: HOST_CID,
+            dst_cid: self.cid,
+            src_port: key.0,
+            dst_port: key.1,
+            type_: TYPE_STREAM,
+            op,
+            buf_alloc: BUF_ALLOC,
+            ..Header::default()
+        }
+    }
+
+    /// Queue a control packet for the guest.
+    fn send_control(&mut self, key: Key, op: u16, flags: u32) {
+        let mut header = self.header(key, op);
+        header.flags = flags;
+        self.control.push_back(header);
+    }
+
+    /// Handle every packet the guest has queued on TX.
+    fn process_tx(&mut self) {
+        let Some(memory) = self.memory() else {
+            return;
+        };
+        while let Some(head) = self.queues[TX_QUEUE].pop_avail(memory) {
+            match read_packet(memory, &self.queues[TX_QUEUE], head) {
+                Some((header, data)) => self.handle_packet(header, data),
+                None => warn!("[virtio-vsock] Malformed TX packet"),
+            }
+            if self.queues[TX_QUEUE].push_used(memory, head, 0).is_none() {
+                warn!("[virtio-vsock] Failed to push to used ring");
+            }
+        }
+        if self.queues[TX_QUEUE].needs_interrupt(memory) {
+            self.interrupt_status |= 1;
+            if let Some(irq) = &self.irq {
+                irq.trigger();
+            }
+        }
+    }
+
+    /// Act on one packet from the guest.
+    fn handle_packet(&mut self, header: Header, data: Vec<u8>) {
+        let key = (header.dst_port, header.src_port);
+        if header.dst_cid != HOST_CID || header.type_ != TYPE_STREAM {
+            if header.op != OP_RST {
+                self.send_control(key, OP_RST, 0);
+            }
+            return;
+        }
+        if let Some(conn) = self.connections.get_mut(&key) {
+            conn.peer_buf_alloc = header.buf_alloc;
+            conn.peer_fwd_cnt = header.fwd_cnt;
+        }
+
+        match header.op {
+            OP_REQUEST if !self.connections.contains_key(&key) => self.connect_host(key),
+            OP_RESPONSE => match self.connections.get_mut(&key) {
+                Some(conn) if conn.connecting => {
+                    conn.connecting = false;
+                    let reply = format!("OK {}\n", key.0);
+                    if conn.stream.write_all(reply.as_bytes()).is_err() {
+                        self.reset_connection(key);
+                    }
+                }
+                _ => self.send_control(key, OP_RST, 0),
+            },
+            OP_RW => match self.connections.get_mut(&key) {
+                Some(conn) if !conn.connecting => {
+                    conn.to_host.extend_from_slice(&data);
+                    if conn.flush().is_err() {
+                        self.reset_connection(key);
+                    }
+                }
+                _ => self.send_control(key, OP_RST, 0),
+            },
+            OP_SHUTDOWN => {
+                if header.flags & (SHUTDOWN_RECV | SHUTDOWN_SEND) == SHUTDOWN_RECV | SHUTDOWN_SEND {
+                    self.reset_connection(key);
+                } else if let Some(conn) = self.connections.get_mut(&key) {
+                    conn.guest_done |= header.flags & SHUTDOWN_SEND != 0;
+                    let _ = conn.flush();
+                }
+            }
+            OP_RST => {
+                self.connections.remove(&key);
+            }
+            OP_CREDIT_UPDATE => {}
+            OP_CREDIT_REQUEST => self.send_control(key, OP_CREDIT_UPDATE, 0),
+            _ => self.send_control(key, OP_RST, 0),
+        }
+    }
+
+    /// Guest connected to host port `key.0`: connect to `<uds>_<port>`.
+    fn connect_host(&mut self, key: Key) {
+        let mut path = self.uds.clone().into_os_string();
+        path.push(format!("_{}", key.0));
+        match UnixStream::connect(&path).and_then(|s| s.set_nonblocking(true).map(|()| s)) {
+            Ok(stream) => {
+                audit::record(
+                    audit::Kind::Socket,
+                    "connect",
+                    &Path::new(&path).display().to_string(),
+                );
+                self.connections.insert(key, Connection::new(stream, false));
+                self.send_control(key, OP_RESPONSE, 0);
+            }
+            Err(e) => {
+                debug!(
+                    "[virtio-vsock] Guest connection to port {} refused: {}",
+                    key.0, e
+                );
+                self.send_control(key, OP_RST, 0);
+            }
+        }
+    }
+
+    /// Drop a connection and tell the guest.
+    fn reset_connection(&mut self, key: Key) {
+        self.connections.remove(&key);
+        self.send_control(key, OP_RST, 0);
+    }
+
+    /// Accept host clients, read their `CONNECT` lines, and move guest data
+    /// to the host.
+    fn poll_host(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.pending.push(PendingClient {
+                    stream,
+                    line: Vec::new(),
+                });
+            }
+        }
+
+        let mut still_pending = Vec::new();
+        for mut client in std::mem::take(&mut self.pending) {
+            match read_connect_line(&mut client) {
+                Ok(Some(port)) => {
+                    let key = (self.next_host_port, port);
+                    self.next_host_port = self.next_host_port.wrapping_add(1).max(FIRST_HOST_PORT);
+                    self.connections
+                        .insert(key, Connection::new(client.stream, true));
+                    self.send_control(key, OP_REQUEST, 0);
+                }
+                Ok(None) => still_pending.push(client),
+                Err(e) => debug!("[virtio-vsock] Dropping host client: {}", e),
+            }
+        }
+        self.pending = still_pending;
+
+        let mut failed = Vec::new();
+        let mut credit_updates = Vec::new();
+        for (&key, conn) in &mut self.connections {
+            if conn.flush().is_err() {
+                failed.push(key);
+            } else if conn.fwd_cnt.wrapping_sub(conn.fwd_cnt_sent) >= BUF_ALLOC / 2 {
+                // Tell a guest that is only sending that it has room again
+                conn.fwd_cnt_sent = conn.fwd_cnt;
+                credit_updates.push(key);
+            }
+        }
+        for key in failed {
+            self.reset_connection(key);
+        }
+        for key in credit_updates {
+            self.send_control(key, OP_CREDIT_UPDATE, 0);
+        }
+    }
+
+    /// Fill RX buffers with control packets and host data.
+    fn process_rx(&mut self) {
+        let Some(memory) = self.memory() else {
+            return;
+        };
+        while let Some(head) = self.queues[RX_QUEUE].pop_avail(memory) {
+            let Some(buffers) = writable_buffers(memory, &self.queues[RX_QUEUE], head) else {
+                warn!("[virtio-vsock] Malformed RX buffer");
+                continue;
+            };
+            let capacity: usize = buffers.iter().map(|d| d.len as usize).sum();
+            let Some((mut header, data)) =
+                self.next_rx_packet(capacity.saturating_sub(HEADER_SIZE))
+            else {
+                // Nothing to send: leave the buffer for later
+                self.queues[RX_QUEUE].undo_pop(memory);
+                break;
+            };
+            if let Some(conn) = self
+                .connections
+                .get_mut(&(header.src_port, header.dst_port))
+            {
+                header.fwd_cnt = conn.fwd_cnt;
+                conn.fwd_cnt_sent = conn.fwd_cnt;
+            }
+            header.len = data.len() as u32;
+
+            let mut packet = header.to_bytes().to_vec();
+            packet.extend_from_slice(&data);
+            let mut written = 0;
+            for desc in &buffers {
+                let len = (desc.len as usize).min(packet.len() - written);
+                if memory
+                    .write(desc.addr, &packet[written..written + len])
+                    .is_err()
+                {
+                    warn!("[virtio-vsock] Failed to write to guest memory");
+                    break;
+                }
+                written += len;
+            }
+            if self.queues[RX_QUEUE]
+                .push_used(memory, head, written as u32)
+                .is_none()
+            {
+                warn!("[virtio-vsock] Failed to push to used ring");
+            }
+        }
+        if self.queues[RX_QUEUE].needs_interrupt(memory) {
+            self.interrupt_status |= 1;
+            if let Some(irq) = &self.irq {
+                irq.trigger();
+            }
+        }
+    }
+
+    /// The next packet for the guest, with at most `max` bytes of data.
+    fn next_rx_packet(&mut self, max: usize) -> Option<(Header, Vec<u8>)> {
+        if let Some(header) = self.control.pop_front() {
+            return Some((header, Vec::new()));
+        }
+
+        let keys: Vec<Key> = self.connections.keys().copied().collect();
+        for key in keys {
+            let conn = self.connections.get_mut(&key)?;
+            if conn.connecting || conn.host_eof {
+                continue;
+            }
+            let len = (conn.credit() as usize).min(max).min(MAX_PAYLOAD);
+            if len == 0 {
+                continue;
+            }
+            let mut data = vec![0u8; len];
+            match conn.stream.read(&mut data) {
+                Ok(0) => {
+                    conn.host_eof = true;
+                    let mut header = self.header(key, OP_SHUTDOWN);
+                    header.flags = SHUTDOWN_RECV | SHUTDOWN_SEND;
+                    return Some((header, Vec::new()));
+                }
+                Ok(n) => {
+                    conn.tx_cnt = conn.tx_cnt.wrapping_add(n as u32);
+                    data.truncate(n);
+                    return Some((self.header(key, OP_RW), data));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => {
+                    self.connections.remove(&key);
+                    return Some((self.header(key, OP_RST), Vec::new()));
+                }
+            }
+        }
+        None
+    }
+
+    /// File descriptors the backend thread should wait on.
+    fn poll_fds(&self) -> Vec<libc::pollfd> {
+        let pollfd = |fd, events| libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        };
+        let mut fds = vec![pollfd(self.listener.as_raw_fd(), libc::POLLIN)];
+        fds.extend(
+            self.pending
+                .iter()
+                .map(|c| pollfd(c.stream.as_raw_fd(), libc::POLLIN)),
+        );
+        let rx_ready = self
+            .memory()
+            .is_some_and(|m| self.queues[RX_QUEUE].has_pending(m));
+        for conn in self.connections.values() {
+            let mut events = 0;
+            // Only wait for host data the guest has room for
+            if rx_ready && !conn.connecting && !conn.host_eof && conn.credit() > 0 {
+                events |= libc::POLLIN;
+            }
+            if !conn.to_host.is_empty() {
+                events |= libc::POLLOUT;
+            }
+            if events != 0 {
+                fds.push(pollfd(conn.stream.as_raw_fd(), events));
+            }
+        }
+        fds
+    }
+}
+
+/// Read the header and payload of the TX packet at `head`.
+fn read_packet(memory: &GuestMemory, queue: &Virtqueue, head: u16) -> Option<(Header, Vec<u8>)> {
+    let mut bytes = Vec::new();
+    let mut idx = head;
+    for _ in 0..queue.size {
+        let desc = queue.read_desc(memory, idx)?;
+        if desc.flags & VIRTQ_DESC_F_WRITE == 0 {
+            let start = bytes.len();
+            bytes.resize(start + desc.len as usize, 0);
+            memory.read(desc.addr, &mut bytes[start..]).ok()?;
+        }
+        if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+            break;
+        }
+        idx = desc.next;
+    }
+    let header = Header::parse(bytes.get(..HEADER_SIZE)?.try_into().ok()?);
+    let data = bytes.get(HEADER_SIZE..HEADER_SIZE + header.len as usize)?;
+    Some((header, data.to_vec()))
+}
+
+/// The device-writable descriptors of the RX chain at `head`.
+fn writable_buffers(memory: &GuestMemory, queue: &Virtqueue, head: u16) -> Option<Vec<VirtqDesc>> {
+    let mut descs = Vec::new();
+    let mut idx = head;
+    for _ in 0..queue.size {
+        let desc = queue.read_desc(memory, idx)?;
+        if desc.flags & VIRTQ_DESC_F_WRITE != 0 {
+            descs.push(desc);
+        }
+        if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+            break;
+        }
+        idx = desc.next;
+    }
+    let capacity: usize = descs.iter().map(|d| d.len as usize).sum();
+    (capacity >= HEADER_SIZE).then_some(descs)
+}
+
+/// Read from a host client until its `CONNECT <port>` line is complete.
+fn read_connect_line(client: &mut PendingClient) -> io::Result<Option<u32>> {
+    let mut byte = [0u8; 1];
+    loop {
+        match client.stream.read(&mut byte) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) if client.line.len() < MAX_CONNECT_LINE => client.line.push(byte[0]),
+            Ok(_) => return Err(io::Error::other("CONNECT line too long")),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+    parse_connect(&client.line)
+        .map(Some)
+        .ok_or_else(|| io::Error::other("expected CONNECT <port>"))
+}
+
+fn parse_connect(line: &[u8]) -> Option<u32> {
+    let line = std::str::from_utf8(line).ok()?.trim_end_matches('\r');
+    line.strip_prefix("CONNECT ")?.trim().parse().ok()
+}
+
+/// Virtio socket device.
+pub struct VirtioVsock {
+    inner: Arc<Mutex<Inner>>,
+    stop: Arc<AtomicBool>,
+    backend: Option<JoinHandle<()>>,
+    /// Removes the Unix socket on drop or panic.
+    _cleanup: CleanupGuard,
+}
+
+impl VirtioVsock {
+    /// Create the device and listen for host clients on `config.uds`.
+    ///
+    /// A stale socket file at the path is replaced.
+    pub fn new(config: &VsockConfig) -> io::Result<Self> {
+        match std::fs::remove_file(&config.uds) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(&config.uds)?;
+        listener.set_nonblocking(true)?;
+        audit::record(
+            audit::Kind::Socket,
+            "bind",
+            &config.uds.display().to_string(),
+        );
+        let socket_path = config.uds.clone();
+        let cleanup = cleanup::register("remove vsock socket", move || {
+            let _ = std::fs::remove_file(&socket_path);
+            audit::record(
+                audit::Kind::Socket,
+                "remove",
+                &socket_path.display().to_string(),
+            );
+        });
+
+        info!(
+            "[virtio-vsock] Guest CID {}, host socket {}",
+            config.cid,
+            config.uds.display()
+        );
+
+        let inner = Arc::new(Mutex::new(Inner {
+            cid: config.cid,
+            uds: config.uds.clone(),
+            driver_features: [0; 2],
+            features_sel: 0,
+            status: 0,
+            interrupt_status: 0,
+            queue_sel: 0,
+            queues: Default::default(),
+            memory: None,
+            irq: None,
+            listener,
+            pending: Vec::new(),
+            connections: HashMap::new(),
+            control: VecDeque::new(),
+            next_host_port: FIRST_HOST_PORT,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let backend = std::thread::Builder::new().name("vsock".into()).spawn({
+            let inner = inner.clone();
+            let stop = stop.clone();
+            move || run_backend(&inner, &stop)
+        })?;
+
+        Ok(Self {
+            inner,
+            stop,
+            backend: Some(backend),
+            _cleanup: cleanup,
+        })
+    }
+
+    /// Set the guest memory reference for virtqueue processing.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the GuestMemory reference remains valid
+    /// for the lifetime of this device.
+    pub fn set_memory(&mut self, memory: &GuestMemory) {
+        lock(&self.inner).memory = Some(MemoryRef(memory as *const GuestMemory));
+    }
+
+    /// Set the line used-buffer notifications are raised on. The backend
+    /// thread raises it too, when host data lands in the RX queue.
+    pub fn set_interrupt(&mut self, irq: Arc<dyn IrqLine>) {
+        lock(&self.inner).irq = Some(irq);
+    }
+}
+
+impl Drop for VirtioVsock {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(backend) = self.backend.take() {
+            let _ = backend.join();
+        }
+    }
+}
+
+fn lock(inner: &Mutex<Inner>) -> MutexGuard<'_, Inner> {
+    inner.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Wait for host socket activity and move data between sockets and queues.
+fn run_backend(inner: &Mutex<Inner>, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds = lock(inner).poll_fds();
+        // SAFETY: fds is a valid array of pollfd of the given length.
+        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, POLL_TIMEOUT_MS) };
+
+        let mut inner = lock(inner);
+        inner.poll_host();
+        inner.process_rx();
+    }
+}
+
+impl Inner {
+    /// Read a 32-bit register value.
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            MMIO_VERSION => VIRTIO_MMIO_VERSION,
+            MMIO_DEVICE_ID => VIRTIO_VSOCK_DEVICE_ID,
+            MMIO_VENDOR_ID => VIRTIO_VENDOR_ID,
+            MMIO_DEVICE_FEATURES => match self.features_sel {
+                0 => VIRTIO_F_EVENT_IDX,
+                1 => VIRTIO_F_VERSION_1,
+                _ => 0,
+            },
+            MMIO_QUEUE_NUM_MAX => self.selected_queue().map_or(0, |_| MAX_QUEUE_SIZE as u32),
+            MMIO_QUEUE_READY => self.selected_queue().map_or(0, |q| q.ready as u32),
+            MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            MMIO_STATUS => self.status,
+            CONFIG_GUEST_CID => self.cid as u32,
+            0x104 => (self.cid >> 32) as u32,
+            _ => {
+                warn!("[virtio-vsock] Unknown register read: {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn selected_queue(&self) -> Option<&Virtqueue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    /// Write a 32-bit register value.
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let sel = self.queue_sel as usize;
+        let event_idx = self.driver_features[0] & VIRTIO_F_EVENT_IDX != 0;
+        let set_low = |addr: &mut u64| *addr = (*addr & 0xFFFF_FFFF_0000_0000) | value as u64;
+        let set_high =
+            |addr: &mut u64| *addr = (*addr & 0x0000_0000_FFFF_FFFF) | ((value as u64) << 32);
+        match offset {
+            MMIO_DEVICE_FEATURES_SEL | MMIO_DRIVER_FEATURES_SEL => self.features_sel = value,
+            MMIO_DRIVER_FEATURES => {
+                if let Some(features) = self.driver_features.get_mut(self.features_sel as usize) {
+                    *features = value;
+                }
+            }
+            MMIO_QUEUE_SEL => self.queue_sel = value,
+            MMIO_QUEUE_NOTIFY => match value as usize {
+                TX_QUEUE => {
+                    self.process_tx();
+                    // Replies (RESPONSE, RST, ...) go out on RX
+                    self.process_rx();
+                }
+                RX_QUEUE => self.process_rx(),
+                _ => {}
+            },
+            MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            MMIO_STATUS => {
+                self.status = value;
+                if value == 0 {
+                    self.reset();
+                    debug!("[virtio-vsock] Device reset");
+                } else {
+                    debug!("[virtio-vsock] Status: {:#x}", value);
+                }
+            }
+            _ => {
+                let Some(queue) = self.queues.get_mut(sel) else {
+                    return;
+                };
+                match offset {
+                    MMIO_QUEUE_NUM if value <= MAX_QUEUE_SIZE as u32 => queue.size = value as u16,
+                    MMIO_QUEUE_READY => {
+                        queue.ready = value != 0;
+                        queue.event_idx = event_idx;
+                    }
+                    MMIO_QUEUE_DESC_LOW => set_low(&mut queue.desc_table),
+                    MMIO_QUEUE_DESC_HIGH => set_high(&mut queue.desc_table),
+                    MMIO_QUEUE_DRIVER_LOW => set_low(&mut queue.avail_ring),
+                    MMIO_QUEUE_DRIVER_HIGH => set_high(&mut queue.avail_ring),
+                    MMIO_QUEUE_DEVICE_LOW => set_low(&mut queue.used_ring),
+                    MMIO_QUEUE_DEVICE_HIGH => set_high(&mut queue.used_ring),
+                    _ => warn!(
+                        "[virtio-vsock] Unknown register write: {:#x} = {:#x}",
+                        offset, value
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl MmioDevice for VirtioVsock {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = lock(&self.inner).read_register(offset & !0x3); // Align to 4 bytes
+        let bytes = value.to_le_bytes();
+
+        // Handle sub-word reads
+        let start = (offset & 0x3) as usize;
+        let len = data.len().min(4 - start);
+        data[..len].copy_from_slice(&bytes[start..start + len]);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        // Only handle 4-byte aligned writes
+        if data.len() != 4 || offset & 0x3 != 0 {
+            warn!(
+                "[virtio-vsock] Non-aligned write: offset={:#x} len={}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        lock(&self.inner).write_register(offset, value);
+    }
+
+    fn notify_queues(&self) -> u32 {
+        NUM_QUEUES as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            "cid=3,uds=/tmp/v.sock".parse(),
+            Ok(VsockConfig {
+                cid: 3,
+                uds: PathBuf::from("/tmp/v.sock"),
+                transport: Transport::Mmio,
+            })
+        );
+        assert_eq!(
+            "cid=3,uds=/tmp/v.sock,transport=pci"
+                .parse::<VsockConfig>()
+                .map(|config| config.transport),
+            Ok(Transport::Pci)
+        );
+        assert!("cid=2,uds=/tmp/v.sock".parse::<VsockConfig>().is_err());
+        assert!("cid=3".parse::<VsockConfig>().is_err());
+        assert!("cid=3,uds=/tmp/v.sock,mtu=9000"
+            .parse::<VsockConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = Header {
+            src_cid: 3,
+            dst_cid: HOST_CID,
+            src_port: 1234,
+            dst_port: 52,
+            len: 5,
+            type_: TYPE_STREAM,
+            op: OP_RW,
+            flags: 0,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt: 17,
+        };
+        assert_eq!(Header::parse(&header.to_bytes()), header);
+        assert_eq!(&header.to_bytes()[16..20], &1234u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_parse_connect() {
+        assert_eq!(parse_connect(b"CONNECT 52"), Some(52));
+        assert_eq!(parse_connect(b"CONNECT 52\r"), Some(52));
+        assert_eq!(parse_connect(b"connect 52"), None);
+        assert_eq!(parse_connect(b"CONNECT x"), None);
+    }
+
+    /// Point `queue` at rings in `memory` and offer one buffer.
+    fn offer(memory: &GuestMemory, queue: &mut Virtqueue, base: u64, buf: u64, flags: u16) {
+        *queue = Virtqueue {
+            size: 8,
+            ready: true,
+            desc_table: base,
+            avail_ring: base + 0x100,
+            used_ring: base + 0x200,
+            ..Virtqueue::default()
+        };
+        memory.write_u64(base, buf).unwrap();
+        memory.write_u32(base + 8, 4096).unwrap();
+        memory.write(base + 12, &flags.to_le_bytes()).unwrap();
+        memory.write(base + 0x100, &[0, 0, 1, 0, 0, 0]).unwrap();
+    }
+
+    #[test]
+    fn test_host_connect_handshake() {
+        let memory = GuestMemory::new(1 << 20).unwrap();
+        let uds = std::env::temp_dir().join(format!("carbon-vsock-{}", std::process::id()));
+        let mut device = VirtioVsock::new(&VsockConfig {
+            cid: 3,
+            uds: uds.clone(),
+            transport: Transport::Mmio,
+        })
+        .unwrap();
+        device.set_memory(&memory);
+        {
+            let mut inner = lock(&device.inner);
+            offer(
+                &memory,
+                &mut inner.queues[RX_QUEUE],
+                0x1000,
+                0x10000,
+                VIRTQ_DESC_F_WRITE,
+            );
+        }
+
+        // The host asks for guest port 52; the guest sees a REQUEST
+        let mut client = UnixStream::connect(&uds).unwrap();
+        client.write_all(b"CONNECT 52\n").unwrap();
+        let mut used = [0u8; 2];
+        for _ in 0..200 {
+            memory.read(0x1000 + 0x202, &mut used).unwrap();
+            if used == [1, 0] {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let mut buf = [0u8; HEADER_SIZE];
+        memory.read(0x10000, &mut buf).unwrap();
+        let request = Header::parse(&buf);
+        assert_eq!(
+            (
+                request.op,
+                request.src_cid,
+                request.dst_cid,
+                request.dst_port
+            ),
+            (OP_REQUEST, HOST_CID, 3, 52)
+        );
+
+        // The guest accepts; the host is told its port
+        let response = Header {
+            src_cid: 3,
+            dst_cid: HOST_CID,
+            src_port: 52,
+            dst_port: request.src_port,
+            type_: TYPE_STREAM,
+            op: OP_RESPONSE,
+            buf_alloc: 4096,
+            ..Header::default()
+        };
+        memory.write(0x20000, &response.to_bytes()).unwrap();
+        {
+            let mut inner = lock(&device.inner);
+            offer(&memory, &mut inner.queues[TX_QUEUE], 0x4000, 0x20000, 0);
+            memory.write_u32(0x4000 + 8, HEADER_SIZE as u32).unwrap();
+        }
+        device.write(MMIO_QUEUE_NOTIFY, &(TX_QUEUE as u32).to_le_bytes());
+
+        let mut reply = String::new();
+        let mut byte = [0u8; 1];
+        while client.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {
+            reply.push(byte[0] as char);
+        }
+        assert_eq!(reply, format!("OK {}", request.src_port));
+        drop(device);
+        assert!(!uds.exists());
+    }
+
+    #[test]
+    fn test_credit() {
+        let (stream, _) = UnixStream::pair().unwrap();
+        let mut conn = Connection::new(stream, false);
+        conn.peer_buf_alloc = 1000;
+        conn.tx_cnt = 600;
+        conn.peer_fwd_cnt = 100;
+        assert_eq!(conn.credit(), 500);
+        conn.tx_cnt = 1200;
+        assert_eq!(conn.credit(), 0);
+    }
+}
@@ -0,0 +1,112 @@
+//! SHA-256 digests pinning the artifacts a VM boots.
+//!
+//! `--kernel-sha256`, `--rootfs-sha256` and the `sha256=` disk option name
+//! the digest an artifact must have; Carbon hashes it before boot and
+//! refuses to start on a mismatch. A digest is written as 64 hex digits,
+//! optionally prefixed with `sha256:` as OCI digests are.
+//!
+//! A kernel is hashed as mapped for loading (see
+//! [`crate::boot::KernelImage`]), and a disk through the descriptor the
+//! device then uses, so what was checked is what the guest gets. A disk is
+//! hashed as stored, ciphertext for an encrypted one, and the digest only
+//! pins its contents at boot: a writable disk changes as the guest writes.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest as _, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// A SHA-256 digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sha256Digest([u8; 32]);
+
+impl Sha256Digest {
+    /// The digest of `data`.
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+
+/// Hashes data fed to it in pieces.
+#[derive(Default)]
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    /// Hash `data`, following what was hashed before.
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// The digest of everything hashed.
+    pub fn finish(self) -> Sha256Digest {
+        Sha256Digest(self.0.finalize().into())
+    }
+}
+
+impl FromStr for Sha256Digest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix("sha256:").unwrap_or(s);
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!(
+                "invalid SHA-256 digest {s:?} (expected 64 hex digits)"
+            ));
+        }
+        let mut digest = [0u8; 32];
+        for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+            // Checked above: two hex digits
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap();
+        }
+        Ok(Self(digest))
+    }
+}
+
+impl fmt::Display for Sha256Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sha256:")?;
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl Serialize for Sha256Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Sha256Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_digest() {
+        let abc = Sha256Digest::of(b"abc");
+        assert_eq!(abc.to_string(), format!("sha256:{ABC}"));
+        assert_eq!(ABC.parse::<Sha256Digest>().unwrap(), abc);
+        assert_eq!(
+            format!("sha256:{ABC}").parse::<Sha256Digest>().unwrap(),
+            abc
+        );
+        assert_eq!(ABC.to_uppercase().parse::<Sha256Digest>().unwrap(), abc);
+
+        let mut hasher = Hasher::default();
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(hasher.finish(), abc);
+
+        assert!(ABC[1..].parse::<Sha256Digest>().is_err());
+        assert!(format!("{}g", &ABC[1..]).parse::<Sha256Digest>().is_err());
+        assert!(format!("sha512:{ABC}").parse::<Sha256Digest>().is_err());
+    }
+}
@@ -0,0 +1,401 @@
+//! Top-level error type and process exit codes.
+//!
+//! Every failure that reaches `main` is a [`CarbonError`]. Errors from the
+//! boot, KVM, device and config modules convert into it with `?`, keeping the
+//! original error as the `source` so the full chain can be printed:
+//!
+//! ```text
+//! error: boot setup failed
+//!   caused by: Failed to read kernel bin/vmlinuz: No such file or directory (os error 2)
+//! ```
+//!
+//! # Exit Codes
+//!
+//! | Code  | Meaning                                                        |
+//! | ----- | -------------------------------------------------------------- |
+//! | 0     | The guest ran and stopped normally                             |
+//! | 2     | Invalid command-line usage (reported by clap)                  |
+//! | 3     | Configuration error: bad profile, missing or unreadable inputs |
+//! | 4     | Host capability error: KVM missing, denied or failing          |
+//! | 5     | Guest failure: the guest crashed or never reached init         |
+//! | 6     | Guest kernel panic, reported by the guest over pvpanic         |
+//! | 7     | Boot timeout: the guest didn't reach init in `--boot-timeout`  |
+//! | 8     | Maximum runtime: the VM was still running at `--max-runtime`   |
+//! | 128+N | Stopped by signal N (SIGINT, SIGTERM or SIGHUP)                |
+//!
+//! The guest can also pick the exit code itself, printing an exit status
+//! line on the console (see `vmm`) or with `--debug-exit` (see
+//! `devices::debug_exit`).
+
+#[cfg(target_os = "linux")]
+use crate::boot::BootError;
+use crate::config::ConfigError;
+#[cfg(target_os = "linux")]
+use crate::config_file::ConfigFileError;
+#[cfg(target_os = "linux")]
+use crate::kvm::KvmError;
+use std::error::Error;
+use thiserror::Error;
+
+/// Exit code for configuration errors.
+pub const EXIT_CONFIG: u8 = 3;
+
+/// Exit code for missing or failing host capabilities (KVM).
+pub const EXIT_HOST: u8 = 4;
+
+/// Exit code for guest failures.
+pub const EXIT_GUEST: u8 = 5;
+
+/// Exit code for a guest kernel panic.
+pub const EXIT_GUEST_PANIC: u8 = 6;
+
+/// Exit code for a guest that didn't boot within its boot timeout.
+pub const EXIT_BOOT_TIMEOUT: u8 = 7;
+
+/// Exit code for a VM stopped at its maximum runtime.
+pub const EXIT_MAX_RUNTIME: u8 = 8;
+
+/// Any error that stops Carbon.
+#[derive(Error, Debug)]
+pub enum CarbonError {
+    /// Invalid or incomplete configuration.
+    #[error("{0}")]
+    Config(String),
+
+    /// A profile couldn't be found or parsed.
+    #[error("failed to load profile")]
+    Profile(#[from] ConfigError),
+
+    /// KVM is unavailable or an ioctl failed.
+    #[cfg(target_os = "linux")]
+    #[error("KVM operation failed")]
+    Kvm(#[from] KvmError),
+
+    /// Loading the kernel or building boot structures failed.
+    #[cfg(target_os = "linux")]
+    #[error("boot setup failed")]
+    Boot(#[from] BootError),
+
+    /// The disk image couldn't be opened.
+    #[error("failed to open disk image {path}")]
+    Disk {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A `--config` file couldn't be read, or describes no valid VM.
+    #[cfg(target_os = "linux")]
+    #[error("failed to load config file {path}")]
+    ConfigFile {
+        path: String,
+        #[source]
+        source: ConfigFileError,
+    },
+
+    /// The audit log couldn't be opened.
+    #[error("failed to open audit log {path}")]
+    AuditLog {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A device plugin couldn't be started.
+    #[error("failed to start device plugin {path}")]
+    DevicePlugin {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The vsock device's host socket couldn't be set up.
+    #[error("failed to listen on vsock socket {path}")]
+    Vsock {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A shared directory's vhost-user backend couldn't be reached.
+    #[error("failed to connect to shared directory backend {path}")]
+    SharedDir {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A 9P share's directory couldn't be opened.
+    #[error("failed to share directory {path} over 9p")]
+    P9Share {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A serial port's console backend couldn't be set up.
+    #[error("failed to set up serial console {target}")]
+    SerialConsole {
+        target: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// swtpm's socket couldn't be reached.
+    #[error("failed to connect to swtpm socket {path}")]
+    Tpm {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A fw_cfg blob's file couldn't be read.
+    #[error("failed to read fw_cfg blob {name}")]
+    FwCfg {
+        name: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The control socket couldn't be set up.
+    #[error("failed to serve control socket {path}")]
+    ControlSocket {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The boot report file couldn't be opened.
+    #[error("failed to open boot report {path}")]
+    BootReport {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The console socket couldn't be set up.
+    #[error("failed to serve console socket {path}")]
+    ConsoleSocket {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The `--event-fd` descriptor isn't usable.
+    #[error("invalid --event-fd {fd}")]
+    EventFd {
+        fd: i32,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The Firecracker-compatible API couldn't be served.
+    #[error("failed to serve API socket {path}")]
+    Api {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The gRPC API couldn't be served.
+    #[error("failed to serve gRPC API {path}")]
+    Serve {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The VM pool couldn't be served.
+    #[error("failed to serve VM pool {path}")]
+    Pool {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A snapshot couldn't be read, or doesn't hold a VM.
+    #[error("failed to restore snapshot {path}")]
+    Snapshot {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A snapshot's state file couldn't be read for `carbon inspect`.
+    #[error("failed to inspect snapshot {path}")]
+    Inspect {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// `--detach` couldn't put Carbon in the background.
+    #[error("failed to detach")]
+    Detach(#[source] std::io::Error),
+
+    /// `carbon jail` couldn't set up the jail or start Carbon in it.
+    #[error("failed to jail carbon")]
+    Jail(#[source] std::io::Error),
+
+    /// A command sent to a running VM's control socket failed.
+    #[error("control command failed on {path}")]
+    Control {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// An OCI image couldn't be pulled or turned into a root filesystem.
+    #[error("failed to build a root filesystem from {image}")]
+    BuildRootfs {
+        image: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The host can't run Carbon at all.
+    #[cfg(not(target_os = "linux"))]
+    #[error("{0}")]
+    Unsupported(String),
+
+    /// The guest crashed or misbehaved.
+    #[error("guest failed: {0}")]
+    Guest(String),
+
+    /// The guest kernel reported a panic.
+    #[error("guest kernel panicked")]
+    GuestPanic,
+
+    /// The guest didn't reach init within the boot timeout.
+    #[error("guest didn't boot within {0:?}")]
+    BootTimeout(std::time::Duration),
+
+    /// The VM was still running at its maximum runtime.
+    #[error("VM stopped after its maximum runtime of {0:?}")]
+    MaxRuntime(std::time::Duration),
+
+    /// A signal stopped the VM.
+    #[error("stopped by signal {0}")]
+    Stopped(i32),
+}
+
+impl CarbonError {
+    /// Process exit code for this error (see the module docs).
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_)
+            | Self::Profile(_)
+            | Self::Disk { .. }
+            | Self::AuditLog { .. }
+            | Self::DevicePlugin { .. }
+            | Self::Vsock { .. }
+            | Self::SharedDir { .. }
+            | Self::P9Share { .. }
+            | Self::SerialConsole { .. }
+            | Self::FwCfg { .. }
+            | Self::Tpm { .. }
+            | Self::BootReport { .. }
+            | Self::ConsoleSocket { .. }
+            | Self::EventFd { .. }
+            | Self::ControlSocket { .. }
+            | Self::Snapshot { .. }
+            | Self::Inspect { .. }
+            | Self::Control { .. }
+            | Self::Detach(_)
+            | Self::Jail(_)
+            | Self::Pool { .. }
+            | Self::Api { .. }
+            | Self::Serve { .. }
+            | Self::BuildRootfs { .. } => EXIT_CONFIG,
+            #[cfg(target_os = "linux")]
+            Self::ConfigFile { .. } => EXIT_CONFIG,
+            #[cfg(target_os = "linux")]
+            Self::Kvm(_) => EXIT_HOST,
+            #[cfg(target_os = "linux")]
+            Self::Boot(e) => match e {
+                BootError::Kvm(_) | BootError::MemoryAllocation(_) => EXIT_HOST,
+                _ => EXIT_CONFIG,
+            },
+            #[cfg(not(target_os = "linux"))]
+            Self::Unsupported(_) => EXIT_HOST,
+            Self::Guest(_) => EXIT_GUEST,
+            Self::GuestPanic => EXIT_GUEST_PANIC,
+            Self::BootTimeout(_) => EXIT_BOOT_TIMEOUT,
+            Self::MaxRuntime(_) => EXIT_MAX_RUNTIME,
+            Self::Stopped(signal) => 128 + *signal as u8,
+        }
+    }
+}
+
+/// Render an error and its chain of causes, one per line.
+///
+/// Many errors repeat their source in their own message (`"...: {0}"`); a
+/// cause that the previous line already ends with is skipped.
+pub fn report(error: &dyn Error) -> String {
+    let mut out = error.to_string();
+    let mut previous = out.clone();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let message = cause.to_string();
+        if !previous.ends_with(&message) {
+            out.push_str("\n  caused by: ");
+            out.push_str(&message);
+        }
+        previous = message;
+        source = cause.source();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_report_prints_chain() {
+        let err = CarbonError::Disk {
+            path: "disk.img".into(),
+            source: io::Error::new(io::ErrorKind::NotFound, "no such file"),
+        };
+        assert_eq!(
+            report(&err),
+            "failed to open disk image disk.img\n  caused by: no such file"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_report_skips_repeated_causes() {
+        let err = CarbonError::from(BootError::ReadKernel {
+            path: "vmlinuz".into(),
+            source: io::Error::new(io::ErrorKind::NotFound, "no such file"),
+        });
+        assert_eq!(
+            report(&err),
+            "boot setup failed\n  caused by: Failed to read kernel vmlinuz: no such file"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(CarbonError::Config("x".into()).exit_code(), EXIT_CONFIG);
+        assert_eq!(CarbonError::Guest("x".into()).exit_code(), EXIT_GUEST);
+        assert_eq!(CarbonError::GuestPanic.exit_code(), EXIT_GUEST_PANIC);
+        let timeout = std::time::Duration::from_secs(30);
+        assert_eq!(
+            CarbonError::BootTimeout(timeout).exit_code(),
+            EXIT_BOOT_TIMEOUT
+        );
+        assert_eq!(
+            CarbonError::MaxRuntime(timeout).exit_code(),
+            EXIT_MAX_RUNTIME
+        );
+        assert_eq!(CarbonError::Stopped(libc::SIGINT).exit_code(), 130);
+        let kvm = KvmError::OpenKvm(kvm_ioctls::Error::new(libc::EACCES));
+        assert_eq!(CarbonError::from(kvm).exit_code(), EXIT_HOST);
+        let boot = BootError::InvalidKernel("bad magic".into());
+        assert_eq!(CarbonError::from(boot).exit_code(), EXIT_CONFIG);
+    }
+}
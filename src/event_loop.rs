@@ -0,0 +1,250 @@
+//! The VMM's main-thread event loop.
+//!
+//! While the vCPUs run on threads of their own, the main thread waits on an
+//! epoll set for everything else the VM reacts to: a vCPU stopping, queue
+//! notifications the guest delivers through ioeventfds, console input, the
+//! control socket, and the signals that stop Carbon. [`EventLoop`] is the
+//! epoll set, with each registered descriptor tagged by a value of the
+//! caller's choosing; [`StopSignals`] turns SIGINT, SIGTERM and SIGHUP into
+//! a readable descriptor, so a stop request goes through the loop and the
+//! VM shuts down as it would for any other reason, running every cleanup.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+/// Most events taken from one `epoll_wait`.
+const MAX_EVENTS: usize = 32;
+
+/// An epoll set whose descriptors carry a tag of type `T`.
+pub struct EventLoop<T> {
+    epoll: OwnedFd,
+    /// The registered descriptors and their tags; the epoll data of each
+    /// is its index here.
+    sources: Vec<Option<(RawFd, T)>>,
+}
+
+impl<T: Copy> EventLoop<T> {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: epoll_create1 has no memory-safety preconditions.
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            // SAFETY: a new descriptor, owned by nothing else.
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+            sources: Vec::new(),
+        })
+    }
+
+    /// Watch `fd` for input, reporting it as `tag`.
+    ///
+    /// Fails with `EPERM` for a descriptor epoll can't watch, such as a
+    /// regular file.
+    pub fn add(&mut self, fd: RawFd, tag: T) -> io::Result<()> {
+        let index = self
+            .sources
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.sources.len());
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: index as u64,
+        };
+        // SAFETY: `event` is a valid epoll_event for the duration of the call.
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) }
+            != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if index == self.sources.len() {
+            self.sources.push(None);
+        }
+        self.sources[index] = Some((fd, tag));
+        Ok(())
+    }
+
+    /// Stop watching `fd`.
+    pub fn remove(&mut self, fd: RawFd) {
+        // SAFETY: removal takes no event; a descriptor that isn't in the set
+        // (or is closed) just fails.
+        unsafe {
+            libc::epoll_ctl(
+                self.epoll.as_raw_fd(),
+                libc::EPOLL_CTL_DEL,
+                fd,
+                std::ptr::null_mut(),
+            )
+        };
+        if let Some(source) = self
+            .sources
+            .iter_mut()
+            .find(|source| matches!(source, Some((watched, _)) if *watched == fd))
+        {
+            *source = None;
+        }
+    }
+
+    /// Wait up to `timeout` (forever if `None`) for input, and return the
+    /// tags of the descriptors that have some. Returns nothing if the wait
+    /// timed out or was interrupted by a signal.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<Vec<T>> {
+        let timeout = timeout.map_or(-1, |timeout| {
+            // Round up, so a wait never returns before the timeout
+            timeout
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .min(libc::c_int::MAX as u128) as libc::c_int
+        });
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        // SAFETY: `events` has room for MAX_EVENTS entries.
+        let count = unsafe {
+            libc::epoll_wait(
+                self.epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                MAX_EVENTS as libc::c_int,
+                timeout,
+            )
+        };
+        if count < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+        Ok(events[..count as usize]
+            .iter()
+            .filter_map(|event| {
+                let index = event.u64 as usize;
+                self.sources
+                    .get(index)
+                    .copied()
+                    .flatten()
+                    .map(|(_, tag)| tag)
+            })
+            .collect())
+    }
+}
+
+/// The epoll set, readable while any of its descriptors has input: one
+/// event loop can wait on another (see [`crate::reactor`]).
+impl<T> AsRawFd for EventLoop<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+/// The signals that stop Carbon.
+const STOP_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// The eventfd [`StopSignals`] signals, or -1 when none is installed.
+static STOP_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// The last stop signal received.
+static STOP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Turns the stop signals into input on a descriptor while it lives,
+/// restoring their previous handling when dropped.
+pub struct StopSignals {
+    eventfd: EventFd,
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+impl StopSignals {
+    pub fn install() -> io::Result<Self> {
+        let eventfd = EventFd::new(EFD_NONBLOCK)?;
+        STOP_FD.store(eventfd.as_raw_fd(), Ordering::SeqCst);
+        let mut signals = Self {
+            eventfd,
+            previous: Vec::new(),
+        };
+        for signal in STOP_SIGNALS {
+            // SAFETY: sigaction is plain old data; the handler only makes
+            // async-signal-safe calls.
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction =
+                    on_stop_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(signal, &action, &mut previous) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                signals.previous.push((signal, previous));
+            }
+        }
+        Ok(signals)
+    }
+
+    /// The signal received since the last call, if any.
+    pub fn take(&self) -> Option<libc::c_int> {
+        self.eventfd.read().ok()?;
+        Some(STOP_SIGNAL.load(Ordering::SeqCst))
+    }
+}
+
+impl AsRawFd for StopSignals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
+
+impl Drop for StopSignals {
+    fn drop(&mut self) {
+        for (signal, previous) in &self.previous {
+            // SAFETY: restoring an action sigaction returned.
+            unsafe { libc::sigaction(*signal, previous, std::ptr::null_mut()) };
+        }
+        STOP_FD.store(-1, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn on_stop_signal(signal: libc::c_int) {
+    STOP_SIGNAL.store(signal, Ordering::SeqCst);
+    let fd = STOP_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let one = 1u64;
+        // SAFETY: write is async-signal-safe and `one` outlives the call.
+        unsafe { libc::write(fd, &one as *const u64 as *const libc::c_void, 8) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_event_loop() {
+        let mut events = EventLoop::new().unwrap();
+        let (a, mut a_peer) = UnixStream::pair().unwrap();
+        let (b, mut b_peer) = UnixStream::pair().unwrap();
+        events.add(a.as_raw_fd(), 'a').unwrap();
+        events.add(b.as_raw_fd(), 'b').unwrap();
+        assert!(events
+            .wait(Some(Duration::from_millis(1)))
+            .unwrap()
+            .is_empty());
+
+        b_peer.write_all(b"x").unwrap();
+        assert_eq!(events.wait(None).unwrap(), ['b']);
+
+        // A removed descriptor's slot is reused
+        events.remove(b.as_raw_fd());
+        a_peer.write_all(b"x").unwrap();
+        assert_eq!(events.wait(None).unwrap(), ['a']);
+        let (c, mut c_peer) = UnixStream::pair().unwrap();
+        events.add(c.as_raw_fd(), 'c').unwrap();
+        assert_eq!(events.sources.len(), 2);
+        c_peer.write_all(b"x").unwrap();
+        let mut ready = events.wait(None).unwrap();
+        ready.sort();
+        assert_eq!(ready, ['a', 'c']);
+    }
+}
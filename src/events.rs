@@ -0,0 +1,278 @@
+//! VM lifecycle events (`--event-fd`).
+//!
+//! A run reports its milestones as [`Event`]s, to two kinds of reader:
+//!
+//! - Text, one event per write, as the console socket's `events` channel
+//!   carries them (see [`crate::mux`]): `vcpu-started`, `init-reached`,
+//!   `stopped reason=Shutdown`.
+//! - An [`EventLog`], one JSON object per line, for orchestrators that would
+//!   otherwise scrape the log on stderr. `--event-fd FD` writes one to an
+//!   inherited descriptor.
+//!
+//! Each line of the log names the event and the VM, and carries the time in
+//! milliseconds since the Unix epoch, with the event's fields alongside:
+//!
+//! ```text
+//! {"event":"vm_started","vm_id":"k3x9","time_ms":1760600000000}
+//! {"event":"disk_error","vm_id":"k3x9","time_ms":1760600000450,"disk":"data.img","error":"write at offset 4096: No space left on device (os error 28)"}
+//! {"event":"vm_exited","vm_id":"k3x9","time_ms":1760600003000,"code":0,"reason":"GuestExit"}
+//! ```
+//!
+//! | Event                | When                                                |
+//! | -------------------- | --------------------------------------------------- |
+//! | `vm_started`         | The KVM VM exists; nothing is loaded yet            |
+//! | `kernel_loaded`      | The kernel is in guest memory (not when restoring)  |
+//! | `console_ready`      | COM1's console is open (a socket's client is in)    |
+//! | `vcpu_started`       | The vCPUs start running                             |
+//! | `init_reached`       | The guest printed the init marker                   |
+//! | `guest_panicked`     | The guest kernel reported a panic over pvpanic      |
+//! | `guest_crash_loaded` | The guest loaded its crash kernel after a panic     |
+//! | `disk_error`         | Reading, writing or syncing a disk's image failed   |
+//! | `vm_stopped`         | The VM stopped, and why (`reason`)                  |
+//! | `vm_exited`          | Carbon is exiting: why, and its exit code           |
+//!
+//! `vm_exited` is last: its `reason` is `vm_stopped`'s, or `failed` if the
+//! VM never ran, and `error` says what went wrong when Carbon fails.
+//! Writes that fail are ignored, so a reader going away never stops a VM.
+
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A VM lifecycle event (see the [module docs](self)).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    VmStarted,
+    KernelLoaded,
+    ConsoleReady,
+    VcpuStarted,
+    InitReached,
+    GuestPanicked,
+    GuestCrashLoaded,
+    DiskError {
+        /// The disk's image, as configured.
+        disk: String,
+        error: String,
+    },
+    VmStopped {
+        /// The [`StopReason`](crate::StopReason), e.g. `Signal(15)`.
+        reason: String,
+    },
+    VmExited {
+        reason: String,
+        code: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl Event {
+    /// The event as the console socket's `events` channel carries it.
+    pub fn text(&self) -> String {
+        match self {
+            Self::VmStarted => "vm-started".into(),
+            Self::KernelLoaded => "kernel-loaded".into(),
+            Self::ConsoleReady => "console-ready".into(),
+            Self::VcpuStarted => "vcpu-started".into(),
+            Self::InitReached => "init-reached".into(),
+            Self::GuestPanicked => "guest-panicked".into(),
+            Self::GuestCrashLoaded => "guest-crash-loaded".into(),
+            Self::DiskError { disk, error } => format!("disk-error {disk}: {error}"),
+            Self::VmStopped { reason } => format!("stopped reason={reason}"),
+            Self::VmExited { reason, code, .. } => format!("exited reason={reason} code={code}"),
+        }
+    }
+}
+
+/// Where a run sends its events: a text writer, an [`EventLog`], both or
+/// neither. Clones send to the same readers.
+#[derive(Clone, Default)]
+pub struct Events {
+    text: Option<Arc<Mutex<Box<dyn Write + Send>>>>,
+    log: Option<EventLog>,
+}
+
+impl Events {
+    /// Write each event to `writer` as text, in one write.
+    pub fn set_text(&mut self, writer: Box<dyn Write + Send>) {
+        self.text = Some(Arc::new(Mutex::new(writer)));
+    }
+
+    /// Append each event to `log`.
+    pub fn set_log(&mut self, log: EventLog) {
+        self.log = Some(log);
+    }
+
+    pub fn emit(&self, event: &Event) {
+        if let Some(text) = &self.text {
+            let _ = lock(text).write_all(event.text().as_bytes());
+        }
+        if let Some(log) = &self.log {
+            log.emit(event);
+        }
+    }
+}
+
+/// Events as JSON lines (see the [module docs](self)). Clones append to
+/// the same writer.
+#[derive(Clone)]
+pub struct EventLog(Arc<Mutex<LogState>>);
+
+struct LogState {
+    writer: Box<dyn Write + Send>,
+    vm_id: String,
+    /// The last `vm_stopped` reason, for `vm_exited`.
+    stopped: Option<String>,
+}
+
+impl EventLog {
+    /// A log of the events of VM `vm_id`, written to `writer`.
+    pub fn new(writer: Box<dyn Write + Send>, vm_id: &str) -> Self {
+        Self(Arc::new(Mutex::new(LogState {
+            writer,
+            vm_id: vm_id.to_string(),
+            stopped: None,
+        })))
+    }
+
+    /// A log written to the inherited descriptor `fd`, taking ownership of
+    /// it. It isn't passed on to processes Carbon starts.
+    pub fn from_fd(fd: RawFd, vm_id: &str) -> io::Result<Self> {
+        // SAFETY: fcntl on any fd number has no memory-safety requirements.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor is open, and is the log's from here on.
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(Self::new(Box::new(file), vm_id))
+    }
+
+    pub fn emit(&self, event: &Event) {
+        let mut state = lock(&self.0);
+        if let Event::VmStopped { reason } = event {
+            state.stopped = Some(reason.clone());
+        }
+        let mut fields = match serde_json::to_value(event) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => return,
+        };
+        let name = fields.remove("event").unwrap_or_default();
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        // The event's name, the VM and the time lead, whatever the order
+        // of the event's own fields
+        let mut line = format!(
+            "{{\"event\":{},\"vm_id\":{},\"time_ms\":{}",
+            name,
+            serde_json::Value::from(state.vm_id.as_str()),
+            time_ms
+        );
+        for (key, value) in fields {
+            line += &format!(",{}:{}", serde_json::Value::from(key), value);
+        }
+        line += "}\n";
+        let _ = state.writer.write_all(line.as_bytes());
+        let _ = state.writer.flush();
+    }
+
+    /// Record that Carbon is exiting with `code`, and `error` if it failed.
+    pub fn exited(&self, code: u8, error: Option<String>) {
+        let reason = lock(&self.0)
+            .stopped
+            .clone()
+            .unwrap_or_else(|| "failed".into());
+        self.emit(&Event::VmExited {
+            reason,
+            code,
+            error,
+        });
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer whose output the test keeps.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            lock(&self.0).extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn lines(&self) -> Vec<serde_json::Value> {
+            String::from_utf8(lock(&self.0).clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_event_log() {
+        let output = Output::default();
+        let log = EventLog::new(Box::new(output.clone()), "vm1");
+        log.emit(&Event::VmStarted);
+        log.emit(&Event::DiskError {
+            disk: "data.img".into(),
+            error: "EIO".into(),
+        });
+        log.exited(3, Some("bad config".into()));
+        log.emit(&Event::VmStopped {
+            reason: "Signal(15)".into(),
+        });
+        log.exited(143, None);
+
+        let lines = output.lines();
+        assert_eq!(lines[0]["event"], "vm_started");
+        assert_eq!(lines[0]["vm_id"], "vm1");
+        assert!(lines[0]["time_ms"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["event"], "disk_error");
+        assert_eq!(lines[1]["disk"], "data.img");
+        assert_eq!(lines[1]["error"], "EIO");
+        assert_eq!(lines[2]["event"], "vm_exited");
+        assert_eq!(lines[2]["reason"], "failed");
+        assert_eq!(lines[2]["code"], 3);
+        assert_eq!(lines[2]["error"], "bad config");
+        assert_eq!(lines[4]["reason"], "Signal(15)");
+        assert_eq!(lines[4]["code"], 143);
+        assert!(lines[4].get("error").is_none());
+    }
+
+    #[test]
+    fn test_events() {
+        let (text, json) = (Output::default(), Output::default());
+        let mut events = Events::default();
+        events.emit(&Event::InitReached);
+        events.set_text(Box::new(text.clone()));
+        events.set_log(EventLog::new(Box::new(json.clone()), "vm1"));
+        events.clone().emit(&Event::VcpuStarted);
+        events.emit(&Event::VmStopped {
+            reason: "GuestExit".into(),
+        });
+        assert_eq!(
+            String::from_utf8(lock(&text.0).clone()).unwrap(),
+            "vcpu-startedstopped reason=GuestExit"
+        );
+        assert_eq!(json.lines().len(), 2);
+    }
+}
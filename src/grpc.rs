@@ -0,0 +1,744 @@
+//! gRPC control API (`carbon serve`).
+//!
+//! `carbon serve` is a daemon for control planes that would rather have a
+//! typed interface than the text protocols of the control and console
+//! sockets: it serves `CarbonService` (`proto/carbon.proto`) on a Unix
+//! socket, and runs each VM a client creates.
+//!
+//! | RPC             | Effect                                              |
+//! | --------------- | --------------------------------------------------- |
+//! | `CreateVm`      | Start a VM from a kernel, disks and so on           |
+//! | `ListVms`       | The VMs started, and how each exited                |
+//! | `StopVm`        | Stop a VM, as SIGTERM stops `carbon`                |
+//! | `AttachDevice`  | Plug a disk into a hot-plug slot (`disk-plug`)      |
+//! | `DetachDevice`  | Unplug a hot-plugged disk (`disk-unplug`)           |
+//! | `Pause`         | Stop the vCPUs (`pause`)                            |
+//! | `Resume`        | Let a paused VM run on (`resume`)                   |
+//! | `Snapshot`      | Write a snapshot (`snapshot`)                       |
+//! | `StreamEvents`  | The VM's lifecycle events, from its start           |
+//! | `StreamConsole` | The guest's serial console, from the call on        |
+//!
+//! Like the VMs of a [pool](crate::pool), each VM is a `carbon` process of
+//! its own, so one that crashes takes no other with it. The server gives it
+//! a control socket (see [`crate::control`]), which the device, pause and
+//! snapshot calls are sent to, and a console socket (see [`crate::mux`]),
+//! which the server reads the console and events from and hands on to the
+//! streams. A VM's sockets and log are `ID.sock`, `ID.console` and
+//! `ID.log` in the server's directory.
+//!
+//! `CreateVm` answers once the VM is booting. A VM that exits is kept, with
+//! its exit code, until one with its ID is created again; its streams end
+//! as it exits. Each stream is sent what the VM produces as it produces it:
+//! one that can't keep up misses what it fell [`STREAM_BACKLOG`] messages
+//! behind on, rather than hold up the VM. Errors are gRPC statuses:
+//! `NOT_FOUND` for an unknown VM, `INVALID_ARGUMENT` for a request Carbon
+//! refuses, and `FAILED_PRECONDITION` for one the VM refuses (a disk that
+//! won't plug, a VM that has exited).
+//!
+//! Stopping the server stops every VM it runs.
+
+use crate::audit;
+use crate::cleanup::{self, CleanupGuard};
+use crate::control;
+use crate::event_loop::StopSignals;
+use crate::mux::{Channel, Decoder};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{BroadcastStream, UnixListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+/// The types and service generated from `proto/carbon.proto`.
+pub mod proto {
+    tonic::include_proto!("carbon.v1");
+}
+
+use proto::carbon_service_server::{CarbonService, CarbonServiceServer};
+
+/// How long a VM may take to open its console socket.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check for the console socket while a VM starts.
+const START_POLL: Duration = Duration::from_millis(10);
+
+/// How long a VM's control socket may take to answer; a snapshot writes
+/// all of guest RAM first.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How many messages a stream may fall behind by before it misses some.
+pub const STREAM_BACKLOG: usize = 1024;
+
+/// What a server runs its VMs with.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Where the VMs' sockets and logs go.
+    pub dir: PathBuf,
+    /// Audit log for the VMs to append to, if any.
+    pub audit_log: Option<PathBuf>,
+}
+
+/// Serve the API on the socket at `path` until a stop signal arrives, then
+/// stop the VMs.
+pub fn serve(config: ServerConfig, path: &Path) -> io::Result<()> {
+    let (listener, _guard) = bind(path)?;
+    listener.set_nonblocking(true)?;
+    let signals = StopSignals::install()?;
+    let vms = Arc::new(Vms::new(config));
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let result = runtime.block_on(async {
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+        let signals = AsyncFd::new(signals)?;
+        let stop = async move {
+            while let Ok(mut ready) = signals.readable().await {
+                if let Some(signal) = ready.get_inner().take() {
+                    info!("[serve] Stopping on signal {}", signal);
+                    return;
+                }
+                ready.clear_ready();
+            }
+        };
+        tonic::transport::Server::builder()
+            .add_service(CarbonServiceServer::new(Service { vms: vms.clone() }))
+            .serve_with_incoming_shutdown(UnixListenerStream::new(listener), stop)
+            .await
+            .map_err(io::Error::other)
+    });
+    vms.stop_all();
+    result
+}
+
+/// Listen on `path`, replacing any stale socket there. The socket is
+/// removed when the guard drops, or on exit.
+fn bind(path: &Path) -> io::Result<(UnixListener, CleanupGuard)> {
+    let (listener, guard) = cleanup::bind_socket(path, "remove gRPC socket")?;
+    info!("[serve] Serving gRPC on {}", path.display());
+    Ok((listener, guard))
+}
+
+/// A VM the server started.
+struct ServedVm {
+    id: String,
+    pid: u32,
+    control_socket: PathBuf,
+    /// Reaped by the thread reading the console socket once the VM exits.
+    child: Mutex<Child>,
+    state: Mutex<VmState>,
+}
+
+/// What a VM has produced, and who is listening.
+struct VmState {
+    /// Every event so far, for streams that open late.
+    events: Vec<String>,
+    /// Closed once the VM exits, ending the streams.
+    event_sender: Option<broadcast::Sender<String>>,
+    console_sender: Option<broadcast::Sender<Vec<u8>>>,
+    /// The process's exit code, once it has exited: `None` in it if a
+    /// signal killed it.
+    exit: Option<Option<i32>>,
+}
+
+impl ServedVm {
+    fn state(&self) -> MutexGuard<'_, VmState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn info(&self) -> proto::Vm {
+        let exit = self.state().exit;
+        proto::Vm {
+            vm_id: self.id.clone(),
+            pid: self.pid,
+            state: if exit.is_some() { "exited" } else { "running" }.into(),
+            exit_code: exit.flatten(),
+        }
+    }
+
+    /// Send `command` to the VM's control socket.
+    fn control(&self, command: &str) -> Result<String, Status> {
+        if self.state().exit.is_some() {
+            return Err(Status::failed_precondition(format!(
+                "VM {} has exited",
+                self.id
+            )));
+        }
+        control::send(&self.control_socket, command, CONTROL_TIMEOUT)
+            .map_err(|e| Status::failed_precondition(format!("VM {}: {}", self.id, e)))
+    }
+
+    /// Stop the VM unless it has exited, and wait for it to.
+    fn stop(&self) {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        if let Ok(None) = child.try_wait() {
+            // SAFETY: kill has no memory effects; the child isn't reaped
+            // yet, so its PID is still its own.
+            unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGTERM) };
+            let _ = child.wait();
+        }
+    }
+
+    /// Read the VM's console socket until it closes, handing output and
+    /// events on, then reap the VM.
+    fn read_console(&self, mut socket: UnixStream) {
+        let mut decoder = Decoder::default();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = match socket.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("[serve] VM {} console: {}", self.id, e);
+                    break;
+                }
+            };
+            decoder.push(&buf[..len]);
+            while let Some(frame) = decoder.next_frame() {
+                let mut state = self.state();
+                match frame.channel {
+                    c if c == Channel::Serial as u8 => {
+                        if let Some(sender) = &state.console_sender {
+                            // No stream listening is fine
+                            let _ = sender.send(frame.payload);
+                        }
+                    }
+                    c if c == Channel::Events as u8 => {
+                        state.event(String::from_utf8_lossy(&frame.payload).into_owned());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let status = self.child.lock().unwrap_or_else(|e| e.into_inner()).wait();
+        let code = status.ok().and_then(|status| status.code());
+        info!(
+            "[serve] VM {} exited ({})",
+            self.id,
+            code.map_or("killed".into(), |code| format!("exit code {code}"))
+        );
+        let mut state = self.state();
+        state.event(match code {
+            Some(code) => format!("exited {code}"),
+            None => "exited".into(),
+        });
+        state.exit = Some(code);
+        state.event_sender = None;
+        state.console_sender = None;
+    }
+}
+
+impl VmState {
+    fn event(&mut self, event: String) {
+        if let Some(sender) = &self.event_sender {
+            let _ = sender.send(event.clone());
+        }
+        self.events.push(event);
+    }
+}
+
+/// The VMs a server runs, by ID.
+struct Vms {
+    config: ServerConfig,
+    vms: Mutex<BTreeMap<String, Arc<ServedVm>>>,
+}
+
+impl Vms {
+    fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            vms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Arc<ServedVm>, Status> {
+        self.vms
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("no VM {id:?}")))
+    }
+
+    fn list(&self) -> Vec<proto::Vm> {
+        let vms = self.vms.lock().unwrap_or_else(|e| e.into_inner());
+        vms.values().map(|vm| vm.info()).collect()
+    }
+
+    /// Start the VM `request` describes, once it is booting.
+    fn create(&self, mut request: proto::CreateVmRequest) -> Result<Arc<ServedVm>, Status> {
+        if request.vm_id.is_empty() {
+            request.vm_id = audit::new_vm_id();
+        }
+        let id = request.vm_id.clone();
+        check_id(&id)?;
+        let command = self.command(&request)?;
+        {
+            let vms = self.vms.lock().unwrap_or_else(|e| e.into_inner());
+            if vms.get(&id).is_some_and(|vm| vm.state().exit.is_none()) {
+                return Err(Status::already_exists(format!("VM {id:?} is running")));
+            }
+        }
+        let vm = self
+            .start(&id, command)
+            .map_err(|e| Status::failed_precondition(format!("VM {id}: {e}")))?;
+        self.vms
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, vm.clone());
+        Ok(vm)
+    }
+
+    /// The `carbon` command line to run the VM `request` describes.
+    fn command(&self, request: &proto::CreateVmRequest) -> Result<Command, Status> {
+        let id = &request.vm_id;
+        if request.kernel.is_empty() {
+            return Err(Status::invalid_argument("a VM needs a kernel"));
+        }
+        // Fail here rather than in a VM that has started
+        File::open(&request.kernel).map_err(|e| {
+            Status::invalid_argument(format!("failed to open kernel {}: {e}", request.kernel))
+        })?;
+        let mut command = Command::new(std::env::current_exe().map_err(internal)?);
+        command.arg("run");
+        if let Some(path) = &self.config.audit_log {
+            command.arg("--audit-log").arg(path);
+        }
+        command.args(["--vm-id", id, "--kernel", &request.kernel]);
+        for (flag, value) in [
+            ("--cmdline", &request.cmdline),
+            ("--initrd", &request.initrd),
+            ("--vsock", &request.vsock),
+        ] {
+            if !value.is_empty() {
+                command.args([flag, value]);
+            }
+        }
+        if request.memory_mib > 0 {
+            command.arg(format!("--memory={}M", request.memory_mib));
+        }
+        if request.cpus > 0 {
+            command.arg(format!("--cpus={}", request.cpus));
+        }
+        if request.hotplug_slots > 0 {
+            command.arg(format!("--hotplug-disks={}", request.hotplug_slots));
+        }
+        for disk in &request.disks {
+            command.arg(format!("--disk={disk}"));
+        }
+        let dir = &self.config.dir;
+        command
+            .arg("--control-socket")
+            .arg(dir.join(format!("{id}.sock")))
+            .arg("--console-socket")
+            .arg(dir.join(format!("{id}.console")))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        Ok(command)
+    }
+
+    /// Run `command` as VM `id`, and wait for it to open its console
+    /// socket: it boots once the server is reading it.
+    fn start(&self, id: &str, mut command: Command) -> io::Result<Arc<ServedVm>> {
+        let dir = &self.config.dir;
+        let console_socket = dir.join(format!("{id}.console"));
+        let log_path = dir.join(format!("{id}.log"));
+        match std::fs::remove_file(&console_socket) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        command.stderr(File::create(&log_path)?);
+        let mut child = command.spawn()?;
+        let pid = child.id();
+        audit::record(
+            audit::Kind::Process,
+            "spawn",
+            &format!("VM {id} (pid {pid})"),
+        );
+
+        let started = Instant::now();
+        let socket = loop {
+            if let Ok(socket) = UnixStream::connect(&console_socket) {
+                break socket;
+            }
+            if let Some(status) = child.try_wait()? {
+                return Err(io::Error::other(format!(
+                    "failed to start ({status}): {}",
+                    last_line(&log_path)
+                )));
+            }
+            if started.elapsed() > START_TIMEOUT {
+                // SAFETY: as in `ServedVm::stop`.
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no console socket after {START_TIMEOUT:?}"),
+                ));
+            }
+            std::thread::sleep(START_POLL);
+        };
+
+        let (event_sender, _) = broadcast::channel(STREAM_BACKLOG);
+        let (console_sender, _) = broadcast::channel(STREAM_BACKLOG);
+        let vm = Arc::new(ServedVm {
+            id: id.to_string(),
+            pid,
+            control_socket: dir.join(format!("{id}.sock")),
+            child: Mutex::new(child),
+            state: Mutex::new(VmState {
+                events: Vec::new(),
+                event_sender: Some(event_sender),
+                console_sender: Some(console_sender),
+                exit: None,
+            }),
+        });
+        let reader = vm.clone();
+        std::thread::Builder::new()
+            .name(format!("vm-{id}"))
+            .spawn(move || reader.read_console(socket))?;
+        info!("[serve] Started VM {} (pid {})", id, pid);
+        Ok(vm)
+    }
+
+    /// Stop every VM still running.
+    fn stop_all(&self) {
+        let vms: Vec<_> = {
+            let vms = self.vms.lock().unwrap_or_else(|e| e.into_inner());
+            vms.values().cloned().collect()
+        };
+        for vm in vms {
+            vm.stop();
+        }
+    }
+}
+
+/// VM IDs name files in the server's directory.
+fn check_id(id: &str) -> Result<(), Status> {
+    let valid = !id.is_empty()
+        && !id.starts_with('.')
+        && id.len() <= 64
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+    if valid {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "invalid VM ID {id:?} (letters, digits, '-', '_' and '.', up to 64)"
+        )))
+    }
+}
+
+/// The last line of a VM's log, to say why it failed.
+fn last_line(path: &Path) -> String {
+    let log = std::fs::read_to_string(path).unwrap_or_default();
+    match log.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => line.to_string(),
+        None => format!("see {}", path.display()),
+    }
+}
+
+/// A path for the control socket's command line, which splits on spaces.
+fn control_path(path: &str) -> Result<&str, Status> {
+    if path.is_empty() || path.contains(char::is_whitespace) {
+        return Err(Status::invalid_argument(format!(
+            "invalid path {path:?} (must be non-empty, without spaces)"
+        )));
+    }
+    Ok(path)
+}
+
+fn internal(e: impl std::fmt::Display) -> Status {
+    Status::internal(e.to_string())
+}
+
+/// Run `f` where it may block.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, Status> + Send + 'static,
+) -> Result<T, Status> {
+    tokio::task::spawn_blocking(f).await.map_err(internal)?
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+type ConsoleStream = Pin<Box<dyn Stream<Item = Result<proto::ConsoleOutput, Status>> + Send>>;
+
+/// `CarbonService` over the server's VMs.
+struct Service {
+    vms: Arc<Vms>,
+}
+
+impl Service {
+    /// Run `command` on VM `id`'s control socket.
+    async fn control(&self, id: String, command: String) -> Result<String, Status> {
+        let vm = self.vms.get(&id)?;
+        blocking(move || vm.control(&command)).await
+    }
+}
+
+#[tonic::async_trait]
+impl CarbonService for Service {
+    async fn create_vm(
+        &self,
+        request: Request<proto::CreateVmRequest>,
+    ) -> Result<Response<proto::Vm>, Status> {
+        let vms = self.vms.clone();
+        let vm = blocking(move || vms.create(request.into_inner())).await?;
+        Ok(Response::new(vm.info()))
+    }
+
+    async fn list_vms(
+        &self,
+        _request: Request<proto::ListVmsRequest>,
+    ) -> Result<Response<proto::ListVmsResponse>, Status> {
+        Ok(Response::new(proto::ListVmsResponse {
+            vms: self.vms.list(),
+        }))
+    }
+
+    async fn stop_vm(
+        &self,
+        request: Request<proto::VmRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let vm = self.vms.get(&request.into_inner().vm_id)?;
+        blocking(move || {
+            vm.stop();
+            Ok(())
+        })
+        .await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn attach_device(
+        &self,
+        request: Request<proto::AttachDeviceRequest>,
+    ) -> Result<Response<proto::AttachDeviceResponse>, Status> {
+        let request = request.into_inner();
+        let disk = control_path(&request.disk)?;
+        let result = self
+            .control(request.vm_id, format!("disk-plug {disk}"))
+            .await?;
+        let parsed = result.split_once(' ').and_then(|(disk, base)| {
+            let base = u64::from_str_radix(base.strip_prefix("0x")?, 16).ok()?;
+            Some((disk.parse().ok()?, base))
+        });
+        let (disk, mmio_base) =
+            parsed.ok_or_else(|| internal(format!("unexpected disk-plug answer {result:?}")))?;
+        Ok(Response::new(proto::AttachDeviceResponse {
+            disk,
+            mmio_base,
+        }))
+    }
+
+    async fn detach_device(
+        &self,
+        request: Request<proto::DetachDeviceRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        self.control(request.vm_id, format!("disk-unplug {}", request.disk))
+            .await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn pause(
+        &self,
+        request: Request<proto::VmRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.control(request.into_inner().vm_id, "pause".into())
+            .await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn resume(
+        &self,
+        request: Request<proto::VmRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.control(request.into_inner().vm_id, "resume".into())
+            .await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn snapshot(
+        &self,
+        request: Request<proto::SnapshotRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        let command = format!(
+            "snapshot {} {}",
+            control_path(&request.state_path)?,
+            control_path(&request.memory_path)?
+        );
+        self.control(request.vm_id, command).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    type StreamEventsStream = EventStream;
+
+    async fn stream_events(
+        &self,
+        request: Request<proto::VmRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let vm = self.vms.get(&request.into_inner().vm_id)?;
+        let (history, receiver) = {
+            let state = vm.state();
+            let receiver = state.event_sender.as_ref().map(|s| s.subscribe());
+            (state.events.clone(), receiver)
+        };
+        let live: Pin<Box<dyn Stream<Item = String> + Send>> = match receiver {
+            Some(receiver) => Box::pin(BroadcastStream::new(receiver).filter_map(Result::ok)),
+            None => Box::pin(tokio_stream::empty()),
+        };
+        let id = vm.id.clone();
+        let stream = tokio_stream::iter(history).chain(live).map(move |event| {
+            Ok(proto::Event {
+                vm_id: id.clone(),
+                event,
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type StreamConsoleStream = ConsoleStream;
+
+    async fn stream_console(
+        &self,
+        request: Request<proto::VmRequest>,
+    ) -> Result<Response<ConsoleStream>, Status> {
+        let vm = self.vms.get(&request.into_inner().vm_id)?;
+        let receiver = vm
+            .state()
+            .console_sender
+            .as_ref()
+            .map(|sender| sender.subscribe())
+            .ok_or_else(|| Status::failed_precondition(format!("VM {} has exited", vm.id)))?;
+        let stream = BroadcastStream::new(receiver)
+            .filter_map(Result::ok)
+            .map(|data| Ok(proto::ConsoleOutput { data }));
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vms() -> Vms {
+        Vms::new(ServerConfig {
+            dir: std::env::temp_dir(),
+            audit_log: None,
+        })
+    }
+
+    #[test]
+    fn test_check_id() {
+        assert!(check_id("vm-1_a.b").is_ok());
+        for id in ["", ".hidden", "../escape", "a b", &"x".repeat(65)] {
+            let status = check_id(id).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{id:?}");
+        }
+        assert!(control_path("/tmp/vm.json").is_ok());
+        assert!(control_path("/tmp/my vm.json").is_err());
+    }
+
+    #[test]
+    fn test_command() {
+        let request = proto::CreateVmRequest {
+            vm_id: "web".into(),
+            kernel: "/dev/null".into(),
+            memory_mib: 256,
+            cpus: 2,
+            disks: vec!["path=root.img,ro".into()],
+            hotplug_slots: 1,
+            ..Default::default()
+        };
+        let command = vms().command(&request).unwrap();
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            args,
+            [
+                "run".to_string(),
+                "--vm-id".into(),
+                "web".into(),
+                "--kernel".into(),
+                "/dev/null".into(),
+                "--memory=256M".into(),
+                "--cpus=2".into(),
+                "--hotplug-disks=1".into(),
+                "--disk=path=root.img,ro".into(),
+                "--control-socket".into(),
+                dir.join("web.sock").display().to_string(),
+                "--console-socket".into(),
+                dir.join("web.console").display().to_string(),
+            ]
+        );
+
+        for kernel in ["", "/nonexistent/bzImage"] {
+            let request = proto::CreateVmRequest {
+                kernel: kernel.into(),
+                ..Default::default()
+            };
+            let status = vms().command(&request).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn test_unknown_vm() {
+        let vms = vms();
+        assert!(vms.list().is_empty());
+        assert_eq!(vms.get("nope").err().unwrap().code(), tonic::Code::NotFound);
+    }
+
+    #[test]
+    fn test_read_console() {
+        let (mut host, vm_side) = UnixStream::pair().unwrap();
+        let child = Command::new("true").spawn().unwrap();
+        let (event_sender, mut events) = broadcast::channel(STREAM_BACKLOG);
+        let (console_sender, mut console) = broadcast::channel(STREAM_BACKLOG);
+        let vm = ServedVm {
+            id: "test".into(),
+            pid: child.id(),
+            control_socket: "test.sock".into(),
+            child: Mutex::new(child),
+            state: Mutex::new(VmState {
+                events: Vec::new(),
+                event_sender: Some(event_sender),
+                console_sender: Some(console_sender),
+                exit: None,
+            }),
+        };
+
+        let mut wire = Vec::new();
+        crate::mux::encode(Channel::Serial, b"login: ", &mut wire);
+        crate::mux::encode(Channel::Events, b"init-reached", &mut wire);
+        crate::mux::encode(Channel::AgentStdout, b"ignored", &mut wire);
+        std::io::Write::write_all(&mut host, &wire).unwrap();
+        drop(host);
+        vm.read_console(vm_side);
+
+        assert_eq!(console.try_recv().unwrap(), b"login: ");
+        assert_eq!(events.try_recv().unwrap(), "init-reached");
+        assert_eq!(events.try_recv().unwrap(), "exited 0");
+        let info = vm.info();
+        assert_eq!((info.state.as_str(), info.exit_code), ("exited", Some(0)));
+        assert_eq!(vm.state().events, ["init-reached", "exited 0"]);
+        // The streams end, and the VM takes no more commands
+        assert!(vm.state().event_sender.is_none());
+        let status = vm.control("pause").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+}
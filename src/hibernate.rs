@@ -0,0 +1,234 @@
+//! Idle hibernation (`carbon run --hibernate-after SECONDS`).
+//!
+//! A VM left with nothing to do is snapshotted and stopped, freeing its
+//! memory and vCPU threads, for `carbon restore` to carry on from when
+//! there is work again:
+//!
+//! ```text
+//! running ──(idle for idle_timeout)──► snapshot to DIR/state.json and
+//!    ▲                                 DIR/memory.bin, stop
+//!    │                                          │
+//!    └──(a client connects to the control socket, with [`run`])
+//! ```
+//!
+//! The VM is idle when nothing moves between it and the host: no vCPU
+//! exits to Carbon for port or MMIO access, no virtio queue is notified,
+//! and no console input or control client arrives. The run loop counts
+//! these as it serves them and checks the count at most once a second, so
+//! a busy VM pays nothing for the policy. A guest computing without
+//! touching a device looks idle too; the snapshot resumes it exactly where
+//! it was.
+//!
+//! Only a VM that can be snapshotted can hibernate (see
+//! [`crate::snapshot`]); the run refuses to start otherwise.
+//!
+//! A VM run by [`run`] with a control socket wakes on demand: the socket
+//! is bound once and stays bound while the VM is hibernated, so the first
+//! client to connect restores the VM, and its command waits in the
+//! socket's backlog until the restored VM serves it. Without a control
+//! socket the VM stays hibernated, for `carbon restore`.
+
+use crate::cleanup;
+use crate::error::CarbonError;
+use crate::event_loop::StopSignals;
+use crate::vmm::{RunOutcome, StopReason};
+use crate::VmmBuilder;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// The state file a hibernated VM leaves in its directory.
+pub const STATE_FILE: &str = "state.json";
+
+/// The memory file a hibernated VM leaves in its directory.
+pub const MEMORY_FILE: &str = "memory.bin";
+
+/// Longest time between idle checks.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When and where a VM hibernates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hibernate {
+    /// How long the VM must be idle first.
+    pub idle_timeout: Duration,
+    /// Where the snapshot goes; created if missing.
+    pub dir: PathBuf,
+}
+
+impl Hibernate {
+    pub fn new(idle_timeout: Duration, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            idle_timeout,
+            dir: dir.into(),
+        }
+    }
+
+    /// The snapshot's state file.
+    pub fn state_path(&self) -> PathBuf {
+        self.dir.join(STATE_FILE)
+    }
+
+    /// The snapshot's memory file.
+    pub fn memory_path(&self) -> PathBuf {
+        self.dir.join(MEMORY_FILE)
+    }
+}
+
+/// Run the VM `builder` describes, hibernating it as `hibernate` says and
+/// waking it from the snapshot whenever a client connects to its control
+/// socket, until it stops for any other reason.
+///
+/// `restored` sets up each restored VM beyond what the snapshot holds,
+/// such as its console. A stop signal while the VM is hibernated stops
+/// waiting, returning [`StopReason::Hibernated`]. Without a control socket
+/// nothing can wake the VM, and the first hibernation is returned.
+pub fn run(
+    builder: VmmBuilder,
+    hibernate: &Hibernate,
+    mut restored: impl FnMut(VmmBuilder) -> VmmBuilder,
+) -> Result<RunOutcome, CarbonError> {
+    let mut builder = builder.hibernate(hibernate.clone());
+    let Some(path) = builder.config_mut().control_socket.clone() else {
+        return builder.build()?.run();
+    };
+    let control_error = |source| CarbonError::ControlSocket {
+        path: path.display().to_string(),
+        source,
+    };
+    let (listener, _guard) =
+        cleanup::bind_socket(&path, "remove control socket").map_err(control_error)?;
+    info!("[VMM] Control socket on {}", path.display());
+    loop {
+        let served = listener.try_clone().map_err(control_error)?;
+        let outcome = builder.control_listener(served).build()?.run()?;
+        if outcome.reason != StopReason::Hibernated {
+            return Ok(outcome);
+        }
+        info!(
+            "[VMM] Hibernated; waking on the next client of {}",
+            path.display()
+        );
+        if !wait_for_client(&listener).map_err(control_error)? {
+            return Ok(outcome);
+        }
+        info!(
+            "[VMM] Client waiting, waking from {}",
+            hibernate.dir.display()
+        );
+        builder = restored(VmmBuilder::restore(
+            &hibernate.state_path(),
+            &hibernate.memory_path(),
+        )?)
+        .control_socket(&path)
+        .hibernate(hibernate.clone());
+    }
+}
+
+/// Wait for a client to connect to `listener`, leaving it to be accepted.
+/// Returns false if a stop signal came first.
+fn wait_for_client(listener: &UnixListener) -> io::Result<bool> {
+    let signals = StopSignals::install()?;
+    let mut fds = [listener.as_raw_fd(), signals.as_raw_fd()].map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    loop {
+        // SAFETY: `fds` is a valid array of two entries.
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if let Some(signal) = signals.take() {
+            info!("[VMM] Received signal {}, staying hibernated", signal);
+            return Ok(false);
+        }
+        if fds[0].revents != 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Tells from a count of activity that only grows whether a VM has been
+/// idle for a whole timeout.
+#[derive(Debug)]
+pub(crate) struct IdleMonitor {
+    timeout: Duration,
+    /// The count last observed.
+    activity: u64,
+    /// When the count last changed.
+    since: Instant,
+}
+
+impl IdleMonitor {
+    pub(crate) fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            timeout,
+            activity: 0,
+            since: now,
+        }
+    }
+
+    /// How long to wait before observing again.
+    pub(crate) fn interval(&self) -> Duration {
+        self.timeout.min(CHECK_INTERVAL)
+    }
+
+    /// Observe the activity count at `now`: whether it hasn't changed for
+    /// the whole timeout.
+    pub(crate) fn observe(&mut self, activity: u64, now: Instant) -> bool {
+        if activity != self.activity {
+            self.activity = activity;
+            self.since = now;
+            return false;
+        }
+        now.saturating_duration_since(self.since) >= self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_after_timeout() {
+        let start = Instant::now();
+        let mut monitor = IdleMonitor::new(Duration::from_secs(10), start);
+        assert!(!monitor.observe(0, start + Duration::from_secs(5)));
+        assert!(!monitor.observe(0, start + Duration::from_secs(9)));
+        assert!(monitor.observe(0, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_activity_restarts_timeout() {
+        let start = Instant::now();
+        let mut monitor = IdleMonitor::new(Duration::from_secs(10), start);
+        assert!(!monitor.observe(3, start + Duration::from_secs(8)));
+        assert!(!monitor.observe(3, start + Duration::from_secs(12)));
+        assert!(!monitor.observe(4, start + Duration::from_secs(18)));
+        assert!(monitor.observe(4, start + Duration::from_secs(28)));
+    }
+
+    #[test]
+    fn test_interval() {
+        let now = Instant::now();
+        assert_eq!(
+            IdleMonitor::new(Duration::from_secs(600), now).interval(),
+            CHECK_INTERVAL
+        );
+        let short = Duration::from_millis(200);
+        assert_eq!(IdleMonitor::new(short, now).interval(), short);
+    }
+
+    #[test]
+    fn test_paths() {
+        let hibernate = Hibernate::new(Duration::from_secs(60), "/srv/vm");
+        assert_eq!(hibernate.state_path(), PathBuf::from("/srv/vm/state.json"));
+        assert_eq!(hibernate.memory_path(), PathBuf::from("/srv/vm/memory.bin"));
+    }
+}
@@ -0,0 +1,310 @@
+//! Many VMs in one process.
+//!
+//! A VM per `carbon` process costs a process per VM: its threads, its
+//! mappings and its startup. A [`Host`] runs VMs side by side in the
+//! process embedding it instead, each known by an ID of its own. Every VM is
+//! built as it would be alone, with its own KVM VM, guest memory and device
+//! tree, so none can reach another's. What they share is overhead:
+//!
+//! - Their devices are served from one thread, a [`Reactor`] waiting on
+//!   every VM's event loop, rather than a thread each.
+//! - A kernel booted by VMs running together is read and decompressed
+//!   once (see [`crate::boot::KernelImage::shared`]).
+//! - Read-only disk images read by VMs running together are read through
+//!   one cache (see [`crate::devices::virtio::cache`]).
+//! - Given host cores ([`Host::with_cores`]), the host schedules the vCPUs
+//!   of all its VMs across them: each VM's vCPUs are pinned to the cores
+//!   with the fewest hosted vCPUs as it starts, and freed when it stops. A
+//!   VM whose configuration already pins its vCPUs is left as configured.
+//!
+//! Hosted VMs don't stop on SIGINT, SIGTERM or SIGHUP: signals are the
+//! embedding program's to handle, by [`Host::stop_all`] for instance. As
+//! only one VM can read stdin, a hosted VM needs a console other than
+//! stdio. Dropping the host stops its VMs and waits for them.
+
+use crate::builder::{Vmm, VmmBuilder};
+use crate::error::CarbonError;
+use crate::kvm::{Cores, CpuAffinity};
+use crate::reactor::Reactor;
+use crate::vmm::RunOutcome;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+/// VMs running in this process (see the [module docs](self)).
+pub struct Host {
+    reactor: Arc<Reactor>,
+    placement: Arc<Mutex<Placement>>,
+    vms: Mutex<BTreeMap<String, Arc<HostedVm>>>,
+}
+
+/// A VM run by a [`Host`].
+pub struct HostedVm {
+    id: String,
+    vmm: Vmm,
+    /// The host core each vCPU is pinned to, if the host placed them.
+    cores: Vec<usize>,
+    running: AtomicBool,
+    /// The thread running the VM, until waited for.
+    thread: Mutex<Option<JoinHandle<Result<RunOutcome, CarbonError>>>>,
+}
+
+/// What a host's running VMs add up to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostStats {
+    /// VMs running.
+    pub running: usize,
+    /// VMs stopped, not yet relaunched or removed.
+    pub stopped: usize,
+    /// vCPUs of the running VMs.
+    pub vcpus: u32,
+    /// Guest memory of the running VMs, in bytes.
+    pub memory: u64,
+}
+
+impl Host {
+    /// A host leaving vCPUs to the host scheduler.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            reactor: Arc::new(Reactor::new()?),
+            placement: Arc::new(Mutex::new(Placement::default())),
+            vms: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// A host pinning the vCPUs of its VMs to `cores`, spread evenly.
+    pub fn with_cores(cores: &Cores) -> io::Result<Self> {
+        let host = Self::new()?;
+        *lock(&host.placement) = Placement(cores.iter().map(|core| (core, 0)).collect());
+        Ok(host)
+    }
+
+    /// Build the VM `builder` describes and start it on a thread of its
+    /// own, as `id`. A stopped VM of the same ID is replaced.
+    pub fn launch(
+        &self,
+        id: impl Into<String>,
+        mut builder: VmmBuilder,
+    ) -> Result<Arc<HostedVm>, CarbonError> {
+        let id = id.into();
+        if id.is_empty() {
+            return Err(CarbonError::Config("a hosted VM needs an ID".into()));
+        }
+        if builder.uses_stdin() {
+            return Err(CarbonError::Config(format!(
+                "VM {id} reads stdin, which hosted VMs can't share: give it another console"
+            )));
+        }
+        let mut vms = lock(&self.vms);
+        if vms.get(&id).is_some_and(|vm| vm.is_running()) {
+            return Err(CarbonError::Config(format!("VM {id} is already running")));
+        }
+
+        let config = builder.config_mut();
+        let cores = match config.cpu_affinity.is_pinned() {
+            true => Vec::new(),
+            false => lock(&self.placement).place(config.topology.cpus()),
+        };
+        if !cores.is_empty() {
+            config.cpu_affinity = CpuAffinity::pinned(&cores);
+        }
+        let vmm = match builder.reactor(self.reactor.clone()).build() {
+            Ok(vmm) => vmm,
+            Err(e) => {
+                lock(&self.placement).release(&cores);
+                return Err(e);
+            }
+        };
+        let vm = Arc::new(HostedVm {
+            id: id.clone(),
+            vmm,
+            cores,
+            running: AtomicBool::new(true),
+            thread: Mutex::new(None),
+        });
+        let thread = thread::Builder::new().name(format!("vm-{id}")).spawn({
+            let (vm, placement) = (vm.clone(), self.placement.clone());
+            move || {
+                let outcome = vm.vmm.run();
+                match &outcome {
+                    Ok(outcome) => info!("[host] VM {} stopped: {:?}", vm.id, outcome.reason),
+                    Err(e) => warn!("[host] VM {} failed: {}", vm.id, e),
+                }
+                lock(&placement).release(&vm.cores);
+                vm.running.store(false, Ordering::SeqCst);
+                outcome
+            }
+        });
+        match thread {
+            Ok(thread) => *lock(&vm.thread) = Some(thread),
+            Err(e) => {
+                lock(&self.placement).release(&vm.cores);
+                return Err(CarbonError::Guest(format!("failed to start VM {id}: {e}")));
+            }
+        }
+        info!("[host] Started VM {} ({} hosted)", id, vms.len() + 1);
+        vms.insert(id, vm.clone());
+        Ok(vm)
+    }
+
+    /// The VM `id`, running or stopped.
+    pub fn get(&self, id: &str) -> Option<Arc<HostedVm>> {
+        lock(&self.vms).get(id).cloned()
+    }
+
+    /// Every VM, running or stopped, by ID.
+    pub fn list(&self) -> Vec<Arc<HostedVm>> {
+        lock(&self.vms).values().cloned().collect()
+    }
+
+    /// Forget the VM `id`, stopping it if it's running, and return it to
+    /// wait for.
+    pub fn remove(&self, id: &str) -> Option<Arc<HostedVm>> {
+        let vm = lock(&self.vms).remove(id)?;
+        if vm.is_running() {
+            let _ = vm.vmm.shutdown();
+        }
+        Some(vm)
+    }
+
+    /// Stop every running VM, without waiting for them.
+    pub fn stop_all(&self) {
+        for vm in lock(&self.vms).values() {
+            if vm.is_running() {
+                let _ = vm.vmm.shutdown();
+            }
+        }
+    }
+
+    pub fn stats(&self) -> HostStats {
+        let mut stats = HostStats::default();
+        for vm in lock(&self.vms).values() {
+            if vm.is_running() {
+                let config = vm.vmm.config();
+                stats.running += 1;
+                stats.vcpus += config.topology.cpus();
+                stats.memory += config.mem_size;
+            } else {
+                stats.stopped += 1;
+            }
+        }
+        stats
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        self.stop_all();
+        for vm in lock(&self.vms).values() {
+            vm.wait();
+        }
+    }
+}
+
+impl HostedVm {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The VM, to pause, snapshot or stop.
+    pub fn vmm(&self) -> &Vmm {
+        &self.vmm
+    }
+
+    /// The host core each vCPU is pinned to, empty if the host didn't
+    /// place them.
+    pub fn cores(&self) -> &[usize] {
+        &self.cores
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the VM to stop, and return how its run ended. Only the
+    /// first call gets it; later ones return `None` once it has stopped.
+    pub fn wait(&self) -> Option<Result<RunOutcome, CarbonError>> {
+        let thread = lock(&self.thread).take()?;
+        Some(
+            thread
+                .join()
+                .unwrap_or_else(|_| Err(CarbonError::Guest(format!("VM {} panicked", self.id)))),
+        )
+    }
+}
+
+/// The host cores a host pins vCPUs to, each with how many it has.
+#[derive(Debug, Default)]
+struct Placement(Vec<(usize, u32)>);
+
+impl Placement {
+    /// Place `count` vCPUs, each on the core with the fewest (the lowest
+    /// numbered of those tied). Places none without cores.
+    fn place(&mut self, count: u32) -> Vec<usize> {
+        (0..count)
+            .map_while(|_| {
+                let (core, vcpus) = self
+                    .0
+                    .iter_mut()
+                    .min_by_key(|(core, vcpus)| (*vcpus, *core))?;
+                *vcpus += 1;
+                Some(*core)
+            })
+            .collect()
+    }
+
+    /// Free the cores vCPUs were placed on.
+    fn release(&mut self, cores: &[usize]) {
+        for core in cores {
+            if let Some((_, vcpus)) = self.0.iter_mut().find(|(placed, _)| placed == core) {
+                *vcpus = vcpus.saturating_sub(1);
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConsoleConfig;
+
+    #[test]
+    fn test_placement() {
+        let mut placement = Placement(vec![(2, 0), (3, 0), (4, 0)]);
+        let first = placement.place(2);
+        assert_eq!(first, [2, 3]);
+        // The next VM starts on the idle core
+        assert_eq!(placement.place(2), [4, 2]);
+        placement.release(&first);
+        assert_eq!(placement.place(3), [3, 2, 3]);
+        assert!(Placement::default().place(2).is_empty());
+    }
+
+    #[test]
+    fn test_launch() {
+        let host = Host::with_cores(&"0".parse().unwrap()).unwrap();
+        let err = host.launch("", VmmBuilder::new("k")).err().unwrap();
+        assert!(err.to_string().contains("needs an ID"), "{err}");
+        let err = host.launch("a", VmmBuilder::new("k")).err().unwrap();
+        assert!(err.to_string().contains("stdin"), "{err}");
+
+        // A VM that fails to build stops, and its ID can be reused
+        let builder = || VmmBuilder::new("/nonexistent/kernel").serial(ConsoleConfig::Pty);
+        let vm = host.launch("a", builder().cpus(2)).unwrap();
+        assert_eq!(vm.cores(), [0, 0]);
+        assert!(vm.wait().unwrap().is_err());
+        assert!(!vm.is_running() && vm.wait().is_none());
+        assert_eq!(host.stats().stopped, 1);
+        assert_eq!(lock(&host.placement).0, [(0, 0)]);
+        let vm = host.launch("a", builder()).unwrap();
+        assert!(vm.wait().unwrap().is_err());
+        assert_eq!(host.list().len(), 1);
+        assert!(host.remove("a").is_some() && host.get("a").is_none());
+    }
+}
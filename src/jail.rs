@@ -0,0 +1,770 @@
+//! Running `carbon` in a jail (`carbon jail`).
+//!
+//! A guest that escapes into the VMM gets whatever the VMM process has. The
+//! jail takes away nearly all of it before Carbon starts, in the manner of
+//! Firecracker's jailer:
+//!
+//! ```text
+//! carbon jail (root) ── prepare BASE/carbon/ID/root ── clone ──► carbon (pid 1)
+//!   │                     carbon, /dev/kvm,               │ new user, mount, pid
+//!   │                     /dev/net/tun, /dev/urandom      │ and net namespaces
+//!   │                                                     │ --bind mounts, /proc
+//!   ├─ uid_map, gid_map ─────────────────────────────────►│ pivot_root into root
+//!   │                                                     │ --uid, --gid
+//!   │                                                     │ no capabilities
+//!   ├─ forwards SIGTERM, SIGHUP ─────────────────────────►│ exec /carbon ARGS
+//!   exits with its exit code
+//! ```
+//!
+//! The jail's root holds a copy of the `carbon` binary (with its loader and
+//! shared libraries, if it isn't static), the device nodes it needs (owned
+//! by `--uid`) and an empty placeholder for each `--bind`,
+//! which is mounted over it in the jail's mount namespace only. Nothing
+//! else of the host's filesystem is visible, so the jailed command takes
+//! paths as they are inside the jail:
+//!
+//! ```text
+//! carbon jail --id vm1 --uid 1000 --gid 1000 --bind bzImage --bind-ro rootfs.ext4 \
+//!     -- run --kernel /bzImage --disk /rootfs.ext4
+//! ```
+//!
+//! Carbon runs as `--uid`/`--gid`, mapped to the same ids in its user
+//! namespace, with an empty capability bounding set and `no_new_privs`, so
+//! neither it nor anything it execs can regain a capability. It gets an
+//! empty network namespace of its own, or joins `--netns` (e.g.
+//! `/var/run/netns/vm1`), where its tap devices must already exist, owned
+//! by `--uid`. Only `CARBON_*` variables are passed through the
+//! environment, with `CARBON_VM_ID` set to the jail's `--id`, and
+//! descriptors other than stdio are closed.
+//!
+//! Files Carbon writes (sockets, snapshots) stay in the jail's root when it
+//! exits. A root left by an earlier jail with the same `--id` must be
+//! removed first: the jail never reuses one.
+//!
+//! The jail must be set up as root, and before Carbon starts any other
+//! thread, as a new user namespace needs a single-threaded process.
+
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{chown, DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+
+/// Directory jails go under unless `--chroot-base` says otherwise.
+pub const DEFAULT_BASE: &str = "/srv/jailer";
+
+/// Longest `--id` allowed.
+const MAX_ID_LEN: usize = 64;
+
+/// The Carbon binary, as seen inside the jail.
+const JAILED_BINARY: &str = "/carbon";
+
+/// Device nodes copied into the jail, if the host has them.
+const DEVICES: [&str; 3] = ["/dev/kvm", "/dev/net/tun", "/dev/urandom"];
+
+/// Signals the jail waits for: the ones it forwards, and its child's exit.
+const FORWARDED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGCHLD];
+
+/// How to jail Carbon.
+#[derive(Debug, Clone)]
+pub struct JailConfig {
+    /// Names the jail: its root is `BASE/carbon/ID/root`.
+    pub id: String,
+    /// Directory the jail's root is created under.
+    pub base: PathBuf,
+    /// User Carbon runs as, inside the jail and out.
+    pub uid: u32,
+    /// Group Carbon runs as.
+    pub gid: u32,
+    /// Host files and directories visible in the jail.
+    pub binds: Vec<Bind>,
+    /// Network namespace to join instead of a new, empty one.
+    pub netns: Option<PathBuf>,
+}
+
+/// A host file or directory mounted into the jail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bind {
+    pub source: PathBuf,
+    /// Where it appears, relative to the jail's root.
+    pub target: PathBuf,
+    pub read_only: bool,
+}
+
+impl Bind {
+    /// Parse `SOURCE[:TARGET]`; the target defaults to `/` and the source's
+    /// file name.
+    pub fn parse(spec: &str, read_only: bool) -> Result<Self, String> {
+        let (source, target) = match spec.split_once(':') {
+            Some((source, target)) => (Path::new(source), PathBuf::from(target)),
+            None => {
+                let source = Path::new(spec);
+                let name = source
+                    .file_name()
+                    .ok_or_else(|| format!("{spec} has no file name to mount it at"))?;
+                (source, Path::new("/").join(name))
+            }
+        };
+        if source.as_os_str().is_empty() {
+            return Err(format!("{spec} has no source"));
+        }
+        if !target.is_absolute()
+            || target
+                .components()
+                .any(|c| matches!(c, Component::ParentDir | Component::CurDir))
+            || target == Path::new("/")
+        {
+            return Err(format!(
+                "{} isn't an absolute path below the jail's root",
+                target.display()
+            ));
+        }
+        Ok(Self {
+            source: source.to_path_buf(),
+            target,
+            read_only,
+        })
+    }
+}
+
+impl JailConfig {
+    /// The jail's root directory on the host.
+    pub fn root(&self) -> PathBuf {
+        self.base.join("carbon").join(&self.id).join("root")
+    }
+}
+
+/// Check that `id` can name a jail: letters, digits and `-` only.
+pub fn validate_id(id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        return Err(format!("must be 1 to {MAX_ID_LEN} characters long"));
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err("may only contain letters, digits and '-'".into());
+    }
+    Ok(())
+}
+
+/// Run Carbon with `args` in a jail (see the [module docs](self)) and wait
+/// for it, returning its exit code: its own, or 128+N if signal N killed it.
+pub fn run(config: &JailConfig, args: &[OsString]) -> io::Result<u8> {
+    // SAFETY: geteuid has no preconditions.
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "setting up a jail needs root",
+        ));
+    }
+    let root = config.root();
+    prepare(config, &root)?;
+    let jailed = Jailed::new(config, &root, args)?;
+    if let Some(path) = &config.netns {
+        let netns = File::open(path).map_err(|e| annotate(e, "network namespace", path))?;
+        // SAFETY: `netns` is an open namespace file; only this thread moves.
+        if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } == -1 {
+            return Err(annotate(
+                io::Error::last_os_error(),
+                "network namespace",
+                path,
+            ));
+        }
+    }
+
+    let (mut status_read, status_write) = pipe()?;
+    let (maps_read, mut maps_write) = pipe()?;
+    // Signals for the jailed Carbon wait here until it runs
+    let forwarded = signal_set(&FORWARDED_SIGNALS);
+    let mut old_mask = empty_signal_set();
+    // SAFETY: both sets are initialized.
+    unsafe { libc::sigprocmask(libc::SIG_BLOCK, &forwarded, &mut old_mask) };
+
+    // The user namespace comes last, from the child: made here, it would
+    // own the mount namespace, and the child couldn't mount host files
+    let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+    if config.netns.is_none() {
+        flags |= libc::CLONE_NEWNET;
+    }
+    // SAFETY: with no stack given, clone copies this (single-threaded)
+    // process like fork; the child never returns from `enter`.
+    let pid = unsafe {
+        libc::syscall(
+            libc::SYS_clone,
+            (flags | libc::SIGCHLD) as libc::c_ulong,
+            0,
+            0,
+            0,
+            0,
+        )
+    };
+    match pid {
+        -1 => {
+            let e = io::Error::last_os_error();
+            // SAFETY: restores the mask saved above.
+            unsafe { libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut()) };
+            return Err(io::Error::new(
+                e.kind(),
+                format!("creating namespaces: {e}"),
+            ));
+        }
+        0 => {
+            drop(status_read);
+            drop(maps_write);
+            jailed.enter(maps_read, status_write, &old_mask)
+        }
+        _ => {}
+    }
+    let pid = pid as libc::pid_t;
+    drop(status_write);
+    drop(maps_read);
+
+    // A zero byte once the child has its user namespace; then nothing (the
+    // pipe closed on exec) or its error
+    let mut answer = Vec::new();
+    let mut mapped = Ok(());
+    let mut first = [0u8];
+    if status_read.read(&mut first)? == 1 {
+        if first[0] == 0 {
+            mapped = write_id_maps(pid, config.uid, config.gid);
+            if mapped.is_ok() {
+                let _ = maps_write.write_all(&[0]);
+            }
+        } else {
+            answer.push(first[0]);
+        }
+    } else {
+        answer.extend_from_slice(b"carbon exited while entering the jail");
+    }
+    drop(maps_write);
+    status_read.read_to_end(&mut answer)?;
+    let code = supervise(pid);
+    // SAFETY: restores the mask saved above.
+    unsafe { libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut()) };
+    mapped?;
+    if !answer.is_empty() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&answer).into_owned(),
+        ));
+    }
+    code
+}
+
+/// Create the jail's root, with Carbon, its devices and a placeholder for
+/// each bind mount.
+fn prepare(config: &JailConfig, root: &Path) -> io::Result<()> {
+    fs::create_dir_all(root.parent().expect("root has a parent"))?;
+    fs::DirBuilder::new()
+        .mode(0o755)
+        .create(root)
+        .map_err(|e| annotate(e, "jail root", root))?;
+    chown(root, Some(config.uid), Some(config.gid))?;
+
+    let binary = root.join(&JAILED_BINARY[1..]);
+    fs::copy("/proc/self/exe", &binary).map_err(|e| annotate(e, "copying carbon to", &binary))?;
+    fs::set_permissions(&binary, fs::Permissions::from_mode(0o755))?;
+    for library in shared_libraries()? {
+        let copy = root.join(library.strip_prefix("/").expect("absolute library path"));
+        fs::create_dir_all(copy.parent().expect("library has a parent"))?;
+        fs::copy(&library, &copy).map_err(|e| annotate(e, "copying", &library))?;
+    }
+
+    for device in DEVICES {
+        let Ok(metadata) = fs::metadata(device) else {
+            continue;
+        };
+        if !metadata.file_type().is_char_device() {
+            continue;
+        }
+        let node = root.join(&device[1..]);
+        fs::create_dir_all(node.parent().expect("device has a parent"))?;
+        let path = c_path(&node)?;
+        // SAFETY: `path` is a valid C string; mknod makes a node like the
+        // host's, with the same device number.
+        if unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o600, metadata.rdev()) } == -1 {
+            return Err(annotate(io::Error::last_os_error(), "device node", &node));
+        }
+        chown(&node, Some(config.uid), Some(config.gid))?;
+    }
+
+    for bind in &config.binds {
+        let is_dir = fs::metadata(&bind.source)
+            .map_err(|e| annotate(e, "bind source", &bind.source))?
+            .is_dir();
+        let target = root.join(bind.target.strip_prefix("/").expect("absolute target"));
+        if is_dir {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::create_dir_all(target.parent().expect("target has a parent"))?;
+            File::create(&target)?;
+        }
+    }
+    fs::create_dir(root.join("proc"))?;
+    Ok(())
+}
+
+/// What a dynamically linked Carbon needs to start in the jail: its ELF
+/// interpreter, and the shared libraries this process has loaded. None for
+/// a static one.
+fn shared_libraries() -> io::Result<Vec<PathBuf>> {
+    let mut libraries: Vec<PathBuf> = interpreter(&fs::read("/proc/self/exe")?)
+        .into_iter()
+        .collect();
+    for line in fs::read_to_string("/proc/self/maps")?.lines() {
+        // ADDRESS PERMS OFFSET DEV INODE PATH
+        let Some(path) = line.split_whitespace().nth(5) else {
+            continue;
+        };
+        let path = Path::new(path);
+        let is_library = path
+            .file_name()
+            .is_some_and(|name| name.as_bytes().windows(3).any(|w| w == b".so"));
+        if path.is_absolute() && is_library && !libraries.iter().any(|l| l == path) {
+            libraries.push(path.to_path_buf());
+        }
+    }
+    Ok(libraries)
+}
+
+/// The `PT_INTERP` path of a 64-bit little-endian ELF image, if it has one.
+fn interpreter(elf: &[u8]) -> Option<PathBuf> {
+    const PT_INTERP: u32 = 3;
+    let u16_at = |at: usize| Some(u16::from_le_bytes(elf.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_le_bytes(elf.get(at..at + 4)?.try_into().ok()?));
+    let u64_at = |at: usize| Some(u64::from_le_bytes(elf.get(at..at + 8)?.try_into().ok()?));
+
+    if elf.get(..5)? != b"\x7fELF\x02" {
+        return None;
+    }
+    let phoff = u64_at(0x20)? as usize;
+    let phentsize = u16_at(0x36)? as usize;
+    for i in 0..u16_at(0x38)? as usize {
+        let header = phoff + i * phentsize;
+        if u32_at(header)? != PT_INTERP {
+            continue;
+        }
+        let offset = u64_at(header + 0x08)? as usize;
+        let size = u64_at(header + 0x20)? as usize;
+        let path = elf.get(offset..offset + size)?;
+        let path = path.strip_suffix(b"\0").unwrap_or(path);
+        return Some(PathBuf::from(OsStr::from_bytes(path)));
+    }
+    None
+}
+
+/// Everything the jailed child needs, made before cloning it.
+struct Jailed {
+    root: CString,
+    proc: CString,
+    /// Each bind's source, where it goes, and if it is read-only.
+    binds: Vec<(CString, CString, bool)>,
+    uid: u32,
+    gid: u32,
+    binary: CString,
+    argv: Vec<CString>,
+    envp: Vec<CString>,
+}
+
+impl Jailed {
+    fn new(config: &JailConfig, root: &Path, args: &[OsString]) -> io::Result<Self> {
+        let binds = config
+            .binds
+            .iter()
+            .map(|bind| {
+                let target = root.join(bind.target.strip_prefix("/").expect("absolute target"));
+                Ok((c_path(&bind.source)?, c_path(&target)?, bind.read_only))
+            })
+            .collect::<io::Result<_>>()?;
+        let argv = std::iter::once(OsStr::new("carbon"))
+            .chain(args.iter().map(OsString::as_os_str))
+            .map(c_string)
+            .collect::<io::Result<_>>()?;
+        let envp = std::env::vars_os()
+            .filter(|(name, _)| name.as_bytes().starts_with(b"CARBON_") && name != "CARBON_VM_ID")
+            .map(|(name, value)| {
+                let mut var = name;
+                var.push("=");
+                var.push(value);
+                c_string(&var)
+            })
+            .chain(std::iter::once(c_string(OsStr::new(&format!(
+                "CARBON_VM_ID={}",
+                config.id
+            )))))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            root: c_path(root)?,
+            proc: c_path(&root.join("proc"))?,
+            binds,
+            uid: config.uid,
+            gid: config.gid,
+            binary: c_path(Path::new(JAILED_BINARY))?,
+            argv,
+            envp,
+        })
+    }
+
+    /// In the cloned child: move into the jail's root, take the user
+    /// namespace, and once its ids are mapped, drop privileges and exec
+    /// Carbon. Reports a failure on `status` and exits.
+    fn enter(self, mut maps: File, mut status: File, mask: &libc::sigset_t) -> ! {
+        let result = self.enter_root().and_then(|()| {
+            // SAFETY: unshare has no memory-safety preconditions.
+            check("creating a user namespace", unsafe {
+                libc::unshare(libc::CLONE_NEWUSER)
+            })?;
+            status.write_all(&[0])?;
+            let mut mapped = [0u8];
+            if maps.read_exact(&mut mapped).is_err() {
+                // The jail already has the error
+                // SAFETY: as below.
+                unsafe { libc::_exit(1) };
+            }
+            self.drop_privileges(mask)?;
+            self.exec()
+        });
+        if let Err(e) = result {
+            let _ = status.write_all(e.to_string().as_bytes());
+        }
+        // SAFETY: the child exits without unwinding or running destructors.
+        unsafe { libc::_exit(1) }
+    }
+
+    /// Mount the binds and `/proc`, and make the jail's root the root of
+    /// this mount namespace, with the host's detached.
+    fn enter_root(&self) -> io::Result<()> {
+        let null = std::ptr::null::<libc::c_char>();
+        // SAFETY (all of the below): every pointer is a valid C string or
+        // null where the call allows it; this child is pid 1 of its own
+        // mount namespace, changing only that.
+        unsafe {
+            check(
+                "making mounts private",
+                libc::mount(
+                    null,
+                    c"/".as_ptr(),
+                    null,
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ),
+            )?;
+            check(
+                "mounting the jail root",
+                libc::mount(
+                    self.root.as_ptr(),
+                    self.root.as_ptr(),
+                    null,
+                    libc::MS_BIND | libc::MS_REC,
+                    std::ptr::null(),
+                ),
+            )?;
+            for (source, target, read_only) in &self.binds {
+                check(
+                    "bind mount",
+                    libc::mount(
+                        source.as_ptr(),
+                        target.as_ptr(),
+                        null,
+                        libc::MS_BIND | libc::MS_REC,
+                        std::ptr::null(),
+                    ),
+                )?;
+                if *read_only {
+                    // A remount has to keep the flags the mount is locked with
+                    let mut stat: libc::statvfs = std::mem::zeroed();
+                    check("bind mount", libc::statvfs(target.as_ptr(), &mut stat))?;
+                    check(
+                        "read-only bind mount",
+                        libc::mount(
+                            null,
+                            target.as_ptr(),
+                            null,
+                            libc::MS_BIND
+                                | libc::MS_REMOUNT
+                                | libc::MS_RDONLY
+                                | locked_flags(stat.f_flag),
+                            std::ptr::null(),
+                        ),
+                    )?;
+                }
+            }
+            check(
+                "mounting /proc",
+                libc::mount(
+                    c"proc".as_ptr(),
+                    self.proc.as_ptr(),
+                    c"proc".as_ptr(),
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                    std::ptr::null(),
+                ),
+            )?;
+
+            // The old root is stacked under the new one, then detached
+            check("entering the jail root", libc::chdir(self.root.as_ptr()))?;
+            check(
+                "pivot_root",
+                libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int,
+            )?;
+            check(
+                "detaching the host's root",
+                libc::umount2(c".".as_ptr(), libc::MNT_DETACH),
+            )?;
+            check("entering the jail root", libc::chdir(c"/".as_ptr()))?;
+        }
+        Ok(())
+    }
+
+    /// Become `uid` and `gid` with no capabilities, for good.
+    fn drop_privileges(&self, mask: &libc::sigset_t) -> io::Result<()> {
+        // SAFETY (all of the below): these calls change only this process's
+        // credentials and attributes; every pointer is valid or null where
+        // the call allows it.
+        unsafe {
+            for cap in 0.. {
+                if libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) == -1 {
+                    if io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+                        break;
+                    }
+                    check("dropping capabilities", -1)?;
+                }
+            }
+            check(
+                "dropping capabilities",
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                    0,
+                    0,
+                    0,
+                ),
+            )?;
+            check("setgroups", libc::setgroups(0, std::ptr::null()))?;
+            check("setresgid", libc::setresgid(self.gid, self.gid, self.gid))?;
+            check("setresuid", libc::setresuid(self.uid, self.uid, self.uid))?;
+            // Running as root (uid 0) keeps capabilities past setresuid
+            let header = CapHeader {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let data = [CapData::default(); 2];
+            check(
+                "dropping capabilities",
+                libc::syscall(libc::SYS_capset, &header, data.as_ptr()) as libc::c_int,
+            )?;
+            check(
+                "no_new_privs",
+                libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0),
+            )?;
+            // After setresuid, which clears it: the jail going takes Carbon
+            check(
+                "parent death signal",
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0),
+            )?;
+            check(
+                "closing descriptors",
+                libc::syscall(
+                    libc::SYS_close_range,
+                    3,
+                    libc::c_uint::MAX,
+                    libc::CLOSE_RANGE_CLOEXEC,
+                ) as libc::c_int,
+            )?;
+            libc::sigprocmask(libc::SIG_SETMASK, mask, std::ptr::null_mut());
+        }
+        Ok(())
+    }
+
+    fn exec(&self) -> io::Result<()> {
+        let argv: Vec<_> = self
+            .argv
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect();
+        let envp: Vec<_> = self
+            .envp
+            .iter()
+            .map(|var| var.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect();
+        // SAFETY: both arrays are null-terminated lists of C strings that
+        // outlive the call.
+        unsafe { libc::execve(self.binary.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+        let e = io::Error::last_os_error();
+        Err(io::Error::new(
+            e.kind(),
+            format!("exec {JAILED_BINARY}: {e}"),
+        ))
+    }
+}
+
+/// Map `uid` and `gid` to themselves in the user namespace of `pid`.
+fn write_id_maps(pid: libc::pid_t, uid: u32, gid: u32) -> io::Result<()> {
+    fs::write(format!("/proc/{pid}/uid_map"), format!("{uid} {uid} 1\n"))
+        .and_then(|()| fs::write(format!("/proc/{pid}/gid_map"), format!("{gid} {gid} 1\n")))
+        .map_err(|e| io::Error::new(e.kind(), format!("mapping user and group ids: {e}")))
+}
+
+/// Wait for the jailed Carbon to exit, passing on the signals sent to the
+/// jail. Signals from the terminal reach Carbon directly, as it is in the
+/// same process group, so only those sent with `kill` are passed on.
+fn supervise(pid: libc::pid_t) -> io::Result<u8> {
+    let set = signal_set(&FORWARDED_SIGNALS);
+    loop {
+        // SAFETY: siginfo_t is plain data, filled in by sigwaitinfo.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        // SAFETY: `set` is initialized and `info` is writable.
+        let signal = unsafe { libc::sigwaitinfo(&set, &mut info) };
+        if signal == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if signal != libc::SIGCHLD {
+            if info.si_code <= libc::SI_USER {
+                // SAFETY: `pid` is our child, not yet reaped.
+                unsafe { libc::kill(pid, signal) };
+            }
+            continue;
+        }
+        let mut status = 0;
+        // SAFETY: `status` is writable.
+        match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+            0 => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            _ if libc::WIFEXITED(status) => return Ok(libc::WEXITSTATUS(status) as u8),
+            _ if libc::WIFSIGNALED(status) => return Ok(128 + libc::WTERMSIG(status) as u8),
+            _ => continue,
+        }
+    }
+}
+
+/// Turn a syscall's -1 into the error, saying what failed.
+fn check(what: &str, result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => {
+            let e = io::Error::last_os_error();
+            Err(io::Error::new(e.kind(), format!("{what}: {e}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `MS_*` flags a remount must keep, from `statvfs` flags.
+fn locked_flags(flags: libc::c_ulong) -> libc::c_ulong {
+    [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ]
+    .into_iter()
+    .filter(|(st, _)| flags & st != 0)
+    .fold(0, |ms, (_, flag)| ms | flag)
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// `struct __user_cap_header_struct`.
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct`; version 3 takes two.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+fn signal_set(signals: &[libc::c_int]) -> libc::sigset_t {
+    let mut set = empty_signal_set();
+    for &signal in signals {
+        // SAFETY: `set` is initialized.
+        unsafe { libc::sigaddset(&mut set, signal) };
+    }
+    set
+}
+
+fn empty_signal_set() -> libc::sigset_t {
+    // SAFETY: sigemptyset initializes the set it is given.
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        set
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    c_string(path.as_os_str())
+}
+
+fn c_string(s: &OsStr) -> io::Result<CString> {
+    CString::new(s.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn annotate(e: io::Error, what: &str, path: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{what} {}: {e}", path.display()))
+}
+
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds: [RawFd; 2] = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe2 returns.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just opened both descriptors, owned by nothing else.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_parse() {
+        assert_eq!(
+            Bind::parse("images/rootfs.ext4", true).unwrap(),
+            Bind {
+                source: "images/rootfs.ext4".into(),
+                target: "/rootfs.ext4".into(),
+                read_only: true,
+            }
+        );
+        let bind = Bind::parse("/srv/kernels/6.6:/boot/kernel", false).unwrap();
+        assert_eq!(bind.source, Path::new("/srv/kernels/6.6"));
+        assert_eq!(bind.target, Path::new("/boot/kernel"));
+        assert!(Bind::parse("disk.img:relative", false).is_err());
+        assert!(Bind::parse("disk.img:/../escape", false).is_err());
+        assert!(Bind::parse("disk.img:/", false).is_err());
+        assert!(Bind::parse(":/disk.img", false).is_err());
+    }
+
+    #[test]
+    fn test_interpreter() {
+        // What a dynamically linked test binary was built with
+        let exe = fs::read("/proc/self/exe").unwrap();
+        if let Some(path) = interpreter(&exe) {
+            assert!(path.is_absolute(), "{}", path.display());
+        }
+        assert_eq!(interpreter(b"#!/bin/sh\n"), None);
+        assert_eq!(interpreter(b"\x7fELF\x02"), None);
+    }
+
+    #[test]
+    fn test_validate_id() {
+        assert!(validate_id("vm-1").is_ok());
+        assert!(validate_id("").is_err());
+        assert!(validate_id("../vm").is_err());
+        assert!(validate_id(&"a".repeat(MAX_ID_LEN + 1)).is_err());
+    }
+}
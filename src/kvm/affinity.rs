@@ -0,0 +1,204 @@
+//! Pinning vCPU threads to host cores (`--cpu-affinity`).
+//!
+//! Left alone, the host scheduler moves vCPU threads between cores as it
+//! sees fit. Pinning each to cores of its own keeps its caches warm and
+//! keeps it from waiting behind unrelated work, which tightens tail latency.
+//! Cores are written as a list of numbers and ranges, e.g. `2,3` or `4-7`:
+//!
+//! - A list with one core per vCPU gives each vCPU its own: with two vCPUs,
+//!   `--cpu-affinity 2,3` pins vCPU 0 to core 2 and vCPU 1 to core 3.
+//! - Any other list is shared: `--cpu-affinity 4-7` lets every vCPU run on
+//!   any of cores 4 to 7.
+//! - A profile can instead list each vCPU's cores, one entry per vCPU:
+//!   `cpu_affinity = ["2", "3-4"]`.
+//!
+//! Only the vCPU threads are pinned; the main thread, serving devices, runs
+//! wherever the scheduler puts it.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// A set of host cores.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cores(Vec<usize>);
+
+impl Cores {
+    /// The cores, in order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Restrict the calling thread to these cores.
+    pub fn pin_current_thread(&self) -> io::Result<()> {
+        // SAFETY: cpu_set_t is plain old data; all zeroes is the empty set.
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &core in &self.0 {
+            // SAFETY: parsing keeps cores below CPU_SETSIZE, inside the set.
+            unsafe { libc::CPU_SET(core, &mut set) };
+        }
+        // SAFETY: `set` is a valid cpu_set_t of the size passed.
+        let result =
+            unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cores {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let core = |n: &str| {
+            n.parse::<usize>()
+                .ok()
+                .filter(|&n| n < libc::CPU_SETSIZE as usize)
+                .ok_or_else(|| format!("invalid host core {n:?}"))
+        };
+        let mut cores = Vec::new();
+        for part in s.split(',') {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let (first, last) = (core(first)?, core(last)?);
+            if first > last {
+                return Err(format!("invalid core range {part:?}"));
+            }
+            cores.extend(first..=last);
+        }
+        cores.sort_unstable();
+        cores.dedup();
+        Ok(Self(cores))
+    }
+}
+
+impl fmt::Display for Cores {
+    /// Runs of consecutive cores are written as ranges: `0,2-3`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut i = 0;
+        while i < self.0.len() {
+            let first = self.0[i];
+            while i + 1 < self.0.len() && self.0[i + 1] == self.0[i] + 1 {
+                i += 1;
+            }
+            let sep = if first == self.0[0] { "" } else { "," };
+            match self.0[i] {
+                last if last == first => write!(f, "{sep}{first}")?,
+                last => write!(f, "{sep}{first}-{last}")?,
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+/// The host cores each vCPU thread runs on. By default no vCPU is pinned.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuAffinity(Vec<Cores>);
+
+impl CpuAffinity {
+    /// Resolve `--cpu-affinity` for `cpus` vCPUs: a core each if `spec`
+    /// lists one per vCPU, every core listed for each otherwise.
+    pub fn resolve(cpus: u32, spec: &str) -> Result<Self, String> {
+        let cores: Cores = spec.parse()?;
+        if cores.0.len() == cpus as usize {
+            return Ok(Self(
+                cores.0.into_iter().map(|core| Cores(vec![core])).collect(),
+            ));
+        }
+        Ok(Self(vec![cores; cpus as usize]))
+    }
+
+    /// Each vCPU on a core of its own: vCPU `n` on `cores[n]`.
+    pub fn pinned(cores: &[usize]) -> Self {
+        Self(cores.iter().map(|&core| Cores(vec![core])).collect())
+    }
+
+    /// Each of `cpus` vCPUs' cores, as listed in `specs`.
+    pub fn per_vcpu(cpus: u32, specs: &[String]) -> Result<Self, String> {
+        if specs.len() != cpus as usize {
+            return Err(format!(
+                "{} entries for {} vCPUs (expected one per vCPU)",
+                specs.len(),
+                cpus
+            ));
+        }
+        specs
+            .iter()
+            .map(|spec| spec.parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Whether any vCPU is pinned.
+    pub fn is_pinned(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// The cores vCPU `index` may run on, `None` if it isn't pinned.
+    pub fn vcpu(&self, index: usize) -> Option<&Cores> {
+        self.0.get(index)
+    }
+}
+
+impl fmt::Display for CpuAffinity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, cores) in self.0.iter().enumerate() {
+            let sep = if index == 0 { "" } else { " " };
+            write!(f, "{sep}vcpu{index}={cores}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cores() {
+        let cores: Cores = "5,0,2-3,3".parse().unwrap();
+        assert_eq!(cores, Cores(vec![0, 2, 3, 5]));
+        assert_eq!(cores.to_string(), "0,2-3,5");
+        assert_eq!("4-7".parse::<Cores>().unwrap().to_string(), "4-7");
+
+        assert!("".parse::<Cores>().is_err());
+        assert!("3-1".parse::<Cores>().is_err());
+        assert!("1,,2".parse::<Cores>().is_err());
+        assert!("-1".parse::<Cores>().is_err());
+        assert!("100000".parse::<Cores>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        // One core per vCPU: a core each
+        let affinity = CpuAffinity::resolve(2, "2,3").unwrap();
+        assert_eq!(affinity.to_string(), "vcpu0=2 vcpu1=3");
+
+        // Otherwise the cores are shared
+        let affinity = CpuAffinity::resolve(2, "4-7").unwrap();
+        assert_eq!(affinity.to_string(), "vcpu0=4-7 vcpu1=4-7");
+        assert_eq!(affinity.vcpu(1), Some(&Cores(vec![4, 5, 6, 7])));
+        assert_eq!(affinity.vcpu(2), None);
+
+        let specs = ["2".to_string(), "3-4".to_string()];
+        let affinity = CpuAffinity::per_vcpu(2, &specs).unwrap();
+        assert_eq!(affinity.to_string(), "vcpu0=2 vcpu1=3-4");
+        assert!(CpuAffinity::per_vcpu(3, &specs).is_err());
+        assert!(!CpuAffinity::default().is_pinned());
+    }
+
+    #[test]
+    fn test_pin_current_thread() {
+        std::thread::spawn(|| {
+            // SAFETY: sched_getcpu has no preconditions.
+            let core = unsafe { libc::sched_getcpu() } as usize;
+            Cores(vec![core]).pin_current_thread().unwrap();
+            // SAFETY: as above.
+            assert_eq!(unsafe { libc::sched_getcpu() } as usize, core);
+        })
+        .join()
+        .unwrap();
+    }
+}
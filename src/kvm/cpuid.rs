@@ -0,0 +1,756 @@
+//! CPUID policy applied to guest vCPUs.
+//!
+//! KVM reports every CPU feature it is able to virtualize on the current host.
+//! Passing that list straight through gives the guest the best performance,
+//! but it ties the guest to the exact host CPU: a snapshot taken on a host
+//! with AVX-512 will crash when restored on a host without it.
+//!
+//! # CPU Modes
+//!
+//! - **Host**: Expose everything KVM supports (maximum performance).
+//! - **Portable**: The x86-64 baseline every 64-bit host has (SSE2, CMOV,
+//!   CX8), for snapshots that must restore anywhere.
+//! - **Baseline**: Expose a curated, stable subset roughly matching the
+//!   x86-64-v2 microarchitecture level (SSE4.2, POPCNT, CX16, AES-NI).
+//!   Guests see the same feature set on any reasonably modern host, which
+//!   makes snapshots and clones portable between machines.
+//! - **x86-64-v3**: Baseline plus AVX, AVX2, BMI1/2, FMA, F16C, LZCNT and
+//!   MOVBE, for hosts from Haswell and Excavator on.
+//!
+//! The templates work by masking the feature leaves KVM returns, so a
+//! feature is only ever exposed if it is present in both the template and
+//! the host. Each keeps the extended XSAVE state (leaf 0xd) its features
+//! need, and no other.
+//!
+//! # Feature Overrides
+//!
+//! `--cpu-features +avx2,-rdtscp` adjusts the mode's feature set, naming
+//! features as `/proc/cpuinfo` does. `+` exposes a feature the mode hides,
+//! still only if the host has it; `-` hides one. The XSAVE state follows: hiding `avx512f` drops the AVX-512 state
+//! components, and exposing `avx` on a mode without it brings its state
+//! back. A feature only makes sense with what it builds on: `+avx` wants
+//! `xsave` too, which `portable` hides.
+//!
+//! # Nested Virtualization
+//!
+//! VMX and SVM are hidden in every mode unless `--enable-nested` is given,
+//! which exposes whichever the host has on top of the mode, as `+vmx` or
+//! `+svm` would. A guest that runs a hypervisor of its own drives far more
+//! of the host's KVM, so it has to be asked for.
+//!
+//! # Paravirtual Clock
+//!
+//! Every mode keeps KVM's paravirtual leaves (0x4000_0000+) untouched, in
+//! particular kvm-clock ([`KVM_FEATURE_CLOCKSOURCE2`]). kvm-clock is what lets
+//! a guest use the `ptp_kvm` driver: `/dev/ptp0` is backed by the
+//! `KVM_HC_CLOCK_PAIRING` hypercall, which KVM answers in the host kernel with
+//! a (host realtime, guest TSC) pair, so guests can discipline their clock to
+//! the host's with sub-microsecond error, e.g. with chrony:
+//!
+//! ```text
+//! refclock PHC /dev/ptp0 poll 2
+//! ```
+//!
+//! KVM only answers the hypercall while the host clocksource is `tsc`; see
+//! [`super::host::ptp_kvm_status`].
+
+use kvm_bindings::kvm_cpuid_entry2;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// How much of the host CPU to expose to the guest: everything, or one of
+/// the named templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, Serialize, Deserialize)]
+pub enum CpuMode {
+    /// Expose every feature KVM supports on this host.
+    #[default]
+    Host,
+    /// Expose only the x86-64 baseline, for snapshots that restore anywhere.
+    #[value(alias = "x86-64")]
+    Portable,
+    /// Expose a stable x86-64-v2 subset for snapshot portability.
+    #[value(alias = "x86-64-v2")]
+    Baseline,
+    /// Expose the x86-64-v3 subset: baseline plus AVX2, BMI and FMA.
+    #[value(name = "x86-64-v3")]
+    X86_64V3,
+}
+
+/// Leaf 0x1 EDX features kept in baseline mode.
+///
+/// FPU, VME, DE, PSE, TSC, MSR, PAE, MCE, CX8, APIC, SEP, MTRR, PGE, MCA,
+/// CMOV, PAT, PSE36, CLFSH, MMX, FXSR, SSE, SSE2.
+const BASELINE_1_EDX: u32 = 0x078b_fbff;
+
+/// Leaf 0x1 ECX features kept in baseline mode.
+///
+/// SSE3 (0), PCLMULQDQ (1), SSSE3 (9), CX16 (13), SSE4.1 (19), SSE4.2 (20),
+/// x2APIC (21), POPCNT (23), TSC-deadline (24), AES (25), XSAVE (26),
+/// OSXSAVE (27), HYPERVISOR (31).
+const BASELINE_1_ECX: u32 = (1 << 0)
+    | (1 << 1)
+    | (1 << 9)
+    | (1 << 13)
+    | (1 << 19)
+    | (1 << 20)
+    | (1 << 21)
+    | (1 << 23)
+    | (1 << 24)
+    | (1 << 25)
+    | (1 << 26)
+    | (1 << 27)
+    | (1 << 31);
+
+/// Leaf 0x1 ECX features kept in portable mode: none of the instruction
+/// set extensions, only x2APIC (21), TSC-deadline (24) and HYPERVISOR (31).
+const PORTABLE_1_ECX: u32 = (1 << 21) | (1 << 24) | (1 << 31);
+
+/// Leaf 0x1 ECX features x86-64-v3 adds to baseline: FMA (12), MOVBE (22),
+/// AVX (28), F16C (29).
+const V3_1_ECX: u32 = BASELINE_1_ECX | (1 << 12) | (1 << 22) | (1 << 28) | (1 << 29);
+
+/// Leaf 0x7 subleaf 0 EBX features kept in baseline mode (FSGSBASE only).
+const BASELINE_7_EBX: u32 = 1 << 0;
+
+/// Leaf 0x7 subleaf 0 EBX features x86-64-v3 adds: BMI1 (3), AVX2 (5),
+/// BMI2 (8).
+const V3_7_EBX: u32 = BASELINE_7_EBX | (1 << 3) | (1 << 5) | (1 << 8);
+
+/// Leaf 0x7 subleaf 0 EDX features kept in baseline mode.
+///
+/// These are speculative-execution mitigation bits (MD_CLEAR, IBRS/IBPB,
+/// STIBP, L1D_FLUSH, ARCH_CAPABILITIES, SSBD). Hiding them would silently
+/// disable guest mitigations, which is never the right portability tradeoff.
+const BASELINE_7_EDX: u32 = (1 << 10) | (1 << 26) | (1 << 27) | (1 << 28) | (1 << 29) | (1 << 31);
+
+/// Leaf 0x80000001 ECX features kept in baseline mode (LAHF_LM, PREFETCHW).
+const BASELINE_EXT_ECX: u32 = (1 << 0) | (1 << 8);
+
+/// Leaf 0x80000001 ECX features x86-64-v3 adds: LZCNT (5).
+const V3_EXT_ECX: u32 = BASELINE_EXT_ECX | (1 << 5);
+
+/// Leaf 0x80000001 EDX features kept in baseline mode.
+///
+/// SYSCALL (11), NX (20), RDTSCP (27), LM (29).
+const BASELINE_EXT_EDX: u32 = (1 << 11) | (1 << 20) | (1 << 27) | (1 << 29);
+
+/// Leaf 0x80000001 EDX features kept in portable mode: baseline's, less
+/// RDTSCP.
+const PORTABLE_EXT_EDX: u32 = BASELINE_EXT_EDX & !(1 << 27);
+
+/// XCR0 components kept in baseline mode (x87 + SSE state only).
+const BASELINE_XCR0: u32 = 0x3;
+
+/// Size of the XSAVE area holding only x87 and SSE state: the legacy
+/// region and the XSAVE header.
+const LEGACY_XSAVE_SIZE: u32 = 512 + 64;
+
+/// The features a template keeps, by register; the rest are hidden.
+struct Template {
+    leaf_1_ecx: u32,
+    leaf_1_edx: u32,
+    leaf_7_ebx: u32,
+    leaf_7_edx: u32,
+    ext_ecx: u32,
+    ext_edx: u32,
+}
+
+const PORTABLE: Template = Template {
+    leaf_1_ecx: PORTABLE_1_ECX,
+    leaf_1_edx: BASELINE_1_EDX,
+    leaf_7_ebx: 0,
+    leaf_7_edx: BASELINE_7_EDX,
+    ext_ecx: 0,
+    ext_edx: PORTABLE_EXT_EDX,
+};
+
+const BASELINE: Template = Template {
+    leaf_1_ecx: BASELINE_1_ECX,
+    leaf_1_edx: BASELINE_1_EDX,
+    leaf_7_ebx: BASELINE_7_EBX,
+    leaf_7_edx: BASELINE_7_EDX,
+    ext_ecx: BASELINE_EXT_ECX,
+    ext_edx: BASELINE_EXT_EDX,
+};
+
+const X86_64_V3: Template = Template {
+    leaf_1_ecx: V3_1_ECX,
+    leaf_1_edx: BASELINE_1_EDX,
+    leaf_7_ebx: V3_7_EBX,
+    leaf_7_edx: BASELINE_7_EDX,
+    ext_ecx: V3_EXT_ECX,
+    ext_edx: BASELINE_EXT_EDX,
+};
+
+/// A CPUID register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// The features `--cpu-features` can name, as `/proc/cpuinfo` does: name,
+/// leaf, subleaf, register and bit.
+const FEATURES: &[(&str, u32, u32, Reg, u32)] = &[
+    ("sse3", 0x1, 0, Reg::Ecx, 0),
+    ("pclmulqdq", 0x1, 0, Reg::Ecx, 1),
+    ("vmx", 0x1, 0, Reg::Ecx, 5),
+    ("ssse3", 0x1, 0, Reg::Ecx, 9),
+    ("fma", 0x1, 0, Reg::Ecx, 12),
+    ("cx16", 0x1, 0, Reg::Ecx, 13),
+    ("pcid", 0x1, 0, Reg::Ecx, 17),
+    ("sse4_1", 0x1, 0, Reg::Ecx, 19),
+    ("sse4_2", 0x1, 0, Reg::Ecx, 20),
+    ("x2apic", 0x1, 0, Reg::Ecx, 21),
+    ("movbe", 0x1, 0, Reg::Ecx, 22),
+    ("popcnt", 0x1, 0, Reg::Ecx, 23),
+    ("tsc_deadline_timer", 0x1, 0, Reg::Ecx, 24),
+    ("aes", 0x1, 0, Reg::Ecx, 25),
+    ("xsave", 0x1, 0, Reg::Ecx, 26),
+    ("avx", 0x1, 0, Reg::Ecx, 28),
+    ("f16c", 0x1, 0, Reg::Ecx, 29),
+    ("rdrand", 0x1, 0, Reg::Ecx, 30),
+    ("fsgsbase", 0x7, 0, Reg::Ebx, 0),
+    ("bmi1", 0x7, 0, Reg::Ebx, 3),
+    ("hle", 0x7, 0, Reg::Ebx, 4),
+    ("avx2", 0x7, 0, Reg::Ebx, 5),
+    ("smep", 0x7, 0, Reg::Ebx, 7),
+    ("bmi2", 0x7, 0, Reg::Ebx, 8),
+    ("erms", 0x7, 0, Reg::Ebx, 9),
+    ("invpcid", 0x7, 0, Reg::Ebx, 10),
+    ("rtm", 0x7, 0, Reg::Ebx, 11),
+    ("mpx", 0x7, 0, Reg::Ebx, 14),
+    ("avx512f", 0x7, 0, Reg::Ebx, 16),
+    ("avx512dq", 0x7, 0, Reg::Ebx, 17),
+    ("rdseed", 0x7, 0, Reg::Ebx, 18),
+    ("adx", 0x7, 0, Reg::Ebx, 19),
+    ("smap", 0x7, 0, Reg::Ebx, 20),
+    ("avx512ifma", 0x7, 0, Reg::Ebx, 21),
+    ("clflushopt", 0x7, 0, Reg::Ebx, 23),
+    ("clwb", 0x7, 0, Reg::Ebx, 24),
+    ("avx512cd", 0x7, 0, Reg::Ebx, 28),
+    ("sha_ni", 0x7, 0, Reg::Ebx, 29),
+    ("avx512bw", 0x7, 0, Reg::Ebx, 30),
+    ("avx512vl", 0x7, 0, Reg::Ebx, 31),
+    ("avx512vbmi", 0x7, 0, Reg::Ecx, 1),
+    ("umip", 0x7, 0, Reg::Ecx, 2),
+    ("pku", 0x7, 0, Reg::Ecx, 3),
+    ("waitpkg", 0x7, 0, Reg::Ecx, 5),
+    ("avx512_vbmi2", 0x7, 0, Reg::Ecx, 6),
+    ("gfni", 0x7, 0, Reg::Ecx, 8),
+    ("vaes", 0x7, 0, Reg::Ecx, 9),
+    ("vpclmulqdq", 0x7, 0, Reg::Ecx, 10),
+    ("avx512_vnni", 0x7, 0, Reg::Ecx, 11),
+    ("avx512_bitalg", 0x7, 0, Reg::Ecx, 12),
+    ("avx512_vpopcntdq", 0x7, 0, Reg::Ecx, 14),
+    ("la57", 0x7, 0, Reg::Ecx, 16),
+    ("rdpid", 0x7, 0, Reg::Ecx, 22),
+    ("serialize", 0x7, 0, Reg::Edx, 14),
+    ("amx_tile", 0x7, 0, Reg::Edx, 24),
+    ("lahf_lm", 0x8000_0001, 0, Reg::Ecx, 0),
+    ("svm", 0x8000_0001, 0, Reg::Ecx, 2),
+    ("abm", 0x8000_0001, 0, Reg::Ecx, 5),
+    ("sse4a", 0x8000_0001, 0, Reg::Ecx, 6),
+    ("3dnowprefetch", 0x8000_0001, 0, Reg::Ecx, 8),
+    ("xop", 0x8000_0001, 0, Reg::Ecx, 11),
+    ("fma4", 0x8000_0001, 0, Reg::Ecx, 16),
+    ("tbm", 0x8000_0001, 0, Reg::Ecx, 21),
+    ("pdpe1gb", 0x8000_0001, 0, Reg::Edx, 26),
+    ("rdtscp", 0x8000_0001, 0, Reg::Edx, 27),
+];
+
+/// The features that let a guest run a hypervisor of its own: Intel VT-x and
+/// AMD-V.
+pub const NESTED_VIRT_FEATURES: [&str; 2] = ["vmx", "svm"];
+
+/// The XSAVE state components (XCR0 bits) each feature needs.
+const XSAVE_FEATURES: &[(&str, u64)] = &[
+    ("avx", 1 << 2),
+    ("mpx", (1 << 3) | (1 << 4)),
+    ("avx512f", (1 << 5) | (1 << 6) | (1 << 7)),
+    ("pku", 1 << 9),
+    ("amx_tile", (1 << 17) | (1 << 18)),
+];
+
+/// Features to expose or hide on top of the CPU mode (`--cpu-features`),
+/// e.g. `+avx2,-rdtscp`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuFeatures(Vec<(bool, &'static str)>);
+
+impl CpuFeatures {
+    /// Whether there are no overrides.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Expose whichever of VMX and SVM the `host` entries have, so the guest
+    /// can run a hypervisor of its own. Returns whether the host has either.
+    pub fn expose_nested_virt(&mut self, host: &[kvm_cpuid_entry2]) -> bool {
+        let len = self.0.len();
+        self.0.extend(
+            NESTED_VIRT_FEATURES
+                .iter()
+                .filter(|name| has_feature(host, name))
+                .map(|&name| (true, name)),
+        );
+        self.0.len() > len
+    }
+
+    /// The features to expose that the `host` entries lack, so the guest
+    /// won't see them.
+    pub fn unsupported(&self, host: &[kvm_cpuid_entry2]) -> Vec<&'static str> {
+        self.0
+            .iter()
+            .filter(|&&(expose, name)| expose && !has_feature(host, name))
+            .map(|&(_, name)| name)
+            .collect()
+    }
+}
+
+impl FromStr for CpuFeatures {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|item| {
+                let (expose, name) = match item.split_at_checked(1) {
+                    Some(("+", name)) => (true, name),
+                    Some(("-", name)) => (false, name),
+                    _ => {
+                        return Err(format!(
+                            "invalid feature {item:?} (expected +NAME or -NAME)"
+                        ))
+                    }
+                };
+                let (name, ..) = FEATURES
+                    .iter()
+                    .find(|(known, ..)| *known == name)
+                    .ok_or_else(|| format!("unknown CPU feature {name:?}"))?;
+                Ok((expose, *name))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (expose, name)) in self.0.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            let sign = if *expose { '+' } else { '-' };
+            write!(f, "{sep}{sign}{name}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for CpuFeatures {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CpuFeatures {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let features = String::deserialize(deserializer)?;
+        if features.is_empty() {
+            return Ok(Self::default());
+        }
+        features.parse().map_err(de::Error::custom)
+    }
+}
+
+/// KVM paravirtual feature leaf.
+pub const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+
+/// kvm-clock via the new MSRs (`MSR_KVM_SYSTEM_TIME_NEW`), in EAX of
+/// [`KVM_CPUID_FEATURES`].
+pub const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+
+/// Whether `entries` expose kvm-clock, which `ptp_kvm` builds on.
+pub fn has_kvm_clock(entries: &[kvm_cpuid_entry2]) -> bool {
+    entries
+        .iter()
+        .any(|e| e.function == KVM_CPUID_FEATURES && e.eax & KVM_FEATURE_CLOCKSOURCE2 != 0)
+}
+
+/// Apply the CPU mode policy to a list of CPUID entries in place.
+pub fn apply_cpu_mode(entries: &mut Vec<kvm_cpuid_entry2>, mode: CpuMode) {
+    match mode {
+        CpuMode::Host => {}
+        CpuMode::Portable => apply_template(entries, &PORTABLE),
+        CpuMode::Baseline => apply_template(entries, &BASELINE),
+        CpuMode::X86_64V3 => apply_template(entries, &X86_64_V3),
+    }
+}
+
+/// Apply `features` to entries the CPU `mode` has been applied to, taking
+/// features to expose from the `host` entries KVM supports.
+pub fn apply_cpu_features(
+    entries: &mut Vec<kvm_cpuid_entry2>,
+    host: &[kvm_cpuid_entry2],
+    mode: CpuMode,
+    features: &CpuFeatures,
+) {
+    if features.is_empty() {
+        return;
+    }
+    for &(expose, name) in &features.0 {
+        let Some(&(_, function, index, reg, bit)) = FEATURES.iter().find(|f| f.0 == name) else {
+            continue;
+        };
+        let host_has = host
+            .iter()
+            .find(|e| e.function == function && e.index == index)
+            .is_some_and(|e| register(e, reg) & (1 << bit) != 0);
+        if let Some(entry) = entries
+            .iter_mut()
+            .find(|e| e.function == function && e.index == index)
+        {
+            let value = register_mut(entry, reg);
+            if expose && host_has {
+                *value |= 1 << bit;
+            } else if !expose {
+                *value &= !(1 << bit);
+            }
+        }
+    }
+    sync_xsave(entries, host, mode != CpuMode::Host);
+}
+
+/// Hide VMX and SVM in `entries`.
+pub fn hide_nested_virt(entries: &mut [kvm_cpuid_entry2]) {
+    for &(name, function, index, reg, bit) in FEATURES {
+        if !NESTED_VIRT_FEATURES.contains(&name) {
+            continue;
+        }
+        for entry in entries
+            .iter_mut()
+            .filter(|e| e.function == function && e.index == index)
+        {
+            *register_mut(entry, reg) &= !(1 << bit);
+        }
+    }
+}
+
+/// Whether `entries` expose the feature called `name`.
+fn has_feature(entries: &[kvm_cpuid_entry2], name: &str) -> bool {
+    FEATURES
+        .iter()
+        .filter(|f| f.0 == name)
+        .any(|&(_, function, index, reg, bit)| {
+            entries.iter().any(|e| {
+                e.function == function && e.index == index && register(e, reg) & (1 << bit) != 0
+            })
+        })
+}
+
+fn register(entry: &kvm_cpuid_entry2, reg: Reg) -> u32 {
+    match reg {
+        Reg::Ebx => entry.ebx,
+        Reg::Ecx => entry.ecx,
+        Reg::Edx => entry.edx,
+    }
+}
+
+fn register_mut(entry: &mut kvm_cpuid_entry2, reg: Reg) -> &mut u32 {
+    match reg {
+        Reg::Ebx => &mut entry.ebx,
+        Reg::Ecx => &mut entry.ecx,
+        Reg::Edx => &mut entry.edx,
+    }
+}
+
+/// Offer the XSAVE state components (leaf 0xd) the features in `entries`
+/// need, as far as the `host` entries have them. A `conservative` policy
+/// drops components no feature accounts for; otherwise they stay as the
+/// host has them.
+fn sync_xsave(entries: &mut Vec<kvm_cpuid_entry2>, host: &[kvm_cpuid_entry2], conservative: bool) {
+    entries.retain(|e| !(e.function == 0xd && e.index >= 2));
+    let Some(host_xsave) = host.iter().find(|e| e.function == 0xd && e.index == 0) else {
+        return;
+    };
+    let host_xcr0 = (host_xsave.edx as u64) << 32 | host_xsave.eax as u64;
+    let mut allowed = if conservative {
+        BASELINE_XCR0 as u64
+    } else {
+        u64::MAX
+    };
+    for &(feature, components) in XSAVE_FEATURES {
+        if has_feature(entries, feature) {
+            allowed |= components;
+        } else {
+            allowed &= !components;
+        }
+    }
+    let xcr0 = host_xcr0 & allowed;
+
+    // Supervisor components (XSS) have subleaves too; only a conservative
+    // policy, which hides XSAVES, drops them
+    let xss = match host.iter().find(|e| e.function == 0xd && e.index == 1) {
+        Some(e) if !conservative => (e.edx as u64) << 32 | e.ecx as u64,
+        _ => 0,
+    };
+    let keep = |component: u32| component < 64 && (xcr0 | xss) & (1 << component) != 0;
+    entries.extend(
+        host.iter()
+            .filter(|e| e.function == 0xd && e.index >= 2 && keep(e.index))
+            .copied(),
+    );
+
+    // The XSAVE area the components need: each subleaf gives the size (EAX)
+    // and offset (EBX) of its component
+    let size = entries
+        .iter()
+        .filter(|e| e.function == 0xd && e.index >= 2 && xcr0 & (1 << e.index) != 0)
+        .map(|e| e.ebx + e.eax)
+        .fold(LEGACY_XSAVE_SIZE, u32::max);
+    if let Some(entry) = entries
+        .iter_mut()
+        .find(|e| e.function == 0xd && e.index == 0)
+    {
+        entry.eax = xcr0 as u32;
+        entry.edx = (xcr0 >> 32) as u32;
+        entry.ebx = size;
+        entry.ecx = size;
+    }
+}
+
+/// Mask CPUID entries down to the features of `template`.
+fn apply_template(entries: &mut Vec<kvm_cpuid_entry2>, template: &Template) {
+    let host = entries.clone();
+    for entry in entries.iter_mut() {
+        match (entry.function, entry.index) {
+            (0x1, _) => {
+                entry.ecx &= template.leaf_1_ecx;
+                entry.edx &= template.leaf_1_edx;
+            }
+            // Thermal and power management: never needed by a guest
+            (0x6, _) => {
+                entry.eax = 0;
+                entry.ebx = 0;
+                entry.ecx = 0;
+                entry.edx = 0;
+            }
+            (0x7, 0) => {
+                entry.ebx &= template.leaf_7_ebx;
+                entry.ecx = 0;
+                entry.edx &= template.leaf_7_edx;
+            }
+            (0x7, _) => {
+                entry.eax = 0;
+                entry.ebx = 0;
+                entry.ecx = 0;
+                entry.edx = 0;
+            }
+            (0xd, 1) => {
+                // XSAVEOPT/XSAVEC/XGETBV1/XSAVES
+                entry.eax = 0;
+                entry.ebx = 0;
+                entry.ecx = 0;
+                entry.edx = 0;
+            }
+            (0x8000_0001, _) => {
+                entry.ecx &= template.ext_ecx;
+                entry.edx &= template.ext_edx;
+            }
+            _ => {}
+        }
+    }
+    // Extended XSAVE components (AVX, MPX, AVX-512, AMX, ...) are described
+    // by leaf 0xd subleaves >= 2; keep those of the features kept
+    sync_xsave(entries, &host, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, index: u32, ecx: u32, edx: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            eax: u32::MAX,
+            ebx: u32::MAX,
+            ecx,
+            edx,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_host_mode_is_passthrough() {
+        let mut entries = vec![entry(0x1, 0, u32::MAX, u32::MAX)];
+        apply_cpu_mode(&mut entries, CpuMode::Host);
+        assert_eq!(entries[0].ecx, u32::MAX);
+        assert_eq!(entries[0].edx, u32::MAX);
+    }
+
+    #[test]
+    fn test_baseline_masks_avx() {
+        let mut entries = vec![
+            entry(0x1, 0, u32::MAX, u32::MAX),
+            entry(0x7, 0, u32::MAX, u32::MAX),
+        ];
+        apply_cpu_mode(&mut entries, CpuMode::Baseline);
+
+        // AVX (leaf 1 ECX bit 28) and AVX2 (leaf 7 EBX bit 5) are hidden
+        assert_eq!(entries[0].ecx & (1 << 28), 0);
+        assert_eq!(entries[1].ebx & (1 << 5), 0);
+
+        // SSE4.2 and POPCNT survive
+        assert_ne!(entries[0].ecx & (1 << 20), 0);
+        assert_ne!(entries[0].ecx & (1 << 23), 0);
+    }
+
+    #[test]
+    fn test_baseline_never_adds_features() {
+        let mut entries = vec![entry(0x1, 0, 0, 0)];
+        apply_cpu_mode(&mut entries, CpuMode::Baseline);
+        assert_eq!(entries[0].ecx, 0);
+        assert_eq!(entries[0].edx, 0);
+    }
+
+    #[test]
+    fn test_baseline_keeps_kvm_clock() {
+        let mut entries = vec![entry(KVM_CPUID_FEATURES, 0, 0, 0)];
+        apply_cpu_mode(&mut entries, CpuMode::Baseline);
+        assert!(has_kvm_clock(&entries));
+        assert!(!has_kvm_clock(&[entry(0x1, 0, 0, 0)]));
+    }
+
+    #[test]
+    fn test_baseline_drops_extended_xsave_leaves() {
+        let mut entries = vec![
+            entry(0xd, 0, 0, 0),
+            entry(0xd, 1, 0, 0),
+            entry(0xd, 2, 0, 0),
+            entry(0xd, 5, 0, 0),
+        ];
+        apply_cpu_mode(&mut entries, CpuMode::Baseline);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].eax, BASELINE_XCR0);
+    }
+
+    #[test]
+    fn test_templates() {
+        let host = || {
+            vec![
+                entry(0x1, 0, u32::MAX, u32::MAX),
+                entry(0x7, 0, u32::MAX, u32::MAX),
+                entry(0x8000_0001, 0, u32::MAX, u32::MAX),
+            ]
+        };
+        let mut portable = host();
+        apply_cpu_mode(&mut portable, CpuMode::Portable);
+        assert_eq!(portable[0].ecx, PORTABLE_1_ECX);
+        assert_eq!(portable[1].ebx, 0);
+        assert_eq!(portable[2].edx & (1 << 27), 0); // RDTSCP
+
+        let mut v3 = host();
+        apply_cpu_mode(&mut v3, CpuMode::X86_64V3);
+        assert!(has_feature(&v3, "avx2"));
+        assert!(has_feature(&v3, "fma"));
+        assert!(!has_feature(&v3, "avx512f"));
+    }
+
+    #[test]
+    fn test_parse_cpu_features() {
+        let features: CpuFeatures = "+avx2,-rdtscp".parse().unwrap();
+        assert_eq!(features.to_string(), "+avx2,-rdtscp");
+        assert!(CpuFeatures::default().is_empty());
+
+        assert!("avx2".parse::<CpuFeatures>().is_err());
+        assert!("+avx3".parse::<CpuFeatures>().is_err());
+        assert!("+avx2,".parse::<CpuFeatures>().is_err());
+    }
+
+    #[test]
+    fn test_cpu_features() {
+        // A host with AVX2 but no AVX-512
+        let mut host = vec![
+            entry(0x1, 0, u32::MAX, u32::MAX),
+            entry(0x7, 0, 0, 0),
+            entry(0x8000_0001, 0, u32::MAX, u32::MAX),
+        ];
+        host[1].ebx = 1 << 5;
+        let features: CpuFeatures = "+avx2,+avx512f,-rdtscp".parse().unwrap();
+        assert_eq!(features.unsupported(&host), ["avx512f"]);
+
+        let mut entries = host.clone();
+        apply_cpu_mode(&mut entries, CpuMode::Baseline);
+        apply_cpu_features(&mut entries, &host, CpuMode::Baseline, &features);
+        assert!(has_feature(&entries, "avx2"));
+        assert!(!has_feature(&entries, "avx512f"));
+        assert!(!has_feature(&entries, "rdtscp"));
+        assert!(has_feature(&entries, "sse4_2"));
+    }
+
+    #[test]
+    fn test_nested_virt() {
+        let host = vec![
+            entry(0x1, 0, u32::MAX, u32::MAX),
+            entry(0x8000_0001, 0, 0, u32::MAX),
+        ];
+        let mut hidden = host.clone();
+        hide_nested_virt(&mut hidden);
+        assert!(!has_feature(&hidden, "vmx"));
+        assert!(has_feature(&hidden, "sse4_2"));
+
+        // Only the host's own extension is exposed, in any mode
+        let mut features = CpuFeatures::default();
+        assert!(features.expose_nested_virt(&host));
+        assert_eq!(features.to_string(), "+vmx");
+        let mut entries = host.clone();
+        apply_cpu_mode(&mut entries, CpuMode::Baseline);
+        assert!(!has_feature(&entries, "vmx"));
+        apply_cpu_features(&mut entries, &host, CpuMode::Baseline, &features);
+        assert!(has_feature(&entries, "vmx"));
+
+        assert!(!CpuFeatures::default().expose_nested_virt(&hidden));
+    }
+
+    #[test]
+    fn test_cpu_features_sync_xsave() {
+        // A host with AVX (component 2) and AVX-512 (components 5-7)
+        let mut host = vec![
+            entry(0x1, 0, u32::MAX, u32::MAX),
+            entry(0x7, 0, 0, 0),
+            entry(0xd, 0, 0, 0),
+            entry(0xd, 2, 0, 0),
+            entry(0xd, 5, 0, 0),
+            entry(0xd, 6, 0, 0),
+            entry(0xd, 7, 0, 0),
+        ];
+        host[1].ebx = (1 << 5) | (1 << 16);
+        host[2].eax = 0xe7;
+        for (i, (size, offset)) in [(256, 576), (64, 832), (512, 896), (1024, 1408)]
+            .into_iter()
+            .enumerate()
+        {
+            host[3 + i].eax = size;
+            host[3 + i].ebx = offset;
+        }
+
+        // Baseline hides AVX; +avx brings back its state only
+        let mut entries = host.clone();
+        apply_cpu_mode(&mut entries, CpuMode::Baseline);
+        assert_eq!(entries.iter().filter(|e| e.function == 0xd).count(), 1);
+        let features: CpuFeatures = "+avx".parse().unwrap();
+        apply_cpu_features(&mut entries, &host, CpuMode::Baseline, &features);
+        let xsave: Vec<_> = entries.iter().filter(|e| e.function == 0xd).collect();
+        assert_eq!(xsave.len(), 2);
+        assert_eq!((xsave[0].eax, xsave[0].ebx), (0x7, 832));
+        assert_eq!(xsave[1].index, 2);
+
+        // Host mode keeps what it isn't told to hide
+        let mut entries = host.clone();
+        let features: CpuFeatures = "-avx512f".parse().unwrap();
+        apply_cpu_features(&mut entries, &host, CpuMode::Host, &features);
+        let xsave = entries.iter().find(|e| e.function == 0xd).unwrap();
+        assert_eq!((xsave.eax, xsave.ebx), (0x7, 832));
+        assert!(entries.iter().all(|e| e.function != 0xd || e.index < 5));
+    }
+}
@@ -0,0 +1,194 @@
+//! Breakpoints and single-stepping, for harnesses asserting on a guest.
+//!
+//! A test harness embedding Carbon can stop a vCPU at a guest physical
+//! address, step it an instruction at a time and look at the code it is
+//! about to run, through [`VcpuFd`](super::VcpuFd)'s debugging methods, or
+//! on a running VM through [`Vmm::debug`](crate::Vmm::debug), which has the
+//! vCPU's thread call them while the VM is paused:
+//!
+//! - `set_breakpoint` / `remove_breakpoint` patch an INT3 (0xCC) over the
+//!   instruction at a GPA, keeping the byte it replaced. With one set,
+//!   `run_with_io` returns [`VcpuExit::Debug`](super::VcpuExit::Debug)
+//!   when the vCPU reaches it, RIP still on the breakpoint.
+//! - `single_step` runs N instructions with the trap flag KVM manages,
+//!   stepping over a breakpoint under RIP by lifting it for that one
+//!   instruction. I/O the instructions perform goes to the handler as usual.
+//! - `read_memory_at_rip` reads the bytes at RIP, translating through the
+//!   guest's page tables, with any breakpoints shown as the bytes they
+//!   replaced.
+//!
+//! Breakpoints live in guest memory, which every vCPU shares, while
+//! `KVM_SET_GUEST_DEBUG` is per vCPU: another vCPU reaching one takes the
+//! INT3 as the guest's own #BP. They are meant for single-vCPU harnesses.
+
+use super::KvmError;
+use kvm_bindings::{KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_SW_BP};
+use std::io;
+
+/// The INT3 instruction.
+const INT3: u8 = 0xcc;
+
+/// Guest physical memory the debugger reads, and patches breakpoints into.
+pub trait GuestRam {
+    /// Read `data.len()` bytes at guest physical address `gpa`.
+    fn read_ram(&self, gpa: u64, data: &mut [u8]) -> io::Result<()>;
+
+    /// Write `data` at guest physical address `gpa`.
+    fn write_ram(&self, gpa: u64, data: &[u8]) -> io::Result<()>;
+}
+
+/// The software breakpoints set on a vCPU, with the bytes they replaced.
+#[derive(Debug, Default)]
+pub(super) struct Breakpoints(Vec<(u64, u8)>);
+
+impl Breakpoints {
+    /// Patch an INT3 in at `gpa`. Returns false if one is already there.
+    pub fn insert(&mut self, memory: &impl GuestRam, gpa: u64) -> Result<bool, KvmError> {
+        if self.original(gpa).is_some() {
+            return Ok(false);
+        }
+        let mut original = [0u8];
+        read(memory, gpa, &mut original)?;
+        write(memory, gpa, &[INT3])?;
+        self.0.push((gpa, original[0]));
+        Ok(true)
+    }
+
+    /// Put back the byte the breakpoint at `gpa` replaced. Returns false if
+    /// there is none.
+    pub fn remove(&mut self, memory: &impl GuestRam, gpa: u64) -> Result<bool, KvmError> {
+        let Some(index) = self.0.iter().position(|&(addr, _)| addr == gpa) else {
+            return Ok(false);
+        };
+        write(memory, gpa, &[self.0[index].1])?;
+        self.0.swap_remove(index);
+        Ok(true)
+    }
+
+    /// Put back the original byte at `gpa` for a moment, if a breakpoint is
+    /// there. Returns whether one was, to [`Self::rearm`] afterwards.
+    pub fn lift(&self, memory: &impl GuestRam, gpa: u64) -> Result<bool, KvmError> {
+        match self.original(gpa) {
+            Some(byte) => write(memory, gpa, &[byte]).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Patch the INT3 a [`Self::lift`] took out back in.
+    pub fn rearm(&self, memory: &impl GuestRam, gpa: u64) -> Result<(), KvmError> {
+        write(memory, gpa, &[INT3])
+    }
+
+    /// The byte the breakpoint at `gpa` replaced.
+    pub fn original(&self, gpa: u64) -> Option<u8> {
+        self.0
+            .iter()
+            .find(|&&(addr, _)| addr == gpa)
+            .map(|&(_, byte)| byte)
+    }
+
+    /// Replace the INT3s in `data`, read from `gpa`, with the bytes they
+    /// replaced.
+    pub fn unpatch(&self, gpa: u64, data: &mut [u8]) {
+        for &(addr, byte) in &self.0 {
+            if let Some(offset) = addr.checked_sub(gpa) {
+                if let Some(slot) = data.get_mut(offset as usize) {
+                    *slot = byte;
+                }
+            }
+        }
+    }
+
+    /// The `KVM_SET_GUEST_DEBUG` control flags, trapping INT3 while any
+    /// breakpoint is set and every instruction with `single_step`.
+    pub fn control(&self, single_step: bool) -> u32 {
+        let mut control = 0;
+        if !self.0.is_empty() {
+            control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP;
+        }
+        if single_step {
+            control |= KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP;
+        }
+        control
+    }
+}
+
+fn read(memory: &impl GuestRam, gpa: u64, data: &mut [u8]) -> Result<(), KvmError> {
+    memory
+        .read_ram(gpa, data)
+        .map_err(|source| KvmError::GuestMemory { addr: gpa, source })
+}
+
+fn write(memory: &impl GuestRam, gpa: u64, data: &[u8]) -> Result<(), KvmError> {
+    memory
+        .write_ram(gpa, data)
+        .map_err(|source| KvmError::GuestMemory { addr: gpa, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    struct Ram(RefCell<Vec<u8>>);
+
+    impl GuestRam for Ram {
+        fn read_ram(&self, gpa: u64, data: &mut [u8]) -> io::Result<()> {
+            let ram = self.0.borrow();
+            let bytes = ram
+                .get(gpa as usize..gpa as usize + data.len())
+                .ok_or(io::ErrorKind::InvalidInput)?;
+            data.copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn write_ram(&self, gpa: u64, data: &[u8]) -> io::Result<()> {
+            let mut ram = self.0.borrow_mut();
+            ram.get_mut(gpa as usize..gpa as usize + data.len())
+                .ok_or(io::ErrorKind::InvalidInput)?
+                .copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let ram = Ram(RefCell::new(vec![0x90, 0xf4, 0x90, 0x90]));
+        let mut breakpoints = Breakpoints::default();
+        assert_eq!(breakpoints.control(false), 0);
+
+        assert!(breakpoints.insert(&ram, 1).unwrap());
+        assert!(!breakpoints.insert(&ram, 1).unwrap());
+        assert!(breakpoints.insert(&ram, 3).unwrap());
+        assert_eq!(*ram.0.borrow(), [0x90, INT3, 0x90, INT3]);
+        assert_eq!(breakpoints.original(1), Some(0xf4));
+        assert_eq!(
+            breakpoints.control(false),
+            KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP
+        );
+        assert!(breakpoints.insert(&ram, 4).is_err());
+
+        // Reads show the original bytes
+        let mut data = [0u8; 3];
+        ram.read_ram(1, &mut data).unwrap();
+        breakpoints.unpatch(1, &mut data);
+        assert_eq!(data, [0xf4, 0x90, 0x90]);
+
+        // Lifted for a step, then rearmed
+        assert!(breakpoints.lift(&ram, 1).unwrap());
+        assert!(!breakpoints.lift(&ram, 2).unwrap());
+        assert_eq!(*ram.0.borrow(), [0x90, 0xf4, 0x90, INT3]);
+        breakpoints.rearm(&ram, 1).unwrap();
+        assert_eq!(*ram.0.borrow(), [0x90, INT3, 0x90, INT3]);
+
+        assert!(breakpoints.remove(&ram, 1).unwrap());
+        assert!(!breakpoints.remove(&ram, 1).unwrap());
+        assert!(breakpoints.remove(&ram, 3).unwrap());
+        assert_eq!(*ram.0.borrow(), [0x90, 0xf4, 0x90, 0x90]);
+        assert_eq!(breakpoints.control(false), 0);
+        assert_eq!(
+            breakpoints.control(true),
+            KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP
+        );
+    }
+}
@@ -0,0 +1,795 @@
+//! Host capability report.
+//!
+//! Collects what Carbon depends on from the host — the KVM API version, the
+//! KVM capabilities we use, and the host CPU features that shape what guests
+//! can see — so `carbon --version --verbose` can make bug reports
+//! self-contained.
+//!
+//! It also answers host-side questions about optional guest features, such as
+//! whether guests can use `ptp_kvm` ([`ptp_kvm_status`]).
+//!
+//! # `carbon check`
+//!
+//! [`check`] goes further: it opens `/dev/kvm`, checks the capabilities
+//! Carbon can't run without and those optional features depend on, creates
+//! a VM and a vCPU, and reports each finding with what to do about it. A
+//! missing required capability also stops [`super::create_vm`] up front,
+//! and a missing optional one turns its feature off, so neither surfaces
+//! halfway through a boot.
+//!
+//! # `carbon capabilities`
+//!
+//! [`capabilities`] answers a scheduler's question instead: what VMs this
+//! build of Carbon can run here. It reports limits (vCPUs, memory slots),
+//! the host CPU, and each feature a workload may ask for with whether it is
+//! usable and why not. A feature counts as supported only if both the host
+//! and Carbon support it: a host with SEV enabled still reports `sev` as
+//! unsupported, as Carbon doesn't run confidential guests.
+
+use super::{CpuFeatures, CpuMode, KvmError, Topology};
+use kvm_bindings::{
+    KVM_CAP_ADJUST_CLOCK, KVM_CAP_EXT_CPUID, KVM_CAP_GET_TSC_KHZ, KVM_CAP_IMMEDIATE_EXIT,
+    KVM_CAP_IOEVENTFD, KVM_CAP_IRQCHIP, KVM_CAP_IRQFD, KVM_CAP_MAX_VCPUS, KVM_CAP_NESTED_STATE,
+    KVM_CAP_NR_MEMSLOTS, KVM_CAP_PIT2, KVM_CAP_SET_TSS_ADDR, KVM_CAP_SYS_HYPERV_CPUID,
+    KVM_CAP_TSC_CONTROL, KVM_CAP_USER_MEMORY, KVM_CAP_VCPU_EVENTS, KVM_CAP_XCRS, KVM_CAP_XSAVE,
+};
+use kvm_ioctls::Kvm;
+use serde::Serialize;
+use std::arch::x86_64::{__cpuid_count, CpuidResult};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// KVM capabilities worth reporting, with the name shown in the report.
+///
+/// Most are booleans; `max-vcpus`, `nr-memslots` and `nested-state` report a
+/// count or size.
+const CAPABILITIES: &[(&str, u32)] = &[
+    ("irqchip", KVM_CAP_IRQCHIP),
+    ("user-memory", KVM_CAP_USER_MEMORY),
+    ("set-tss-addr", KVM_CAP_SET_TSS_ADDR),
+    ("pit2", KVM_CAP_PIT2),
+    ("irqfd", KVM_CAP_IRQFD),
+    ("ioeventfd", KVM_CAP_IOEVENTFD),
+    ("xsave", KVM_CAP_XSAVE),
+    ("xcrs", KVM_CAP_XCRS),
+    ("vcpu-events", KVM_CAP_VCPU_EVENTS),
+    ("tsc-control", KVM_CAP_TSC_CONTROL),
+    ("get-tsc-khz", KVM_CAP_GET_TSC_KHZ),
+    ("immediate-exit", KVM_CAP_IMMEDIATE_EXIT),
+    ("nested-state", KVM_CAP_NESTED_STATE),
+    ("max-vcpus", KVM_CAP_MAX_VCPUS),
+    ("nr-memslots", KVM_CAP_NR_MEMSLOTS),
+];
+
+/// Capabilities Carbon can't run a VM without.
+pub const REQUIRED_CAPABILITIES: &[(&str, u32)] = &[
+    ("irqchip", KVM_CAP_IRQCHIP),
+    ("user-memory", KVM_CAP_USER_MEMORY),
+    ("set-tss-addr", KVM_CAP_SET_TSS_ADDR),
+    ("pit2", KVM_CAP_PIT2),
+    ("ext-cpuid", KVM_CAP_EXT_CPUID),
+];
+
+/// Capabilities optional features depend on, with what goes without them.
+const OPTIONAL_CAPABILITIES: &[(&str, u32, &str)] = &[
+    (
+        "irqfd",
+        KVM_CAP_IRQFD,
+        "devices interrupt through KVM_IRQ_LINE and vhost-user is unavailable",
+    ),
+    (
+        "ioeventfd",
+        KVM_CAP_IOEVENTFD,
+        "virtio queue notifications exit to the VMM",
+    ),
+    ("adjust-clock", KVM_CAP_ADJUST_CLOCK, "kvm-clock isn't set"),
+    (
+        "get-tsc-khz",
+        KVM_CAP_GET_TSC_KHZ,
+        "guests calibrate the TSC at boot",
+    ),
+    (
+        "nested-state",
+        KVM_CAP_NESTED_STATE,
+        "nested guests can't be saved",
+    ),
+    (
+        "sys-hyperv-cpuid",
+        KVM_CAP_SYS_HYPERV_CPUID,
+        "--hyperv is unavailable",
+    ),
+];
+
+/// `/sys/module` parameters saying whether KVM allows nested guests.
+const NESTED_PARAMS: [&str; 2] = [
+    "/sys/module/kvm_intel/parameters/nested",
+    "/sys/module/kvm_amd/parameters/nested",
+];
+
+/// Host CPU features relevant to guests: (name, leaf, subleaf, register, bit).
+const CPU_FEATURES: &[(&str, u32, u32, Reg, u32)] = &[
+    ("vmx", 0x1, 0, Reg::Ecx, 5),
+    ("svm", 0x8000_0001, 0, Reg::Ecx, 2),
+    ("x2apic", 0x1, 0, Reg::Ecx, 21),
+    ("tsc-deadline", 0x1, 0, Reg::Ecx, 24),
+    ("xsave", 0x1, 0, Reg::Ecx, 26),
+    ("avx", 0x1, 0, Reg::Ecx, 28),
+    ("hypervisor", 0x1, 0, Reg::Ecx, 31),
+    ("avx2", 0x7, 0, Reg::Ebx, 5),
+    ("avx512f", 0x7, 0, Reg::Ebx, 16),
+    ("pdpe1gb", 0x8000_0001, 0, Reg::Edx, 26),
+    ("invariant-tsc", 0x8000_0007, 0, Reg::Edx, 8),
+];
+
+#[derive(Clone, Copy)]
+enum Reg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// `/sys/module` parameter saying whether KVM runs SEV guests.
+const SEV_PARAM: &str = "/sys/module/kvm_amd/parameters/sev";
+
+/// Whether the host kernel allows io_uring (0: for everyone).
+const IO_URING_DISABLED: &str = "/proc/sys/kernel/io_uring_disabled";
+
+/// One directory per huge page size, each with the size of its pool.
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+
+/// Host clocksource, as chosen by the host kernel.
+const CLOCKSOURCE_PATH: &str = "/sys/devices/system/clocksource/clocksource0/current_clocksource";
+
+/// What KVM on this host supports.
+#[derive(Debug, Clone)]
+pub struct KvmInfo {
+    /// `KVM_GET_API_VERSION` (always 12 on modern kernels).
+    pub api_version: i32,
+    /// Capability name and `KVM_CHECK_EXTENSION` result.
+    pub capabilities: Vec<(&'static str, i32)>,
+    /// Whether guests can sync their clock with `ptp_kvm`, and why not.
+    pub ptp_kvm: Result<(), String>,
+}
+
+/// Host CPU identification and feature flags.
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    /// CPUID vendor string, e.g. `GenuineIntel`.
+    pub vendor: String,
+    /// CPUID brand string, e.g. `Intel(R) Xeon(R) ...`.
+    pub brand: String,
+    /// Feature name and whether the host CPU has it.
+    pub features: Vec<(&'static str, bool)>,
+}
+
+/// What VMs this build of Carbon can run on this host (see the module
+/// docs), as `carbon capabilities` prints it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    /// Carbon's version.
+    pub version: &'static str,
+    /// Whether `/dev/kvm` can be opened; `carbon check` says why not.
+    pub kvm: bool,
+    /// Most vCPUs one VM can have, by KVM's limit and Carbon's; 0 without
+    /// KVM.
+    pub max_vcpus: u32,
+    /// KVM memory slots per VM; 0 without KVM.
+    pub max_memory_slots: u32,
+    pub cpu: CpuSummary,
+    /// CPU models `--cpu` takes.
+    pub cpu_models: Vec<String>,
+    /// Each optional feature, by name.
+    pub features: BTreeMap<&'static str, Feature>,
+}
+
+/// The host CPU, as [`Capabilities`] reports it.
+#[derive(Debug, Clone, Serialize)]
+pub struct CpuSummary {
+    pub vendor: String,
+    pub brand: String,
+    /// The features of [`CpuInfo`] the host CPU has.
+    pub features: Vec<&'static str>,
+}
+
+/// Whether a workload can use an optional feature here.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Feature {
+    pub supported: bool,
+    /// Why not, or how to use it.
+    pub detail: String,
+}
+
+impl Feature {
+    fn new(supported: bool, detail: impl Into<String>) -> Self {
+        Self {
+            supported,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// How a check came out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Carbon runs, without some optional feature.
+    Warn,
+    /// Carbon can't run VMs.
+    Fail,
+}
+
+/// One finding of [`check`].
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about a warning or failure.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Everything [`check`] found.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport(pub Vec<Check>);
+
+impl CheckReport {
+    /// Whether Carbon can run VMs on this host.
+    pub fn passed(&self) -> bool {
+        self.0.iter().all(|check| check.status != Status::Fail)
+    }
+}
+
+/// Check that this host can run Carbon's VMs, and which optional features
+/// it supports.
+pub fn check() -> CheckReport {
+    let mut report = CheckReport::default();
+    let checks = &mut report.0;
+    let kvm = match Kvm::new() {
+        Ok(kvm) => {
+            checks.push(Check::new("/dev/kvm", Status::Ok, "readable and writable"));
+            kvm
+        }
+        Err(e) => {
+            checks.push(open_failure(e));
+            return report;
+        }
+    };
+
+    let api_version = kvm.get_api_version();
+    checks.push(if api_version == 12 {
+        Check::new("api version", Status::Ok, "12")
+    } else {
+        Check::new(
+            "api version",
+            Status::Fail,
+            format!("{api_version}, not 12"),
+        )
+        .hint("upgrade the host kernel")
+    });
+
+    for &(name, cap) in REQUIRED_CAPABILITIES {
+        checks.push(if kvm.check_extension_raw(cap as _) > 0 {
+            Check::new(name, Status::Ok, "supported")
+        } else {
+            Check::new(name, Status::Fail, "missing").hint("upgrade the host kernel")
+        });
+    }
+    for &(name, cap, without) in OPTIONAL_CAPABILITIES {
+        checks.push(if kvm.check_extension_raw(cap as _) > 0 {
+            Check::new(name, Status::Ok, "supported")
+        } else {
+            Check::new(name, Status::Warn, format!("missing: {without}"))
+        });
+    }
+    for (name, cap) in [
+        ("max vcpus", KVM_CAP_MAX_VCPUS),
+        ("memslots", KVM_CAP_NR_MEMSLOTS),
+    ] {
+        let count = kvm.check_extension_raw(cap as _);
+        checks.push(Check::new(name, Status::Ok, count.to_string()));
+    }
+    drop(kvm);
+
+    // Creating a VM exercises the TSS, IRQ chip, PIT and kvm-clock setup
+    match super::create_vm(
+        CpuMode::Host,
+        &CpuFeatures::default(),
+        Topology::default(),
+        false,
+        false,
+    ) {
+        Ok(vm) => {
+            checks.push(Check::new(
+                "vm",
+                Status::Ok,
+                "created, with IRQ chip and PIT",
+            ));
+            checks.push(match vm.create_vcpu(0) {
+                Ok(_) => Check::new("vcpu", Status::Ok, "created"),
+                Err(e) => Check::new("vcpu", Status::Fail, e.to_string())
+                    .hint("check `dmesg` for KVM errors"),
+            });
+        }
+        Err(e) => checks.push(
+            Check::new("vm", Status::Fail, e.to_string()).hint("check `dmesg` for KVM errors"),
+        ),
+    }
+
+    let cpu = probe_cpu();
+    let has = |feature| {
+        cpu.features
+            .iter()
+            .any(|&(name, has)| name == feature && has)
+    };
+    let nested = NESTED_PARAMS
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok());
+    checks.push(match nested.as_deref().map(str::trim) {
+        Some("Y" | "1") => Check::new("nested virtualization", Status::Ok, "allowed"),
+        Some(_) => Check::new(
+            "nested virtualization",
+            Status::Warn,
+            "off: --enable-nested is unavailable",
+        )
+        .hint("reload kvm_intel or kvm_amd with nested=1"),
+        None if has("vmx") || has("svm") => Check::new(
+            "nested virtualization",
+            Status::Warn,
+            "unknown: --enable-nested may be unavailable",
+        ),
+        None => Check::new(
+            "nested virtualization",
+            Status::Warn,
+            "no VMX or SVM exposed to this host: --enable-nested is unavailable",
+        ),
+    });
+
+    checks.push(match ptp_kvm_status() {
+        Ok(()) => Check::new("guest ptp_kvm", Status::Ok, "available"),
+        Err(reason) => Check::new("guest ptp_kvm", Status::Warn, reason)
+            .hint("use the tsc clocksource on the host for guest clock sync"),
+    });
+    report
+}
+
+/// Describe what VMs this build of Carbon can run on this host.
+pub fn capabilities() -> Capabilities {
+    use clap::ValueEnum;
+
+    let kvm = Kvm::new().ok();
+    let cap = |cap: u32| {
+        kvm.as_ref()
+            .map_or(0, |kvm| kvm.check_extension_raw(cap as _).max(0) as u32)
+    };
+    let has_cap = |c: u32| cap(c) > 0;
+    let cpu = probe_cpu();
+    let mut features = BTreeMap::new();
+
+    let nested = NESTED_PARAMS
+        .iter()
+        .find_map(|path| read_param(Path::new(path)));
+    features.insert(
+        "nested",
+        match nested {
+            Some(true) if has_cap(KVM_CAP_NESTED_STATE) => {
+                Feature::new(true, "guests can run VMs of their own (--enable-nested)")
+            }
+            Some(true) => Feature::new(false, "KVM can't save nested state"),
+            _ => Feature::new(false, "nested virtualization is off in KVM"),
+        },
+    );
+    features.insert(
+        "hyperv",
+        if has_cap(KVM_CAP_SYS_HYPERV_CPUID) {
+            Feature::new(true, "Hyper-V enlightenments (--hyperv)")
+        } else {
+            Feature::new(false, "KVM lacks KVM_CAP_SYS_HYPERV_CPUID")
+        },
+    );
+    features.insert(
+        "vhost_user",
+        if has_cap(KVM_CAP_IRQFD) && has_cap(KVM_CAP_IOEVENTFD) {
+            Feature::new(true, "vhost-user disks and virtio-fs (--shared-dir)")
+        } else {
+            Feature::new(false, "KVM lacks irqfd or ioeventfd")
+        },
+    );
+    features.insert(
+        "ptp_kvm",
+        match ptp_kvm_status() {
+            Ok(()) => Feature::new(true, "guests can sync their clock with ptp_kvm"),
+            Err(reason) => Feature::new(false, reason),
+        },
+    );
+    features.insert("hugepages", hugepages(Path::new(HUGEPAGES_DIR)));
+    features.insert(
+        "sev",
+        Feature::new(
+            false,
+            match read_param(Path::new(SEV_PARAM)) {
+                Some(true) => "the host runs SEV guests, but Carbon doesn't",
+                _ => "the host doesn't run SEV guests, and Carbon doesn't either",
+            },
+        ),
+    );
+    let io_uring = match std::fs::read_to_string(IO_URING_DISABLED) {
+        Ok(value) if value.trim() != "0" => "the host disables io_uring",
+        _ => "the host allows io_uring",
+    };
+    features.insert(
+        "io_uring",
+        Feature::new(
+            false,
+            format!("{io_uring}, but Carbon serves disks with blocking I/O"),
+        ),
+    );
+
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        kvm: kvm.is_some(),
+        max_vcpus: match kvm {
+            Some(_) => cap(KVM_CAP_MAX_VCPUS).min(Topology::MAX_CPUS),
+            None => 0,
+        },
+        max_memory_slots: cap(KVM_CAP_NR_MEMSLOTS),
+        cpu: CpuSummary {
+            vendor: cpu.vendor,
+            brand: cpu.brand,
+            features: cpu
+                .features
+                .iter()
+                .filter(|(_, has)| *has)
+                .map(|&(name, _)| name)
+                .collect(),
+        },
+        cpu_models: CpuMode::value_variants()
+            .iter()
+            .filter_map(|mode| mode.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect(),
+        features,
+    }
+}
+
+/// A `Y`/`N` (or `1`/`0`) module parameter, if readable.
+fn read_param(path: &Path) -> Option<bool> {
+    match std::fs::read_to_string(path).ok()?.trim() {
+        "Y" | "1" => Some(true),
+        _ => Some(false),
+    }
+}
+
+/// Whether guest memory can go on huge pages: some pool under `dir` (one
+/// `hugepages-SIZEkB` directory per page size) has pages free.
+fn hugepages(dir: &Path) -> Feature {
+    let mut pools = Vec::new();
+    let mut free_total = 0;
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(size) = name.strip_prefix("hugepages-") else {
+            continue;
+        };
+        let count = |file: &str| {
+            std::fs::read_to_string(entry.path().join(file))
+                .ok()
+                .and_then(|count| count.trim().parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+        let (total, free) = (count("nr_hugepages"), count("free_hugepages"));
+        free_total += free;
+        pools.push(format!("{size}: {free} free of {total}"));
+    }
+    pools.sort();
+    match (pools.is_empty(), free_total) {
+        (true, _) => Feature::new(false, "the host has no huge page pools"),
+        (false, 0) => Feature::new(false, format!("no free huge pages ({})", pools.join(", "))),
+        (false, _) => Feature::new(
+            true,
+            format!(
+                "--memory-backend file=PATH on hugetlbfs ({})",
+                pools.join(", ")
+            ),
+        ),
+    }
+}
+
+/// The check for a `/dev/kvm` that couldn't be opened.
+fn open_failure(e: kvm_ioctls::Error) -> Check {
+    let check = Check::new("/dev/kvm", Status::Fail, format!("cannot open: {e}"));
+    match e.errno() {
+        libc::ENOENT | libc::ENXIO | libc::ENODEV => check.hint(
+            "load KVM (modprobe kvm_intel or kvm_amd) and enable VT-x/AMD-V in firmware; \
+             inside a VM, enable nested virtualization on its host",
+        ),
+        libc::EACCES | libc::EPERM => check.hint(
+            "add yourself to the group owning /dev/kvm (usually kvm): \
+             sudo usermod -aG kvm $USER, then log in again",
+        ),
+        _ => check,
+    }
+}
+
+/// Fail, naming it, if KVM lacks a capability Carbon can't run without.
+pub(super) fn require_capabilities(kvm: &Kvm) -> Result<(), KvmError> {
+    match REQUIRED_CAPABILITIES
+        .iter()
+        .find(|&&(_, cap)| kvm.check_extension_raw(cap as _) <= 0)
+    {
+        Some(&(name, _)) => Err(KvmError::MissingCapability(name)),
+        None => Ok(()),
+    }
+}
+
+/// Query `/dev/kvm` for its API version and capabilities.
+pub fn probe_kvm() -> Result<KvmInfo, KvmError> {
+    let kvm = Kvm::new().map_err(KvmError::OpenKvm)?;
+    let capabilities = CAPABILITIES
+        .iter()
+        .map(|&(name, cap)| (name, kvm.check_extension_raw(cap as _)))
+        .collect();
+    Ok(KvmInfo {
+        api_version: kvm.get_api_version(),
+        capabilities,
+        ptp_kvm: ptp_kvm_status(),
+    })
+}
+
+/// Whether KVM will answer the guest's `ptp_kvm` clock-pairing hypercall.
+///
+/// KVM only pairs clocks while the host clocksource is `tsc`; on anything
+/// else the guest driver loads but every read fails.
+pub fn ptp_kvm_status() -> Result<(), String> {
+    match std::fs::read_to_string(CLOCKSOURCE_PATH) {
+        Ok(source) => check_clocksource(source.trim()),
+        Err(e) => Err(format!("cannot read host clocksource: {e}")),
+    }
+}
+
+fn check_clocksource(source: &str) -> Result<(), String> {
+    if source == "tsc" {
+        Ok(())
+    } else {
+        Err(format!("host clocksource is {source}, not tsc"))
+    }
+}
+
+/// Identify the host CPU.
+pub fn probe_cpu() -> CpuInfo {
+    let max_ext = cpuid(0x8000_0000, 0).eax;
+    let features = CPU_FEATURES
+        .iter()
+        .map(|&(name, leaf, subleaf, reg, bit)| {
+            let present = if leaf >= 0x8000_0000 && leaf > max_ext {
+                false
+            } else {
+                let r = cpuid(leaf, subleaf);
+                let value = match reg {
+                    Reg::Ebx => r.ebx,
+                    Reg::Ecx => r.ecx,
+                    Reg::Edx => r.edx,
+                };
+                value & (1 << bit) != 0
+            };
+            (name, present)
+        })
+        .collect();
+
+    let leaf0 = cpuid(0, 0);
+    let vendor = registers_to_string(&[leaf0.ebx, leaf0.edx, leaf0.ecx]);
+    let brand = if max_ext >= 0x8000_0004 {
+        let regs: Vec<u32> = (0x8000_0002..=0x8000_0004)
+            .flat_map(|leaf| {
+                let r = cpuid(leaf, 0);
+                [r.eax, r.ebx, r.ecx, r.edx]
+            })
+            .collect();
+        registers_to_string(&regs)
+    } else {
+        String::new()
+    };
+
+    CpuInfo {
+        vendor,
+        brand,
+        features,
+    }
+}
+
+fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    __cpuid_count(leaf, subleaf)
+}
+
+/// Decode CPUID registers holding ASCII text (NUL-padded).
+fn registers_to_string(regs: &[u32]) -> String {
+    let bytes: Vec<u8> = regs.iter().flat_map(|r| r.to_le_bytes()).collect();
+    String::from_utf8_lossy(&bytes)
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
+}
+
+impl fmt::Display for KvmInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "kvm api version: {}", self.api_version)?;
+        writeln!(f, "kvm capabilities:")?;
+        for (name, value) in &self.capabilities {
+            match value {
+                0 => writeln!(f, "  {:<16} no", name)?,
+                1 => writeln!(f, "  {:<16} yes", name)?,
+                n => writeln!(f, "  {:<16} {}", name, n)?,
+            }
+        }
+        match &self.ptp_kvm {
+            Ok(()) => writeln!(f, "guest ptp_kvm: available"),
+            Err(reason) => writeln!(f, "guest ptp_kvm: unavailable ({reason})"),
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "carbon {}", self.version)?;
+        writeln!(
+            f,
+            "kvm: {}",
+            if self.kvm {
+                "available"
+            } else {
+                "unavailable (see `carbon check`)"
+            }
+        )?;
+        writeln!(f, "max vcpus: {}", self.max_vcpus)?;
+        writeln!(f, "max memory slots: {}", self.max_memory_slots)?;
+        writeln!(f, "cpu: {} ({})", self.cpu.brand, self.cpu.vendor)?;
+        writeln!(f, "cpu features: {}", self.cpu.features.join(" "))?;
+        writeln!(f, "cpu models: {}", self.cpu_models.join(" "))?;
+        writeln!(f, "features:")?;
+        for (name, feature) in &self.features {
+            let supported = if feature.supported { "yes" } else { "no" };
+            writeln!(f, "  {name:<12} {supported:<3}  {}", feature.detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.0 {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "{:<5} {}: {}", status, check.name, check.detail)?;
+            if let Some(hint) = &check.hint {
+                writeln!(f, "      hint: {hint}")?;
+            }
+        }
+        let failed = self.0.iter().filter(|c| c.status == Status::Fail).count();
+        let warned = self.0.iter().filter(|c| c.status == Status::Warn).count();
+        match failed {
+            0 => writeln!(f, "carbon can run VMs on this host ({warned} warnings)"),
+            n => writeln!(f, "carbon can't run VMs on this host ({n} failed)"),
+        }
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "host cpu: {} ({})", self.brand, self.vendor)?;
+        let present: Vec<&str> = self
+            .features
+            .iter()
+            .filter(|(_, has)| *has)
+            .map(|(name, _)| *name)
+            .collect();
+        let missing: Vec<&str> = self
+            .features
+            .iter()
+            .filter(|(_, has)| !*has)
+            .map(|(name, _)| *name)
+            .collect();
+        writeln!(f, "host cpu features: {}", present.join(" "))?;
+        writeln!(f, "host cpu missing: {}", missing.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers_to_string() {
+        // "GenuineIntel" is returned in EBX, EDX, ECX order
+        assert_eq!(
+            registers_to_string(&[0x756e_6547, 0x4965_6e69, 0x6c65_746e]),
+            "GenuineIntel"
+        );
+        assert_eq!(registers_to_string(&[0x2020_4120, 0]), "A");
+    }
+
+    #[test]
+    fn test_ptp_kvm_needs_tsc_clocksource() {
+        assert_eq!(check_clocksource("tsc"), Ok(()));
+        assert!(check_clocksource("hpet").unwrap_err().contains("hpet"));
+    }
+
+    #[test]
+    fn test_check_report() {
+        let mut report = CheckReport(vec![
+            Check::new("/dev/kvm", Status::Ok, "readable and writable"),
+            Check::new("ioeventfd", Status::Warn, "missing"),
+        ]);
+        assert!(report.passed());
+        let failure = open_failure(kvm_ioctls::Error::new(libc::EACCES));
+        assert!(failure.hint.as_ref().unwrap().contains("usermod"));
+        report.0.push(failure);
+        assert!(!report.passed());
+        let text = report.to_string();
+        assert!(text.starts_with("ok    /dev/kvm: readable and writable\n"));
+        assert!(text.contains("FAIL  /dev/kvm: cannot open"));
+        assert!(text.ends_with("carbon can't run VMs on this host (1 failed)\n"));
+    }
+
+    #[test]
+    fn test_hugepages() {
+        let dir = std::env::temp_dir().join(format!("carbon-hugepages-{}", std::process::id()));
+        assert!(!hugepages(&dir).supported);
+
+        let pool = dir.join("hugepages-2048kB");
+        std::fs::create_dir_all(&pool).unwrap();
+        std::fs::write(pool.join("nr_hugepages"), "8\n").unwrap();
+        std::fs::write(pool.join("free_hugepages"), "0\n").unwrap();
+        let feature = hugepages(&dir);
+        assert!(!feature.supported);
+        assert!(
+            feature.detail.contains("2048kB: 0 free of 8"),
+            "{feature:?}"
+        );
+
+        std::fs::write(pool.join("free_hugepages"), "6\n").unwrap();
+        let feature = hugepages(&dir);
+        assert!(feature.supported);
+        assert!(
+            feature.detail.contains("2048kB: 6 free of 8"),
+            "{feature:?}"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        assert!(capabilities.max_vcpus <= Topology::MAX_CPUS);
+        assert!(!capabilities.features["sev"].supported);
+        assert!(capabilities.cpu_models.contains(&"portable".to_string()));
+        let json = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["features"]["io_uring"]["supported"].is_boolean());
+        let text = capabilities.to_string();
+        assert!(text.contains("\n  sev          no   "), "{text}");
+    }
+
+    #[test]
+    fn test_probe_cpu_reports_every_feature() {
+        let cpu = probe_cpu();
+        assert!(!cpu.vendor.is_empty());
+        assert_eq!(cpu.features.len(), CPU_FEATURES.len());
+    }
+}
@@ -0,0 +1,425 @@
+//! Hyper-V enlightenments (`--hyperv`).
+//!
+//! KVM can present the paravirtual interfaces of Microsoft's hypervisor as
+//! well as its own. Windows, and software written against Hyper-V, use them
+//! to avoid the slow paths of emulated hardware:
+//!
+//! - **relaxed**: tells the guest not to treat long delays as hardware
+//!   faults, so a descheduled vCPU doesn't trip a watchdog (a CPUID hint).
+//! - **vapic**: the APIC's EOI, ICR and TPR as MSRs, plus an assist page
+//!   that lets most EOIs skip the exit altogether.
+//! - **synic**: the synthetic interrupt controller, message and event
+//!   pages the guest receives hypervisor interrupts through.
+//! - **stimer**: synthetic timers on top of the SynIC, which Windows
+//!   prefers over the LAPIC and HPET timers.
+//!
+//! The partition reference counter and TSC page, the hypercall page and
+//! the VP index always come with them. An enlightenment the host's KVM
+//! lacks is left out, with a warning.
+//!
+//! # CPUID Layout
+//!
+//! Hyper-V's leaves start at 0x4000_0000, where KVM's own are, so with
+//! enlightenments on KVM's leaves move up to 0x4000_0100, the next base
+//! guests scan. The vendor signature stays KVM's `Linux KVM Hv`, which
+//! Windows ignores: Linux guests keep finding KVM, and its kvm-clock,
+//! rather than taking the Hyper-V paths meant for Microsoft's hypervisor.
+
+use super::KvmError;
+use kvm_bindings::{
+    kvm_cpuid_entry2, kvm_enable_cap, CpuId, KVM_CAP_HYPERV_SYNIC2, KVM_CAP_HYPERV_TIME,
+    KVM_CAP_HYPERV_VAPIC, KVM_CAP_SYS_HYPERV_CPUID, KVM_MAX_CPUID_ENTRIES,
+};
+use kvm_ioctls::Kvm;
+use std::fmt;
+use std::os::unix::io::AsRawFd;
+
+/// `_IOWR(KVMIO, 0xc1, struct kvm_cpuid2)`, on `/dev/kvm`.
+const KVM_GET_SUPPORTED_HV_CPUID: u64 = (3 << 30) | (8 << 16) | (0xae << 8) | 0xc1;
+
+/// Vendor and highest Hyper-V leaf.
+const HV_CPUID_VENDOR: u32 = 0x4000_0000;
+
+/// Features: partition privileges (EAX, EBX) and feature flags (EDX).
+const HV_CPUID_FEATURES: u32 = 0x4000_0003;
+
+/// Implementation recommendations.
+const HV_CPUID_ENLIGHTENMENTS: u32 = 0x4000_0004;
+
+/// Implementation limits, the last leaf exposed.
+const HV_CPUID_LIMITS: u32 = 0x4000_0005;
+
+/// How far KVM's leaves move to make room for Hyper-V's.
+const KVM_LEAF_SHIFT: u32 = 0x100;
+
+/// Partition privileges (leaf 0x4000_0003 EAX) that come with any
+/// enlightenment: VP runtime (0), partition reference counter (1),
+/// hypercall MSRs (5), VP index (6), reference TSC page (9) and the TSC
+/// frequency MSRs (11).
+const HV_BASE_PRIVILEGES: u32 = (1 << 0) | (1 << 1) | (1 << 5) | (1 << 6) | (1 << 9) | (1 << 11);
+
+/// Privilege: SynIC MSRs.
+const HV_SYNIC_PRIVILEGE: u32 = 1 << 2;
+
+/// Privilege: synthetic timer MSRs.
+const HV_STIMER_PRIVILEGE: u32 = 1 << 3;
+
+/// Privilege: APIC access MSRs.
+const HV_VAPIC_PRIVILEGE: u32 = 1 << 4;
+
+/// Feature (leaf 0x4000_0003 EDX): TSC and APIC frequencies readable.
+const HV_FREQUENCY_FEATURE: u32 = 1 << 8;
+
+/// Feature: synthetic timers may interrupt directly, without the SynIC
+/// message page.
+const HV_STIMER_DIRECT_FEATURE: u32 = 1 << 19;
+
+/// Recommendation (leaf 0x4000_0004 EAX): APIC access through MSRs.
+const HV_APIC_MSRS_RECOMMENDED: u32 = 1 << 3;
+
+/// Recommendation: relaxed timing.
+const HV_RELAXED_TIMING_RECOMMENDED: u32 = 1 << 5;
+
+/// Recommendation, which KVM makes when it uses APIC virtualization: don't
+/// use auto-EOI, which would turn it off.
+const HV_DEPRECATING_AEOI_RECOMMENDED: u32 = 1 << 9;
+
+/// Spinlock retries before notifying the hypervisor (leaf 0x4000_0004
+/// EBX): never.
+const HV_SPINLOCK_NEVER_NOTIFY: u32 = u32::MAX;
+
+/// Hyper-V MSRs saved with a vCPU: the guest OS ID before the hypercall
+/// page, which KVM ignores until the ID is set.
+const HV_BASE_MSRS: [u32; 3] = [0x4000_0000, 0x4000_0001, 0x4000_0021];
+
+/// APIC assist page MSR.
+const HV_VAPIC_MSRS: [u32; 1] = [0x4000_0073];
+
+/// SynIC control, event flags page, message page and SINT0-15 MSRs.
+const HV_SYNIC_MSRS: [u32; 19] = [
+    0x4000_0080,
+    0x4000_0082,
+    0x4000_0083,
+    0x4000_0090,
+    0x4000_0091,
+    0x4000_0092,
+    0x4000_0093,
+    0x4000_0094,
+    0x4000_0095,
+    0x4000_0096,
+    0x4000_0097,
+    0x4000_0098,
+    0x4000_0099,
+    0x4000_009a,
+    0x4000_009b,
+    0x4000_009c,
+    0x4000_009d,
+    0x4000_009e,
+    0x4000_009f,
+];
+
+/// STIMER0-3 config and count MSRs.
+const HV_STIMER_MSRS: [u32; 8] = [
+    0x4000_00b0,
+    0x4000_00b1,
+    0x4000_00b2,
+    0x4000_00b3,
+    0x4000_00b4,
+    0x4000_00b5,
+    0x4000_00b6,
+    0x4000_00b7,
+];
+
+/// The Hyper-V enlightenments a VM exposes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Enlightenments {
+    pub relaxed: bool,
+    pub vapic: bool,
+    pub synic: bool,
+    pub stimer: bool,
+}
+
+impl Enlightenments {
+    /// The enlightenments not in `self`.
+    pub fn missing(self) -> Self {
+        Self {
+            relaxed: !self.relaxed,
+            vapic: !self.vapic,
+            synic: !self.synic,
+            stimer: !self.stimer,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self == Self::default()
+    }
+}
+
+impl fmt::Display for Enlightenments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (self.relaxed, "relaxed"),
+            (self.vapic, "vapic"),
+            (self.synic, "synic"),
+            (self.stimer, "stimer"),
+        ];
+        let names: Vec<_> = names
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, n)| *n)
+            .collect();
+        f.write_str(&names.join(" "))
+    }
+}
+
+/// Hyper-V as one VM presents it: the CPUID leaves of its enlightenments.
+#[derive(Debug, Clone)]
+pub struct Hyperv {
+    enlightenments: Enlightenments,
+    leaves: Vec<kvm_cpuid_entry2>,
+}
+
+impl Hyperv {
+    /// The enlightenments `kvm` supports, and their leaves. `None` if KVM
+    /// can't report its Hyper-V leaves: before Linux 5.11, or built without
+    /// Hyper-V support (`CONFIG_KVM_HYPERV`).
+    pub fn probe(kvm: &Kvm) -> Result<Option<Self>, KvmError> {
+        if kvm.check_extension_raw(KVM_CAP_SYS_HYPERV_CPUID as _) <= 0 {
+            return Ok(None);
+        }
+        let supported = supported_hv_cpuid(kvm)?;
+        let privileges = supported
+            .iter()
+            .find(|e| e.function == HV_CPUID_FEATURES)
+            .map_or(0, |e| e.eax);
+        let has = |cap: u32, privilege: u32| {
+            kvm.check_extension_raw(cap as _) > 0 && privileges & privilege != 0
+        };
+        let synic = has(KVM_CAP_HYPERV_SYNIC2, HV_SYNIC_PRIVILEGE);
+        let enlightenments = Enlightenments {
+            relaxed: true,
+            vapic: has(KVM_CAP_HYPERV_VAPIC, HV_VAPIC_PRIVILEGE),
+            synic,
+            stimer: synic && has(KVM_CAP_HYPERV_TIME, HV_STIMER_PRIVILEGE),
+        };
+        Ok(Some(Self {
+            enlightenments,
+            leaves: leaves(&supported, enlightenments),
+        }))
+    }
+
+    pub fn enlightenments(&self) -> Enlightenments {
+        self.enlightenments
+    }
+
+    /// Move KVM's leaves in `entries` up and add Hyper-V's in their place.
+    pub fn apply_cpuid(&self, entries: &mut Vec<kvm_cpuid_entry2>) {
+        let kvm_leaves = 0x4000_0000..0x4000_0000 + KVM_LEAF_SHIFT;
+        for entry in entries.iter_mut() {
+            if kvm_leaves.contains(&entry.function) {
+                // The vendor leaf names the highest KVM leaf
+                if entry.function == 0x4000_0000 && kvm_leaves.contains(&entry.eax) {
+                    entry.eax += KVM_LEAF_SHIFT;
+                }
+                entry.function += KVM_LEAF_SHIFT;
+            }
+        }
+        entries.extend_from_slice(&self.leaves);
+    }
+
+    /// Prepare a new vCPU for the enlightenments, before its first run.
+    pub fn enable(&self, vcpu: &kvm_ioctls::VcpuFd) -> Result<(), KvmError> {
+        if self.enlightenments.synic {
+            let cap = kvm_enable_cap {
+                cap: KVM_CAP_HYPERV_SYNIC2,
+                ..Default::default()
+            };
+            vcpu.enable_cap(&cap).map_err(KvmError::EnableHyperv)?;
+        }
+        Ok(())
+    }
+
+    /// The Hyper-V MSRs a vCPU holds state in, in restore order.
+    pub fn msrs(&self) -> Vec<u32> {
+        let e = self.enlightenments;
+        let mut msrs = HV_BASE_MSRS.to_vec();
+        if e.vapic {
+            msrs.extend(HV_VAPIC_MSRS);
+        }
+        if e.synic {
+            msrs.extend(HV_SYNIC_MSRS);
+        }
+        if e.stimer {
+            msrs.extend(HV_STIMER_MSRS);
+        }
+        msrs
+    }
+}
+
+/// The Hyper-V leaves KVM supports, via `KVM_GET_SUPPORTED_HV_CPUID`.
+fn supported_hv_cpuid(kvm: &Kvm) -> Result<Vec<kvm_cpuid_entry2>, KvmError> {
+    let mut cpuid = CpuId::new(KVM_MAX_CPUID_ENTRIES)
+        .map_err(|_| KvmError::GetSupportedHvCpuid(kvm_ioctls::Error::new(libc::ENOMEM)))?;
+    // SAFETY: the buffer holds a kvm_cpuid2 header with room for the
+    // number of entries it declares, which is all KVM writes.
+    let ret = unsafe {
+        libc::ioctl(
+            kvm.as_raw_fd(),
+            KVM_GET_SUPPORTED_HV_CPUID as _,
+            cpuid.as_mut_fam_struct_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(KvmError::GetSupportedHvCpuid(kvm_ioctls::Error::last()));
+    }
+    Ok(cpuid.as_slice().to_vec())
+}
+
+/// The leaves of `enlightenments`, from those KVM supports.
+fn leaves(supported: &[kvm_cpuid_entry2], enlightenments: Enlightenments) -> Vec<kvm_cpuid_entry2> {
+    let mut leaves: Vec<_> = supported
+        .iter()
+        .filter(|e| (HV_CPUID_VENDOR..=HV_CPUID_LIMITS).contains(&e.function))
+        .copied()
+        .collect();
+    for leaf in &mut leaves {
+        match leaf.function {
+            HV_CPUID_VENDOR => leaf.eax = HV_CPUID_LIMITS,
+            HV_CPUID_FEATURES => {
+                let mut privileges = HV_BASE_PRIVILEGES;
+                let mut features = HV_FREQUENCY_FEATURE;
+                if enlightenments.vapic {
+                    privileges |= HV_VAPIC_PRIVILEGE;
+                }
+                if enlightenments.synic {
+                    privileges |= HV_SYNIC_PRIVILEGE;
+                }
+                if enlightenments.stimer {
+                    privileges |= HV_STIMER_PRIVILEGE;
+                    features |= HV_STIMER_DIRECT_FEATURE;
+                }
+                leaf.eax &= privileges;
+                leaf.ebx = 0;
+                leaf.ecx = 0;
+                leaf.edx &= features;
+            }
+            HV_CPUID_ENLIGHTENMENTS => {
+                let mut recommended = leaf.eax & HV_DEPRECATING_AEOI_RECOMMENDED;
+                if enlightenments.relaxed {
+                    recommended |= HV_RELAXED_TIMING_RECOMMENDED;
+                }
+                if enlightenments.vapic {
+                    recommended |= HV_APIC_MSRS_RECOMMENDED;
+                }
+                leaf.eax = recommended;
+                leaf.ebx = HV_SPINLOCK_NEVER_NOTIFY;
+                leaf.ecx = 0;
+                leaf.edx = 0;
+            }
+            _ => {}
+        }
+    }
+    leaves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(function: u32, eax: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            eax,
+            ebx: u32::MAX,
+            ecx: u32::MAX,
+            edx: u32::MAX,
+            ..Default::default()
+        }
+    }
+
+    /// Everything KVM reports, including leaves past the limits leaf.
+    fn supported() -> Vec<kvm_cpuid_entry2> {
+        (0x4000_0000..=0x4000_000a)
+            .chain([0x4000_0080])
+            .map(|function| leaf(function, u32::MAX))
+            .collect()
+    }
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // Value from <linux/kvm.h> on x86_64
+        assert_eq!(KVM_GET_SUPPORTED_HV_CPUID, 0xc008_aec1);
+    }
+
+    #[test]
+    fn test_leaves() {
+        let relaxed = Enlightenments {
+            relaxed: true,
+            ..Default::default()
+        };
+        let relaxed = leaves(&supported(), relaxed);
+        assert_eq!(relaxed.len(), 6);
+        assert_eq!(relaxed[0].eax, HV_CPUID_LIMITS);
+        let features = relaxed[3];
+        assert_eq!(features.eax, HV_BASE_PRIVILEGES);
+        assert_eq!(features.edx, HV_FREQUENCY_FEATURE);
+        let recommended = relaxed[4];
+        assert_eq!(
+            recommended.eax,
+            HV_RELAXED_TIMING_RECOMMENDED | HV_DEPRECATING_AEOI_RECOMMENDED
+        );
+
+        let all = Enlightenments {
+            relaxed: true,
+            vapic: true,
+            synic: true,
+            stimer: true,
+        };
+        assert_eq!(all.to_string(), "relaxed vapic synic stimer");
+        let features = leaves(&supported(), all)[3];
+        assert_ne!(features.eax & HV_STIMER_PRIVILEGE, 0);
+        assert_ne!(features.edx & HV_STIMER_DIRECT_FEATURE, 0);
+    }
+
+    #[test]
+    fn test_apply_cpuid_moves_kvm_leaves() {
+        let hyperv = Hyperv {
+            enlightenments: Enlightenments::default(),
+            leaves: vec![leaf(HV_CPUID_VENDOR, HV_CPUID_LIMITS)],
+        };
+        let mut entries = vec![
+            leaf(0x1, 0),
+            leaf(0x4000_0000, 0x4000_0010),
+            leaf(0x4000_0001, 0),
+            leaf(0x4000_0010, 2_000_000),
+        ];
+        hyperv.apply_cpuid(&mut entries);
+        let functions: Vec<_> = entries.iter().map(|e| (e.function, e.eax)).collect();
+        assert_eq!(
+            functions,
+            [
+                (0x1, 0),
+                (0x4000_0100, 0x4000_0110),
+                (0x4000_0101, 0),
+                (0x4000_0110, 2_000_000),
+                (0x4000_0000, HV_CPUID_LIMITS),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_msrs() {
+        let hyperv = Hyperv {
+            enlightenments: Enlightenments {
+                relaxed: true,
+                synic: true,
+                ..Default::default()
+            },
+            leaves: Vec::new(),
+        };
+        let msrs = hyperv.msrs();
+        assert_eq!(msrs.len(), HV_BASE_MSRS.len() + HV_SYNIC_MSRS.len());
+        // The guest OS ID goes before the hypercall page
+        assert_eq!(&msrs[..2], [0x4000_0000, 0x4000_0001]);
+    }
+}
@@ -0,0 +1,109 @@
+//! Interrupt injection for devices.
+//!
+//! Devices raise their GSI through an [`IrqLine`], handed to them when they
+//! are registered and created with [`VmFd::irq_line`](super::VmFd::irq_line).
+//! A device only calls [`IrqLine::trigger`]; how the interrupt reaches the
+//! in-kernel IOAPIC depends on what KVM offers:
+//!
+//! - An [`Irqfd`], where KVM has `KVM_CAP_IRQFD`: an eventfd that KVM
+//!   watches, pulsing the GSI whenever it is written. That works from any
+//!   thread (a backend thread can interrupt the guest while the vCPU runs)
+//!   and costs no VM exit, and the eventfd can be handed to another process
+//!   (a vhost-user backend) to interrupt the guest directly.
+//! - A [`LineIrq`] otherwise: `KVM_IRQ_LINE` on the VM, raising and
+//!   lowering the GSI, an ioctl per interrupt.
+//!
+//! A pulse is one interrupt because the DSDT declares virtio-mmio lines
+//! edge-triggered. The guest finds out why from the device's
+//! INTERRUPT_STATUS register, so devices set that before triggering.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use vmm_sys_util::eventfd::EventFd;
+
+/// An interrupt line a device raises.
+pub trait IrqLine: Send + Sync {
+    /// Interrupt the guest.
+    fn trigger(&self);
+
+    /// The eventfd that raises this line, for handing to another process
+    /// (e.g. as a vhost-user call eventfd), if it has one.
+    fn irqfd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Raises one GSI through a KVM irqfd.
+#[derive(Debug)]
+pub struct Irqfd {
+    eventfd: EventFd,
+    gsi: u32,
+}
+
+impl Irqfd {
+    pub(super) fn new(eventfd: EventFd, gsi: u32) -> Self {
+        Self { eventfd, gsi }
+    }
+
+    /// An irqfd no VM listens to, for device tests.
+    #[cfg(test)]
+    pub fn unconnected(gsi: u32) -> Self {
+        Self::new(EventFd::new(libc::EFD_NONBLOCK).unwrap(), gsi)
+    }
+}
+
+impl IrqLine for Irqfd {
+    fn trigger(&self) {
+        // Only fails if the counter would overflow, which KVM draining it
+        // on every pulse prevents
+        if let Err(e) = self.eventfd.write(1) {
+            warn!("[IRQ] Failed to raise GSI {}: {}", self.gsi, e);
+        }
+    }
+
+    fn irqfd(&self) -> Option<RawFd> {
+        Some(self.eventfd.as_raw_fd())
+    }
+}
+
+/// Raises one GSI with `KVM_IRQ_LINE`.
+pub struct LineIrq {
+    vm: Arc<kvm_ioctls::VmFd>,
+    gsi: u32,
+}
+
+impl LineIrq {
+    pub(super) fn new(vm: Arc<kvm_ioctls::VmFd>, gsi: u32) -> Self {
+        Self { vm, gsi }
+    }
+
+    fn set_level(&self, active: bool) -> io::Result<()> {
+        self.vm
+            .set_irq_line(self.gsi, active)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+}
+
+impl IrqLine for LineIrq {
+    fn trigger(&self) {
+        if let Err(e) = self.set_level(true).and_then(|()| self.set_level(false)) {
+            warn!("[IRQ] Failed to raise GSI {}: {}", self.gsi, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irqfd() {
+        let irq = Irqfd::unconnected(5);
+        let line: &dyn IrqLine = &irq;
+        line.trigger();
+        line.trigger();
+        assert_eq!(irq.eventfd.read().unwrap(), 2);
+        assert_eq!(line.irqfd(), Some(irq.eventfd.as_raw_fd()));
+    }
+}
@@ -62,7 +62,7 @@
 //!
 //! ```ignore
 //! // Create a VM
-//! let vm = kvm::create_vm()?;
+//! let vm = kvm::create_vm(CpuMode::Host, &CpuFeatures::default(), Topology::default(), false, false)?;
 //!
 //! // Set up memory
 //! vm.set_user_memory_region(0, 0, size, host_addr)?;
@@ -85,13 +85,35 @@
 //! }
 //! ```
 
+mod affinity;
+mod cpuid;
+mod debug;
+pub mod host;
+mod hyperv;
+mod irq;
+mod state;
+mod stats;
+mod topology;
 mod vcpu;
 mod vm;
 
+pub use affinity::{Cores, CpuAffinity};
+pub use cpuid::{CpuFeatures, CpuMode};
+pub use debug::GuestRam;
+
+use cpuid::{hide_nested_virt, NESTED_VIRT_FEATURES};
+pub use irq::IrqLine;
+#[cfg(test)]
+pub use irq::Irqfd;
+pub use state::{ClockState, NestedState, VcpuState, VmState};
+pub use stats::{StatsReader, VcpuStats};
+pub use topology::Topology;
 pub use vcpu::{IoData, IoHandler, MmioHandler, VcpuExit, VcpuFd};
 pub use vm::VmFd;
 
-use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+use crate::audit;
+use hyperv::Hyperv;
+use kvm_bindings::{KVM_CAP_NESTED_STATE, KVM_MAX_CPUID_ENTRIES};
 use kvm_ioctls::Kvm;
 use thiserror::Error;
 
@@ -107,6 +129,10 @@ pub enum KvmError {
     #[error("Failed to open /dev/kvm: {0}")]
     OpenKvm(#[source] kvm_ioctls::Error),
 
+    /// KVM lacks a capability Carbon can't run without.
+    #[error("KVM lacks the {0} capability; run `carbon check` for details")]
+    MissingCapability(&'static str),
+
     /// Failed to create a new VM.
     #[error("Failed to create VM: {0}")]
     CreateVm(#[source] kvm_ioctls::Error),
@@ -139,6 +165,22 @@ pub enum KvmError {
     #[error("Failed to create IRQ chip: {0}")]
     CreateIrqChip(#[source] kvm_ioctls::Error),
 
+    /// Failed to set up an irqfd for a device interrupt.
+    #[error("Failed to register irqfd for GSI {gsi}: {source}")]
+    RegisterIrqfd {
+        gsi: u32,
+        #[source]
+        source: kvm_ioctls::Error,
+    },
+
+    /// Failed to set up an ioeventfd for a device register.
+    #[error("Failed to register ioeventfd at {addr:#x}: {source}")]
+    RegisterIoeventfd {
+        addr: u64,
+        #[source]
+        source: kvm_ioctls::Error,
+    },
+
     /// Failed to create PIT (Programmable Interval Timer).
     #[error("Failed to create PIT2: {0}")]
     CreatePit2(#[source] kvm_ioctls::Error),
@@ -154,6 +196,70 @@ pub enum KvmError {
     /// Failed to set MSRs (Model Specific Registers).
     #[error("Failed to set MSRs: {0}")]
     SetMsrs(#[source] kvm_ioctls::Error),
+
+    /// Failed to read whether a vCPU is running, halted or waiting for SIPI.
+    #[error("Failed to get vCPU MP state: {0}")]
+    GetMpState(#[source] kvm_ioctls::Error),
+
+    /// Failed to read vCPU state (APIC, events, XSAVE, MSRs, ...).
+    #[error("Failed to save vCPU state: {0}")]
+    SaveState(#[source] kvm_ioctls::Error),
+
+    /// Failed to write vCPU state.
+    #[error("Failed to restore vCPU state: {0}")]
+    RestoreState(#[source] kvm_ioctls::Error),
+
+    /// Failed to get the Hyper-V CPUID entries KVM supports.
+    #[error("Failed to get supported Hyper-V CPUID: {0}")]
+    GetSupportedHvCpuid(#[source] kvm_ioctls::Error),
+
+    /// Failed to enable Hyper-V enlightenments on a vCPU.
+    #[error("Failed to enable Hyper-V SynIC: {0}")]
+    EnableHyperv(#[source] kvm_ioctls::Error),
+
+    /// Failed to read the interrupt controllers or PIT.
+    #[error("Failed to save VM state: {0}")]
+    SaveVmState(#[source] kvm_ioctls::Error),
+
+    /// Failed to write the interrupt controllers or PIT.
+    #[error("Failed to restore VM state: {0}")]
+    RestoreVmState(#[source] kvm_ioctls::Error),
+
+    /// Failed to read the VM's kvm-clock.
+    #[error("Failed to get kvm-clock: {0}")]
+    GetClock(#[source] kvm_ioctls::Error),
+
+    /// Failed to set the VM's kvm-clock.
+    #[error("Failed to set kvm-clock: {0}")]
+    SetClock(#[source] kvm_ioctls::Error),
+
+    /// Failed to read nested virtualization state.
+    #[error("Failed to get nested state: {0}")]
+    GetNestedState(#[source] kvm_ioctls::Error),
+
+    /// Failed to restore nested virtualization state.
+    #[error("Failed to set nested state: {0}")]
+    SetNestedState(#[source] kvm_ioctls::Error),
+
+    /// Failed to set breakpoint or single-step trapping.
+    #[error("Failed to set guest debug: {0}")]
+    SetGuestDebug(#[source] kvm_ioctls::Error),
+
+    /// Failed to translate a guest linear address.
+    #[error("Failed to translate guest address: {0}")]
+    Translate(#[source] kvm_ioctls::Error),
+
+    /// A guest linear address isn't mapped by the guest's page tables.
+    #[error("Guest address {0:#x} is not mapped")]
+    UnmappedAddress(u64),
+
+    /// Failed to read or patch guest memory while debugging.
+    #[error("Failed to access guest memory at {addr:#x}: {source}")]
+    GuestMemory {
+        addr: u64,
+        #[source]
+        source: std::io::Error,
+    },
 }
 
 /// Open the KVM device and create a new virtual machine.
@@ -164,6 +270,15 @@ pub enum KvmError {
 /// 3. Creates a new VM
 /// 4. Initializes required VM components (TSS, IRQ chip, PIT)
 ///
+/// The `cpu_mode` selects whether vCPUs see the full host CPUID or one of
+/// the portable templates (see [`CpuMode`]), `cpu_features` the features
+/// exposed or hidden on top of it (see [`CpuFeatures`]), and `topology` how
+/// they are arranged into sockets, cores and threads (see [`Topology`]).
+/// With `hyperv`, they also see the Hyper-V enlightenments this host's KVM
+/// supports (see [`hyperv`]). VMX and SVM stay hidden unless `nested` is
+/// set: a guest running a hypervisor of its own reaches far more of the
+/// host's KVM.
+///
 /// # CPUID
 ///
 /// The CPUID instruction allows software to query CPU features. KVM provides
@@ -181,19 +296,69 @@ pub enum KvmError {
 /// - KVM is not available or accessible
 /// - VM creation fails
 /// - Required VM components cannot be initialized
-pub fn create_vm() -> Result<VmFd, KvmError> {
+pub fn create_vm(
+    cpu_mode: CpuMode,
+    cpu_features: &CpuFeatures,
+    topology: Topology,
+    hyperv: bool,
+    nested: bool,
+) -> Result<VmFd, KvmError> {
     // Open /dev/kvm
     let kvm = Kvm::new().map_err(KvmError::OpenKvm)?;
+    audit::record(audit::Kind::Device, "open", "/dev/kvm");
+    host::require_capabilities(&kvm)?;
 
     // Query supported CPUID entries from KVM
     // These will be set on each vCPU so the guest sees appropriate CPU features
-    let supported_cpuid = kvm
+    let mut supported_cpuid = kvm
         .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
         .map_err(KvmError::GetSupportedCpuid)?;
+    let mut cpu_features = cpu_features.clone();
+    if !nested {
+        hide_nested_virt(supported_cpuid.as_mut_slice());
+    } else if !cpu_features.expose_nested_virt(supported_cpuid.as_slice()) {
+        warn!("[KVM] Nested virtualization not supported by this host (is kvm_intel or kvm_amd loaded with nested=1?), not enabled");
+    }
+    for feature in cpu_features.unsupported(supported_cpuid.as_slice()) {
+        if !nested && NESTED_VIRT_FEATURES.contains(&feature) {
+            warn!("[KVM] CPU feature {feature} needs --enable-nested, not exposed");
+        } else {
+            warn!("[KVM] CPU feature {feature} not supported by this host, not exposed");
+        }
+    }
+
+    let hyperv = if hyperv {
+        let probed = Hyperv::probe(&kvm)?;
+        match &probed {
+            Some(probed) => {
+                let enlightenments = probed.enlightenments();
+                info!("[KVM] Hyper-V enlightenments: {}", enlightenments);
+                let missing = enlightenments.missing();
+                if !missing.is_empty() {
+                    warn!("[KVM] Hyper-V {missing} not supported by this host, not exposed");
+                }
+            }
+            None => warn!("[KVM] Hyper-V enlightenments not supported by this host, not exposed"),
+        }
+        probed
+    } else {
+        None
+    };
 
     // Create the VM
     let vm = kvm.create_vm().map_err(KvmError::CreateVm)?;
 
+    // Maximum nested state size, or 0 if KVM can't save nested guests
+    let nested_state_size = kvm.check_extension_raw(KVM_CAP_NESTED_STATE as _).max(0) as usize;
+
     // Initialize VM components and return
-    VmFd::new(vm, supported_cpuid)
+    VmFd::new(
+        vm,
+        supported_cpuid,
+        cpu_mode,
+        cpu_features,
+        topology,
+        hyperv,
+        nested_state_size,
+    )
 }
@@ -0,0 +1,281 @@
+//! vCPU state capture and restore.
+//!
+//! A [`VcpuState`] holds everything KVM needs to resume a vCPU exactly where
+//! it stopped: general and special registers, XSAVE/XCR state, the local APIC,
+//! pending events, the MP state and the MSRs Carbon configures at boot.
+//!
+//! # Nested Virtualization
+//!
+//! When the guest can see VMX (Intel) or SVM (AMD) it may be running its own
+//! hypervisor. The L2 guest's state (VMCS/VMCB, VMXON region, pending nested
+//! VM-entry) lives inside KVM, not in any of the registers above, and is only
+//! reachable through `KVM_GET_NESTED_STATE` / `KVM_SET_NESTED_STATE`. Without
+//! it, a VM running KVM restores with a corrupt L2 guest.
+//!
+//! Nested state is an opaque, variable-sized blob whose maximum size is
+//! reported by `KVM_CAP_NESTED_STATE`. We capture it whenever the capability
+//! is present and the guest CPUID exposes VMX or SVM.
+//!
+//! # Guest Clock
+//!
+//! The kvm-clock a guest reads its time from belongs to the VM, not to any
+//! vCPU: [`ClockState`] captures it with `KVM_GET_CLOCK`. Each vCPU only
+//! holds the guest address of its pvclock page, in the kvm-clock MSRs saved
+//! with the rest. Restoring sets the clock to the captured value, so guest
+//! time resumes where it stopped instead of jumping by however long the VM
+//! was paused or stored, or going backwards on a host whose clock started
+//! later.
+//!
+//! # VM State
+//!
+//! Besides the clock, KVM keeps the in-kernel interrupt controllers (both
+//! 8259 PICs and the IOAPIC) and the 8254 PIT for the whole VM. [`VmState`]
+//! captures them with the clock: an interrupt the guest has yet to
+//! acknowledge, or a masked IOAPIC pin, is part of where it stopped just as
+//! much as its registers are.
+//!
+//! # Restore Order
+//!
+//! KVM validates nested state against the current vCPU mode, so special
+//! registers (EFER.SVME, CR4.VMXE) must be restored before nested state, and
+//! vCPU events (which may reference a pending nested exception) after it.
+//! The VM state goes before any vCPU, since writing a vCPU's kvm-clock MSR
+//! publishes the VM clock to its pvclock page.
+
+use kvm_bindings::{
+    kvm_clock_data, kvm_cpuid_entry2, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_msr_entry,
+    kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+    KVM_CLOCK_TSC_STABLE,
+};
+use serde::{Deserialize, Serialize};
+use std::os::unix::io::AsRawFd;
+
+/// KVM ioctl type number.
+const KVMIO: u64 = 0xae;
+
+/// Size of the fixed `kvm_nested_state` header (flags, format, size, hdr union).
+const NESTED_STATE_HEADER_SIZE: usize = 128;
+
+/// `_IOWR(KVMIO, 0xbe, struct kvm_nested_state)`
+const KVM_GET_NESTED_STATE: u64 =
+    (3 << 30) | ((NESTED_STATE_HEADER_SIZE as u64) << 16) | (KVMIO << 8) | 0xbe;
+
+/// `_IOW(KVMIO, 0xbf, struct kvm_nested_state)`
+const KVM_SET_NESTED_STATE: u64 =
+    (1 << 30) | ((NESTED_STATE_HEADER_SIZE as u64) << 16) | (KVMIO << 8) | 0xbf;
+
+/// Leaf 0x1 ECX bit 5: VMX (Intel VT-x).
+const CPUID_1_ECX_VMX: u32 = 1 << 5;
+
+/// Leaf 0x80000001 ECX bit 2: SVM (AMD-V).
+const CPUID_EXT_ECX_SVM: u32 = 1 << 2;
+
+/// Complete architectural state of one vCPU.
+pub struct VcpuState {
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub xsave: kvm_xsave,
+    pub xcrs: kvm_xcrs,
+    pub lapic: kvm_lapic_state,
+    pub events: kvm_vcpu_events,
+    pub mp_state: kvm_mp_state,
+    pub msrs: Vec<kvm_msr_entry>,
+    /// Nested virtualization state, present when the guest can run its own
+    /// hypervisor.
+    pub nested: Option<NestedState>,
+}
+
+/// State KVM keeps for the whole VM rather than for any one vCPU.
+pub struct VmState {
+    pub clock: ClockState,
+    /// PIC master, PIC slave and IOAPIC, in `KVM_GET_IRQCHIP` chip order.
+    pub irqchips: [kvm_irqchip; 3],
+    pub pit: kvm_pit_state2,
+}
+
+/// The VM's kvm-clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClockState {
+    /// Guest nanoseconds on the clock.
+    pub nanos: u64,
+    /// Whether KVM presented one clock, from a stable host TSC, to every
+    /// vCPU (`KVM_CLOCK_TSC_STABLE`).
+    pub tsc_stable: bool,
+}
+
+impl ClockState {
+    /// The state `KVM_GET_CLOCK` reported.
+    pub fn from_kvm(data: &kvm_clock_data) -> Self {
+        Self {
+            nanos: data.clock,
+            tsc_stable: data.flags & KVM_CLOCK_TSC_STABLE != 0,
+        }
+    }
+
+    /// The argument to `KVM_SET_CLOCK` restoring this state. It carries no
+    /// `KVM_CLOCK_REALTIME`, which would advance the clock by the host time
+    /// passed since the capture.
+    pub fn to_kvm(self) -> kvm_clock_data {
+        kvm_clock_data {
+            clock: self.nanos,
+            ..Default::default()
+        }
+    }
+}
+
+/// Opaque `kvm_nested_state` blob (header + VMX/SVM data).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NestedState {
+    data: Vec<u8>,
+}
+
+impl NestedState {
+    /// Wrap a raw `kvm_nested_state` buffer.
+    ///
+    /// Returns `None` if the buffer is shorter than the header or than the
+    /// size the header claims.
+    pub fn from_bytes(data: Vec<u8>) -> Option<Self> {
+        let size = header_size(&data)?;
+        if size < NESTED_STATE_HEADER_SIZE || size > data.len() {
+            return None;
+        }
+        Some(Self { data })
+    }
+
+    /// The raw `kvm_nested_state` buffer.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Read the `size` field of a `kvm_nested_state` header.
+fn header_size(data: &[u8]) -> Option<usize> {
+    let bytes = data.get(4..8)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// Whether the CPUID presented to the guest exposes VMX or SVM.
+pub fn nested_virt_exposed(entries: &[kvm_cpuid_entry2]) -> bool {
+    entries.iter().any(|e| match e.function {
+        0x1 => e.ecx & CPUID_1_ECX_VMX != 0,
+        0x8000_0001 => e.ecx & CPUID_EXT_ECX_SVM != 0,
+        _ => false,
+    })
+}
+
+/// Fetch the nested state of a vCPU via `KVM_GET_NESTED_STATE`.
+///
+/// `max_size` is the value reported by `KVM_CAP_NESTED_STATE`.
+pub fn get_nested_state(
+    vcpu: &impl AsRawFd,
+    max_size: usize,
+) -> Result<NestedState, kvm_ioctls::Error> {
+    let len = max_size.max(NESTED_STATE_HEADER_SIZE);
+    let mut data = vec![0u8; len];
+    // Tell KVM how large our buffer is via the header's size field
+    data[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+
+    // SAFETY: the buffer is at least as large as the size we told KVM,
+    // and the header layout matches struct kvm_nested_state.
+    let ret = unsafe {
+        libc::ioctl(
+            vcpu.as_raw_fd(),
+            KVM_GET_NESTED_STATE as _,
+            data.as_mut_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(kvm_ioctls::Error::last());
+    }
+
+    // KVM rewrites the size field with the bytes it actually used
+    let used = header_size(&data).unwrap_or(data.len()).min(data.len());
+    data.truncate(used.max(NESTED_STATE_HEADER_SIZE));
+    NestedState::from_bytes(data).ok_or(kvm_ioctls::Error::new(libc::EINVAL))
+}
+
+/// Restore the nested state of a vCPU via `KVM_SET_NESTED_STATE`.
+pub fn set_nested_state(vcpu: &impl AsRawFd, state: &NestedState) -> Result<(), kvm_ioctls::Error> {
+    // SAFETY: the buffer holds a complete kvm_nested_state of the size in
+    // its header (validated in NestedState::from_bytes); KVM only reads it.
+    let ret = unsafe {
+        libc::ioctl(
+            vcpu.as_raw_fd(),
+            KVM_SET_NESTED_STATE as _,
+            state.data.as_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(kvm_ioctls::Error::last());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::KVM_CLOCK_REALTIME;
+
+    fn cpuid(function: u32, ecx: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            ecx,
+            ..Default::default()
+        }
+    }
+
+    fn nested_blob(flags: u16, size: u32, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        data[0..2].copy_from_slice(&flags.to_le_bytes());
+        data[4..8].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // Values from <linux/kvm.h> on x86_64
+        assert_eq!(KVM_GET_NESTED_STATE, 0xc080_aebe);
+        assert_eq!(KVM_SET_NESTED_STATE, 0x4080_aebf);
+    }
+
+    #[test]
+    fn test_nested_virt_exposed() {
+        assert!(!nested_virt_exposed(&[
+            cpuid(0x1, 0),
+            cpuid(0x8000_0001, 0)
+        ]));
+        assert!(nested_virt_exposed(&[cpuid(0x1, CPUID_1_ECX_VMX)]));
+        assert!(nested_virt_exposed(&[cpuid(
+            0x8000_0001,
+            CPUID_EXT_ECX_SVM
+        )]));
+    }
+
+    #[test]
+    fn test_clock_state() {
+        let data = kvm_clock_data {
+            clock: 5_000_000_000,
+            flags: KVM_CLOCK_TSC_STABLE | KVM_CLOCK_REALTIME,
+            realtime: 1_700_000_000_000_000_000,
+            ..Default::default()
+        };
+        let clock = ClockState::from_kvm(&data);
+        assert_eq!(clock.nanos, 5_000_000_000);
+        assert!(clock.tsc_stable);
+
+        // Restored as captured, not advanced by the host time since
+        let restored = clock.to_kvm();
+        assert_eq!(restored.clock, 5_000_000_000);
+        assert_eq!(restored.flags, 0);
+    }
+
+    #[test]
+    fn test_nested_state_validates_size() {
+        assert!(NestedState::from_bytes(vec![0; 16]).is_none());
+        assert!(NestedState::from_bytes(nested_blob(0, 64, 128)).is_none());
+        assert!(NestedState::from_bytes(nested_blob(0, 8192, 4096)).is_none());
+
+        let state = NestedState::from_bytes(nested_blob(1, 128 + 4096, 128 + 4096)).unwrap();
+        assert_eq!(state.as_bytes().len(), 128 + 4096);
+    }
+}
@@ -0,0 +1,309 @@
+//! Per-vCPU runtime statistics.
+//!
+//! Where a vCPU's time goes: how often it leaves the guest for Carbon and
+//! why, and how long it spends inside `KVM_RUN` (running the guest, or in
+//! KVM handling exits that never reach Carbon) against handling exits in
+//! Carbon between runs. Carbon counts these itself, on every host, as each
+//! run returns: a vCPU still in the guest hasn't added its current run yet.
+//!
+//! Where KVM has binary statistics (`KVM_GET_STATS_FD`, Linux 5.14), the
+//! vCPU's own counters are added: every exit KVM handled, by kind, and how
+//! halts were spent polling or waiting. [`KVM_STATS`] lists those reported.
+//! Formatted, the statistics are one line of `name=value` pairs:
+//!
+//! ```text
+//! exits=1520 io=1200 mmio=300 hlt=12 interrupted=8 other=0 guest_ms=950 host_ms=41 kvm.exits=48210 ...
+//! ```
+//!
+//! A [`StatsReader`], from [`VcpuFd::stats_reader`](super::VcpuFd::stats_reader),
+//! reads them from any thread while the vCPU runs; the control socket's
+//! `vcpu-stats` command serves them.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use kvm_bindings::{
+    kvm_stats_desc, kvm_stats_header, KVM_STATS_TYPE_CUMULATIVE, KVM_STATS_TYPE_INSTANT,
+    KVM_STATS_TYPE_MASK, KVM_STATS_TYPE_PEAK,
+};
+
+/// `KVM_GET_STATS_FD`: `_IO(KVMIO, 0xce)`.
+const KVM_GET_STATS_FD: u64 = (0xae << 8) | 0xce;
+
+/// The KVM statistics reported, of those the host's KVM has.
+pub const KVM_STATS: [&str; 14] = [
+    "exits",
+    "io_exits",
+    "mmio_exits",
+    "halt_exits",
+    "irq_exits",
+    "signal_exits",
+    "irq_window_exits",
+    "insn_emulation",
+    "halt_attempted_poll",
+    "halt_successful_poll",
+    "halt_wakeup",
+    "halt_poll_success_ns",
+    "halt_poll_fail_ns",
+    "halt_wait_ns",
+];
+
+/// Why a vCPU returned to Carbon, as counted.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Exit {
+    Io,
+    Mmio,
+    Hlt,
+    Interrupted,
+    Other,
+}
+
+/// The names of [`Exit`]'s variants, in order.
+const EXITS: [&str; 5] = ["io", "mmio", "hlt", "interrupted", "other"];
+
+/// The counters a vCPU updates as it runs.
+#[derive(Debug, Default)]
+pub(super) struct Counters {
+    exits: [AtomicU64; EXITS.len()],
+    guest_ns: AtomicU64,
+    host_ns: AtomicU64,
+}
+
+impl Counters {
+    /// Count a return from `KVM_RUN` after `guest` inside it, and `host`
+    /// in Carbon before it.
+    pub fn record(&self, exit: Exit, guest: Duration, host: Duration) {
+        self.exits[exit as usize].fetch_add(1, Ordering::Relaxed);
+        self.guest_ns
+            .fetch_add(guest.as_nanos() as u64, Ordering::Relaxed);
+        self.host_ns
+            .fetch_add(host.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// A vCPU's statistics at one moment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VcpuStats {
+    /// Returns to Carbon, by reason.
+    pub exits: Vec<(&'static str, u64)>,
+    /// Time inside `KVM_RUN`: in the guest, or KVM handling its exits.
+    pub guest: Duration,
+    /// Time in Carbon between runs, handling exits.
+    pub host: Duration,
+    /// KVM's own counters, if it has binary statistics.
+    pub kvm: Vec<(&'static str, u64)>,
+}
+
+impl VcpuStats {
+    /// Returns to Carbon, for any reason.
+    pub fn total_exits(&self) -> u64 {
+        self.exits.iter().map(|&(_, count)| count).sum()
+    }
+}
+
+impl fmt::Display for VcpuStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exits={}", self.total_exits())?;
+        for (name, count) in &self.exits {
+            write!(f, " {name}={count}")?;
+        }
+        write!(
+            f,
+            " guest_ms={} host_ms={}",
+            self.guest.as_millis(),
+            self.host.as_millis()
+        )?;
+        for (name, value) in &self.kvm {
+            write!(f, " kvm.{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a vCPU's statistics, from any thread.
+#[derive(Debug, Clone)]
+pub struct StatsReader {
+    counters: Arc<Counters>,
+    kvm: Option<Arc<BinaryStats>>,
+}
+
+impl StatsReader {
+    pub(super) fn new(counters: Arc<Counters>, kvm: Option<Arc<BinaryStats>>) -> Self {
+        Self { counters, kvm }
+    }
+
+    /// The vCPU's statistics now.
+    pub fn stats(&self) -> VcpuStats {
+        let counters = &self.counters;
+        let kvm = match &self.kvm {
+            Some(kvm) => kvm.read().unwrap_or_else(|e| {
+                debug!("[KVM] Failed to read vCPU stats: {}", e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        VcpuStats {
+            exits: EXITS
+                .iter()
+                .zip(&counters.exits)
+                .map(|(&name, count)| (name, count.load(Ordering::Relaxed)))
+                .collect(),
+            guest: Duration::from_nanos(counters.guest_ns.load(Ordering::Relaxed)),
+            host: Duration::from_nanos(counters.host_ns.load(Ordering::Relaxed)),
+            kvm,
+        }
+    }
+}
+
+/// A vCPU's KVM binary statistics file.
+#[derive(Debug)]
+pub(super) struct BinaryStats {
+    file: File,
+    /// Each reported statistic, with its offset in the file.
+    stats: Vec<(&'static str, u64)>,
+}
+
+impl BinaryStats {
+    /// Open the statistics of `vcpu`. Fails where KVM has none.
+    pub fn open(vcpu: &kvm_ioctls::VcpuFd) -> io::Result<Self> {
+        // SAFETY: KVM_GET_STATS_FD takes no argument.
+        let fd = unsafe { libc::ioctl(vcpu.as_raw_fd(), KVM_GET_STATS_FD as _) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: a new descriptor, owned by nothing else.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mut header = [0u8; size_of::<kvm_stats_header>()];
+        file.read_exact_at(&mut header, 0)?;
+        let header = parse_header(&header);
+        let desc_size = size_of::<kvm_stats_desc>() + header.name_size as usize;
+        let mut descriptors = vec![0u8; desc_size * header.num_desc as usize];
+        file.read_exact_at(&mut descriptors, header.desc_offset.into())?;
+        let stats = parse_descriptors(&descriptors, desc_size, header.data_offset.into());
+        Ok(Self { file, stats })
+    }
+
+    /// The current value of each reported statistic.
+    fn read(&self) -> io::Result<Vec<(&'static str, u64)>> {
+        self.stats
+            .iter()
+            .map(|&(name, offset)| {
+                let mut value = [0u8; 8];
+                self.file.read_exact_at(&mut value, offset)?;
+                Ok((name, u64::from_ne_bytes(value)))
+            })
+            .collect()
+    }
+}
+
+fn parse_header(data: &[u8]) -> kvm_stats_header {
+    let field =
+        |index: usize| u32::from_ne_bytes(data[index * 4..index * 4 + 4].try_into().unwrap());
+    kvm_stats_header {
+        flags: field(0),
+        name_size: field(1),
+        num_desc: field(2),
+        id_offset: field(3),
+        desc_offset: field(4),
+        data_offset: field(5),
+    }
+}
+
+/// The [`KVM_STATS`] among `descriptors`, each `desc_size` bytes, with the
+/// offsets of their values. Histograms and statistics of several values are
+/// left out.
+fn parse_descriptors(
+    descriptors: &[u8],
+    desc_size: usize,
+    data_offset: u64,
+) -> Vec<(&'static str, u64)> {
+    let name_start = size_of::<kvm_stats_desc>();
+    let mut stats = Vec::new();
+    for desc in descriptors.chunks_exact(desc_size) {
+        let flags = u32::from_ne_bytes(desc[0..4].try_into().unwrap());
+        let size = u16::from_ne_bytes(desc[6..8].try_into().unwrap());
+        let offset = u32::from_ne_bytes(desc[8..12].try_into().unwrap());
+        let name = &desc[name_start..];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        let scalar = matches!(
+            flags & KVM_STATS_TYPE_MASK,
+            KVM_STATS_TYPE_CUMULATIVE | KVM_STATS_TYPE_INSTANT | KVM_STATS_TYPE_PEAK
+        );
+        if let Some(&known) = KVM_STATS.iter().find(|known| known.as_bytes() == name) {
+            if scalar && size == 1 {
+                stats.push((known, data_offset + u64::from(offset)));
+            }
+        }
+    }
+    // Report in the order listed
+    stats.sort_by_key(|&(name, _)| KVM_STATS.iter().position(|&known| known == name));
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(name: &str, flags: u32, size: u16, offset: u32, name_size: usize) -> Vec<u8> {
+        let mut desc = Vec::new();
+        desc.extend_from_slice(&flags.to_ne_bytes());
+        desc.extend_from_slice(&0i16.to_ne_bytes());
+        desc.extend_from_slice(&size.to_ne_bytes());
+        desc.extend_from_slice(&offset.to_ne_bytes());
+        desc.extend_from_slice(&0u32.to_ne_bytes());
+        desc.extend_from_slice(name.as_bytes());
+        desc.resize(size_of::<kvm_stats_desc>() + name_size, 0);
+        desc
+    }
+
+    #[test]
+    fn test_parse_descriptors() {
+        let name_size = 48;
+        let desc_size = size_of::<kvm_stats_desc>() + name_size;
+        let descriptors = [
+            descriptor("halt_wait_ns", KVM_STATS_TYPE_CUMULATIVE, 1, 0, name_size),
+            descriptor("halt_wait_hist", 4, 32, 8, name_size),
+            descriptor("not_reported", KVM_STATS_TYPE_CUMULATIVE, 1, 264, name_size),
+            descriptor("exits", KVM_STATS_TYPE_CUMULATIVE, 1, 272, name_size),
+        ]
+        .concat();
+        assert_eq!(
+            parse_descriptors(&descriptors, desc_size, 0x1000),
+            [("exits", 0x1110), ("halt_wait_ns", 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let counters = Arc::new(Counters::default());
+        counters.record(Exit::Io, Duration::from_millis(5), Duration::ZERO);
+        counters.record(Exit::Io, Duration::from_millis(3), Duration::from_millis(1));
+        counters.record(
+            Exit::Hlt,
+            Duration::from_millis(2),
+            Duration::from_millis(1),
+        );
+
+        let stats = StatsReader::new(counters, None).stats();
+        assert_eq!(stats.total_exits(), 3);
+        assert_eq!(
+            stats.to_string(),
+            "exits=3 io=2 mmio=0 hlt=1 interrupted=0 other=0 guest_ms=10 host_ms=2"
+        );
+
+        let stats = VcpuStats {
+            kvm: vec![("exits", 40), ("halt_wait_ns", 7)],
+            ..stats
+        };
+        assert!(stats
+            .to_string()
+            .ends_with(" kvm.exits=40 kvm.halt_wait_ns=7"));
+    }
+}
@@ -0,0 +1,366 @@
+//! Guest CPU topology: how vCPUs group into sockets, cores and threads.
+//!
+//! The guest learns its topology from two places, which have to agree:
+//!
+//! - **CPUID**, per vCPU: each vCPU reports its own APIC ID, and leaves
+//!   0xB and 0x1F say how many of its low bits number the threads of a core
+//!   and the cores of a socket.
+//! - **The MADT** (and MP table), listing every processor's APIC ID.
+//!
+//! APIC IDs are built from those fields, each rounded up to a power of two
+//! the way hardware does it:
+//!
+//! ```text
+//! sockets=2,cores=3,threads=2
+//!
+//!   socket | core  | thread      APIC IDs
+//!   ───────┼───────┼───────
+//!    bit 3 | 2..1  |   0        0 1 2 3 4 5   8 9 10 11 12 13
+//! ```
+//!
+//! An SMP guest with the default topology gets one socket of single-thread
+//! cores, as before.
+
+use kvm_bindings::{kvm_cpuid_entry2, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// 0xFF is the broadcast APIC ID, and IDs past it need x2APIC.
+const MAX_APIC_ID: u32 = 0xfe;
+
+/// Level types in CPUID leaves 0xB and 0x1F, ECX[15:8].
+const LEVEL_SMT: u32 = 1;
+const LEVEL_CORE: u32 = 2;
+
+/// CPUID leaf 1 EDX: more than one logical processor per package.
+const CPUID_1_EDX_HTT: u32 = 1 << 28;
+
+/// How the guest's vCPUs are arranged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Topology {
+    pub sockets: u8,
+    pub cores: u8,
+    pub threads: u8,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self::flat(1)
+    }
+}
+
+impl Topology {
+    /// Most vCPUs a VM can have, each with an APIC ID below the broadcast
+    /// one.
+    pub const MAX_CPUS: u32 = MAX_APIC_ID + 1;
+
+    /// `cpus` single-thread cores in one socket.
+    pub fn flat(cpus: u8) -> Self {
+        Self {
+            sockets: 1,
+            cores: cpus,
+            threads: 1,
+        }
+    }
+
+    /// Resolve `--cpus` and `--topology` (e.g. `sockets=1,cores=2,threads=2`)
+    /// into a topology. Fields left out of `spec` are 1, except `cores`,
+    /// which takes up whatever `cpus` leaves; with only `spec`, `cpus` is
+    /// its product.
+    pub fn resolve(cpus: Option<u32>, spec: Option<&str>) -> Result<Self, String> {
+        let (mut sockets, mut cores, mut threads) = (None, None, None);
+        for field in spec.into_iter().flat_map(|spec| spec.split(',')) {
+            let (name, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("invalid topology field {field:?} (expected NAME=COUNT)"))?;
+            let slot = match name {
+                "sockets" => &mut sockets,
+                "cores" => &mut cores,
+                "threads" => &mut threads,
+                _ => {
+                    return Err(format!(
+                        "unknown topology field {name:?} (sockets, cores or threads)"
+                    ))
+                }
+            };
+            let count = value
+                .parse::<u32>()
+                .ok()
+                .filter(|&count| count > 0)
+                .ok_or_else(|| format!("invalid {name} count {value:?}"))?;
+            *slot = Some(count);
+        }
+
+        let (sockets, threads) = (sockets.unwrap_or(1), threads.unwrap_or(1));
+        let cores = match (cores, cpus) {
+            (Some(cores), _) => cores,
+            (None, Some(cpus)) if cpus % (sockets * threads) == 0 => cpus / (sockets * threads),
+            (None, Some(cpus)) => {
+                return Err(format!(
+                    "{cpus} CPUs don't divide evenly into sockets={sockets},threads={threads}"
+                ))
+            }
+            (None, None) => 1,
+        };
+        let total = sockets as u64 * cores as u64 * threads as u64;
+        if let Some(cpus) = cpus {
+            if total != cpus as u64 {
+                return Err(format!(
+                    "topology sockets={sockets},cores={cores},threads={threads} \
+                     has {total} CPUs, but --cpus is {cpus}"
+                ));
+            }
+        }
+        if total == 0 {
+            return Err("the guest needs at least one CPU".into());
+        }
+
+        let narrow = |count: u32| {
+            u8::try_from(count).map_err(|_| format!("{total} CPUs is more than {MAX_APIC_ID} + 1"))
+        };
+        let topology = Self {
+            sockets: narrow(sockets)?,
+            cores: narrow(cores)?,
+            threads: narrow(threads)?,
+        };
+        let last = topology.wide_apic_id(topology.cpus() - 1);
+        if last > MAX_APIC_ID {
+            return Err(format!(
+                "topology {topology} needs APIC IDs up to {last}, past the limit of {MAX_APIC_ID}"
+            ));
+        }
+        Ok(topology)
+    }
+
+    /// Number of vCPUs.
+    pub fn cpus(&self) -> u32 {
+        self.sockets as u32 * self.cores as u32 * self.threads as u32
+    }
+
+    /// APIC ID bits numbering the threads of a core.
+    fn thread_bits(&self) -> u32 {
+        bits(self.threads)
+    }
+
+    /// APIC ID bits numbering the threads of a socket.
+    fn socket_shift(&self) -> u32 {
+        bits(self.cores) + self.thread_bits()
+    }
+
+    /// APIC ID of the vCPU numbered `index` (0 being the BSP), counting
+    /// threads first, then cores, then sockets.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is past the last vCPU.
+    pub fn apic_id(&self, index: u32) -> u8 {
+        assert!(index < self.cpus(), "vCPU {index} out of range");
+        self.wide_apic_id(index) as u8
+    }
+
+    /// [`Self::apic_id`] before it is checked to fit.
+    fn wide_apic_id(&self, index: u32) -> u32 {
+        let thread = index % self.threads as u32;
+        let core = index / self.threads as u32 % self.cores as u32;
+        let socket = index / (self.threads as u32 * self.cores as u32);
+        socket << self.socket_shift() | core << self.thread_bits() | thread
+    }
+
+    /// APIC IDs of every vCPU, in vCPU order.
+    pub fn apic_ids(&self) -> Vec<u8> {
+        (0..self.cpus()).map(|index| self.apic_id(index)).collect()
+    }
+
+    /// Describe this topology in the CPUID entries of the vCPU with
+    /// `apic_id`: leaf 1's APIC ID and logical processor count, leaf 4's
+    /// cache sharing, and the extended topology leaves 0xB and (if KVM
+    /// offers it) 0x1F.
+    pub fn apply_cpuid(&self, entries: &mut Vec<kvm_cpuid_entry2>, apic_id: u8) {
+        let per_socket = 1u32 << self.socket_shift();
+        let max_leaf = entries
+            .iter()
+            .find(|e| e.function == 0)
+            .map_or(0, |e| e.eax);
+
+        for entry in entries.iter_mut() {
+            match entry.function {
+                1 => {
+                    entry.ebx = (entry.ebx & 0xffff) | (per_socket.min(0xff) << 16);
+                    entry.ebx |= (apic_id as u32) << 24;
+                    if per_socket > 1 {
+                        entry.edx |= CPUID_1_EDX_HTT;
+                    } else {
+                        entry.edx &= !CPUID_1_EDX_HTT;
+                    }
+                }
+                // Deterministic cache parameters, one subleaf per cache
+                4 if entry.eax & 0x1f != 0 => {
+                    let level = (entry.eax >> 5) & 0x7;
+                    let sharing = if level >= 3 {
+                        per_socket
+                    } else {
+                        1 << self.thread_bits()
+                    };
+                    let cores = 1u32 << bits(self.cores);
+                    entry.eax &= 0x3fff;
+                    entry.eax |= ((cores - 1) & 0x3f) << 26 | ((sharing - 1) & 0xfff) << 14;
+                }
+                _ => {}
+            }
+        }
+
+        let has_1f = entries.iter().any(|e| e.function == 0x1f);
+        entries.retain(|e| e.function != 0xb && e.function != 0x1f);
+        if max_leaf < 0xb {
+            return;
+        }
+        let leaves: &[u32] = if has_1f && max_leaf >= 0x1f {
+            &[0xb, 0x1f]
+        } else {
+            &[0xb]
+        };
+        for &function in leaves {
+            entries.extend(self.extended_leaf(function, apic_id));
+        }
+    }
+
+    /// The subleaves of extended topology leaf `function` (0xB or 0x1F):
+    /// threads, then cores, then the invalid level that ends the list.
+    fn extended_leaf(&self, function: u32, apic_id: u8) -> [kvm_cpuid_entry2; 3] {
+        let level = |index: u32, shift: u32, count: u32, kind: u32| kvm_cpuid_entry2 {
+            function,
+            index,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax: shift,
+            ebx: count,
+            ecx: kind << 8 | index,
+            edx: apic_id as u32,
+            ..Default::default()
+        };
+        let threads = self.threads as u32;
+        [
+            level(0, self.thread_bits(), threads, LEVEL_SMT),
+            level(
+                1,
+                self.socket_shift(),
+                threads * self.cores as u32,
+                LEVEL_CORE,
+            ),
+            level(2, 0, 0, 0),
+        ]
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sockets={},cores={},threads={}",
+            self.sockets, self.cores, self.threads
+        )
+    }
+}
+
+/// Bits needed to number `count` things: ceil(log2(count)).
+fn bits(count: u8) -> u32 {
+    (count as u32).next_power_of_two().trailing_zeros()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, index: u32, eax: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            eax,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let resolve = |cpus, spec| Topology::resolve(cpus, spec);
+        assert_eq!(resolve(None, None), Ok(Topology::flat(1)));
+        assert_eq!(resolve(Some(4), None), Ok(Topology::flat(4)));
+
+        let smt = Topology {
+            sockets: 1,
+            cores: 2,
+            threads: 2,
+        };
+        assert_eq!(
+            resolve(Some(4), Some("sockets=1,cores=2,threads=2")),
+            Ok(smt)
+        );
+        assert_eq!(resolve(Some(4), Some("threads=2")), Ok(smt));
+        assert_eq!(resolve(None, Some("cores=2,threads=2")), Ok(smt));
+        assert_eq!(smt.to_string(), "sockets=1,cores=2,threads=2");
+
+        assert!(resolve(Some(4), Some("cores=3")).is_err());
+        assert!(resolve(Some(5), Some("threads=2")).is_err());
+        assert!(resolve(Some(0), None).is_err());
+        assert!(resolve(None, Some("cores=0")).is_err());
+        assert!(resolve(None, Some("dies=2")).is_err());
+        assert!(resolve(None, Some("cores")).is_err());
+        assert!(resolve(Some(255), None).is_ok());
+        assert!(resolve(Some(256), None).is_err());
+        assert!(resolve(None, Some("sockets=3,cores=65")).is_err());
+    }
+
+    #[test]
+    fn test_apic_ids() {
+        let topology = Topology::resolve(None, Some("sockets=2,cores=3,threads=2")).unwrap();
+        assert_eq!(topology.cpus(), 12);
+        assert_eq!(
+            topology.apic_ids(),
+            [0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13]
+        );
+        assert_eq!(Topology::flat(3).apic_ids(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_apply_cpuid() {
+        let topology = Topology::resolve(Some(4), Some("cores=2,threads=2")).unwrap();
+        let mut entries = vec![
+            entry(0, 0, 0x1f),
+            entry(1, 0, 0),
+            // L1d, then L3
+            entry(4, 0, 1 | 1 << 5),
+            entry(4, 3, 3 | 3 << 5),
+            entry(0xb, 0, 0),
+        ];
+        topology.apply_cpuid(&mut entries, 3);
+
+        assert_eq!(entries[1].ebx >> 24, 3);
+        assert_eq!((entries[1].ebx >> 16) & 0xff, 4);
+        assert_ne!(entries[1].edx & CPUID_1_EDX_HTT, 0);
+        assert_eq!((entries[2].eax >> 14) & 0xfff, 1);
+        assert_eq!((entries[3].eax >> 14) & 0xfff, 3);
+        assert_eq!(entries[3].eax >> 26, 1);
+
+        // KVM didn't offer 0x1F, so only 0xB is described
+        let leaf_b: Vec<_> = entries.iter().filter(|e| e.function == 0xb).collect();
+        assert_eq!(leaf_b.len(), 3);
+        assert!(entries.iter().all(|e| e.function != 0x1f));
+        assert_eq!((leaf_b[0].eax, leaf_b[0].ebx, leaf_b[0].ecx), (1, 2, 0x100));
+        assert_eq!((leaf_b[1].eax, leaf_b[1].ebx, leaf_b[1].ecx), (2, 4, 0x201));
+        assert_eq!((leaf_b[2].ebx, leaf_b[2].ecx), (0, 2));
+        assert!(leaf_b.iter().all(|e| e.edx == 3));
+    }
+
+    #[test]
+    fn test_apply_cpuid_leaf_1f() {
+        let mut entries = vec![entry(0, 0, 0x1f), entry(1, 0, 0), entry(0x1f, 0, 0)];
+        Topology::flat(1).apply_cpuid(&mut entries, 0);
+        assert_eq!(entries.iter().filter(|e| e.function == 0x1f).count(), 3);
+        assert_eq!(entries[1].edx & CPUID_1_EDX_HTT, 0);
+
+        // A CPU too old for leaf 0xB doesn't get one
+        let mut entries = vec![entry(0, 0, 0xa), entry(1, 0, 0)];
+        Topology::flat(2).apply_cpuid(&mut entries, 1);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].ebx >> 24, 1);
+    }
+}
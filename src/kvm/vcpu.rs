@@ -57,10 +57,32 @@
 //! - **Special registers**: CR0, CR3, CR4, EFER, segment registers
 //! - **FPU/SSE state**: x87 registers, XMM registers, MXCSR
 //! - **MSRs**: Model-specific registers (EFER, STAR, LSTAR, etc.)
+//! - **Nested state**: L2 guest state, if the guest runs its own hypervisor
+//!
+//! `save_state` / `restore_state` capture and reapply all of it as a
+//! [`VcpuState`] (see the `state` module).
+//!
+//! # Debugging
+//!
+//! `set_breakpoint`, `single_step` and `read_memory_at_rip` let a harness
+//! stop the guest and inspect it (see the `debug` module).
+//!
+//! # Statistics
+//!
+//! Each return from `KVM_RUN` is counted by reason and timed, alongside
+//! KVM's own counters where it has them (see the `stats` module).
 
+use super::debug::{Breakpoints, GuestRam};
+use super::state::{self, VcpuState};
+use super::stats::{BinaryStats, Counters, Exit, StatsReader, VcpuStats};
 use super::KvmError;
-use kvm_bindings::{kvm_fpu, kvm_msr_entry, kvm_regs, kvm_sregs, Msrs};
+use kvm_bindings::{
+    kvm_fpu, kvm_guest_debug, kvm_msr_entry, kvm_regs, kvm_sregs, Msrs, KVM_MP_STATE_HALTED,
+    KVM_MP_STATE_INIT_RECEIVED, KVM_MP_STATE_UNINITIALIZED,
+};
 use kvm_ioctls::VcpuExit as KvmVcpuExit;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Model-Specific Register (MSR) indices.
 ///
@@ -101,10 +123,41 @@ mod msr {
     /// MTRR default type - Memory Type Range Register default.
     pub const MTRR_DEF_TYPE: u32 = 0x2ff;
 
+    /// KVM wall clock: guest address of the boot time structure.
+    pub const KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+
+    /// KVM system time: guest address of this vCPU's pvclock page, bit 0
+    /// enabling kvm-clock.
+    pub const KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
     /// Bit 0 of MISC_ENABLE: Fast string operations.
     pub const MISC_ENABLE_FAST_STRING: u64 = 1;
+
+    /// MSRs captured by `save_state`: everything we configure at boot, and
+    /// the guest's kvm-clock setup.
+    pub const SAVED: [u32; 13] = [
+        IA32_SYSENTER_CS,
+        IA32_SYSENTER_ESP,
+        IA32_SYSENTER_EIP,
+        STAR,
+        CSTAR,
+        KERNEL_GS_BASE,
+        SYSCALL_MASK,
+        LSTAR,
+        IA32_TSC,
+        IA32_MISC_ENABLE,
+        MTRR_DEF_TYPE,
+        KVM_WALL_CLOCK_NEW,
+        KVM_SYSTEM_TIME_NEW,
+    ];
 }
 
+/// RFLAGS.IF: maskable interrupts are enabled.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Size of a guest page, the granularity of address translation.
+const PAGE_SIZE: u64 = 4096;
+
 /// Maximum size for I/O operations (x86 supports 1, 2, or 4 byte I/O).
 pub const MAX_IO_SIZE: usize = 4;
 
@@ -155,6 +208,12 @@ impl IoData {
         self.len as usize
     }
 
+    /// Whether there is no data.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     /// Set a byte at index.
     #[inline]
     pub fn set(&mut self, index: usize, value: u8) {
@@ -179,6 +238,24 @@ impl Default for IoData {
 pub struct VcpuFd {
     /// The underlying KVM vCPU file descriptor.
     vcpu: kvm_ioctls::VcpuFd,
+
+    /// Maximum nested state size, if nested state should be saved.
+    nested_state_size: Option<usize>,
+
+    /// Hyper-V MSRs to save along with `msr::SAVED`.
+    hyperv_msrs: Vec<u32>,
+
+    /// Software breakpoints set through `set_breakpoint`.
+    breakpoints: Breakpoints,
+
+    /// Exits and time, counted by `run_with_io`.
+    counters: Arc<Counters>,
+
+    /// KVM's statistics for this vCPU, if it has binary statistics.
+    kvm_stats: Option<Arc<BinaryStats>>,
+
+    /// When `KVM_RUN` last returned.
+    last_exit: Option<Instant>,
 }
 
 /// Exit reasons from vCPU execution.
@@ -219,6 +296,19 @@ pub enum VcpuExit {
     /// Contains the event type code.
     SystemEvent(u32),
 
+    /// The guest changed Hyper-V SynIC state, which KVM has already applied.
+    Hyperv,
+
+    /// The guest reached a breakpoint, or finished a single step.
+    ///
+    /// Contains the guest linear address of the next instruction.
+    Debug(u64),
+
+    /// A signal interrupted `KVM_RUN` before the guest exited.
+    ///
+    /// Another thread kicked this vCPU out of the guest, e.g. to stop it.
+    Interrupted,
+
     /// Unknown or unhandled exit reason.
     ///
     /// Contains a static description of the exit type.
@@ -314,8 +404,60 @@ pub trait MmioHandler {
 
 impl VcpuFd {
     /// Create a new VcpuFd wrapper.
-    pub fn new(vcpu: kvm_ioctls::VcpuFd) -> Self {
-        Self { vcpu }
+    ///
+    /// `nested_state_size` is the `KVM_CAP_NESTED_STATE` size when the guest
+    /// can see VMX/SVM, and `None` otherwise. `hyperv_msrs` are the Hyper-V
+    /// MSRs the guest's enlightenments keep state in.
+    pub fn new(
+        vcpu: kvm_ioctls::VcpuFd,
+        nested_state_size: Option<usize>,
+        hyperv_msrs: Vec<u32>,
+    ) -> Self {
+        let kvm_stats = match BinaryStats::open(&vcpu) {
+            Ok(stats) => Some(Arc::new(stats)),
+            Err(e) => {
+                debug!("[KVM] No binary stats ({}): counting exits only", e);
+                None
+            }
+        };
+        Self {
+            vcpu,
+            nested_state_size,
+            hyperv_msrs,
+            breakpoints: Breakpoints::default(),
+            counters: Arc::default(),
+            kvm_stats,
+            last_exit: None,
+        }
+    }
+
+    /// This vCPU's statistics so far.
+    pub fn stats(&self) -> VcpuStats {
+        self.stats_reader().stats()
+    }
+
+    /// A reader of this vCPU's statistics, for other threads.
+    pub fn stats_reader(&self) -> StatsReader {
+        StatsReader::new(self.counters.clone(), self.kvm_stats.clone())
+    }
+
+    /// Whether nothing but another vCPU can wake this one: it is halted
+    /// with interrupts masked (as after the guest's `halt`), or waits for
+    /// the INIT/SIPI that starts an AP.
+    ///
+    /// An NMI would still wake a halted vCPU, but nothing in Carbon sends
+    /// one.
+    pub fn is_parked(&self) -> Result<bool, KvmError> {
+        let mp_state = self
+            .vcpu
+            .get_mp_state()
+            .map_err(KvmError::GetMpState)?
+            .mp_state;
+        Ok(match mp_state {
+            KVM_MP_STATE_HALTED => self.get_regs()?.rflags & RFLAGS_IF == 0,
+            KVM_MP_STATE_UNINITIALIZED | KVM_MP_STATE_INIT_RECEIVED => true,
+            _ => false,
+        })
     }
 
     /// Get the current general-purpose registers.
@@ -377,10 +519,100 @@ impl VcpuFd {
         let msrs = Msrs::from_entries(&entries).expect("failed to create MSRs");
         self.vcpu.set_msrs(&msrs).map_err(KvmError::SetMsrs)?;
 
-        eprintln!("[KVM] Set {} boot MSRs", entries.len());
+        debug!("[KVM] Set {} boot MSRs", entries.len());
+        Ok(())
+    }
+
+    /// Capture the complete vCPU state.
+    ///
+    /// The vCPU must not be running. Nested state is included when the guest
+    /// has VMX/SVM and KVM supports `KVM_CAP_NESTED_STATE`.
+    pub fn save_state(&self) -> Result<VcpuState, KvmError> {
+        let mut msrs = Msrs::from_entries(
+            &msr::SAVED
+                .iter()
+                .chain(&self.hyperv_msrs)
+                .map(|&index| kvm_msr_entry {
+                    index,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>(),
+        )
+        .expect("failed to create MSRs");
+        let count = self.vcpu.get_msrs(&mut msrs).map_err(KvmError::SaveState)?;
+
+        let nested = match self.nested_state_size {
+            Some(max_size) => Some(
+                state::get_nested_state(&self.vcpu, max_size).map_err(KvmError::GetNestedState)?,
+            ),
+            None => None,
+        };
+
+        Ok(VcpuState {
+            regs: self.get_regs()?,
+            sregs: self.get_sregs()?,
+            xsave: self.vcpu.get_xsave().map_err(KvmError::SaveState)?,
+            xcrs: self.vcpu.get_xcrs().map_err(KvmError::SaveState)?,
+            lapic: self.vcpu.get_lapic().map_err(KvmError::SaveState)?,
+            events: self.vcpu.get_vcpu_events().map_err(KvmError::SaveState)?,
+            mp_state: self.vcpu.get_mp_state().map_err(KvmError::SaveState)?,
+            msrs: msrs.as_slice()[..count].to_vec(),
+            nested,
+        })
+    }
+
+    /// Reapply state captured by [`save_state`](Self::save_state).
+    ///
+    /// Special registers go first so KVM sees the right EFER/CR4 when it
+    /// validates nested state; vCPU events go after nested state since they
+    /// may describe a pending exception for the L2 guest.
+    pub fn restore_state(&self, state: &VcpuState) -> Result<(), KvmError> {
+        self.set_sregs(&state.sregs)?;
+
+        if let Some(ref nested) = state.nested {
+            state::set_nested_state(&self.vcpu, nested).map_err(KvmError::SetNestedState)?;
+        }
+
+        self.set_regs(&state.regs)?;
+        self.vcpu
+            .set_xsave(&state.xsave)
+            .map_err(KvmError::RestoreState)?;
+        self.vcpu
+            .set_xcrs(&state.xcrs)
+            .map_err(KvmError::RestoreState)?;
+
+        let msrs = Msrs::from_entries(&state.msrs).expect("failed to create MSRs");
+        self.vcpu.set_msrs(&msrs).map_err(KvmError::SetMsrs)?;
+
+        self.vcpu
+            .set_vcpu_events(&state.events)
+            .map_err(KvmError::RestoreState)?;
+        self.vcpu
+            .set_mp_state(state.mp_state)
+            .map_err(KvmError::RestoreState)?;
+        self.vcpu
+            .set_lapic(&state.lapic)
+            .map_err(KvmError::RestoreState)?;
+
         Ok(())
     }
 
+    /// Finish the I/O of the last exit without entering the guest.
+    ///
+    /// KVM only completes an emulated IN or MMIO read, storing the data the
+    /// handler returned in the guest's registers, on the next `KVM_RUN`:
+    /// state saved before that misses it. `immediate_exit` has that run
+    /// return at once instead of entering the guest.
+    pub fn complete_io(&mut self) -> Result<(), KvmError> {
+        self.vcpu.set_kvm_immediate_exit(1);
+        let result = self.vcpu.run().map(|_| ());
+        self.vcpu.set_kvm_immediate_exit(0);
+        match result {
+            Err(e) if e.errno() != libc::EINTR => Err(KvmError::Run(e)),
+            _ => Ok(()),
+        }
+    }
+
     /// Run the vCPU until it exits, handling I/O and MMIO with the provided handler.
     ///
     /// This is the main execution loop entry point. It:
@@ -391,11 +623,37 @@ impl VcpuFd {
     /// For I/O exits (IN/OUT instructions), the handler is called immediately
     /// and data is exchanged with KVM's buffers. For MMIO exits, the handler
     /// processes memory-mapped device access.
+    ///
+    /// A signal delivered to the thread makes this return
+    /// [`VcpuExit::Interrupted`] instead of an error.
     pub fn run_with_io<H: IoHandler + MmioHandler>(
         &mut self,
         handler: &mut H,
     ) -> Result<VcpuExit, KvmError> {
-        match self.vcpu.run().map_err(KvmError::Run)? {
+        let entered = Instant::now();
+        let host = self
+            .last_exit
+            .map_or(Duration::ZERO, |exited| entered - exited);
+        let result = self.vcpu.run();
+        let exited = Instant::now();
+        self.last_exit = Some(exited);
+        let record = |exit| self.counters.record(exit, exited - entered, host);
+
+        let exit = match result {
+            Ok(exit) => exit,
+            Err(e) if e.errno() == libc::EINTR => {
+                record(Exit::Interrupted);
+                return Ok(VcpuExit::Interrupted);
+            }
+            Err(e) => return Err(KvmError::Run(e)),
+        };
+        record(match exit {
+            KvmVcpuExit::IoIn(..) | KvmVcpuExit::IoOut(..) => Exit::Io,
+            KvmVcpuExit::MmioRead(..) | KvmVcpuExit::MmioWrite(..) => Exit::Mmio,
+            KvmVcpuExit::Hlt => Exit::Hlt,
+            _ => Exit::Other,
+        });
+        match exit {
             KvmVcpuExit::IoIn(port, data) => {
                 let mut io_data = IoData::new(data.len());
                 handler.io_read(port, &mut io_data);
@@ -427,8 +685,9 @@ impl VcpuFd {
             KvmVcpuExit::FailEntry(reason, _) => Ok(VcpuExit::FailEntry(reason)),
 
             // Map known exits to static strings
+            KvmVcpuExit::Hyperv => Ok(VcpuExit::Hyperv),
             KvmVcpuExit::Hypercall(_) => Ok(VcpuExit::Unknown("Hypercall")),
-            KvmVcpuExit::Debug(_) => Ok(VcpuExit::Unknown("Debug")),
+            KvmVcpuExit::Debug(arch) => Ok(VcpuExit::Debug(arch.pc)),
             KvmVcpuExit::Exception => Ok(VcpuExit::Unknown("Exception")),
             KvmVcpuExit::IrqWindowOpen => Ok(VcpuExit::Unknown("IrqWindowOpen")),
             KvmVcpuExit::S390Sieic => Ok(VcpuExit::Unknown("S390Sieic")),
@@ -440,4 +699,128 @@ impl VcpuFd {
             _ => Ok(VcpuExit::Unknown("Other")),
         }
     }
+
+    /// Set a software breakpoint at guest physical address `gpa`.
+    ///
+    /// Patches an INT3 into `memory`; `run_with_io` then returns
+    /// [`VcpuExit::Debug`] when the vCPU reaches it. Returns false if one
+    /// was already set there.
+    pub fn set_breakpoint(&mut self, memory: &impl GuestRam, gpa: u64) -> Result<bool, KvmError> {
+        let inserted = self.breakpoints.insert(memory, gpa)?;
+        self.set_guest_debug(false)?;
+        Ok(inserted)
+    }
+
+    /// Remove the breakpoint at `gpa`, restoring the byte it replaced.
+    /// Returns false if none was set there.
+    pub fn remove_breakpoint(
+        &mut self,
+        memory: &impl GuestRam,
+        gpa: u64,
+    ) -> Result<bool, KvmError> {
+        let removed = self.breakpoints.remove(memory, gpa)?;
+        self.set_guest_debug(false)?;
+        Ok(removed)
+    }
+
+    /// Execute `count` guest instructions, one at a time.
+    ///
+    /// Returns [`VcpuExit::Debug`] with the address of the next instruction
+    /// once all have run, or the exit that stopped the vCPU first (a
+    /// breakpoint, HLT, shutdown, ...). A breakpoint under RIP is stepped
+    /// over rather than hit again. I/O and MMIO go to `handler` as in
+    /// `run_with_io`.
+    pub fn single_step<H: IoHandler + MmioHandler>(
+        &mut self,
+        memory: &impl GuestRam,
+        handler: &mut H,
+        count: u32,
+    ) -> Result<VcpuExit, KvmError> {
+        self.set_guest_debug(true)?;
+        let mut exit = Ok(VcpuExit::Debug(self.linear_rip()?));
+        for _ in 0..count {
+            exit = self.step(memory, handler);
+            if !matches!(exit, Ok(VcpuExit::Debug(_))) {
+                break;
+            }
+        }
+        self.set_guest_debug(false)?;
+        exit
+    }
+
+    /// Read `len` bytes of guest memory starting at RIP, translated
+    /// through the guest's page tables. Breakpoints read as the bytes they
+    /// replaced.
+    pub fn read_memory_at_rip(
+        &self,
+        memory: &impl GuestRam,
+        len: usize,
+    ) -> Result<Vec<u8>, KvmError> {
+        let mut data = vec![0u8; len];
+        let mut addr = self.linear_rip()?;
+        let mut done = 0;
+        // Pages contiguous in the guest's address space needn't be in RAM
+        while done < len {
+            let page_left = (PAGE_SIZE - addr % PAGE_SIZE) as usize;
+            let chunk = &mut data[done..len.min(done + page_left)];
+            let gpa = self.translate(addr)?;
+            memory
+                .read_ram(gpa, chunk)
+                .map_err(|source| KvmError::GuestMemory { addr: gpa, source })?;
+            self.breakpoints.unpatch(gpa, chunk);
+            addr += chunk.len() as u64;
+            done += chunk.len();
+        }
+        Ok(data)
+    }
+
+    /// Run one instruction with the single-step trap set, lifting a
+    /// breakpoint under RIP for it.
+    fn step<H: IoHandler + MmioHandler>(
+        &mut self,
+        memory: &impl GuestRam,
+        handler: &mut H,
+    ) -> Result<VcpuExit, KvmError> {
+        let gpa = self.translate(self.linear_rip()?)?;
+        let lifted = self.breakpoints.lift(memory, gpa)?;
+        let exit = loop {
+            match self.run_with_io(handler) {
+                // The instruction completes on the next entry
+                Ok(VcpuExit::Io | VcpuExit::Interrupted | VcpuExit::Hyperv) => {}
+                exit => break exit,
+            }
+        };
+        if lifted {
+            self.breakpoints.rearm(memory, gpa)?;
+        }
+        exit
+    }
+
+    /// Trap breakpoints while any is set, and every instruction with
+    /// `single_step`.
+    fn set_guest_debug(&self, single_step: bool) -> Result<(), KvmError> {
+        let debug = kvm_guest_debug {
+            control: self.breakpoints.control(single_step),
+            ..Default::default()
+        };
+        self.vcpu
+            .set_guest_debug(&debug)
+            .map_err(KvmError::SetGuestDebug)
+    }
+
+    /// The guest linear address of the next instruction.
+    fn linear_rip(&self) -> Result<u64, KvmError> {
+        let regs = self.get_regs()?;
+        let sregs = self.get_sregs()?;
+        Ok(sregs.cs.base.wrapping_add(regs.rip))
+    }
+
+    /// Translate a guest linear address to a guest physical address.
+    fn translate(&self, addr: u64) -> Result<u64, KvmError> {
+        let translation = self.vcpu.translate_gva(addr).map_err(KvmError::Translate)?;
+        if translation.valid == 0 {
+            return Err(KvmError::UnmappedAddress(addr));
+        }
+        Ok(translation.physical_address)
+    }
 }
@@ -3,6 +3,7 @@
 //! This module handles VM-level KVM operations including:
 //! - Initializing required VM components (TSS, IRQ chip, PIT)
 //! - Registering guest memory regions
+//! - Wiring device interrupts to the IOAPIC
 //! - Creating vCPUs
 //!
 //! # VM Initialization
@@ -31,6 +32,13 @@
 //! and generates periodic interrupts. Even though modern systems use other
 //! timers (HPET, TSC), the kernel still expects a PIT during early boot.
 //!
+//! ## kvm-clock
+//!
+//! The paravirtual clock KVM keeps per VM, which guests prefer over the PIT
+//! and TSC. It is set to zero at creation, so guest time counts from VM
+//! setup rather than from whenever KVM happened to initialize it, and can
+//! be saved and restored with the VM (see [`super::state::ClockState`]).
+//!
 //! # Memory Regions
 //!
 //! Guest memory is managed through "memory slots". Each slot maps a range of
@@ -49,10 +57,18 @@
 //! KVM uses EPT (Extended Page Tables) or NPT (Nested Page Tables) to translate
 //! guest physical addresses to host physical addresses through the host's MMU.
 
-use super::{KvmError, VcpuFd};
+use super::cpuid::{apply_cpu_features, apply_cpu_mode, has_kvm_clock, CpuFeatures, CpuMode};
+use super::hyperv::Hyperv;
+use super::irq::{IrqLine, Irqfd, LineIrq};
+use super::state::{nested_virt_exposed, ClockState, VmState};
+use super::{KvmError, Topology, VcpuFd};
 use kvm_bindings::{
-    kvm_cpuid_entry2, kvm_pit_config, kvm_userspace_memory_region, CpuId, KVM_PIT_SPEAKER_DUMMY,
+    kvm_cpuid_entry2, kvm_irqchip, kvm_pit_config, kvm_userspace_memory_region, CpuId,
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_PIT_SPEAKER_DUMMY,
 };
+use kvm_ioctls::{Cap, IoEventAddress};
+use std::sync::Arc;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 /// Wrapper around the KVM VM file descriptor.
 ///
@@ -63,14 +79,29 @@ use kvm_bindings::{
 /// The VM is automatically initialized with required x86 components
 /// (TSS address, IRQ chip, PIT) when created via `VmFd::new()`.
 pub struct VmFd {
-    /// The underlying KVM VM file descriptor.
-    vm: kvm_ioctls::VmFd,
+    /// The underlying KVM VM file descriptor, shared with [`LineIrq`]s.
+    vm: Arc<kvm_ioctls::VmFd>,
 
     /// Supported CPUID entries to apply to new vCPUs.
     ///
     /// When a guest executes CPUID, KVM returns these entries.
     /// This tells the guest what CPU features are available.
     supported_cpuid: CpuId,
+
+    /// Policy for how much of the host CPU is exposed to the guest.
+    cpu_mode: CpuMode,
+
+    /// Features exposed or hidden on top of `cpu_mode`.
+    cpu_features: CpuFeatures,
+
+    /// How vCPUs are arranged into sockets, cores and threads.
+    topology: Topology,
+
+    /// Hyper-V enlightenments, if the guest sees any.
+    hyperv: Option<Hyperv>,
+
+    /// Maximum `kvm_nested_state` size (`KVM_CAP_NESTED_STATE`), 0 if unsupported.
+    nested_state_size: usize,
 }
 
 impl VmFd {
@@ -87,15 +118,30 @@ impl VmFd {
     /// 3. **PIT**: Creates the 8254 Programmable Interval Timer.
     ///    We use `KVM_PIT_SPEAKER_DUMMY` to disable PC speaker emulation.
     ///
+    /// 4. **kvm-clock**: Starts the guest's paravirtual clock at zero.
+    ///
     /// # Arguments
     ///
     /// * `vm` - Raw KVM VM file descriptor
     /// * `supported_cpuid` - CPUID entries to apply to vCPUs
+    /// * `cpu_mode` - Host passthrough or a portable CPUID template
+    /// * `cpu_features` - Features exposed or hidden on top of `cpu_mode`
+    /// * `topology` - Sockets, cores and threads the vCPUs report
+    /// * `hyperv` - Hyper-V enlightenments the vCPUs expose, if any
+    /// * `nested_state_size` - Value of `KVM_CAP_NESTED_STATE` (0 if unsupported)
     ///
     /// # Errors
     ///
     /// Returns an error if any component fails to initialize.
-    pub fn new(vm: kvm_ioctls::VmFd, supported_cpuid: CpuId) -> Result<Self, KvmError> {
+    pub fn new(
+        vm: kvm_ioctls::VmFd,
+        supported_cpuid: CpuId,
+        cpu_mode: CpuMode,
+        cpu_features: CpuFeatures,
+        topology: Topology,
+        hyperv: Option<Hyperv>,
+        nested_state_size: usize,
+    ) -> Result<Self, KvmError> {
         // Set TSS address (required for Intel VT-x)
         //
         // The TSS address must be set before creating vCPUs. We use an address
@@ -126,12 +172,84 @@ impl VmFd {
         };
         vm.create_pit2(pit_config).map_err(KvmError::CreatePit2)?;
 
+        // Start the kvm-clock at zero
+        //
+        // KVM starts it when the VM is created, but setting it explicitly
+        // pins down where guest time begins, before any vCPU reads it.
+        if vm.check_extension(Cap::AdjustClock) {
+            vm.set_clock(&ClockState::default().to_kvm())
+                .map_err(KvmError::SetClock)?;
+        } else {
+            debug!("[KVM] KVM_CAP_ADJUST_CLOCK unsupported: kvm-clock left as KVM started it");
+        }
+
         Ok(Self {
-            vm,
+            vm: Arc::new(vm),
             supported_cpuid,
+            cpu_mode,
+            cpu_features,
+            topology,
+            hyperv,
+            nested_state_size,
+        })
+    }
+
+    /// Capture the kvm-clock.
+    ///
+    /// The vCPUs must be stopped, so the guest doesn't read the clock
+    /// between the capture and the vCPU states saved with it.
+    pub fn save_clock(&self) -> Result<ClockState, KvmError> {
+        let data = self.vm.get_clock().map_err(KvmError::GetClock)?;
+        Ok(ClockState::from_kvm(&data))
+    }
+
+    /// Reapply a kvm-clock captured by [`save_clock`](Self::save_clock),
+    /// before restoring any vCPU.
+    pub fn restore_clock(&self, clock: ClockState) -> Result<(), KvmError> {
+        if !clock.tsc_stable {
+            debug!("[KVM] kvm-clock was captured without a stable TSC");
+        }
+        self.vm
+            .set_clock(&clock.to_kvm())
+            .map_err(KvmError::SetClock)
+    }
+
+    /// Capture the kvm-clock, interrupt controllers and PIT, with the
+    /// vCPUs stopped (see [`VmState`]).
+    pub fn save_state(&self) -> Result<VmState, KvmError> {
+        let mut irqchips = [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ]
+        .map(|chip_id| kvm_irqchip {
+            chip_id,
+            ..Default::default()
+        });
+        for chip in &mut irqchips {
+            self.vm.get_irqchip(chip).map_err(KvmError::SaveVmState)?;
+        }
+        Ok(VmState {
+            clock: self.save_clock()?,
+            irqchips,
+            pit: self.vm.get_pit2().map_err(KvmError::SaveVmState)?,
         })
     }
 
+    /// Reapply state captured by [`save_state`](Self::save_state), before
+    /// restoring any vCPU.
+    pub fn restore_state(&self, state: &VmState) -> Result<(), KvmError> {
+        self.restore_clock(state.clock)?;
+        for chip in &state.irqchips {
+            self.vm
+                .set_irqchip(chip)
+                .map_err(KvmError::RestoreVmState)?;
+        }
+        self.vm
+            .set_pit2(&state.pit)
+            .map_err(KvmError::RestoreVmState)
+    }
+
     /// Register a guest memory region with KVM.
     ///
     /// This maps a range of guest physical addresses to a region of host
@@ -181,14 +299,56 @@ impl VmFd {
         }
     }
 
+    /// Create the line devices raise `gsi` on the in-kernel IOAPIC with.
+    ///
+    /// The line is an irqfd where KVM supports them (see [`Irqfd`]), which
+    /// stays registered until the VM is destroyed, and `KVM_IRQ_LINE`
+    /// otherwise (see [`LineIrq`]).
+    pub fn irq_line(&self, gsi: u32) -> Result<Arc<dyn IrqLine>, KvmError> {
+        if !self.vm.check_extension(Cap::Irqfd) {
+            return Ok(Arc::new(LineIrq::new(self.vm.clone(), gsi)));
+        }
+        let irqfd_error = |source| KvmError::RegisterIrqfd { gsi, source };
+        let eventfd = EventFd::new(EFD_NONBLOCK).map_err(|e| {
+            irqfd_error(kvm_ioctls::Error::new(
+                e.raw_os_error().unwrap_or(libc::EIO),
+            ))
+        })?;
+        self.vm.register_irqfd(&eventfd, gsi).map_err(irqfd_error)?;
+        Ok(Arc::new(Irqfd::new(eventfd, gsi)))
+    }
+
+    /// Whether KVM supports [`mmio_notifier`](Self::mmio_notifier).
+    pub fn has_ioeventfd(&self) -> bool {
+        self.vm.check_extension(Cap::Ioeventfd)
+    }
+
+    /// Create an eventfd that KVM signals, instead of exiting to the VMM,
+    /// when the guest writes the 32-bit `value` to MMIO address `addr`.
+    ///
+    /// It stays registered until the VM is destroyed.
+    pub fn mmio_notifier(&self, addr: u64, value: u32) -> Result<EventFd, KvmError> {
+        let ioeventfd_error = |source| KvmError::RegisterIoeventfd { addr, source };
+        let eventfd = EventFd::new(EFD_NONBLOCK).map_err(|e| {
+            ioeventfd_error(kvm_ioctls::Error::new(
+                e.raw_os_error().unwrap_or(libc::EIO),
+            ))
+        })?;
+        self.vm
+            .register_ioevent(&eventfd, &IoEventAddress::Mmio(addr), value)
+            .map_err(ioeventfd_error)?;
+        Ok(eventfd)
+    }
+
     /// Create a new virtual CPU.
     ///
-    /// This creates a vCPU with the specified ID and automatically configures
+    /// This creates the vCPU numbered `index` and automatically configures
     /// its CPUID entries from the VM's supported_cpuid list.
     ///
     /// # Arguments
     ///
-    /// * `id` - vCPU ID (0 for the first/boot CPU)
+    /// * `index` - vCPU number, from 0 (the boot CPU) to one less than the
+    ///   topology's CPU count
     ///
     /// # CPUID Setup
     ///
@@ -202,23 +362,47 @@ impl VmFd {
     ///
     /// # Multi-vCPU Support
     ///
-    /// For SMP guests, create multiple vCPUs with sequential IDs.
-    /// vCPU 0 is the BSP (Bootstrap Processor) that runs first.
-    /// Other vCPUs are APs (Application Processors) started by the BSP.
-    pub fn create_vcpu(&self, id: u64) -> Result<VcpuFd, KvmError> {
-        // Create the vCPU
+    /// For SMP guests, create one vCPU per index. vCPU 0 is the BSP
+    /// (Bootstrap Processor) that runs first. Other vCPUs are APs
+    /// (Application Processors) started by the BSP. Each vCPU's KVM ID is
+    /// its APIC ID from the [`Topology`], which need not be sequential.
+    pub fn create_vcpu(&self, index: u32) -> Result<VcpuFd, KvmError> {
+        // Create the vCPU; KVM gives its local APIC the same ID
+        let apic_id = self.topology.apic_id(index);
+        let id = apic_id as u64;
         let vcpu = self.vm.create_vcpu(id).map_err(KvmError::CreateVcpu)?;
 
         // Get TSC frequency from KVM for fast boot (avoids calibration)
         let tsc_khz = vcpu.get_tsc_khz().unwrap_or(0);
 
+        // Filter the host CPUID according to the configured CPU mode, then
+        // apply the feature overrides
+        let host = self.supported_cpuid.as_slice();
+        let mut entries = host.to_vec();
+        apply_cpu_mode(&mut entries, self.cpu_mode);
+        apply_cpu_features(&mut entries, host, self.cpu_mode, &self.cpu_features);
+        self.topology.apply_cpuid(&mut entries, apic_id);
+        if index == 0 && !has_kvm_clock(&entries) {
+            debug!("[KVM] kvm-clock not offered by KVM: guest ptp_kvm unavailable");
+        }
+
         // Build CPUID with TSC frequency if available
-        let cpuid = if tsc_khz > 0 {
-            self.build_cpuid_with_tsc(tsc_khz)?
+        let mut cpuid = if tsc_khz > 0 {
+            Self::build_cpuid_with_tsc(entries, tsc_khz)?
         } else {
-            self.supported_cpuid.clone()
+            CpuId::from_entries(&entries)
+                .map_err(|_| KvmError::SetCpuid(kvm_ioctls::Error::new(22)))?
         };
 
+        // Hyper-V leaves go where the KVM leaves just built were
+        if let Some(ref hyperv) = self.hyperv {
+            let mut entries = cpuid.as_slice().to_vec();
+            hyperv.apply_cpuid(&mut entries);
+            cpuid = CpuId::from_entries(&entries)
+                .map_err(|_| KvmError::SetCpuid(kvm_ioctls::Error::new(22)))?;
+            hyperv.enable(&vcpu)?;
+        }
+
         // Configure CPUID entries
         //
         // This must be done before the first vcpu.run() call.
@@ -226,21 +410,29 @@ impl VmFd {
         vcpu.set_cpuid2(&cpuid).map_err(KvmError::SetCpuid)?;
 
         if tsc_khz > 0 {
-            eprintln!(
-                "[KVM] Set {} CPUID entries on vCPU {} (TSC: {} kHz)",
+            debug!(
+                "[KVM] Set {} CPUID entries on vCPU {} (TSC: {} kHz, mode: {:?})",
                 cpuid.as_slice().len(),
                 id,
-                tsc_khz
+                tsc_khz,
+                self.cpu_mode
             );
         } else {
-            eprintln!(
-                "[KVM] Set {} CPUID entries on vCPU {}",
+            debug!(
+                "[KVM] Set {} CPUID entries on vCPU {} (mode: {:?})",
                 cpuid.as_slice().len(),
-                id
+                id,
+                self.cpu_mode
             );
         }
 
-        Ok(VcpuFd::new(vcpu))
+        // Only track nested state if the guest can actually run a hypervisor
+        let nested_state_size = (self.nested_state_size > 0
+            && nested_virt_exposed(cpuid.as_slice()))
+        .then_some(self.nested_state_size);
+
+        let hyperv_msrs = self.hyperv.as_ref().map(Hyperv::msrs).unwrap_or_default();
+        Ok(VcpuFd::new(vcpu, nested_state_size, hyperv_msrs))
     }
 
     /// Build CPUID entries with TSC frequency for fast boot.
@@ -249,9 +441,10 @@ impl VmFd {
     /// - 0x40000000: KVM signature ("KVMKVMKVM")
     /// - 0x40000001: KVM features (clocksource, async PF, etc.)
     /// - 0x40000010: TSC frequency in kHz
-    fn build_cpuid_with_tsc(&self, tsc_khz: u32) -> Result<CpuId, KvmError> {
-        let mut entries: Vec<kvm_cpuid_entry2> = self.supported_cpuid.as_slice().to_vec();
-
+    fn build_cpuid_with_tsc(
+        mut entries: Vec<kvm_cpuid_entry2>,
+        tsc_khz: u32,
+    ) -> Result<CpuId, KvmError> {
         // Set hypervisor bit (ECX bit 31) in CPUID leaf 1
         // This tells the guest it's running in a VM
         for entry in &mut entries {
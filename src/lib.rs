@@ -0,0 +1,79 @@
+//! Carbon - A minimal microVM runtime for AI agent sandboxing.
+//!
+//! The library behind the `carbon` command, for programs that run VMs of
+//! their own: describe one with [`VmmBuilder`], then run and control the
+//! [`Vmm`] it builds (see [`builder`]). The modules below are what the
+//! command itself is built from.
+//!
+//! This VMM requires Linux with KVM support. It will not run on other platforms.
+
+#[macro_use]
+pub mod logging;
+
+#[cfg(target_os = "linux")]
+pub mod api;
+#[cfg(target_os = "linux")]
+pub mod async_vmm;
+pub mod audit;
+#[cfg(target_os = "linux")]
+pub mod boot;
+#[cfg(target_os = "linux")]
+pub mod builder;
+pub mod cleanup;
+#[cfg(target_os = "linux")]
+pub mod clone;
+pub mod config;
+#[cfg(target_os = "linux")]
+pub mod config_file;
+#[cfg(target_os = "linux")]
+pub mod control;
+#[cfg(target_os = "linux")]
+pub mod coredump;
+#[cfg(target_os = "linux")]
+pub mod daemon;
+#[cfg(target_os = "linux")]
+pub mod devices;
+#[cfg(target_os = "linux")]
+pub mod digest;
+pub mod error;
+#[cfg(target_os = "linux")]
+pub mod event_loop;
+#[cfg(target_os = "linux")]
+pub mod events;
+#[cfg(target_os = "linux")]
+pub mod grpc;
+#[cfg(target_os = "linux")]
+pub mod hibernate;
+#[cfg(target_os = "linux")]
+pub mod host;
+#[cfg(target_os = "linux")]
+pub mod jail;
+#[cfg(target_os = "linux")]
+pub mod kvm;
+#[cfg(target_os = "linux")]
+pub mod mux;
+#[cfg(target_os = "linux")]
+pub mod oci;
+#[cfg(target_os = "linux")]
+pub mod pool;
+#[cfg(target_os = "linux")]
+pub mod progress;
+#[cfg(target_os = "linux")]
+pub mod reactor;
+#[cfg(target_os = "linux")]
+pub mod rootfs;
+#[cfg(target_os = "linux")]
+pub mod scratch;
+pub mod size;
+#[cfg(target_os = "linux")]
+pub mod snapshot;
+#[cfg(target_os = "linux")]
+pub mod vmm;
+
+pub use error::CarbonError;
+#[cfg(target_os = "linux")]
+pub use {
+    builder::{Vmm, VmmBuilder},
+    devices::{ConsoleBackend, ConsoleConfig},
+    vmm::{BootTimeline, DiskConfig, RunOutcome, StopReason, VcpuDebug, VmConfig},
+};
@@ -0,0 +1,266 @@
+//! VMM diagnostic logging.
+//!
+//! Carbon's own messages (`[VMM]`, `[Boot]`, `[KVM]`, ...) go to stderr and
+//! are filtered by a single process-wide level, set with `--log-level` or the
+//! `CARBON_LOG` environment variable. Guest console output is not affected.
+//!
+//! | Level   | Shows                                                     |
+//! | ------- | --------------------------------------------------------- |
+//! | `error` | Failures that stop the guest                              |
+//! | `warn`  | Recoverable problems (bad guest requests, timeouts)       |
+//! | `info`  | VM configuration and lifecycle (default, see below)       |
+//! | `debug` | Boot setup details: memory layout, registers, CPUID, MSRs |
+//! | `trace` | Per-exit and per-access device traffic, raw AML           |
+//!
+//! Without an explicit level, interactive runs show `warn` and above plus the
+//! boot progress line (see `progress`); everything else defaults to `info`.
+//!
+//! Messages other than `info` carry a level prefix (`error:`, `warning:`,
+//! ...), colored when stderr is a terminal unless `--color`/`CARBON_COLOR` or
+//! `NO_COLOR` say otherwise.
+//!
+//! # Guest vs VMM Output
+//!
+//! Guest console bytes go to stdout, and only guest console bytes: redirect
+//! stdout and you get exactly what the guest printed. Diagnostics go to
+//! stderr. Both streams share one lock, so a diagnostic is never split by
+//! console output or vice versa. When both streams are the same terminal and
+//! the guest is mid-line, the diagnostic starts on a fresh line (the extra
+//! newline goes to stderr, never into the console stream).
+//!
+//! Use the `error!` .. `trace!` macros rather than `eprintln!`, and
+//! [`ConsoleOutput`] rather than `io::stdout()` for guest output.
+
+use clap::ValueEnum;
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Verbosity of VMM diagnostics, from least to most verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, ValueEnum)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+/// When to color level prefixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// Color if stderr is a terminal and `NO_COLOR` is unset
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Serializes writes to stdout and stderr, and holds the status line.
+static OUTPUT: Mutex<StatusLine> = Mutex::new(StatusLine {
+    text: None,
+    shown: false,
+});
+
+/// A transient line kept at the bottom of the terminal (see [`set_status`]).
+struct StatusLine {
+    text: Option<String>,
+    /// Whether `text` is currently drawn on stderr.
+    shown: bool,
+}
+
+impl StatusLine {
+    /// Erase the status line if it is drawn.
+    fn erase(&mut self, out: &mut String) {
+        if self.shown {
+            out.push_str("\r\x1b[K");
+            self.shown = false;
+        }
+    }
+
+    /// Draw the status line, unless the console cursor is mid-line.
+    fn draw(&mut self, out: &mut String) {
+        if let Some(text) = &self.text {
+            if !CONSOLE_MID_LINE.load(Ordering::Relaxed) {
+                out.push_str(text);
+                self.shown = true;
+            }
+        }
+    }
+}
+
+/// Whether the last console byte written was not a newline.
+static CONSOLE_MID_LINE: AtomicBool = AtomicBool::new(false);
+
+/// Set the most verbose level that will be printed.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages at `level` are printed.
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Decide whether level prefixes are colored.
+pub fn set_color(choice: ColorChoice) {
+    let color = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+    };
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+fn lock_output() -> MutexGuard<'static, StatusLine> {
+    OUTPUT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Write one diagnostic line to stderr. Use the level macros instead.
+pub fn write(level: Level, args: fmt::Arguments) {
+    let mut status = lock_output();
+
+    let mut line = String::new();
+    status.erase(&mut line);
+    if CONSOLE_MID_LINE.swap(false, Ordering::Relaxed)
+        && io::stdout().is_terminal()
+        && io::stderr().is_terminal()
+    {
+        line.push('\n');
+    }
+    line.push_str(&prefix(level, COLOR.load(Ordering::Relaxed)));
+    line.push_str(&args.to_string());
+    line.push('\n');
+    status.draw(&mut line);
+
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// Show `text` as a status line at the bottom of the terminal, or remove it.
+///
+/// The line lives on stderr and is erased around every diagnostic and console
+/// write, then redrawn whenever the console is at the start of a line. Only
+/// use it when stderr is a terminal.
+pub fn set_status(text: Option<String>) {
+    let mut status = lock_output();
+    let mut out = String::new();
+    status.erase(&mut out);
+    status.text = text;
+    status.draw(&mut out);
+    let _ = io::stderr().lock().write_all(out.as_bytes());
+}
+
+/// Level prefix of a diagnostic line.
+fn prefix(level: Level, color: bool) -> String {
+    let (name, ansi) = match level {
+        Level::Error => ("error", "1;31"),
+        Level::Warn => ("warning", "1;33"),
+        Level::Info => return String::new(),
+        Level::Debug => ("debug", "36"),
+        Level::Trace => ("trace", "2"),
+    };
+    if color {
+        format!("\x1b[{ansi}m{name}:\x1b[0m ")
+    } else {
+        format!("{name}: ")
+    }
+}
+
+/// Guest console writer: stdout, serialized against diagnostics.
+pub struct ConsoleOutput;
+
+impl Write for ConsoleOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut status = lock_output();
+        let mut erase = String::new();
+        status.erase(&mut erase);
+        let _ = io::stderr().lock().write_all(erase.as_bytes());
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(buf)?;
+        stdout.flush()?;
+        if let Some(&last) = buf.last() {
+            CONSOLE_MID_LINE.store(last != b'\n', Ordering::Relaxed);
+        }
+
+        let mut redraw = String::new();
+        status.draw(&mut redraw);
+        let _ = io::stderr().lock().write_all(redraw.as_bytes());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _guard = lock_output();
+        io::stdout().flush()
+    }
+}
+
+/// Print a diagnostic to stderr if `$level` is enabled. This and the level
+/// macros are exported for the `carbon` binary, but aren't part of the API.
+#[macro_export]
+#[doc(hidden)]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level) {
+            $crate::logging::write($level, format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::logging::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::logging::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::logging::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels_are_ordered() {
+        assert!(Level::Error < Level::Warn);
+        assert!(Level::Debug < Level::Trace);
+        assert_eq!(Level::from_str("DEBUG", true), Ok(Level::Debug));
+        assert!(Level::from_str("loud", true).is_err());
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(prefix(Level::Info, true), "");
+        assert_eq!(prefix(Level::Warn, false), "warning: ");
+        assert_eq!(prefix(Level::Error, true), "\x1b[1;31merror:\x1b[0m ");
+    }
+}
@@ -1,285 +1,1778 @@
 //! Carbon - A minimal microVM runtime for AI agent sandboxing.
 //!
-//! Milestone 2: Boot Linux with virtio-blk disk support.
+//! The `carbon` command: parses the command line and profiles into a VM
+//! description for the library (see `lib.rs`), and reports how it ran.
 //!
 //! This VMM requires Linux with KVM support. It will not run on other platforms.
 
+#[macro_use]
+extern crate carbon;
+
+use carbon::error::{self, CarbonError};
 #[cfg(target_os = "linux")]
-mod boot;
-#[cfg(target_os = "linux")]
-mod devices;
+use carbon::events::EventLog;
 #[cfg(target_os = "linux")]
-mod kvm;
-
-use clap::Parser;
+use carbon::{
+    api, boot, clone, config_file, control, daemon, devices, digest, grpc, hibernate, jail, kvm,
+    mux, oci, pool, progress, rootfs, scratch, snapshot, vmm, VmmBuilder,
+};
+use carbon::{audit, cleanup, config, logging, size};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use std::io::IsTerminal;
 use std::process::ExitCode;
 
+/// Without Linux no VM runs, so there are no events to log.
+#[cfg(not(target_os = "linux"))]
+type EventLog = std::convert::Infallible;
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0  the guest ran and stopped normally
+  2  invalid command-line usage
+  3  configuration error (profile, missing or unreadable inputs)
+  4  host capability error (KVM missing, denied or failing)
+  5  guest failure (crashed, or never reached init under `bench`)
+  6  the guest kernel panicked
+  7  the guest didn't reach init within --boot-timeout
+  8  the VM was still running at --max-runtime
+A guest that prints the line CARBON_EXIT_STATUS=N (0-255) on its console stops
+the VM, and carbon exits with N. With --debug-exit, a guest write of N to the
+port exits with (N << 1) | 1.";
+
+/// How long `carbon snapshot` waits for the VM to write guest RAM out.
+#[cfg(target_os = "linux")]
+const SNAPSHOT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
 #[derive(Parser, Debug)]
 #[command(name = "carbon")]
 #[command(about = "A minimal microVM runtime for AI agent sandboxing")]
-struct Args {
-    /// Path to the Linux kernel bzImage
-    #[arg(short, long)]
-    kernel: String,
+#[command(disable_version_flag = true)]
+#[command(after_help = EXIT_STATUS_HELP)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Append a record of every host resource the VM touches (files, disks,
+    /// sockets, devices) to this log
+    #[arg(long, value_name = "PATH", global = true, env = "CARBON_AUDIT_LOG")]
+    audit_log: Option<std::path::PathBuf>,
+
+    /// ID identifying this VM in the audit log and the API [default: random]
+    #[arg(
+        long,
+        alias = "id",
+        value_name = "ID",
+        global = true,
+        env = "CARBON_VM_ID"
+    )]
+    vm_id: Option<String>,
+
+    /// Write the VM's lifecycle events (vm_started, kernel_loaded, ...,
+    /// vm_exited) as JSON lines to this inherited file descriptor, when
+    /// running or restoring a VM
+    #[arg(long, value_name = "FD", global = true, env = "CARBON_EVENT_FD")]
+    event_fd: Option<i32>,
 
-    /// Kernel command line (fast-boot options added automatically)
-    #[arg(short, long, default_value = "console=ttyS0")]
-    cmdline: String,
+    /// Run in the background once set up, as a daemon in a session of its
+    /// own, with stdin on /dev/null and stdout and stderr on --log-file
+    #[arg(long, global = true, env = "CARBON_DETACH")]
+    detach: bool,
 
-    /// Memory size in megabytes
-    #[arg(short, long, default_value = "512")]
-    memory: u64,
+    /// With --detach, write the daemon's pid to this file, locked while it
+    /// runs and removed when it exits
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        requires = "detach",
+        env = "CARBON_PID_FILE"
+    )]
+    pid_file: Option<std::path::PathBuf>,
 
-    /// Path to raw disk image (enables virtio-blk device)
-    #[arg(short, long)]
-    disk: Option<String>,
+    /// With --detach, append the console and diagnostics to this file
+    /// instead of discarding them
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        requires = "detach",
+        env = "CARBON_LOG_FILE"
+    )]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Print version
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// With --version, also report KVM capabilities and host CPU features
+    #[arg(short, long, requires = "version")]
+    verbose: bool,
+
+    /// VMM diagnostic verbosity on stderr [default: info, or a boot progress
+    /// line plus warnings when stderr is a terminal]
+    #[arg(long, value_enum, global = true, env = "CARBON_LOG")]
+    log_level: Option<logging::Level>,
+
+    /// Color diagnostic level prefixes
+    #[arg(long, value_enum, global = true, env = "CARBON_COLOR", default_value_t)]
+    color: logging::ColorChoice,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Boot a VM and run it until it stops
+    Run(Box<RunArgs>),
+    /// Snapshot a running VM through its control socket, to resume later
+    /// with `carbon restore`
+    Snapshot(SnapshotArgs),
+    /// Resume a VM from a snapshot taken with `carbon snapshot`
+    Restore(RestoreArgs),
+    /// Check that this host can run VMs, and which optional features it
+    /// supports
+    Check,
+    /// Report what VMs this build can run on this host, for schedulers
+    /// placing workloads
+    Capabilities(CapabilitiesArgs),
+    /// Describe the VM a snapshot holds
+    Inspect(InspectArgs),
+    /// Boot a kernel repeatedly and report kernel-start to init latency
+    Bench(Box<BenchArgs>),
+    /// Build an ext4 root filesystem image from an OCI container image
+    BuildRootfs(BuildRootfsArgs),
+    /// Keep clones of a snapshot restored and paused, and hand them out
+    /// on request
+    Pool(PoolArgs),
+    /// Serve the gRPC control API on a Unix socket, running the VMs
+    /// clients create
+    Serve(ServeArgs),
+    /// Run a carbon command as an unprivileged user in a chroot of its own,
+    /// in new namespaces and without capabilities (run as root)
+    Jail(JailArgs),
+}
+
+// `carbon run`: the VM, and how it is driven. (Plain comment: a doc comment
+// here would replace the subcommand's `about` text.)
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[command(flatten)]
+    vm: VmArgs,
+
+    /// Serve the guest console and VMM events as framed channels on a Unix
+    /// socket instead of stdout; waits for a client before booting
+    #[arg(long, value_name = "PATH", env = "CARBON_CONSOLE_SOCKET")]
+    console_socket: Option<std::path::PathBuf>,
+
+    /// Serve a Firecracker-compatible API on this Unix socket, and boot the
+    /// VM a client describes there instead of one from flags
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["kernel", "profile"])]
+    api_sock: Option<std::path::PathBuf>,
+
+    /// Stop the VM, exiting with code 7, if the guest hasn't reached init
+    /// (printed the init marker on its console) within SECONDS
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "CARBON_BOOT_TIMEOUT",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "api_sock"
+    )]
+    boot_timeout: Option<u64>,
+
+    /// Stop the VM, exiting with code 8, once it has run for SECONDS,
+    /// whatever the guest is doing
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "CARBON_MAX_RUNTIME",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "api_sock"
+    )]
+    max_runtime: Option<u64>,
+
+    /// Snapshot the VM into --hibernate-dir once it has been idle (no
+    /// device access, console input or control client) for SECONDS. With
+    /// --control-socket the next client wakes it from the snapshot;
+    /// otherwise Carbon exits, for `carbon restore` to carry on later
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "CARBON_HIBERNATE_AFTER",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "hibernate_dir",
+        conflicts_with = "api_sock"
+    )]
+    hibernate_after: Option<u64>,
+
+    /// Where an idle VM's snapshot goes (state.json and memory.bin);
+    /// created if missing
+    #[arg(
+        long,
+        value_name = "DIR",
+        env = "CARBON_HIBERNATE_DIR",
+        requires = "hibernate_after"
+    )]
+    hibernate_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct SnapshotArgs {
+    /// The running VM's --control-socket
+    #[arg(long, value_name = "PATH")]
+    control_socket: std::path::PathBuf,
+
+    /// Where to write the snapshot's state file
+    #[arg(long, value_name = "PATH")]
+    snapshot: std::path::PathBuf,
+
+    /// Where to write guest RAM
+    #[arg(long, value_name = "PATH")]
+    memory: std::path::PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct CapabilitiesArgs {
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// The snapshot's state file
+    #[arg(long, value_name = "PATH")]
+    snapshot: std::path::PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct RestoreArgs {
+    /// The snapshot's state file
+    #[arg(long, value_name = "PATH")]
+    snapshot: std::path::PathBuf,
+
+    /// The snapshot's memory file, mapped as guest RAM; the guest's writes
+    /// don't reach it
+    #[arg(long, value_name = "PATH")]
+    memory: std::path::PathBuf,
+
+    /// Serve the control socket on PATH (see `carbon run --help`); the
+    /// snapshotted VM's isn't reused
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<std::path::PathBuf>,
+
+    /// Restore as one of many clones of the snapshot: each disk the guest
+    /// can write is replaced by a private copy, removed on exit
+    #[arg(long)]
+    clone: bool,
+
+    /// Keep the vCPUs paused until `resume` on the control socket
+    #[arg(long, requires = "control_socket")]
+    paused: bool,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// Serve the API on this Unix socket
+    #[arg(long, value_name = "PATH")]
+    socket: std::path::PathBuf,
+
+    /// Where the VMs' control sockets, consoles and logs go; created if
+    /// missing
+    #[arg(long, value_name = "DIR")]
+    dir: std::path::PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct PoolArgs {
+    /// The snapshot's state file
+    #[arg(long, value_name = "PATH")]
+    snapshot: std::path::PathBuf,
+
+    /// The snapshot's memory file
+    #[arg(long, value_name = "PATH")]
+    memory: std::path::PathBuf,
+
+    /// How many VMs to keep ready
+    #[arg(long, default_value = "4")]
+    size: usize,
+
+    /// Serve the pool on this Unix socket
+    #[arg(long, value_name = "PATH")]
+    socket: std::path::PathBuf,
+
+    /// Where the VMs' control sockets, consoles and logs go; created if
+    /// missing
+    #[arg(long, value_name = "DIR")]
+    dir: std::path::PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct JailArgs {
+    /// Run carbon as this user
+    #[arg(long)]
+    uid: u32,
+
+    /// Run carbon as this group
+    #[arg(long)]
+    gid: u32,
+
+    /// Where jail roots are created: the jail's is CHROOT_BASE/carbon/ID/root,
+    /// for the --id given, and must not exist yet
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "DIR", default_value = jail::DEFAULT_BASE)]
+    chroot_base: std::path::PathBuf,
+
+    /// Mount a host file or directory in the jail, at TARGET or /NAME
+    /// (repeatable)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "SOURCE[:TARGET]", value_parser = |s: &str| jail::Bind::parse(s, false))]
+    bind: Vec<jail::Bind>,
+
+    /// Like --bind, but read-only
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "SOURCE[:TARGET]", value_parser = |s: &str| jail::Bind::parse(s, true))]
+    bind_ro: Vec<jail::Bind>,
+
+    /// Join this network namespace (e.g. /var/run/netns/NAME), where the
+    /// VM's tap devices are, instead of a new, empty one
+    #[arg(long, value_name = "PATH")]
+    netns: Option<std::path::PathBuf>,
+
+    /// The carbon command to run, with paths as seen in the jail, e.g.
+    /// `-- run --kernel /bzImage`
+    #[arg(last = true, required = true, value_name = "ARGS")]
+    args: Vec<std::ffi::OsString>,
+}
+
+#[derive(Args, Debug)]
+struct BuildRootfsArgs {
+    /// Image to pull, e.g. python:3.12 or ghcr.io/OWNER/NAME@sha256:DIGEST
+    #[arg(long, value_name = "REF")]
+    image: String,
+
+    /// Where to write the ext4 image
+    #[arg(long, value_name = "PATH")]
+    out: std::path::PathBuf,
+
+    /// Image size, e.g. 2G (a bare number is MiB) [default: the contents
+    /// plus a quarter, at least 64M spare]
+    #[arg(long, value_parser = size::parse_disk)]
+    size: Option<size::ByteSize>,
+}
+
+// Options describing the VM to boot. Each option is taken from its flag, then
+// its CARBON_* environment variable, then the --config file or the selected
+// profile, then the built-in default. (Plain comment: a doc comment here would replace the
+// command's `about` text.)
+#[derive(Args, Debug)]
+struct VmArgs {
+    /// Load defaults from a named profile (~/.config/carbon/profiles/NAME.toml)
+    #[arg(short, long, env = "CARBON_PROFILE")]
+    profile: Option<String>,
+
+    /// Describe the VM in a JSON or TOML file instead of a profile:
+    /// machine, boot, drives, vsock, console and logging sections, checked
+    /// before anything starts. Flags override it
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "CARBON_CONFIG",
+        conflicts_with = "profile"
+    )]
+    config: Option<std::path::PathBuf>,
+
+    /// Path to the Linux kernel (a bzImage, or an uncompressed vmlinux)
+    #[arg(short, long, env = "CARBON_KERNEL")]
+    kernel: Option<String>,
+
+    /// Refuse to boot unless the kernel file has this SHA-256 digest (64
+    /// hex digits, optionally prefixed `sha256:`)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "DIGEST", env = "CARBON_KERNEL_SHA256")]
+    kernel_sha256: Option<digest::Sha256Digest>,
+
+    /// Kernel command line, with the --boot-profile parameters appended [default: console=ttyS0]
+    #[arg(short, long, env = "CARBON_CMDLINE")]
+    cmdline: Option<String>,
+
+    /// Memory size, e.g. 512M or 2G (a bare number is MiB) [default: 512M]
+    #[arg(short, long, env = "CARBON_MEMORY", value_parser = size::parse_memory)]
+    memory: Option<size::ByteSize>,
+
+    /// What backs guest memory: `anonymous` private memory, a `memfd` that
+    /// can be shared with other processes, a memfd `sealed` at its size, or
+    /// a named file, e.g. `file=/dev/shm/vm0,shared=on` (shared so other
+    /// processes see the guest's writes; off by default). vhost-user
+    /// devices need shared memory, and get a memfd instead of anonymous
+    /// [default: anonymous]
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "BACKEND", env = "CARBON_MEMORY_BACKEND")]
+    memory_backend: Option<boot::MemoryBacking>,
+
+    /// Fault in all guest memory at startup, so the guest never waits on
+    /// the host to allocate a page: slower to start, steadier latency after
+    #[cfg(target_os = "linux")]
+    #[arg(long, env = "CARBON_PREFAULT")]
+    prefault: bool,
+
+    /// Lock guest memory into host RAM so host swap never stalls the guest.
+    /// Needs an RLIMIT_MEMLOCK (`ulimit -l`) of at least --memory, or
+    /// CAP_IPC_LOCK
+    #[cfg(target_os = "linux")]
+    #[arg(long, env = "CARBON_MLOCK", conflicts_with = "swappable")]
+    mlock: bool,
+
+    /// Let the --control-socket's `page-out` push guest memory out to swap,
+    /// for VMs that will sit idle
+    #[cfg(target_os = "linux")]
+    #[arg(long, env = "CARBON_SWAPPABLE")]
+    swappable: bool,
+
+    /// Path to an initrd/initramfs image
+    #[arg(long, env = "CARBON_INITRD")]
+    initrd: Option<String>,
+
+    /// Run the kernel at its link address instead of a random one (adds
+    /// `nokaslr` to the command line)
+    #[arg(long, env = "CARBON_NO_KASLR")]
+    no_kaslr: bool,
+
+    /// Parameters appended to the kernel command line: `fast` adds
+    /// `reboot=k panic=-1 noapictimer`, `compat` leaves out `noapictimer`,
+    /// `custom` adds none. Unless `custom`, a command line without
+    /// `console=` gets `console=ttyS0`, and one without `root=` boots from
+    /// the first --disk holding a root filesystem. Parameters other options
+    /// need (`root=` for --rootfs, `nokaslr`) are added regardless
+    /// [default: fast]
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_enum, env = "CARBON_BOOT_PROFILE")]
+    boot_profile: Option<vmm::BootProfile>,
+
+    /// Add nothing Carbon would infer or choose to the kernel command line
+    /// (same as `--boot-profile custom`)
+    #[arg(long, env = "CARBON_NO_AUTO_CMDLINE", conflicts_with = "boot_profile")]
+    no_auto_cmdline: bool,
+
+    /// Raw disk image or host block device (e.g. an LVM volume under
+    /// /dev/mapper) to attach as a virtio-blk device (repeatable: the
+    /// guest sees /dev/vda, /dev/vdb, ... in order). The `path=IMAGE` form
+    /// takes options: `ro` attaches it read-only, and
+    /// `logical-block-size=N` / `physical-block-size=N` set the block sizes
+    /// the guest sees (512 by default; use 4K for 4K-native storage).
+    /// `direct=on` opens the image with O_DIRECT, bypassing the host page
+    /// cache. `cache=writeback` (the default) leaves writes in the host page
+    /// cache until the guest flushes, `cache=writethrough` syncs every write
+    /// and `cache=none` is writeback over O_DIRECT. `exclusive=on` opens a
+    /// block device with O_EXCL, refusing one that is mounted or in use by
+    /// another VM. `key-file=PATH`, `key-fd=N` or `key-command=PROGRAM`
+    /// encrypts the disk with AES-256-XTS under a key read from a file, an
+    /// inherited descriptor or a program's output, so the image holds only
+    /// ciphertext. `sha256=DIGEST` refuses to boot unless the image, as
+    /// stored, has this SHA-256 digest. `fd=N` uses descriptor N, inherited already open, instead
+    /// of opening a path (same options, bar `exclusive`).
+    /// `vhost-user=SOCKET` attaches a disk served by a vhost-user-blk
+    /// backend (e.g. SPDK) instead, which decides all of these itself.
+    /// `iops=N` and `bw=SIZE` (bytes per second) limit the disk's I/O, with
+    /// bursts of up to `iops-burst=N` / `bw-burst=SIZE` (one second's worth
+    /// by default). Every form but a bare IMAGE takes `transport=pci` to
+    /// attach the disk over PCI, for kernels without virtio-mmio
+    #[arg(
+        short,
+        long,
+        value_name = "IMAGE|path=IMAGE[,OPTIONS]|fd=N[,OPTIONS]|vhost-user=SOCKET[,transport=pci]",
+        env = "CARBON_DISK",
+        value_delimiter = ';'
+    )]
+    disk: Vec<String>,
+
+    /// Boot from a read-only base image with a fresh scratch overlay disk,
+    /// adding the root= and overlayroot= kernel parameters (the guest
+    /// initramfs must provide the overlayroot hook)
+    #[arg(long, value_name = "BASE", env = "CARBON_ROOTFS")]
+    rootfs: Option<String>,
+
+    /// Refuse to boot unless the --rootfs base image has this SHA-256
+    /// digest
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "DIGEST", env = "CARBON_ROOTFS_SHA256")]
+    rootfs_sha256: Option<digest::Sha256Digest>,
+
+    /// Size of the --rootfs scratch overlay (a bare number is MiB) [default: 1G]
+    #[arg(long, value_name = "SIZE", env = "CARBON_ROOTFS_OVERLAY_SIZE", value_parser = size::parse_disk)]
+    rootfs_overlay_size: Option<size::ByteSize>,
+
+    /// Attach a blank, memory-backed scratch disk of SIZE (a bare number is
+    /// MiB) after all other disks. Nothing is written to the host
+    /// filesystem and the contents are gone when the VM exits. Add
+    /// `transport=pci` to attach it over PCI
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "size=SIZE[,transport=pci]",
+        env = "CARBON_SCRATCH_DISK"
+    )]
+    scratch_disk: Option<scratch::ScratchDiskConfig>,
+
+    /// Record which disk blocks the guest reads while booting and prefetch
+    /// them in the background on later boots of the same image (applies to
+    /// every --disk image and the --rootfs base)
+    #[arg(long, env = "CARBON_DISK_PREFETCH")]
+    disk_prefetch: bool,
+
+    /// Expose every disk image read-only. Read-only images can be shared by
+    /// several VMs, which then share one cache of its blocks
+    #[arg(long, env = "CARBON_DISK_READ_ONLY")]
+    disk_read_only: bool,
+
+    /// Map an image straight into guest memory as a virtio-pmem device
+    /// (`/dev/pmem0`, mountable with `-o dax`); its size must be a multiple
+    /// of 2 MiB. The `path=IMAGE` form takes `transport=pci`
+    #[arg(
+        long,
+        value_name = "IMAGE|path=IMAGE[,transport=pci]",
+        env = "CARBON_PMEM"
+    )]
+    pmem: Option<String>,
+
+    /// CPU model: `host` exposes every feature KVM supports; `portable`,
+    /// `baseline` (x86-64-v2) and `x86-64-v3` expose a stable subset so
+    /// snapshots stay portable across hosts [default: host]
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_enum, env = "CARBON_CPU")]
+    cpu: Option<kvm::CpuMode>,
+
+    /// Features to expose or hide on top of --cpu, named as in
+    /// /proc/cpuinfo, e.g. +avx2,-rdtscp
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "+FEATURE,-FEATURE",
+        allow_hyphen_values = true,
+        env = "CARBON_CPU_FEATURES"
+    )]
+    cpu_features: Option<kvm::CpuFeatures>,
+
+    /// Number of vCPUs [default: 1]
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "N", env = "CARBON_CPUS")]
+    cpus: Option<u32>,
+
+    /// How the vCPUs are arranged, e.g. sockets=1,cores=2,threads=2; fields
+    /// left out are 1, except cores, which makes up --cpus
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "sockets=N,cores=N,threads=N",
+        env = "CARBON_TOPOLOGY"
+    )]
+    topology: Option<String>,
+
+    /// Pin the vCPU threads to these host cores, e.g. 2,3 or 4-7: with one
+    /// core per vCPU each gets its own, otherwise they share them all
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "CORES", env = "CARBON_CPU_AFFINITY")]
+    cpu_affinity: Option<String>,
+
+    /// Expose Hyper-V enlightenments (relaxed timing, vapic, synic, stimer)
+    /// for guests that run better with them, such as Windows
+    #[cfg(target_os = "linux")]
+    #[arg(long, env = "CARBON_HYPERV")]
+    hyperv: bool,
+
+    /// Expose VMX/SVM so the guest can run KVM or VMs of its own, when the
+    /// host allows nested virtualization
+    #[cfg(target_os = "linux")]
+    #[arg(long, env = "CARBON_ENABLE_NESTED")]
+    enable_nested: bool,
+
+    /// Shift the guest's RTC from host UTC by this many seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        env = "CARBON_RTC_OFFSET",
+        allow_negative_numbers = true
+    )]
+    rtc_offset: Option<i64>,
+
+    /// Start the guest's RTC at this Unix time instead of the host clock,
+    /// for reproducible runs
+    #[arg(
+        long,
+        value_name = "UNIX_TIME",
+        env = "CARBON_RTC_START",
+        conflicts_with = "rtc_offset"
+    )]
+    rtc_start: Option<i64>,
+
+    /// Run an external program emulating MMIO or port I/O devices for the
+    /// guest (repeatable; see the device plugin protocol)
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "CARBON_DEVICE_PLUGINS",
+        value_delimiter = ','
+    )]
+    device_plugin: Vec<String>,
+
+    /// Add a virtio-vsock device: the guest gets context ID N, and host
+    /// processes reach it through the Unix socket PATH (send `CONNECT PORT`).
+    /// Add `transport=pci` to attach it over PCI
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "cid=N,uds=PATH[,transport=pci]",
+        env = "CARBON_VSOCK"
+    )]
+    vsock: Option<devices::VsockConfig>,
+
+    /// Share a host directory served by a vhost-user-fs backend (virtiofsd)
+    /// listening on PATH; the guest mounts it with `mount -t virtiofs TAG DIR`
+    /// (repeatable). Add `transport=pci` to attach it over PCI
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "socket=PATH,tag=TAG[,transport=pci]",
+        env = "CARBON_SHARED_DIRS",
+        value_delimiter = ';'
+    )]
+    shared_dir: Vec<devices::SharedDirConfig>,
+
+    /// Share host directory DIR over virtio-9p, served by Carbon itself;
+    /// the guest mounts it with
+    /// `mount -t 9p -o trans=virtio,version=9p2000.L TAG DIR` (repeatable).
+    /// Add `transport=pci` to attach it over PCI
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long = "9p",
+        value_name = "path=DIR,tag=TAG[,transport=pci]",
+        env = "CARBON_9P",
+        value_delimiter = ';'
+    )]
+    p9: Vec<devices::P9Share>,
+
+    /// Add an isa-debug-exit port: a guest write of N to it stops the VM,
+    /// and Carbon exits with code (N << 1) | 1. For test runs (QEMU uses 0xf4)
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "PORT",
+        env = "CARBON_DEBUG_EXIT",
+        value_parser = devices::debug_exit::parse_port
+    )]
+    debug_exit: Option<u16>,
+
+    /// Stop the VM once every vCPU has halted with interrupts disabled, as
+    /// after the guest's `halt`, instead of leaving it idle
+    #[cfg(target_os = "linux")]
+    #[arg(long, env = "CARBON_EXIT_ON_HALT")]
+    exit_on_halt: bool,
+
+    /// Console backend of the first serial port (COM1, the guest's ttyS0):
+    /// `stdio`, `file=PATH` (append), `socket=PATH` (wait for a client) or
+    /// `pty` [default: stdio]
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "SPEC", env = "CARBON_SERIAL")]
+    serial: Option<devices::ConsoleConfig>,
+
+    /// Attach a second serial port (COM2, the guest's ttyS1) on its own
+    /// console backend, e.g. to keep an application log apart from the
+    /// console on ttyS0. Takes the same specs as --serial; a bare FILE
+    /// appends to it
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "SPEC", env = "CARBON_SERIAL2")]
+    serial2: Option<devices::ConsoleConfig>,
+
+    /// Pass a named blob to the guest over fw_cfg, from a file or a literal
+    /// string (repeatable). Linux shows it at
+    /// /sys/firmware/qemu_fw_cfg/by_name/NAME/raw; use names under `opt/`
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "name=NAME,file=PATH|string=TEXT",
+        env = "CARBON_FW_CFG",
+        value_delimiter = ';'
+    )]
+    fw_cfg: Vec<devices::FwCfgItem>,
+
+    /// Give the guest a TPM 2.0 (CRB interface) backed by swtpm, whose data
+    /// socket is at SOCKET (start swtpm with `--server type=unixio,path=SOCKET
+    /// --flags not-need-init,startup-clear`)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "SOCKET", env = "CARBON_TPM")]
+    tpm: Option<std::path::PathBuf>,
+
+    /// Accept commands that change the running VM (e.g. `disk-rate-limit 0
+    /// iops=100`) on a Unix socket at PATH, one per line; doesn't wait for a
+    /// client
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "PATH", env = "CARBON_CONTROL_SOCKET")]
+    control_socket: Option<std::path::PathBuf>,
+
+    /// Reserve N free virtio-blk slots for disks plugged in while the VM
+    /// runs, with `disk-plug` on the --control-socket
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "N", env = "CARBON_HOTPLUG_DISKS")]
+    hotplug_disks: Option<usize>,
+
+    /// Write guest memory and vCPU registers to PATH as an ELF core if the
+    /// guest triple-faults, for `crash` or gdb against its vmlinux (the
+    /// --control-socket's `dump-core PATH` writes one on demand)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "PATH", env = "CARBON_DUMP_CORE_ON_FAULT")]
+    dump_core_on_fault: Option<std::path::PathBuf>,
+
+    /// Append the boot's milestone times (KVM ready, first vCPU entry, first
+    /// console output, init) to PATH as a line of JSON when the VM stops;
+    /// `bench` appends one line per boot
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "PATH", env = "CARBON_BOOT_REPORT")]
+    boot_report: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct BenchArgs {
+    #[command(flatten)]
+    vm: VmArgs,
+
+    /// Number of boots to measure
+    #[arg(short = 'n', long, default_value = "10")]
+    iterations: u32,
+
+    /// Per-boot timeout in seconds
+    #[arg(long, default_value = "30")]
+    timeout: u64,
+
+    /// Console string that marks init being reached
+    #[arg(long, default_value = "as init process")]
+    marker: String,
 }
 
 fn main() -> ExitCode {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    // Before anything starts a thread, and before stderr is looked at
+    let _pid_file = match detach(&cli) {
+        Ok(guard) => guard,
+        Err(e) => {
+            error!("{}", error::report(&e));
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    let (log_level, audit_log) = file_logging(&cli);
+    let log_level = cli.log_level.or(log_level);
+    let audit_log = cli.audit_log.clone().or(audit_log);
+    // On a terminal, a progress line replaces the log unless one was asked for
+    let show_progress = log_level.is_none()
+        && matches!(cli.command, Some(Command::Run(_)))
+        && !cli.version
+        && std::io::stderr().is_terminal();
+    logging::set_max_level(match log_level {
+        Some(level) => level,
+        None if show_progress => logging::Level::Warn,
+        None => logging::Level::Info,
+    });
+    #[cfg(target_os = "linux")]
+    if show_progress {
+        progress::enable();
+    }
+    logging::set_color(cli.color);
+    cleanup::install_panic_hook();
+    let _terminal = cleanup::guard_terminal();
+
+    if cli.version {
+        print!("{}", version_report(cli.verbose));
+        return ExitCode::SUCCESS;
+    }
+    let Some(command) = cli.command else {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a command is required, e.g. `carbon run --kernel bzImage`",
+            )
+            .exit();
+    };
 
-    if let Err(e) = run(args) {
-        eprintln!("Error: {e}");
-        return ExitCode::FAILURE;
+    let vm_id = cli.vm_id.clone().unwrap_or_else(audit::new_vm_id);
+    if let Some(path) = &audit_log {
+        if let Err(source) = audit::open(path, &vm_id) {
+            let e = CarbonError::AuditLog {
+                path: path.display().to_string(),
+                source,
+            };
+            error!("{}", error::report(&e));
+            return ExitCode::from(e.exit_code());
+        }
     }
 
-    ExitCode::SUCCESS
+    let result = match command {
+        Command::Run(mut args) => match args.api_sock.take() {
+            Some(path) => serve_api(&path, &vm_id),
+            None => with_event_log(cli.event_fd, &vm_id, |log| run(*args, log)),
+        },
+        Command::Snapshot(args) => snapshot(args).map(|()| 0),
+        Command::Restore(args) => with_event_log(cli.event_fd, &vm_id, |log| restore(args, log)),
+        Command::Check => check(),
+        Command::Capabilities(args) => capabilities(args).map(|()| 0),
+        Command::Inspect(args) => inspect(args).map(|()| 0),
+        Command::Bench(args) => bench(*args).map(|()| 0),
+        Command::BuildRootfs(args) => build_rootfs(args).map(|()| 0),
+        Command::Pool(args) => pool(args, audit_log),
+        Command::Serve(args) => serve(args, audit_log),
+        Command::Jail(args) => jail(args, cli.vm_id.as_deref()),
+    };
+
+    let code = match &result {
+        Ok(code) => *code,
+        Err(e) => {
+            error!("{}", error::report(e));
+            e.exit_code()
+        }
+    };
+    audit::close(&format!("exit code {code}"));
+    ExitCode::from(code)
 }
 
+/// The log level and audit log a `--config` file sets, for whichever flags
+/// aren't given. A file with errors sets neither: they are reported once
+/// the VM is described.
 #[cfg(target_os = "linux")]
-fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
-    use boot::{BootConfig, GuestMemory, VirtioDeviceConfig};
-    use devices::{
-        Cmos, MmioBus, Serial, VirtioBlk, CMOS_PORT_DATA, CMOS_PORT_INDEX, SERIAL_COM1_BASE,
-        SERIAL_COM1_END, VIRTIO_BLK_IRQ, VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE,
+fn file_logging(cli: &Cli) -> (Option<logging::Level>, Option<std::path::PathBuf>) {
+    let args = match &cli.command {
+        Some(Command::Run(args)) => &args.vm,
+        Some(Command::Bench(args)) => &args.vm,
+        _ => return (None, None),
     };
-    use kvm::{IoData, IoHandler, MmioHandler, VcpuExit};
-
-    eprintln!("[VMM] Carbon starting...");
-    eprintln!("[VMM] Kernel: {}", args.kernel);
-    eprintln!("[VMM] Memory: {} MB", args.memory);
-    if let Some(ref disk) = args.disk {
-        eprintln!("[VMM] Disk: {}", disk);
-    }
-
-    // Create VM
-    let vm = kvm::create_vm()?;
-
-    // Allocate guest memory
-    let mem_size = args.memory * 1024 * 1024;
-    let memory = GuestMemory::new(mem_size)?;
-
-    // Set up MMIO bus and virtio-blk device if disk provided
-    let mut mmio_bus = MmioBus::new();
-
-    // Build kernel command line
-    // Note: virtio devices are discovered via ACPI, not kernel command line
-    let mut cmdline_parts = vec![args.cmdline.clone()];
-    cmdline_parts.push("reboot=t".into());
-    cmdline_parts.push("panic=-1".into());
-    cmdline_parts.push("noapictimer".into());
-    let cmdline = cmdline_parts.join(" ");
-    eprintln!("[VMM] Cmdline: {}", cmdline);
-
-    // Build virtio device configuration for ACPI DSDT
-    let mut virtio_devices = Vec::new();
-    if args.disk.is_some() {
-        virtio_devices.push(VirtioDeviceConfig {
-            id: 0,
-            mmio_base: VIRTIO_MMIO_BASE,
-            mmio_size: VIRTIO_MMIO_SIZE as u32,
-            gsi: VIRTIO_BLK_IRQ,
-        });
-    }
-
-    // Set up ACPI tables with HW_REDUCED flag and virtio device definitions
-    boot::setup_acpi(&memory, 1, &virtio_devices)?;
-
-    // Set up MP tables for interrupt routing (used with HW_REDUCED ACPI)
-    boot::setup_mptable(&memory, 1)?;
-
-    // Set up boot using Linux 64-bit boot protocol
-    let config = BootConfig {
-        kernel_path: args.kernel.clone(),
-        cmdline,
-        mem_size,
+    match args
+        .config
+        .as_deref()
+        .and_then(|path| config_file::ConfigFile::load(path).ok())
+    {
+        Some(file) => (file.log_level(), file.logging.audit_log),
+        None => (None, None),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn file_logging(_cli: &Cli) -> (Option<logging::Level>, Option<std::path::PathBuf>) {
+    (None, None)
+}
+
+/// With `--detach`, carry on as a daemon (see `daemon`), holding its pid
+/// file until dropped.
+#[cfg(target_os = "linux")]
+fn detach(cli: &Cli) -> Result<Option<cleanup::CleanupGuard>, CarbonError> {
+    if !cli.detach {
+        return Ok(None);
+    }
+    let config = daemon::DaemonConfig {
+        pid_file: cli.pid_file.clone(),
+        log_file: cli.log_file.clone(),
     };
-    boot::setup_boot(&vm, &memory, &config)?;
-
-    // Create virtio-blk device after memory is set up
-    if let Some(ref disk_path) = args.disk {
-        let mut blk = VirtioBlk::new(disk_path)?;
-        blk.set_memory(&memory);
-        mmio_bus.register(VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE, Box::new(blk));
-        eprintln!("[VMM] virtio-blk registered at {:#x}", VIRTIO_MMIO_BASE);
-    }
-
-    // Create vCPU (also sets CPUID)
-    let mut vcpu = vm.create_vcpu(0)?;
-
-    // Set up CPU registers for 64-bit long mode boot
-    vcpu.set_boot_msrs()?;
-    boot::setup_vcpu_regs(&vcpu, &memory)?;
-
-    // Create I/O and MMIO handler with devices
-    struct DeviceHandler {
-        serial: Serial,
-        cmos: Cmos,
-        mmio_bus: MmioBus,
-        io_count: u64,
-    }
-
-    impl IoHandler for DeviceHandler {
-        fn io_read(&mut self, port: u16, data: &mut IoData) {
-            self.io_count += 1;
-            if (SERIAL_COM1_BASE..=SERIAL_COM1_END).contains(&port) {
-                let offset = port - SERIAL_COM1_BASE;
-                let value = self.serial.read(offset);
-                for i in 0..data.len() {
-                    data.set(i, value);
-                }
-                if self.io_count <= 10 {
-                    eprintln!(
-                        "[I/O] IN  port={:#x} (serial+{}) -> {:#x}",
-                        port, offset, value
-                    );
-                }
-            } else if port == CMOS_PORT_INDEX || port == CMOS_PORT_DATA {
-                let value = self.cmos.read(port);
-                for i in 0..data.len() {
-                    data.set(i, value);
+    daemon::detach(&config).map_err(CarbonError::Detach)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn detach(_cli: &Cli) -> Result<Option<cleanup::CleanupGuard>, CarbonError> {
+    Ok(None)
+}
+
+/// `carbon --version [--verbose]` output.
+fn version_report(verbose: bool) -> String {
+    let mut report = format!("carbon {}\n", env!("CARGO_PKG_VERSION"));
+    if !verbose {
+        return report;
+    }
+
+    let features: Vec<&str> = [("integration", cfg!(feature = "integration"))]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect();
+    report += &format!(
+        "target: {}-{}\n",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    );
+    report += &format!(
+        "cargo features: {}\n",
+        if features.is_empty() {
+            "(none)".to_string()
+        } else {
+            features.join(" ")
+        }
+    );
+
+    #[cfg(target_os = "linux")]
+    {
+        match kvm::host::probe_kvm() {
+            Ok(info) => report += &info.to_string(),
+            Err(e) => report += &format!("kvm: unavailable ({e})\n"),
+        }
+        report += &kvm::host::probe_cpu().to_string();
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        report += "kvm: unavailable (requires Linux)\n";
+    }
+
+    report
+}
+
+#[cfg(target_os = "linux")]
+impl VmArgs {
+    /// Merge command-line flags over the selected profile and the defaults.
+    fn vm_config(&self) -> Result<vmm::VmConfig, CarbonError> {
+        use clap::ValueEnum;
+
+        let profile = match &self.config {
+            Some(path) => config_file::ConfigFile::load(path)
+                .and_then(|file| file.to_profile())
+                .map_err(|source| CarbonError::ConfigFile {
+                    path: path.display().to_string(),
+                    source,
+                })?,
+            None => config::Profile::load(self.profile.as_deref())?,
+        };
+
+        let kernel_path = self.kernel.clone().or(profile.kernel).ok_or_else(|| {
+            CarbonError::Config(
+                "no kernel given: pass --kernel, set CARBON_KERNEL, or set `kernel` in a \
+                 profile or `boot.kernel` in a --config file"
+                    .into(),
+            )
+        })?;
+        let cpu_mode = match (self.cpu, profile.cpu) {
+            (Some(cpu), _) => cpu,
+            (None, Some(name)) => kvm::CpuMode::from_str(&name, true).map_err(|_| {
+                CarbonError::Config(format!(
+                    "invalid cpu {:?} in profile (expected host, portable, baseline or x86-64-v3)",
+                    name
+                ))
+            })?,
+            (None, None) => kvm::CpuMode::default(),
+        };
+        let cpu_features = match (self.cpu_features.clone(), profile.cpu_features) {
+            (Some(features), _) => features,
+            (None, Some(features)) => features.parse().map_err(|e| {
+                CarbonError::Config(format!("invalid cpu_features {features:?} in profile: {e}"))
+            })?,
+            (None, None) => kvm::CpuFeatures::default(),
+        };
+        let boot_profile = match (self.no_auto_cmdline, self.boot_profile) {
+            (true, _) => vmm::BootProfile::Custom,
+            (false, Some(boot_profile)) => boot_profile,
+            (false, None) if profile.no_auto_cmdline.unwrap_or(false) => vmm::BootProfile::Custom,
+            (false, None) => match profile.boot_profile {
+                Some(name) => vmm::BootProfile::from_str(&name, true).map_err(|_| {
+                    CarbonError::Config(format!(
+                        "invalid boot_profile {:?} in profile (expected fast, compat or custom)",
+                        name
+                    ))
+                })?,
+                None => vmm::BootProfile::default(),
+            },
+        };
+        let memory_backing = match (self.memory_backend.clone(), profile.memory_backend) {
+            (Some(backing), _) => backing,
+            (None, Some(spec)) => spec.parse().map_err(|e| {
+                CarbonError::Config(format!("invalid memory_backend {spec:?} in profile: {e}"))
+            })?,
+            (None, None) => boot::MemoryBacking::default(),
+        };
+        let swap_policy = match (self.mlock, self.swappable) {
+            (true, _) => boot::SwapPolicy::Locked,
+            (false, true) => boot::SwapPolicy::Swappable,
+            (false, false) => match (profile.mlock, profile.swappable) {
+                (Some(true), Some(true)) => {
+                    return Err(CarbonError::Config(
+                        "profile sets both mlock and swappable".into(),
+                    ))
                 }
+                (Some(true), _) => boot::SwapPolicy::Locked,
+                (_, Some(true)) => boot::SwapPolicy::Swappable,
+                _ => boot::SwapPolicy::Default,
+            },
+        };
+        let topology = self.topology.clone().or(profile.topology);
+        let topology = kvm::Topology::resolve(self.cpus.or(profile.cpus), topology.as_deref())
+            .map_err(|e| CarbonError::Config(format!("invalid CPU topology: {e}")))?;
+        let cpu_affinity = match (self.cpu_affinity.clone(), profile.cpu_affinity) {
+            (Some(cores), _) | (None, Some(config::CpuAffinity::Cores(cores))) => {
+                kvm::CpuAffinity::resolve(topology.cpus(), &cores)
+            }
+            (None, Some(config::CpuAffinity::PerVcpu(cores))) => {
+                kvm::CpuAffinity::per_vcpu(topology.cpus(), &cores)
+            }
+            (None, None) => Ok(kvm::CpuAffinity::default()),
+        }
+        .map_err(|e| CarbonError::Config(format!("invalid CPU affinity: {e}")))?;
+        let disk_specs = if self.disk.is_empty() {
+            profile.disk.unwrap_or_default()
+        } else {
+            self.disk.clone()
+        };
+        let rootfs = self.rootfs.clone().or(profile.rootfs);
+        let pinned = |flag: Option<digest::Sha256Digest>, name: &str, value: Option<String>| match (
+            flag, value,
+        ) {
+            (Some(digest), _) => Ok(Some(digest)),
+            (None, Some(value)) => value.parse().map(Some).map_err(|e| {
+                CarbonError::Config(format!("invalid {name} {value:?} in profile: {e}"))
+            }),
+            (None, None) => Ok(None),
+        };
+        let kernel_sha256 = pinned(self.kernel_sha256, "kernel_sha256", profile.kernel_sha256)?;
+        let rootfs_sha256 = pinned(self.rootfs_sha256, "rootfs_sha256", profile.rootfs_sha256)?;
+        let vsock = match (&self.vsock, profile.vsock) {
+            (Some(vsock), _) => Some(vsock.clone()),
+            (None, Some(spec)) => Some(spec.parse().map_err(|e| {
+                CarbonError::Config(format!("invalid vsock {spec:?} in profile: {e}"))
+            })?),
+            (None, None) => None,
+        };
+        let shared_dirs = if self.shared_dir.is_empty() {
+            profile
+                .shared_dirs
+                .unwrap_or_default()
+                .iter()
+                .map(|spec| {
+                    spec.parse().map_err(|e| {
+                        CarbonError::Config(format!("invalid shared dir {spec:?} in profile: {e}"))
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            self.shared_dir.clone()
+        };
+        let p9_shares = if self.p9.is_empty() {
+            profile
+                .p9_shares
+                .unwrap_or_default()
+                .iter()
+                .map(|spec| {
+                    spec.parse().map_err(|e| {
+                        CarbonError::Config(format!("invalid 9p share {spec:?} in profile: {e}"))
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            self.p9.clone()
+        };
+        let pmem = match self.pmem.clone().or(profile.pmem) {
+            Some(spec) => Some(
+                spec.parse()
+                    .map_err(|e| CarbonError::Config(format!("invalid pmem {spec:?}: {e}")))?,
+            ),
+            None => None,
+        };
+        let scratch_disk = match (&self.scratch_disk, profile.scratch_disk) {
+            (Some(scratch), _) => Some(scratch.clone()),
+            (None, Some(spec)) => Some(spec.parse().map_err(|e| {
+                CarbonError::Config(format!("invalid scratch disk {spec:?} in profile: {e}"))
+            })?),
+            (None, None) => None,
+        };
+        let prefetch = self.disk_prefetch || profile.disk_prefetch.unwrap_or(false);
+        let read_only = self.disk_read_only || profile.disk_read_only.unwrap_or(false);
+        let mut disks = Vec::new();
+        for spec in &disk_specs {
+            let mut disk: vmm::DiskConfig = spec
+                .parse()
+                .map_err(|e| CarbonError::Config(format!("invalid disk {spec:?}: {e}")))?;
+            if !disk.vhost_user {
+                disk.options.read_only |= read_only;
+                disk.options.prefetch = prefetch;
+            }
+            disks.push(disk);
+        }
+        let serial = match (&self.serial, profile.serial) {
+            (Some(serial), _) => serial.clone(),
+            (None, Some(spec)) => spec.parse().map_err(|e| {
+                CarbonError::Config(format!("invalid serial {spec:?} in profile: {e}"))
+            })?,
+            (None, None) => devices::ConsoleConfig::default(),
+        };
+        let serial2 = match (&self.serial2, profile.serial2) {
+            (Some(serial2), _) => Some(serial2.clone()),
+            (None, Some(spec)) => Some(spec.parse().map_err(|e| {
+                CarbonError::Config(format!("invalid serial2 {spec:?} in profile: {e}"))
+            })?),
+            (None, None) => None,
+        };
+        let fw_cfg = if self.fw_cfg.is_empty() {
+            profile
+                .fw_cfg
+                .unwrap_or_default()
+                .iter()
+                .map(|spec| {
+                    spec.parse().map_err(|e| {
+                        CarbonError::Config(format!("invalid fw_cfg {spec:?} in profile: {e}"))
+                    })
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            self.fw_cfg.clone()
+        };
+        let rtc = match (self.rtc_start, self.rtc_offset) {
+            (Some(start), _) => devices::RtcClock::Fixed { start },
+            (None, Some(offset)) => devices::RtcClock::Host { offset },
+            (None, None) => match (profile.rtc_start, profile.rtc_offset) {
+                (Some(start), _) => devices::RtcClock::Fixed { start },
+                (None, offset) => devices::RtcClock::Host {
+                    offset: offset.unwrap_or(0),
+                },
+            },
+        };
+
+        Ok(vmm::VmConfig {
+            kernel_path,
+            kernel_sha256,
+            cmdline: self
+                .cmdline
+                .clone()
+                .or(profile.cmdline)
+                .unwrap_or_else(|| vmm::DEFAULT_CMDLINE.to_string()),
+            boot_profile,
+            mem_size: self
+                .memory
+                .or(profile.memory)
+                .unwrap_or(size::ByteSize(boot::layout::DEFAULT_MEM_SIZE))
+                .bytes(),
+            memory_backing,
+            prefault: self.prefault || profile.prefault.unwrap_or(false),
+            swap_policy,
+            initrd: self.initrd.clone().or(profile.initrd),
+            kaslr: !(self.no_kaslr || profile.no_kaslr.unwrap_or(false)),
+            disks,
+            rootfs: rootfs.map(|base| rootfs::RootfsConfig {
+                base,
+                overlay_size: self
+                    .rootfs_overlay_size
+                    .or(profile.rootfs_overlay_size)
+                    .map_or(rootfs::DEFAULT_OVERLAY_SIZE, size::ByteSize::bytes),
+                prefetch,
+                sha256: rootfs_sha256,
+            }),
+            scratch_disk,
+            cpu_mode,
+            cpu_features,
+            topology,
+            cpu_affinity,
+            hyperv: self.hyperv || profile.hyperv.unwrap_or(false),
+            nested: self.enable_nested || profile.enable_nested.unwrap_or(false),
+            rtc,
+            device_plugins: if self.device_plugin.is_empty() {
+                profile.device_plugins.unwrap_or_default()
             } else {
-                // Return 0xff for unhandled ports
-                for i in 0..data.len() {
-                    data.set(i, 0xff);
-                }
-                if self.io_count <= 10 {
-                    eprintln!(
-                        "[I/O] IN  port={:#x} size={} -> 0xff (unhandled)",
-                        port,
-                        data.len()
-                    );
-                }
+                self.device_plugin.clone()
+            },
+            vsock,
+            shared_dirs,
+            p9_shares,
+            pmem,
+            debug_exit: self.debug_exit.or(profile.debug_exit),
+            exit_on_halt: self.exit_on_halt || profile.exit_on_halt.unwrap_or(false),
+            serial,
+            serial2,
+            fw_cfg,
+            tpm: self.tpm.clone().or(profile.tpm),
+            control_socket: self.control_socket.clone(),
+            hotplug_disks: self.hotplug_disks.unwrap_or(0),
+            dump_core_on_fault: self.dump_core_on_fault.clone(),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Boot the VM; returns the process exit code.
+fn run(run_args: RunArgs, event_log: Option<EventLog>) -> Result<u8, CarbonError> {
+    use std::io::Write;
+    use std::time::Duration;
+
+    let args = run_args.vm;
+    let config = args.vm_config()?;
+
+    info!("[VMM] Carbon starting...");
+    if let Some(ref profile) = args.profile {
+        info!("[VMM] Profile: {}", profile);
+    }
+    if let Some(ref path) = args.config {
+        info!("[VMM] Config: {}", path.display());
+    }
+    info!("[VMM] Kernel: {}", config.kernel_path);
+    info!("[VMM] Memory: {}", size::ByteSize(config.mem_size));
+    if config.memory_backing != boot::MemoryBacking::Anonymous {
+        info!("[VMM] Memory backend: {}", config.memory_backing);
+    }
+    if config.prefault {
+        info!("[VMM] Prefault: faulting in guest memory at startup");
+    }
+    if config.swap_policy != boot::SwapPolicy::Default {
+        info!("[VMM] Guest memory swap: {}", config.swap_policy);
+    }
+    info!("[VMM] Boot profile: {:?}", config.boot_profile);
+    info!("[VMM] CPU mode: {:?}", config.cpu_mode);
+    if !config.cpu_features.is_empty() {
+        info!("[VMM] CPU features: {}", config.cpu_features);
+    }
+    info!(
+        "[VMM] CPUs: {} ({})",
+        config.topology.cpus(),
+        config.topology
+    );
+    if config.cpu_affinity.is_pinned() {
+        info!("[VMM] CPU affinity: {}", config.cpu_affinity);
+    }
+    if let Some(ref initrd) = config.initrd {
+        info!("[VMM] Initrd: {}", initrd);
+    }
+    for disk in &config.disks {
+        info!(
+            "[VMM] Disk: {}{}",
+            disk.path,
+            if disk.vhost_user {
+                " (vhost-user)"
+            } else if disk.options.read_only {
+                " (read-only)"
+            } else {
+                ""
             }
+        );
+    }
+    if let Some(ref scratch) = config.scratch_disk {
+        info!(
+            "[VMM] Scratch disk: {} in memory",
+            size::ByteSize(scratch.size)
+        );
+    }
+    if let Some(ref pmem) = config.pmem {
+        info!("[VMM] Pmem: {}", pmem.path);
+    }
+    if let Some(ref vsock) = config.vsock {
+        info!("[VMM] Vsock: CID {} via {}", vsock.cid, vsock.uds.display());
+    }
+    for dir in &config.shared_dirs {
+        info!("[VMM] Shared dir: {} via {}", dir.tag, dir.socket.display());
+    }
+    for share in &config.p9_shares {
+        info!("[VMM] 9p share: {} as {}", share.path.display(), share.tag);
+    }
+    for plugin in &config.device_plugins {
+        info!("[VMM] Device plugin: {}", plugin);
+    }
+    if let Some(port) = config.debug_exit {
+        info!("[VMM] Debug exit: port {:#x}", port);
+    }
+    if config.exit_on_halt {
+        info!("[VMM] Exit on halt: stopping once every vCPU halts with interrupts disabled");
+    }
+    if let Some(ref tpm) = config.tpm {
+        info!("[VMM] TPM: swtpm at {}", tpm.display());
+    }
+    if let Some(ref path) = config.dump_core_on_fault {
+        info!("[VMM] Core dump on triple fault: {}", path.display());
+    }
+    for item in &config.fw_cfg {
+        info!("[VMM] fw_cfg: {}", item.name);
+    }
+    if let Some(ref rootfs) = config.rootfs {
+        info!(
+            "[VMM] Rootfs: {} (overlay {})",
+            rootfs.base,
+            size::ByteSize(rootfs.overlay_size)
+        );
+    }
+
+    let boot_timeout = run_args.boot_timeout.map(Duration::from_secs);
+    let max_runtime = run_args.max_runtime.map(Duration::from_secs);
+    if let Some(timeout) = boot_timeout {
+        info!("[VMM] Boot timeout: {:?}", timeout);
+    }
+    if let Some(timeout) = max_runtime {
+        info!("[VMM] Maximum runtime: {:?}", timeout);
+    }
+
+    let mut builder = VmmBuilder::with_config(config);
+    if let Some(timeout) = boot_timeout {
+        builder = builder.boot_timeout(timeout);
+    }
+    if let Some(timeout) = max_runtime {
+        builder = builder.timeout(timeout);
+    }
+    let hibernate = run_args
+        .hibernate_after
+        .zip(run_args.hibernate_dir)
+        .map(|(after, dir)| hibernate::Hibernate::new(Duration::from_secs(after), dir));
+    if let Some(hibernate) = &hibernate {
+        info!(
+            "[VMM] Hibernating to {} after {:?} idle",
+            hibernate.dir.display(),
+            hibernate.idle_timeout
+        );
+    }
+    let mut socket = None;
+    if let Some(path) = run_args.console_socket {
+        let (mux, guard) =
+            mux::MuxSocket::accept(&path).map_err(|source| CarbonError::ConsoleSocket {
+                path: path.display().to_string(),
+                source,
+            })?;
+        if builder.config_mut().serial != devices::ConsoleConfig::Stdio {
+            return Err(CarbonError::Config(
+                "--serial and --console-socket both set COM1's console".into(),
+            ));
         }
+        builder = builder
+            .console(Box::new(mux.channel(mux::Channel::Serial)))
+            .events(Box::new(mux.channel(mux::Channel::Events)));
+        socket = Some((mux, guard));
+    }
 
-        fn io_write(&mut self, port: u16, data: &IoData) {
-            self.io_count += 1;
-            if (SERIAL_COM1_BASE..=SERIAL_COM1_END).contains(&port) {
-                let offset = port - SERIAL_COM1_BASE;
-                if self.io_count <= 10 {
-                    eprintln!(
-                        "[I/O] OUT port={:#x} (serial+{}) <- {:?}",
-                        port,
-                        offset,
-                        data.as_slice()
+    if let Some(log) = &event_log {
+        builder = builder.event_log(log.clone());
+    }
+    let mut report = open_boot_report(args.boot_report.as_deref())?;
+    let run = match &hibernate {
+        // Each VM woken from the snapshot gets the same console and events
+        Some(hibernate) => hibernate::run(builder, hibernate, |mut restored| {
+            if let Some((mux, _)) = &socket {
+                restored = restored
+                    .console(Box::new(mux.channel(mux::Channel::Serial)))
+                    .events(Box::new(mux.channel(mux::Channel::Events)));
+            }
+            if let Some(log) = &event_log {
+                restored = restored.event_log(log.clone());
+            }
+            restored
+        }),
+        None => builder.build().and_then(|vmm| vmm.run()),
+    };
+    let result = run.and_then(|outcome| {
+        info!("[VMM] Boot timeline: {}", outcome.timeline);
+        write_boot_report(&mut report, &outcome.timeline);
+        match (outcome.reason, boot_timeout, max_runtime) {
+            (vmm::StopReason::BootTimeout, Some(timeout), _) => {
+                Err(CarbonError::BootTimeout(timeout))
+            }
+            (vmm::StopReason::Timeout, _, Some(timeout)) => Err(CarbonError::MaxRuntime(timeout)),
+            (vmm::StopReason::Hibernated, _, _) => {
+                if let Some(hibernate) = &hibernate {
+                    info!(
+                        "[VMM] Hibernated; resume with carbon restore --snapshot {} --memory {}",
+                        hibernate.state_path().display(),
+                        hibernate.memory_path().display()
                     );
                 }
-                for &byte in data.as_slice() {
-                    self.serial.write(offset, byte);
-                }
-            } else if port == CMOS_PORT_INDEX || port == CMOS_PORT_DATA {
-                for &byte in data.as_slice() {
-                    self.cmos.write(port, byte);
-                }
-            } else if self.io_count <= 10 {
-                eprintln!(
-                    "[I/O] OUT port={:#x} <- {:?} (unhandled)",
-                    port,
-                    data.as_slice()
-                );
+                Ok(0)
             }
+            (reason, _, _) => exit_code(reason),
+        }
+    });
+    if let (Err(e), Some((mux, _))) = (&result, &socket) {
+        let event = format!("error {}", error::report(e));
+        let _ = mux
+            .channel(mux::Channel::Events)
+            .write_all(event.as_bytes());
+    }
+    result
+}
+
+#[cfg(target_os = "linux")]
+fn bench(args: BenchArgs) -> Result<(), CarbonError> {
+    use std::time::Duration;
+
+    if args.iterations == 0 {
+        return Err(CarbonError::Config(
+            "--iterations must be at least 1".into(),
+        ));
+    }
+
+    let config = args.vm.vm_config()?;
+    let mut samples = Vec::with_capacity(args.iterations as usize);
+    // Held so every boot reuses one mapped, decompressed kernel
+    let _kernel = boot::KernelImage::shared(&config.kernel_path)?;
+    let mut report = open_boot_report(args.vm.boot_report.as_deref())?;
+
+    for i in 1..=args.iterations {
+        let outcome = VmmBuilder::with_config(config.clone())
+            .console(Box::new(std::io::sink()))
+            .stop_at_init(args.marker.clone())
+            .timeout(Duration::from_secs(args.timeout))
+            .build()?
+            .run()?;
+        write_boot_report(&mut report, &outcome.timeline);
+        if let vmm::StopReason::Signal(signal) = outcome.reason {
+            return Err(CarbonError::Stopped(signal));
         }
+        let Some(boot_time) = outcome.timeline.kernel_to_init() else {
+            return Err(CarbonError::Guest(format!(
+                "boot {} did not reach init ({:?}); is the marker {:?} printed by this kernel/rootfs?",
+                i, outcome.reason, args.marker
+            )));
+        };
+        let setup = outcome.timeline.vmm_setup().unwrap_or_default();
+        println!(
+            "boot {:>3}/{}: {:>8.2} ms (VMM setup {:.2} ms)",
+            i,
+            args.iterations,
+            as_millis(boot_time),
+            as_millis(setup)
+        );
+        samples.push(boot_time);
     }
 
-    impl MmioHandler for DeviceHandler {
-        fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
-            self.io_count += 1;
-            self.mmio_bus.read(addr, data);
+    samples.sort();
+    println!(
+        "kernel start -> init: p50 {:.2} ms, p95 {:.2} ms, max {:.2} ms ({} boots)",
+        as_millis(percentile(&samples, 50)),
+        as_millis(percentile(&samples, 95)),
+        as_millis(samples[samples.len() - 1]),
+        samples.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn build_rootfs(args: BuildRootfsArgs) -> Result<(), CarbonError> {
+    let image: oci::ImageRef = args.image.parse().map_err(CarbonError::Config)?;
+    oci::build_rootfs(&image, &args.out, args.size.map(size::ByteSize::bytes)).map_err(|source| {
+        CarbonError::BuildRootfs {
+            image: image.to_string(),
+            source,
         }
+    })
+}
 
-        fn mmio_write(&mut self, addr: u64, data: &[u8]) {
-            self.io_count += 1;
-            self.mmio_bus.write(addr, data);
+/// `carbon restore`: rebuild the snapshotted VM on its memory file and
+/// resume it (see `snapshot`).
+#[cfg(target_os = "linux")]
+fn restore(args: RestoreArgs, event_log: Option<EventLog>) -> Result<u8, CarbonError> {
+    let mut builder = VmmBuilder::restore(&args.snapshot, &args.memory)?;
+    let config = builder.config_mut();
+    config.control_socket = args.control_socket;
+    let mut _copies = Vec::new();
+    if args.clone {
+        for disk in config.disks.iter_mut().filter(|d| !d.options.read_only) {
+            let copy =
+                clone::DiskCopy::create(std::path::Path::new(&disk.path)).map_err(|source| {
+                    CarbonError::Disk {
+                        path: disk.path.clone(),
+                        source,
+                    }
+                })?;
+            info!("[VMM] Disk {} cloned to {}", disk.path, copy.path());
+            disk.path = copy.path().to_string();
+            _copies.push(copy);
         }
     }
 
-    let mut handler = DeviceHandler {
-        serial: Serial::new(),
-        cmos: Cmos::new(),
-        mmio_bus,
-        io_count: 0,
+    info!(
+        "[VMM] Restoring {} ({} of memory, {} vCPUs)",
+        args.snapshot.display(),
+        size::ByteSize(config.mem_size),
+        config.topology.cpus()
+    );
+    if args.paused {
+        builder = builder.paused();
+    }
+    if let Some(log) = event_log {
+        builder = builder.event_log(log);
+    }
+    let outcome = builder.build()?.run()?;
+    exit_code(outcome.reason)
+}
+
+/// `carbon snapshot`: have the VM serving the control socket snapshot
+/// itself. Paths are made absolute first, as that VM resolves them from its
+/// own working directory.
+#[cfg(target_os = "linux")]
+fn snapshot(args: SnapshotArgs) -> Result<(), CarbonError> {
+    let control_error = |source| CarbonError::Control {
+        path: args.control_socket.display().to_string(),
+        source,
+    };
+    let state = std::path::absolute(&args.snapshot).map_err(control_error)?;
+    let memory = std::path::absolute(&args.memory).map_err(control_error)?;
+    let command = format!("snapshot {} {}", state.display(), memory.display());
+    control::send(&args.control_socket, &command, SNAPSHOT_TIMEOUT).map_err(control_error)?;
+    info!(
+        "[VMM] Snapshot written to {} and {}",
+        state.display(),
+        memory.display()
+    );
+    Ok(())
+}
+
+/// `carbon inspect`: print the VM a snapshot holds.
+#[cfg(target_os = "linux")]
+fn inspect(args: InspectArgs) -> Result<(), CarbonError> {
+    let snapshot =
+        snapshot::Snapshot::read(&args.snapshot).map_err(|source| CarbonError::Inspect {
+            path: args.snapshot.display().to_string(),
+            source,
+        })?;
+    print!("{snapshot}");
+    Ok(())
+}
+
+/// Run `run`, with the events of the VM it runs logged to `--event-fd`
+/// if given, ending with `vm_exited`.
+#[cfg(target_os = "linux")]
+fn with_event_log(
+    event_fd: Option<i32>,
+    vm_id: &str,
+    run: impl FnOnce(Option<EventLog>) -> Result<u8, CarbonError>,
+) -> Result<u8, CarbonError> {
+    let Some(fd) = event_fd else {
+        return run(None);
+    };
+    let log = EventLog::from_fd(fd, vm_id).map_err(|source| CarbonError::EventFd { fd, source })?;
+    let result = run(Some(log.clone()));
+    match &result {
+        Ok(code) => log.exited(*code, None),
+        Err(e) => log.exited(e.exit_code(), Some(error::report(e))),
+    }
+    result
+}
+
+/// `carbon pool`: serve a pool of paused clones of a snapshot until
+/// signalled.
+#[cfg(target_os = "linux")]
+fn pool(args: PoolArgs, audit_log: Option<std::path::PathBuf>) -> Result<u8, CarbonError> {
+    // Fail here, not in every VM started
+    snapshot::Snapshot::read(&args.snapshot).map_err(|source| CarbonError::Snapshot {
+        path: args.snapshot.display().to_string(),
+        source,
+    })?;
+    let pool_error = |source| CarbonError::Pool {
+        path: args.socket.display().to_string(),
+        source,
     };
+    std::fs::create_dir_all(&args.dir).map_err(pool_error)?;
+    info!(
+        "[pool] Keeping {} clones of {} ready on {}",
+        args.size,
+        args.snapshot.display(),
+        args.socket.display()
+    );
+    let pool = pool::Pool::new(pool::PoolConfig {
+        snapshot: args.snapshot.clone(),
+        memory: args.memory.clone(),
+        size: args.size,
+        dir: args.dir.clone(),
+        audit_log,
+    });
+    pool::serve(pool, &args.socket).map_err(pool_error)?;
+    Ok(0)
+}
 
-    eprintln!("[VMM] Starting vCPU...");
+/// `carbon serve`: serve the gRPC API until stopped.
+#[cfg(target_os = "linux")]
+fn serve(args: ServeArgs, audit_log: Option<std::path::PathBuf>) -> Result<u8, CarbonError> {
+    let serve_error = |source| CarbonError::Serve {
+        path: args.socket.display().to_string(),
+        source,
+    };
+    std::fs::create_dir_all(&args.dir).map_err(serve_error)?;
+    let config = grpc::ServerConfig {
+        dir: args.dir.clone(),
+        audit_log,
+    };
+    grpc::serve(config, &args.socket).map_err(serve_error)?;
+    Ok(0)
+}
+
+/// `carbon jail`: run a carbon command in a jail, exiting with its code.
+#[cfg(target_os = "linux")]
+fn jail(args: JailArgs, id: Option<&str>) -> Result<u8, CarbonError> {
+    let id = id.ok_or_else(|| CarbonError::Config("carbon jail needs an --id".into()))?;
+    jail::validate_id(id).map_err(|e| CarbonError::Config(format!("invalid --id {id}: {e}")))?;
+    let config = jail::JailConfig {
+        id: id.to_string(),
+        base: args.chroot_base,
+        uid: args.uid,
+        gid: args.gid,
+        binds: args.bind.into_iter().chain(args.bind_ro).collect(),
+        netns: args.netns,
+    };
+    info!("[jail] Running carbon in {}", config.root().display());
+    jail::run(&config, &args.args).map_err(CarbonError::Jail)
+}
+
+/// `carbon run --api-sock PATH`: run the VM a Firecracker API client starts.
+#[cfg(target_os = "linux")]
+fn serve_api(path: &std::path::Path, vm_id: &str) -> Result<u8, CarbonError> {
+    let outcome = api::serve(path, vm_id)?;
+    exit_code(outcome.reason)
+}
+
+/// The exit code for a VM that stopped for `reason`.
+#[cfg(target_os = "linux")]
+fn exit_code(reason: vmm::StopReason) -> Result<u8, CarbonError> {
+    match reason {
+        vmm::StopReason::GuestPanic => Err(CarbonError::GuestPanic),
+        vmm::StopReason::DebugExit(code) | vmm::StopReason::ExitStatus(code) => Ok(code),
+        vmm::StopReason::Signal(signal) => Err(CarbonError::Stopped(signal)),
+        _ => Ok(0),
+    }
+}
+
+/// `carbon check`: print what the host supports, failing with the host
+/// error exit code if it can't run VMs.
+#[cfg(target_os = "linux")]
+fn check() -> Result<u8, CarbonError> {
+    let report = kvm::host::check();
+    print!("{report}");
+    Ok(if report.passed() { 0 } else { error::EXIT_HOST })
+}
+
+/// `carbon capabilities`: print what VMs this build can run on this host.
+/// Unlike `carbon check`, a host without KVM isn't a failure: the report
+/// says so.
+#[cfg(target_os = "linux")]
+fn capabilities(args: CapabilitiesArgs) -> Result<(), CarbonError> {
+    let capabilities = kvm::host::capabilities();
+    if args.json {
+        let json = serde_json::to_string_pretty(&capabilities).expect("capabilities serialize");
+        println!("{json}");
+    } else {
+        print!("{capabilities}");
+    }
+    Ok(())
+}
+
+/// Open the --boot-report file, if one was given, for appending.
+#[cfg(target_os = "linux")]
+fn open_boot_report(path: Option<&std::path::Path>) -> Result<Option<std::fs::File>, CarbonError> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|source| CarbonError::BootReport {
+            path: path.display().to_string(),
+            source,
+        })?;
+    audit::record(audit::Kind::File, "append", &path.display().to_string());
+    Ok(Some(file))
+}
+
+/// Append `timeline` to the --boot-report file, if any, in one write. The VM
+/// has already run, so a failed write is only a warning.
+#[cfg(target_os = "linux")]
+fn write_boot_report(report: &mut Option<std::fs::File>, timeline: &vmm::BootTimeline) {
     use std::io::Write;
-    std::io::stderr().flush().ok();
-
-    // Run the VM
-    let mut iteration = 0u64;
-    loop {
-        iteration += 1;
-        if iteration == 1 {
-            eprintln!("[VMM] Entering KVM (first run)...");
-            std::io::stderr().flush().ok();
-        }
-        let exit = vcpu.run_with_io(&mut handler)?;
-        if iteration == 1 {
-            eprintln!("[VMM] First vCPU exit received!");
-        }
 
-        // Log first 10 exits and every 100000 after
-        if iteration <= 10 || iteration.is_multiple_of(100000) {
-            eprintln!(
-                "[VMM] iteration {}: {:?}, {} I/O ops",
-                iteration, exit, handler.io_count
-            );
-        }
-        match exit {
-            VcpuExit::Io => {
-                // I/O handled by the handler
-            }
-            VcpuExit::Hlt => {
-                eprintln!(
-                    "\n[VMM] Guest halted after {} iterations, {} I/O ops",
-                    iteration, handler.io_count
-                );
-                break;
-            }
-            VcpuExit::Shutdown => {
-                eprintln!(
-                    "\n[VMM] Guest shutdown after {} iterations, {} I/O ops",
-                    iteration, handler.io_count
-                );
-                if let Ok(regs) = vcpu.get_regs() {
-                    eprintln!("[VMM] Final RIP: {:#x}", regs.rip);
-                }
-                break;
-            }
-            VcpuExit::InternalError => {
-                eprintln!("[VMM] KVM internal error");
-                break;
-            }
-            VcpuExit::FailEntry(reason) => {
-                eprintln!("[VMM] Failed to enter guest: reason={}", reason);
-                break;
-            }
-            VcpuExit::SystemEvent(event) => {
-                eprintln!("[VMM] System event: {}", event);
-                break;
-            }
-            VcpuExit::Unknown(reason) => {
-                eprintln!("[VMM] Unknown exit: {}", reason);
-                break;
-            }
+    if let Some(file) = report {
+        let line = format!("{}\n", timeline.to_json());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("[VMM] Failed to write the boot report: {}", e);
         }
     }
+}
 
-    Ok(())
+/// Nearest-rank percentile of an ascending, non-empty sample set.
+#[cfg(target_os = "linux")]
+fn percentile(sorted: &[std::time::Duration], pct: usize) -> std::time::Duration {
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(target_os = "linux")]
+fn as_millis(duration: std::time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn with_event_log(
+    _event_fd: Option<i32>,
+    _vm_id: &str,
+    run: impl FnOnce(Option<EventLog>) -> Result<u8, CarbonError>,
+) -> Result<u8, CarbonError> {
+    run(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run(_args: RunArgs, _event_log: Option<EventLog>) -> Result<u8, CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check() -> Result<u8, CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn capabilities(_args: CapabilitiesArgs) -> Result<(), CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn jail(_args: JailArgs, _id: Option<&str>) -> Result<u8, CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn snapshot(_args: SnapshotArgs) -> Result<(), CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn inspect(_args: InspectArgs) -> Result<(), CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restore(_args: RestoreArgs, _event_log: Option<EventLog>) -> Result<u8, CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pool(_args: PoolArgs, _audit_log: Option<std::path::PathBuf>) -> Result<u8, CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn serve(_args: ServeArgs, _audit_log: Option<std::path::PathBuf>) -> Result<u8, CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn serve_api(_path: &std::path::Path, _vm_id: &str) -> Result<u8, CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bench(_args: BenchArgs) -> Result<(), CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
 }
 
 #[cfg(not(target_os = "linux"))]
-fn run(_args: Args) -> Result<(), Box<dyn std::error::Error>> {
-    Err("Carbon requires Linux with KVM support. This platform is not supported.".into())
+fn build_rootfs(_args: BuildRootfsArgs) -> Result<(), CarbonError> {
+    Err(CarbonError::Unsupported(
+        "Carbon requires Linux with KVM support. This platform is not supported.".into(),
+    ))
 }
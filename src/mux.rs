@@ -0,0 +1,248 @@
+//! Console multiplexing over a single socket.
+//!
+//! With `--console-socket PATH`, Carbon listens on a Unix socket, waits for
+//! one client to connect, and then sends everything the embedder needs over
+//! that one connection as a sequence of frames:
+//!
+//! ```text
+//! ┌─────────┬──────────────────┬────────────────────────┐
+//! │ channel │ length (u32, BE) │ payload (length bytes) │
+//! │ 1 byte  │ 4 bytes          │                        │
+//! └─────────┴──────────────────┴────────────────────────┘
+//! ```
+//!
+//! | Channel | Name           | Payload                                       |
+//! | ------- | -------------- | --------------------------------------------- |
+//! | 0       | `serial`       | Raw guest serial console bytes                |
+//! | 1       | `agent-stdout` | Stdout of commands run by the guest agent     |
+//! | 2       | `agent-stderr` | Stderr of commands run by the guest agent     |
+//! | 3       | `events`       | One VMM event per frame, e.g. `init-reached`  |
+//!
+//! Frames are never interleaved: each is written whole under a lock. A
+//! payload is at most [`MAX_PAYLOAD`] bytes; longer writes are split. Readers
+//! must skip frames on channels they don't know, so channels can be added
+//! without breaking existing embedders. Channels 1 and 2 are reserved for the
+//! guest agent and not produced yet.
+
+use crate::cleanup::{self, CleanupGuard};
+use crate::devices::ConsoleBackend;
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Size of the frame header: channel byte plus big-endian length.
+pub const HEADER_SIZE: usize = 5;
+
+/// Largest payload in one frame.
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// Logical streams carried over the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Channel {
+    Serial = 0,
+    #[allow(dead_code)] // Produced by the guest agent
+    AgentStdout = 1,
+    #[allow(dead_code)] // Produced by the guest agent
+    AgentStderr = 2,
+    Events = 3,
+}
+
+/// Append one or more frames carrying `payload` on `channel` to `out`.
+pub fn encode(channel: Channel, payload: &[u8], out: &mut Vec<u8>) {
+    for chunk in payload.chunks(MAX_PAYLOAD) {
+        out.push(channel as u8);
+        out.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// A decoded frame. `channel` is the raw byte so unknown channels survive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub channel: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Incremental frame parser for the reading side of the socket.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    /// Feed bytes read from the socket.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Take the next complete frame, if one has arrived.
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        let header = self.buf.get(..HEADER_SIZE)?;
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if self.buf.len() < HEADER_SIZE + len {
+            return None;
+        }
+        let channel = self.buf[0];
+        let payload = self.buf[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        self.buf.drain(..HEADER_SIZE + len);
+        Some(Frame { channel, payload })
+    }
+}
+
+/// The connected client, shared by every channel writer.
+#[derive(Clone)]
+pub struct MuxSocket {
+    stream: Arc<Mutex<UnixStream>>,
+}
+
+impl MuxSocket {
+    /// Listen on `path` and block until one client connects.
+    ///
+    /// The returned guard removes the socket file on exit (see
+    /// [`accept_client`]).
+    pub fn accept(path: &Path) -> io::Result<(Self, CleanupGuard)> {
+        let (stream, guard) = accept_client(path)?;
+        Ok((
+            Self {
+                stream: Arc::new(Mutex::new(stream)),
+            },
+            guard,
+        ))
+    }
+
+    /// A writer that sends everything written to it on `channel`.
+    pub fn channel(&self, channel: Channel) -> ChannelWriter {
+        ChannelWriter {
+            socket: self.clone(),
+            channel,
+        }
+    }
+
+    fn send(&self, channel: Channel, payload: &[u8]) -> io::Result<()> {
+        let mut frames = Vec::with_capacity(payload.len() + HEADER_SIZE);
+        encode(channel, payload, &mut frames);
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        stream.write_all(&frames)
+    }
+}
+
+/// Listen on `path` and block until one client connects.
+///
+/// A stale socket file at `path` is replaced (see [`cleanup::bind_socket`]).
+/// The returned guard removes the socket file on exit, including after a
+/// panic.
+pub fn accept_client(path: &Path) -> io::Result<(UnixStream, CleanupGuard)> {
+    let (listener, guard) = cleanup::bind_socket(path, "remove console socket")?;
+
+    info!("[VMM] Waiting for a client on {}", path.display());
+    let (stream, _) = listener.accept()?;
+    Ok((stream, guard))
+}
+
+/// [`Write`] adapter for one channel of a [`MuxSocket`].
+pub struct ChannelWriter {
+    socket: MuxSocket,
+    channel: Channel,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            self.socket.send(self.channel, buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The serial channel as COM1's console: output only.
+impl ConsoleBackend for ChannelWriter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let mut wire = Vec::new();
+        encode(Channel::Serial, b"Linux version", &mut wire);
+        encode(Channel::Events, b"init-reached", &mut wire);
+        assert_eq!(&wire[..HEADER_SIZE], &[0, 0, 0, 0, 13]);
+
+        let mut decoder = Decoder::default();
+        decoder.push(&wire);
+        assert_eq!(
+            decoder.next_frame(),
+            Some(Frame {
+                channel: 0,
+                payload: b"Linux version".to_vec()
+            })
+        );
+        assert_eq!(decoder.next_frame().unwrap().channel, 3);
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[test]
+    fn test_decoder_waits_for_whole_frame() {
+        let mut wire = Vec::new();
+        encode(Channel::Serial, b"hello", &mut wire);
+
+        let mut decoder = Decoder::default();
+        decoder.push(&wire[..3]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&wire[3..8]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&wire[8..]);
+        assert_eq!(decoder.next_frame().unwrap().payload, b"hello");
+    }
+
+    #[test]
+    fn test_large_payload_is_split() {
+        let payload = vec![0xab; MAX_PAYLOAD + 10];
+        let mut wire = Vec::new();
+        encode(Channel::Serial, &payload, &mut wire);
+
+        let mut decoder = Decoder::default();
+        decoder.push(&wire);
+        assert_eq!(decoder.next_frame().unwrap().payload.len(), MAX_PAYLOAD);
+        assert_eq!(decoder.next_frame().unwrap().payload.len(), 10);
+    }
+
+    #[test]
+    fn test_socket_carries_channels() {
+        let dir = std::env::temp_dir().join(format!("carbon-mux-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("console.sock");
+
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || loop {
+            if let Ok(mut stream) = UnixStream::connect(&client_path) {
+                let mut wire = Vec::new();
+                io::Read::read_to_end(&mut stream, &mut wire).unwrap();
+                return wire;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        });
+
+        let (socket, guard) = MuxSocket::accept(&path).unwrap();
+        socket.channel(Channel::Serial).write_all(b"boot").unwrap();
+        socket
+            .channel(Channel::Events)
+            .write_all(b"stopped")
+            .unwrap();
+        drop(socket);
+        drop(guard);
+        assert!(!path.exists());
+
+        let mut decoder = Decoder::default();
+        decoder.push(&client.join().unwrap());
+        assert_eq!(decoder.next_frame().unwrap().payload, b"boot");
+        assert_eq!(decoder.next_frame().unwrap().channel, Channel::Events as u8);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,947 @@
+//! Root filesystem images from OCI container images (`carbon build-rootfs`).
+//!
+//! `carbon build-rootfs --image python:3.12 --out rootfs.ext4` pulls an
+//! image from its registry and writes its filesystem out as an ext4 image
+//! ready for `--disk` or `--rootfs`:
+//!
+//! 1. The manifest is fetched (for a multi-platform image, the
+//!    `linux/amd64` one), then each layer in order, unpacked into a staging
+//!    directory next to the output as it downloads and checked against its
+//!    digest. Whiteouts (`.wh.NAME`, `.wh..wh..opq`) delete what lower
+//!    layers added, as overlayfs does.
+//! 2. `mkfs.ext4 -d` (e2fsprogs) builds the image from the staging
+//!    directory. Unless `--size` is given, the image is the size of its
+//!    contents plus a quarter, with at least 64 MiB spare.
+//!
+//! Nothing is left behind on failure, and the output only appears once the
+//! image is complete.
+//!
+//! # Registries
+//!
+//! References take the usual forms: `python:3.12`, or `python` for the
+//! `latest` tag, on Docker Hub; `ghcr.io/OWNER/NAME:TAG` elsewhere; and
+//! `NAME@sha256:DIGEST` to pin the content. Pulls are anonymous, with the
+//! bearer tokens registries hand out for public images. Registries on
+//! `localhost` are reached over plain HTTP, every other one over HTTPS.
+//!
+//! # Ownership
+//!
+//! Run as root, the unpacked files keep their owners. Otherwise they are
+//! unpacked as the invoking user, and `debugfs` then sets the owners, and
+//! the modes of directories (which have to stay writable while layers are
+//! unpacked), in the finished image. Device nodes are skipped: the guest
+//! kernel's devtmpfs provides them.
+//!
+//! # Booting
+//!
+//! The image's entry point and environment aren't part of its filesystem.
+//! They are logged, and the guest has to be told what to run, e.g.
+//! `--disk rootfs.ext4 --cmdline "console=ttyS0 root=/dev/vda rw
+//! init=/bin/sh"`.
+
+use crate::audit;
+use crate::cleanup::{self, CleanupGuard};
+use crate::size;
+use flate2::read::MultiGzDecoder;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+/// The platform picked from multi-platform images.
+const OS: &str = "linux";
+const ARCHITECTURE: &str = "amd64";
+
+/// Media types of multi-platform image indexes.
+const INDEX_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Media types of single-platform image manifests.
+const MANIFEST_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// Largest manifest or image config accepted.
+const MAX_DOCUMENT_SIZE: u64 = 4 * size::MIB;
+
+/// Least spare room left in the image.
+const MIN_SPARE: u64 = 64 * size::MIB;
+
+/// Opaque whiteout: the directory hides everything lower layers put in it.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Whiteout prefix: `.wh.NAME` deletes NAME.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// An image reference: `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    /// Registry host, with its port if any (`docker.io` for Docker Hub).
+    pub registry: String,
+    /// Repository within the registry, e.g. `library/python`.
+    pub repository: String,
+    /// Tag or `sha256:` digest.
+    pub reference: String,
+}
+
+impl ImageRef {
+    /// Base URL of the registry's API.
+    fn base_url(&self) -> String {
+        let host = match self.registry.as_str() {
+            "docker.io" => "registry-1.docker.io",
+            host => host,
+        };
+        let local = ["localhost", "127.0.0.1", "[::1]"]
+            .iter()
+            .any(|name| host == *name || host.starts_with(&format!("{name}:")));
+        let scheme = if local { "http" } else { "https" };
+        format!("{scheme}://{host}")
+    }
+
+    fn digest(&self) -> Option<&str> {
+        self.reference
+            .starts_with("sha256:")
+            .then_some(self.reference.as_str())
+    }
+}
+
+impl FromStr for ImageRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid image reference {s:?}");
+        let (name, reference) = match s.split_once('@') {
+            Some((name, digest)) => (name, check_digest(digest).map_err(|_| invalid())?),
+            None => match s.rsplit_once(':') {
+                Some((name, tag)) if !tag.contains('/') => (name, tag),
+                _ => (s, "latest"),
+            },
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest)) if host.contains(['.', ':']) || host == "localhost" => {
+                (host.to_string(), rest.to_string())
+            }
+            _ if !name.contains('/') => ("docker.io".into(), format!("library/{name}")),
+            _ => ("docker.io".into(), name.to_string()),
+        };
+        let repository_ok = repository.split('/').all(|part| {
+            !part.is_empty()
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-".contains(&b))
+        });
+        let tag_ok = reference.starts_with("sha256:")
+            || (reference.len() <= 128
+                && reference
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b)));
+        if registry.is_empty() || !repository_ok || reference.is_empty() || !tag_ok {
+            return Err(invalid());
+        }
+        Ok(Self {
+            registry,
+            repository,
+            reference: reference.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = if self.digest().is_some() { '@' } else { ':' };
+        write!(
+            f,
+            "{}/{}{}{}",
+            self.registry, self.repository, separator, self.reference
+        )
+    }
+}
+
+/// A pointer to a blob or manifest.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    size: u64,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+/// A manifest or an image index, told apart by which fields are present.
+#[derive(Debug, Deserialize)]
+struct Document {
+    manifests: Option<Vec<Descriptor>>,
+    config: Option<Descriptor>,
+    layers: Option<Vec<Descriptor>>,
+}
+
+/// The parts of an image config worth reporting.
+#[derive(Debug, Default, Deserialize)]
+struct ImageConfig {
+    config: Option<RunConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RunConfig {
+    entrypoint: Option<Vec<String>>,
+    cmd: Option<Vec<String>>,
+    env: Option<Vec<String>>,
+    working_dir: Option<String>,
+}
+
+/// A token endpoint's answer.
+#[derive(Debug, Deserialize)]
+struct Token {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Owner of an unpacked path, to set in the image when unpacking unprivileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Owner {
+    uid: u32,
+    gid: u32,
+    /// A directory's mode (unpacked with owner rwx forced on).
+    dir_mode: Option<u32>,
+}
+
+type Owners = BTreeMap<PathBuf, Owner>;
+
+/// Pull `image` and write its filesystem to `out` as an ext4 image of
+/// `size` bytes (sized to fit if `None`).
+pub fn build_rootfs(image: &ImageRef, out: &Path, size: Option<u64>) -> io::Result<()> {
+    let name = out
+        .file_name()
+        .ok_or_else(|| io::Error::other(format!("invalid output path {}", out.display())))?
+        .to_string_lossy();
+    let dir = out.parent().unwrap_or(Path::new(""));
+    let staging = TempPath::new(
+        dir.join(format!(".{name}.staging-{}", std::process::id())),
+        "remove rootfs staging directory",
+    );
+    let partial = TempPath::new(
+        dir.join(format!(".{name}.partial-{}", std::process::id())),
+        "remove partial rootfs image",
+    );
+
+    info!("[build-rootfs] Pulling {}", image);
+    let mut registry = Registry::new(image);
+    let (config, layers) = registry.manifest()?;
+    let image_config: ImageConfig = registry.fetch_json(
+        &format!("blobs/{}", check_digest(&config.digest)?),
+        &config.media_type,
+        Some(&config.digest),
+    )?;
+
+    fs::create_dir(&staging.path)?;
+    let root = staging.path.canonicalize()?;
+    let mut owners = (!is_root()).then(Owners::new);
+    for (i, layer) in layers.iter().enumerate() {
+        info!(
+            "[build-rootfs] Layer {}/{}: {} ({:.1} MiB)",
+            i + 1,
+            layers.len(),
+            layer.digest,
+            layer.size as f64 / size::MIB as f64
+        );
+        registry.unpack_layer(layer, &root, owners.as_mut())?;
+    }
+
+    let (bytes, inodes) = usage(&root)?;
+    let size = size.unwrap_or_else(|| {
+        let spare = (bytes / 4).max(MIN_SPARE);
+        (bytes + spare).div_ceil(size::MIB) * size::MIB
+    });
+    info!(
+        "[build-rootfs] {:.1} MiB in {} files, building a {:.1} MiB ext4 image",
+        bytes as f64 / size::MIB as f64,
+        inodes,
+        size as f64 / size::MIB as f64
+    );
+    File::create(&partial.path)?.set_len(size)?;
+    audit::record(
+        audit::Kind::Disk,
+        "create",
+        &partial.path.display().to_string(),
+    );
+    // Room for the files, and as many again for the guest
+    let inodes = (inodes * 2).max(16384);
+    run(Command::new("mkfs.ext4")
+        .args([
+            "-q",
+            "-F",
+            "-m",
+            "0",
+            "-L",
+            "rootfs",
+            "-E",
+            "root_owner=0:0",
+        ])
+        .arg("-N")
+        .arg(inodes.to_string())
+        .arg("-d")
+        .arg(&root)
+        .arg(&partial.path))?;
+    if let Some(owners) = &owners {
+        set_owners(&root, &partial.path, owners)?;
+    }
+    fs::rename(&partial.path, out)?;
+    audit::record(audit::Kind::Disk, "rename", &out.display().to_string());
+
+    let run_config = image_config.config.unwrap_or_default();
+    let command: Vec<_> = [run_config.entrypoint, run_config.cmd]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect();
+    if !command.is_empty() {
+        info!("[build-rootfs] Image command: {}", command.join(" "));
+    }
+    if let Some(dir) = run_config.working_dir.filter(|dir| !dir.is_empty()) {
+        info!("[build-rootfs] Image working directory: {}", dir);
+    }
+    for var in run_config.env.unwrap_or_default() {
+        debug!("[build-rootfs] Image environment: {}", var);
+    }
+    info!("[build-rootfs] Wrote {}", out.display());
+    Ok(())
+}
+
+/// A temporary file or directory, removed on drop or panic.
+struct TempPath {
+    path: PathBuf,
+    _cleanup: CleanupGuard,
+}
+
+impl TempPath {
+    fn new(path: PathBuf, what: &'static str) -> Self {
+        let remove_path = path.clone();
+        let cleanup = cleanup::register(what, move || {
+            let removed = match fs::symlink_metadata(&remove_path) {
+                Ok(meta) if meta.is_dir() => fs::remove_dir_all(&remove_path),
+                Ok(_) => fs::remove_file(&remove_path),
+                Err(_) => return,
+            };
+            if let Err(e) = removed {
+                warn!("Failed to remove {}: {}", remove_path.display(), e);
+            }
+        });
+        Self {
+            path,
+            _cleanup: cleanup,
+        }
+    }
+}
+
+/// A registry session for one repository.
+struct Registry<'a> {
+    image: &'a ImageRef,
+    agent: ureq::Agent,
+    /// Bearer token, once the registry asked for one.
+    token: Option<String>,
+}
+
+impl<'a> Registry<'a> {
+    fn new(image: &'a ImageRef) -> Self {
+        Self {
+            image,
+            agent: ureq::AgentBuilder::new()
+                .user_agent(concat!("carbon/", env!("CARGO_PKG_VERSION")))
+                .build(),
+            token: None,
+        }
+    }
+
+    /// GET `path` under the repository, authenticating if asked to.
+    fn get(&mut self, path: &str, accept: &str) -> io::Result<ureq::Response> {
+        let url = format!(
+            "{}/v2/{}/{}",
+            self.image.base_url(),
+            self.image.repository,
+            path
+        );
+        loop {
+            let mut request = self.agent.get(&url).set("Accept", accept);
+            if let Some(token) = &self.token {
+                request = request.set("Authorization", &format!("Bearer {token}"));
+            }
+            match request.call() {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(401, response)) if self.token.is_none() => {
+                    let challenge = response.header("www-authenticate").unwrap_or_default();
+                    self.token = Some(self.authenticate(challenge)?);
+                }
+                Err(ureq::Error::Status(code, response)) => {
+                    return Err(io::Error::other(format!(
+                        "{url}: {code} {}",
+                        response.status_text()
+                    )))
+                }
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+
+    /// Get an anonymous pull token as `challenge` (a WWW-Authenticate header)
+    /// directs.
+    fn authenticate(&self, challenge: &str) -> io::Result<String> {
+        let params = parse_challenge(challenge).ok_or_else(|| {
+            io::Error::other(format!(
+                "{} needs credentials ({challenge:?})",
+                self.image.registry
+            ))
+        })?;
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| io::Error::other("authentication challenge has no realm"))?;
+        let mut request = self.agent.get(realm);
+        if let Some(service) = params.get("service") {
+            request = request.query("service", service);
+        }
+        let scope = match params.get("scope") {
+            Some(scope) => scope.clone(),
+            None => format!("repository:{}:pull", self.image.repository),
+        };
+        let response = request
+            .query("scope", &scope)
+            .call()
+            .map_err(|e| io::Error::other(format!("{realm}: {e}")))?;
+        let token: Token = serde_json::from_reader(response.into_reader())?;
+        token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| io::Error::other(format!("{realm} gave no token")))
+    }
+
+    /// Fetch and parse a JSON document, checking its digest if known.
+    fn fetch_json<T: DeserializeOwned>(
+        &mut self,
+        path: &str,
+        accept: &str,
+        digest: Option<&str>,
+    ) -> io::Result<T> {
+        let mut reader = DigestReader::new(
+            self.get(path, accept)?
+                .into_reader()
+                .take(MAX_DOCUMENT_SIZE),
+        );
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        if let Some(digest) = digest {
+            reader.verify(digest)?;
+        }
+        serde_json::from_slice(&body).map_err(|e| io::Error::other(format!("{path}: {e}")))
+    }
+
+    /// The image's config and layers, for this platform.
+    fn manifest(&mut self) -> io::Result<(Descriptor, Vec<Descriptor>)> {
+        let accept = [INDEX_TYPES, MANIFEST_TYPES].concat().join(", ");
+        let reference = self.image.reference.clone();
+        let digest = self.image.digest().map(str::to_string);
+        let mut document: Document = self.fetch_json(
+            &format!("manifests/{reference}"),
+            &accept,
+            digest.as_deref(),
+        )?;
+        if let Some(manifests) = document.manifests {
+            let manifest = manifests
+                .iter()
+                .find(|m| {
+                    m.platform
+                        .as_ref()
+                        .is_some_and(|p| p.os == OS && p.architecture == ARCHITECTURE)
+                })
+                .ok_or_else(|| {
+                    io::Error::other(format!("{} has no {OS}/{ARCHITECTURE} image", self.image))
+                })?;
+            let digest = check_digest(&manifest.digest)?;
+            document = self.fetch_json(&format!("manifests/{digest}"), &accept, Some(digest))?;
+        }
+        match (document.config, document.layers) {
+            (Some(config), Some(layers)) => Ok((config, layers)),
+            _ => Err(io::Error::other(format!(
+                "{} has no image manifest",
+                self.image
+            ))),
+        }
+    }
+
+    /// Download `layer` and unpack it over `root`.
+    fn unpack_layer(
+        &mut self,
+        layer: &Descriptor,
+        root: &Path,
+        owners: Option<&mut Owners>,
+    ) -> io::Result<()> {
+        let digest = check_digest(&layer.digest)?;
+        let gzip = match layer.media_type.as_str() {
+            t if t.ends_with(".tar") => false,
+            t if t.ends_with("+gzip") || t.ends_with(".gzip") => true,
+            t => return Err(io::Error::other(format!("unsupported layer type {t}"))),
+        };
+        let mut blob = DigestReader::new(
+            self.get(&format!("blobs/{digest}"), &layer.media_type)?
+                .into_reader(),
+        );
+        {
+            let mut tar: Box<dyn Read + '_> = if gzip {
+                Box::new(MultiGzDecoder::new(&mut blob))
+            } else {
+                Box::new(&mut blob)
+            };
+            unpack(&mut tar, root, owners)?;
+            io::copy(&mut tar, &mut io::sink())?;
+        }
+        io::copy(&mut blob, &mut io::sink())?;
+        blob.verify(digest)
+    }
+}
+
+/// The parameters of a `Bearer` WWW-Authenticate challenge.
+fn parse_challenge(header: &str) -> Option<BTreeMap<String, String>> {
+    let (scheme, mut rest) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let mut params = BTreeMap::new();
+    loop {
+        rest = rest.trim_start_matches([',', ' ']);
+        if rest.is_empty() {
+            return Some(params);
+        }
+        let (key, value) = rest.split_once('=')?;
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"')?,
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        params.insert(key.trim().to_ascii_lowercase(), value.to_string());
+        rest = tail;
+    }
+}
+
+/// Check `digest` is a well-formed SHA-256 digest, the only kind supported.
+fn check_digest(digest: &str) -> io::Result<&str> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex)
+            if hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+        {
+            Ok(digest)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported digest {digest:?}"),
+        )),
+    }
+}
+
+/// Hashes everything read through it.
+struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Check what was read matches `digest`.
+    fn verify(self, digest: &str) -> io::Result<()> {
+        let actual = format!("sha256:{:x}", self.hasher.finalize());
+        if actual != digest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("digest mismatch: expected {digest}, got {actual}"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn is_root() -> bool {
+    // SAFETY: geteuid can't fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Unpack a layer tarball over `root` (canonical), applying its whiteouts.
+/// Ownership is kept if `owners` is `None`, else recorded there.
+fn unpack(reader: impl Read, root: &Path, mut owners: Option<&mut Owners>) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(owners.is_none());
+    // What this layer added, which its own opaque whiteouts leave alone
+    let mut added = HashSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let Some(path) = relative_path(&entry.path()?) else {
+            warn!(
+                "[build-rootfs] Skipped {} (outside the image)",
+                entry.path()?.display()
+            );
+            continue;
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name == OPAQUE_WHITEOUT {
+            let Some(dir) = resolve(root, parent)? else {
+                continue;
+            };
+            for child in fs::read_dir(dir)? {
+                let child = parent.join(child?.file_name());
+                if !added.iter().any(|p: &PathBuf| p.starts_with(&child)) {
+                    remove(root, &child, owners.as_deref_mut())?;
+                }
+            }
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            remove(root, &parent.join(hidden), owners.as_deref_mut())?;
+            continue;
+        }
+
+        let kind = entry.header().entry_type();
+        if kind.is_block_special() || kind.is_character_special() || kind.is_fifo() {
+            debug!("[build-rootfs] Skipped device node {}", path.display());
+            continue;
+        }
+        // Replace whatever is in the way, unless both are directories
+        let target = root.join(&path);
+        if fs::symlink_metadata(&target).is_ok_and(|m| !(m.is_dir() && kind.is_dir())) {
+            remove(root, &path, owners.as_deref_mut())?;
+        }
+        entry.unpack_in(root)?;
+        if let Some(owners) = owners.as_deref_mut() {
+            let header = entry.header();
+            let dir_mode = if kind.is_dir() {
+                let mode = header.mode()?;
+                // Layers above may add to it
+                fs::set_permissions(&target, fs::Permissions::from_mode(mode | 0o700))?;
+                Some(mode)
+            } else {
+                None
+            };
+            owners.insert(
+                path.clone(),
+                Owner {
+                    uid: header.uid()? as u32,
+                    gid: header.gid()? as u32,
+                    dir_mode,
+                },
+            );
+        }
+        added.insert(path);
+    }
+    Ok(())
+}
+
+/// `path` relative to the image root, or `None` if it leaves it.
+fn relative_path(path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(part) => relative.push(part),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!relative.as_os_str().is_empty()).then_some(relative)
+}
+
+/// Where `dir` (relative) is under `root`, following symlinks, or `None` if
+/// it doesn't exist. Symlinks leading out of `root` are an error.
+fn resolve(root: &Path, dir: &Path) -> io::Result<Option<PathBuf>> {
+    match root.join(dir).canonicalize() {
+        Ok(resolved) if resolved.starts_with(root) => Ok(Some(resolved)),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} leads outside the image", dir.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Delete `path` (relative), and everything under it, from the image.
+fn remove(root: &Path, path: &Path, owners: Option<&mut Owners>) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let (Some(dir), Some(name)) = (resolve(root, parent)?, path.file_name()) else {
+        return Ok(());
+    };
+    let target = dir.join(name);
+    let removed = match fs::symlink_metadata(&target) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(&target),
+        Ok(_) => fs::remove_file(&target),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Some(owners) = owners {
+        owners.retain(|p, _| !p.starts_with(path));
+    }
+    removed
+}
+
+/// Call `f` with every path under `root` (relative) and its metadata.
+fn walk(root: &Path, f: &mut impl FnMut(&Path, &Metadata)) -> io::Result<()> {
+    fn visit(root: &Path, dir: &Path, f: &mut impl FnMut(&Path, &Metadata)) -> io::Result<()> {
+        for entry in fs::read_dir(root.join(dir))? {
+            let path = dir.join(entry?.file_name());
+            let meta = fs::symlink_metadata(root.join(&path))?;
+            f(&path, &meta);
+            if meta.is_dir() {
+                visit(root, &path, f)?;
+            }
+        }
+        Ok(())
+    }
+    visit(root, Path::new(""), f)
+}
+
+/// Bytes allocated and inodes used under `root`.
+fn usage(root: &Path) -> io::Result<(u64, u64)> {
+    let (mut bytes, mut inodes) = (0, 0);
+    walk(root, &mut |_, meta| {
+        bytes += (meta.blocks() * 512).max(meta.len().next_multiple_of(4096));
+        inodes += 1;
+    })?;
+    Ok((bytes, inodes))
+}
+
+/// Give every file in `image`, built from `root`, the owner (and directory
+/// mode) it had in the layers, with debugfs.
+fn set_owners(root: &Path, image: &Path, owners: &Owners) -> io::Result<()> {
+    // SAFETY: geteuid and getegid can't fail.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let mut script = Vec::new();
+    let mut unquotable = 0;
+    walk(root, &mut |path, _| {
+        let owner = owners.get(path).copied().unwrap_or(Owner {
+            uid: 0,
+            gid: 0,
+            dir_mode: None,
+        });
+        let name = format!("/{}", path.display());
+        if name.contains(['"', '\\', '\n']) {
+            unquotable += 1;
+            return;
+        }
+        if owner.uid != uid {
+            let _ = writeln!(script, "sif \"{name}\" uid {}", owner.uid);
+        }
+        if owner.gid != gid {
+            let _ = writeln!(script, "sif \"{name}\" gid {}", owner.gid);
+        }
+        if let Some(mode) = owner.dir_mode.filter(|mode| mode & 0o700 != 0o700) {
+            let _ = writeln!(script, "sif \"{name}\" mode 0{:o}", libc::S_IFDIR | mode);
+        }
+    })?;
+    if unquotable > 0 {
+        warn!(
+            "[build-rootfs] {} files with quotes, backslashes or newlines in their names keep the invoking user as owner",
+            unquotable
+        );
+    }
+    let script_path = root.join(".carbon-owners");
+    fs::write(&script_path, script)?;
+    run(Command::new("debugfs")
+        .arg("-w")
+        .arg("-f")
+        .arg(&script_path)
+        .arg(image))
+}
+
+/// Run an e2fsprogs tool, failing with its error output if it fails.
+fn run(command: &mut Command) -> io::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                io::Error::other(format!("{program} not found (install e2fsprogs)"))
+            }
+            _ => e,
+        })?;
+    audit::record(audit::Kind::Process, "run", &program);
+    // debugfs reports failed commands but still exits 0
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let errors: Vec<&str> = stderr
+        .lines()
+        .filter(|line| !line.starts_with("debugfs ") && !line.trim().is_empty())
+        .collect();
+    if !output.status.success() || !errors.is_empty() {
+        return Err(io::Error::other(format!(
+            "{program} failed ({}): {}",
+            output.status,
+            errors.join("; ")
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_ref() {
+        let parse = |s: &str| s.parse::<ImageRef>().map(|r| r.to_string());
+        assert_eq!(
+            parse("python:3.12").unwrap(),
+            "docker.io/library/python:3.12"
+        );
+        assert_eq!(parse("python").unwrap(), "docker.io/library/python:latest");
+        assert_eq!(
+            parse("docker.io/library/python:3.12").unwrap(),
+            "docker.io/library/python:3.12"
+        );
+        assert_eq!(
+            parse("bitnami/redis").unwrap(),
+            "docker.io/bitnami/redis:latest"
+        );
+        let image: ImageRef = "localhost:5000/tools/app:v1".parse().unwrap();
+        assert_eq!(image.registry, "localhost:5000");
+        assert_eq!(image.repository, "tools/app");
+        assert_eq!(image.base_url(), "http://localhost:5000");
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let image: ImageRef = format!("ghcr.io/o/n@{digest}").parse().unwrap();
+        assert_eq!(image.reference, digest);
+        assert_eq!(image.digest(), Some(digest.as_str()));
+        assert_eq!(image.base_url(), "https://ghcr.io");
+        assert_eq!(
+            "python".parse::<ImageRef>().unwrap().base_url(),
+            "https://registry-1.docker.io"
+        );
+
+        assert!(parse("Python:3").is_err());
+        assert!(parse("python:3:x").is_err());
+        assert!(parse("python@sha256:1234").is_err());
+        assert!(parse("python:").is_err());
+        assert!(parse("ghcr.io//x").is_err());
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let params = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/python:pull""#,
+        )
+        .unwrap();
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/python:pull");
+        let params = parse_challenge("Bearer realm=https://r/token, service=r").unwrap();
+        assert_eq!(params["service"], "r");
+        assert!(parse_challenge(r#"Basic realm="r""#).is_none());
+    }
+
+    fn layer(build: impl FnOnce(&mut tar::Builder<Vec<u8>>)) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder);
+        builder.into_inner().unwrap()
+    }
+
+    fn add(builder: &mut tar::Builder<Vec<u8>>, path: &str, contents: Option<&[u8]>, mode: u32) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(match contents {
+            Some(_) => tar::EntryType::Regular,
+            None => tar::EntryType::Directory,
+        });
+        let contents = contents.unwrap_or_default();
+        header.set_size(contents.len() as u64);
+        header.set_mode(mode);
+        header.set_uid(0);
+        header.set_gid(42);
+        // Set by hand: set_path rejects `..`
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_cksum();
+        builder.append(&header, contents).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_layers() {
+        let dir = std::env::temp_dir().join(format!("carbon-oci-{}", std::process::id()));
+        fs::create_dir(&dir).unwrap();
+        let root = dir.canonicalize().unwrap();
+        let mut owners = Owners::new();
+
+        let lower = layer(|b| {
+            add(b, "etc/", None, 0o755);
+            add(b, "etc/passwd", Some(b"root"), 0o644);
+            add(b, "etc/shadow", Some(b"secret"), 0o600);
+            add(b, "opt/", None, 0o555);
+            add(b, "opt/old", Some(b"old"), 0o644);
+            add(b, "../escape", Some(b"!"), 0o644);
+        });
+        unpack(&lower[..], &root, Some(&mut owners)).unwrap();
+        assert_eq!(fs::read(root.join("etc/passwd")).unwrap(), b"root");
+        assert!(!dir.parent().unwrap().join("escape").exists());
+        assert_eq!(
+            owners[Path::new("opt")],
+            Owner {
+                uid: 0,
+                gid: 42,
+                dir_mode: Some(0o555)
+            }
+        );
+
+        let upper = layer(|b| {
+            add(b, "etc/.wh.shadow", Some(b""), 0o644);
+            add(b, "opt/", None, 0o755);
+            add(b, "opt/.wh..wh..opq", Some(b""), 0o644);
+            add(b, "opt/new", Some(b"new"), 0o644);
+            add(b, "etc/passwd/", None, 0o755);
+        });
+        unpack(&upper[..], &root, Some(&mut owners)).unwrap();
+        assert!(!root.join("etc/shadow").exists());
+        assert!(!owners.contains_key(Path::new("etc/shadow")));
+        assert!(!root.join("opt/old").exists());
+        assert_eq!(fs::read(root.join("opt/new")).unwrap(), b"new");
+        assert!(root.join("etc/passwd").is_dir());
+        assert_eq!(usage(&root).unwrap().1, 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_digest_reader() {
+        let mut reader = DigestReader::new(&b"abc"[..]);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        reader
+            .verify("sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+            .unwrap();
+        let reader = DigestReader::new(&b""[..]);
+        assert!(reader
+            .verify(&format!("sha256:{}", "0".repeat(64)))
+            .is_err());
+        assert!(check_digest("sha512:00").is_err());
+    }
+}
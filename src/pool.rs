@@ -0,0 +1,401 @@
+//! Pre-warmed VM pool (`carbon pool`).
+//!
+//! Restoring a snapshot (see [`crate::snapshot`]) skips the boot, but the
+//! VM still has to be built. A [`Pool`] does that ahead of time: it keeps a
+//! number of clones of one snapshot (see [`crate::clone`]) restored and
+//! paused, each a `carbon restore --clone --paused` process of its own,
+//! hands them out resumed on request, and starts another in the place of
+//! each one taken.
+//!
+//! `carbon pool` serves a pool on a Unix socket, with the protocol of the
+//! control socket (see [`crate::control`]):
+//!
+//! | Command | Effect                                                  |
+//! | ------- | ------------------------------------------------------- |
+//! | `take`  | Hand out a VM; answers `ok ID PID CONTROL_SOCKET`       |
+//! | `stats` | Answer the pool's metrics                               |
+//!
+//! A VM handed out belongs to the taker: it runs until the guest stops it
+//! or its process is signalled (SIGTERM stops it as it would any Carbon
+//! VM), and its control socket takes the usual commands. The pool only
+//! reaps the process. Each VM's control socket, console output and log are
+//! `ID.sock`, `ID.console` and `ID.log` in the pool's directory.
+//!
+//! A `take` with no VM ready is a miss, and fails at once rather than wait
+//! for one. `stats` answers `ok size=N ready=N warming=N taken=N hits=N
+//! misses=N hit_rate=R warmup_ms=N warmup_max_ms=N`: VMs ready, still
+//! being restored and handed out so far; `take`s served and missed, and
+//! the share served; and the mean and longest time a VM took from start to
+//! ready.
+//!
+//! Stopping the pool stops the VMs it still holds, but not those handed out.
+
+use crate::audit;
+use crate::control::{self, Commands, ControlSocket};
+use crate::event_loop::{EventLoop, StopSignals};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How often the pool checks on its VMs.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait before starting a VM again after one failed to start.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a VM's control socket may take to answer.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a pool holds VMs of.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// The snapshot's state file.
+    pub snapshot: PathBuf,
+    /// The snapshot's memory file.
+    pub memory: PathBuf,
+    /// How many VMs to keep ready.
+    pub size: usize,
+    /// Where the VMs' control sockets, consoles and logs go.
+    pub dir: PathBuf,
+    /// Audit log for the VMs to append to, if any.
+    pub audit_log: Option<PathBuf>,
+}
+
+/// A VM handed out by a pool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PooledVm {
+    pub id: u64,
+    pub pid: u32,
+    pub control_socket: PathBuf,
+}
+
+/// How well a pool has kept up.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolStats {
+    /// `take`s served by a ready VM.
+    pub hits: u64,
+    /// `take`s that found none ready.
+    pub misses: u64,
+    /// VMs that got ready.
+    pub warmed: u64,
+    /// Total time VMs took to get ready.
+    pub warmup_total: Duration,
+    /// Longest time a VM took to get ready.
+    pub warmup_max: Duration,
+}
+
+impl PoolStats {
+    /// The share of `take`s served, from 0 to 1 (1 before any).
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 1.0,
+            takes => self.hits as f64 / takes as f64,
+        }
+    }
+
+    /// Mean time a VM took to get ready.
+    pub fn warmup_mean(&self) -> Duration {
+        match self.warmed {
+            0 => Duration::ZERO,
+            warmed => self.warmup_total / warmed as u32,
+        }
+    }
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hits={} misses={} hit_rate={:.2} warmup_ms={} warmup_max_ms={}",
+            self.hits,
+            self.misses,
+            self.hit_rate(),
+            self.warmup_mean().as_millis(),
+            self.warmup_max.as_millis()
+        )
+    }
+}
+
+/// A VM process the pool started.
+struct Member {
+    id: u64,
+    child: Child,
+    control_socket: PathBuf,
+    started: Instant,
+}
+
+/// A pool of paused clones of one snapshot.
+pub struct Pool {
+    config: PoolConfig,
+    next_id: u64,
+    /// Started, not yet answering as paused.
+    warming: Vec<Member>,
+    /// Paused and ready to hand out, oldest first.
+    ready: VecDeque<Member>,
+    /// Handed out, reaped once they exit.
+    taken: Vec<Child>,
+    /// Don't start VMs before this, after one failed to start.
+    retry_at: Option<Instant>,
+    stats: PoolStats,
+}
+
+impl Pool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            next_id: 0,
+            warming: Vec::new(),
+            ready: VecDeque::new(),
+            taken: Vec::new(),
+            retry_at: None,
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// Check on the VMs: note those that got ready or exited, and start
+    /// more until the pool is full. Call this regularly.
+    pub fn poll(&mut self) -> io::Result<()> {
+        self.taken
+            .retain_mut(|child| !matches!(child.try_wait(), Ok(Some(_))));
+        self.ready
+            .retain_mut(|member| match member.child.try_wait() {
+                Ok(None) => true,
+                _ => {
+                    warn!("[pool] Ready VM {} exited", member.id);
+                    false
+                }
+            });
+
+        let mut index = 0;
+        while index < self.warming.len() {
+            let member = &mut self.warming[index];
+            if let Ok(Some(status)) = member.child.try_wait() {
+                warn!(
+                    "[pool] VM {} failed to start ({}); see {}",
+                    member.id,
+                    status,
+                    self.config.dir.join(format!("{}.log", member.id)).display()
+                );
+                self.warming.remove(index);
+                self.retry_at = Some(Instant::now() + RETRY_DELAY);
+                continue;
+            }
+            if control::send(&member.control_socket, "status", CONTROL_TIMEOUT)
+                .is_ok_and(|status| status == "paused")
+            {
+                let member = self.warming.remove(index);
+                let warmup = member.started.elapsed();
+                self.stats.warmed += 1;
+                self.stats.warmup_total += warmup;
+                self.stats.warmup_max = self.stats.warmup_max.max(warmup);
+                debug!("[pool] VM {} ready in {:?}", member.id, warmup);
+                self.ready.push_back(member);
+                continue;
+            }
+            index += 1;
+        }
+
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return Ok(());
+        }
+        self.retry_at = None;
+        while self.warming.len() + self.ready.len() < self.config.size {
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    /// Hand out a ready VM, resumed.
+    pub fn take(&mut self) -> Result<PooledVm, String> {
+        while let Some(mut member) = self.ready.pop_front() {
+            if let Err(e) = control::send(&member.control_socket, "resume", CONTROL_TIMEOUT) {
+                warn!("[pool] Failed to resume VM {}: {}", member.id, e);
+                stop(&mut member.child);
+                continue;
+            }
+            self.stats.hits += 1;
+            let vm = PooledVm {
+                id: member.id,
+                pid: member.child.id(),
+                control_socket: member.control_socket,
+            };
+            info!("[pool] Handed out VM {} (pid {})", vm.id, vm.pid);
+            self.taken.push(member.child);
+            return Ok(vm);
+        }
+        self.stats.misses += 1;
+        Err("no VM ready".into())
+    }
+
+    /// The pool's metrics.
+    pub fn stats(&self) -> PoolStats {
+        self.stats
+    }
+
+    /// Start a VM.
+    fn start(&mut self) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let dir = &self.config.dir;
+        let control_socket = dir.join(format!("{id}.sock"));
+        let console = File::create(dir.join(format!("{id}.console")))?;
+        let log = File::create(dir.join(format!("{id}.log")))?;
+        let mut command = Command::new(std::env::current_exe()?);
+        if let Some(path) = &self.config.audit_log {
+            command.arg("--audit-log").arg(path);
+        }
+        command
+            .arg("restore")
+            .arg("--snapshot")
+            .arg(&self.config.snapshot)
+            .arg("--memory")
+            .arg(&self.config.memory)
+            .args(["--clone", "--paused", "--control-socket"])
+            .arg(&control_socket)
+            .stdin(Stdio::null())
+            .stdout(console)
+            .stderr(log);
+        let child = command.spawn()?;
+        audit::record(
+            audit::Kind::Process,
+            "spawn",
+            &format!("pool VM {id} (pid {})", child.id()),
+        );
+        debug!("[pool] Starting VM {} (pid {})", id, child.id());
+        self.warming.push(Member {
+            id,
+            child,
+            control_socket,
+            started: Instant::now(),
+        });
+        Ok(())
+    }
+}
+
+impl Commands for Pool {
+    fn execute(&mut self, line: &str) -> Result<Option<String>, String> {
+        match line {
+            "take" => {
+                let vm = self.take()?;
+                Ok(Some(format!(
+                    "{} {} {}",
+                    vm.id,
+                    vm.pid,
+                    vm.control_socket.display()
+                )))
+            }
+            "stats" => Ok(Some(format!(
+                "size={} ready={} warming={} taken={} {}",
+                self.config.size,
+                self.ready.len(),
+                self.warming.len(),
+                self.stats.hits,
+                self.stats()
+            ))),
+            "" => Err("empty command".into()),
+            command => Err(format!("unknown command {command:?}")),
+        }
+    }
+}
+
+impl Drop for Pool {
+    /// Stop the VMs not handed out.
+    fn drop(&mut self) {
+        for member in self.warming.iter_mut().chain(self.ready.iter_mut()) {
+            stop(&mut member.child);
+        }
+    }
+}
+
+/// Stop a VM process the way a signal stops any VM, so it cleans up.
+fn stop(child: &mut Child) {
+    // SAFETY: kill has no memory effects; the child isn't reaped yet, so
+    // its PID is still its own.
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    let _ = child.wait();
+}
+
+/// What the pool's event loop waits for.
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    Signal,
+    Listener,
+    Client(RawFd),
+}
+
+/// Serve `pool` on the socket at `path` until a stop signal arrives.
+pub fn serve(pool: Pool, path: &Path) -> io::Result<()> {
+    let (mut socket, _guard) = ControlSocket::bind(path, pool)?;
+    let mut event_loop = EventLoop::new()?;
+    let signals = StopSignals::install()?;
+    event_loop.add(signals.as_raw_fd(), Event::Signal)?;
+    event_loop.add(socket.as_raw_fd(), Event::Listener)?;
+    loop {
+        socket.commands().poll()?;
+        for event in event_loop.wait(Some(POLL_INTERVAL))? {
+            match event {
+                Event::Signal => {
+                    if let Some(signal) = signals.take() {
+                        info!("[pool] Stopping on signal {}", signal);
+                        return Ok(());
+                    }
+                }
+                Event::Listener => match socket.accept() {
+                    Ok(fd) => event_loop.add(fd, Event::Client(fd))?,
+                    Err(e) => debug!("[pool] Failed to accept a client: {}", e),
+                },
+                Event::Client(fd) => {
+                    if !socket.serve(fd) {
+                        event_loop.remove(fd);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_pool() {
+        let mut pool = Pool::new(PoolConfig {
+            snapshot: "vm.json".into(),
+            memory: "vm.mem".into(),
+            size: 0,
+            dir: std::env::temp_dir(),
+            audit_log: None,
+        });
+        pool.poll().unwrap();
+        assert!(pool.take().is_err());
+        assert!(pool.execute("take").is_err());
+        assert!(pool.execute("drain").is_err());
+        let stats = pool.execute("stats").unwrap().unwrap();
+        assert_eq!(
+            stats,
+            "size=0 ready=0 warming=0 taken=0 hits=0 misses=2 hit_rate=0.00 warmup_ms=0 \
+             warmup_max_ms=0"
+        );
+        assert_eq!(pool.stats().hit_rate(), 0.0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = PoolStats {
+            hits: 3,
+            misses: 1,
+            warmed: 2,
+            warmup_total: Duration::from_millis(300),
+            warmup_max: Duration::from_millis(200),
+        };
+        assert_eq!(stats.hit_rate(), 0.75);
+        assert_eq!(stats.warmup_mean(), Duration::from_millis(150));
+        assert_eq!(PoolStats::default().hit_rate(), 1.0);
+    }
+}
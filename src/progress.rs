@@ -0,0 +1,113 @@
+//! Boot progress status line.
+//!
+//! On an interactive terminal `carbon` shows one line that follows the boot
+//! instead of the `[VMM]`/`[Boot]` log:
+//!
+//! ```text
+//! [3/6] loading kernel (14 ms)
+//! ```
+//!
+//! The line is erased as soon as the guest prints, redrawn below the console
+//! output while the kernel boots, and replaced by a one-line summary when init
+//! is reached. It is only enabled when stderr is a terminal and no log level
+//! was asked for; any explicit `--log-level`/`CARBON_LOG` (and non-interactive
+//! runs) get the plain log instead.
+
+use crate::logging::{self, Level};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Boot milestones, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Stage {
+    CreateVm,
+    Acpi,
+    LoadKernel,
+    StartVcpu,
+    KernelOutput,
+    Init,
+}
+
+const STAGES: u8 = Stage::Init as u8 + 1;
+
+impl Stage {
+    fn description(self) -> &'static str {
+        match self {
+            Stage::CreateVm => "creating VM",
+            Stage::Acpi => "building ACPI tables",
+            Stage::LoadKernel => "loading kernel",
+            Stage::StartVcpu => "starting vCPU",
+            Stage::KernelOutput => "kernel booting",
+            Stage::Init => "init reached",
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Furthest stage reached so far, plus one (0 = none).
+static REACHED: AtomicU8 = AtomicU8::new(0);
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Turn the status line on. Call only when stderr is a terminal.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Record that the boot reached `stage` and redraw the status line.
+///
+/// Stages only move forward; reporting an earlier stage again is a no-op.
+pub fn advance(stage: Stage) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let reached = stage as u8 + 1;
+    if REACHED.fetch_max(reached, Ordering::Relaxed) >= reached {
+        return;
+    }
+    let start = *START.get_or_init(Instant::now);
+
+    if stage == Stage::Init {
+        logging::set_status(None);
+        logging::write(
+            Level::Info,
+            format_args!("[VMM] Booted to init in {} ms", start.elapsed().as_millis()),
+        );
+        return;
+    }
+    logging::set_status(Some(render(stage, start.elapsed().as_millis())));
+}
+
+/// Remove the status line (e.g. when the guest stops before init).
+pub fn clear() {
+    if ENABLED.load(Ordering::Relaxed) {
+        logging::set_status(None);
+    }
+}
+
+fn render(stage: Stage, elapsed_ms: u128) -> String {
+    format!(
+        "[{}/{}] {} ({} ms)",
+        stage as u8 + 1,
+        STAGES,
+        stage.description(),
+        elapsed_ms
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render(Stage::CreateVm, 0), "[1/6] creating VM (0 ms)");
+        assert_eq!(
+            render(Stage::LoadKernel, 14),
+            "[3/6] loading kernel (14 ms)"
+        );
+    }
+}
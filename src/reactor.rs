@@ -0,0 +1,266 @@
+//! One thread serving the devices of many VMs.
+//!
+//! Run alone, a VM serves its devices from the thread running it, waiting on
+//! an event loop of its own (see [`crate::event_loop`]). With many VMs in a
+//! process, that is a thread each spending nearly all its time asleep. A
+//! [`Reactor`] instead waits on every VM's event loop from one thread (an
+//! epoll set is readable while any descriptor in it is) and serves whichever
+//! has events. The vCPUs keep their threads.
+//!
+//! A VM is put on a reactor with [`crate::VmmBuilder::reactor`]; its run
+//! then hands its event loop over and waits for the reactor to report why
+//! the VM stopped.
+
+use crate::event_loop::EventLoop;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+/// What a reactor serves: readable when it has events to serve.
+pub trait Source: AsRawFd + Send {
+    /// How long it may wait for events before it has work of its own,
+    /// `None` for as long as it takes.
+    fn timeout(&self) -> Option<Duration>;
+
+    /// Serve the events it has, and work that is due, without blocking.
+    /// Returns `true` once it is done, to be dropped.
+    fn poll(&mut self) -> bool;
+}
+
+/// A thread serving [`Source`]s, until dropped.
+pub struct Reactor {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// What the reactor's owner and thread share.
+struct Shared {
+    /// Signalled when sources are added, or the reactor is stopping.
+    wake: EventFd,
+    /// Sources added since the thread last woke.
+    added: Mutex<Vec<Box<dyn Source>>>,
+    stopping: AtomicBool,
+    /// How many sources the thread serves.
+    serving: Mutex<usize>,
+}
+
+impl Reactor {
+    pub fn new() -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            wake: EventFd::new(EFD_NONBLOCK)?,
+            added: Mutex::new(Vec::new()),
+            stopping: AtomicBool::new(false),
+            serving: Mutex::new(0),
+        });
+        let mut event_loop = EventLoop::new()?;
+        event_loop.add(shared.wake.as_raw_fd(), None)?;
+        let thread = thread::Builder::new().name("reactor".into()).spawn({
+            let shared = shared.clone();
+            move || serve(event_loop, &shared)
+        })?;
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Serve `source` until it is done.
+    pub fn add(&self, source: Box<dyn Source>) {
+        lock(&self.shared.added).push(source);
+        let _ = self.shared.wake.write(1);
+    }
+
+    /// How many sources are being served, or waiting to be.
+    pub fn len(&self) -> usize {
+        *lock(&self.shared.serving) + lock(&self.shared.added).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for Reactor {
+    /// Stop the thread, dropping the sources it serves.
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        let _ = self.shared.wake.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The reactor thread: wait on every source, tagged by its slot (`None`
+/// for the wake eventfd), and poll those with events or work due.
+fn serve(mut event_loop: EventLoop<Option<usize>>, shared: &Shared) {
+    let mut sources: Vec<Option<Box<dyn Source>>> = Vec::new();
+    loop {
+        let now = Instant::now();
+        let due: Vec<Option<Instant>> = sources
+            .iter()
+            .map(|source| source.as_ref()?.timeout().map(|timeout| now + timeout))
+            .collect();
+        let wait = due
+            .iter()
+            .flatten()
+            .min()
+            .map(|due| due.saturating_duration_since(now));
+        let ready = match event_loop.wait(wait) {
+            Ok(ready) => ready,
+            Err(e) => {
+                warn!("[reactor] Failed to wait for events: {}", e);
+                return;
+            }
+        };
+        if shared.stopping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut polled: Vec<usize> = ready.iter().copied().flatten().collect();
+        if ready.contains(&None) {
+            let _ = shared.wake.read();
+            for source in lock(&shared.added).drain(..) {
+                let slot = sources
+                    .iter()
+                    .position(Option::is_none)
+                    .unwrap_or(sources.len());
+                if let Err(e) = event_loop.add(source.as_raw_fd(), Some(slot)) {
+                    warn!("[reactor] Failed to watch a source: {}", e);
+                    continue;
+                }
+                if slot == sources.len() {
+                    sources.push(None);
+                }
+                // Polled once now, for work it may already have due
+                sources[slot] = Some(source);
+                polled.push(slot);
+            }
+        }
+        let now = Instant::now();
+        polled.extend(
+            due.iter()
+                .enumerate()
+                .filter(|(_, due)| due.is_some_and(|due| due <= now))
+                .map(|(slot, _)| slot),
+        );
+        polled.sort_unstable();
+        polled.dedup();
+        for slot in polled {
+            let Some(source) = &mut sources[slot] else {
+                continue;
+            };
+            if source.poll() {
+                event_loop.remove(source.as_raw_fd());
+                sources[slot] = None;
+            }
+        }
+        *lock(&shared.serving) = sources.iter().flatten().count();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::io::RawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc::{self, Sender};
+
+    /// Reports each byte read, and is done at end of input.
+    struct Bytes(UnixStream, Sender<u8>);
+
+    impl AsRawFd for Bytes {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    impl Source for Bytes {
+        fn timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        fn poll(&mut self) -> bool {
+            let mut byte = [0];
+            match self.0.read(&mut byte) {
+                Ok(1) => {
+                    self.1.send(byte[0]).unwrap();
+                    false
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                _ => true,
+            }
+        }
+    }
+
+    /// Done once polled after its deadline, without ever having events.
+    struct Timer(Instant, UnixStream, Sender<u8>);
+
+    impl AsRawFd for Timer {
+        fn as_raw_fd(&self) -> RawFd {
+            self.1.as_raw_fd()
+        }
+    }
+
+    impl Source for Timer {
+        fn timeout(&self) -> Option<Duration> {
+            Some(self.0.saturating_duration_since(Instant::now()))
+        }
+
+        fn poll(&mut self) -> bool {
+            let done = Instant::now() >= self.0;
+            if done {
+                self.2.send(0).unwrap();
+            }
+            done
+        }
+    }
+
+    /// The reactor thread counts its sources after polling them.
+    fn wait_for_len(reactor: &Reactor, len: usize) {
+        let start = Instant::now();
+        while reactor.len() != len {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_reactor() {
+        let reactor = Reactor::new().unwrap();
+        let (sent, received) = mpsc::channel();
+        let (a, mut a_peer) = UnixStream::pair().unwrap();
+        let (b, mut b_peer) = UnixStream::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+        reactor.add(Box::new(Bytes(a, sent.clone())));
+        reactor.add(Box::new(Bytes(b, sent.clone())));
+        a_peer.write_all(b"a").unwrap();
+        assert_eq!(received.recv().unwrap(), b'a');
+        b_peer.write_all(b"b").unwrap();
+        assert_eq!(received.recv().unwrap(), b'b');
+        wait_for_len(&reactor, 2);
+
+        // A source at end of input is done
+        drop(a_peer);
+        b_peer.write_all(b"c").unwrap();
+        assert_eq!(received.recv().unwrap(), b'c');
+        wait_for_len(&reactor, 1);
+
+        // A timeout polls a source without events
+        let (c, _c_peer) = UnixStream::pair().unwrap();
+        let deadline = Instant::now() + Duration::from_millis(20);
+        reactor.add(Box::new(Timer(deadline, c, sent)));
+        assert_eq!(received.recv().unwrap(), 0);
+        assert!(Instant::now() >= deadline);
+    }
+}
@@ -0,0 +1,312 @@
+//! Immutable base image plus scratch overlay (`--rootfs`).
+//!
+//! `--rootfs base.img` is shorthand for the usual agent setup: boot from a
+//! shared, read-only base image and send every write to a throwaway disk.
+//! Carbon attaches
+//!
+//! - `base.img` read-only as `/dev/vda`, and
+//! - a fresh, sparse overlay image as `/dev/vdb`, removed again on exit,
+//!
+//! and appends the kernel parameters that mount the overlay over the root:
+//!
+//! ```text
+//! root=/dev/vda ro overlayroot=device:dev=/dev/vdb,mkfs=1
+//! ```
+//!
+//! The kernel can't stack overlayfs over its root by itself, so the guest
+//! needs an initramfs with an `overlayroot` hook (e.g. Ubuntu's
+//! `overlayroot` package, or a custom one reading the same parameter). The
+//! overlay starts blank; `mkfs=1` asks the hook to format it. If the
+//! command line already names a `root=`, Carbon leaves the root parameters
+//! to it and only attaches the disks. A partitioned base image boots from
+//! its Linux partition instead (`root=/dev/vda1`, say; see
+//! [`crate::devices::virtio::probe`]).
+//!
+//! Without `--rootfs`, a command line with no `root=` boots from the first
+//! `--disk` holding a root filesystem (see [`infer_root`]), unless
+//! `--boot-profile custom` leaves the command line to the user.
+//!
+//! The overlay stays sparse as the guest uses it: zero writes, discards and
+//! write-zeroes punch holes rather than allocating space (see
+//! [`crate::devices::virtio::blk`]).
+
+use crate::audit;
+use crate::cleanup::{self, CleanupGuard};
+use crate::devices::virtio::probe::ImageFormat;
+use crate::digest::Sha256Digest;
+use crate::size;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Default size of the scratch overlay disk.
+pub const DEFAULT_OVERLAY_SIZE: u64 = size::GIB;
+
+/// A read-only base image with a scratch overlay.
+#[derive(Debug, Clone)]
+pub struct RootfsConfig {
+    /// Path to the base image.
+    pub base: String,
+    /// Size of the scratch overlay disk in bytes.
+    pub overlay_size: u64,
+    /// Prefetch the base image's boot profile.
+    pub prefetch: bool,
+    /// SHA-256 digest the base image must have, if pinned.
+    pub sha256: Option<Sha256Digest>,
+}
+
+/// Scratch overlay image, deleted when dropped (or on panic).
+pub struct Overlay {
+    path: PathBuf,
+    _cleanup: CleanupGuard,
+}
+
+static NEXT_OVERLAY: AtomicU32 = AtomicU32::new(0);
+
+impl Overlay {
+    /// Create an empty, sparse overlay image of `size` bytes in the temp dir.
+    pub fn create(size: u64) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "carbon-overlay-{}-{}.img",
+            std::process::id(),
+            NEXT_OVERLAY.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        audit::record(audit::Kind::Disk, "create", &path.display().to_string());
+        let remove_path = path.clone();
+        let cleanup = cleanup::register("remove rootfs overlay", move || {
+            let _ = std::fs::remove_file(&remove_path);
+            audit::record(
+                audit::Kind::Disk,
+                "remove",
+                &remove_path.display().to_string(),
+            );
+        });
+        file.set_len(size)?;
+        Ok(Self {
+            path,
+            _cleanup: cleanup,
+        })
+    }
+
+    /// Path of the overlay image.
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+}
+
+/// Guest device name of the `index`th virtio-blk disk (`vda`, `vdb`, ...).
+pub fn block_device(index: usize) -> String {
+    format!("/dev/vd{}", (b'a' + index as u8) as char)
+}
+
+/// Whether `cmdline` chooses a root (`root=`).
+pub fn chooses_root(cmdline: &str) -> bool {
+    cmdline.split_whitespace().any(|p| p.starts_with("root="))
+}
+
+/// Kernel parameters mounting `overlay` over a read-only root on `base`.
+///
+/// Returns nothing if `cmdline` already chooses a root.
+pub fn kernel_params(cmdline: &str, base: &str, overlay: &str) -> Vec<String> {
+    if chooses_root(cmdline) {
+        return Vec::new();
+    }
+    vec![
+        format!("root={base}"),
+        "ro".into(),
+        format!("overlayroot=device:dev={overlay},mkfs=1"),
+    ]
+}
+
+/// The root device on the `index`th disk, holding `format`: the disk
+/// itself, or its Linux partition.
+pub fn root_device(index: usize, format: Option<ImageFormat>) -> Result<String, String> {
+    let disk = block_device(index);
+    match format {
+        Some(ImageFormat::Partitioned {
+            root: Some(number), ..
+        }) => Ok(format!("{disk}{number}")),
+        Some(ImageFormat::Partitioned { table, root: None }) => Err(format!(
+            "base image is partitioned ({table}) but has no Linux partition to boot"
+        )),
+        Some(ImageFormat::Blank) => Err("base image is blank".into()),
+        _ => Ok(disk),
+    }
+}
+
+/// Kernel parameters booting from the first of `disks` that holds a root
+/// filesystem, given what each holds and whether it's read-only: `root=`,
+/// `rootfstype=` when the filesystem is known, and `rw` unless the disk or
+/// the filesystem is read-only. `None` if no disk has a root to boot.
+pub fn infer_root(disks: &[(Option<ImageFormat>, bool)]) -> Option<Vec<String>> {
+    disks
+        .iter()
+        .enumerate()
+        .find_map(|(index, &(format, read_only))| {
+            let (device, fstype, fs_read_only) = match format? {
+                ImageFormat::Filesystem(name) => {
+                    let (fstype, fs_read_only) = root_fstype(name)?;
+                    (block_device(index), Some(fstype), fs_read_only)
+                }
+                ImageFormat::Partitioned {
+                    root: Some(number), ..
+                } => (format!("{}{number}", block_device(index)), None, false),
+                _ => return None,
+            };
+            let mut params = vec![format!("root={device}")];
+            params.extend(fstype.map(|fstype| format!("rootfstype={fstype}")));
+            params.push(
+                if read_only || fs_read_only {
+                    "ro"
+                } else {
+                    "rw"
+                }
+                .into(),
+            );
+            Some(params)
+        })
+}
+
+/// The kernel's name for a root filesystem found by the probe, and whether
+/// it can only be mounted read-only. ext4 mounts ext2 and ext3 as well.
+fn root_fstype(name: &str) -> Option<(&'static str, bool)> {
+    match name {
+        "ext2" | "ext3" | "ext4" | "ext2/3/4" => Some(("ext4", false)),
+        "XFS" => Some(("xfs", false)),
+        "btrfs" => Some(("btrfs", false)),
+        "squashfs" => Some(("squashfs", true)),
+        "EROFS" => Some(("erofs", true)),
+        _ => None,
+    }
+}
+
+/// Why a `root=/dev/vdX[N]` on `cmdline` won't mount, given what each disk
+/// holds (`None` for vhost-user disks, which can't be probed).
+pub fn check_root(cmdline: &str, formats: &[Option<ImageFormat>]) -> Option<String> {
+    // The kernel takes the last root=
+    let root = cmdline
+        .split_whitespace()
+        .rev()
+        .find_map(|p| p.strip_prefix("root="))?;
+    let name = root.strip_prefix("/dev/vd")?;
+    let letter = *name.as_bytes().first()?;
+    let partition = &name[1..];
+    if !letter.is_ascii_lowercase() || !partition.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let index = (letter - b'a') as usize;
+    let disk = block_device(index);
+    let Some(format) = formats.get(index) else {
+        return Some(format!("root={root}, but {disk} isn't attached"));
+    };
+    match ((*format)?, partition.is_empty()) {
+        (ImageFormat::Blank, _) => Some(format!("root={root}, but {disk} is blank")),
+        (ImageFormat::Partitioned { table, root: part }, true) => Some(match part {
+            Some(n) => {
+                format!("root={root}, but {disk} is partitioned ({table}); Linux is on {disk}{n}")
+            }
+            None => format!("root={root}, but {disk} is partitioned ({table})"),
+        }),
+        (ImageFormat::Filesystem(name), false) => Some(format!(
+            "root={root}, but {disk} holds {name} with no partition table; use root={disk}"
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_params() {
+        assert_eq!(
+            kernel_params("console=ttyS0", &block_device(0), &block_device(1)),
+            vec![
+                "root=/dev/vda",
+                "ro",
+                "overlayroot=device:dev=/dev/vdb,mkfs=1"
+            ]
+        );
+        assert!(kernel_params("console=ttyS0 root=/dev/vdc", "/dev/vda", "/dev/vdb").is_empty());
+    }
+
+    #[test]
+    fn test_root_device() {
+        let gpt = |root| Some(ImageFormat::Partitioned { table: "GPT", root });
+        assert_eq!(root_device(1, gpt(Some(2))).unwrap(), "/dev/vdb2");
+        assert_eq!(
+            root_device(0, Some(ImageFormat::Filesystem("ext4"))).unwrap(),
+            "/dev/vda"
+        );
+        assert_eq!(root_device(0, None).unwrap(), "/dev/vda");
+        assert!(root_device(0, gpt(None)).is_err());
+        assert!(root_device(0, Some(ImageFormat::Blank)).is_err());
+
+        let formats = [
+            Some(ImageFormat::Filesystem("ext4")),
+            gpt(Some(2)),
+            Some(ImageFormat::Blank),
+            None,
+        ];
+        let check = |cmdline| check_root(cmdline, &formats);
+        assert_eq!(check("console=ttyS0"), None);
+        assert_eq!(check("root=/dev/vda"), None);
+        assert_eq!(check("root=/dev/vdb2"), None);
+        assert_eq!(check("root=/dev/vdd"), None);
+        assert_eq!(check("root=LABEL=rootfs"), None);
+        assert!(check("root=/dev/vda1")
+            .unwrap()
+            .contains("use root=/dev/vda"));
+        assert!(check("root=/dev/vdb").unwrap().contains("/dev/vdb2"));
+        assert!(check("root=/dev/vdc").unwrap().contains("blank"));
+        assert!(check("root=/dev/vde").unwrap().contains("isn't attached"));
+        assert_eq!(check("root=/dev/vdb root=/dev/vda"), None);
+    }
+
+    #[test]
+    fn test_infer_root() {
+        let ext4 = Some(ImageFormat::Filesystem("ext4"));
+        assert_eq!(
+            infer_root(&[(ext4, false)]).unwrap(),
+            ["root=/dev/vda", "rootfstype=ext4", "rw"]
+        );
+        assert_eq!(
+            infer_root(&[(ext4, true)]).unwrap(),
+            ["root=/dev/vda", "rootfstype=ext4", "ro"]
+        );
+        // Disks without a root are passed over
+        let gpt = Some(ImageFormat::Partitioned {
+            table: "GPT",
+            root: Some(2),
+        });
+        let disks = [
+            (None, false),
+            (Some(ImageFormat::Blank), false),
+            (Some(ImageFormat::Filesystem("swap")), false),
+            (gpt, false),
+        ];
+        assert_eq!(infer_root(&disks).unwrap(), ["root=/dev/vdd2", "rw"]);
+        assert_eq!(
+            infer_root(&[(Some(ImageFormat::Filesystem("squashfs")), false)]).unwrap(),
+            ["root=/dev/vda", "rootfstype=squashfs", "ro"]
+        );
+        assert_eq!(infer_root(&disks[..3]), None);
+        assert_eq!(infer_root(&[]), None);
+    }
+
+    #[test]
+    fn test_overlay_is_sparse_and_removed() {
+        let overlay = Overlay::create(64 * size::MIB).unwrap();
+        let path = PathBuf::from(overlay.path());
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.len(), 64 * size::MIB);
+        drop(overlay);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,115 @@
+//! Ephemeral in-memory scratch disk (`--scratch-disk`).
+//!
+//! `--scratch-disk size=2G` attaches a blank virtio-blk disk for throwaway
+//! space. It is backed by a memfd rather than an image file, so it is fast,
+//! nothing is written to the host filesystem, and it disappears with the
+//! process however the VM exits. It comes after every other disk (`/dev/vdb`
+//! behind a single `--disk`), and the guest has to format it before use.
+//!
+//! The memfd starts sparse: host memory is only used as the guest writes,
+//! and discards hand it back (see [`crate::devices::virtio::blk`]).
+
+use crate::audit;
+use crate::devices::Transport;
+use crate::size;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::str::FromStr;
+
+/// `--scratch-disk size=SIZE[,transport=pci]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScratchDiskConfig {
+    /// Size of the disk in bytes.
+    pub size: u64,
+    /// How the device is attached.
+    pub transport: Transport,
+}
+
+impl FromStr for ScratchDiskConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut size = None;
+        let mut transport = Transport::default();
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("size", value)) => {
+                    let bytes = size::parse_disk(value).map_err(|e| e.to_string())?;
+                    size = Some(bytes.bytes());
+                }
+                Some(("transport", value)) => transport = value.parse()?,
+                _ => return Err(format!("invalid scratch disk option {option:?}")),
+            }
+        }
+        match size {
+            Some(0) => Err("scratch disk size must be non-zero".into()),
+            Some(size) => Ok(Self { size, transport }),
+            None => Err("scratch disk needs size=SIZE".into()),
+        }
+    }
+}
+
+/// A sparse memfd holding a scratch disk's contents, freed when dropped.
+pub struct ScratchDisk {
+    file: File,
+}
+
+impl ScratchDisk {
+    /// Create an empty scratch disk of `size` bytes.
+    pub fn create(size: u64) -> io::Result<Self> {
+        // SAFETY: the name is a valid C string.
+        let fd = unsafe { libc::memfd_create(c"carbon-scratch".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just created and is owned by nothing else.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size)?;
+        let disk = Self { file };
+        audit::record(audit::Kind::Disk, "create", &disk.path());
+        Ok(disk)
+    }
+
+    /// A path the disk can be opened by while this process holds it.
+    pub fn path(&self) -> String {
+        format!("/proc/self/fd/{}", self.file.as_raw_fd())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn test_parse_scratch_disk() {
+        let config: ScratchDiskConfig = "size=2G".parse().unwrap();
+        assert_eq!(config.size, 2 * size::GIB);
+        assert_eq!(config.transport, Transport::Mmio);
+        let config: ScratchDiskConfig = "size=512,transport=pci".parse().unwrap();
+        assert_eq!(
+            (config.size, config.transport),
+            (512 * size::MIB, Transport::Pci)
+        );
+        assert!("".parse::<ScratchDiskConfig>().is_err());
+        assert!("size=0".parse::<ScratchDiskConfig>().is_err());
+        assert!("size=1000B".parse::<ScratchDiskConfig>().is_err());
+        assert!("size=1G,ro".parse::<ScratchDiskConfig>().is_err());
+    }
+
+    #[test]
+    fn test_scratch_disk() {
+        let disk = ScratchDisk::create(size::MIB).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(disk.path())
+            .unwrap();
+        assert_eq!(file.metadata().unwrap().len(), size::MIB);
+        file.write_all_at(b"scratch", 4096).unwrap();
+        let mut buf = [0u8; 7];
+        disk.file.read_exact_at(&mut buf, 4096).unwrap();
+        assert_eq!(&buf, b"scratch");
+    }
+}
@@ -0,0 +1,290 @@
+//! Human-friendly byte sizes.
+//!
+//! Sizes are written as a number with an optional unit suffix, e.g. `512M`,
+//! `2G`, `1.5GiB` or `10gb`. Units are case-insensitive and always binary
+//! (powers of 1024), matching how memory and disk sizes are used in practice:
+//!
+//! | Suffix              | Multiplier |
+//! | ------------------- | ---------- |
+//! | `B`                 | 1          |
+//! | `K`, `KB`, `KiB`    | 1024       |
+//! | `M`, `MB`, `MiB`    | 1024²      |
+//! | `G`, `GB`, `GiB`    | 1024³      |
+//! | `T`, `TB`, `TiB`    | 1024⁴      |
+//!
+//! A bare number uses a caller-chosen default unit, so `--memory 512` keeps
+//! meaning 512 MiB. Fractional values are allowed as long as they come out to
+//! a whole number of bytes (`1.5G` is fine, `0.3K` is not).
+
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+pub const KIB: u64 = 1024;
+pub const MIB: u64 = 1024 * KIB;
+pub const GIB: u64 = 1024 * MIB;
+pub const TIB: u64 = 1024 * GIB;
+
+/// Most digits allowed after the decimal point. Twelve is already well past
+/// a byte at every unit, and keeps the exact arithmetic inside a `u128`.
+const MAX_FRACTION_DIGITS: usize = 12;
+
+/// Errors from parsing a size string.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SizeError {
+    #[error("size is empty")]
+    Empty,
+
+    #[error("invalid number {0:?}")]
+    InvalidNumber(String),
+
+    #[error("unknown size unit {0:?} (expected B, K, M, G or T, optionally with B/iB)")]
+    UnknownUnit(String),
+
+    #[error("size {0:?} is too large")]
+    Overflow(String),
+
+    #[error("size {0:?} is not a whole number of bytes")]
+    FractionalBytes(String),
+
+    #[error("size {0:?} has more than {max} digits after the decimal point", max = MAX_FRACTION_DIGITS)]
+    TooPrecise(String),
+
+    #[error("size {value} is smaller than {min}")]
+    TooSmall { value: ByteSize, min: ByteSize },
+
+    #[error("size {value} is not a multiple of {align}")]
+    Misaligned { value: ByteSize, align: ByteSize },
+}
+
+/// A size in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    /// Size in bytes.
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+
+    /// Parse a size, interpreting a bare number as a multiple of `default_unit`.
+    pub fn parse_with_default_unit(s: &str, default_unit: u64) -> Result<Self, SizeError> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(SizeError::Empty);
+        }
+
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let multiplier = match unit.trim() {
+            "" => default_unit,
+            u => unit_multiplier(u).ok_or_else(|| SizeError::UnknownUnit(u.to_string()))?,
+        };
+
+        let (whole, frac) = number.split_once('.').unwrap_or((number, ""));
+        if whole.is_empty() && frac.is_empty() {
+            return Err(SizeError::InvalidNumber(number.to_string()));
+        }
+        let invalid = || SizeError::InvalidNumber(number.to_string());
+        let overflow = || SizeError::Overflow(s.to_string());
+
+        let whole: u64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| invalid())?
+        };
+        let mut bytes = whole.checked_mul(multiplier).ok_or_else(overflow)?;
+
+        if !frac.is_empty() {
+            if frac.contains('.') {
+                return Err(invalid());
+            }
+            if frac.len() > MAX_FRACTION_DIGITS {
+                return Err(SizeError::TooPrecise(s.to_string()));
+            }
+            // frac / 10^n * multiplier, in exact integer arithmetic
+            let digits: u128 = frac.parse().map_err(|_| invalid())?;
+            let scale = 10u128.checked_pow(frac.len() as u32).ok_or_else(invalid)?;
+            let scaled = digits
+                .checked_mul(multiplier as u128)
+                .ok_or_else(overflow)?;
+            if !scaled.is_multiple_of(scale) {
+                return Err(SizeError::FractionalBytes(s.to_string()));
+            }
+            let extra = u64::try_from(scaled / scale).map_err(|_| overflow())?;
+            bytes = bytes.checked_add(extra).ok_or_else(overflow)?;
+        }
+
+        Ok(Self(bytes))
+    }
+
+    /// Require the size to be a multiple of `align` bytes.
+    pub fn aligned_to(self, align: u64) -> Result<Self, SizeError> {
+        if !self.0.is_multiple_of(align) {
+            return Err(SizeError::Misaligned {
+                value: self,
+                align: ByteSize(align),
+            });
+        }
+        Ok(self)
+    }
+
+    /// Require the size to be at least `min` bytes.
+    pub fn at_least(self, min: u64) -> Result<Self, SizeError> {
+        if self.0 < min {
+            return Err(SizeError::TooSmall {
+                value: self,
+                min: ByteSize(min),
+            });
+        }
+        Ok(self)
+    }
+}
+
+/// Multiplier for a (case-insensitive) unit suffix.
+fn unit_multiplier(unit: &str) -> Option<u64> {
+    let unit = unit.to_ascii_lowercase();
+    let prefix = unit
+        .strip_suffix("ib")
+        .or_else(|| unit.strip_suffix('b'))
+        .unwrap_or(&unit);
+    match prefix {
+        "" => Some(1),
+        "k" => Some(KIB),
+        "m" => Some(MIB),
+        "g" => Some(GIB),
+        "t" => Some(TIB),
+        _ => None,
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = SizeError;
+
+    /// Parse a size; a bare number is bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_default_unit(s, 1)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    /// Format using the largest unit that represents the size exactly.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (unit, name) in [(TIB, "TiB"), (GIB, "GiB"), (MIB, "MiB"), (KIB, "KiB")] {
+            if self.0 >= unit && self.0.is_multiple_of(unit) {
+                return write!(f, "{} {}", self.0 / unit, name);
+            }
+        }
+        write!(f, "{} B", self.0)
+    }
+}
+
+/// Parse a guest memory size for clap: bare numbers are MiB, and the result
+/// must be a whole, non-zero number of pages (KVM maps guest RAM in 4 KiB
+/// pages).
+pub fn parse_memory(s: &str) -> Result<ByteSize, SizeError> {
+    ByteSize::parse_with_default_unit(s, MIB)?
+        .at_least(4 * KIB)?
+        .aligned_to(4 * KIB)
+}
+
+/// Parse a disk size for clap: bare numbers are MiB, and the result must be
+/// a whole number of 512-byte sectors.
+pub fn parse_disk(s: &str) -> Result<ByteSize, SizeError> {
+    ByteSize::parse_with_default_unit(s, MIB)?.aligned_to(512)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<u64, SizeError> {
+        s.parse::<ByteSize>().map(ByteSize::bytes)
+    }
+
+    #[test]
+    fn test_units() {
+        assert_eq!(parse("4096"), Ok(4096));
+        assert_eq!(parse("1b"), Ok(1));
+        assert_eq!(parse("2K"), Ok(2 * KIB));
+        assert_eq!(parse("512M"), Ok(512 * MIB));
+        assert_eq!(parse("2G"), Ok(2 * GIB));
+        assert_eq!(parse("10GiB"), Ok(10 * GIB));
+        assert_eq!(parse("10gb"), Ok(10 * GIB));
+        assert_eq!(parse("1 TiB"), Ok(TIB));
+    }
+
+    #[test]
+    fn test_fractions() {
+        assert_eq!(parse("1.5G"), Ok(GIB + 512 * MIB));
+        assert_eq!(parse("0.5K"), Ok(512));
+        assert_eq!(parse(".25M"), Ok(256 * KIB));
+        assert_eq!(parse("2."), Ok(2));
+        assert!(matches!(parse("0.3K"), Err(SizeError::FractionalBytes(_))));
+        assert_eq!(
+            parse("0.000000000001T"),
+            Err(SizeError::FractionalBytes("0.000000000001T".into()))
+        );
+        assert_eq!(
+            parse("1.0000000000000T"),
+            Err(SizeError::TooPrecise("1.0000000000000T".into()))
+        );
+        // Enough digits to overflow a u128 product without the limit
+        let long = format!("0.{}T", "9".repeat(38));
+        assert_eq!(parse(&long), Err(SizeError::TooPrecise(long.clone())));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(""), Err(SizeError::Empty));
+        assert!(matches!(parse("G"), Err(SizeError::InvalidNumber(_))));
+        assert!(matches!(parse("."), Err(SizeError::InvalidNumber(_))));
+        assert!(matches!(parse("1.2.3M"), Err(SizeError::InvalidNumber(_))));
+        assert!(matches!(parse("12X"), Err(SizeError::UnknownUnit(_))));
+        assert!(matches!(parse("-1G"), Err(SizeError::UnknownUnit(_))));
+        assert!(matches!(parse("99999999999T"), Err(SizeError::Overflow(_))));
+    }
+
+    #[test]
+    fn test_memory_defaults_to_mib() {
+        assert_eq!(parse_memory("512"), Ok(ByteSize(512 * MIB)));
+        assert_eq!(parse_memory("2G"), Ok(ByteSize(2 * GIB)));
+        assert!(matches!(
+            parse_memory("1000B"),
+            Err(SizeError::TooSmall { .. })
+        ));
+        assert!(matches!(
+            parse_memory("5000B"),
+            Err(SizeError::Misaligned { .. })
+        ));
+        assert_eq!(parse_memory("4K"), Ok(ByteSize(4 * KIB)));
+    }
+
+    #[test]
+    fn test_memory_rejects_empty() {
+        assert_eq!(
+            parse_memory("0"),
+            Err(SizeError::TooSmall {
+                value: ByteSize(0),
+                min: ByteSize(4 * KIB)
+            })
+        );
+        assert!(matches!(
+            parse_memory("0.0001K"),
+            Err(SizeError::FractionalBytes(_))
+        ));
+        assert!(matches!(
+            parse_memory("0.5K"),
+            Err(SizeError::TooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(ByteSize(512 * MIB).to_string(), "512 MiB");
+        assert_eq!(ByteSize(GIB + 512 * MIB).to_string(), "1536 MiB");
+        assert_eq!(ByteSize(1000).to_string(), "1000 B");
+    }
+}
@@ -0,0 +1,561 @@
+//! VM snapshots (`carbon snapshot`, or `snapshot STATE MEMORY` on the
+//! control socket; `carbon restore`, `carbon inspect`).
+//!
+//! A snapshot is two files: guest RAM, byte for byte, and a JSON state file
+//! with everything else needed to carry on where the VM stopped: its
+//! configuration, the VM's and each vCPU's KVM state, and the state of the
+//! emulated devices.
+//!
+//! ```text
+//! STATE (JSON)                              MEMORY
+//! ┌────────────────────────────────┐        ┌───────────────────┐
+//! │ version                        │        │ RAM region 0      │
+//! │ config   the VmConfig          │        ├───────────────────┤
+//! │ vm       clock, PICs, IOAPIC,  │        │ RAM region 1 ...  │
+//! │          PIT                   │        └───────────────────┘
+//! │ vcpus    registers, MSRs,      │        regions back to back, in
+//! │          LAPIC, events, ...    │        guest address order; pages
+//! │ devices  bus, base, state      │        that are all zero are holes
+//! └────────────────────────────────┘
+//! ```
+//!
+//! KVM structures are stored as the hex of their bytes: they only mean
+//! anything to the KVM that produced them, on the same kind of host.
+//!
+//! # Taking a Snapshot
+//!
+//! The VM is captured the way a core dump is: every vCPU stops at its next
+//! exit, finishing any I/O it was in, and waits while the VM and device
+//! state is saved and RAM written out; then the guest runs on. Devices
+//! serve requests as they arrive, so none are in flight to save.
+//!
+//! # Restoring
+//!
+//! `carbon restore --snapshot STATE --memory MEMORY` builds the VM from the
+//! recorded configuration, maps MEMORY as guest RAM, puts the state back
+//! and resumes the vCPUs. No kernel is loaded and nothing boots, so the
+//! guest is running again as soon as the VM is set up. MEMORY is mapped
+//! private: pages are read in as the guest touches them, and its writes
+//! never reach the file, so one snapshot can be restored any number of
+//! times, at once (see [`crate::clone`] for running clones of a VM).
+//!
+//! # Limits
+//!
+//! Snapshots cover the serial ports, the legacy PC devices and virtio-blk
+//! disks over MMIO. A VM with a device whose state lives outside Carbon, or
+//! that a restored VM couldn't get back, refuses to be snapshotted:
+//! vhost-user backends, vsock, virtio-fs and 9p, pmem, the TPM, device
+//! plugins, hot-plug slots, PCI transports, disks and keys passed as
+//! descriptors, and the throwaway disks of `--rootfs` and `--scratch-disk`.
+//!
+//! Disk images are reopened by path, and must be as they were when the
+//! snapshot was taken; a restored VM writes to them, unless restored with
+//! `--clone`. The guest has already probed its CPU, so a snapshot
+//! restores on a host with the same CPU features (see `--cpu portable`).
+
+use crate::audit;
+use crate::boot::GuestMemory;
+use crate::coredump;
+use crate::kvm::{ClockState, NestedState, VcpuState, VmState};
+use crate::size::ByteSize;
+use crate::vmm::VmConfig;
+use kvm_bindings::{
+    kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_msr_entry, kvm_pit_state2, kvm_regs, kvm_sregs,
+    kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+
+/// Version of the state file format.
+const VERSION: u32 = 1;
+
+/// A device's state, in whatever form the device describes it.
+pub type DeviceState = serde_json::Value;
+
+/// Which bus a device sits on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    Pio,
+    Mmio,
+}
+
+/// A device's state, and where to find the device again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedDevice {
+    pub bus: Bus,
+    /// First port or address of the device's range.
+    pub base: u64,
+    pub state: DeviceState,
+}
+
+/// A snapshot's state file.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    /// The VM the snapshot was taken of.
+    pub config: VmConfig,
+    vm: SavedVm,
+    vcpus: Vec<SavedVcpu>,
+    /// The state of each device that has any.
+    pub devices: Vec<SavedDevice>,
+}
+
+/// [`VmState`], as stored.
+#[derive(Serialize, Deserialize)]
+struct SavedVm {
+    clock: ClockState,
+    pic_master: Blob,
+    pic_slave: Blob,
+    ioapic: Blob,
+    pit: Blob,
+}
+
+/// [`VcpuState`], as stored.
+#[derive(Serialize, Deserialize)]
+struct SavedVcpu {
+    regs: Blob,
+    sregs: Blob,
+    xsave: Blob,
+    xcrs: Blob,
+    lapic: Blob,
+    events: Blob,
+    mp_state: Blob,
+    /// Index and value of each saved MSR.
+    msrs: Vec<(u32, u64)>,
+    nested: Option<Blob>,
+}
+
+impl Snapshot {
+    /// Describe the VM `config` built, from the state captured with its
+    /// vCPUs stopped: `vcpus` by index.
+    pub fn new<'a>(
+        config: VmConfig,
+        vm: &VmState,
+        vcpus: impl IntoIterator<Item = &'a VcpuState>,
+        devices: Vec<SavedDevice>,
+    ) -> Self {
+        let [pic_master, pic_slave, ioapic] = &vm.irqchips;
+        Self {
+            version: VERSION,
+            config,
+            vm: SavedVm {
+                clock: vm.clock,
+                pic_master: Blob::of(pic_master),
+                pic_slave: Blob::of(pic_slave),
+                ioapic: Blob::of(ioapic),
+                pit: Blob::of(&vm.pit),
+            },
+            vcpus: vcpus
+                .into_iter()
+                .map(|state| SavedVcpu {
+                    regs: Blob::of(&state.regs),
+                    sregs: Blob::of(&state.sregs),
+                    xsave: Blob::of(&state.xsave),
+                    xcrs: Blob::of(&state.xcrs),
+                    lapic: Blob::of(&state.lapic),
+                    events: Blob::of(&state.events),
+                    mp_state: Blob::of(&state.mp_state),
+                    msrs: state.msrs.iter().map(|m| (m.index, m.data)).collect(),
+                    nested: state
+                        .nested
+                        .as_ref()
+                        .map(|nested| Blob(nested.as_bytes().to_vec())),
+                })
+                .collect(),
+            devices,
+        }
+    }
+
+    /// The VM state to restore.
+    pub fn vm_state(&self) -> Result<VmState, String> {
+        Ok(VmState {
+            clock: self.vm.clock,
+            irqchips: [
+                self.vm.pic_master.to("PIC master")?,
+                self.vm.pic_slave.to("PIC slave")?,
+                self.vm.ioapic.to("IOAPIC")?,
+            ],
+            pit: self.vm.pit.to("PIT")?,
+        })
+    }
+
+    /// The state to restore each vCPU to, by index.
+    pub fn vcpu_states(&self) -> Result<Vec<VcpuState>, String> {
+        self.vcpus
+            .iter()
+            .enumerate()
+            .map(|(index, saved)| {
+                let field = |name: &str| format!("vCPU {index} {name}");
+                let nested = match &saved.nested {
+                    Some(blob) => Some(
+                        NestedState::from_bytes(blob.0.clone())
+                            .ok_or_else(|| format!("{} is truncated", field("nested state")))?,
+                    ),
+                    None => None,
+                };
+                Ok(VcpuState {
+                    regs: saved.regs.to(&field("registers"))?,
+                    sregs: saved.sregs.to(&field("special registers"))?,
+                    xsave: saved.xsave.to(&field("XSAVE"))?,
+                    xcrs: saved.xcrs.to(&field("XCRs"))?,
+                    lapic: saved.lapic.to(&field("LAPIC"))?,
+                    events: saved.events.to(&field("events"))?,
+                    mp_state: saved.mp_state.to(&field("MP state"))?,
+                    msrs: saved
+                        .msrs
+                        .iter()
+                        .map(|&(index, data)| kvm_msr_entry {
+                            index,
+                            data,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    nested,
+                })
+            })
+            .collect()
+    }
+
+    /// Write guest RAM to `memory_path`, then this to `path`: a state file
+    /// is only there once its memory is complete.
+    pub fn write(&self, path: &Path, memory: &GuestMemory, memory_path: &Path) -> io::Result<()> {
+        let mut file = File::create(memory_path)?;
+        audit::record(
+            audit::Kind::File,
+            "create",
+            &memory_path.display().to_string(),
+        );
+        let end = coredump::write_ram(&mut file, memory, 0)?;
+        file.set_len(end)?;
+        file.sync_all()?;
+
+        let mut file = File::create(path)?;
+        audit::record(audit::Kind::File, "create", &path.display().to_string());
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()
+    }
+
+    /// Read the state file at `path`.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        audit::record(audit::Kind::File, "open", &path.display().to_string());
+        let snapshot: Self = serde_json::from_reader(BufReader::new(file))?;
+        if snapshot.version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "snapshot format version {} isn't supported (expected {})",
+                    snapshot.version, VERSION
+                ),
+            ));
+        }
+        if snapshot.vcpus.len() != snapshot.config.topology.cpus() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "snapshot has {} vCPUs, but its configuration {}",
+                    snapshot.vcpus.len(),
+                    snapshot.config.topology.cpus()
+                ),
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// What `carbon inspect` prints: the VM a snapshot holds, one field a line.
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = &self.config;
+        writeln!(f, "format version: {}", self.version)?;
+        writeln!(f, "kernel: {}", config.kernel_path)?;
+        writeln!(f, "cmdline: {}", config.cmdline)?;
+        writeln!(f, "memory: {}", ByteSize(config.mem_size))?;
+        writeln!(f, "vcpus: {} ({})", config.topology.cpus(), config.topology)?;
+        writeln!(f, "cpu mode: {:?}", config.cpu_mode)?;
+        if !config.cpu_features.is_empty() {
+            writeln!(f, "cpu features: {}", config.cpu_features)?;
+        }
+        for disk in &config.disks {
+            let access = if disk.options.read_only {
+                "read-only"
+            } else {
+                "read-write"
+            };
+            writeln!(f, "disk: {} ({access})", disk.path)?;
+        }
+        writeln!(f, "devices: {} with saved state", self.devices.len())?;
+        writeln!(f, "guest clock: {:.3} s", self.vm.clock.nanos as f64 / 1e9)
+    }
+}
+
+/// KVM structures that are plain bytes.
+///
+/// # Safety
+///
+/// Implementors are `repr(C)`, hold no pointers, and any bytes are a valid
+/// value. Their padding is spelled out as fields, so every byte is
+/// initialized.
+unsafe trait Pod: Default {}
+
+// SAFETY: bindgen-generated `repr(C)` mirrors of the kernel's structures,
+// made of integers and arrays of them.
+unsafe impl Pod for kvm_regs {}
+unsafe impl Pod for kvm_sregs {}
+unsafe impl Pod for kvm_xsave {}
+unsafe impl Pod for kvm_xcrs {}
+unsafe impl Pod for kvm_lapic_state {}
+unsafe impl Pod for kvm_vcpu_events {}
+unsafe impl Pod for kvm_mp_state {}
+unsafe impl Pod for kvm_irqchip {}
+unsafe impl Pod for kvm_pit_state2 {}
+
+/// The bytes of a KVM structure, stored as hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Blob(Vec<u8>);
+
+impl Blob {
+    fn of<T: Pod>(value: &T) -> Self {
+        // SAFETY: `T` is plain bytes (see `Pod`), all of them initialized.
+        let bytes =
+            unsafe { std::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) };
+        Self(bytes.to_vec())
+    }
+
+    /// The structure, named `what` in errors.
+    fn to<T: Pod>(&self, what: &str) -> Result<T, String> {
+        if self.0.len() != size_of::<T>() {
+            return Err(format!(
+                "{what} is {} bytes, not {}",
+                self.0.len(),
+                size_of::<T>()
+            ));
+        }
+        let mut value = T::default();
+        // SAFETY: the sizes match, and any bytes are a valid `T` (see `Pod`).
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.0.as_ptr(),
+                (&mut value as *mut T).cast::<u8>(),
+                self.0.len(),
+            );
+        }
+        Ok(value)
+    }
+}
+
+impl Serialize for Blob {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = self.0.iter().map(|byte| format!("{byte:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+}
+
+impl<'de> Deserialize<'de> for Blob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(de::Error::custom("expected an even number of hex digits"));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            // Checked above: two hex digits
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        Ok(Self(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::MemoryBacking;
+    use crate::kvm::Topology;
+
+    fn vcpu_state(rip: u64) -> VcpuState {
+        VcpuState {
+            regs: kvm_regs {
+                rip,
+                rsp: 0x8000,
+                ..Default::default()
+            },
+            sregs: kvm_sregs::default(),
+            xsave: kvm_xsave::default(),
+            xcrs: kvm_xcrs::default(),
+            lapic: kvm_lapic_state::default(),
+            events: kvm_vcpu_events::default(),
+            mp_state: kvm_mp_state { mp_state: 3 },
+            msrs: vec![kvm_msr_entry {
+                index: 0x10,
+                data: 12345,
+                ..Default::default()
+            }],
+            nested: None,
+        }
+    }
+
+    fn vm_state() -> VmState {
+        let mut pit = kvm_pit_state2::default();
+        pit.channels[0].count = 0x1234;
+        VmState {
+            clock: ClockState {
+                nanos: 5_000_000_000,
+                tsc_stable: true,
+            },
+            irqchips: [0, 1, 2].map(|chip_id| kvm_irqchip {
+                chip_id,
+                ..Default::default()
+            }),
+            pit,
+        }
+    }
+
+    fn config() -> VmConfig {
+        VmConfig {
+            kernel_path: "vmlinux".into(),
+            kernel_sha256: None,
+            cmdline: "console=ttyS0".into(),
+            boot_profile: Default::default(),
+            mem_size: 4 << 20,
+            memory_backing: MemoryBacking::default(),
+            prefault: false,
+            swap_policy: Default::default(),
+            initrd: None,
+            kaslr: false,
+            disks: Vec::new(),
+            rootfs: None,
+            scratch_disk: None,
+            cpu_mode: Default::default(),
+            cpu_features: Default::default(),
+            topology: Topology::flat(2),
+            cpu_affinity: Default::default(),
+            hyperv: false,
+            nested: false,
+            exit_on_halt: false,
+            rtc: Default::default(),
+            device_plugins: Vec::new(),
+            vsock: None,
+            shared_dirs: Vec::new(),
+            p9_shares: Vec::new(),
+            pmem: None,
+            debug_exit: None,
+            serial: Default::default(),
+            serial2: None,
+            fw_cfg: Vec::new(),
+            tpm: None,
+            control_socket: None,
+            dump_core_on_fault: None,
+            hotplug_disks: 0,
+        }
+    }
+
+    #[test]
+    fn test_blob() {
+        let regs = kvm_regs {
+            rax: 0x1122_3344,
+            ..Default::default()
+        };
+        let blob = Blob::of(&regs);
+        assert_eq!(blob.0.len(), size_of::<kvm_regs>());
+        assert_eq!(blob.to::<kvm_regs>("regs").unwrap(), regs);
+
+        let json = serde_json::to_string(&blob).unwrap();
+        assert!(json.starts_with("\"44332211"));
+        assert_eq!(serde_json::from_str::<Blob>(&json).unwrap(), blob);
+
+        // The wrong size for the structure, or not hex
+        let err = Blob(vec![0; 8]).to::<kvm_regs>("regs").unwrap_err();
+        assert!(err.contains("8 bytes"), "{err}");
+        assert!(serde_json::from_str::<Blob>("\"abc\"").is_err());
+        assert!(serde_json::from_str::<Blob>("\"zz\"").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("carbon-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (state_path, memory_path) = (dir.join("state.json"), dir.join("mem.bin"));
+
+        let memory = GuestMemory::new(4 << 20).unwrap();
+        memory.write(0x1000, b"guest data").unwrap();
+        let device = SavedDevice {
+            bus: Bus::Mmio,
+            base: 0xd000_0000,
+            state: serde_json::json!({ "status": 15 }),
+        };
+        let snapshot = Snapshot::new(
+            config(),
+            &vm_state(),
+            &[vcpu_state(0x1000), vcpu_state(0x2000)],
+            vec![device.clone()],
+        );
+        snapshot.write(&state_path, &memory, &memory_path).unwrap();
+
+        let read = Snapshot::read(&state_path).unwrap();
+        assert_eq!(read.config.mem_size, 4 << 20);
+        assert_eq!(read.devices, vec![device]);
+        let vm = read.vm_state().unwrap();
+        assert_eq!(vm.clock, vm_state().clock);
+        assert_eq!(vm.pit, vm_state().pit);
+        assert_eq!(vm.irqchips.map(|chip| chip.chip_id), [0, 1, 2]);
+        let vcpus = read.vcpu_states().unwrap();
+        assert_eq!(vcpus.len(), 2);
+        assert_eq!(vcpus[1].regs.rip, 0x2000);
+        assert_eq!(vcpus[0].mp_state.mp_state, 3);
+        assert_eq!(
+            (vcpus[0].msrs[0].index, vcpus[0].msrs[0].data),
+            (0x10, 12345)
+        );
+
+        // The memory file maps back as guest RAM
+        assert_eq!(
+            std::fs::metadata(&memory_path).unwrap().len(),
+            memory.size()
+        );
+        let backing = MemoryBacking::File {
+            path: memory_path.clone(),
+            shared: false,
+        };
+        let restored = GuestMemory::with_backing(memory.size(), &backing).unwrap();
+        let mut data = [0u8; 10];
+        restored.read(0x1000, &mut data).unwrap();
+        assert_eq!(&data, b"guest data");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_display() {
+        let vcpus = [vcpu_state(0), vcpu_state(0)];
+        let snapshot = Snapshot::new(config(), &vm_state(), &vcpus, Vec::new());
+        let summary = snapshot.to_string();
+        assert!(summary.contains("kernel: vmlinux\n"), "{summary}");
+        assert!(summary.contains("memory: 4 MiB\n"), "{summary}");
+        assert!(summary.contains("vcpus: 2 ("), "{summary}");
+        assert!(
+            summary.contains("devices: 0 with saved state\n"),
+            "{summary}"
+        );
+        assert!(summary.ends_with("guest clock: 5.000 s\n"), "{summary}");
+    }
+
+    #[test]
+    fn test_read_checks_version() {
+        let path = std::env::temp_dir().join(format!(
+            "carbon-snapshot-version-{}.json",
+            std::process::id()
+        ));
+        let vcpus = [vcpu_state(0), vcpu_state(0)];
+        let mut snapshot = Snapshot::new(config(), &vm_state(), &vcpus, Vec::new());
+        snapshot.version = VERSION + 1;
+        std::fs::write(&path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+        let err = Snapshot::read(&path).err().unwrap();
+        assert!(err.to_string().contains("version"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,3087 @@
+//! VM construction and the vCPU run loop.
+//!
+//! This module ties the boot, KVM and device modules together: it creates the
+//! VM, loads the kernel (or restores a snapshot, see [`crate::snapshot`]),
+//! registers devices and runs the vCPUs until the guest stops (or until a
+//! caller-supplied stop condition is met).
+//!
+//! # Threads
+//!
+//! Each vCPU runs on a thread of its own, handling the port and MMIO exits
+//! that stop it. The main thread waits on an [`EventLoop`] for the rest:
+//!
+//! - a vCPU stopping, which stops the VM;
+//! - QUEUE_NOTIFY writes to the virtio devices on the MMIO bus, which KVM
+//!   signals on ioeventfds rather than exiting, so a vCPU notifying a queue
+//!   goes straight back to the guest while the main thread serves it;
+//! - console input, fed to the serial ports;
+//! - control socket clients (see [`crate::control`]);
+//! - SIGINT, SIGTERM and SIGHUP, which stop the VM as any other stop does.
+//!
+//! Device state is shared between the threads behind one mutex.
+//!
+//! # Boot Phases
+//!
+//! The run loop records timestamps for the major boot milestones so callers
+//! (e.g. `carbon bench`) can measure boot latency:
+//!
+//! ```text
+//! VMM start ──► kernel start ──► init reached
+//!               (first vCPU      (console prints
+//!                entry)           "... as init process")
+//! ```
+//!
+//! "Init reached" is detected by watching the serial console for a marker
+//! string. The kernel prints `Run /sbin/init as init process` right before it
+//! execs userspace, which makes a robust, kernel-version-independent marker.
+//!
+//! # Exit Status
+//!
+//! The guest reports how its workload went by printing a line of its own on
+//! COM1, `CARBON_EXIT_STATUS=N` with N from 0 to 255, e.g. from init:
+//!
+//! ```text
+//! run-workload; echo "CARBON_EXIT_STATUS=$?" > /dev/ttyS0
+//! ```
+//!
+//! The VM stops as soon as the line is complete, with
+//! [`StopReason::ExitStatus`], and `carbon` exits with N. Only a whole line
+//! counts, so a shell tracing the `echo` doesn't stop the VM early.
+
+use crate::boot::{
+    self, BootConfig, GuestMemory, MemoryBacking, PciHostConfig, SwapPolicy, TpmConfig,
+    VirtioDeviceConfig,
+};
+use crate::control::{ControlSocket, Controls};
+use crate::coredump;
+use crate::devices::virtio::crypt::KeySource;
+use crate::devices::virtio::hotplug::DiskSlots;
+use crate::devices::virtio::rate_limiter::{RateLimit, RetryTimer};
+use crate::devices::{
+    self as devices, plugin, Cmos, ConsoleBackend, ConsoleConfig, ConsoleInput, DebugExit,
+    DiskOptions, FwCfg, FwCfgItem, MmioBus, MmioDevice, P9Share, PanicEvent, PciBus, PioBus,
+    Plugin, PmemConfig, Pvpanic, ResetControl, RtcClock, Serial, SerialInput, SharedDirConfig,
+    TpmCrb, Transport, VhostUserDevice, Virtio9p, VirtioBlk, VirtioPci, VirtioPmem, VirtioVsock,
+    VsockConfig, CMOS_PORT_INDEX, FW_CFG_PORT_BASE, FW_CFG_PORT_COUNT, I8042, I8042_DATA_PORT,
+    I8042_PORT_COUNT, PCI_ECAM_BASE, PCI_ECAM_SIZE, PCI_MMIO_BASE, PCI_MMIO_SIZE, PVPANIC_PORT,
+    RESET_CONTROL_PORT, RX_RETRY_INTERVAL, SERIAL_COM1_BASE, SERIAL_COM1_END, SERIAL_COM1_IRQ,
+    SERIAL_COM2_BASE, SERIAL_COM2_END, SERIAL_COM2_IRQ, TPM_CRB_BASE, TPM_CRB_CONTROL_AREA,
+    TPM_CRB_SIZE, VIRTIO_9P_SLOTS, VIRTIO_BLK_SLOTS, VIRTIO_FS_SLOTS, VIRTIO_MMIO_SIZE,
+    VIRTIO_PMEM_SLOT, VIRTIO_VSOCK_SLOT,
+};
+use crate::digest::Sha256Digest;
+use crate::error::CarbonError;
+use crate::event_loop::{EventLoop, StopSignals};
+use crate::events::{Event as VmEvent, Events};
+use crate::hibernate::{Hibernate, IdleMonitor};
+use crate::kvm::{
+    self, CpuAffinity, CpuFeatures, CpuMode, IoData, IoHandler, IrqLine, MmioHandler, StatsReader,
+    Topology, VcpuExit, VcpuState, VcpuStats,
+};
+use crate::progress::{self, Stage};
+use crate::reactor::{Reactor, Source};
+use crate::rootfs::{self, Overlay, RootfsConfig};
+use crate::scratch::{ScratchDisk, ScratchDiskConfig};
+use crate::size::ByteSize;
+use crate::snapshot::{Bus, SavedDevice, Snapshot};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+/// Default kernel command line.
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0";
+
+/// Console marker printed by the kernel right before it execs init.
+pub const DEFAULT_INIT_MARKER: &str = "as init process";
+
+/// Start of the console line a guest prints to report its workload's exit
+/// status, e.g. `CARBON_EXIT_STATUS=3` (see the [module docs](self)).
+pub const EXIT_STATUS_MARKER: &str = "CARBON_EXIT_STATUS=";
+
+/// Longest console line checked for an exit status.
+const MAX_EXIT_STATUS_LINE: usize = 64;
+
+/// How often a stopping VM re-signals vCPUs still in the guest.
+const KICK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How long a core dump or snapshot waits for the vCPUs to stop.
+const DUMP_PAUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often `exit_on_halt` kicks the vCPUs to check whether the guest
+/// has halted for good.
+const HALT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Which kernel parameters the VMM appends to the user's command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, Serialize, Deserialize)]
+pub enum BootProfile {
+    /// `reboot=k panic=-1 noapictimer`: reboot through the keyboard
+    /// controller (see `devices::reset`), reboot on panic, and skip the
+    /// local APIC timer for a faster boot.
+    #[default]
+    Fast,
+    /// `reboot=k panic=-1`: keep the local APIC timer, for kernels or
+    /// workloads that need it.
+    Compat,
+    /// Nothing: the command line is exactly the user's, bar what other
+    /// options need (`root=` for a rootfs, `nokaslr`). The other profiles
+    /// also add `console=ttyS0` and a `root=` for the first disk holding a
+    /// root filesystem when the command line has none.
+    Custom,
+}
+
+impl BootProfile {
+    /// The parameters appended to the command line.
+    pub fn params(self) -> &'static [&'static str] {
+        match self {
+            BootProfile::Fast => &["reboot=k", "panic=-1", "noapictimer"],
+            BootProfile::Compat => &["reboot=k", "panic=-1"],
+            BootProfile::Custom => &[],
+        }
+    }
+}
+
+/// Static description of a VM to boot.
+///
+/// A snapshot (see `snapshot`) records it, bar the devices snapshots don't
+/// cover and the paths only the process that took it serves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmConfig {
+    /// Path to the kernel bzImage.
+    pub kernel_path: String,
+    /// SHA-256 digest the kernel must have, if pinned (see `digest`).
+    pub kernel_sha256: Option<Sha256Digest>,
+    /// User-supplied kernel command line (`boot_profile` flags are appended).
+    pub cmdline: String,
+    /// Kernel parameters appended to `cmdline`.
+    pub boot_profile: BootProfile,
+    /// Guest memory size in bytes.
+    pub mem_size: u64,
+    /// What backs guest memory.
+    pub memory_backing: MemoryBacking,
+    /// Fault in all guest memory before boot.
+    pub prefault: bool,
+    /// Whether the host may swap guest memory out.
+    pub swap_policy: SwapPolicy,
+    /// Optional initrd/initramfs image.
+    pub initrd: Option<String>,
+    /// Randomize the kernel's address (KASLR); off adds `nokaslr` to the
+    /// command line.
+    pub kaslr: bool,
+    /// Raw disk images exposed as virtio-blk, in guest device order.
+    pub disks: Vec<DiskConfig>,
+    /// Read-only base image plus scratch overlay, attached after `disks`.
+    #[serde(skip)]
+    pub rootfs: Option<RootfsConfig>,
+    /// Memory-backed scratch disk, attached after the rootfs disks.
+    #[serde(skip)]
+    pub scratch_disk: Option<ScratchDiskConfig>,
+    /// CPUID policy for the guest vCPUs.
+    pub cpu_mode: CpuMode,
+    /// Features exposed or hidden on top of `cpu_mode`.
+    pub cpu_features: CpuFeatures,
+    /// vCPU count and their arrangement into sockets, cores and threads.
+    pub topology: Topology,
+    /// Host cores each vCPU thread is pinned to (see `kvm::CpuAffinity`).
+    pub cpu_affinity: CpuAffinity,
+    /// Expose the Hyper-V enlightenments KVM supports.
+    pub hyperv: bool,
+    /// Expose VMX/SVM so the guest can run a hypervisor of its own.
+    pub nested: bool,
+    /// Stop once every vCPU has halted with interrupts disabled, rather
+    /// than leave the guest idle (see `VcpuFd::is_parked`).
+    pub exit_on_halt: bool,
+    /// Time source for the CMOS RTC.
+    pub rtc: RtcClock,
+    /// Device plugin executables (see `devices::plugin`).
+    #[serde(skip)]
+    pub device_plugins: Vec<String>,
+    /// virtio-vsock device, if any.
+    #[serde(skip)]
+    pub vsock: Option<VsockConfig>,
+    /// Host directories shared over virtio-fs.
+    #[serde(skip)]
+    pub shared_dirs: Vec<SharedDirConfig>,
+    /// Host directories served over virtio-9p.
+    #[serde(skip)]
+    pub p9_shares: Vec<P9Share>,
+    /// Image mapped into guest memory over virtio-pmem, if any.
+    #[serde(skip)]
+    pub pmem: Option<PmemConfig>,
+    /// I/O port of the debug exit device, if any.
+    pub debug_exit: Option<u16>,
+    /// Console backend of the first serial port (COM1).
+    pub serial: ConsoleConfig,
+    /// Console backend of the second serial port (COM2), if attached.
+    pub serial2: Option<ConsoleConfig>,
+    /// Blobs passed to the guest over fw_cfg.
+    pub fw_cfg: Vec<FwCfgItem>,
+    /// swtpm data socket backing the guest's TPM, if any.
+    #[serde(skip)]
+    pub tpm: Option<PathBuf>,
+    /// Where to serve runtime control commands (see `control`), if anywhere.
+    #[serde(skip)]
+    pub control_socket: Option<PathBuf>,
+    /// Where to write a core dump (see `coredump`) if the guest
+    /// triple-faults, if anywhere.
+    #[serde(skip)]
+    pub dump_core_on_fault: Option<PathBuf>,
+    /// virtio-blk slots reserved for hot-plugged disks (see
+    /// `devices::virtio::hotplug`), after every boot disk.
+    #[serde(skip)]
+    pub hotplug_disks: usize,
+}
+
+/// A disk attached as virtio-blk: `--disk IMAGE`, `--disk path=IMAGE[,ro]
+/// [,logical-block-size=N][,physical-block-size=N][,direct=on|off]
+/// [,cache=writeback|writethrough|none][,exclusive=on|off]
+/// [,key-file=PATH|key-fd=N|key-command=PROGRAM][,sha256=DIGEST][,iops=N]
+/// [,bw=SIZE]...`,
+/// `--disk fd=N[,...]` for an image the parent process already opened as
+/// descriptor N (same options, bar `exclusive`),
+/// or `--disk vhost-user=SOCKET` for a disk served by a vhost-user-blk
+/// backend. All but the first take `transport=pci` too.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskConfig {
+    /// Path to the raw image or host block device, or the backend's socket
+    /// (`fd=N` for an inherited descriptor).
+    pub path: String,
+    /// How the image is exposed (read-only, prefetch, block sizes, direct
+    /// I/O, cache policy, exclusive open, encryption, rate limits). The
+    /// backend of a vhost-user disk decides all of these itself.
+    pub options: DiskOptions,
+    /// The image is the inherited descriptor N, not opened by path.
+    pub fd: Option<RawFd>,
+    /// `path` is a vhost-user-blk backend's socket.
+    pub vhost_user: bool,
+    /// How the device is attached.
+    pub transport: Transport,
+}
+
+impl FromStr for DiskConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self {
+            path: s.to_string(),
+            options: DiskOptions::default(),
+            fd: None,
+            vhost_user: false,
+            transport: Transport::default(),
+        };
+        let parse_fd = |what: &str, number: &str| {
+            number
+                .parse::<RawFd>()
+                .ok()
+                .filter(|&fd| fd > libc::STDERR_FILENO)
+                .ok_or_else(|| format!("invalid {what}={number:?} (must be 3 or more)"))
+        };
+        // A bare path keeps working, even one containing `,` or `=`
+        let spec = if let Some(spec) = s.strip_prefix("vhost-user=") {
+            config.vhost_user = true;
+            spec
+        } else if let Some(spec) = s.strip_prefix("path=") {
+            spec
+        } else if let Some(spec) = s.strip_prefix("fd=") {
+            let number = spec.split(',').next().unwrap_or_default();
+            config.fd = Some(parse_fd("disk fd", number)?);
+            spec
+        } else {
+            return Ok(config);
+        };
+        let mut options = spec.split(',');
+        config.path = options.next().unwrap_or_default().to_string();
+        if config.path.is_empty() {
+            return Err(if config.vhost_user {
+                "disk needs vhost-user=SOCKET".into()
+            } else {
+                "disk needs path=IMAGE".into()
+            });
+        }
+        if let Some(fd) = config.fd {
+            config.path = format!("fd={fd}");
+        }
+        let block_size = |value: &str| {
+            value
+                .parse::<ByteSize>()
+                .ok()
+                .and_then(|size| u32::try_from(size.bytes()).ok())
+                .ok_or_else(|| format!("invalid block size {value:?}"))
+        };
+        let on_off = |option: &str, value: &str| match value {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(format!("invalid disk option {option:?} (on or off)")),
+        };
+        for option in options {
+            match option.split_once('=') {
+                Some(("transport", value)) => config.transport = value.parse()?,
+                // The backend decides everything else
+                _ if config.vhost_user => {
+                    return Err(format!("invalid vhost-user disk option {option:?}"));
+                }
+                None if option == "ro" => config.options.read_only = true,
+                Some(("logical-block-size", value)) => {
+                    config.options.logical_block_size = Some(block_size(value)?);
+                }
+                Some(("physical-block-size", value)) => {
+                    config.options.physical_block_size = Some(block_size(value)?);
+                }
+                Some(("direct", value)) => config.options.direct = on_off(option, value)?,
+                Some(("cache", value)) => config.options.cache = value.parse()?,
+                Some(("exclusive", value)) => config.options.exclusive = on_off(option, value)?,
+                Some(("sha256", value)) => config.options.sha256 = Some(value.parse()?),
+                Some((key, value)) if RateLimit::KEYS.contains(&key) => {
+                    config.options.rate_limit.set(key, value)?;
+                }
+                Some((key @ ("key-file" | "key-fd" | "key-command"), value)) => {
+                    if config.options.key.is_some() {
+                        return Err("only one of key-file, key-fd and key-command".into());
+                    }
+                    config.options.key = Some(match key {
+                        "key-file" => KeySource::File(value.into()),
+                        "key-fd" => KeySource::Fd(parse_fd(key, value)?),
+                        _ => KeySource::Command(value.into()),
+                    });
+                }
+                _ => return Err(format!("invalid disk option {option:?}")),
+            }
+        }
+        config.options.block_sizes()?;
+        config.options.rate_limit.validate()?;
+        if config.fd.is_some() && config.options.exclusive {
+            return Err("exclusive=on needs path=IMAGE, not fd=N".into());
+        }
+        Ok(config)
+    }
+}
+
+/// Options controlling a single run of the VM.
+pub struct RunOptions {
+    /// COM1's console backend, replacing the configured one (e.g. the
+    /// console socket's serial channel).
+    pub console: Option<Box<dyn ConsoleBackend>>,
+    /// Console string that marks the guest reaching init.
+    pub init_marker: String,
+    /// Stop the VM as soon as init is reached (used for benchmarking).
+    pub stop_at_init: bool,
+    /// Give up if the VM is still running after this long.
+    pub timeout: Option<Duration>,
+    /// Give up if the guest hasn't printed `init_marker` after this long.
+    /// A restored VM, whose guest booted long ago, has no boot to time.
+    pub boot_timeout: Option<Duration>,
+    /// Where VMM lifecycle events go (see [`crate::events`]).
+    pub events: Events,
+    /// Resume this snapshot rather than boot the kernel. Its memory must
+    /// already back the VM (`config.memory_backing`).
+    pub restore: Option<Snapshot>,
+    /// Start with the vCPUs paused, until resumed through the control
+    /// socket.
+    pub start_paused: bool,
+    /// Lets other threads pause, snapshot or stop the VM while it runs.
+    pub handle: Option<Arc<VmHandle>>,
+    /// Serve the devices from this reactor's thread rather than the one
+    /// running the VM, which then takes no stop signals.
+    pub reactor: Option<Arc<Reactor>>,
+    /// Snapshot and stop the VM once it sits idle (see
+    /// [`crate::hibernate`]).
+    pub hibernate: Option<Hibernate>,
+    /// Serve the control socket on this listener, bound to
+    /// `config.control_socket` by the caller, rather than binding it.
+    pub control_listener: Option<UnixListener>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            console: None,
+            init_marker: DEFAULT_INIT_MARKER.to_string(),
+            stop_at_init: false,
+            timeout: None,
+            boot_timeout: None,
+            events: Events::default(),
+            restore: None,
+            start_paused: false,
+            handle: None,
+            reactor: None,
+            hibernate: None,
+            control_listener: None,
+        }
+    }
+}
+
+/// Controls a VM [`run`] on another thread: the calls the control socket
+/// serves (see [`crate::control`]), without the socket. Pausing and
+/// snapshotting fail until the VM is built, and once it has stopped; a
+/// shutdown asked for before then stops it as soon as it starts.
+pub struct VmHandle {
+    /// The running VM's capture, while it runs.
+    capture: Mutex<Option<Arc<Capture>>>,
+    /// Signalled to stop the VM.
+    shutdown: EventFd,
+}
+
+impl VmHandle {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            capture: Mutex::new(None),
+            shutdown: EventFd::new(EFD_NONBLOCK)?,
+        })
+    }
+
+    /// Stop the vCPUs until [`resume`](Self::resume).
+    pub fn pause(&self) -> io::Result<()> {
+        self.running()?.pause()
+    }
+
+    /// Let the vCPUs of a paused VM run on.
+    pub fn resume(&self) -> io::Result<()> {
+        self.running()?.resume()
+    }
+
+    /// Whether the VM is running, and paused.
+    pub fn is_paused(&self) -> bool {
+        self.running().is_ok_and(|capture| capture.is_paused())
+    }
+
+    /// Snapshot the VM: its state to `state`, guest RAM to `memory` (see
+    /// [`crate::snapshot`]).
+    pub fn snapshot(&self, state: &Path, memory: &Path) -> io::Result<()> {
+        self.running()?.snapshot(state, memory)
+    }
+
+    /// Dump the guest to `path` as an ELF core (see [`crate::coredump`]).
+    pub fn dump_core(&self, path: &Path) -> io::Result<()> {
+        self.running()?.dump(path)
+    }
+
+    /// Each vCPU's statistics so far, by index.
+    pub fn stats(&self) -> io::Result<Vec<VcpuStats>> {
+        self.running()?.stats()
+    }
+
+    /// Debug vCPU `index` of the running VM (see [`VcpuDebug`]).
+    pub fn debug(&self, index: usize) -> io::Result<VcpuDebug> {
+        let capture = self.running()?;
+        if index >= capture.config.topology.cpus() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the VM has no vCPU {index}"),
+            ));
+        }
+        Ok(VcpuDebug { capture, index })
+    }
+
+    /// Stop the VM, as a signal would: [`run`] returns
+    /// [`StopReason::Shutdown`].
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.shutdown.write(1)
+    }
+
+    fn running(&self) -> io::Result<Arc<Capture>> {
+        lock(&self.capture)
+            .clone()
+            .ok_or_else(|| io::Error::other("the VM isn't running"))
+    }
+}
+
+/// Breakpoints and single-stepping on one vCPU of a running VM, for test
+/// harnesses (see [`crate::kvm`]'s `VcpuFd` for what each does).
+///
+/// Every call needs the VM paused, as [`VmHandle::pause`], a paused start
+/// or the vCPU reaching a breakpoint leaves it: the vCPU's own thread
+/// carries the call out, between exits. A vCPU resumed off a breakpoint
+/// steps over it first. Breakpoints patch guest memory
+/// that every vCPU runs, while only this vCPU traps on them, so they suit
+/// single-vCPU VMs.
+pub struct VcpuDebug {
+    capture: Arc<Capture>,
+    index: usize,
+}
+
+impl VcpuDebug {
+    /// Set a software breakpoint at guest physical address `gpa`. Returns
+    /// false if one was already set there.
+    pub fn set_breakpoint(&self, gpa: u64) -> io::Result<bool> {
+        match self
+            .capture
+            .debug(self.index, DebugRequest::SetBreakpoint(gpa))?
+        {
+            DebugReply::Changed(changed) => Ok(changed),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Remove the breakpoint at `gpa`. Returns false if none was set there.
+    pub fn remove_breakpoint(&self, gpa: u64) -> io::Result<bool> {
+        match self
+            .capture
+            .debug(self.index, DebugRequest::RemoveBreakpoint(gpa))?
+        {
+            DebugReply::Changed(changed) => Ok(changed),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Execute `count` guest instructions, one at a time, returning
+    /// [`VcpuExit::Debug`] with the address of the next, or the exit that
+    /// stopped the vCPU first. The VM stays paused.
+    pub fn single_step(&self, count: u32) -> io::Result<VcpuExit> {
+        match self.capture.debug(self.index, DebugRequest::Step(count))? {
+            DebugReply::Stopped(exit) => Ok(exit),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Read `len` bytes of guest memory at RIP, with breakpoints read as
+    /// the bytes they replaced.
+    pub fn read_memory_at_rip(&self, len: usize) -> io::Result<Vec<u8>> {
+        match self
+            .capture
+            .debug(self.index, DebugRequest::ReadAtRip(len))?
+        {
+            DebugReply::Code(code) => Ok(code),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A [`VcpuDebug`] call, for the vCPU's thread to carry out.
+#[derive(Debug)]
+enum DebugRequest {
+    SetBreakpoint(u64),
+    RemoveBreakpoint(u64),
+    Step(u32),
+    ReadAtRip(usize),
+}
+
+/// What a [`DebugRequest`] returned.
+#[derive(Debug)]
+enum DebugReply {
+    Changed(bool),
+    Stopped(VcpuExit),
+    Code(Vec<u8>),
+}
+
+/// Gives a [`VmHandle`] the running VM's capture until dropped.
+struct Attached<'a>(&'a VmHandle);
+
+impl<'a> Attached<'a> {
+    fn new(handle: &'a VmHandle, capture: Arc<Capture>) -> Self {
+        *lock(&handle.capture) = Some(capture);
+        Self(handle)
+    }
+}
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        *lock(&self.0.capture) = None;
+    }
+}
+
+/// Timestamps of the boot milestones of one VM run.
+#[derive(Debug, Clone)]
+pub struct BootTimeline {
+    /// When the VMM started constructing the VM.
+    pub vmm_start: Instant,
+    /// When KVM had created the VM.
+    pub kvm_ready: Instant,
+    /// When the boot vCPU first entered the guest.
+    pub kernel_start: Option<Instant>,
+    /// When the guest first wrote to the console.
+    pub first_output: Option<Instant>,
+    /// When the init marker appeared on the console.
+    pub init_reached: Option<Instant>,
+}
+
+impl BootTimeline {
+    /// Each milestone after VMM start, and how long after it was reached.
+    pub fn milestones(&self) -> [(&'static str, Option<Duration>); 4] {
+        let since = |at: Option<Instant>| Some(at?.saturating_duration_since(self.vmm_start));
+        [
+            ("kvm_ready", since(Some(self.kvm_ready))),
+            ("vcpu_entry", since(self.kernel_start)),
+            ("first_output", since(self.first_output)),
+            ("init", since(self.init_reached)),
+        ]
+    }
+
+    /// The timeline as a JSON object: the VMM start as Unix time in
+    /// seconds, then each milestone in milliseconds after it (`null` if it
+    /// wasn't reached).
+    pub fn to_json(&self) -> String {
+        let start = SystemTime::now()
+            .checked_sub(self.vmm_start.elapsed())
+            .and_then(|start| start.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        // Built by hand to keep the milestones in order
+        let mut fields = vec![format!("\"vmm_start\":{}", start.as_secs_f64())];
+        for (name, after) in self.milestones() {
+            let ms = after.map(|after| after.as_secs_f64() * 1000.0);
+            let ms = serde_json::Value::from(ms);
+            fields.push(format!("\"{name}_ms\":{ms}"));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// Time spent building the VM before the first vCPU entry.
+    pub fn vmm_setup(&self) -> Option<Duration> {
+        Some(self.kernel_start?.saturating_duration_since(self.vmm_start))
+    }
+
+    /// Time from first vCPU entry until init was reached.
+    pub fn kernel_to_init(&self) -> Option<Duration> {
+        Some(
+            self.init_reached?
+                .saturating_duration_since(self.kernel_start?),
+        )
+    }
+}
+
+/// `kvm_ready=1.21ms vcpu_entry=14.30ms ...`, with `-` for a milestone not
+/// reached.
+impl fmt::Display for BootTimeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, after)) in self.milestones().into_iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            match after {
+                Some(after) => write!(f, "{sep}{name}={:.2}ms", after.as_secs_f64() * 1000.0)?,
+                None => write!(f, "{sep}{name}=-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why the run loop stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The guest halted, shut down or hit a fatal exit.
+    GuestExit,
+    /// The guest kernel reported a panic over pvpanic.
+    GuestPanic,
+    /// The guest asked for a reset through the i8042 or 0xCF9.
+    GuestReboot,
+    /// The guest wrote to the debug exit port; Carbon exits with this code.
+    DebugExit(u8),
+    /// The guest printed an exit status line on the console; Carbon exits
+    /// with this code.
+    ExitStatus(u8),
+    /// Init was reached and `stop_at_init` was set.
+    InitReached,
+    /// The run timeout elapsed.
+    Timeout,
+    /// The boot timeout elapsed before the guest reached init.
+    BootTimeout,
+    /// SIGINT, SIGTERM or SIGHUP (this signal) stopped the VM.
+    Signal(i32),
+    /// [`VmHandle::shutdown`] stopped the VM.
+    Shutdown,
+    /// The VM was idle for `RunOptions::hibernate`'s timeout, and stopped
+    /// once snapshotted (see [`crate::hibernate`]).
+    Hibernated,
+}
+
+/// Result of running a VM.
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    /// Why the VM stopped.
+    pub reason: StopReason,
+    /// Boot milestone timestamps.
+    pub timeline: BootTimeline,
+}
+
+/// Console writer that forwards output and records when output first
+/// appears, when a marker does, and the exit status line, if printed.
+struct MarkerWatcher {
+    inner: Box<dyn Write + Send>,
+    marker: Vec<u8>,
+    /// Trailing bytes of output, at most `marker.len()` long.
+    window: Vec<u8>,
+    seen: Arc<OnceLock<Instant>>,
+    first_output: Arc<OnceLock<Instant>>,
+    /// The current line, unless too long to be an exit status.
+    line: Option<Vec<u8>>,
+    exit_status: Arc<OnceLock<u8>>,
+}
+
+impl MarkerWatcher {
+    fn new(
+        inner: Box<dyn Write + Send>,
+        marker: &str,
+        seen: Arc<OnceLock<Instant>>,
+        first_output: Arc<OnceLock<Instant>>,
+        exit_status: Arc<OnceLock<u8>>,
+    ) -> Self {
+        Self {
+            inner,
+            marker: marker.as_bytes().to_vec(),
+            window: Vec::with_capacity(marker.len()),
+            seen,
+            first_output,
+            line: Some(Vec::new()),
+            exit_status,
+        }
+    }
+
+    /// Follow the output line by line for an exit status.
+    fn scan_exit_status(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            if byte == b'\n' {
+                if let Some(code) = self.line.as_deref().and_then(parse_exit_status) {
+                    let _ = self.exit_status.set(code);
+                    return;
+                }
+                self.line = Some(Vec::new());
+            } else if let Some(line) = &mut self.line {
+                if line.len() < MAX_EXIT_STATUS_LINE {
+                    line.push(byte);
+                } else {
+                    self.line = None;
+                }
+            }
+        }
+    }
+}
+
+/// The exit status a console line reports, if it is an exit status line.
+fn parse_exit_status(line: &[u8]) -> Option<u8> {
+    std::str::from_utf8(line)
+        .ok()?
+        .trim()
+        .strip_prefix(EXIT_STATUS_MARKER)?
+        .parse()
+        .ok()
+}
+
+impl Write for MarkerWatcher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.seen.get().is_none() && !self.marker.is_empty() {
+            for &byte in buf {
+                if self.window.len() == self.marker.len() {
+                    self.window.remove(0);
+                }
+                self.window.push(byte);
+                if self.window == self.marker {
+                    let _ = self.seen.set(Instant::now());
+                    break;
+                }
+            }
+        }
+        if self.exit_status.get().is_none() {
+            self.scan_exit_status(buf);
+        }
+        if !buf.is_empty() {
+            self.first_output.get_or_init(Instant::now);
+            progress::advance(Stage::KernelOutput);
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Open the console backend `spec` for serial port `port`.
+fn open_console(spec: &ConsoleConfig, port: &str) -> Result<Box<dyn ConsoleBackend>, CarbonError> {
+    spec.open(port)
+        .map_err(|source| CarbonError::SerialConsole {
+            target: spec.to_string(),
+            source,
+        })
+}
+
+/// Create a serial port writing to `output` and interrupting on `irq`. Its
+/// `input`, if given, is read by the event loop, or by a thread of its own
+/// if epoll can't wait on it (a regular file, say).
+fn serial_port(
+    output: Box<dyn Write + Send>,
+    input: Option<Box<dyn ConsoleInput>>,
+    irq: Arc<dyn IrqLine>,
+    event_loop: &mut EventLoop<Event>,
+    consoles: &mut Vec<ConsoleFeed>,
+) -> Serial {
+    let mut serial = Serial::with_output(output);
+    serial.set_interrupt(irq);
+    if let Some(input) = input {
+        match event_loop.add(input.as_raw_fd(), Event::Console(consoles.len())) {
+            Ok(()) => consoles.push(ConsoleFeed {
+                input,
+                serial: serial.input(),
+                pending: Vec::new(),
+            }),
+            Err(_) => {
+                if let Err(e) = serial.set_input(Box::new(input)) {
+                    warn!("[VMM] Console input unavailable: {}", e);
+                }
+            }
+        }
+    }
+    serial
+}
+
+/// Console input the event loop feeds to a serial port.
+struct ConsoleFeed {
+    input: Box<dyn ConsoleInput>,
+    serial: SerialInput,
+    /// Input read while the receive FIFO was full, waiting for room.
+    pending: Vec<u8>,
+}
+
+impl ConsoleFeed {
+    /// Read the input now ready and pass it to the guest. Returns `false`
+    /// at end of input.
+    fn read(&mut self) -> bool {
+        let mut buf = [0u8; 64];
+        match self.input.read(&mut buf) {
+            Ok(0) => false,
+            Ok(len) => {
+                self.pending.extend_from_slice(&buf[..len]);
+                self.deliver();
+                true
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => true,
+            Err(e) => {
+                warn!("[VMM] Failed to read console input: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Pass the guest as much pending input as its FIFO has room for.
+    /// Returns `true` once none is left.
+    fn deliver(&mut self) -> bool {
+        let taken = self.serial.receive(&self.pending);
+        self.pending.drain(..taken);
+        self.pending.is_empty()
+    }
+}
+
+/// I/O port and MMIO dispatch for the emulated devices.
+struct DeviceHandler {
+    pio_bus: PioBus,
+    mmio_bus: MmioBus,
+    /// Devices on the port I/O bus the guest stops the VM through, checked
+    /// after each port I/O exit.
+    pvpanic: Arc<Mutex<Pvpanic>>,
+    i8042: Arc<Mutex<I8042>>,
+    reset_control: Arc<Mutex<ResetControl>>,
+    debug_exit: Option<Arc<Mutex<DebugExit>>>,
+    io_count: u64,
+}
+
+impl DeviceHandler {
+    /// Whether the guest asked for a reset, through either reset port.
+    fn take_reset(&self) -> bool {
+        // Take both, so neither request lingers
+        lock(&self.i8042).take_reset() | lock(&self.reset_control).take_reset()
+    }
+
+    /// The state of every device that has any, for a snapshot.
+    fn save_state(&self) -> Vec<SavedDevice> {
+        let ports = self
+            .pio_bus
+            .save_state()
+            .into_iter()
+            .map(|(base, state)| SavedDevice {
+                bus: Bus::Pio,
+                base: base.into(),
+                state,
+            });
+        let mmio = self
+            .mmio_bus
+            .save_state()
+            .into_iter()
+            .map(|(base, state)| SavedDevice {
+                bus: Bus::Mmio,
+                base,
+                state,
+            });
+        ports.chain(mmio).collect()
+    }
+
+    /// Put back the device state of a snapshot.
+    fn restore_state(&mut self, devices: &[SavedDevice]) -> Result<(), String> {
+        for device in devices {
+            match device.bus {
+                Bus::Pio => {
+                    let base = u16::try_from(device.base)
+                        .map_err(|_| format!("no port {:#x}", device.base))?;
+                    self.pio_bus.restore_state(base, &device.state)?;
+                }
+                Bus::Mmio => self.mmio_bus.restore_state(device.base, &device.state)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IoHandler for DeviceHandler {
+    fn io_read(&mut self, port: u16, data: &mut IoData) {
+        self.io_count += 1;
+        let mut value = [0u8; 4];
+        let value = &mut value[..data.len()];
+        let handled = self.pio_bus.read(port, value);
+        for (i, &byte) in value.iter().enumerate() {
+            data.set(i, byte);
+        }
+        if self.io_count <= 10 {
+            trace!(
+                "[I/O] IN  port={:#x} -> {:?}{}",
+                port,
+                value,
+                if handled { "" } else { " (unhandled)" }
+            );
+        }
+    }
+
+    fn io_write(&mut self, port: u16, data: &IoData) {
+        self.io_count += 1;
+        let handled = self.pio_bus.write(port, data.as_slice());
+        if self.io_count <= 10 {
+            trace!(
+                "[I/O] OUT port={:#x} <- {:?}{}",
+                port,
+                data.as_slice(),
+                if handled { "" } else { " (unhandled)" }
+            );
+        }
+    }
+}
+
+impl MmioHandler for DeviceHandler {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        self.io_count += 1;
+        self.mmio_bus.read(addr, data);
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        self.io_count += 1;
+        self.mmio_bus.write(addr, data);
+    }
+}
+
+/// Build a VM from `config` and run it until it stops.
+///
+/// A guest that halts or shuts down is a normal stop; KVM failing to run it
+/// is a [`CarbonError::Guest`].
+pub fn run(config: &VmConfig, options: RunOptions) -> Result<RunOutcome, CarbonError> {
+    let vmm_start = Instant::now();
+    if options.hibernate.is_some() {
+        if let Some(reason) = snapshot_blocker(config) {
+            return Err(CarbonError::Config(format!(
+                "can't hibernate when idle: {reason}"
+            )));
+        }
+    }
+
+    // Create VM
+    // Clears the status line however the run ends
+    let _status = ClearStatus;
+
+    progress::advance(Stage::CreateVm);
+    let vm = Arc::new(kvm::create_vm(
+        config.cpu_mode,
+        &config.cpu_features,
+        config.topology,
+        config.hyperv,
+        config.nested,
+    )?);
+    let kvm_ready = Instant::now();
+    let events = options.events.clone();
+    events.emit(&VmEvent::VmStarted);
+    if let Err(reason) = kvm::host::ptp_kvm_status() {
+        debug!("[VMM] Guest ptp_kvm unavailable: {}", reason);
+    }
+
+    // Allocate guest memory, on a memfd if vhost-user backends need to map
+    // it and no other shared memory was asked for
+    let vhost_user = !config.shared_dirs.is_empty() || config.disks.iter().any(|d| d.vhost_user);
+    let backing = match &config.memory_backing {
+        MemoryBacking::Anonymous if vhost_user => &MemoryBacking::Memfd,
+        backing if vhost_user && !backing.is_shared() => {
+            return Err(CarbonError::Config(format!(
+                "vhost-user devices need shared guest memory, not --memory-backend {backing}"
+            )));
+        }
+        backing => backing,
+    };
+    let memory = Arc::new(GuestMemory::with_backing(config.mem_size, backing)?);
+    debug!("[VMM] Guest memory: {}", backing);
+    if config.prefault {
+        let start = Instant::now();
+        memory.prefault()?;
+        info!(
+            "[VMM] Prefaulted {} of guest memory in {:?}",
+            ByteSize(config.mem_size),
+            start.elapsed()
+        );
+    }
+    if config.swap_policy == SwapPolicy::Locked {
+        let start = Instant::now();
+        memory.lock()?;
+        info!(
+            "[VMM] Locked {} of guest memory in {:?}",
+            ByteSize(config.mem_size),
+            start.elapsed()
+        );
+    }
+
+    // Set up MMIO bus and virtio-blk device if disk provided
+    let mut mmio_bus = MmioBus::new();
+
+    // Disks: --disk images, then the --rootfs base and its scratch overlay,
+    // then the --scratch-disk
+    let mut disks = config.disks.clone();
+    let mut rootfs_base = None;
+    let mut _overlay = None;
+    if let Some(rootfs) = &config.rootfs {
+        let overlay = Overlay::create(rootfs.overlay_size).map_err(|source| CarbonError::Disk {
+            path: "rootfs overlay".into(),
+            source,
+        })?;
+        rootfs_base = Some(disks.len());
+        disks.push(DiskConfig {
+            path: rootfs.base.clone(),
+            options: DiskOptions {
+                read_only: true,
+                prefetch: rootfs.prefetch,
+                sha256: rootfs.sha256,
+                ..DiskOptions::default()
+            },
+            fd: None,
+            vhost_user: false,
+            transport: Transport::default(),
+        });
+        disks.push(DiskConfig {
+            path: overlay.path().to_string(),
+            options: DiskOptions::default(),
+            fd: None,
+            vhost_user: false,
+            transport: Transport::default(),
+        });
+        _overlay = Some(overlay);
+    }
+    let mut _scratch = None;
+    if let Some(scratch) = &config.scratch_disk {
+        let disk = ScratchDisk::create(scratch.size).map_err(|source| CarbonError::Disk {
+            path: "scratch disk".into(),
+            source,
+        })?;
+        info!(
+            "[VMM] Scratch disk is {}",
+            rootfs::block_device(disks.len())
+        );
+        disks.push(DiskConfig {
+            path: disk.path(),
+            options: DiskOptions::default(),
+            fd: None,
+            vhost_user: false,
+            transport: scratch.transport,
+        });
+        _scratch = Some(disk);
+    }
+    if disks.len() + config.hotplug_disks > VIRTIO_BLK_SLOTS.len() {
+        return Err(CarbonError::Config(format!(
+            "{} disks and {} hot-plug slots requested, but at most {} can be attached",
+            disks.len(),
+            config.hotplug_disks,
+            VIRTIO_BLK_SLOTS.len()
+        )));
+    }
+    if config.hotplug_disks > 0 && config.control_socket.is_none() {
+        return Err(CarbonError::Config(
+            "hot-plug slots need a control socket to plug disks into them".into(),
+        ));
+    }
+    let key_fd = |disk: &DiskConfig| match disk.options.key {
+        Some(KeySource::Fd(fd)) => Some(fd),
+        _ => None,
+    };
+    let mut fds: Vec<_> = disks
+        .iter()
+        .flat_map(|d| [d.fd, key_fd(d)])
+        .flatten()
+        .collect();
+    fds.sort_unstable();
+    if let Some(pair) = fds.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(CarbonError::Config(format!(
+            "descriptor {} is given to more than one disk or key",
+            pair[0]
+        )));
+    }
+    if config.p9_shares.len() > VIRTIO_9P_SLOTS.len() {
+        return Err(CarbonError::Config(format!(
+            "{} 9p shares requested, but at most {} can be attached",
+            config.p9_shares.len(),
+            VIRTIO_9P_SLOTS.len()
+        )));
+    }
+    if config.shared_dirs.len() > VIRTIO_FS_SLOTS.len() {
+        return Err(CarbonError::Config(format!(
+            "{} shared dirs requested, but at most {} can be attached",
+            config.shared_dirs.len(),
+            VIRTIO_FS_SLOTS.len()
+        )));
+    }
+
+    if config.serial == ConsoleConfig::Stdio && config.serial2 == Some(ConsoleConfig::Stdio) {
+        return Err(CarbonError::Config(
+            "COM1 and COM2 can't both use stdio".into(),
+        ));
+    }
+
+    // Read fw_cfg blobs now, so a missing file stops the run before boot
+    let mut fw_cfg = None;
+    if !config.fw_cfg.is_empty() {
+        let device = fw_cfg.insert(FwCfg::new());
+        for item in &config.fw_cfg {
+            let data = item.load().map_err(|source| CarbonError::FwCfg {
+                name: item.name.clone(),
+                source,
+            })?;
+            debug!("[VMM] fw_cfg: {} ({} bytes)", item.name, data.len());
+            device
+                .add_file(&item.name, data)
+                .map_err(CarbonError::Config)?;
+        }
+    }
+
+    // Open the disk images now, so one the guest can't use stops the run
+    // here rather than hanging the guest, and see what they hold
+    let mut images = Vec::with_capacity(disks.len());
+    let mut formats = Vec::with_capacity(disks.len());
+    for (index, disk) in disks.iter().enumerate() {
+        if disk.vhost_user {
+            images.push(None);
+            formats.push(None);
+            continue;
+        }
+        let disk_error = |source| CarbonError::Disk {
+            path: disk.path.clone(),
+            source,
+        };
+        // VMs sharing a reactor share a host, and its images' blocks
+        let disk_options = DiskOptions {
+            shared_cache: disk.options.shared_cache || options.reactor.is_some(),
+            ..disk.options.clone()
+        };
+        let blk = match disk.fd {
+            Some(fd) => VirtioBlk::from_fd(fd, disk_options),
+            None => VirtioBlk::new(&disk.path, disk_options),
+        };
+        let blk = blk.map_err(disk_error)?;
+        let format = blk.probe().map_err(disk_error)?;
+        if let Some(e) = format.error() {
+            return Err(disk_error(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        info!("[VMM] {}: {}", rootfs::block_device(index), format);
+        images.push(Some(blk));
+        formats.push(Some(format));
+    }
+    let mut root_params = Vec::new();
+    if let Some(base) = rootfs_base {
+        let device = rootfs::root_device(base, formats[base]).map_err(|e| CarbonError::Disk {
+            path: disks[base].path.clone(),
+            source: io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        root_params =
+            rootfs::kernel_params(&config.cmdline, &device, &rootfs::block_device(base + 1));
+    } else if config.boot_profile != BootProfile::Custom
+        && !config.disks.is_empty()
+        && !rootfs::chooses_root(&config.cmdline)
+    {
+        let candidates: Vec<_> = config
+            .disks
+            .iter()
+            .zip(&formats)
+            .map(|(disk, format)| (*format, disk.options.read_only))
+            .collect();
+        match rootfs::infer_root(&candidates) {
+            Some(params) => {
+                info!("[VMM] No root= given; booting with {}", params.join(" "));
+                root_params = params;
+            }
+            None if config.initrd.is_none() => warn!(
+                "[VMM] No root= given and no disk holds a root filesystem; \
+                 pass root= in the command line"
+            ),
+            None => {}
+        }
+    }
+    if let Some(problem) = rootfs::check_root(&config.cmdline, &formats) {
+        warn!("[VMM] {}", problem);
+    }
+
+    // Build kernel command line
+    // Note: virtio devices are discovered via ACPI, not kernel command line
+    let mut cmdline_parts = vec![config.cmdline.clone()];
+    cmdline_parts.extend(root_params);
+    if config.boot_profile != BootProfile::Custom
+        && !config
+            .cmdline
+            .split_whitespace()
+            .any(|p| p.starts_with("console="))
+    {
+        cmdline_parts.push("console=ttyS0".into());
+    }
+    if !config.kaslr {
+        cmdline_parts.push("nokaslr".into());
+    }
+    cmdline_parts.extend(config.boot_profile.params().iter().map(|p| p.to_string()));
+    let cmdline = cmdline_parts.join(" ");
+    info!("[VMM] Cmdline: {}", cmdline);
+
+    // Each device's slot and transport, in attach order
+    let hotplug_slots = &VIRTIO_BLK_SLOTS[disks.len()..disks.len() + config.hotplug_disks];
+    let slots: Vec<_> = VIRTIO_BLK_SLOTS
+        .into_iter()
+        .zip(disks.iter().map(|d| d.transport))
+        .chain(hotplug_slots.iter().map(|&slot| (slot, Transport::Mmio)))
+        .chain(
+            config
+                .vsock
+                .as_ref()
+                .map(|v| (VIRTIO_VSOCK_SLOT, v.transport)),
+        )
+        .chain(
+            VIRTIO_FS_SLOTS
+                .into_iter()
+                .zip(config.shared_dirs.iter().map(|d| d.transport)),
+        )
+        .chain(
+            VIRTIO_9P_SLOTS
+                .into_iter()
+                .zip(config.p9_shares.iter().map(|s| s.transport)),
+        )
+        .chain(
+            config
+                .pmem
+                .as_ref()
+                .map(|p| (VIRTIO_PMEM_SLOT, p.transport)),
+        )
+        .collect();
+
+    // Build virtio device configuration for ACPI DSDT
+    let virtio_devices: Vec<_> = slots
+        .iter()
+        .filter(|(_, transport)| *transport == Transport::Mmio)
+        .enumerate()
+        .map(|(id, &((mmio_base, gsi), _))| VirtioDeviceConfig {
+            id: id as u8,
+            mmio_base,
+            mmio_size: VIRTIO_MMIO_SIZE as u32,
+            gsi,
+        })
+        .collect();
+
+    // PCI devices keep their slot's GSI, and are numbered in attach order
+    // after the host bridge
+    let pci_irqs: Vec<_> = slots
+        .iter()
+        .filter(|(_, transport)| *transport == Transport::Pci)
+        .enumerate()
+        .map(|(i, &((_, gsi), _))| (i as u8 + 1, gsi))
+        .collect();
+    let pci_host = (!pci_irqs.is_empty()).then_some(PciHostConfig {
+        ecam_base: PCI_ECAM_BASE,
+        ecam_size: PCI_ECAM_SIZE as u32,
+        mmio_base: PCI_MMIO_BASE,
+        mmio_size: PCI_MMIO_SIZE as u32,
+        irqs: pci_irqs,
+    });
+
+    // A restored guest has its tables and kernel in memory already
+    let entry = match &options.restore {
+        Some(_) => None,
+        None => {
+            // Set up ACPI tables with HW_REDUCED flag and virtio device definitions
+            progress::advance(Stage::Acpi);
+            let apic_ids = config.topology.apic_ids();
+            boot::setup_acpi(
+                &memory,
+                &apic_ids,
+                &virtio_devices,
+                pci_host.as_ref(),
+                PVPANIC_PORT,
+                fw_cfg
+                    .is_some()
+                    .then_some((FW_CFG_PORT_BASE, FW_CFG_PORT_COUNT)),
+                config
+                    .tpm
+                    .as_ref()
+                    .map(|_| TpmConfig {
+                        mmio_base: TPM_CRB_BASE as u32,
+                        mmio_size: TPM_CRB_SIZE as u32,
+                        control_area: TPM_CRB_BASE + TPM_CRB_CONTROL_AREA,
+                    })
+                    .as_ref(),
+            )?;
+
+            // Set up MP tables for interrupt routing (used with HW_REDUCED ACPI)
+            boot::setup_mptable(&memory, &apic_ids)?;
+
+            // Set up boot using Linux 64-bit boot protocol
+            let boot_config = BootConfig {
+                kernel_path: config.kernel_path.clone(),
+                kernel_sha256: config.kernel_sha256,
+                cmdline,
+                mem_size: config.mem_size,
+                initrd_path: config.initrd.clone(),
+            };
+            progress::advance(Stage::LoadKernel);
+            let entry = boot::setup_boot(&vm, &memory, &boot_config)?;
+            events.emit(&VmEvent::KernelLoaded);
+            Some(entry)
+        }
+    };
+
+    // Create virtio devices after memory is set up. Each raises its own GSI
+    // (an irqfd) when it completes requests.
+    let mut pci_bus = PciBus::new();
+    let mut controls = Controls::default();
+    let retry_timer = Arc::new(RetryTimer::new().map_err(event_loop_error)?);
+    for ((disk, image), &(mmio_base, gsi)) in disks.iter().zip(images).zip(&VIRTIO_BLK_SLOTS) {
+        let Some(mut blk) = image else {
+            let spec = devices::virtio::blk::vhost_user_spec();
+            let mut device =
+                VhostUserDevice::connect(Path::new(&disk.path), spec).map_err(|source| {
+                    CarbonError::Disk {
+                        path: disk.path.clone(),
+                        source,
+                    }
+                })?;
+            device.set_memory(&memory);
+            device.set_interrupt(vm.irq_line(gsi)?);
+            let slot = (mmio_base, gsi, disk.transport);
+            let location = attach(&mut mmio_bus, &mut pci_bus, slot, Box::new(device));
+            info!("[VMM] vhost-user-blk registered at {}", location);
+            controls.disks.push(None);
+            continue;
+        };
+        controls.disks.push(Some(blk.rate_limiter()));
+        blk.set_memory(&memory);
+        blk.set_vcpus(config.topology.cpus());
+        blk.set_retry_timer(retry_timer.clone());
+        blk.set_interrupt(vm.irq_line(gsi)?);
+        blk.report_errors(events.clone(), &disk.path);
+        let slot = (mmio_base, gsi, disk.transport);
+        let location = attach(&mut mmio_bus, &mut pci_bus, slot, Box::new(blk));
+        info!("[VMM] virtio-blk registered at {}", location);
+    }
+
+    // Empty slots for disks plugged in later, on the MMIO bus from the
+    // start so the guest finds them in the DSDT
+    let mut _hotplug = None;
+    if !hotplug_slots.is_empty() {
+        let mut triggers = Vec::new();
+        for &(mmio_base, gsi) in hotplug_slots {
+            triggers.push((mmio_base, vm.irq_line(gsi)?));
+        }
+        let slots = DiskSlots::new(
+            &memory,
+            disks.len(),
+            triggers,
+            config.topology.cpus(),
+            retry_timer.clone(),
+        );
+        for (mmio_base, device) in slots.devices() {
+            mmio_bus.register(mmio_base, VIRTIO_MMIO_SIZE, device);
+        }
+        info!(
+            "[VMM] {} hot-plug slots, disks {} to {}",
+            hotplug_slots.len(),
+            disks.len(),
+            disks.len() + hotplug_slots.len() - 1
+        );
+        controls.hotplug = Some(slots.handle());
+        _hotplug = Some(slots);
+    }
+
+    if let Some(vsock) = &config.vsock {
+        let mut device = VirtioVsock::new(vsock).map_err(|source| CarbonError::Vsock {
+            path: vsock.uds.display().to_string(),
+            source,
+        })?;
+        let (mmio_base, gsi) = VIRTIO_VSOCK_SLOT;
+        device.set_memory(&memory);
+        device.set_interrupt(vm.irq_line(gsi)?);
+        let slot = (mmio_base, gsi, vsock.transport);
+        let location = attach(&mut mmio_bus, &mut pci_bus, slot, Box::new(device));
+        info!("[VMM] virtio-vsock registered at {}", location);
+    }
+
+    for (dir, &(mmio_base, gsi)) in config.shared_dirs.iter().zip(&VIRTIO_FS_SLOTS) {
+        let mut fs = VhostUserDevice::connect(&dir.socket, dir.spec()).map_err(|source| {
+            CarbonError::SharedDir {
+                path: dir.socket.display().to_string(),
+                source,
+            }
+        })?;
+        fs.set_memory(&memory);
+        fs.set_interrupt(vm.irq_line(gsi)?);
+        let slot = (mmio_base, gsi, dir.transport);
+        let location = attach(&mut mmio_bus, &mut pci_bus, slot, Box::new(fs));
+        info!("[VMM] virtio-fs {:?} registered at {}", dir.tag, location);
+    }
+
+    for (share, &(mmio_base, gsi)) in config.p9_shares.iter().zip(&VIRTIO_9P_SLOTS) {
+        let mut p9 = Virtio9p::new(share).map_err(|source| CarbonError::P9Share {
+            path: share.path.display().to_string(),
+            source,
+        })?;
+        p9.set_memory(&memory);
+        p9.set_interrupt(vm.irq_line(gsi)?);
+        let slot = (mmio_base, gsi, share.transport);
+        let location = attach(&mut mmio_bus, &mut pci_bus, slot, Box::new(p9));
+        info!("[VMM] virtio-9p {:?} registered at {}", share.tag, location);
+    }
+
+    if let Some(pmem_config) = &config.pmem {
+        let path = &pmem_config.path;
+        let mut pmem =
+            VirtioPmem::new(path, devices::pmem_guest_addr(memory.end())).map_err(|source| {
+                CarbonError::Disk {
+                    path: path.clone(),
+                    source,
+                }
+            })?;
+        let (mmio_base, gsi) = VIRTIO_PMEM_SLOT;
+        pmem.set_memory(&memory);
+        pmem.set_interrupt(vm.irq_line(gsi)?);
+        // The image gets its own memory slot, after RAM's
+        let (guest_addr, size, host_addr) = pmem.region();
+        // SAFETY: the mapping is owned by the device, which lives on the MMIO
+        // bus until the VM stops.
+        unsafe {
+            vm.set_user_memory_region(devices::PMEM_MEMORY_SLOT, guest_addr, size, host_addr)?;
+        }
+        let slot = (mmio_base, gsi, pmem_config.transport);
+        let location = attach(&mut mmio_bus, &mut pci_bus, slot, Box::new(pmem));
+        info!("[VMM] virtio-pmem registered at {}", location);
+    }
+
+    // The TPM, forwarding commands to swtpm
+    if let Some(path) = &config.tpm {
+        let tpm = TpmCrb::connect(path).map_err(|source| CarbonError::Tpm {
+            path: path.display().to_string(),
+            source,
+        })?;
+        mmio_bus.register(TPM_CRB_BASE, TPM_CRB_SIZE, Box::new(tpm));
+        info!("[VMM] TPM CRB registered at {:#x}", TPM_CRB_BASE);
+    }
+
+    // The host bridge, when any device sits behind it
+    if !pci_bus.is_empty() {
+        mmio_bus.register(
+            PCI_ECAM_BASE,
+            PCI_ECAM_SIZE + PCI_MMIO_SIZE,
+            Box::new(pci_bus),
+        );
+        info!("[VMM] PCI host bridge registered at {:#x}", PCI_ECAM_BASE);
+    }
+
+    // Start device plugins; their processes live as long as their devices
+    let mut plugin_ports = Vec::new();
+    let mut claimed: Vec<(String, plugin::Region)> = Vec::new();
+    for path in &config.device_plugins {
+        let plugin = Plugin::spawn(path).map_err(|source| CarbonError::DevicePlugin {
+            path: path.clone(),
+            source,
+        })?;
+        for region in plugin.regions() {
+            if let Some((other, _)) = claimed.iter().find(|(_, r)| r.overlaps(region)) {
+                return Err(CarbonError::Config(format!(
+                    "device plugin {path} claims {region}, which overlaps a region of {other}"
+                )));
+            }
+            info!("[VMM] Device plugin {}: {}", path, region);
+            claimed.push((path.clone(), region.clone()));
+        }
+        for (base, size, device) in plugin.mmio_devices() {
+            mmio_bus.register(base, size, Box::new(device));
+        }
+        plugin_ports.extend(plugin.port_devices());
+    }
+
+    // Create the vCPUs (also sets CPUID). The APs wait in the in-kernel
+    // LAPIC until the BSP starts them.
+    let threads = Arc::new(Mutex::new(Vec::new()));
+    let capture = Arc::new(Capture::new(
+        vm.clone(),
+        memory.clone(),
+        threads.clone(),
+        config,
+    ));
+    if options.start_paused {
+        capture.hold();
+    }
+    controls.capture = Some(capture.clone());
+    let handle = options.handle.clone();
+    let attached = handle
+        .as_deref()
+        .map(|handle| Attached::new(handle, capture.clone()));
+    controls.memory = Some(memory.clone());
+    controls.swap_policy = config.swap_policy;
+    let mut vcpus = Vec::new();
+    for index in 0..config.topology.cpus() {
+        let vcpu = vm.create_vcpu(index)?;
+        vcpu.set_boot_msrs()?;
+        controls.vcpus.push(vcpu.stats_reader());
+        vcpus.push(vcpu);
+    }
+    let _ = capture.stats.set(controls.vcpus.clone());
+
+    let (control, _control_guard) = match (options.control_listener, &config.control_socket) {
+        (Some(listener), _) => (Some(ControlSocket::from_listener(listener, controls)), None),
+        (None, Some(path)) => {
+            let (socket, guard) = ControlSocket::bind(path, controls).map_err(|source| {
+                CarbonError::ControlSocket {
+                    path: path.display().to_string(),
+                    source,
+                }
+            })?;
+            (Some(socket), Some(guard))
+        }
+        (None, None) => (None, None),
+    };
+
+    // Set up the BSP's registers for 64-bit long mode boot
+    if let Some(entry) = entry {
+        boot::setup_vcpu_regs(&vcpus[0], &memory, entry)?;
+    }
+
+    // Or put back where the snapshot left them: the VM's state before any
+    // vCPU's, for their LAPICs
+    if let Some(snapshot) = &options.restore {
+        let invalid = |e| CarbonError::Config(format!("invalid snapshot: {e}"));
+        vm.restore_state(&snapshot.vm_state().map_err(invalid)?)?;
+        for (vcpu, state) in vcpus.iter().zip(snapshot.vcpu_states().map_err(invalid)?) {
+            vcpu.restore_state(&state)?;
+        }
+        info!("[VMM] Restored {} vCPU(s) from the snapshot", vcpus.len());
+    }
+
+    // Everything but running the vCPUs happens in the main thread's loop
+    let mut event_loop = EventLoop::new().map_err(event_loop_error)?;
+    event_loop
+        .add(retry_timer.as_raw_fd(), Event::Retry)
+        .map_err(event_loop_error)?;
+    let mut consoles = Vec::new();
+
+    // Watch the console for the guest's first output, the init marker and
+    // the exit status
+    let init_reached = Arc::new(OnceLock::new());
+    let first_output = Arc::new(OnceLock::new());
+    let exit_status = Arc::new(OnceLock::new());
+    let mut backend = match options.console {
+        Some(backend) => backend,
+        None => open_console(&config.serial, "COM1")?,
+    };
+    events.emit(&VmEvent::ConsoleReady);
+    let input = backend.take_input();
+    let console = MarkerWatcher::new(
+        Box::new(backend),
+        &options.init_marker,
+        init_reached.clone(),
+        first_output.clone(),
+        exit_status.clone(),
+    );
+    let serial = serial_port(
+        Box::new(console),
+        input,
+        vm.irq_line(SERIAL_COM1_IRQ)?,
+        &mut event_loop,
+        &mut consoles,
+    );
+
+    // COM2 (ttyS1), on a backend of its own
+    let serial2 = match &config.serial2 {
+        Some(spec) => {
+            let mut backend = open_console(spec, "COM2")?;
+            let input = backend.take_input();
+            info!("[VMM] COM2 (ttyS1) on {}", spec);
+            Some(serial_port(
+                Box::new(backend),
+                input,
+                vm.irq_line(SERIAL_COM2_IRQ)?,
+                &mut event_loop,
+                &mut consoles,
+            ))
+        }
+        None => None,
+    };
+
+    // Queue notifications to the MMIO bus's devices arrive on ioeventfds,
+    // or, where KVM has none, as MMIO exits the bus handles
+    let mut notifiers = Vec::new();
+    let queue_notifiers = if vm.has_ioeventfd() {
+        mmio_bus.queue_notifiers()
+    } else {
+        warn!("[VMM] KVM has no ioeventfds: queue notifications exit to the VMM");
+        Vec::new()
+    };
+    for (addr, queue) in queue_notifiers {
+        let eventfd = vm.mmio_notifier(addr, queue)?;
+        event_loop
+            .add(eventfd.as_raw_fd(), Event::QueueNotify(notifiers.len()))
+            .map_err(event_loop_error)?;
+        notifiers.push((addr, queue, eventfd));
+    }
+    debug!("[VMM] {} queue notifiers on ioeventfds", notifiers.len());
+
+    // Port devices: the debug exit port first, to win over built-in
+    // devices, then those, then the plugins' ranges
+    let mut pio_bus = PioBus::new();
+    let debug_exit = config.debug_exit.map(|port| {
+        let device = Arc::new(Mutex::new(DebugExit::new()));
+        pio_bus.register(port, 1, Box::new(device.clone()));
+        device
+    });
+    pio_bus.register(
+        SERIAL_COM1_BASE,
+        SERIAL_COM1_END - SERIAL_COM1_BASE + 1,
+        Box::new(serial),
+    );
+    if let Some(serial2) = serial2 {
+        pio_bus.register(
+            SERIAL_COM2_BASE,
+            SERIAL_COM2_END - SERIAL_COM2_BASE + 1,
+            Box::new(serial2),
+        );
+    }
+    pio_bus.register(CMOS_PORT_INDEX, 2, Box::new(Cmos::new(config.rtc)));
+    let pvpanic = Arc::new(Mutex::new(Pvpanic::new()));
+    pio_bus.register(PVPANIC_PORT, 1, Box::new(pvpanic.clone()));
+    let i8042 = Arc::new(Mutex::new(I8042::new()));
+    pio_bus.register(I8042_DATA_PORT, I8042_PORT_COUNT, Box::new(i8042.clone()));
+    let reset_control = Arc::new(Mutex::new(ResetControl::new()));
+    pio_bus.register(RESET_CONTROL_PORT, 1, Box::new(reset_control.clone()));
+    if let Some(mut fw_cfg) = fw_cfg {
+        fw_cfg.set_memory(&memory);
+        pio_bus.register(FW_CFG_PORT_BASE, FW_CFG_PORT_COUNT.into(), Box::new(fw_cfg));
+    }
+    for (base, count, device) in plugin_ports {
+        pio_bus.register(base, count, Box::new(device));
+    }
+
+    let mut handler = DeviceHandler {
+        pio_bus,
+        mmio_bus,
+        pvpanic,
+        i8042,
+        reset_control,
+        debug_exit,
+        io_count: 0,
+    };
+    if let Some(snapshot) = &options.restore {
+        handler
+            .restore_state(&snapshot.devices)
+            .map_err(|e| CarbonError::Config(format!("the snapshot doesn't fit the VM: {e}")))?;
+        // Serve what the rate limits held back when the snapshot was taken
+        retry_timer.schedule(Duration::ZERO);
+    }
+    let handler = Arc::new(Mutex::new(handler));
+    let _ = capture.devices.set(handler.clone());
+
+    debug!("[VMM] Starting {} vCPU(s)...", vcpus.len());
+    progress::advance(Stage::StartVcpu);
+    events.emit(&VmEvent::VcpuStarted);
+
+    // Run the VM, a thread per vCPU, serving everything else from the main
+    // thread. The first vCPU to stop says why; the rest are then kicked out
+    // of the guest.
+    let run = VcpuRun {
+        handler,
+        events,
+        init_reached,
+        stop_at_init: options.stop_at_init,
+        exit_status,
+        affinity: config.cpu_affinity.clone(),
+        init_reported: AtomicBool::new(false),
+        kernel_start: OnceLock::new(),
+        stopping: AtomicBool::new(false),
+        threads,
+        capture,
+        dump_core_on_fault: config.dump_core_on_fault.clone(),
+        exit_on_halt: config.exit_on_halt,
+        halt_round: Arc::new(AtomicU64::new(0)),
+        parked: Mutex::new(vec![None; vcpus.len()]),
+    };
+    install_kick_handler();
+    // Sharing a reactor, the VM shares the process with others, whose host
+    // decides what a signal stops
+    let reactor = options.reactor;
+    let signals = match reactor {
+        Some(_) => None,
+        None => Some(StopSignals::install().map_err(event_loop_error)?),
+    };
+    if let Some(signals) = &signals {
+        event_loop
+            .add(signals.as_raw_fd(), Event::Signal)
+            .map_err(event_loop_error)?;
+    }
+    let vcpu_stopped = Arc::new(EventFd::new(EFD_NONBLOCK).map_err(event_loop_error)?);
+    event_loop
+        .add(vcpu_stopped.as_raw_fd(), Event::VcpuStopped)
+        .map_err(event_loop_error)?;
+    if let Some(handle) = &handle {
+        event_loop
+            .add(handle.shutdown.as_raw_fd(), Event::Shutdown)
+            .map_err(event_loop_error)?;
+    }
+    if let Some(control) = &control {
+        event_loop
+            .add(control.as_raw_fd(), Event::ControlListener)
+            .map_err(event_loop_error)?;
+    }
+    let (stopped, stop) = mpsc::channel();
+    let mut main_loop = MainLoop {
+        event_loop,
+        stop,
+        vcpu_stopped: vcpu_stopped.clone(),
+        signals,
+        shutdown: handle.clone(),
+        handler: run.handler.clone(),
+        threads: run.threads.clone(),
+        halt_round: run.halt_round.clone(),
+        consoles,
+        control,
+        notifiers,
+        retry_timer,
+        deadline: options
+            .timeout
+            .map(|timeout| (vmm_start + timeout, timeout)),
+        boot_deadline: options
+            .boot_timeout
+            .filter(|_| options.restore.is_none())
+            .map(|timeout| (vmm_start + timeout, timeout)),
+        init_reached: run.init_reached.clone(),
+        halt_check: config
+            .exit_on_halt
+            .then(|| Instant::now() + HALT_CHECK_INTERVAL),
+        activity: 0,
+        hibernation: options.hibernate.map(|policy| {
+            let now = Instant::now();
+            let monitor = IdleMonitor::new(policy.idle_timeout, now);
+            Hibernation {
+                check: now + monitor.interval(),
+                policy,
+                monitor,
+                capture: run.capture.clone(),
+            }
+        }),
+    };
+    let reason = thread::scope(|scope| {
+        let mut threads = Vec::new();
+        let mut failed = None;
+        for (index, vcpu) in vcpus.into_iter().enumerate() {
+            let (run, stopped, vcpu_stopped) = (&run, stopped.clone(), &*vcpu_stopped);
+            let stats = vcpu.stats_reader();
+            let spawned = thread::Builder::new()
+                .name(format!("vcpu{index}"))
+                .spawn_scoped(scope, move || {
+                    // Wake the main thread once the reason is sent, or the
+                    // sender dropped by a panic
+                    let _wake = Wake(vcpu_stopped);
+                    let stopped = stopped;
+                    let reason = run.vcpu_loop(index, vcpu);
+                    debug!("[VMM] vCPU {} stats: {}", index, stats.stats());
+                    let _ = stopped.send(reason);
+                });
+            match spawned {
+                Ok(thread) => threads.push(thread),
+                Err(e) => {
+                    failed = Some(Err(CarbonError::Guest(format!(
+                        "failed to start vCPU {index}: {e}"
+                    ))));
+                    break;
+                }
+            }
+        }
+        drop(stopped);
+
+        let reason = failed.unwrap_or_else(|| match &reactor {
+            Some(reactor) => {
+                let (stopped, stop) = mpsc::channel();
+                reactor.add(Box::new(Reactive { main_loop, stopped }));
+                stop.recv().unwrap_or_else(|_| {
+                    Err(CarbonError::Guest(
+                        "the reactor serving the VM stopped".into(),
+                    ))
+                })
+            }
+            None => main_loop.run(),
+        });
+        // Nothing more to pause or capture once the vCPUs are stopping
+        drop(attached);
+
+        // Kick until every vCPU has noticed: one between checking `stopping`
+        // and entering the guest misses a signal
+        run.stopping.store(true, Ordering::SeqCst);
+        // Paused vCPUs wait outside the guest, where no kick reaches them
+        let _ = run.capture.resume();
+        while !threads.iter().all(|thread| thread.is_finished()) {
+            run.kick();
+            thread::sleep(KICK_INTERVAL);
+        }
+        reason
+    })?;
+
+    run.events.emit(&VmEvent::VmStopped {
+        reason: format!("{reason:?}"),
+    });
+
+    Ok(RunOutcome {
+        reason,
+        timeline: BootTimeline {
+            vmm_start,
+            kvm_ready,
+            kernel_start: run.kernel_start.get().copied(),
+            first_output: first_output.get().copied(),
+            init_reached: run.init_reached.get().copied(),
+        },
+    })
+}
+
+/// State the vCPU threads share while the VM runs.
+struct VcpuRun {
+    handler: Arc<Mutex<DeviceHandler>>,
+    events: Events,
+    init_reached: Arc<OnceLock<Instant>>,
+    stop_at_init: bool,
+    /// The exit status the guest printed on the console.
+    exit_status: Arc<OnceLock<u8>>,
+    affinity: CpuAffinity,
+    init_reported: AtomicBool,
+    /// When the BSP first entered the guest.
+    kernel_start: OnceLock<Instant>,
+    /// Set once the VM is stopping; each vCPU checks it before entering the
+    /// guest.
+    stopping: AtomicBool,
+    /// vCPU threads, for [`install_kick_handler`]'s signal.
+    threads: Arc<Mutex<Vec<libc::pthread_t>>>,
+    /// Stops the vCPUs for core dumps and snapshots.
+    capture: Arc<Capture>,
+    /// Where to dump the guest if it triple-faults.
+    dump_core_on_fault: Option<PathBuf>,
+    /// Stop once every vCPU is parked (see `VcpuFd::is_parked`).
+    exit_on_halt: bool,
+    /// The halt check the main loop last kicked the vCPUs for.
+    halt_round: Arc<AtomicU64>,
+    /// The last halt check each vCPU was found parked in.
+    parked: Mutex<Vec<Option<u64>>>,
+}
+
+impl VcpuRun {
+    /// Signal every vCPU thread, kicking those in the guest out of it.
+    fn kick(&self) {
+        kick(&self.threads);
+    }
+
+    /// Record whether vCPU `index` is parked in the current halt check,
+    /// and return whether every vCPU is.
+    fn all_parked(&self, index: usize, vcpu: &kvm::VcpuFd) -> Result<bool, CarbonError> {
+        let round = self.halt_round.load(Ordering::SeqCst);
+        let mut parked = lock(&self.parked);
+        parked[index] = vcpu.is_parked()?.then_some(round);
+        Ok(parked.iter().all(|&parked| parked == Some(round)))
+    }
+
+    /// Run vCPU `index` until it stops the VM or is told to stop.
+    fn vcpu_loop(&self, index: usize, mut vcpu: kvm::VcpuFd) -> Result<StopReason, CarbonError> {
+        // SAFETY: pthread_self has no preconditions.
+        lock(&self.threads).push(unsafe { libc::pthread_self() });
+        if let Some(cores) = self.affinity.vcpu(index) {
+            cores.pin_current_thread().map_err(|e| {
+                CarbonError::Config(format!(
+                    "failed to pin vCPU {index} to host cores {cores}: {e}"
+                ))
+            })?;
+        }
+        let mut handler = SharedHandler(&self.handler);
+        let io_count = || lock(&self.handler).io_count;
+
+        let mut iteration = 0u64;
+        // Stopped on a breakpoint, to step over when resumed
+        let mut at_breakpoint = false;
+        loop {
+            if self.stopping.load(Ordering::SeqCst) {
+                // Another vCPU already said why
+                return Ok(StopReason::GuestExit);
+            }
+            self.capture.pause_point(index, &mut vcpu, &mut handler);
+            iteration += 1;
+            if iteration == 1 && index == 0 {
+                debug!("[VMM] Entering KVM (first run)...");
+                self.kernel_start.get_or_init(Instant::now);
+            }
+            let exit = if std::mem::take(&mut at_breakpoint) {
+                match vcpu.single_step(&*self.capture.memory, &mut handler, 1)? {
+                    VcpuExit::Debug(_) => continue,
+                    exit => exit,
+                }
+            } else {
+                vcpu.run_with_io(&mut handler)?
+            };
+            if iteration == 1 && index == 0 {
+                debug!("[VMM] First vCPU exit received!");
+            }
+
+            // Log first 10 exits and every 100000 after
+            if iteration <= 10 || iteration.is_multiple_of(100000) {
+                trace!(
+                    "[VMM] vCPU {} iteration {}: {:?}, {} I/O ops",
+                    index,
+                    iteration,
+                    exit,
+                    io_count()
+                );
+            }
+            match exit {
+                VcpuExit::Io => {
+                    // I/O handled by the handler; a panic report, a debug
+                    // exit or a reset request stops the VM
+                    let devices = lock(&self.handler);
+                    let panic_event = lock(&devices.pvpanic).take_event();
+                    match panic_event {
+                        Some(PanicEvent::Panicked) => {
+                            warn!("[VMM] Guest kernel panicked");
+                            self.events.emit(&VmEvent::GuestPanicked);
+                            return Ok(StopReason::GuestPanic);
+                        }
+                        Some(PanicEvent::CrashLoaded) => {
+                            warn!("[VMM] Guest kernel panicked, booting its crash kernel");
+                            self.events.emit(&VmEvent::GuestCrashLoaded);
+                        }
+                        None => {}
+                    }
+                    if let Some(code) = devices
+                        .debug_exit
+                        .as_ref()
+                        .and_then(|debug_exit| lock(debug_exit).take_exit_code())
+                    {
+                        info!("[VMM] Guest requested exit code {} via debug exit", code);
+                        return Ok(StopReason::DebugExit(code));
+                    }
+                    if devices.take_reset() {
+                        info!(
+                            "[VMM] Guest requested a reboot after {} iterations, {} I/O ops",
+                            iteration, devices.io_count
+                        );
+                        return Ok(StopReason::GuestReboot);
+                    }
+                }
+                // With the in-kernel LAPIC, KVM keeps a halted vCPU blocked
+                // until an interrupt arrives; HLT only exits here, to be
+                // resumed, when KVM has no interrupt to wait for
+                VcpuExit::Interrupted | VcpuExit::Hlt if self.exit_on_halt => {
+                    if self.all_parked(index, &vcpu)? {
+                        info!(
+                            "[VMM] Guest halted after {} iterations, {} I/O ops",
+                            iteration,
+                            io_count()
+                        );
+                        return Ok(StopReason::GuestExit);
+                    }
+                }
+                VcpuExit::Interrupted | VcpuExit::Hyperv | VcpuExit::Hlt => {}
+                VcpuExit::Shutdown => {
+                    info!(
+                        "[VMM] Guest shutdown after {} iterations, {} I/O ops",
+                        iteration,
+                        io_count()
+                    );
+                    if let Ok(regs) = vcpu.get_regs() {
+                        debug!("[VMM] Final RIP: {:#x}", regs.rip);
+                    }
+                    if let Some(path) = &self.dump_core_on_fault {
+                        match self.capture.dump_from(path, index, &vcpu) {
+                            Ok(()) => info!("[VMM] Guest core dumped to {}", path.display()),
+                            Err(e) => warn!("[VMM] Failed to dump guest core: {}", e),
+                        }
+                    }
+                    return Ok(StopReason::GuestExit);
+                }
+                VcpuExit::InternalError => {
+                    return Err(CarbonError::Guest(format!(
+                        "KVM internal error after {} iterations{}",
+                        iteration,
+                        rip_suffix(&vcpu)
+                    )));
+                }
+                VcpuExit::FailEntry(reason) => {
+                    return Err(CarbonError::Guest(format!(
+                        "failed to enter guest (hardware reason {:#x}){}",
+                        reason,
+                        rip_suffix(&vcpu)
+                    )));
+                }
+                VcpuExit::SystemEvent(event) => {
+                    info!("[VMM] System event: {}", event);
+                    return Ok(StopReason::GuestExit);
+                }
+                VcpuExit::Debug(pc) => {
+                    info!(
+                        "[VMM] vCPU {} reached a breakpoint at {:#x}, pausing",
+                        index, pc
+                    );
+                    if let Err(e) = self.capture.pause_from(index, &mut vcpu) {
+                        warn!("[VMM] Failed to pause at the breakpoint: {}", e);
+                    }
+                    at_breakpoint = true;
+                }
+                VcpuExit::Unknown(reason) => {
+                    warn!("[VMM] Unknown exit: {}", reason);
+                    return Ok(StopReason::GuestExit);
+                }
+            }
+
+            if self.init_reached.get().is_some() {
+                if !self.init_reported.swap(true, Ordering::SeqCst) {
+                    progress::advance(Stage::Init);
+                    self.events.emit(&VmEvent::InitReached);
+                }
+                if self.stop_at_init {
+                    return Ok(StopReason::InitReached);
+                }
+            }
+            if let Some(&code) = self.exit_status.get() {
+                info!("[VMM] Guest reported exit status {} on the console", code);
+                return Ok(StopReason::ExitStatus(code));
+            }
+        }
+    }
+}
+
+/// Signal every thread in `threads`, kicking those running a vCPU out of
+/// the guest.
+fn kick(threads: &Mutex<Vec<libc::pthread_t>>) {
+    for &thread in lock(threads).iter() {
+        // SAFETY: the thread is running or finished, but not yet joined, so
+        // its handle is valid.
+        unsafe { libc::pthread_kill(thread, libc::SIGRTMIN()) };
+    }
+}
+
+/// Captures the running VM, as a core dump (see [`crate::coredump`]) or a
+/// snapshot (see [`crate::snapshot`]): stops every vCPU at its next exit,
+/// captures their state, writes it with guest memory, then lets them run on.
+pub struct Capture {
+    vm: Arc<kvm::VmFd>,
+    memory: Arc<GuestMemory>,
+    threads: Arc<Mutex<Vec<libc::pthread_t>>>,
+    /// The devices, once they are all built.
+    devices: OnceLock<Arc<Mutex<DeviceHandler>>>,
+    /// Readers of the vCPUs' statistics, once they are all created.
+    stats: OnceLock<Vec<StatsReader>>,
+    /// The configuration the VM was built from, for snapshots.
+    config: VmConfig,
+    /// Why the VM can't be snapshotted, if it can't.
+    snapshot_blocker: Option<String>,
+    /// Whether a capture is waiting for the vCPUs, checked without the lock.
+    requested: AtomicBool,
+    pause: Mutex<Pause>,
+    /// Signalled as vCPUs stop for a capture, and once it is written.
+    changed: Condvar,
+}
+
+/// A capture in progress, if `requested`.
+struct Pause {
+    requested: bool,
+    /// The VM is paused: the vCPUs stay stopped once the capture is done.
+    held: bool,
+    /// The state of each vCPU stopped so far, by index.
+    captured: Vec<Option<VcpuState>>,
+    /// Whether each vCPU is waiting at its pause point, by index.
+    waiting: Vec<bool>,
+    /// A [`VcpuDebug`] call for each vCPU, and then its result.
+    debug: Vec<Option<DebugRequest>>,
+    debugged: Vec<Option<io::Result<DebugReply>>>,
+}
+
+impl Capture {
+    fn new(
+        vm: Arc<kvm::VmFd>,
+        memory: Arc<GuestMemory>,
+        threads: Arc<Mutex<Vec<libc::pthread_t>>>,
+        config: &VmConfig,
+    ) -> Self {
+        let vcpus = config.topology.cpus() as usize;
+        Self {
+            vm,
+            memory,
+            threads,
+            devices: OnceLock::new(),
+            stats: OnceLock::new(),
+            config: config.clone(),
+            snapshot_blocker: snapshot_blocker(config),
+            requested: AtomicBool::new(false),
+            pause: Mutex::new(Pause {
+                requested: false,
+                held: false,
+                captured: (0..vcpus).map(|_| None).collect(),
+                waiting: vec![false; vcpus],
+                debug: (0..vcpus).map(|_| None).collect(),
+                debugged: (0..vcpus).map(|_| None).collect(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Each vCPU's statistics so far, by index.
+    pub fn stats(&self) -> io::Result<Vec<VcpuStats>> {
+        let readers = self
+            .stats
+            .get()
+            .ok_or_else(|| io::Error::other("the VM hasn't started yet"))?;
+        Ok(readers.iter().map(StatsReader::stats).collect())
+    }
+
+    /// Dump the guest to `path`, from outside the vCPU threads.
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        self.dump_with(path, None)
+    }
+
+    /// Dump the guest to `path` from the thread of vCPU `index`, which is
+    /// stopped already.
+    fn dump_from(&self, path: &Path, index: usize, vcpu: &kvm::VcpuFd) -> io::Result<()> {
+        let state = vcpu.save_state().map_err(io::Error::other)?;
+        self.dump_with(path, Some((index, state)))
+    }
+
+    fn dump_with(&self, path: &Path, own: Option<(usize, VcpuState)>) -> io::Result<()> {
+        self.paused(own, |vcpus, count| {
+            if vcpus.len() < count {
+                warn!(
+                    "[VMM] Core dump has registers of {} of {} vCPUs",
+                    vcpus.len(),
+                    count
+                );
+            }
+            coredump::write(path, &self.memory, vcpus)
+        })
+    }
+
+    /// Snapshot the VM: its state to `state`, guest RAM to `memory`.
+    pub fn snapshot(&self, state: &Path, memory: &Path) -> io::Result<()> {
+        if let Some(reason) = &self.snapshot_blocker {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, reason.clone()));
+        }
+        let devices = self
+            .devices
+            .get()
+            .ok_or_else(|| io::Error::other("the VM hasn't started yet"))?;
+        self.paused(None, |vcpus, count| {
+            // A vCPU that stopped for good has nothing to resume
+            if vcpus.len() < count {
+                return Err(io::Error::other(format!(
+                    "only {} of {} vCPUs stopped for the snapshot",
+                    vcpus.len(),
+                    count
+                )));
+            }
+            let vm = self.vm.save_state().map_err(io::Error::other)?;
+            let vcpus = vcpus.iter().map(|(_, state)| state);
+            let devices = lock(devices).save_state();
+            let snapshot = Snapshot::new(self.config.clone(), &vm, vcpus, devices);
+            snapshot.write(state, &self.memory, memory)
+        })
+    }
+
+    /// Stop the vCPUs until [`resume`](Self::resume).
+    pub fn pause(&self) -> io::Result<()> {
+        let pause = lock(&self.pause);
+        if pause.held {
+            return Err(io::Error::other("the VM is already paused"));
+        }
+        if pause.requested {
+            return Err(io::Error::other("a capture is in progress"));
+        }
+        let mut pause = self.stop_vcpus(pause, None);
+        pause.held = true;
+        Ok(())
+    }
+
+    /// Pause the VM from the thread of vCPU `index`, which stopped on a
+    /// breakpoint. Nothing to do if it is pausing already.
+    fn pause_from(&self, index: usize, vcpu: &mut kvm::VcpuFd) -> io::Result<()> {
+        let state = vcpu.save_state().map_err(io::Error::other)?;
+        let pause = lock(&self.pause);
+        if pause.requested {
+            return Ok(());
+        }
+        let mut pause = self.stop_vcpus(pause, Some((index, state)));
+        pause.held = true;
+        Ok(())
+    }
+
+    /// Have vCPU `index`, waiting at its pause point, carry out `request`.
+    fn debug(&self, index: usize, request: DebugRequest) -> io::Result<DebugReply> {
+        let mut pause = lock(&self.pause);
+        if !pause.held {
+            return Err(io::Error::other("the VM isn't paused"));
+        }
+        // A VM paused from the start may not have reached its pause points
+        let deadline = Instant::now() + DUMP_PAUSE_TIMEOUT;
+        while !pause.waiting[index] {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::other(format!("vCPU {index} isn't stopped")));
+            }
+            pause = self
+                .changed
+                .wait_timeout(pause, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        pause.debugged[index] = None;
+        pause.debug[index] = Some(request);
+        self.changed.notify_all();
+        loop {
+            if let Some(reply) = pause.debugged[index].take() {
+                return reply;
+            }
+            if !pause.waiting[index] {
+                pause.debug[index] = None;
+                return Err(io::Error::other(format!("vCPU {index} was resumed")));
+            }
+            pause = self.changed.wait(pause).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Keep the vCPUs from entering the guest at all until
+    /// [`resume`](Self::resume), for a VM that starts paused.
+    fn hold(&self) {
+        let mut pause = lock(&self.pause);
+        pause.requested = true;
+        pause.held = true;
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Let the vCPUs of a paused VM run on.
+    pub fn resume(&self) -> io::Result<()> {
+        let mut pause = lock(&self.pause);
+        if !pause.held {
+            return Err(io::Error::other("the VM isn't paused"));
+        }
+        pause.captured.iter_mut().for_each(|state| *state = None);
+        self.release(&mut pause);
+        Ok(())
+    }
+
+    /// Whether the VM is paused (see [`pause`](Self::pause)).
+    pub fn is_paused(&self) -> bool {
+        lock(&self.pause).held
+    }
+
+    /// Stop the vCPUs, unless the VM is paused already, and run `capture`
+    /// with the state of those that stopped, by index, and how many there
+    /// are; then let them run on, unless paused. `own` is the state of the
+    /// calling vCPU's, if called from one.
+    fn paused<T>(
+        &self,
+        own: Option<(usize, VcpuState)>,
+        capture: impl FnOnce(&[(usize, VcpuState)], usize) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut pause = lock(&self.pause);
+        let held = pause.held;
+        if held {
+            if let Some((index, state)) = own {
+                pause.captured[index] = Some(state);
+            }
+        } else if pause.requested {
+            return Err(io::Error::other("a capture is already in progress"));
+        } else {
+            pause = self.stop_vcpus(pause, own);
+        }
+        let count = pause.captured.len();
+        let vcpus: Vec<_> = pause
+            .captured
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, state)| Some((index, state.take()?)))
+            .collect();
+        let result = capture(&vcpus, count);
+        if held {
+            for (index, state) in vcpus {
+                pause.captured[index] = Some(state);
+            }
+        } else {
+            self.release(&mut pause);
+        }
+        result
+    }
+
+    /// Ask the vCPUs to stop, and wait until they all have, or for as long
+    /// as the VM's vCPUs can take to. `own` is as for
+    /// [`paused`](Self::paused).
+    fn stop_vcpus<'a>(
+        &'a self,
+        mut pause: MutexGuard<'a, Pause>,
+        own: Option<(usize, VcpuState)>,
+    ) -> MutexGuard<'a, Pause> {
+        pause.requested = true;
+        self.requested.store(true, Ordering::SeqCst);
+        pause.captured.iter_mut().for_each(|state| *state = None);
+        if let Some((index, state)) = own {
+            pause.captured[index] = Some(state);
+        }
+        kick(&self.threads);
+
+        // vCPUs that have stopped for good never check in
+        let deadline = Instant::now() + DUMP_PAUSE_TIMEOUT;
+        while pause.captured.iter().any(Option::is_none) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            pause = self
+                .changed
+                .wait_timeout(pause, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        pause
+    }
+
+    /// Let the stopped vCPUs run on.
+    fn release(&self, pause: &mut Pause) {
+        pause.requested = false;
+        pause.held = false;
+        self.requested.store(false, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+
+    /// Called by vCPU `index` between exits: while a capture is requested,
+    /// finish any I/O the vCPU was in, capture its state and wait for the
+    /// capture to be written, carrying out [`VcpuDebug`] calls meanwhile.
+    fn pause_point<H: IoHandler + MmioHandler>(
+        &self,
+        index: usize,
+        vcpu: &mut kvm::VcpuFd,
+        handler: &mut H,
+    ) {
+        if !self.requested.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut pause = lock(&self.pause);
+        if !pause.requested {
+            return;
+        }
+        match vcpu.complete_io().and_then(|()| vcpu.save_state()) {
+            Ok(state) => pause.captured[index] = Some(state),
+            // Captured without this vCPU's registers, once the wait ends
+            Err(e) => warn!("[VMM] Failed to capture vCPU {}: {}", index, e),
+        }
+        pause.waiting[index] = true;
+        self.changed.notify_all();
+        while pause.requested {
+            let Some(request) = pause.debug[index].take() else {
+                pause = self.changed.wait(pause).unwrap_or_else(|e| e.into_inner());
+                continue;
+            };
+            drop(pause);
+            let stepped = matches!(request, DebugRequest::Step(_));
+            let reply = self.carry_out(vcpu, handler, request);
+            pause = lock(&self.pause);
+            if stepped {
+                match vcpu.save_state() {
+                    Ok(state) => pause.captured[index] = Some(state),
+                    Err(e) => warn!("[VMM] Failed to capture vCPU {}: {}", index, e),
+                }
+            }
+            pause.debugged[index] = Some(reply);
+            self.changed.notify_all();
+        }
+        pause.waiting[index] = false;
+    }
+
+    fn carry_out<H: IoHandler + MmioHandler>(
+        &self,
+        vcpu: &mut kvm::VcpuFd,
+        handler: &mut H,
+        request: DebugRequest,
+    ) -> io::Result<DebugReply> {
+        let memory = &*self.memory;
+        match request {
+            DebugRequest::SetBreakpoint(gpa) => {
+                vcpu.set_breakpoint(memory, gpa).map(DebugReply::Changed)
+            }
+            DebugRequest::RemoveBreakpoint(gpa) => {
+                vcpu.remove_breakpoint(memory, gpa).map(DebugReply::Changed)
+            }
+            DebugRequest::Step(count) => vcpu
+                .single_step(memory, handler, count)
+                .map(DebugReply::Stopped),
+            DebugRequest::ReadAtRip(len) => {
+                vcpu.read_memory_at_rip(memory, len).map(DebugReply::Code)
+            }
+        }
+        .map_err(io::Error::other)
+    }
+}
+
+/// Why a VM built from `config` can't be snapshotted, if it can't: it has a
+/// device whose state lives outside Carbon, or that a restored VM couldn't
+/// get back.
+fn snapshot_blocker(config: &VmConfig) -> Option<String> {
+    let descriptor =
+        |disk: &DiskConfig| disk.fd.is_some() || matches!(disk.options.key, Some(KeySource::Fd(_)));
+    let device = if config.disks.iter().any(|d| d.vhost_user) || !config.shared_dirs.is_empty() {
+        "vhost-user backends"
+    } else if config.vsock.is_some() {
+        "vsock"
+    } else if !config.p9_shares.is_empty() {
+        "9p shares"
+    } else if config.pmem.is_some() {
+        "pmem"
+    } else if config.tpm.is_some() {
+        "a TPM"
+    } else if !config.device_plugins.is_empty() {
+        "device plugins"
+    } else if config.hotplug_disks > 0 {
+        "hot-plug slots"
+    } else if config.disks.iter().any(|d| d.transport != Transport::Mmio) {
+        "devices on PCI"
+    } else if config.disks.iter().any(descriptor) {
+        "disks or keys passed as descriptors"
+    } else if config.rootfs.is_some() || config.scratch_disk.is_some() {
+        "a --rootfs or --scratch-disk"
+    } else {
+        return None;
+    };
+    Some(format!("VMs with {device} can't be snapshotted"))
+}
+
+/// What the main thread's event loop waits for.
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    /// A vCPU stopped; why is on [`MainLoop::stop`].
+    VcpuStopped,
+    /// A stop signal arrived.
+    Signal,
+    /// [`VmHandle::shutdown`] was called.
+    Shutdown,
+    /// Input for the serial port fed by `consoles[n]`.
+    Console(usize),
+    /// A control client is waiting to connect.
+    ControlListener,
+    /// The control client on this descriptor sent something.
+    ControlClient(RawFd),
+    /// The guest notified the queue of `notifiers[n]`.
+    QueueNotify(usize),
+    /// [`MainLoop::retry_timer`] fired.
+    Retry,
+}
+
+/// The main thread's side of a running VM.
+struct MainLoop {
+    event_loop: EventLoop<Event>,
+    /// Why each vCPU stopped, signalled on `vcpu_stopped`.
+    stop: Receiver<Result<StopReason, CarbonError>>,
+    vcpu_stopped: Arc<EventFd>,
+    /// The stop signals, unless the VM is served on a reactor.
+    signals: Option<StopSignals>,
+    /// Signalled by [`VmHandle::shutdown`], if there is a handle.
+    shutdown: Option<Arc<VmHandle>>,
+    handler: Arc<Mutex<DeviceHandler>>,
+    /// The vCPU threads, kicked for halt checks.
+    threads: Arc<Mutex<Vec<libc::pthread_t>>>,
+    halt_round: Arc<AtomicU64>,
+    consoles: Vec<ConsoleFeed>,
+    control: Option<ControlSocket>,
+    /// The QUEUE_NOTIFY register and value of each ioeventfd.
+    notifiers: Vec<(u64, u32, EventFd)>,
+    /// Armed by devices holding back requests over their rate limits.
+    retry_timer: Arc<RetryTimer>,
+    /// When the run times out, and its timeout.
+    deadline: Option<(Instant, Duration)>,
+    /// When the boot times out unless init is reached first, and its
+    /// timeout.
+    boot_deadline: Option<(Instant, Duration)>,
+    init_reached: Arc<OnceLock<Instant>>,
+    /// When to next kick the vCPUs to check for a halted guest, with
+    /// `exit_on_halt`.
+    halt_check: Option<Instant>,
+    /// Queue notifications, console input and control events served.
+    activity: u64,
+    /// The idle hibernation policy, if any.
+    hibernation: Option<Hibernation>,
+}
+
+/// A VM's idle hibernation, checked by its [`MainLoop`].
+struct Hibernation {
+    policy: Hibernate,
+    monitor: IdleMonitor,
+    capture: Arc<Capture>,
+    /// When to next check whether the VM is idle.
+    check: Instant,
+}
+
+impl Hibernation {
+    /// Whether the VM has been idle for the whole timeout, given the
+    /// events `served` by the main loop: the vCPUs' port and MMIO exits
+    /// count as activity too.
+    fn idle(&mut self, served: u64) -> bool {
+        let exits: u64 = self
+            .capture
+            .stats()
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|stats| &stats.exits)
+            .filter(|(name, _)| matches!(*name, "io" | "mmio"))
+            .map(|(_, count)| count)
+            .sum();
+        let now = Instant::now();
+        self.check = now + self.monitor.interval();
+        self.monitor.observe(served + exits, now)
+    }
+
+    /// Snapshot the VM to the policy's directory. The files are written
+    /// aside and renamed into place: a VM woken from the last snapshot
+    /// still maps its memory file.
+    fn hibernate(&self) -> io::Result<()> {
+        std::fs::create_dir_all(&self.policy.dir)?;
+        let state = self.policy.state_path();
+        let memory = self.policy.memory_path();
+        let state_tmp = state.with_extension("json.tmp");
+        let memory_tmp = memory.with_extension("bin.tmp");
+        self.capture.snapshot(&state_tmp, &memory_tmp)?;
+        std::fs::rename(&memory_tmp, &memory)?;
+        std::fs::rename(&state_tmp, &state)
+    }
+}
+
+impl MainLoop {
+    /// Serve events until the VM stops, returning why.
+    fn run(&mut self) -> Result<StopReason, CarbonError> {
+        loop {
+            if let Some(reason) = self.turn(self.timeout()) {
+                return reason;
+            }
+        }
+    }
+
+    /// How long the loop may wait for events before it has work of its
+    /// own: a timeout, a halt or idle check, or console input to retry.
+    fn timeout(&self) -> Option<Duration> {
+        let mut wait = self
+            .deadline
+            .map(|(deadline, _)| deadline.saturating_duration_since(Instant::now()));
+        if let Some((deadline, _)) = self.boot_deadline {
+            let until = deadline.saturating_duration_since(Instant::now());
+            wait = Some(wait.map_or(until, |wait| wait.min(until)));
+        }
+        if let Some(check) = self.halt_check {
+            let until = check.saturating_duration_since(Instant::now());
+            wait = Some(wait.map_or(until, |wait| wait.min(until)));
+        }
+        if let Some(hibernation) = &self.hibernation {
+            let until = hibernation.check.saturating_duration_since(Instant::now());
+            wait = Some(wait.map_or(until, |wait| wait.min(until)));
+        }
+        if self
+            .consoles
+            .iter()
+            .any(|console| !console.pending.is_empty())
+        {
+            wait = Some(wait.map_or(RX_RETRY_INTERVAL, |wait| wait.min(RX_RETRY_INTERVAL)));
+        }
+        wait
+    }
+
+    /// Wait up to `wait` for events, and serve them and whatever work is
+    /// due. Returns the run's result once the VM stops.
+    fn turn(&mut self, wait: Option<Duration>) -> Option<Result<StopReason, CarbonError>> {
+        let events = match self.event_loop.wait(wait) {
+            Ok(events) => events,
+            Err(e) => return Some(Err(event_loop_error(e))),
+        };
+        for event in events {
+            if let Some(reason) = self.handle(event) {
+                return Some(reason);
+            }
+        }
+        if let Err(e) = self.retry_consoles() {
+            return Some(Err(e));
+        }
+        if self.halt_check.is_some_and(|check| Instant::now() >= check) {
+            self.halt_round.fetch_add(1, Ordering::SeqCst);
+            kick(&self.threads);
+            self.halt_check = Some(Instant::now() + HALT_CHECK_INTERVAL);
+        }
+        if let Some((deadline, timeout)) = self.deadline {
+            if Instant::now() >= deadline {
+                warn!("[VMM] Run timed out after {:?}", timeout);
+                return Some(Ok(StopReason::Timeout));
+            }
+        }
+        if let Some((deadline, timeout)) = self.boot_deadline {
+            if self.init_reached.get().is_some() {
+                self.boot_deadline = None;
+            } else if Instant::now() >= deadline {
+                warn!("[VMM] Guest didn't reach init within {:?}", timeout);
+                return Some(Ok(StopReason::BootTimeout));
+            }
+        }
+        if let Some(hibernation) = &mut self.hibernation {
+            if Instant::now() >= hibernation.check && hibernation.idle(self.activity) {
+                let policy = &hibernation.policy;
+                info!(
+                    "[VMM] Idle for {:?}, hibernating to {}",
+                    policy.idle_timeout,
+                    policy.dir.display()
+                );
+                match hibernation.hibernate() {
+                    Ok(()) => return Some(Ok(StopReason::Hibernated)),
+                    Err(e) => {
+                        // Trying again would stop the guest every interval
+                        warn!("[VMM] Failed to hibernate, staying up: {}", e);
+                        self.hibernation = None;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Handle one event. Returns the run's result once the VM stops.
+    fn handle(&mut self, event: Event) -> Option<Result<StopReason, CarbonError>> {
+        if matches!(
+            event,
+            Event::Console(_)
+                | Event::ControlListener
+                | Event::ControlClient(_)
+                | Event::QueueNotify(_)
+                | Event::Retry
+        ) {
+            self.activity += 1;
+        }
+        match event {
+            Event::VcpuStopped => {
+                let _ = self.vcpu_stopped.read();
+                match self.stop.try_recv() {
+                    Ok(reason) => return Some(reason),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => return Some(Ok(StopReason::GuestExit)),
+                }
+            }
+            Event::Signal => {
+                if let Some(signal) = self.signals.as_ref().and_then(StopSignals::take) {
+                    info!("[VMM] Stopping on signal {}", signal);
+                    return Some(Ok(StopReason::Signal(signal)));
+                }
+            }
+            Event::Shutdown => {
+                if let Some(handle) = &self.shutdown {
+                    if handle.shutdown.read().is_ok() {
+                        info!("[VMM] Stopping on request");
+                        return Some(Ok(StopReason::Shutdown));
+                    }
+                }
+            }
+            Event::Console(index) => {
+                let console = &mut self.consoles[index];
+                // At end of input, or with input the guest has no room for
+                // yet, stop waiting on it
+                if !console.read() || !console.pending.is_empty() {
+                    self.event_loop.remove(console.input.as_raw_fd());
+                }
+            }
+            Event::ControlListener => {
+                if let Some(control) = &mut self.control {
+                    match control.accept() {
+                        Ok(fd) => {
+                            if let Err(e) = self.event_loop.add(fd, Event::ControlClient(fd)) {
+                                return Some(Err(event_loop_error(e)));
+                            }
+                        }
+                        Err(e) => warn!("[VMM] Failed to accept control client: {}", e),
+                    }
+                }
+            }
+            Event::ControlClient(fd) => {
+                if let Some(control) = &mut self.control {
+                    if !control.serve(fd) {
+                        self.event_loop.remove(fd);
+                    }
+                }
+            }
+            Event::QueueNotify(index) => {
+                let (addr, queue, eventfd) = &self.notifiers[index];
+                // Notifications since the last read are one: the device
+                // serves whatever the queue holds
+                if eventfd.read().is_ok() {
+                    lock(&self.handler).mmio_write(*addr, &queue.to_le_bytes());
+                }
+            }
+            Event::Retry => {
+                if self.retry_timer.take() {
+                    lock(&self.handler).mmio_bus.retry();
+                }
+            }
+        }
+        None
+    }
+
+    /// Pass pending console input the guest now has room for, waiting on
+    /// the input again once all of it is through.
+    fn retry_consoles(&mut self) -> Result<(), CarbonError> {
+        for (index, console) in self.consoles.iter_mut().enumerate() {
+            if !console.pending.is_empty() && console.deliver() {
+                self.event_loop
+                    .add(console.input.as_raw_fd(), Event::Console(index))
+                    .map_err(event_loop_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A [`MainLoop`] served on a [`Reactor`], which sends the run's result
+/// once the VM stops.
+struct Reactive {
+    main_loop: MainLoop,
+    stopped: Sender<Result<StopReason, CarbonError>>,
+}
+
+impl AsRawFd for Reactive {
+    fn as_raw_fd(&self) -> RawFd {
+        self.main_loop.event_loop.as_raw_fd()
+    }
+}
+
+impl Source for Reactive {
+    fn timeout(&self) -> Option<Duration> {
+        self.main_loop.timeout()
+    }
+
+    fn poll(&mut self) -> bool {
+        match self.main_loop.turn(Some(Duration::ZERO)) {
+            Some(reason) => {
+                let _ = self.stopped.send(reason);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Signals an eventfd when dropped.
+struct Wake<'a>(&'a EventFd);
+
+impl Drop for Wake<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.write(1);
+    }
+}
+
+/// A failure setting up or waiting on the event loop.
+fn event_loop_error(e: io::Error) -> CarbonError {
+    CarbonError::Guest(format!("event loop failed: {e}"))
+}
+
+/// [`DeviceHandler`] shared by the vCPU threads, locked for each exit.
+struct SharedHandler<'a>(&'a Mutex<DeviceHandler>);
+
+impl IoHandler for SharedHandler<'_> {
+    fn io_read(&mut self, port: u16, data: &mut IoData) {
+        lock(self.0).io_read(port, data);
+    }
+
+    fn io_write(&mut self, port: u16, data: &IoData) {
+        lock(self.0).io_write(port, data);
+    }
+}
+
+impl MmioHandler for SharedHandler<'_> {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) {
+        lock(self.0).mmio_read(addr, data);
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) {
+        lock(self.0).mmio_write(addr, data);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Install the handler for the signal that kicks a vCPU thread out of
+/// `KVM_RUN`. It does nothing: being interrupted is the point, so it is
+/// installed without `SA_RESTART`.
+fn install_kick_handler() {
+    extern "C" fn kick(_: libc::c_int) {}
+
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        // SAFETY: the handler is async-signal-safe (it does nothing), and
+        // the sigaction struct is fully initialized.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = kick as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGRTMIN(), &action, std::ptr::null_mut()) != 0 {
+                warn!(
+                    "[VMM] Failed to install vCPU kick handler: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    });
+}
+
+/// Attach a virtio device at its slot `(mmio_base, gsi, transport)`: at the
+/// slot's MMIO region, or as the next device on the PCI bus raising the
+/// slot's GSI. Returns where it ended up, for the log.
+fn attach(
+    mmio_bus: &mut MmioBus,
+    pci_bus: &mut PciBus,
+    (mmio_base, gsi, transport): (u64, u32, Transport),
+    device: Box<dyn MmioDevice>,
+) -> String {
+    match transport {
+        Transport::Mmio => {
+            mmio_bus.register(mmio_base, VIRTIO_MMIO_SIZE, device);
+            format!("{mmio_base:#x}")
+        }
+        Transport::Pci => {
+            let device = VirtioPci::new(device);
+            let config = device.config(gsi as u8);
+            let number = pci_bus.add(config, Box::new(device));
+            format!("PCI 00:{number:02x}.0")
+        }
+    }
+}
+
+/// Removes the progress status line when dropped.
+struct ClearStatus;
+
+impl Drop for ClearStatus {
+    fn drop(&mut self) {
+        progress::clear();
+    }
+}
+
+/// `" at RIP 0x..."` for error messages, if the vCPU registers are readable.
+fn rip_suffix(vcpu: &kvm::VcpuFd) -> String {
+    vcpu.get_regs()
+        .map(|regs| format!(" at RIP {:#x}", regs.rip))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::blk::CachePolicy;
+
+    fn watch(chunks: &[&[u8]], marker: &str) -> bool {
+        let seen = Arc::new(OnceLock::new());
+        let mut watcher = MarkerWatcher::new(
+            Box::new(io::sink()),
+            marker,
+            seen.clone(),
+            Arc::new(OnceLock::new()),
+            Arc::new(OnceLock::new()),
+        );
+        for chunk in chunks {
+            watcher.write_all(chunk).unwrap();
+        }
+        seen.get().is_some()
+    }
+
+    fn exit_status(chunks: &[&[u8]]) -> Option<u8> {
+        let exit_status = Arc::new(OnceLock::new());
+        let mut watcher = MarkerWatcher::new(
+            Box::new(io::sink()),
+            DEFAULT_INIT_MARKER,
+            Arc::new(OnceLock::new()),
+            Arc::new(OnceLock::new()),
+            exit_status.clone(),
+        );
+        for chunk in chunks {
+            watcher.write_all(chunk).unwrap();
+        }
+        exit_status.get().copied()
+    }
+
+    #[test]
+    fn test_boot_timeline_report() {
+        let vmm_start = Instant::now();
+        let timeline = BootTimeline {
+            vmm_start,
+            kvm_ready: vmm_start + Duration::from_micros(1500),
+            kernel_start: Some(vmm_start + Duration::from_millis(12)),
+            first_output: None,
+            init_reached: Some(vmm_start + Duration::from_millis(250)),
+        };
+        assert_eq!(
+            timeline.to_string(),
+            "kvm_ready=1.50ms vcpu_entry=12.00ms first_output=- init=250.00ms"
+        );
+
+        let json = timeline.to_json();
+        assert!(json.find("kvm_ready_ms") < json.find("init_ms"));
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(report["vmm_start"].as_f64().unwrap() > 1.6e9);
+        assert_eq!(report["kvm_ready_ms"], 1.5);
+        assert_eq!(report["vcpu_entry_ms"], 12.0);
+        assert!(report["first_output_ms"].is_null());
+        assert_eq!(report["init_ms"], 250.0);
+    }
+
+    #[test]
+    fn test_marker_in_single_write() {
+        assert!(watch(
+            &[b"[    1.0] Run /sbin/init as init process\n"],
+            DEFAULT_INIT_MARKER
+        ));
+    }
+
+    #[test]
+    fn test_marker_split_across_writes() {
+        // The serial port delivers output one byte at a time
+        let text = b"Run /init as init process";
+        let chunks: Vec<&[u8]> = text.chunks(1).collect();
+        assert!(watch(&chunks, DEFAULT_INIT_MARKER));
+    }
+
+    #[test]
+    fn test_marker_absent() {
+        assert!(!watch(
+            &[b"Kernel panic - not syncing: VFS: Unable to mount root fs"],
+            DEFAULT_INIT_MARKER
+        ));
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(exit_status(&[b"ok\r\nCARBON_EXIT_STATUS=3\r\n"]), Some(3));
+        assert_eq!(
+            exit_status(&[
+                b"CARBON_EXIT",
+                b"_STATUS=0",
+                b"\n",
+                b"CARBON_EXIT_STATUS=1\n"
+            ]),
+            Some(0)
+        );
+        // Not until the line ends, and only a line of its own
+        assert_eq!(exit_status(&[b"CARBON_EXIT_STATUS=3"]), None);
+        assert_eq!(exit_status(&[b"+ echo CARBON_EXIT_STATUS=3\n"]), None);
+        assert_eq!(exit_status(&[b"CARBON_EXIT_STATUS=256\n"]), None);
+        let long = format!("{}\nCARBON_EXIT_STATUS=2\n", "x".repeat(200));
+        assert_eq!(exit_status(&[long.as_bytes()]), Some(2));
+    }
+
+    #[test]
+    fn test_parse_disk() {
+        let disk: DiskConfig = "images/base.img".parse().unwrap();
+        assert_eq!(disk.path, "images/base.img");
+        assert!(!disk.options.read_only);
+
+        let disk: DiskConfig = "path=base.img,ro".parse().unwrap();
+        assert_eq!(disk.path, "base.img");
+        assert!(disk.options.read_only);
+
+        assert!("path=,ro".parse::<DiskConfig>().is_err());
+
+        let disk: DiskConfig = "vhost-user=/run/spdk/vhost.0".parse().unwrap();
+        assert_eq!(disk.path, "/run/spdk/vhost.0");
+        assert!(disk.vhost_user);
+        assert!("vhost-user=".parse::<DiskConfig>().is_err());
+        assert!("vhost-user=/s,ro".parse::<DiskConfig>().is_err());
+        let disk: DiskConfig = "vhost-user=/s,transport=pci".parse().unwrap();
+        assert_eq!((disk.path.as_str(), disk.transport), ("/s", Transport::Pci));
+        let disk: DiskConfig = "path=base.img,ro,transport=pci".parse().unwrap();
+        assert_eq!(disk.transport, Transport::Pci);
+        assert!("path=base.img,transport=usb".parse::<DiskConfig>().is_err());
+        assert!("path=base.img,rw".parse::<DiskConfig>().is_err());
+
+        let disk: DiskConfig = "path=base.img,logical-block-size=4K,physical-block-size=4096"
+            .parse()
+            .unwrap();
+        assert_eq!(disk.options.block_sizes(), Ok((4096, 4096)));
+        let disk: DiskConfig = "path=base.img,physical-block-size=4096".parse().unwrap();
+        assert_eq!(disk.options.block_sizes(), Ok((512, 4096)));
+        assert!("path=base.img,logical-block-size=1000"
+            .parse::<DiskConfig>()
+            .is_err());
+        assert!("path=base.img,logical-block-size=8K"
+            .parse::<DiskConfig>()
+            .is_err());
+        assert!(
+            "path=base.img,logical-block-size=4K,physical-block-size=512"
+                .parse::<DiskConfig>()
+                .is_err()
+        );
+
+        let disk: DiskConfig = "path=base.img,direct=on".parse().unwrap();
+        assert!(disk.options.direct);
+        let disk: DiskConfig = "path=base.img,direct=off".parse().unwrap();
+        assert!(!disk.options.direct);
+        assert!("path=base.img,direct=yes".parse::<DiskConfig>().is_err());
+        assert!("vhost-user=/s,direct=on".parse::<DiskConfig>().is_err());
+        let disk: DiskConfig = "path=base.img".parse().unwrap();
+        assert_eq!(disk.options.cache, CachePolicy::Writeback);
+        let disk: DiskConfig = "path=base.img,cache=writethrough".parse().unwrap();
+        assert_eq!(disk.options.cache, CachePolicy::Writethrough);
+        assert!(!disk.options.direct_io());
+        let disk: DiskConfig = "path=base.img,cache=none".parse().unwrap();
+        assert!(disk.options.direct_io() && !disk.options.direct);
+        assert!("path=base.img,cache=unsafe".parse::<DiskConfig>().is_err());
+        assert!("vhost-user=/s,cache=none".parse::<DiskConfig>().is_err());
+        let disk: DiskConfig = "path=base.img,key-file=/run/keys/base".parse().unwrap();
+        assert_eq!(
+            disk.options.key,
+            Some(KeySource::File("/run/keys/base".into()))
+        );
+        let disk: DiskConfig = "fd=5,key-fd=6".parse().unwrap();
+        assert_eq!(disk.options.key, Some(KeySource::Fd(6)));
+        assert!("path=base.img,key-fd=2".parse::<DiskConfig>().is_err());
+        assert!("path=base.img,key-file=k,key-command=kms"
+            .parse::<DiskConfig>()
+            .is_err());
+        assert!("vhost-user=/s,key-file=k".parse::<DiskConfig>().is_err());
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let disk: DiskConfig = format!("path=base.img,sha256={digest}").parse().unwrap();
+        assert_eq!(disk.options.sha256, Some(digest.parse().unwrap()));
+        assert!("path=base.img,sha256=abab".parse::<DiskConfig>().is_err());
+        let disk: DiskConfig = "fd=5".parse().unwrap();
+        assert_eq!((disk.fd, disk.path.as_str()), (Some(5), "fd=5"));
+        let disk: DiskConfig = "fd=7,ro,direct=on".parse().unwrap();
+        assert_eq!(disk.fd, Some(7));
+        assert!(disk.options.read_only && disk.options.direct);
+        assert!("fd=".parse::<DiskConfig>().is_err());
+        assert!("fd=1".parse::<DiskConfig>().is_err());
+        assert!("fd=x,ro".parse::<DiskConfig>().is_err());
+        assert_eq!("path=fd=5".parse::<DiskConfig>().unwrap().fd, None);
+        assert!("fd=5,exclusive=on".parse::<DiskConfig>().is_err());
+
+        let disk: DiskConfig = "path=base.img,iops=500,bw=20M,bw-burst=40M"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            disk.options.rate_limit.to_string(),
+            "iops=500,bw=20971520,bw-burst=41943040"
+        );
+        assert!("path=base.img,iops-burst=10".parse::<DiskConfig>().is_err());
+        assert!("vhost-user=/s,iops=10".parse::<DiskConfig>().is_err());
+
+        let disk: DiskConfig = "path=/dev/mapper/vg-vm0,exclusive=on".parse().unwrap();
+        assert!(disk.options.exclusive && !disk.options.direct);
+        assert!("path=base.img,exclusive=1".parse::<DiskConfig>().is_err());
+    }
+}
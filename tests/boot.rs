@@ -0,0 +1,51 @@
+//! End-to-end boot tests.
+//!
+//! These boot a real guest and need `/dev/kvm`, so they only build with the
+//! `integration` feature:
+//!
+//! ```text
+//! cargo test --features integration --test boot
+//! ```
+
+#![cfg(all(target_os = "linux", feature = "integration"))]
+
+mod common;
+
+use common::{require_kvm, Guest, TempFile};
+
+/// Data the host seeds at the start of the disk; the guest echoes it back.
+const DISK_SEED: &[u8; 16] = b"CARBON-DISK-DATA";
+
+/// Data the guest writes to sector 1 of the disk.
+const GUEST_WRITE: &[u8; 16] = b"GUEST-WROTE-THIS";
+
+#[test]
+fn test_boots_initramfs_and_shuts_down() {
+    require_kvm();
+
+    let run = Guest::new().run();
+
+    run.assert_console_contains("Linux version");
+    run.assert_console_contains("Run /init as init process");
+    run.assert_console_contains("CARBON-INIT: started");
+    run.assert_console_contains("CARBON-INIT: no disk");
+    run.assert_console_contains("CARBON-INIT: done");
+    run.assert_clean_shutdown();
+}
+
+#[test]
+fn test_virtio_blk_read_write() {
+    require_kvm();
+
+    let disk = TempFile::with_contents("blk.raw", 1024 * 1024, DISK_SEED);
+    let run = Guest::new().disk(disk.path()).run();
+
+    run.assert_console_contains("virtio_blk");
+    run.assert_console_contains("CARBON-INIT: disk read: CARBON-DISK-DATA");
+    run.assert_console_contains("CARBON-INIT: disk write ok");
+    run.assert_console_contains("CARBON-INIT: done");
+    run.assert_clean_shutdown();
+
+    assert_eq!(disk.read_at(512, GUEST_WRITE.len()), GUEST_WRITE);
+    assert_eq!(disk.read_at(0, DISK_SEED.len()), DISK_SEED);
+}
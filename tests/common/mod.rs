@@ -0,0 +1,200 @@
+//! Shared harness for end-to-end tests that boot a real guest.
+//!
+//! Tests run the `carbon` binary against the bundled kernel (`bin/vmlinuz`)
+//! and the initramfs fixture (`tests/fixtures/initramfs.cpio`), capturing the
+//! guest console (stdout) and VMM log (stderr) separately.
+//!
+//! The fixture's `/init` prints `CARBON-INIT: ...` progress lines, exercises
+//! `/dev/vda` when a disk is attached, and then reboots, which Carbon turns
+//! into a VM exit. See `tests/fixtures/init.S`.
+
+#![allow(dead_code)] // Each test binary uses part of the harness
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a single guest may run before the harness kills it.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Panic with a clear message if this host cannot run KVM guests.
+pub fn require_kvm() {
+    if let Err(e) = OpenOptions::new().read(true).write(true).open("/dev/kvm") {
+        panic!("integration tests need read/write access to /dev/kvm: {e}");
+    }
+}
+
+/// Path to the kernel under test (`CARBON_TEST_KERNEL` overrides the bundled one).
+pub fn kernel_path() -> PathBuf {
+    std::env::var_os("CARBON_TEST_KERNEL")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("bin/vmlinuz"))
+}
+
+/// Path to the bundled initramfs fixture.
+pub fn initramfs_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/initramfs.cpio")
+}
+
+/// A scratch file that is removed when dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create a zero-filled file of `size` bytes with `contents` at offset 0.
+    pub fn with_contents(name: &str, size: u64, contents: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!("carbon-{}-{}", std::process::id(), name));
+        let mut file = File::create(&path).expect("create temp file");
+        file.write_all(contents).expect("write temp file");
+        file.set_len(size).expect("size temp file");
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read `len` bytes at `offset`.
+    pub fn read_at(&self, offset: u64, len: usize) -> Vec<u8> {
+        let data = fs::read(&self.path).expect("read temp file");
+        data[offset as usize..offset as usize + len].to_vec()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Output of one guest run.
+pub struct GuestRun {
+    /// Guest serial console output.
+    pub console: String,
+    /// VMM log output.
+    pub log: String,
+    /// Whether `carbon` exited successfully.
+    pub success: bool,
+    /// Whether the harness had to kill the VM.
+    pub timed_out: bool,
+}
+
+impl GuestRun {
+    /// Assert that the console contains `needle`, dumping all output if not.
+    pub fn assert_console_contains(&self, needle: &str) {
+        assert!(
+            self.console.contains(needle),
+            "console output missing {needle:?}\n{}",
+            self.dump()
+        );
+    }
+
+    /// Assert the VM exited on its own after the guest rebooted.
+    pub fn assert_clean_shutdown(&self) {
+        assert!(
+            !self.timed_out,
+            "guest did not stop in time\n{}",
+            self.dump()
+        );
+        assert!(self.success, "carbon exited with an error\n{}", self.dump());
+        assert!(
+            !self.console.contains("Kernel panic"),
+            "guest panicked\n{}",
+            self.dump()
+        );
+        assert!(
+            self.log.contains("Guest shutdown"),
+            "VM did not stop via guest shutdown\n{}",
+            self.dump()
+        );
+    }
+
+    fn dump(&self) -> String {
+        format!(
+            "--- console ---\n{}\n--- vmm log ---\n{}",
+            self.console, self.log
+        )
+    }
+}
+
+/// Builder for a guest boot.
+pub struct Guest {
+    memory_mb: u64,
+    cmdline: String,
+    disk: Option<PathBuf>,
+}
+
+impl Guest {
+    pub fn new() -> Self {
+        Self {
+            memory_mb: 128,
+            cmdline: "console=ttyS0".to_string(),
+            disk: None,
+        }
+    }
+
+    pub fn disk(mut self, path: &Path) -> Self {
+        self.disk = Some(path.to_path_buf());
+        self
+    }
+
+    /// Boot the guest and wait for it to stop (or for the timeout).
+    pub fn run(self) -> GuestRun {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_carbon"));
+        cmd.arg("run")
+            .arg("--kernel")
+            .arg(kernel_path())
+            .arg("--initrd")
+            .arg(initramfs_path())
+            .arg("--memory")
+            .arg(self.memory_mb.to_string())
+            .arg("--cmdline")
+            .arg(&self.cmdline);
+        if let Some(disk) = &self.disk {
+            cmd.arg("--disk").arg(disk);
+        }
+
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("spawn carbon");
+
+        let stdout = drain(child.stdout.take().unwrap());
+        let stderr = drain(child.stderr.take().unwrap());
+
+        let deadline = Instant::now() + BOOT_TIMEOUT;
+        let mut timed_out = false;
+        let status = loop {
+            if let Some(status) = child.try_wait().expect("wait for carbon") {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                timed_out = true;
+                let _ = child.kill();
+                break child.wait().expect("wait for carbon");
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        GuestRun {
+            console: stdout.join().unwrap(),
+            log: stderr.join().unwrap(),
+            success: status.success(),
+            timed_out,
+        }
+    }
+}
+
+fn drain(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        String::from_utf8_lossy(&buf).into_owned()
+    })
+}
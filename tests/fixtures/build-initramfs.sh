@@ -0,0 +1,25 @@
+#!/bin/bash
+#
+# Rebuild the integration test initramfs (tests/fixtures/initramfs.cpio)
+# from init.S. Requires gcc/binutils and cpio.
+#
+# Usage: ./tests/fixtures/build-initramfs.sh
+#
+
+set -euo pipefail
+
+FIXTURE_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+WORK_DIR="$(mktemp -d)"
+trap 'rm -rf "${WORK_DIR}"' EXIT
+
+mkdir -p "${WORK_DIR}/root"
+gcc -nostdlib -static -no-pie -Wl,--build-id=none -s \
+    -o "${WORK_DIR}/root/init" "${FIXTURE_DIR}/init.S"
+chmod 0755 "${WORK_DIR}/root/init"
+
+# Reproducible archive: fixed owner and mtime
+touch -d @0 "${WORK_DIR}/root/init"
+(cd "${WORK_DIR}/root" && echo init | cpio -o -H newc -R 0:0 --reproducible) \
+    > "${FIXTURE_DIR}/initramfs.cpio"
+
+ls -l "${FIXTURE_DIR}/initramfs.cpio"
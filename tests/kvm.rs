@@ -0,0 +1,380 @@
+//! Tests against the host's KVM: vCPUs running a few bytes of real-mode
+//! code, without a kernel or devices, and VMs that never leave a paused
+//! start.
+//!
+//! These need `/dev/kvm`, so they only build with the `integration` feature:
+//!
+//! ```text
+//! cargo test --features integration --test kvm
+//! ```
+
+#![cfg(all(target_os = "linux", feature = "integration"))]
+
+mod common;
+
+use carbon::boot::GuestMemory;
+use carbon::control;
+use carbon::hibernate::{self, Hibernate};
+use carbon::kvm::{self, CpuFeatures, CpuMode, IoData, IoHandler, MmioHandler, Topology, VcpuExit};
+use carbon::{CarbonError, ConsoleConfig, StopReason, Vmm, VmmBuilder};
+use common::{initramfs_path, kernel_path, require_kvm, TempFile};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the test code is loaded and starts.
+const CODE: u64 = 0x1000;
+
+/// `nop; mov al, 0x42; out 0x80, al; out 0x81, al; hlt`
+const PROGRAM: &[u8] = &[0x90, 0xb0, 0x42, 0xe6, 0x80, 0xe6, 0x81, 0xf4];
+
+/// Whether KVM runs guests on VMX or SVM. A software-only KVM (such as
+/// PVM) emulates real mode, where INT3 goes through the guest's IVT and a
+/// single step can run several instructions.
+fn hardware_virt() -> bool {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    cpuinfo
+        .lines()
+        .filter(|line| line.starts_with("flags"))
+        .any(|line| {
+            line.split_whitespace()
+                .any(|flag| flag == "vmx" || flag == "svm")
+        })
+}
+
+/// Records port writes.
+#[derive(Default)]
+struct Ports(Vec<(u16, u8)>);
+
+impl IoHandler for Ports {
+    fn io_read(&mut self, _port: u16, _data: &mut IoData) {}
+
+    fn io_write(&mut self, port: u16, data: &IoData) {
+        self.0.push((port, data.as_slice()[0]));
+    }
+}
+
+impl MmioHandler for Ports {
+    fn mmio_read(&mut self, _addr: u64, _data: &mut [u8]) {}
+
+    fn mmio_write(&mut self, _addr: u64, _data: &[u8]) {}
+}
+
+/// A VM of `cpus` vCPUs, started paused on another thread, once its vCPUs
+/// are created. Stop it with [`stop`].
+fn paused_vmm(cpus: u8, console: &TempFile) -> (Arc<Vmm>, thread::JoinHandle<StopReason>) {
+    require_kvm();
+    let vmm = VmmBuilder::new(kernel_path().display().to_string())
+        .initrd(initramfs_path().display().to_string())
+        .memory(128 << 20)
+        .cpus(cpus)
+        .serial(ConsoleConfig::File(console.path().to_path_buf()))
+        .paused()
+        .build()
+        .unwrap();
+    let vmm = Arc::new(vmm);
+    let run = thread::spawn({
+        let vmm = vmm.clone();
+        move || vmm.run().unwrap().reason
+    });
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while vmm.stats().is_err() {
+        assert!(Instant::now() < deadline, "the VM never started");
+        thread::sleep(Duration::from_millis(10));
+    }
+    (vmm, run)
+}
+
+/// Shut down a VM from [`paused_vmm`].
+fn stop(vmm: &Vmm, run: thread::JoinHandle<StopReason>) {
+    vmm.shutdown().unwrap();
+    assert!(matches!(run.join().unwrap(), StopReason::Shutdown));
+    assert!(vmm.stats().is_err());
+}
+
+/// A one-vCPU VM about to run [`PROGRAM`] in real mode.
+fn real_mode() -> (kvm::VmFd, kvm::VcpuFd, GuestMemory) {
+    require_kvm();
+    let vm = kvm::create_vm(
+        CpuMode::Host,
+        &CpuFeatures::default(),
+        Topology::default(),
+        false,
+        false,
+    )
+    .unwrap();
+    let memory = GuestMemory::new(1 << 20).unwrap();
+    for (slot, region) in memory.regions().iter().enumerate() {
+        // SAFETY: the region stays mapped for as long as `memory`, which
+        // outlives the VM in every test.
+        unsafe {
+            vm.set_user_memory_region(
+                slot as u32,
+                region.guest_addr,
+                region.size,
+                region.host_addr,
+            )
+            .unwrap();
+        }
+    }
+    memory.write(CODE, PROGRAM).unwrap();
+
+    let vcpu = vm.create_vcpu(0).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = CODE;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    (vm, vcpu, memory)
+}
+
+#[test]
+fn test_breakpoint_and_step() {
+    if !hardware_virt() {
+        eprintln!("skipped: needs VMX or SVM");
+        return;
+    }
+    let (_vm, mut vcpu, memory) = real_mode();
+    let mut ports = Ports::default();
+
+    // Stops at the breakpoint, which reads as the code it replaced
+    assert!(vcpu.set_breakpoint(&memory, CODE + 1).unwrap());
+    assert!(!vcpu.set_breakpoint(&memory, CODE + 1).unwrap());
+    let exit = vcpu.run_with_io(&mut ports).unwrap();
+    assert!(
+        matches!(exit, VcpuExit::Debug(pc) if pc == CODE + 1),
+        "{exit:?}"
+    );
+    assert_eq!(vcpu.read_memory_at_rip(&memory, 4).unwrap(), PROGRAM[1..5]);
+
+    // Steps over it one instruction at a time, the OUT going to the handler
+    let exit = vcpu.single_step(&memory, &mut ports, 1).unwrap();
+    assert!(
+        matches!(exit, VcpuExit::Debug(pc) if pc == CODE + 3),
+        "{exit:?}"
+    );
+    assert_eq!(vcpu.get_regs().unwrap().rax & 0xff, 0x42);
+    let exit = vcpu.single_step(&memory, &mut ports, 1).unwrap();
+    assert!(
+        matches!(exit, VcpuExit::Debug(pc) if pc == CODE + 5),
+        "{exit:?}"
+    );
+    assert_eq!(ports.0, [(0x80, 0x42)]);
+
+    // Removed, the guest's code is as it was
+    assert!(vcpu.remove_breakpoint(&memory, CODE + 1).unwrap());
+    assert!(!vcpu.remove_breakpoint(&memory, CODE + 1).unwrap());
+    let mut code = [0u8; PROGRAM.len()];
+    memory.read(CODE, &mut code).unwrap();
+    assert_eq!(code, PROGRAM);
+}
+
+#[test]
+fn test_step_runs_through_breakpoint_under_rip() {
+    if !hardware_virt() {
+        eprintln!("skipped: needs VMX or SVM");
+        return;
+    }
+    let (_vm, mut vcpu, memory) = real_mode();
+    let mut ports = Ports::default();
+    assert!(vcpu.set_breakpoint(&memory, CODE + 3).unwrap());
+
+    // Steps over the breakpoint they land on, leaving it set
+    let exit = vcpu.single_step(&memory, &mut ports, 4).unwrap();
+    assert!(
+        matches!(exit, VcpuExit::Debug(pc) if pc == CODE + 7),
+        "{exit:?}"
+    );
+    assert_eq!(ports.0, [(0x80, 0x42), (0x81, 0x42)]);
+    let mut byte = [0u8];
+    memory.read(CODE + 3, &mut byte).unwrap();
+    assert_eq!(byte, [0xcc]);
+}
+
+#[test]
+fn test_stats_count_exits() {
+    let (_vm, mut vcpu, _memory) = real_mode();
+    let mut ports = Ports::default();
+    assert_eq!(vcpu.stats().total_exits(), 0);
+
+    for _ in 0..2 {
+        let exit = vcpu.run_with_io(&mut ports).unwrap();
+        assert!(matches!(exit, VcpuExit::Io), "{exit:?}");
+    }
+    assert_eq!(ports.0, [(0x80, 0x42), (0x81, 0x42)]);
+
+    let stats = vcpu.stats();
+    assert_eq!(stats.total_exits(), 2);
+    assert!(stats.exits.contains(&("io", 2)), "{:?}", stats.exits);
+    assert!(stats.guest > std::time::Duration::ZERO);
+    assert_eq!(vcpu.stats_reader().stats().exits, stats.exits);
+}
+
+#[test]
+fn test_vmm_stats() {
+    let console = TempFile::with_contents("stats-console", 0, b"");
+    let (vmm, run) = paused_vmm(2, &console);
+    let stats = vmm.stats().unwrap();
+    assert_eq!(stats.len(), 2);
+    // Held before ever entering the guest
+    assert!(
+        stats.iter().all(|stats| stats.total_exits() == 0),
+        "{stats:?}"
+    );
+    stop(&vmm, run);
+}
+
+#[test]
+fn test_vmm_debug() {
+    let console = TempFile::with_contents("debug-console", 0, b"");
+    let (vmm, run) = paused_vmm(1, &console);
+    assert!(vmm.debug(1).is_err());
+    let debug = vmm.debug(0).unwrap();
+
+    // Held at the kernel's entry point, breakpoints reading as the code
+    // they replaced
+    let code = debug.read_memory_at_rip(16).unwrap();
+    assert!(code.iter().any(|&byte| byte != 0), "{code:x?}");
+    assert!(debug.set_breakpoint(0x1000).unwrap());
+    assert!(!debug.set_breakpoint(0x1000).unwrap());
+    assert_eq!(debug.read_memory_at_rip(16).unwrap(), code);
+    assert!(debug.remove_breakpoint(0x1000).unwrap());
+    assert!(!debug.remove_breakpoint(0x1000).unwrap());
+
+    stop(&vmm, run);
+}
+
+#[test]
+fn test_vmm_pauses_at_breakpoint() {
+    if !hardware_virt() {
+        eprintln!("skipped: needs VMX or SVM");
+        return;
+    }
+    let console = TempFile::with_contents("breakpoint-console", 0, b"");
+    let (vmm, run) = paused_vmm(1, &console);
+    let debug = vmm.debug(0).unwrap();
+
+    // Boot identity-maps low memory, so the step's linear address is the
+    // breakpoint's physical one
+    let exit = debug.single_step(1).unwrap();
+    let VcpuExit::Debug(rip) = exit else {
+        panic!("{exit:?}");
+    };
+    assert!(vmm.is_paused());
+    assert!(debug.set_breakpoint(rip).unwrap());
+
+    // Reaching it pauses the VM again, and a step goes over it
+    vmm.resume().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !vmm.is_paused() {
+        assert!(Instant::now() < deadline, "the breakpoint was never hit");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let exit = debug.single_step(1).unwrap();
+    assert!(matches!(exit, VcpuExit::Debug(pc) if pc != rip), "{exit:?}");
+    assert!(debug.remove_breakpoint(rip).unwrap());
+
+    stop(&vmm, run);
+}
+
+#[test]
+fn test_idle_vm_hibernates() {
+    require_kvm();
+    let console = TempFile::with_contents("hibernate-console", 0, b"");
+    let dir = std::env::temp_dir().join(format!("carbon-{}-hibernate", std::process::id()));
+    let hibernate = Hibernate::new(Duration::from_secs(1), &dir);
+    // Held paused, the guest never touches a device
+    let vmm = VmmBuilder::new(kernel_path().display().to_string())
+        .initrd(initramfs_path().display().to_string())
+        .memory(128 << 20)
+        .serial(ConsoleConfig::File(console.path().to_path_buf()))
+        .paused()
+        .hibernate(hibernate.clone())
+        .build()
+        .unwrap();
+    let started = Instant::now();
+    let run = thread::spawn(move || vmm.run().unwrap().reason);
+    while !run.is_finished() {
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "the VM never hibernated"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(run.join().unwrap(), StopReason::Hibernated);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    // The snapshot restores
+    let vmm = VmmBuilder::restore(&hibernate.state_path(), &hibernate.memory_path())
+        .unwrap()
+        .paused()
+        .build()
+        .unwrap();
+    let vmm = Arc::new(vmm);
+    let run = thread::spawn({
+        let vmm = vmm.clone();
+        move || vmm.run().unwrap().reason
+    });
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while vmm.stats().is_err() {
+        assert!(Instant::now() < deadline, "the VM never restored");
+        thread::sleep(Duration::from_millis(10));
+    }
+    stop(&vmm, run);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_hibernated_vm_wakes_on_control_client() {
+    require_kvm();
+    let console = TempFile::with_contents("wake-console", 0, b"");
+    let dir = std::env::temp_dir().join(format!("carbon-{}-wake", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("control.sock");
+    let hibernate = Hibernate::new(Duration::from_secs(1), &dir);
+    let builder = VmmBuilder::new(kernel_path().display().to_string())
+        .initrd(initramfs_path().display().to_string())
+        .memory(128 << 20)
+        .serial(ConsoleConfig::File(console.path().to_path_buf()))
+        .paused()
+        .control_socket(&socket);
+    // The woken VM stops well before it could hibernate again
+    let run = thread::spawn({
+        let hibernate = hibernate.clone();
+        move || {
+            hibernate::run(builder, &hibernate, |restored| {
+                restored.paused().timeout(Duration::from_millis(500))
+            })
+            .unwrap()
+            .reason
+        }
+    });
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !hibernate.state_path().exists() {
+        assert!(Instant::now() < deadline, "the VM never hibernated");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // The client's command waits for the VM to wake, then is served
+    let status = control::send(&socket, "status", Duration::from_secs(30)).unwrap();
+    assert_eq!(status, "paused");
+    assert_eq!(run.join().unwrap(), StopReason::Timeout);
+    assert!(!socket.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_hibernate_needs_snapshots() {
+    require_kvm();
+    let mut builder = VmmBuilder::new(kernel_path().display().to_string())
+        .hibernate(Hibernate::new(Duration::from_secs(1), "/nonexistent"));
+    builder.config_mut().hotplug_disks = 1;
+    let err = builder.build().unwrap().run().unwrap_err();
+    assert!(
+        matches!(&err, CarbonError::Config(message) if message.contains("hot-plug")),
+        "{err:?}"
+    );
+}